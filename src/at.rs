@@ -0,0 +1,519 @@
+//! AT command engine, for cellular modems and other peripherals driven by AT commands
+//!
+//! `AtClient` sends AT commands over any asynchronous serial port - e.g. an `AsyncUartDriver` -
+//! and collects their responses, up to the final result code:
+//!
+//! ```ignore
+//! let timer_service = EspTaskTimerService::new()?;
+//! let mut at = AtClient::new(uart, &timer_service, &Default::default())?;
+//!
+//! at.subscribe("+CREG", |urc| info!("Registration: {urc}"));
+//!
+//! at.command("ATE0").await?;
+//!
+//! let response = at.command("AT+CSQ").await?;
+//! info!("Signal quality: {:?}", response.value("+CSQ"));
+//!
+//! at.command_with_data("AT+CMGS=\"+3161234567\"", b"Hello\x1a", Duration::from_secs(60))
+//!     .await?;
+//!
+//! loop {
+//!     // Dispatch the unsolicited result codes received in between the commands
+//!     at.process(Duration::from_secs(1)).await?;
+//! }
+//! ```
+//!
+//! The unsolicited result codes (URCs) - the lines which start with a subscribed prefix - are
+//! dispatched to their callbacks whenever they are received, including in the middle of the
+//! response to a command, unless the command itself has that prefix (e.g. the `+CREG:` lines
+//! answering `AT+CREG?`). The echo of the commands is ignored.
+//!
+//! Besides `OK`, `CONNECT` - e.g. answering `ATD*99#`, once the modem switched to data mode -
+//! is a final result code of success, and so are the codes passed to `command_with_final_codes`.
+
+use core::fmt::{self, Debug, Display};
+use core::pin::pin;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embassy_futures::select::{select, Either};
+
+use embedded_svc::io::asynch::{Read, Write};
+
+use crate::sys::*;
+use crate::timer::{EspAsyncTimer, EspTaskTimerService};
+
+/// An error of an AT command
+#[derive(Debug)]
+pub enum AtError<E> {
+    /// The serial port failed
+    Io(E),
+    /// The timer of the timeouts failed
+    Esp(EspError),
+    /// No final result code within the timeout
+    Timeout,
+    /// The command failed with `ERROR`, or with another final result code such as `NO CARRIER`
+    Error(String),
+    /// The command failed with `+CME ERROR: <code>`
+    CmeError(u16),
+    /// The command failed with `+CMS ERROR: <code>`
+    CmsError(u16),
+    /// A line longer than `max_line_len` was received
+    Overflow,
+}
+
+impl<E> Display for AtError<E>
+where
+    E: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "IO error: {err:?}"),
+            Self::Esp(err) => write!(f, "{err}"),
+            Self::Timeout => write!(f, "Timeout"),
+            Self::Error(code) => write!(f, "Command failed: {code}"),
+            Self::CmeError(code) => write!(f, "+CME ERROR: {code}"),
+            Self::CmsError(code) => write!(f, "+CMS ERROR: {code}"),
+            Self::Overflow => write!(f, "Line too long"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for AtError<E> where E: Debug {}
+
+impl<E> From<EspError> for AtError<E> {
+    fn from(err: EspError) -> Self {
+        Self::Esp(err)
+    }
+}
+
+/// The response to a successful AT command
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AtResponse {
+    /// The information lines, followed by the final result code unless it is `OK`
+    pub lines: Vec<String>,
+}
+
+impl AtResponse {
+    /// The value of the first line with `prefix`, e.g. `"23,99"` for the line `+CSQ: 23,99` and
+    /// the prefix `+CSQ`
+    pub fn value(&self, prefix: &str) -> Option<&str> {
+        self.values(prefix).next()
+    }
+
+    /// The values of all the lines with `prefix`, e.g. for the messages listed by `AT+CMGL`
+    pub fn values<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.lines.iter().filter_map(move |line| {
+            line.strip_prefix(prefix)
+                .and_then(|rest| rest.strip_prefix(':'))
+                .map(str::trim)
+        })
+    }
+}
+
+/// The configuration of an `AtClient`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AtConfiguration {
+    /// The timeout of the commands sent with `command`
+    pub timeout: Duration,
+    /// The maximum length of a line received
+    pub max_line_len: usize,
+}
+
+impl AtConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            max_line_len: 256,
+        }
+    }
+}
+
+impl Default for AtConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    Text(String),
+    /// The `> ` prompt for the data of a command
+    Prompt,
+    Overflow,
+}
+
+/// Splits the bytes received into lines
+struct LineReader {
+    line: Vec<u8>,
+    max_len: usize,
+    overflow: bool,
+    lines: VecDeque<Line>,
+}
+
+impl LineReader {
+    fn new(max_len: usize) -> Self {
+        Self {
+            line: Vec::new(),
+            max_len,
+            overflow: false,
+            lines: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        for byte in data {
+            match byte {
+                b'\n' => {
+                    let line = core::mem::take(&mut self.line);
+
+                    if core::mem::take(&mut self.overflow) {
+                        self.lines.push_back(Line::Overflow);
+                    } else if let Ok(line) = core::str::from_utf8(&line) {
+                        let line = line.trim();
+
+                        if !line.is_empty() {
+                            self.lines.push_back(Line::Text(line.to_string()));
+                        }
+                    }
+                }
+                b'\r' => (),
+                // The prompt is not terminated by a line ending
+                b'>' if self.line.is_empty() && !self.overflow => {
+                    self.lines.push_back(Line::Prompt)
+                }
+                b' ' if self.line.is_empty() => (),
+                _ if self.line.len() >= self.max_len => self.overflow = true,
+                _ => self.line.push(*byte),
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Line> {
+        self.lines.pop_front()
+    }
+}
+
+struct Urc {
+    prefix: String,
+    callback: Box<dyn FnMut(&str) + Send + 'static>,
+}
+
+/// An AT command engine, over an asynchronous serial port
+pub struct AtClient<T> {
+    port: T,
+    timer: EspAsyncTimer,
+    reader: LineReader,
+    urcs: Vec<Urc>,
+    timeout: Duration,
+}
+
+impl<T> AtClient<T>
+where
+    T: Read + Write,
+{
+    pub fn new(
+        port: T,
+        timer_service: &EspTaskTimerService,
+        conf: &AtConfiguration,
+    ) -> Result<Self, EspError> {
+        Ok(Self {
+            port,
+            timer: timer_service.timer_async()?,
+            reader: LineReader::new(conf.max_line_len),
+            urcs: Vec::new(),
+            timeout: conf.timeout,
+        })
+    }
+
+    /// Call `callback` with the unsolicited result codes which start with `prefix`, e.g. `+CREG`
+    /// or `RING`, replacing the previous callback of the prefix if any
+    pub fn subscribe<F>(&mut self, prefix: &str, callback: F)
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        self.unsubscribe(prefix);
+
+        self.urcs.push(Urc {
+            prefix: prefix.to_string(),
+            callback: Box::new(callback),
+        });
+    }
+
+    pub fn unsubscribe(&mut self, prefix: &str) {
+        self.urcs.retain(|urc| urc.prefix != prefix);
+    }
+
+    /// Send a command, e.g. `AT+CSQ`, and wait for its final result code, up to the timeout of
+    /// the configuration
+    pub async fn command(&mut self, command: &str) -> Result<AtResponse, AtError<T::Error>> {
+        self.command_with_timeout(command, self.timeout).await
+    }
+
+    /// Send a command, and wait for its final result code up to `timeout`, e.g. for the network
+    /// operations which take seconds
+    pub async fn command_with_timeout(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<AtResponse, AtError<T::Error>> {
+        self.command_with_final_codes(command, &[], timeout).await
+    }
+
+    /// Send a command, and wait up to `timeout` for its final result code, which can also be a
+    /// line starting with one of `final_codes`, e.g. for the commands whose success is not
+    /// reported with `OK`
+    ///
+    /// The final line is part of the response, unless it is `OK`.
+    pub async fn command_with_final_codes(
+        &mut self,
+        command: &str,
+        final_codes: &[&str],
+        timeout: Duration,
+    ) -> Result<AtResponse, AtError<T::Error>> {
+        let deadline = now() + timeout;
+
+        self.send(command).await?;
+
+        self.response(command, final_codes, deadline).await
+    }
+
+    /// Send a command, wait for its `> ` prompt, send `data` - including its terminator if any,
+    /// e.g. Ctrl-Z for `AT+CMGS` - and wait for the final result code, all within `timeout`
+    pub async fn command_with_data(
+        &mut self,
+        command: &str,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<AtResponse, AtError<T::Error>> {
+        let deadline = now() + timeout;
+
+        self.send(command).await?;
+
+        loop {
+            match self.line(deadline).await? {
+                Some(Line::Prompt) => break,
+                Some(Line::Text(line)) => {
+                    if let Some(result) = final_result(&line, &[]) {
+                        result?;
+
+                        // Answered without a prompt
+                        return Ok(AtResponse::default());
+                    }
+
+                    self.dispatch(&line);
+                }
+                Some(Line::Overflow) => return Err(AtError::Overflow),
+                None => return Err(AtError::Timeout),
+            }
+        }
+
+        self.port.write_all(data).await.map_err(AtError::Io)?;
+        self.port.flush().await.map_err(AtError::Io)?;
+
+        self.response(command, &[], deadline).await
+    }
+
+    /// Receive and dispatch the unsolicited result codes for up to `duration`
+    pub async fn process(&mut self, duration: Duration) -> Result<(), AtError<T::Error>> {
+        let deadline = now() + duration;
+
+        while let Some(line) = self.line(deadline).await? {
+            match line {
+                Line::Text(line) => {
+                    if !self.dispatch(&line) {
+                        debug!("Ignoring {line}");
+                    }
+                }
+                Line::Prompt => (),
+                Line::Overflow => warn!("Ignoring a line too long"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send `AT` until the modem answers, up to `attempts` times, e.g. after its power-up
+    pub async fn sync(&mut self, attempts: usize) -> Result<(), AtError<T::Error>> {
+        for attempt in 1..=attempts {
+            match self.command("AT").await {
+                Ok(_) => return Ok(()),
+                Err(AtError::Timeout) | Err(AtError::Overflow) | Err(AtError::Error(_)) => {
+                    debug!("No answer to AT, attempt {attempt}/{attempts}");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(AtError::Timeout)
+    }
+
+    pub fn port(&mut self) -> &mut T {
+        &mut self.port
+    }
+
+    pub fn release(self) -> T {
+        self.port
+    }
+
+    async fn send(&mut self, command: &str) -> Result<(), AtError<T::Error>> {
+        // Dispatch what was received before, so as not to take it for the response
+        while let Some(line) = self.reader.pop() {
+            if let Line::Text(line) = line {
+                if !self.dispatch(&line) {
+                    debug!("Ignoring {line}");
+                }
+            }
+        }
+
+        debug!("Sending {command}");
+
+        self.port
+            .write_all(command.as_bytes())
+            .await
+            .map_err(AtError::Io)?;
+        self.port.write_all(b"\r").await.map_err(AtError::Io)?;
+        self.port.flush().await.map_err(AtError::Io)
+    }
+
+    async fn response(
+        &mut self,
+        command: &str,
+        final_codes: &[&str],
+        deadline: Duration,
+    ) -> Result<AtResponse, AtError<T::Error>> {
+        // The prefix of the information lines of the command, e.g. `+CREG` for `AT+CREG?`
+        let prefix = command
+            .get(2..)
+            .unwrap_or("")
+            .split(['=', '?'])
+            .next()
+            .unwrap_or("");
+
+        let mut response = AtResponse::default();
+
+        loop {
+            let line = match self.line(deadline).await? {
+                Some(Line::Text(line)) => line,
+                Some(Line::Prompt) => continue,
+                Some(Line::Overflow) => return Err(AtError::Overflow),
+                None => return Err(AtError::Timeout),
+            };
+
+            if line == command {
+                // The echo
+                continue;
+            }
+
+            if let Some(result) = final_result(&line, final_codes) {
+                if result.is_ok() && line != "OK" {
+                    // E.g. `CONNECT 115200`
+                    response.lines.push(line);
+                }
+
+                return result.map(|_| response);
+            }
+
+            if prefix.len() > 1 && line.starts_with(prefix) {
+                response.lines.push(line);
+            } else if !self.dispatch(&line) {
+                response.lines.push(line);
+            }
+        }
+    }
+
+    /// The next line, or `None` once past the deadline
+    async fn line(&mut self, deadline: Duration) -> Result<Option<Line>, AtError<T::Error>> {
+        let mut buf = [0; 64];
+
+        loop {
+            if let Some(line) = self.reader.pop() {
+                return Ok(Some(line));
+            }
+
+            let Some(remaining) = deadline.checked_sub(now()).filter(|rem| !rem.is_zero()) else {
+                return Ok(None);
+            };
+
+            let result = {
+                let read = pin!(self.port.read(&mut buf));
+                let timeout = pin!(self.timer.after(remaining));
+
+                select(read, timeout).await
+            };
+
+            match result {
+                Either::First(result) => {
+                    let len = result.map_err(AtError::Io)?;
+                    self.reader.push(&buf[..len]);
+                }
+                Either::Second(result) => {
+                    result?;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Dispatch a line to the callback of its URC prefix; return whether it was one
+    fn dispatch(&mut self, line: &str) -> bool {
+        match self
+            .urcs
+            .iter_mut()
+            .find(|urc| line.starts_with(urc.prefix.as_str()))
+        {
+            Some(urc) => {
+                (urc.callback)(line);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<T> Debug for AtClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtClient")
+            .field("timeout", &self.timeout)
+            .field(
+                "urcs",
+                &self.urcs.iter().map(|urc| &urc.prefix).collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+/// The result of a final result code, or `None` if `line` is not one
+fn final_result<E>(line: &str, final_codes: &[&str]) -> Option<Result<(), AtError<E>>> {
+    let code = |rest: &str| rest.trim().parse().unwrap_or(0);
+
+    if line == "OK"
+        || line.starts_with("CONNECT")
+        || final_codes.iter().any(|code| line.starts_with(code))
+    {
+        Some(Ok(()))
+    } else if let Some(rest) = line.strip_prefix("+CME ERROR:") {
+        Some(Err(AtError::CmeError(code(rest))))
+    } else if let Some(rest) = line.strip_prefix("+CMS ERROR:") {
+        Some(Err(AtError::CmsError(code(rest))))
+    } else if matches!(
+        line,
+        "ERROR" | "NO CARRIER" | "NO DIALTONE" | "BUSY" | "NO ANSWER"
+    ) {
+        Some(Err(AtError::Error(line.to_string())))
+    } else {
+        None
+    }
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
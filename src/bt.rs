@@ -1,5 +1,24 @@
+//! Bluetooth
+//!
+//! # Host stacks
+//!
+//! The host stack is selected in the sdkconfig - not with a cargo feature, as the bindings of
+//! `esp-idf-sys` are generated for the stack enabled there:
+//! - `CONFIG_BT_BLUEDROID_ENABLED=y` for Bluedroid, the default, supporting BLE and Bluetooth
+//!   Classic;
+//! - `CONFIG_BT_NIMBLE_ENABLED=y` for NimBLE, supporting BLE only, with a much smaller RAM and
+//!   flash footprint.
+//!
+//! The support of NimBLE is limited to the host initialization: `BtDriver` initializes the
+//! controller, starts the NimBLE host task and waits for the host to sync with the controller,
+//! and `BtDriver::set_device_name` sets the GAP device name. Besides, `ble::l2cap` provides LE
+//! connection-oriented channels, and `ble::mesh` works with either stack. The other wrappers -
+//! `ble::gap`, `ble::gatt`, `beacon` and the Bluetooth Classic modules - are Bluedroid-only; with
+//! NimBLE, GAP and GATT are used through the `ble_gap_*` and `ble_gatts_*` / `ble_gattc_*` APIs
+//! of `esp_idf_svc::sys`, once the driver is created.
+
 use core::cell::UnsafeCell;
-use core::fmt::{self, Debug};
+use core::fmt;
 use core::marker::PhantomData;
 use core::sync::atomic::{AtomicBool, Ordering};
 
@@ -8,6 +27,7 @@ use alloc::sync::Arc;
 
 use log::info;
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 use num_enum::TryFromPrimitive;
 
 use crate::hal::modem::BluetoothModemPeripheral;
@@ -26,18 +46,24 @@ extern crate alloc;
 pub mod a2dp;
 #[cfg(all(esp32, esp_idf_bt_classic_enabled, esp_idf_bt_a2dp_enable))]
 pub mod avrc;
+#[cfg(esp_idf_bt_bluedroid_enabled)]
+pub mod beacon;
 pub mod ble;
 #[cfg(all(esp32, esp_idf_bt_classic_enabled))]
 pub mod gap;
 #[cfg(all(esp32, esp_idf_bt_classic_enabled, esp_idf_bt_hfp_enable))]
 pub mod hfp;
+#[cfg(all(esp32, esp_idf_bt_classic_enabled, esp_idf_bt_l2cap_enabled))]
+pub mod l2cap;
+#[cfg(all(esp32, esp_idf_bt_classic_enabled, esp_idf_bt_spp_enabled))]
+pub mod spp;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(transparent)]
-pub struct BdAddr(esp_bd_addr_t);
+pub struct BdAddr([u8; 6]);
 
 impl BdAddr {
-    pub const fn raw(&self) -> esp_bd_addr_t {
+    pub const fn raw(&self) -> [u8; 6] {
         self.0
     }
 
@@ -60,22 +86,24 @@ impl fmt::Display for BdAddr {
     }
 }
 
-impl From<BdAddr> for esp_bd_addr_t {
+impl From<BdAddr> for [u8; 6] {
     fn from(value: BdAddr) -> Self {
         value.0
     }
 }
 
-impl From<esp_bd_addr_t> for BdAddr {
-    fn from(value: esp_bd_addr_t) -> Self {
+impl From<[u8; 6]> for BdAddr {
+    fn from(value: [u8; 6]) -> Self {
         Self(value)
     }
 }
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 #[derive(Clone)]
 #[repr(transparent)]
 pub struct BtUuid(esp_bt_uuid_t);
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 impl BtUuid {
     pub const fn raw(&self) -> esp_bt_uuid_t {
         self.0
@@ -124,26 +152,31 @@ impl BtUuid {
     }
 }
 
-impl Debug for BtUuid {
+#[cfg(esp_idf_bt_bluedroid_enabled)]
+impl fmt::Debug for BtUuid {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "BtUuid {{{:?}}}", self.as_bytes())
     }
 }
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 impl PartialEq for BtUuid {
     fn eq(&self, other: &BtUuid) -> bool {
         self.as_bytes() == other.as_bytes()
     }
 }
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 impl Eq for BtUuid {}
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 impl From<BtUuid> for esp_bt_uuid_t {
     fn from(uuid: BtUuid) -> Self {
         uuid.0
     }
 }
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 impl From<esp_bt_uuid_t> for BtUuid {
     fn from(uuid: esp_bt_uuid_t) -> Self {
         Self(uuid)
@@ -292,6 +325,7 @@ impl BtMode for BtDual {
     }
 }
 
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u32)]
 pub enum BtStatus {
@@ -385,6 +419,9 @@ pub enum BtStatus {
     HciMacConnectionFailed = esp_bt_status_t_ESP_BT_STATUS_HCI_MAC_CONNECTION_FAILED,
 }
 
+#[cfg(esp_idf_bt_nimble_enabled)]
+const NIMBLE_SYNC_TIMEOUT_MS: u32 = 5000;
+
 static MEM_FREED: mutex::Mutex<bool> = mutex::Mutex::new(false);
 
 pub fn reduce_bt_memory<'d, B: BluetoothModemPeripheral>(
@@ -422,6 +459,10 @@ pub fn free_bt_memory<B: BluetoothModemPeripheral>(_modem: B) -> Result<(), EspE
     Ok(())
 }
 
+/// The Bluetooth controller and host stack
+///
+/// The host stack is either Bluedroid or NimBLE, as selected in the sdkconfig; see the
+/// documentation of the module for what is supported with each.
 pub struct BtDriver<'d, M>
 where
     M: BtMode,
@@ -462,6 +503,7 @@ where
         })
     }
 
+    #[cfg(esp_idf_bt_bluedroid_enabled)]
     #[allow(clippy::needless_update)]
     fn init(_nvs_enabled: bool) -> Result<(), EspError> {
         #[cfg(esp32)]
@@ -702,17 +744,72 @@ where
         Ok(())
     }
 
+    /// With NimBLE, the controller is initialized by the NimBLE port, with the default
+    /// controller configuration of the sdkconfig
+    #[cfg(esp_idf_bt_nimble_enabled)]
+    fn init(_nvs_enabled: bool) -> Result<(), EspError> {
+        #[cfg(esp_idf_version_major = "4")]
+        {
+            info!("Init bluetooth controller and NimBLE HCI");
+            esp!(unsafe { esp_nimble_hci_and_controller_init() })?;
+
+            info!("Init NimBLE");
+            unsafe { nimble_port_init() };
+        }
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        {
+            info!("Init bluetooth controller and NimBLE");
+            esp!(unsafe { nimble_port_init() })?;
+        }
+
+        info!("Start NimBLE host task");
+        unsafe { nimble_port_freertos_init(Some(Self::nimble_host_task)) };
+
+        // Wait for the host and the controller to sync
+        for _ in 0..NIMBLE_SYNC_TIMEOUT_MS / 10 {
+            if unsafe { ble_hs_synced() } != 0 {
+                return Ok(());
+            }
+
+            crate::hal::delay::FreeRtos::delay_ms(10);
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    #[cfg(esp_idf_bt_nimble_enabled)]
+    unsafe extern "C" fn nimble_host_task(_arg: *mut core::ffi::c_void) {
+        // Returns once `nimble_port_stop` is called
+        nimble_port_run();
+
+        nimble_port_freertos_deinit();
+    }
+
+    #[cfg(esp_idf_bt_bluedroid_enabled)]
     pub fn set_device_name(&self, device_name: &str) -> Result<(), EspError> {
         let device_name = to_cstring_arg(device_name)?;
 
         esp!(unsafe { esp_bt_dev_set_device_name(device_name.as_ptr()) })
     }
+
+    #[cfg(esp_idf_bt_nimble_enabled)]
+    pub fn set_device_name(&self, device_name: &str) -> Result<(), EspError> {
+        let device_name = to_cstring_arg(device_name)?;
+
+        if unsafe { ble_svc_gap_device_name_set(device_name.as_ptr()) } != 0 {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl<'d, M> Drop for BtDriver<'d, M>
 where
     M: BtMode,
 {
+    #[cfg(esp_idf_bt_bluedroid_enabled)]
     fn drop(&mut self) {
         let _ = esp!(unsafe { esp_bluedroid_disable() });
 
@@ -722,6 +819,22 @@ where
 
         esp!(unsafe { esp_bt_controller_deinit() }).unwrap();
     }
+
+    #[cfg(esp_idf_bt_nimble_enabled)]
+    fn drop(&mut self) {
+        let _ = unsafe { nimble_port_stop() };
+
+        #[cfg(esp_idf_version_major = "4")]
+        {
+            unsafe { nimble_port_deinit() };
+
+            esp!(unsafe { esp_nimble_hci_and_controller_deinit() }).unwrap();
+        }
+
+        // Also de-initializes the controller
+        #[cfg(not(esp_idf_version_major = "4"))]
+        esp!(unsafe { nimble_port_deinit() }).unwrap();
+    }
 }
 
 unsafe impl<'d, M> Send for BtDriver<'d, M> where M: BtMode {}
@@ -97,6 +97,25 @@ impl Codec {
             None
         }
     }
+
+    /// Return the sample rate and number of channels of the PCM data exchanged with
+    /// `A2dpEvent::SinkData` and `A2dpEvent::SourceData`, which is always 16 bits per sample
+    pub fn pcm_format(&self) -> Option<PcmFormat> {
+        Some(PcmFormat {
+            sample_rate_hz: self.bitrate()?,
+            channels: if self.stereo()? { 2 } else { 1 },
+        })
+    }
+}
+
+/// The format of the decoded PCM data
+///
+/// The samples are signed 16 bits, little endian, and interleaved when stereo - i.e. ready to be
+/// written to an I2S driver configured with the same sample rate.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PcmFormat {
+    pub sample_rate_hz: u32,
+    pub channels: u8,
 }
 
 impl Debug for Codec {
@@ -200,7 +219,9 @@ pub enum A2dpEvent<'a> {
     SinkDelay(u16),
     #[cfg(not(esp_idf_version_major = "4"))]
     SourceDelay(u16),
+    /// Decoded PCM data received by the sink, in the `PcmFormat` of the configured codec
     SinkData(&'a [u8]),
+    /// PCM data requested by the source; the subscriber fills the buffer and returns the number of bytes written
     SourceData(&'a mut [u8]),
     Other {
         raw_event: esp_a2d_cb_event_t,
@@ -390,6 +411,18 @@ where
         esp!(unsafe { esp_a2d_source_disconnect(bd_addr as *const _ as *mut _) })
     }
 
+    /// Control the media stream of the source
+    ///
+    /// Once started with `MediaControlCommand::Start`, the PCM data to send is requested with
+    /// `A2dpEvent::SourceData`, whose buffer should be filled with 44.1kHz 16 bits stereo samples;
+    /// the subscriber returns the number of bytes written.
+    pub fn media_control(&self, command: MediaControlCommand) -> Result<(), EspError>
+    where
+        S: SourceEnabled,
+    {
+        esp!(unsafe { esp_a2d_media_ctrl(command as _) })
+    }
+
     pub fn subscribe<F>(&self, events_cb: F) -> Result<(), EspError>
     where
         F: FnMut(A2dpEvent) -> usize + Send + 'static,
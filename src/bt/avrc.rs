@@ -581,3 +581,303 @@ pub mod controller {
 
     static SINGLETON: BtSingleton<AvrccEvent, ()> = BtSingleton::new(());
 }
+
+pub mod target {
+    use core::borrow::Borrow;
+    use core::convert::{TryFrom, TryInto};
+    use core::fmt::{self, Debug};
+    use core::marker::PhantomData;
+
+    use enumset::EnumSet;
+
+    use log::info;
+
+    use crate::bt::{BdAddr, BtClassicEnabled, BtDriver, BtSingleton};
+
+    use super::*;
+
+    pub struct EventRawData<'a>(pub &'a esp_avrc_tg_cb_param_t);
+
+    impl<'a> Debug for EventRawData<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("RawData").finish()
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum AvrctEvent<'a> {
+        Connected(BdAddr),
+        Disconnected(BdAddr),
+        RemoteFeatures {
+            bd_addr: BdAddr,
+            features: EnumSet<Feature>,
+            controller_features: u16,
+        },
+        /// A transport control (play, pause, next track...) sent by the controller
+        Passthrough {
+            key_code: KeyCode,
+            key_pressed: bool,
+        },
+        /// The controller sets the absolute volume, in the range 0..=127
+        SetVolume(u8),
+        /// The controller registers for a notification, to be answered with `EspAvrct::send_notification`
+        RegisterNotification {
+            notification: NotificationType,
+            parameter: u32,
+        },
+        Other {
+            raw_event: esp_avrc_tg_cb_event_t,
+            raw_data: EventRawData<'a>,
+        },
+    }
+
+    #[allow(non_upper_case_globals)]
+    impl<'a> From<(esp_avrc_tg_cb_event_t, &'a esp_avrc_tg_cb_param_t)> for AvrctEvent<'a> {
+        fn from(value: (esp_avrc_tg_cb_event_t, &'a esp_avrc_tg_cb_param_t)) -> Self {
+            let (event, param) = value;
+
+            unsafe {
+                match event {
+                    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_CONNECTION_STATE_EVT => {
+                        if param.conn_stat.connected {
+                            Self::Connected(param.conn_stat.remote_bda.into())
+                        } else {
+                            Self::Disconnected(param.conn_stat.remote_bda.into())
+                        }
+                    }
+                    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_REMOTE_FEATURES_EVT => {
+                        Self::RemoteFeatures {
+                            bd_addr: param.rmt_feats.remote_bda.into(),
+                            features: EnumSet::from_repr_truncated(
+                                param.rmt_feats.feat_mask as u16,
+                            ),
+                            controller_features: param.rmt_feats.ct_feat_flag,
+                        }
+                    }
+                    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_PASSTHROUGH_CMD_EVT => {
+                        match param.psth_cmd.key_code.try_into() {
+                            Ok(key_code) => Self::Passthrough {
+                                key_code,
+                                key_pressed: param.psth_cmd.key_state == 0,
+                            },
+                            Err(_) => Self::Other {
+                                raw_event: event,
+                                raw_data: EventRawData(param),
+                            },
+                        }
+                    }
+                    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_SET_ABSOLUTE_VOLUME_CMD_EVT => {
+                        Self::SetVolume(param.set_abs_vol.volume)
+                    }
+                    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_REGISTER_NOTIFICATION_EVT => {
+                        match NotificationType::try_from(param.reg_ntf.event_id) {
+                            Ok(notification) => Self::RegisterNotification {
+                                notification,
+                                parameter: param.reg_ntf.event_parameter,
+                            },
+                            Err(_) => Self::Other {
+                                raw_event: event,
+                                raw_data: EventRawData(param),
+                            },
+                        }
+                    }
+                    _ => Self::Other {
+                        raw_event: event,
+                        raw_data: EventRawData(param),
+                    },
+                }
+            }
+        }
+    }
+
+    /// The AVRCP target role, e.g. for a speaker reporting and applying the volume set by a phone
+    pub struct EspAvrct<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>>,
+    {
+        _driver: T,
+        _p: PhantomData<&'d ()>,
+        _m: PhantomData<M>,
+    }
+
+    impl<'d, M, T> EspAvrct<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>>,
+    {
+        pub fn new(driver: T) -> Result<Self, EspError> {
+            SINGLETON.take()?;
+
+            esp!(unsafe { esp_avrc_tg_register_callback(Some(Self::event_handler)) })?;
+            esp!(unsafe { esp_avrc_tg_init() })?;
+
+            Ok(Self {
+                _driver: driver,
+                _p: PhantomData,
+                _m: PhantomData,
+            })
+        }
+
+        pub fn subscribe<F>(&self, events_cb: F) -> Result<(), EspError>
+        where
+            F: FnMut(AvrctEvent) + Send + 'static,
+        {
+            SINGLETON.subscribe(events_cb);
+
+            Ok(())
+        }
+
+        /// # Safety
+        ///
+        /// This method - in contrast to method `subscribe` - allows the user to pass
+        /// a non-static callback/closure. This enables users to borrow
+        /// - in the closure - variables that live on the stack - or more generally - in the same
+        ///   scope where the service is created.
+        ///
+        /// HOWEVER: care should be taken NOT to call `core::mem::forget()` on the service,
+        /// as that would immediately lead to an UB (crash).
+        /// Also note that forgetting the service might happen with `Rc` and `Arc`
+        /// when circular references are introduced: https://github.com/rust-lang/rust/issues/24456
+        ///
+        /// The reason is that the closure is actually sent to a hidden ESP IDF thread.
+        /// This means that if the service is forgotten, Rust is free to e.g. unwind the stack
+        /// and the closure now owned by this other thread will end up with references to variables that no longer exist.
+        ///
+        /// The destructor of the service takes care - prior to the service being dropped and e.g.
+        /// the stack being unwind - to remove the closure from the hidden thread and destroy it.
+        /// Unfortunately, when the service is forgotten, the un-subscription does not happen
+        /// and invalid references are left dangling.
+        ///
+        /// This "local borrowing" will only be possible to express in a safe way once/if `!Leak` types
+        /// are introduced to Rust (i.e. the impossibility to "forget" a type and thus not call its destructor).
+        pub unsafe fn subscribe_nonstatic<F>(&self, events_cb: F) -> Result<(), EspError>
+        where
+            F: FnMut(AvrctEvent) + Send + 'd,
+        {
+            SINGLETON.subscribe(events_cb);
+
+            Ok(())
+        }
+
+        pub fn unsubscribe(&self) -> Result<(), EspError> {
+            SINGLETON.unsubscribe();
+
+            Ok(())
+        }
+
+        /// Set the notifications the controller is allowed to register for
+        pub fn set_notification_capabilities(
+            &self,
+            notifications: EnumSet<NotificationType>,
+        ) -> Result<(), EspError> {
+            let caps = esp_avrc_rn_evt_cap_mask_t {
+                bits: notifications.as_repr(),
+            };
+
+            esp!(unsafe { esp_avrc_tg_set_rn_evt_cap(&caps) })
+        }
+
+        /// Answer a registered notification
+        ///
+        /// The first response to a `AvrctEvent::RegisterNotification` should be an interim one
+        /// (`changed` set to `false`), followed by a changed one once the value changes.
+        pub fn send_notification(
+            &self,
+            notification: Notification,
+            changed: bool,
+        ) -> Result<(), EspError> {
+            let mut param: esp_avrc_rn_param_t = unsafe { core::mem::zeroed() };
+
+            let notification_type = match notification {
+                Notification::Volume(volume) => {
+                    param.volume = volume;
+                    NotificationType::Volume
+                }
+                Notification::Playback(status) => {
+                    param.playback = status as _;
+                    NotificationType::Playback
+                }
+                Notification::PlaybackPosition(position) => {
+                    param.play_pos = position;
+                    NotificationType::PlaybackPosition
+                }
+                Notification::Battery(status) => {
+                    param.batt = status as _;
+                    NotificationType::BatteryStatus
+                }
+                Notification::TrackChanged => {
+                    // No track selected
+                    param.elm_id = [0xff; 8];
+                    NotificationType::TrackChanged
+                }
+                Notification::TrackStarted => NotificationType::TrackStart,
+                Notification::TrackEnded => NotificationType::TrackEnd,
+                Notification::SystemStatus => NotificationType::SystemStatus,
+                Notification::AppSettings => NotificationType::AppSettings,
+                Notification::NowPlaying => NotificationType::NowPlaying,
+                Notification::AvailablePlayers => NotificationType::AvailablePlayers,
+                Notification::AddressedPlayer => NotificationType::AddressedPlayer,
+                Notification::Uuids => NotificationType::Uuids,
+                Notification::Other(other) => other,
+            };
+
+            esp!(unsafe {
+                esp_avrc_tg_send_rn_rsp(
+                    notification_type as _,
+                    if changed {
+                        esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_CHANGED
+                    } else {
+                        esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_INTERIM
+                    },
+                    &mut param,
+                )
+            })
+        }
+
+        unsafe extern "C" fn event_handler(
+            event: esp_avrc_tg_cb_event_t,
+            param: *mut esp_avrc_tg_cb_param_t,
+        ) {
+            if let Some(param) = unsafe { param.as_ref() } {
+                let event = AvrctEvent::from((event, param));
+
+                info!("Got event {{ {:#?} }}", event);
+
+                SINGLETON.call(event);
+            }
+        }
+    }
+
+    impl<'d, M, T> Drop for EspAvrct<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>>,
+    {
+        fn drop(&mut self) {
+            self.unsubscribe().unwrap();
+
+            esp!(unsafe { esp_avrc_tg_deinit() }).unwrap();
+
+            SINGLETON.release().unwrap();
+        }
+    }
+
+    unsafe impl<'d, M, T> Send for EspAvrct<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>> + Send,
+    {
+    }
+
+    // Safe because the ESP IDF Bluedroid APIs all do message passing
+    // to a dedicated Bluedroid task
+    unsafe impl<'d, M, T> Sync for EspAvrct<'d, M, T>
+    where
+        M: BtClassicEnabled,
+        T: Borrow<BtDriver<'d, M>> + Send,
+    {
+    }
+
+    static SINGLETON: BtSingleton<AvrctEvent, ()> = BtSingleton::new(());
+}
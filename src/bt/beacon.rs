@@ -0,0 +1,356 @@
+//! iBeacon and Eddystone beacons
+//!
+//! `Beacon` encodes iBeacon and Eddystone (UID, URL and TLM) frames into advertising payloads, and
+//! conversely parses them from the payloads of scanned devices:
+//!
+//! ```ignore
+//! if let Some(beacon) = Beacon::from_scan_result(&result) {
+//!     info!("{beacon:?} at {} dBm", result.rssi);
+//! }
+//! ```
+//!
+//! `EspBeacon` advertises a beacon with non-connectable advertising. With
+//! `EspBeacon::start_with_tlm`, an Eddystone UID or URL beacon is periodically interleaved with
+//! TLM frames, carrying the battery voltage and the temperature read from a user callback, along
+//! with the advertising count and the uptime. Bluedroid does not report the advertising events,
+//! so the advertising count is an estimate, from the time advertised and the advertising
+//! interval.
+
+use core::borrow::Borrow;
+use core::marker::PhantomData;
+use core::time::Duration;
+
+use alloc::vec::Vec;
+
+use crate::bt::ble::gap::adv::{AdvData, AdvFlag, AdvParams, AdvType};
+use crate::bt::ble::gap::scan::{
+    AdvFields, BleScanResult, EDDYSTONE_URL_EXPANSIONS, EDDYSTONE_URL_SCHEMES, EDDYSTONE_UUID,
+    IBEACON_COMPANY_ID,
+};
+use crate::bt::ble::gap::EspBleGap;
+use crate::bt::{BleEnabled, BtDriver, BtUuid};
+use crate::sys::*;
+
+pub use crate::bt::ble::gap::scan::{Eddystone, IBeacon};
+
+/// The maximum length of an encoded Eddystone URL, after the scheme prefix
+pub const EDDYSTONE_URL_MAX_LEN: usize = 17;
+
+impl IBeacon {
+    /// Return the advertising payload of the iBeacon
+    pub fn adv_data(&self) -> AdvData {
+        let mut data = Vec::with_capacity(23);
+        data.extend_from_slice(&[0x02, 0x15]);
+        data.extend_from_slice(&self.uuid.to_be_bytes());
+        data.extend_from_slice(&self.major.to_be_bytes());
+        data.extend_from_slice(&self.minor.to_be_bytes());
+        data.push(self.measured_power as u8);
+
+        AdvData::new()
+            .flags(AdvFlag::GeneralDiscoverable | AdvFlag::BrEdrNotSupported)
+            .manufacturer_data(IBEACON_COMPANY_ID, &data)
+    }
+}
+
+impl Eddystone {
+    /// Return the service data of the Eddystone frame
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the URL of a URL frame cannot be encoded, or
+    /// `ESP_ERR_INVALID_SIZE` if it is too long.
+    pub fn frame(&self) -> Result<Vec<u8>, EspError> {
+        let mut frame = Vec::with_capacity(20);
+
+        match self {
+            Self::Uid {
+                tx_power,
+                namespace,
+                instance,
+            } => {
+                frame.extend_from_slice(&[0x00, *tx_power as u8]);
+                frame.extend_from_slice(namespace);
+                frame.extend_from_slice(instance);
+                // Reserved
+                frame.extend_from_slice(&[0x00, 0x00]);
+            }
+            Self::Url { tx_power, url } => {
+                frame.extend_from_slice(&[0x10, *tx_power as u8]);
+                encode_url(url, &mut frame)?;
+            }
+            Self::Tlm {
+                battery_mv,
+                temperature,
+                adv_count,
+                uptime,
+            } => {
+                // Signed 8.8 fixed point, 0x8000 if not supported
+                let temperature = temperature
+                    .map(|temperature| (temperature * 256.0) as i16)
+                    .unwrap_or(i16::MIN);
+                let uptime = (uptime.as_millis() / 100).min(u32::MAX as _) as u32;
+
+                frame.extend_from_slice(&[0x20, 0x00]);
+                frame.extend_from_slice(&battery_mv.to_be_bytes());
+                frame.extend_from_slice(&temperature.to_be_bytes());
+                frame.extend_from_slice(&adv_count.to_be_bytes());
+                frame.extend_from_slice(&uptime.to_be_bytes());
+            }
+        }
+
+        Ok(frame)
+    }
+
+    /// Return the advertising payload of the Eddystone frame
+    pub fn adv_data(&self) -> Result<AdvData, EspError> {
+        let uuid = BtUuid::uuid16(EDDYSTONE_UUID);
+
+        Ok(AdvData::new()
+            .flags(AdvFlag::GeneralDiscoverable | AdvFlag::BrEdrNotSupported)
+            .service_uuids(&[uuid.clone()], true)
+            .service_data(&uuid, &self.frame()?))
+    }
+}
+
+fn encode_url(url: &str, frame: &mut Vec<u8>) -> Result<(), EspError> {
+    let (scheme, mut rest) = EDDYSTONE_URL_SCHEMES
+        .iter()
+        .enumerate()
+        .find_map(|(index, scheme)| url.strip_prefix(scheme).map(|rest| (index, rest)))
+        .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+    frame.push(scheme as u8);
+
+    let start = frame.len();
+
+    while let Some(byte) = rest.as_bytes().first() {
+        // The expansions with a trailing slash come first, so that they take precedence
+        if let Some((index, expansion)) = EDDYSTONE_URL_EXPANSIONS
+            .iter()
+            .enumerate()
+            .find(|(_, expansion)| rest.starts_with(*expansion))
+        {
+            frame.push(index as u8);
+            rest = &rest[expansion.len()..];
+        } else if (0x21..0x7f).contains(byte) {
+            frame.push(*byte);
+            rest = &rest[1..];
+        } else {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+    }
+
+    if frame.len() - start > EDDYSTONE_URL_MAX_LEN {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+    }
+
+    Ok(())
+}
+
+/// An iBeacon or Eddystone beacon
+#[derive(Clone, Debug, PartialEq)]
+pub enum Beacon {
+    IBeacon(IBeacon),
+    Eddystone(Eddystone),
+}
+
+impl Beacon {
+    /// Parse an advertising payload, returning the beacon it contains, if any
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        Self::from_fields(&AdvFields::parse(data))
+    }
+
+    /// Return the beacon advertised by a scanned device, if any
+    pub fn from_scan_result(result: &BleScanResult) -> Option<Self> {
+        Self::from_fields(&result.fields())
+    }
+
+    fn from_fields(fields: &AdvFields) -> Option<Self> {
+        fields
+            .ibeacon()
+            .map(Self::IBeacon)
+            .or_else(|| fields.eddystone().map(Self::Eddystone))
+    }
+
+    /// Return the advertising payload of the beacon
+    pub fn adv_data(&self) -> Result<AdvData, EspError> {
+        match self {
+            Self::IBeacon(ibeacon) => Ok(ibeacon.adv_data()),
+            Self::Eddystone(eddystone) => eddystone.adv_data(),
+        }
+    }
+}
+
+impl From<IBeacon> for Beacon {
+    fn from(ibeacon: IBeacon) -> Self {
+        Self::IBeacon(ibeacon)
+    }
+}
+
+impl From<Eddystone> for Beacon {
+    fn from(eddystone: Eddystone) -> Self {
+        Self::Eddystone(eddystone)
+    }
+}
+
+/// The sensor readings of an Eddystone TLM frame
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TlmReading {
+    /// The battery voltage in mV, or 0 if not supported
+    pub battery_mv: u16,
+    /// The temperature in degrees Celsius, if supported
+    pub temperature: Option<f32>,
+}
+
+/// How the TLM frames are interleaved with the UID / URL frames
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TlmConfiguration {
+    /// The period at which a TLM frame is advertised
+    pub period: Duration,
+    /// How long the TLM frame is advertised, before switching back to the UID / URL frame;
+    /// shorter than the period
+    pub duration: Duration,
+}
+
+impl Default for TlmConfiguration {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_secs(10),
+            duration: Duration::from_secs(1),
+        }
+    }
+}
+
+/// An advertised beacon
+///
+/// Advertising is stopped when dropped.
+pub struct EspBeacon<'a> {
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    timer: Option<crate::timer::EspTimer<'static>>,
+    _gap: PhantomData<&'a ()>,
+}
+
+impl<'a> EspBeacon<'a> {
+    /// Start advertising the beacon with the provided advertising interval
+    pub fn start<'d, M, T>(
+        gap: &'a EspBleGap<'d, M, T>,
+        beacon: &Beacon,
+        interval: Duration,
+    ) -> Result<Self, EspError>
+    where
+        M: BleEnabled,
+        T: Borrow<BtDriver<'d, M>>,
+    {
+        gap.set_adv_data(&beacon.adv_data()?)?;
+        gap.start_advertising_with(&Self::adv_params(interval))?;
+
+        Ok(Self {
+            #[cfg(esp_idf_comp_esp_timer_enabled)]
+            timer: None,
+            _gap: PhantomData,
+        })
+    }
+
+    /// Start advertising an Eddystone UID or URL beacon with the provided advertising interval,
+    /// interleaved with TLM frames
+    ///
+    /// The readings of each TLM frame are returned by `reading`, which is called from the
+    /// timer service task. The advertising count of the frames is estimated as the time since the
+    /// start divided by `interval`; it is thus slightly higher than the actual count, as the
+    /// controller adds a random delay of up to 10 ms to each advertising event.
+    #[cfg(esp_idf_comp_esp_timer_enabled)]
+    pub fn start_with_tlm<'d, M, T, F>(
+        gap: &'a EspBleGap<'d, M, T>,
+        beacon: &Eddystone,
+        interval: Duration,
+        conf: &TlmConfiguration,
+        mut reading: F,
+    ) -> Result<Self, EspError>
+    where
+        M: BleEnabled,
+        T: Borrow<BtDriver<'d, M>>,
+        F: FnMut() -> TlmReading + Send + 'static,
+    {
+        if matches!(beacon, Eddystone::Tlm { .. })
+            || conf.duration.is_zero()
+            || conf.period <= conf.duration
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let adv_data = beacon.adv_data()?;
+        let primary = adv_data.build()?.to_vec();
+
+        gap.set_adv_data(&adv_data)?;
+        gap.start_advertising_with(&Self::adv_params(interval))?;
+
+        let timer_service = crate::timer::EspTaskTimerService::new()?;
+        let started = timer_service.now();
+        let now = || Duration::from_micros(unsafe { esp_timer_get_time() } as _);
+        let slots = (conf.period.as_millis() / conf.duration.as_millis()).max(1) as u32;
+        let mut slot = 0;
+
+        let timer = timer_service.timer(move || {
+            slot = (slot + 1) % slots;
+
+            let data = match slot {
+                0 => {
+                    let reading = reading();
+                    let uptime = now();
+
+                    // The advertising events since the beacon started, as an estimate of the
+                    // PDUs sent on one of the channels
+                    let adv_count = ((uptime - started).as_millis() / interval.as_millis().max(1))
+                        .min(u32::MAX as _) as u32;
+
+                    let tlm = Eddystone::Tlm {
+                        battery_mv: reading.battery_mv,
+                        temperature: reading.temperature,
+                        adv_count,
+                        uptime,
+                    };
+
+                    match tlm.adv_data() {
+                        Ok(data) => data.build().map(|data| data.to_vec()).ok(),
+                        Err(_) => None,
+                    }
+                }
+                1 => Some(primary.clone()),
+                _ => None,
+            };
+
+            if let Some(data) = data {
+                unsafe {
+                    esp_ble_gap_config_adv_data_raw(data.as_ptr() as *mut _, data.len() as _);
+                }
+            }
+        })?;
+
+        timer.every(conf.duration)?;
+
+        Ok(Self {
+            timer: Some(timer),
+            _gap: PhantomData,
+        })
+    }
+
+    fn adv_params(interval: Duration) -> AdvParams {
+        let interval_ms = interval.as_millis() as u32;
+
+        AdvParams {
+            interval_min_ms: interval_ms,
+            interval_max_ms: interval_ms,
+            adv_type: AdvType::NonConnInd,
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for EspBeacon<'_> {
+    fn drop(&mut self) {
+        #[cfg(esp_idf_comp_esp_timer_enabled)]
+        if let Some(timer) = self.timer.take() {
+            timer.cancel().unwrap();
+        }
+
+        esp!(unsafe { esp_ble_gap_stop_advertising() }).unwrap();
+    }
+}
@@ -1,2 +1,12 @@
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 pub mod gap;
+#[cfg(esp_idf_bt_bluedroid_enabled)]
 pub mod gatt;
+#[cfg(esp_idf_bt_nimble_enabled)]
+pub mod l2cap;
+#[cfg(all(
+    esp_idf_ble_mesh,
+    esp_idf_ble_mesh_node,
+    esp_idf_ble_mesh_generic_server
+))]
+pub mod mesh;
@@ -3,16 +3,25 @@ use core::fmt::{self, Debug};
 use core::marker::PhantomData;
 use core::{ffi::CStr, ops::BitOr};
 
+use alloc::vec::Vec;
+
 use crate::bt::BtSingleton;
 use crate::sys::*;
 
 use log::debug;
 
+use num_enum::TryFromPrimitive;
+
 use crate::{
     bt::{BdAddr, BleEnabled, BtDriver, BtStatus, BtUuid},
     private::cstr::to_cstring_arg,
 };
 
+pub mod adv;
+pub mod conn;
+pub mod scan;
+pub mod security;
+
 #[derive(Default, Copy, Clone, Eq, PartialEq)]
 #[repr(u8)]
 pub enum IOCapabilities {
@@ -91,6 +100,16 @@ pub enum BleEncryption {
     EncryptionMitm = 0x03,
 }
 
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u32)]
+pub enum BleAddrType {
+    #[default]
+    Public = esp_ble_addr_type_t_BLE_ADDR_TYPE_PUBLIC,
+    Random = esp_ble_addr_type_t_BLE_ADDR_TYPE_RANDOM,
+    RpaPublic = esp_ble_addr_type_t_BLE_ADDR_TYPE_RPA_PUBLIC,
+    RpaRandom = esp_ble_addr_type_t_BLE_ADDR_TYPE_RPA_RANDOM,
+}
+
 #[derive(Default, Clone)]
 pub struct SecurityConfiguration {
     pub auth_req_mode: AuthenticationRequest,
@@ -255,22 +274,26 @@ pub enum BleGapEvent<'a> {
     },
     // TODO: Parameters
     Key,
-    // TODO: Parameters
-    SecurityRequest,
+    SecurityRequest {
+        addr: BdAddr,
+    },
     PasskeyNotification {
         addr: BdAddr,
         passkey: u32,
     },
-    // TODO: Parameters
-    PasskeyRequest,
+    PasskeyRequest {
+        addr: BdAddr,
+    },
     OOBRequest {
         oob_c: &'a [u8],
         oob_r: &'a [u8],
     },
     LocalIR,
     LocalER,
-    // TODO: Parameters
-    NumericComparisonRequest,
+    NumericComparisonRequest {
+        addr: BdAddr,
+        passkey: u32,
+    },
     AdvertisingStopped(BtStatus),
     ScanStopped(BtStatus),
     StaticRandomAddressConfigured(BtStatus),
@@ -311,8 +334,14 @@ pub enum BleGapEvent<'a> {
     ),
     ChannelsConfigured(BtStatus),
     // BLE 5.0
-    // TODO
-    ReadFeaturesConfigured(esp_ble_gap_cb_param_t_ble_read_phy_cmpl_evt_param),
+    #[cfg(esp_idf_bt_ble_50_features_supported)]
+    PhyRead {
+        addr: BdAddr,
+        status: BtStatus,
+        /// `None` if the stack reported no valid PHY, e.g. as the operation failed
+        tx_phy: Option<conn::Phy>,
+        rx_phy: Option<conn::Phy>,
+    },
     PreferredDefaultPhyConfigured(BtStatus),
     PreferredPhyConfigured(BtStatus),
     ExtendedAdvertisingRandomAddressConfigured(BtStatus),
@@ -337,9 +366,16 @@ pub enum BleGapEvent<'a> {
     ExtendedAdvertisingScanStarted(BtStatus),
     ExtendedAdvertisingScanStopped(BtStatus),
     ExtendedAdvertisingExtendedConnectionParamsConfigured(BtStatus),
+    #[cfg(esp_idf_bt_ble_50_features_supported)]
+    PhyUpdated {
+        addr: BdAddr,
+        status: BtStatus,
+        /// `None` if the stack reported no valid PHY, e.g. as the operation failed
+        tx_phy: Option<conn::Phy>,
+        rx_phy: Option<conn::Phy>,
+    },
     /*
     #if (BLE_50_FEATURE_SUPPORT == TRUE)
-        PHY_UPDATE_COMPLETE_EVT,
         EXT_ADV_REPORT_EVT,
         SCAN_TIMEOUT_EVT,
         ADV_TERMINATED_EVT,
@@ -403,19 +439,26 @@ impl<'a> From<(esp_gap_ble_cb_event_t, &'a esp_ble_gap_cb_param_t)> for BleGapEv
                     },
                 },
                 esp_gap_ble_cb_event_t_ESP_GAP_BLE_KEY_EVT => Self::Key,
-                esp_gap_ble_cb_event_t_ESP_GAP_BLE_SEC_REQ_EVT => Self::SecurityRequest,
+                esp_gap_ble_cb_event_t_ESP_GAP_BLE_SEC_REQ_EVT => Self::SecurityRequest {
+                    addr: param.ble_security.ble_req.bd_addr.into(),
+                },
                 esp_gap_ble_cb_event_t_ESP_GAP_BLE_PASSKEY_NOTIF_EVT => Self::PasskeyNotification {
                     addr: param.ble_security.key_notif.bd_addr.into(),
                     passkey: param.ble_security.key_notif.passkey,
                 },
-                esp_gap_ble_cb_event_t_ESP_GAP_BLE_PASSKEY_REQ_EVT => Self::PasskeyRequest,
+                esp_gap_ble_cb_event_t_ESP_GAP_BLE_PASSKEY_REQ_EVT => Self::PasskeyRequest {
+                    addr: param.ble_security.ble_req.bd_addr.into(),
+                },
                 esp_gap_ble_cb_event_t_ESP_GAP_BLE_OOB_REQ_EVT => Self::OOBRequest {
                     oob_c: &param.ble_security.oob_data.oob_c,
                     oob_r: &param.ble_security.oob_data.oob_r,
                 },
                 esp_gap_ble_cb_event_t_ESP_GAP_BLE_LOCAL_IR_EVT => Self::LocalIR,
                 esp_gap_ble_cb_event_t_ESP_GAP_BLE_LOCAL_ER_EVT => Self::LocalER,
-                esp_gap_ble_cb_event_t_ESP_GAP_BLE_NC_REQ_EVT => Self::NumericComparisonRequest,
+                esp_gap_ble_cb_event_t_ESP_GAP_BLE_NC_REQ_EVT => Self::NumericComparisonRequest {
+                    addr: param.ble_security.key_notif.bd_addr.into(),
+                    passkey: param.ble_security.key_notif.passkey,
+                },
                 esp_gap_ble_cb_event_t_ESP_GAP_BLE_ADV_STOP_COMPLETE_EVT => {
                     Self::AdvertisingStopped(param.adv_stop_cmpl.status.try_into().unwrap())
                 }
@@ -493,6 +536,30 @@ impl<'a> From<(esp_gap_ble_cb_event_t, &'a esp_ble_gap_cb_param_t)> for BleGapEv
                 esp_gap_ble_cb_event_t_ESP_GAP_BLE_SET_CHANNELS_EVT => {
                     Self::ChannelsConfigured(param.ble_set_channels.stat.try_into().unwrap())
                 }
+                #[cfg(esp_idf_bt_ble_50_features_supported)]
+                esp_gap_ble_cb_event_t_ESP_GAP_BLE_READ_PHY_COMPLETE_EVT => Self::PhyRead {
+                    addr: param.read_phy.bda.into(),
+                    status: param.read_phy.status.try_into().unwrap(),
+                    tx_phy: param.read_phy.tx_phy.try_into().ok(),
+                    rx_phy: param.read_phy.rx_phy.try_into().ok(),
+                },
+                #[cfg(esp_idf_bt_ble_50_features_supported)]
+                esp_gap_ble_cb_event_t_ESP_GAP_BLE_SET_PREFERRED_DEFAULT_PHY_COMPLETE_EVT => {
+                    Self::PreferredDefaultPhyConfigured(
+                        param.set_perf_def_phy.status.try_into().unwrap(),
+                    )
+                }
+                #[cfg(esp_idf_bt_ble_50_features_supported)]
+                esp_gap_ble_cb_event_t_ESP_GAP_BLE_SET_PREFERRED_PHY_COMPLETE_EVT => {
+                    Self::PreferredPhyConfigured(param.set_perf_phy.status.try_into().unwrap())
+                }
+                #[cfg(esp_idf_bt_ble_50_features_supported)]
+                esp_gap_ble_cb_event_t_ESP_GAP_BLE_PHY_UPDATE_COMPLETE_EVT => Self::PhyUpdated {
+                    addr: param.phy_update.bda.into(),
+                    status: param.phy_update.status.try_into().unwrap(),
+                    tx_phy: param.phy_update.tx_phy.try_into().ok(),
+                    rx_phy: param.phy_update.rx_phy.try_into().ok(),
+                },
                 _ => Self::Other {
                     raw_event: event,
                     raw_data: EventRawData(param),
@@ -597,6 +664,10 @@ where
             })
         }
 
+        set(
+            esp_ble_sm_param_t_ESP_BLE_SM_AUTHEN_REQ_MODE,
+            &conf.auth_req_mode,
+        )?;
         set(
             esp_ble_sm_param_t_ESP_BLE_SM_IOCAP_MODE,
             &conf.io_capabilities,
@@ -656,6 +727,69 @@ where
         esp!(unsafe { esp_ble_set_encryption(&addr.0 as *const _ as *mut _, encryption as u32) })
     }
 
+    /// Accept or reject the security request of a peer
+    pub fn reply_security_request(&self, addr: &BdAddr, accept: bool) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gap_security_rsp(addr as *const _ as *mut _, accept) })
+    }
+
+    /// Reply to a `BleGapEvent::PasskeyRequest` with the passkey entered by the user,
+    /// or reject the pairing with `None`
+    pub fn reply_passkey(&self, addr: &BdAddr, passkey: Option<u32>) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_ble_passkey_reply(
+                addr as *const _ as *mut _,
+                passkey.is_some(),
+                passkey.unwrap_or(0),
+            )
+        })
+    }
+
+    /// Reply to a `BleGapEvent::NumericComparisonRequest`
+    pub fn reply_confirm(&self, addr: &BdAddr, confirm: bool) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_confirm_reply(addr as *const _ as *mut _, confirm) })
+    }
+
+    /// Return the number of bonded devices
+    ///
+    /// Bonds are persisted in NVS by Bluedroid, provided that the `BtDriver` is created
+    /// with an NVS partition.
+    pub fn get_bond_device_count(&self) -> usize {
+        (unsafe { esp_ble_get_bond_device_num() }).max(0) as _
+    }
+
+    /// Return the addresses of the bonded devices
+    pub fn get_bond_devices(&self) -> Result<Vec<BdAddr>, EspError> {
+        let mut dev_num = self.get_bond_device_count() as core::ffi::c_int;
+
+        if dev_num == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut devices: Vec<esp_ble_bond_dev_t> = Vec::with_capacity(dev_num as _);
+
+        esp!(unsafe { esp_ble_get_bond_device_list(&mut dev_num, devices.as_mut_ptr()) })?;
+
+        unsafe {
+            devices.set_len((dev_num as usize).min(devices.capacity()));
+        }
+
+        Ok(devices.iter().map(|device| device.bd_addr.into()).collect())
+    }
+
+    /// Remove the bond of a device, from RAM and NVS
+    pub fn remove_bond_device(&self, addr: &BdAddr) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_remove_bond_device(addr as *const _ as *mut _) })
+    }
+
+    /// Remove the bonds of all devices
+    pub fn clear_bond_devices(&self) -> Result<(), EspError> {
+        for addr in self.get_bond_devices()? {
+            self.remove_bond_device(&addr)?;
+        }
+
+        Ok(())
+    }
+
     pub fn start_advertising(&self) -> Result<(), EspError> {
         let mut adv_param: esp_ble_adv_params_t = esp_ble_adv_params_t {
             // TODO
@@ -0,0 +1,582 @@
+//! Typed BLE advertising payloads and advertising parameters
+//!
+//! `AdvData` builds the raw advertising data or scan response payload out of typed
+//! AD structures (flags, names, service UUIDs, service and manufacturer data, TX power, appearance)
+//! and validates that the encoded payload fits the legacy 31-byte limit, or the extended
+//! advertising limit when built with `AdvData::extended`.
+//!
+//! On chips with BLE 5.0 support (`CONFIG_BT_BLE_50_FEATURES_SUPPORTED`), `EspBleGap` additionally
+//! supports extended and periodic advertising sets.
+
+use core::borrow::Borrow;
+
+use alloc::vec::Vec;
+
+use enumset::{EnumSet, EnumSetType};
+
+use crate::bt::{BdAddr, BleEnabled, BtDriver, BtUuid};
+use crate::sys::*;
+
+use super::{AppearanceCategory, BleAddrType, EspBleGap};
+
+/// The maximum length of a legacy advertising or scan response payload
+pub const ADV_DATA_MAX_LEN: usize = 31;
+
+/// The maximum length of an extended advertising or scan response payload
+pub const EXT_ADV_DATA_MAX_LEN: usize = 1650;
+
+pub(crate) const AD_TYPE_FLAGS: u8 = 0x01;
+pub(crate) const AD_TYPE_UUID16_INCOMPLETE: u8 = 0x02;
+pub(crate) const AD_TYPE_UUID16_COMPLETE: u8 = 0x03;
+pub(crate) const AD_TYPE_UUID32_INCOMPLETE: u8 = 0x04;
+pub(crate) const AD_TYPE_UUID32_COMPLETE: u8 = 0x05;
+pub(crate) const AD_TYPE_UUID128_INCOMPLETE: u8 = 0x06;
+pub(crate) const AD_TYPE_UUID128_COMPLETE: u8 = 0x07;
+pub(crate) const AD_TYPE_NAME_SHORT: u8 = 0x08;
+pub(crate) const AD_TYPE_NAME_COMPLETE: u8 = 0x09;
+pub(crate) const AD_TYPE_TX_POWER: u8 = 0x0a;
+pub(crate) const AD_TYPE_SERVICE_DATA_UUID16: u8 = 0x16;
+pub(crate) const AD_TYPE_APPEARANCE: u8 = 0x19;
+pub(crate) const AD_TYPE_SERVICE_DATA_UUID32: u8 = 0x20;
+pub(crate) const AD_TYPE_SERVICE_DATA_UUID128: u8 = 0x21;
+pub(crate) const AD_TYPE_MANUFACTURER_DATA: u8 = 0xff;
+
+/// The flags of the Flags AD structure
+#[derive(Debug, EnumSetType)]
+#[enumset(repr = "u8")]
+pub enum AdvFlag {
+    LimitedDiscoverable = 0,
+    GeneralDiscoverable = 1,
+    BrEdrNotSupported = 2,
+    SimultaneousLeBrEdrController = 3,
+    SimultaneousLeBrEdrHost = 4,
+}
+
+/// A builder of an advertising or scan response payload
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AdvData {
+    data: Vec<u8>,
+    max_len: usize,
+    overflow: bool,
+}
+
+impl AdvData {
+    /// Create an empty legacy payload, of at most `ADV_DATA_MAX_LEN` bytes
+    pub const fn new() -> Self {
+        Self::with_max_len(ADV_DATA_MAX_LEN)
+    }
+
+    /// Create an empty extended advertising payload, of at most `EXT_ADV_DATA_MAX_LEN` bytes
+    pub const fn extended() -> Self {
+        Self::with_max_len(EXT_ADV_DATA_MAX_LEN)
+    }
+
+    pub const fn with_max_len(max_len: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            max_len,
+            overflow: false,
+        }
+    }
+
+    pub fn flags(self, flags: EnumSet<AdvFlag>) -> Self {
+        self.raw(AD_TYPE_FLAGS, &[flags.as_repr()])
+    }
+
+    /// Add the complete local name
+    pub fn name(self, name: &str) -> Self {
+        self.raw(AD_TYPE_NAME_COMPLETE, name.as_bytes())
+    }
+
+    /// Add the local name, shortened to at most `max_len` bytes if necessary
+    ///
+    /// The name is truncated at a character boundary, and it is marked as shortened if truncated.
+    pub fn short_name(self, name: &str, max_len: usize) -> Self {
+        if name.len() <= max_len {
+            return self.name(name);
+        }
+
+        let mut len = max_len;
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.raw(AD_TYPE_NAME_SHORT, &name.as_bytes()[..len])
+    }
+
+    /// Add the list of service UUIDs, grouped in AD structures by UUID length
+    ///
+    /// `complete` indicates whether the list contains all services of the device.
+    pub fn service_uuids(mut self, uuids: &[BtUuid], complete: bool) -> Self {
+        for (len, ad_type) in [
+            (2, AD_TYPE_UUID16_INCOMPLETE),
+            (4, AD_TYPE_UUID32_INCOMPLETE),
+            (16, AD_TYPE_UUID128_INCOMPLETE),
+        ] {
+            let ad_type = if complete { ad_type + 1 } else { ad_type };
+
+            let mut value = Vec::new();
+
+            for uuid in uuids.iter().filter(|uuid| uuid.as_bytes().len() == len) {
+                value.extend_from_slice(uuid.as_bytes());
+            }
+
+            if !value.is_empty() {
+                self = self.raw(ad_type, &value);
+            }
+        }
+
+        self
+    }
+
+    /// Add service data for the service with the provided UUID
+    pub fn service_data(self, uuid: &BtUuid, data: &[u8]) -> Self {
+        let ad_type = match uuid.as_bytes().len() {
+            2 => AD_TYPE_SERVICE_DATA_UUID16,
+            4 => AD_TYPE_SERVICE_DATA_UUID32,
+            _ => AD_TYPE_SERVICE_DATA_UUID128,
+        };
+
+        let mut value = Vec::with_capacity(uuid.as_bytes().len() + data.len());
+        value.extend_from_slice(uuid.as_bytes());
+        value.extend_from_slice(data);
+
+        self.raw(ad_type, &value)
+    }
+
+    /// Add manufacturer specific data, prefixed with the Bluetooth SIG company identifier
+    pub fn manufacturer_data(self, company_id: u16, data: &[u8]) -> Self {
+        let mut value = Vec::with_capacity(2 + data.len());
+        value.extend_from_slice(&company_id.to_le_bytes());
+        value.extend_from_slice(data);
+
+        self.raw(AD_TYPE_MANUFACTURER_DATA, &value)
+    }
+
+    /// Add the TX power level, in dBm
+    pub fn tx_power(self, dbm: i8) -> Self {
+        self.raw(AD_TYPE_TX_POWER, &[dbm as u8])
+    }
+
+    pub fn appearance(self, appearance: AppearanceCategory) -> Self {
+        self.raw(
+            AD_TYPE_APPEARANCE,
+            &(i32::from(appearance) as u16).to_le_bytes(),
+        )
+    }
+
+    /// Add an AD structure of an arbitrary type
+    pub fn raw(mut self, ad_type: u8, value: &[u8]) -> Self {
+        if value.len() > u8::MAX as usize - 1 || self.data.len() + 2 + value.len() > self.max_len {
+            self.overflow = true;
+        } else {
+            self.data.push(value.len() as u8 + 1);
+            self.data.push(ad_type);
+            self.data.extend_from_slice(value);
+        }
+
+        self
+    }
+
+    /// Return the length of the encoded payload
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Return the encoded payload, or `ESP_ERR_INVALID_SIZE` if any of the
+    /// AD structures did not fit
+    pub fn build(&self) -> Result<&[u8], EspError> {
+        if self.overflow {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())
+        } else {
+            Ok(&self.data)
+        }
+    }
+}
+
+impl Default for AdvData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum AdvType {
+    /// Connectable and scannable undirected advertising
+    #[default]
+    Ind = esp_ble_adv_type_t_ADV_TYPE_IND,
+    /// Connectable directed advertising, high duty cycle
+    DirectIndHigh = esp_ble_adv_type_t_ADV_TYPE_DIRECT_IND_HIGH,
+    /// Scannable undirected advertising
+    ScanInd = esp_ble_adv_type_t_ADV_TYPE_SCAN_IND,
+    /// Non-connectable and non-scannable undirected advertising
+    NonConnInd = esp_ble_adv_type_t_ADV_TYPE_NONCONN_IND,
+    /// Connectable directed advertising, low duty cycle
+    DirectIndLow = esp_ble_adv_type_t_ADV_TYPE_DIRECT_IND_LOW,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum AdvFilterPolicy {
+    /// Allow scan and connection requests from anyone
+    #[default]
+    ScanAnyConnAny = esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_ANY_CON_ANY,
+    /// Allow scan requests from the whitelist only, connection requests from anyone
+    ScanWhitelistConnAny = esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_WLST_CON_ANY,
+    /// Allow scan requests from anyone, connection requests from the whitelist only
+    ScanAnyConnWhitelist = esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_ANY_CON_WLST,
+    /// Allow scan and connection requests from the whitelist only
+    ScanWhitelistConnWhitelist = esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_WLST_CON_WLST,
+}
+
+#[derive(Debug, EnumSetType)]
+#[enumset(repr = "u8")]
+pub enum AdvChannel {
+    Channel37 = 0,
+    Channel38 = 1,
+    Channel39 = 2,
+}
+
+/// The parameters of legacy advertising
+#[derive(Clone, Debug)]
+pub struct AdvParams {
+    pub interval_min_ms: u32,
+    pub interval_max_ms: u32,
+    pub adv_type: AdvType,
+    pub own_addr_type: BleAddrType,
+    /// The peer to advertise to, for directed advertising
+    pub peer_addr: Option<(BdAddr, BleAddrType)>,
+    pub channels: EnumSet<AdvChannel>,
+    pub filter_policy: AdvFilterPolicy,
+}
+
+impl Default for AdvParams {
+    fn default() -> Self {
+        Self {
+            interval_min_ms: 20,
+            interval_max_ms: 40,
+            adv_type: AdvType::Ind,
+            own_addr_type: BleAddrType::Public,
+            peer_addr: None,
+            channels: EnumSet::all(),
+            filter_policy: AdvFilterPolicy::ScanAnyConnAny,
+        }
+    }
+}
+
+impl From<&AdvParams> for esp_ble_adv_params_t {
+    fn from(params: &AdvParams) -> Self {
+        let (peer_addr, peer_addr_type) = params
+            .peer_addr
+            .map(|(addr, addr_type)| (addr.raw(), addr_type))
+            .unwrap_or(([0; 6], BleAddrType::Public));
+
+        Self {
+            adv_int_min: adv_interval(params.interval_min_ms),
+            adv_int_max: adv_interval(params.interval_max_ms),
+            adv_type: params.adv_type as _,
+            own_addr_type: params.own_addr_type as _,
+            peer_addr,
+            peer_addr_type: peer_addr_type as _,
+            channel_map: params.channels.as_repr() as _,
+            adv_filter_policy: params.filter_policy as _,
+        }
+    }
+}
+
+// Advertising intervals are expressed in units of 0.625ms
+fn adv_interval(ms: u32) -> u16 {
+    (ms * 1000 / 625).clamp(0x20, 0x4000) as _
+}
+
+impl<'d, M, T> EspBleGap<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    /// Set the advertising payload
+    pub fn set_adv_data(&self, data: &AdvData) -> Result<(), EspError> {
+        self.set_raw_adv_conf(data.build()?)
+    }
+
+    /// Set the scan response payload
+    pub fn set_scan_rsp_data(&self, data: &AdvData) -> Result<(), EspError> {
+        self.set_raw_scan_rsp_conf(data.build()?)
+    }
+
+    /// Start legacy advertising with the provided parameters
+    pub fn start_advertising_with(&self, params: &AdvParams) -> Result<(), EspError> {
+        let mut params: esp_ble_adv_params_t = params.into();
+
+        esp!(unsafe { esp_ble_gap_start_advertising(&mut params) })
+    }
+}
+
+#[cfg(esp_idf_bt_ble_50_features_supported)]
+pub use ext::*;
+
+#[cfg(esp_idf_bt_ble_50_features_supported)]
+mod ext {
+    use core::borrow::Borrow;
+    use core::time::Duration;
+
+    use enumset::{EnumSet, EnumSetType};
+
+    use crate::bt::{BdAddr, BleEnabled, BtDriver};
+    use crate::sys::*;
+
+    use super::super::{BleAddrType, EspBleGap};
+    use super::{AdvChannel, AdvData, AdvFilterPolicy};
+
+    /// The properties of an extended advertising set
+    #[derive(Debug, EnumSetType)]
+    #[enumset(repr = "u16")]
+    pub enum ExtAdvProperty {
+        Connectable = 0,
+        Scannable = 1,
+        Directed = 2,
+        HighDutyDirected = 3,
+        /// Use legacy advertising PDUs
+        Legacy = 4,
+        AnonymousAdvertising = 5,
+        IncludeTxPower = 6,
+    }
+
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    #[repr(u8)]
+    pub enum PrimaryPhy {
+        #[default]
+        Phy1M = ESP_BLE_GAP_PRI_PHY_1M as _,
+        Coded = ESP_BLE_GAP_PRI_PHY_CODED as _,
+    }
+
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    #[repr(u8)]
+    pub enum SecondaryPhy {
+        #[default]
+        Phy1M = ESP_BLE_GAP_PHY_1M as _,
+        Phy2M = ESP_BLE_GAP_PHY_2M as _,
+        Coded = ESP_BLE_GAP_PHY_CODED as _,
+    }
+
+    /// The parameters of an extended advertising set
+    #[derive(Clone, Debug)]
+    pub struct ExtAdvParams {
+        pub properties: EnumSet<ExtAdvProperty>,
+        pub interval_min_ms: u32,
+        pub interval_max_ms: u32,
+        pub channels: EnumSet<AdvChannel>,
+        pub own_addr_type: BleAddrType,
+        /// The peer to advertise to, for directed advertising
+        pub peer_addr: Option<(BdAddr, BleAddrType)>,
+        pub filter_policy: AdvFilterPolicy,
+        /// The requested TX power in dBm, or `None` to let the controller choose
+        pub tx_power: Option<i8>,
+        pub primary_phy: PrimaryPhy,
+        pub secondary_phy: SecondaryPhy,
+        /// The advertising set ID, sent in the advertising PDUs
+        pub sid: u8,
+        /// Report the scan requests with `ESP_GAP_BLE_SCAN_REQ_RECEIVED_EVT`
+        pub scan_req_notif: bool,
+    }
+
+    impl Default for ExtAdvParams {
+        fn default() -> Self {
+            Self {
+                properties: ExtAdvProperty::Connectable | ExtAdvProperty::Scannable,
+                interval_min_ms: 100,
+                interval_max_ms: 150,
+                channels: EnumSet::all(),
+                own_addr_type: BleAddrType::Public,
+                peer_addr: None,
+                filter_policy: AdvFilterPolicy::ScanAnyConnAny,
+                tx_power: None,
+                primary_phy: PrimaryPhy::Phy1M,
+                secondary_phy: SecondaryPhy::Phy1M,
+                sid: 0,
+                scan_req_notif: false,
+            }
+        }
+    }
+
+    impl From<&ExtAdvParams> for esp_ble_gap_ext_adv_params_t {
+        fn from(params: &ExtAdvParams) -> Self {
+            let (peer_addr, peer_addr_type) = params
+                .peer_addr
+                .map(|(addr, addr_type)| (addr.raw(), addr_type))
+                .unwrap_or(([0; 6], BleAddrType::Public));
+
+            Self {
+                type_: params.properties.as_repr() as _,
+                interval_min: ext_adv_interval(params.interval_min_ms),
+                interval_max: ext_adv_interval(params.interval_max_ms),
+                channel_map: params.channels.as_repr() as _,
+                own_addr_type: params.own_addr_type as _,
+                peer_addr_type: peer_addr_type as _,
+                peer_addr,
+                filter_policy: params.filter_policy as _,
+                // 127 means "no preference"
+                tx_power: params.tx_power.unwrap_or(127),
+                primary_phy: params.primary_phy as _,
+                max_skip: 0,
+                secondary_phy: params.secondary_phy as _,
+                sid: params.sid,
+                scan_req_notif: params.scan_req_notif,
+            }
+        }
+    }
+
+    // Extended advertising intervals are expressed in units of 0.625ms
+    fn ext_adv_interval(ms: u32) -> u32 {
+        (ms * 1000 / 625).max(0x20)
+    }
+
+    /// The parameters of periodic advertising
+    #[derive(Clone, Debug)]
+    pub struct PeriodicAdvParams {
+        pub interval_min_ms: u32,
+        pub interval_max_ms: u32,
+        pub include_tx_power: bool,
+    }
+
+    impl Default for PeriodicAdvParams {
+        fn default() -> Self {
+            Self {
+                interval_min_ms: 100,
+                interval_max_ms: 150,
+                include_tx_power: false,
+            }
+        }
+    }
+
+    /// An advertising set to start with `EspBleGap::start_ext_advertising`
+    #[derive(Copy, Clone, Debug, Default)]
+    pub struct ExtAdvInstance {
+        pub instance: u8,
+        /// For how long to advertise (with a resolution of 10ms), or `None` to advertise until stopped
+        pub duration: Option<Duration>,
+        /// The maximum number of advertising events to send, or 0 for no limit
+        pub max_events: u8,
+    }
+
+    impl<'d, M, T> EspBleGap<'d, M, T>
+    where
+        T: Borrow<BtDriver<'d, M>>,
+        M: BleEnabled,
+    {
+        pub fn set_ext_adv_params(
+            &self,
+            instance: u8,
+            params: &ExtAdvParams,
+        ) -> Result<(), EspError> {
+            let params: esp_ble_gap_ext_adv_params_t = params.into();
+
+            esp!(unsafe { esp_ble_gap_ext_adv_set_params(instance, &params) })
+        }
+
+        pub fn set_ext_adv_rand_addr(&self, instance: u8, addr: BdAddr) -> Result<(), EspError> {
+            let mut addr = addr.raw();
+
+            esp!(unsafe { esp_ble_gap_ext_adv_set_rand_addr(instance, addr.as_mut_ptr()) })
+        }
+
+        pub fn set_ext_adv_data(&self, instance: u8, data: &AdvData) -> Result<(), EspError> {
+            let data = data.build()?;
+
+            esp!(unsafe {
+                esp_ble_gap_config_ext_adv_data_raw(instance, data.len() as _, data.as_ptr())
+            })
+        }
+
+        pub fn set_ext_scan_rsp_data(&self, instance: u8, data: &AdvData) -> Result<(), EspError> {
+            let data = data.build()?;
+
+            esp!(unsafe {
+                esp_ble_gap_config_ext_scan_rsp_data_raw(instance, data.len() as _, data.as_ptr())
+            })
+        }
+
+        pub fn start_ext_advertising(&self, instances: &[ExtAdvInstance]) -> Result<(), EspError> {
+            let instances = instances
+                .iter()
+                .map(|instance| esp_ble_gap_ext_adv_t {
+                    instance: instance.instance,
+                    duration: instance
+                        .duration
+                        .map(|duration| (duration.as_millis() / 10).clamp(1, 0xffff))
+                        .unwrap_or(0) as _,
+                    max_events: instance.max_events,
+                })
+                .collect::<alloc::vec::Vec<_>>();
+
+            esp!(unsafe { esp_ble_gap_ext_adv_start(instances.len() as _, instances.as_ptr()) })
+        }
+
+        pub fn stop_ext_advertising(&self, instances: &[u8]) -> Result<(), EspError> {
+            esp!(unsafe { esp_ble_gap_ext_adv_stop(instances.len() as _, instances.as_ptr()) })
+        }
+
+        pub fn remove_ext_adv_set(&self, instance: u8) -> Result<(), EspError> {
+            esp!(unsafe { esp_ble_gap_ext_adv_set_remove(instance) })
+        }
+
+        pub fn clear_ext_adv_sets(&self) -> Result<(), EspError> {
+            esp!(unsafe { esp_ble_gap_ext_adv_set_clear() })
+        }
+
+        pub fn set_periodic_adv_params(
+            &self,
+            instance: u8,
+            params: &PeriodicAdvParams,
+        ) -> Result<(), EspError> {
+            // Periodic advertising intervals are expressed in units of 1.25ms
+            let params = esp_ble_gap_periodic_adv_params_t {
+                interval_min: (params.interval_min_ms * 100 / 125).max(6) as _,
+                interval_max: (params.interval_max_ms * 100 / 125).max(6) as _,
+                properties: if params.include_tx_power { 1 << 6 } else { 0 },
+            };
+
+            esp!(unsafe { esp_ble_gap_periodic_adv_set_params(instance, &params) })
+        }
+
+        pub fn set_periodic_adv_data(&self, instance: u8, data: &AdvData) -> Result<(), EspError> {
+            let data = data.build()?;
+
+            #[cfg(any(
+                esp_idf_version_major = "4",
+                all(esp_idf_version_major = "5", esp_idf_version_minor = "0")
+            ))]
+            esp!(unsafe {
+                esp_ble_gap_config_periodic_adv_data_raw(instance, data.len() as _, data.as_ptr())
+            })?;
+
+            #[cfg(not(any(
+                esp_idf_version_major = "4",
+                all(esp_idf_version_major = "5", esp_idf_version_minor = "0")
+            )))]
+            esp!(unsafe {
+                esp_ble_gap_config_periodic_adv_data_raw(
+                    instance,
+                    data.len() as _,
+                    data.as_ptr(),
+                    false,
+                )
+            })?;
+
+            Ok(())
+        }
+
+        pub fn start_periodic_advertising(&self, instance: u8) -> Result<(), EspError> {
+            esp!(unsafe { esp_ble_gap_periodic_adv_start(instance) })
+        }
+
+        pub fn stop_periodic_advertising(&self, instance: u8) -> Result<(), EspError> {
+            esp!(unsafe { esp_ble_gap_periodic_adv_stop(instance) })
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! BLE connection parameters, data length and PHY
+//!
+//! These operate on established connections, identified by the address of the peer, and allow
+//! trading throughput and latency against power consumption:
+//! - `EspBleGap::update_conn_params` requests new connection parameters (interval, peripheral
+//!   latency, supervision timeout), reported with `BleGapEvent::ConnectionParamsConfigured`
+//! - `EspBleGap::set_data_length` enables the data length extension, reported with
+//!   `BleGapEvent::PacketLengthConfigured`
+//! - `EspBleGap::set_preferred_phy` selects the 2M or Coded PHY on chips with BLE 5.0 support,
+//!   reported with `BleGapEvent::PhyUpdated`
+//! - `EspBleGap::read_rssi` reads the RSSI of the connection, reported with
+//!   `BleGapEvent::ReadRssiConfigured`
+
+use core::borrow::Borrow;
+use core::time::Duration;
+
+use crate::bt::{BdAddr, BleEnabled, BtDriver};
+use crate::sys::*;
+
+use super::EspBleGap;
+
+/// The minimum and maximum LE data channel PDU payload lengths, in bytes
+pub const DATA_LENGTH_MIN: u16 = 27;
+pub const DATA_LENGTH_MAX: u16 = 251;
+
+/// The parameters of a connection
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ConnParams {
+    /// The minimum connection interval, between 7.5ms and 4s
+    pub min_interval: Duration,
+    /// The maximum connection interval, between 7.5ms and 4s
+    pub max_interval: Duration,
+    /// The number of connection events the peripheral may skip
+    pub latency: u16,
+    /// The supervision timeout, between 100ms and 32s; larger than
+    /// `(1 + latency) * max_interval * 2`
+    pub supervision_timeout: Duration,
+}
+
+impl Default for ConnParams {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_micros(7_500),
+            max_interval: Duration::from_millis(30),
+            latency: 0,
+            supervision_timeout: Duration::from_secs(4),
+        }
+    }
+}
+
+impl ConnParams {
+    // The connection intervals are expressed in units of 1.25ms
+    fn raw_interval(interval: Duration) -> u16 {
+        (interval.as_micros() / 1250).clamp(0x0006, 0x0c80) as _
+    }
+
+    // The supervision timeout is expressed in units of 10ms
+    fn raw_timeout(&self) -> u16 {
+        (self.supervision_timeout.as_millis() / 10).clamp(0x000a, 0x0c80) as _
+    }
+}
+
+impl<'d, M, T> EspBleGap<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    /// Request new parameters for the connection with the peer
+    ///
+    /// The parameters in use are reported with `BleGapEvent::ConnectionParamsConfigured`,
+    /// once accepted by the central.
+    pub fn update_conn_params(&self, addr: &BdAddr, params: &ConnParams) -> Result<(), EspError> {
+        let mut params = esp_ble_conn_update_params_t {
+            bda: addr.raw(),
+            min_int: ConnParams::raw_interval(params.min_interval),
+            max_int: ConnParams::raw_interval(params.max_interval),
+            latency: params.latency,
+            timeout: params.raw_timeout(),
+        };
+
+        esp!(unsafe { esp_ble_gap_update_conn_params(&mut params) })
+    }
+
+    /// Set the connection parameters to use when connecting to the peer as a central
+    pub fn set_preferred_conn_params(
+        &self,
+        addr: &BdAddr,
+        params: &ConnParams,
+    ) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_ble_gap_set_prefer_conn_params(
+                addr as *const _ as *mut _,
+                ConnParams::raw_interval(params.min_interval),
+                ConnParams::raw_interval(params.max_interval),
+                params.latency,
+                params.raw_timeout(),
+            )
+        })
+    }
+
+    /// Set the maximum payload length of the data channel PDUs sent to the peer, between
+    /// `DATA_LENGTH_MIN` and `DATA_LENGTH_MAX`
+    ///
+    /// The negotiated lengths are reported with `BleGapEvent::PacketLengthConfigured`.
+    pub fn set_data_length(&self, addr: &BdAddr, tx_len: u16) -> Result<(), EspError> {
+        if !(DATA_LENGTH_MIN..=DATA_LENGTH_MAX).contains(&tx_len) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        esp!(unsafe { esp_ble_gap_set_pkt_data_len(addr as *const _ as *mut _, tx_len) })
+    }
+
+    /// Read the RSSI of the connection with the peer
+    ///
+    /// The RSSI is reported with `BleGapEvent::ReadRssiConfigured`.
+    pub fn read_rssi(&self, addr: &BdAddr) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gap_read_rssi(addr as *const _ as *mut _) })
+    }
+}
+
+#[cfg(esp_idf_bt_ble_50_features_supported)]
+pub use phy::*;
+
+#[cfg(esp_idf_bt_ble_50_features_supported)]
+mod phy {
+    use core::borrow::Borrow;
+
+    use enumset::{EnumSet, EnumSetType};
+
+    use num_enum::TryFromPrimitive;
+
+    use crate::bt::{BdAddr, BleEnabled, BtDriver};
+    use crate::sys::*;
+
+    use super::super::EspBleGap;
+
+    /// The PHY in use by a connection
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, TryFromPrimitive)]
+    #[repr(u8)]
+    pub enum Phy {
+        Phy1M = ESP_BLE_GAP_PHY_1M as u8,
+        Phy2M = ESP_BLE_GAP_PHY_2M as u8,
+        Coded = ESP_BLE_GAP_PHY_CODED as u8,
+    }
+
+    /// A preferred PHY
+    #[derive(Debug, EnumSetType)]
+    #[enumset(repr = "u8")]
+    pub enum PhyPreference {
+        Phy1M = 0,
+        Phy2M = 1,
+        Coded = 2,
+    }
+
+    /// The preferred coding of the Coded PHY
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    #[repr(u16)]
+    pub enum CodedPhyOption {
+        #[default]
+        NoPreference = ESP_BLE_GAP_PHY_OPTIONS_NO_PREF as u16,
+        /// 500 kbps
+        S2 = ESP_BLE_GAP_PHY_OPTIONS_PREF_S2_CODING as u16,
+        /// 125 kbps, for the longest range
+        S8 = ESP_BLE_GAP_PHY_OPTIONS_PREF_S8_CODING as u16,
+    }
+
+    // No TX / RX preference, as per the `ALL_PHYS` parameter of the HCI LE Set PHY command
+    const ALL_PHYS_NO_TX_PREF: u8 = 0b01;
+    const ALL_PHYS_NO_RX_PREF: u8 = 0b10;
+
+    impl<'d, M, T> EspBleGap<'d, M, T>
+    where
+        T: Borrow<BtDriver<'d, M>>,
+        M: BleEnabled,
+    {
+        /// Set the PHYs preferred for all subsequent connections; an empty set means no preference
+        ///
+        /// The completion is reported with `BleGapEvent::PreferredDefaultPhyConfigured`.
+        pub fn set_preferred_default_phy(
+            &self,
+            tx: EnumSet<PhyPreference>,
+            rx: EnumSet<PhyPreference>,
+        ) -> Result<(), EspError> {
+            esp!(unsafe {
+                esp_ble_gap_set_preferred_default_phy(tx.as_repr() as _, rx.as_repr() as _)
+            })
+        }
+
+        /// Request a PHY change for the connection with the peer; an empty set means no preference
+        ///
+        /// The completion is reported with `BleGapEvent::PreferredPhyConfigured`, and the PHYs
+        /// eventually in use with `BleGapEvent::PhyUpdated`.
+        pub fn set_preferred_phy(
+            &self,
+            addr: &BdAddr,
+            tx: EnumSet<PhyPreference>,
+            rx: EnumSet<PhyPreference>,
+            coded_option: CodedPhyOption,
+        ) -> Result<(), EspError> {
+            let mut all_phys = 0;
+
+            if tx.is_empty() {
+                all_phys |= ALL_PHYS_NO_TX_PREF;
+            }
+
+            if rx.is_empty() {
+                all_phys |= ALL_PHYS_NO_RX_PREF;
+            }
+
+            esp!(unsafe {
+                esp_ble_gap_set_preferred_phy(
+                    addr as *const _ as *mut _,
+                    all_phys as _,
+                    tx.as_repr() as _,
+                    rx.as_repr() as _,
+                    coded_option as _,
+                )
+            })
+        }
+
+        /// Read the PHYs in use by the connection with the peer
+        ///
+        /// The PHYs are reported with `BleGapEvent::PhyRead`.
+        pub fn read_phy(&self, addr: &BdAddr) -> Result<(), EspError> {
+            esp!(unsafe { esp_ble_gap_read_phy(addr as *const _ as *mut _) })
+        }
+    }
+}
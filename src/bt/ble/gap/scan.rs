@@ -0,0 +1,760 @@
+//! BLE scanning
+//!
+//! `ScanParams` configures passive or active scanning, to be started with
+//! `EspBleGap::set_scan_params` and `EspBleGap::start_scanning`. The raw `BleGapEvent::ScanResult`
+//! events can be converted to a `BleScanResult`, whose advertising and scan response payloads are
+//! parsed into typed fields with `BleScanResult::fields`, including iBeacon and Eddystone frames.
+//!
+//! `EspBleScanner` builds on top of `EspBleGap` and turns the scan results into an async stream,
+//! or alternatively passes them to a callback.
+//!
+//! Note that the scanner subscribes to the events of the `EspBleGap` instance it takes ownership of,
+//! so `EspBleGap::subscribe` should not be used in conjunction with it. The GAP events unrelated to
+//! scanning are passed to the callback registered with `EspBleScanner::on_event` instead.
+
+use core::borrow::Borrow;
+use core::time::Duration;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use enumset::EnumSet;
+
+use num_enum::TryFromPrimitive;
+
+use crate::bt::{BdAddr, BleEnabled, BtDriver, BtStatus, BtUuid};
+use crate::hal::task::asynch::Notification;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::adv::*;
+use super::{BleAddrType, BleGapEvent, EspBleGap};
+
+pub(crate) const IBEACON_COMPANY_ID: u16 = 0x004c;
+pub(crate) const EDDYSTONE_UUID: u16 = 0xfeaa;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum ScanFilterPolicy {
+    /// Accept all advertising packets, except directed ones not addressed to this device
+    #[default]
+    All = esp_ble_scan_filter_t_BLE_SCAN_FILTER_ALLOW_ALL,
+    /// Accept only the advertising packets of devices in the whitelist
+    WhitelistOnly = esp_ble_scan_filter_t_BLE_SCAN_FILTER_ALLOW_ONLY_WLST,
+    /// Like `All`, but also accept directed advertising packets with a resolvable private address
+    AllAndDirectedRpa = esp_ble_scan_filter_t_BLE_SCAN_FILTER_ALLOW_UND_RPA_DIR,
+    /// Like `WhitelistOnly`, but also accept directed advertising packets with a resolvable private address
+    WhitelistAndDirectedRpa = esp_ble_scan_filter_t_BLE_SCAN_FILTER_ALLOW_WLIST_RPA_DIR,
+}
+
+/// The scan parameters
+#[derive(Clone, Debug)]
+pub struct ScanParams {
+    /// Request the scan responses of the advertisers
+    pub active: bool,
+    pub own_addr_type: BleAddrType,
+    pub filter_policy: ScanFilterPolicy,
+    pub interval_ms: u32,
+    /// The duration of a scan within each interval; should not be longer than `interval_ms`
+    pub window_ms: u32,
+    /// Report each advertiser only once per scan
+    pub filter_duplicates: bool,
+}
+
+impl Default for ScanParams {
+    fn default() -> Self {
+        Self {
+            active: true,
+            own_addr_type: BleAddrType::Public,
+            filter_policy: ScanFilterPolicy::All,
+            interval_ms: 100,
+            window_ms: 50,
+            filter_duplicates: false,
+        }
+    }
+}
+
+impl From<&ScanParams> for esp_ble_scan_params_t {
+    fn from(params: &ScanParams) -> Self {
+        Self {
+            scan_type: if params.active {
+                esp_ble_scan_type_t_BLE_SCAN_TYPE_ACTIVE
+            } else {
+                esp_ble_scan_type_t_BLE_SCAN_TYPE_PASSIVE
+            },
+            own_addr_type: params.own_addr_type as _,
+            scan_filter_policy: params.filter_policy as _,
+            scan_interval: scan_interval(params.interval_ms),
+            scan_window: scan_interval(params.window_ms.min(params.interval_ms)),
+            scan_duplicate: if params.filter_duplicates {
+                esp_ble_scan_duplicate_t_BLE_SCAN_DUPLICATE_ENABLE
+            } else {
+                esp_ble_scan_duplicate_t_BLE_SCAN_DUPLICATE_DISABLE
+            },
+        }
+    }
+}
+
+// Scan intervals and windows are expressed in units of 0.625ms
+fn scan_interval(ms: u32) -> u16 {
+    (ms * 1000 / 625).clamp(0x4, 0x4000) as _
+}
+
+/// The type of a received advertising packet
+#[derive(Copy, Clone, Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u32)]
+pub enum AdvEventType {
+    ConnectableUndirected = esp_ble_evt_type_t_ESP_BLE_EVT_CONN_ADV,
+    ConnectableDirected = esp_ble_evt_type_t_ESP_BLE_EVT_CONN_DIR_ADV,
+    Scannable = esp_ble_evt_type_t_ESP_BLE_EVT_DISC_ADV,
+    NonConnectable = esp_ble_evt_type_t_ESP_BLE_EVT_NON_CONN_ADV,
+    ScanResponse = esp_ble_evt_type_t_ESP_BLE_EVT_SCAN_RSP,
+}
+
+const SCAN_DATA_MAX_LEN: usize =
+    (ESP_BLE_ADV_DATA_LEN_MAX + ESP_BLE_SCAN_RSP_DATA_LEN_MAX) as usize;
+
+/// A device found while scanning
+#[derive(Clone, Debug)]
+pub struct BleScanResult {
+    pub addr: BdAddr,
+    pub addr_type: BleAddrType,
+    pub event_type: AdvEventType,
+    /// The received signal strength, in dBm
+    pub rssi: i8,
+    data: [u8; SCAN_DATA_MAX_LEN],
+    adv_data_len: u8,
+    scan_rsp_len: u8,
+}
+
+impl BleScanResult {
+    /// Return the raw advertising payload
+    pub fn adv_data(&self) -> &[u8] {
+        &self.data[..self.adv_data_len as usize]
+    }
+
+    /// Return the raw scan response payload, if any
+    pub fn scan_rsp_data(&self) -> &[u8] {
+        let start = self.adv_data_len as usize;
+
+        &self.data[start..start + self.scan_rsp_len as usize]
+    }
+
+    /// Parse the advertising payload, followed by the scan response payload
+    pub fn fields(&self) -> AdvFields<'_> {
+        AdvFields::parse(&self.data[..self.adv_data_len as usize + self.scan_rsp_len as usize])
+    }
+}
+
+impl From<&esp_ble_gap_cb_param_t_ble_scan_result_evt_param> for BleScanResult {
+    fn from(param: &esp_ble_gap_cb_param_t_ble_scan_result_evt_param) -> Self {
+        let adv_data_len = (param.adv_data_len as usize).min(SCAN_DATA_MAX_LEN);
+        let scan_rsp_len = (param.scan_rsp_len as usize).min(SCAN_DATA_MAX_LEN - adv_data_len);
+
+        let mut data = [0; SCAN_DATA_MAX_LEN];
+        let len = adv_data_len + scan_rsp_len;
+        data[..len].copy_from_slice(&param.ble_adv[..len]);
+
+        Self {
+            addr: param.bda.into(),
+            addr_type: param.ble_addr_type.try_into().unwrap(),
+            event_type: param.ble_evt_type.try_into().unwrap(),
+            rssi: param.rssi as _,
+            data,
+            adv_data_len: adv_data_len as _,
+            scan_rsp_len: scan_rsp_len as _,
+        }
+    }
+}
+
+/// An iterator over the `(AD type, value)` structures of an advertising payload
+///
+/// Iteration stops at the first malformed structure.
+#[derive(Clone, Debug)]
+pub struct AdStructures<'a>(&'a [u8]);
+
+impl<'a> AdStructures<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+}
+
+impl<'a> Iterator for AdStructures<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = *self.0.first()? as usize;
+
+        if len == 0 || self.0.len() < len + 1 {
+            self.0 = &[];
+            return None;
+        }
+
+        let ad_type = self.0[1];
+        let value = &self.0[2..len + 1];
+
+        self.0 = &self.0[len + 1..];
+
+        Some((ad_type, value))
+    }
+}
+
+/// The typed fields of an advertising payload
+#[derive(Clone, Debug, Default)]
+pub struct AdvFields<'a> {
+    pub flags: Option<EnumSet<AdvFlag>>,
+    /// The local name, if valid UTF-8
+    pub name: Option<&'a str>,
+    /// `false` if the local name is shortened
+    pub name_complete: bool,
+    pub service_uuids: Vec<BtUuid>,
+    pub service_data: Vec<(BtUuid, &'a [u8])>,
+    /// The company identifier and the data of the manufacturer specific data
+    pub manufacturer_data: Option<(u16, &'a [u8])>,
+    /// The TX power level, in dBm
+    pub tx_power: Option<i8>,
+    pub appearance: Option<u16>,
+}
+
+impl<'a> AdvFields<'a> {
+    /// Parse an advertising or scan response payload, ignoring any unknown or malformed AD structures
+    pub fn parse(data: &'a [u8]) -> Self {
+        let mut fields = Self::default();
+
+        for (ad_type, value) in AdStructures::new(data) {
+            match ad_type {
+                AD_TYPE_FLAGS if !value.is_empty() => {
+                    fields.flags = Some(EnumSet::from_repr_truncated(value[0]));
+                }
+                AD_TYPE_NAME_SHORT | AD_TYPE_NAME_COMPLETE => {
+                    if let Ok(name) = core::str::from_utf8(value) {
+                        fields.name = Some(name);
+                        fields.name_complete = ad_type == AD_TYPE_NAME_COMPLETE;
+                    }
+                }
+                AD_TYPE_UUID16_INCOMPLETE | AD_TYPE_UUID16_COMPLETE => {
+                    fields
+                        .service_uuids
+                        .extend(value.chunks_exact(2).filter_map(uuid_from_bytes));
+                }
+                AD_TYPE_UUID32_INCOMPLETE | AD_TYPE_UUID32_COMPLETE => {
+                    fields
+                        .service_uuids
+                        .extend(value.chunks_exact(4).filter_map(uuid_from_bytes));
+                }
+                AD_TYPE_UUID128_INCOMPLETE | AD_TYPE_UUID128_COMPLETE => {
+                    fields
+                        .service_uuids
+                        .extend(value.chunks_exact(16).filter_map(uuid_from_bytes));
+                }
+                AD_TYPE_SERVICE_DATA_UUID16
+                | AD_TYPE_SERVICE_DATA_UUID32
+                | AD_TYPE_SERVICE_DATA_UUID128 => {
+                    let uuid_len = match ad_type {
+                        AD_TYPE_SERVICE_DATA_UUID16 => 2,
+                        AD_TYPE_SERVICE_DATA_UUID32 => 4,
+                        _ => 16,
+                    };
+
+                    if value.len() >= uuid_len {
+                        if let Some(uuid) = uuid_from_bytes(&value[..uuid_len]) {
+                            fields.service_data.push((uuid, &value[uuid_len..]));
+                        }
+                    }
+                }
+                AD_TYPE_MANUFACTURER_DATA if value.len() >= 2 => {
+                    fields.manufacturer_data =
+                        Some((u16::from_le_bytes([value[0], value[1]]), &value[2..]));
+                }
+                AD_TYPE_TX_POWER if !value.is_empty() => {
+                    fields.tx_power = Some(value[0] as i8);
+                }
+                AD_TYPE_APPEARANCE if value.len() >= 2 => {
+                    fields.appearance = Some(u16::from_le_bytes([value[0], value[1]]));
+                }
+                _ => (),
+            }
+        }
+
+        fields
+    }
+
+    /// Return the data of the service with the provided UUID, if present
+    pub fn service_data_for(&self, uuid: &BtUuid) -> Option<&'a [u8]> {
+        self.service_data
+            .iter()
+            .find(|(service_uuid, _)| service_uuid == uuid)
+            .map(|(_, data)| *data)
+    }
+
+    /// Return the iBeacon frame of the payload, if it is an iBeacon advertisement
+    pub fn ibeacon(&self) -> Option<IBeacon> {
+        let (company_id, data) = self.manufacturer_data?;
+
+        if company_id != IBEACON_COMPANY_ID
+            || data.len() != 23
+            || data[0] != 0x02
+            || data[1] != 0x15
+        {
+            return None;
+        }
+
+        Some(IBeacon {
+            uuid: u128::from_be_bytes(data[2..18].try_into().unwrap()),
+            major: u16::from_be_bytes([data[18], data[19]]),
+            minor: u16::from_be_bytes([data[20], data[21]]),
+            measured_power: data[22] as i8,
+        })
+    }
+
+    /// Return the Eddystone frame of the payload, if it is an Eddystone advertisement
+    pub fn eddystone(&self) -> Option<Eddystone> {
+        Eddystone::parse(self.service_data_for(&BtUuid::uuid16(EDDYSTONE_UUID))?)
+    }
+}
+
+fn uuid_from_bytes(bytes: &[u8]) -> Option<BtUuid> {
+    match bytes.len() {
+        2 => Some(BtUuid::uuid16(u16::from_le_bytes(bytes.try_into().ok()?))),
+        4 => Some(BtUuid::uuid32(u32::from_le_bytes(bytes.try_into().ok()?))),
+        16 => Some(BtUuid::uuid128(u128::from_le_bytes(bytes.try_into().ok()?))),
+        _ => None,
+    }
+}
+
+/// An iBeacon frame
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IBeacon {
+    /// The proximity UUID
+    pub uuid: u128,
+    pub major: u16,
+    pub minor: u16,
+    /// The RSSI at 1m, in dBm
+    pub measured_power: i8,
+}
+
+/// An Eddystone frame
+#[derive(Clone, Debug, PartialEq)]
+pub enum Eddystone {
+    Uid {
+        /// The TX power at 0m, in dBm
+        tx_power: i8,
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+    Url {
+        /// The TX power at 0m, in dBm
+        tx_power: i8,
+        /// The decoded URL
+        url: String,
+    },
+    Tlm {
+        /// The battery voltage in mV, or 0 if not supported
+        battery_mv: u16,
+        /// The temperature in degrees Celsius, if supported
+        temperature: Option<f32>,
+        /// The number of advertising packets sent since boot, or since the beacon started;
+        /// `EspBeacon` advertises an estimate
+        adv_count: u32,
+        uptime: Duration,
+    },
+}
+
+pub(crate) const EDDYSTONE_URL_SCHEMES: [&str; 4] =
+    ["http://www.", "https://www.", "http://", "https://"];
+
+pub(crate) const EDDYSTONE_URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net",
+    ".info", ".biz", ".gov",
+];
+
+impl Eddystone {
+    /// Parse the service data of an Eddystone advertisement
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match *data.first()? {
+            0x00 if data.len() >= 18 => Some(Self::Uid {
+                tx_power: data[1] as i8,
+                namespace: data[2..12].try_into().unwrap(),
+                instance: data[12..18].try_into().unwrap(),
+            }),
+            0x10 if data.len() >= 3 => {
+                let mut url = String::from(*EDDYSTONE_URL_SCHEMES.get(data[2] as usize)?);
+
+                for byte in &data[3..] {
+                    if let Some(expansion) = EDDYSTONE_URL_EXPANSIONS.get(*byte as usize) {
+                        url.push_str(expansion);
+                    } else if (0x21..0x7f).contains(byte) {
+                        url.push(*byte as char);
+                    } else {
+                        return None;
+                    }
+                }
+
+                Some(Self::Url {
+                    tx_power: data[1] as i8,
+                    url,
+                })
+            }
+            // Only the unencrypted TLM frame (version 0) is supported
+            0x20 if data.len() >= 14 && data[1] == 0 => {
+                let temperature = i16::from_be_bytes([data[4], data[5]]);
+
+                Some(Self::Tlm {
+                    battery_mv: u16::from_be_bytes([data[2], data[3]]),
+                    temperature: (temperature != i16::MIN).then(|| temperature as f32 / 256.0),
+                    adv_count: u32::from_be_bytes(data[6..10].try_into().unwrap()),
+                    uptime: Duration::from_millis(
+                        u32::from_be_bytes(data[10..14].try_into().unwrap()) as u64 * 100,
+                    ),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'d, M, T> EspBleGap<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    pub fn set_scan_params(&self, params: &ScanParams) -> Result<(), EspError> {
+        let mut params: esp_ble_scan_params_t = params.into();
+
+        esp!(unsafe { esp_ble_gap_set_scan_params(&mut params) })
+    }
+
+    /// Start scanning for `duration`, or until stopped with `stop_scanning` if `None`
+    ///
+    /// The duration has a resolution of one second.
+    pub fn start_scanning(&self, duration: Option<Duration>) -> Result<(), EspError> {
+        let duration = duration
+            .map(|duration| duration.as_secs().clamp(1, u32::MAX as _) as u32)
+            .unwrap_or(0);
+
+        esp!(unsafe { esp_ble_gap_start_scanning(duration) })
+    }
+
+    pub fn stop_scanning(&self) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gap_stop_scanning() })
+    }
+}
+
+type ResultCallback = Box<dyn FnMut(&BleScanResult) + Send>;
+type EventCallback = Box<dyn FnMut(&BleGapEvent) + Send>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Pending {
+    None,
+    Params,
+    Start,
+    Stop,
+}
+
+struct State {
+    busy: bool,
+    pending: Pending,
+    outcome: Option<BtStatus>,
+    scanning: bool,
+    results: VecDeque<BleScanResult>,
+    results_capacity: usize,
+    results_dropped: usize,
+    results_busy: bool,
+}
+
+impl State {
+    fn complete(&mut self, pending: Pending, status: BtStatus) -> bool {
+        if self.pending == pending {
+            self.pending = Pending::None;
+            self.outcome = Some(status);
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct Callbacks {
+    result: Option<ResultCallback>,
+    event: Option<EventCallback>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    callbacks: Mutex<Callbacks>,
+    op_notification: Notification,
+    rx_notification: Notification,
+}
+
+/// A BLE scanner, yielding the scan results via an async stream or a callback
+pub struct EspBleScanner<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    gap: EspBleGap<'d, M, T>,
+    shared: Arc<Shared>,
+}
+
+impl<'d, M, T> EspBleScanner<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    /// Create a new scanner
+    ///
+    /// Once the queue holds `result_queue_len` results, newly received ones are dropped until `recv`
+    /// is called. Results passed to the callback registered with `on_result` are not queued.
+    pub fn new(gap: EspBleGap<'d, M, T>, result_queue_len: usize) -> Result<Self, EspError> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                busy: false,
+                pending: Pending::None,
+                outcome: None,
+                scanning: false,
+                results: VecDeque::new(),
+                results_capacity: result_queue_len.max(1),
+                results_dropped: 0,
+                results_busy: false,
+            }),
+            callbacks: Mutex::new(Callbacks::default()),
+            op_notification: Notification::new(),
+            rx_notification: Notification::new(),
+        });
+
+        let handler = shared.clone();
+
+        gap.subscribe(move |event| handler.on_gap_event(event))?;
+
+        Ok(Self { gap, shared })
+    }
+
+    pub fn gap(&self) -> &EspBleGap<'d, M, T> {
+        &self.gap
+    }
+
+    /// Pass the scan results to the provided callback, instead of queueing them
+    ///
+    /// The callback is called from the Bluedroid task, so it should not block.
+    pub fn on_result<F>(&self, callback: F)
+    where
+        F: FnMut(&BleScanResult) + Send + 'static,
+    {
+        self.shared.callbacks.lock().result = Some(Box::new(callback));
+    }
+
+    /// Pass the GAP events unrelated to scanning to the provided callback
+    pub fn on_event<F>(&self, callback: F)
+    where
+        F: FnMut(&BleGapEvent) + Send + 'static,
+    {
+        self.shared.callbacks.lock().event = Some(Box::new(callback));
+    }
+
+    /// Start scanning with the provided parameters, for `duration` or until `stop` is called
+    pub async fn start(
+        &self,
+        params: &ScanParams,
+        duration: Option<Duration>,
+    ) -> Result<(), EspError> {
+        self.execute(Pending::Params, || self.gap.set_scan_params(params))
+            .await?;
+
+        self.execute(Pending::Start, || self.gap.start_scanning(duration))
+            .await
+    }
+
+    /// Stop scanning
+    pub async fn stop(&self) -> Result<(), EspError> {
+        self.execute(Pending::Stop, || self.gap.stop_scanning())
+            .await
+    }
+
+    pub fn is_scanning(&self) -> bool {
+        self.shared.state.lock().scanning
+    }
+
+    /// Wait for the next scan result
+    ///
+    /// Returns `None` once scanning is complete and all queued results are returned.
+    pub async fn recv(&self) -> Result<Option<BleScanResult>, EspError> {
+        {
+            let mut state = self.shared.state.lock();
+
+            if state.results_busy {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            state.results_busy = true;
+        }
+
+        let _guard = BusyGuard(&self.shared, |state| state.results_busy = false);
+
+        loop {
+            {
+                let mut state = self.shared.state.lock();
+
+                if let Some(result) = state.results.pop_front() {
+                    return Ok(Some(result));
+                }
+
+                if !state.scanning {
+                    return Ok(None);
+                }
+            }
+
+            self.shared.rx_notification.wait().await;
+        }
+    }
+
+    /// Return the next scan result, if one is already queued
+    pub fn try_recv(&self) -> Option<BleScanResult> {
+        self.shared.state.lock().results.pop_front()
+    }
+
+    /// Return the number of scan results dropped so far because the queue was full
+    pub fn dropped_results(&self) -> usize {
+        self.shared.state.lock().results_dropped
+    }
+
+    async fn execute<F>(&self, pending: Pending, start: F) -> Result<(), EspError>
+    where
+        F: FnOnce() -> Result<(), EspError>,
+    {
+        {
+            let mut state = self.shared.state.lock();
+
+            if state.busy {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            state.busy = true;
+            state.pending = pending;
+            state.outcome = None;
+        }
+
+        let _guard = BusyGuard(&self.shared, |state| {
+            state.busy = false;
+            state.pending = Pending::None;
+            state.outcome = None;
+        });
+
+        start()?;
+
+        loop {
+            if let Some(status) = self.shared.state.lock().outcome.take() {
+                return if status == BtStatus::Success {
+                    Ok(())
+                } else {
+                    Err(EspError::from_infallible::<ESP_FAIL>())
+                };
+            }
+
+            self.shared.op_notification.wait().await;
+        }
+    }
+}
+
+impl<'d, M, T> Drop for EspBleScanner<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    fn drop(&mut self) {
+        self.gap.unsubscribe().unwrap();
+
+        if self.shared.state.lock().scanning {
+            let _ = self.gap.stop_scanning();
+        }
+    }
+}
+
+impl Shared {
+    fn on_gap_event(&self, event: BleGapEvent) {
+        let completed = match &event {
+            BleGapEvent::ScanParameterConfigured(status) => {
+                self.state.lock().complete(Pending::Params, *status)
+            }
+            BleGapEvent::ScanStarted(status) => {
+                let mut state = self.state.lock();
+
+                if *status == BtStatus::Success {
+                    state.scanning = true;
+                }
+
+                state.complete(Pending::Start, *status)
+            }
+            BleGapEvent::ScanStopped(status) => {
+                let mut state = self.state.lock();
+
+                if *status == BtStatus::Success {
+                    state.scanning = false;
+                    self.rx_notification.notify_lsb();
+                }
+
+                state.complete(Pending::Stop, *status)
+            }
+            BleGapEvent::ScanResult(param) => {
+                match param.search_evt {
+                    esp_gap_search_evt_t_ESP_GAP_SEARCH_INQ_RES_EVT => {
+                        self.on_result(BleScanResult::from(param))
+                    }
+                    esp_gap_search_evt_t_ESP_GAP_SEARCH_INQ_CMPL_EVT => {
+                        self.state.lock().scanning = false;
+                        self.rx_notification.notify_lsb();
+                    }
+                    _ => (),
+                }
+
+                false
+            }
+            _ => {
+                if let Some(callback) = self.callbacks.lock().event.as_mut() {
+                    callback(&event);
+                }
+
+                false
+            }
+        };
+
+        if completed {
+            self.op_notification.notify_lsb();
+        }
+    }
+
+    fn on_result(&self, result: BleScanResult) {
+        if let Some(callback) = self.callbacks.lock().result.as_mut() {
+            callback(&result);
+            return;
+        }
+
+        let mut state = self.state.lock();
+
+        if state.results.len() < state.results_capacity {
+            state.results.push_back(result);
+
+            self.rx_notification.notify_lsb();
+        } else {
+            state.results_dropped += 1;
+        }
+    }
+}
+
+// Resets the state of an operation or of a `recv` call, even if its future is dropped early
+struct BusyGuard<'s, F>(&'s Shared, F)
+where
+    F: Fn(&mut State);
+
+impl<'s, F> Drop for BusyGuard<'s, F>
+where
+    F: Fn(&mut State),
+{
+    fn drop(&mut self) {
+        (self.1)(&mut self.0.state.lock());
+    }
+}
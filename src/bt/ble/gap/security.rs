@@ -0,0 +1,189 @@
+//! BLE security manager
+//!
+//! `BleSecurity` applies a `SecurityConfiguration` (IO capabilities, MITM protection,
+//! LE Secure Connections, bonding) and answers the pairing requests of the Security Manager
+//! Protocol with user callbacks: displaying a passkey, entering a passkey, confirming a numeric
+//! comparison and accepting a security request.
+//!
+//! It does not subscribe to the GAP events by itself, so that it can be combined with other
+//! users of the GAP events; instead, the events should be passed to `BleSecurity::handle_event`,
+//! along with the `EspBleGap` to answer them with:
+//!
+//! ```ignore
+//! let gap = Arc::new(EspBleGap::new(bt.clone())?);
+//! let security = Arc::new(BleSecurity::new(&gap, &conf)?);
+//!
+//! security.on_passkey_display(|addr, passkey| info!("Passkey for {addr:?}: {passkey:06}"));
+//!
+//! let (handler, handler_gap) = (security.clone(), gap.clone());
+//! gap.subscribe(move |event| {
+//!     handler.handle_event(&handler_gap, &event).unwrap();
+//! })?;
+//! ```
+//!
+//! Bonds are persisted in NVS by Bluedroid, provided that the `BtDriver` is created with an NVS partition.
+//! The bonded devices can be listed and removed with `EspBleGap::get_bond_devices`,
+//! `EspBleGap::remove_bond_device` and `EspBleGap::clear_bond_devices`.
+
+use core::borrow::Borrow;
+
+use alloc::boxed::Box;
+
+use log::{info, warn};
+
+use crate::bt::{BdAddr, BleEnabled, BtDriver, BtStatus};
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::{BleGapEvent, EspBleGap, SecurityConfiguration};
+
+type PasskeyDisplayCallback = Box<dyn FnMut(&BdAddr, u32) + Send>;
+type PasskeyRequestCallback = Box<dyn FnMut(&BdAddr) -> Option<u32> + Send>;
+type ConfirmCallback = Box<dyn FnMut(&BdAddr, u32) -> bool + Send>;
+type SecurityRequestCallback = Box<dyn FnMut(&BdAddr) -> bool + Send>;
+type AuthenticationCallback = Box<dyn FnMut(&BdAddr, bool) + Send>;
+
+#[derive(Default)]
+struct Callbacks {
+    passkey_display: Option<PasskeyDisplayCallback>,
+    passkey_request: Option<PasskeyRequestCallback>,
+    confirm: Option<ConfirmCallback>,
+    security_request: Option<SecurityRequestCallback>,
+    authentication: Option<AuthenticationCallback>,
+}
+
+/// A BLE security manager, answering pairing requests with user callbacks
+pub struct BleSecurity {
+    callbacks: Mutex<Callbacks>,
+}
+
+impl BleSecurity {
+    /// Create a new security manager, applying the provided security configuration
+    pub fn new<'d, M, T>(
+        gap: &EspBleGap<'d, M, T>,
+        conf: &SecurityConfiguration,
+    ) -> Result<Self, EspError>
+    where
+        T: Borrow<BtDriver<'d, M>>,
+        M: BleEnabled,
+    {
+        gap.set_security_conf(conf)?;
+
+        Ok(Self {
+            callbacks: Mutex::new(Callbacks::default()),
+        })
+    }
+
+    /// Display the passkey the peer should enter
+    ///
+    /// Without a callback, the passkey is logged.
+    pub fn on_passkey_display<F>(&self, callback: F)
+    where
+        F: FnMut(&BdAddr, u32) + Send + 'static,
+    {
+        self.callbacks.lock().passkey_display = Some(Box::new(callback));
+    }
+
+    /// Return the passkey displayed by the peer, as entered by the user, or `None` to reject the pairing
+    ///
+    /// Without a callback, the pairing is rejected.
+    pub fn on_passkey_request<F>(&self, callback: F)
+    where
+        F: FnMut(&BdAddr) -> Option<u32> + Send + 'static,
+    {
+        self.callbacks.lock().passkey_request = Some(Box::new(callback));
+    }
+
+    /// Return whether the passkey displayed by the peer matches the provided one, as confirmed by the user
+    ///
+    /// Without a callback, the pairing is rejected.
+    pub fn on_numeric_comparison<F>(&self, callback: F)
+    where
+        F: FnMut(&BdAddr, u32) -> bool + Send + 'static,
+    {
+        self.callbacks.lock().confirm = Some(Box::new(callback));
+    }
+
+    /// Return whether to accept the security request of a peer
+    ///
+    /// Without a callback, all security requests are accepted.
+    pub fn on_security_request<F>(&self, callback: F)
+    where
+        F: FnMut(&BdAddr) -> bool + Send + 'static,
+    {
+        self.callbacks.lock().security_request = Some(Box::new(callback));
+    }
+
+    /// Get notified of the completion of an authentication, successful or not
+    pub fn on_authentication_complete<F>(&self, callback: F)
+    where
+        F: FnMut(&BdAddr, bool) + Send + 'static,
+    {
+        self.callbacks.lock().authentication = Some(Box::new(callback));
+    }
+
+    /// Handle a GAP event, answering it if it is a pairing request
+    ///
+    /// Returns `true` if the event is handled by the security manager.
+    pub fn handle_event<'d, M, T>(
+        &self,
+        gap: &EspBleGap<'d, M, T>,
+        event: &BleGapEvent,
+    ) -> Result<bool, EspError>
+    where
+        T: Borrow<BtDriver<'d, M>>,
+        M: BleEnabled,
+    {
+        let mut callbacks = self.callbacks.lock();
+
+        match event {
+            BleGapEvent::SecurityRequest { addr } => {
+                let accept = callbacks
+                    .security_request
+                    .as_mut()
+                    .map(|callback| callback(addr))
+                    .unwrap_or(true);
+
+                gap.reply_security_request(addr, accept)?;
+            }
+            BleGapEvent::PasskeyNotification { addr, passkey } => {
+                if let Some(callback) = callbacks.passkey_display.as_mut() {
+                    callback(addr, *passkey);
+                } else {
+                    info!("Passkey for {:?}: {:06}", addr, passkey);
+                }
+            }
+            BleGapEvent::PasskeyRequest { addr } => {
+                let passkey = callbacks
+                    .passkey_request
+                    .as_mut()
+                    .and_then(|callback| callback(addr));
+
+                gap.reply_passkey(addr, passkey)?;
+            }
+            BleGapEvent::NumericComparisonRequest { addr, passkey } => {
+                let confirm = callbacks
+                    .confirm
+                    .as_mut()
+                    .map(|callback| callback(addr, *passkey))
+                    .unwrap_or(false);
+
+                gap.reply_confirm(addr, confirm)?;
+            }
+            BleGapEvent::AuthenticationComplete { bd_addr, status } => {
+                let success = *status == BtStatus::Success;
+
+                if !success {
+                    warn!("Authentication with {:?} failed", bd_addr);
+                }
+
+                if let Some(callback) = callbacks.authentication.as_mut() {
+                    callback(bd_addr, success);
+                }
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
@@ -4,6 +4,7 @@ use num_enum::TryFromPrimitive;
 use crate::bt::BtUuid;
 use crate::sys::*;
 
+pub mod client;
 pub mod server;
 
 pub type GattInterface = u8;
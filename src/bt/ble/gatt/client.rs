@@ -0,0 +1,782 @@
+use core::borrow::Borrow;
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+
+use alloc::vec::Vec;
+
+use log::debug;
+
+use crate::bt::ble::gap::BleAddrType;
+use crate::bt::{BdAddr, BleEnabled, BtDriver, BtSingleton, BtUuid};
+use crate::sys::*;
+
+use super::{GattConnParams, GattConnReason, GattId, GattInterface, GattStatus, Handle};
+
+pub mod app;
+
+pub type AppId = u16;
+pub type ConnectionId = u16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum GattWriteType {
+    NoResponse = esp_gatt_write_type_t_ESP_GATT_WRITE_TYPE_NO_RSP,
+    WithResponse = esp_gatt_write_type_t_ESP_GATT_WRITE_TYPE_RSP,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum GattAuthReq {
+    #[default]
+    None = esp_gatt_auth_req_t_ESP_GATT_AUTH_REQ_NONE,
+    NoMitm = esp_gatt_auth_req_t_ESP_GATT_AUTH_REQ_NO_MITM,
+    Mitm = esp_gatt_auth_req_t_ESP_GATT_AUTH_REQ_MITM,
+    SignedNoMitm = esp_gatt_auth_req_t_ESP_GATT_AUTH_REQ_SIGNED_NO_MITM,
+    SignedMitm = esp_gatt_auth_req_t_ESP_GATT_AUTH_REQ_SIGNED_MITM,
+}
+
+/// A characteristic from the local GATT cache of a connection
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GattcCharacteristic {
+    pub handle: Handle,
+    pub uuid: BtUuid,
+    /// The raw `Property` bits
+    pub properties: u8,
+}
+
+/// A characteristic descriptor from the local GATT cache of a connection
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GattcDescriptor {
+    pub handle: Handle,
+    pub uuid: BtUuid,
+}
+
+pub struct EventRawData<'a>(pub &'a esp_ble_gattc_cb_param_t);
+
+impl<'a> Debug for EventRawData<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EventRawData").finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum GattcEvent<'a> {
+    ClientRegistered {
+        /// Operation status
+        status: GattStatus,
+        /// Application id which input in register API
+        app_id: AppId,
+    },
+    Connected {
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Link role : master role = 0  ; slave role = 1
+        link_role: u8,
+        /// Remote bluetooth device address
+        addr: BdAddr,
+        /// Current connection parameters
+        conn_params: GattConnParams,
+    },
+    Disconnected {
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Remote bluetooth device address
+        addr: BdAddr,
+        /// Indicate the reason of disconnection
+        reason: GattConnReason,
+    },
+    Open {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Remote bluetooth device address
+        addr: BdAddr,
+        /// MTU size
+        mtu: u16,
+    },
+    Close {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Remote bluetooth device address
+        addr: BdAddr,
+        /// The reason of gatt connection close
+        reason: GattConnReason,
+    },
+    Mtu {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+        /// MTU size
+        mtu: u16,
+    },
+    SearchResult {
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Service start handle
+        start_handle: Handle,
+        /// Service end handle
+        end_handle: Handle,
+        /// Service id, include service uuid and other information
+        srvc_id: GattId,
+        /// True if this is the primary service
+        is_primary: bool,
+    },
+    SearchComplete {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+    },
+    ReadCharacteristic {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Characteristic handle
+        handle: Handle,
+        /// Characteristic value
+        value: &'a [u8],
+    },
+    ReadDescriptor {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Descriptor handle
+        handle: Handle,
+        /// Descriptor value
+        value: &'a [u8],
+    },
+    WriteCharacteristic {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Characteristic handle
+        handle: Handle,
+        /// The position offset to write
+        offset: u16,
+    },
+    WriteDescriptor {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Descriptor handle
+        handle: Handle,
+        /// The position offset to write
+        offset: u16,
+    },
+    ExecWrite {
+        /// Operation status
+        status: GattStatus,
+        /// Connection id
+        conn_id: ConnectionId,
+    },
+    Notify {
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Remote bluetooth device address
+        addr: BdAddr,
+        /// The characteristic handle
+        handle: Handle,
+        /// Notify attribute value
+        value: &'a [u8],
+        /// True means notification, false means indication
+        is_notify: bool,
+    },
+    RegisteredForNotify {
+        /// Operation status
+        status: GattStatus,
+        /// The characteristic handle
+        handle: Handle,
+    },
+    UnregisteredForNotify {
+        /// Operation status
+        status: GattStatus,
+        /// The characteristic handle
+        handle: Handle,
+    },
+    ServiceChanged {
+        /// Remote bluetooth device address
+        addr: BdAddr,
+    },
+    Congest {
+        /// Connection id
+        conn_id: ConnectionId,
+        /// Congested or not
+        congested: bool,
+    },
+    Other {
+        raw_event: esp_gattc_cb_event_t,
+        raw_data: EventRawData<'a>,
+    },
+}
+
+#[allow(non_upper_case_globals)]
+impl<'a> From<(esp_gattc_cb_event_t, &'a esp_ble_gattc_cb_param_t)> for GattcEvent<'a> {
+    fn from(value: (esp_gattc_cb_event_t, &'a esp_ble_gattc_cb_param_t)) -> Self {
+        let (event, param) = value;
+
+        match event {
+            esp_gattc_cb_event_t_ESP_GATTC_REG_EVT => unsafe {
+                Self::ClientRegistered {
+                    status: param.reg.status.try_into().unwrap(),
+                    app_id: param.reg.app_id,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_CONNECT_EVT => unsafe {
+                Self::Connected {
+                    conn_id: param.connect.conn_id,
+                    link_role: param.connect.link_role,
+                    addr: param.connect.remote_bda.into(),
+                    conn_params: GattConnParams {
+                        interval_ms: param.connect.conn_params.interval as u32 * 125 / 100,
+                        latency_ms: param.connect.conn_params.latency as u32 * 125 / 100,
+                        timeout_ms: param.connect.conn_params.timeout as u32 * 10,
+                    },
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_DISCONNECT_EVT => unsafe {
+                Self::Disconnected {
+                    conn_id: param.disconnect.conn_id,
+                    addr: param.disconnect.remote_bda.into(),
+                    reason: param.disconnect.reason.try_into().unwrap(),
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_OPEN_EVT => unsafe {
+                Self::Open {
+                    status: param.open.status.try_into().unwrap(),
+                    conn_id: param.open.conn_id,
+                    addr: param.open.remote_bda.into(),
+                    mtu: param.open.mtu,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_CLOSE_EVT => unsafe {
+                Self::Close {
+                    status: param.close.status.try_into().unwrap(),
+                    conn_id: param.close.conn_id,
+                    addr: param.close.remote_bda.into(),
+                    reason: param.close.reason.try_into().unwrap(),
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_CFG_MTU_EVT => unsafe {
+                Self::Mtu {
+                    status: param.cfg_mtu.status.try_into().unwrap(),
+                    conn_id: param.cfg_mtu.conn_id,
+                    mtu: param.cfg_mtu.mtu,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_SEARCH_RES_EVT => unsafe {
+                Self::SearchResult {
+                    conn_id: param.search_res.conn_id,
+                    start_handle: param.search_res.start_handle,
+                    end_handle: param.search_res.end_handle,
+                    srvc_id: param.search_res.srvc_id.into(),
+                    is_primary: param.search_res.is_primary,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_SEARCH_CMPL_EVT => unsafe {
+                Self::SearchComplete {
+                    status: param.search_cmpl.status.try_into().unwrap(),
+                    conn_id: param.search_cmpl.conn_id,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_READ_CHAR_EVT => unsafe {
+                Self::ReadCharacteristic {
+                    status: param.read.status.try_into().unwrap(),
+                    conn_id: param.read.conn_id,
+                    handle: param.read.handle,
+                    value: raw_slice(param.read.value, param.read.value_len),
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_READ_DESCR_EVT => unsafe {
+                Self::ReadDescriptor {
+                    status: param.read.status.try_into().unwrap(),
+                    conn_id: param.read.conn_id,
+                    handle: param.read.handle,
+                    value: raw_slice(param.read.value, param.read.value_len),
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_WRITE_CHAR_EVT => unsafe {
+                Self::WriteCharacteristic {
+                    status: param.write.status.try_into().unwrap(),
+                    conn_id: param.write.conn_id,
+                    handle: param.write.handle,
+                    offset: param.write.offset,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_WRITE_DESCR_EVT => unsafe {
+                Self::WriteDescriptor {
+                    status: param.write.status.try_into().unwrap(),
+                    conn_id: param.write.conn_id,
+                    handle: param.write.handle,
+                    offset: param.write.offset,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_EXEC_EVT => unsafe {
+                Self::ExecWrite {
+                    status: param.exec_cmpl.status.try_into().unwrap(),
+                    conn_id: param.exec_cmpl.conn_id,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_NOTIFY_EVT => unsafe {
+                Self::Notify {
+                    conn_id: param.notify.conn_id,
+                    addr: param.notify.remote_bda.into(),
+                    handle: param.notify.handle,
+                    value: raw_slice(param.notify.value, param.notify.value_len),
+                    is_notify: param.notify.is_notify,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_REG_FOR_NOTIFY_EVT => unsafe {
+                Self::RegisteredForNotify {
+                    status: param.reg_for_notify.status.try_into().unwrap(),
+                    handle: param.reg_for_notify.handle,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_UNREG_FOR_NOTIFY_EVT => unsafe {
+                Self::UnregisteredForNotify {
+                    status: param.unreg_for_notify.status.try_into().unwrap(),
+                    handle: param.unreg_for_notify.handle,
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_SRVC_CHG_EVT => unsafe {
+                Self::ServiceChanged {
+                    addr: param.srvc_chg.remote_bda.into(),
+                }
+            },
+            esp_gattc_cb_event_t_ESP_GATTC_CONGEST_EVT => unsafe {
+                Self::Congest {
+                    conn_id: param.congest.conn_id,
+                    congested: param.congest.congested,
+                }
+            },
+            _ => Self::Other {
+                raw_event: event,
+                raw_data: EventRawData(param),
+            },
+        }
+    }
+}
+
+unsafe fn raw_slice<'a>(data: *const u8, len: u16) -> &'a [u8] {
+    if data.is_null() || len == 0 {
+        &[]
+    } else {
+        core::slice::from_raw_parts(data, len as _)
+    }
+}
+
+pub struct EspGattc<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    _driver: T,
+    _p: PhantomData<&'d ()>,
+    _m: PhantomData<M>,
+}
+
+impl<'d, M, T> EspGattc<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    pub fn new(driver: T) -> Result<Self, EspError> {
+        SINGLETON.take()?;
+
+        esp!(unsafe { esp_ble_gattc_register_callback(Some(Self::event_handler)) })?;
+
+        Ok(Self {
+            _driver: driver,
+            _p: PhantomData,
+            _m: PhantomData,
+        })
+    }
+
+    pub fn subscribe<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut((GattInterface, GattcEvent)) + Send + 'static,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// This method - in contrast to method `subscribe` - allows the user to pass
+    /// a non-static callback/closure. This enables users to borrow
+    /// - in the closure - variables that live on the stack - or more generally - in the same
+    ///   scope where the service is created.
+    ///
+    /// HOWEVER: care should be taken NOT to call `core::mem::forget()` on the service,
+    /// as that would immediately lead to an UB (crash).
+    /// Also note that forgetting the service might happen with `Rc` and `Arc`
+    /// when circular references are introduced: https://github.com/rust-lang/rust/issues/24456
+    ///
+    /// The reason is that the closure is actually sent to a hidden ESP IDF thread.
+    /// This means that if the service is forgotten, Rust is free to e.g. unwind the stack
+    /// and the closure now owned by this other thread will end up with references to variables that no longer exist.
+    ///
+    /// The destructor of the service takes care - prior to the service being dropped and e.g.
+    /// the stack being unwind - to remove the closure from the hidden thread and destroy it.
+    /// Unfortunately, when the service is forgotten, the un-subscription does not happen
+    /// and invalid references are left dangling.
+    ///
+    /// This "local borrowing" will only be possible to express in a safe way once/if `!Leak` types
+    /// are introduced to Rust (i.e. the impossibility to "forget" a type and thus not call its destructor).
+    pub unsafe fn subscribe_nonstatic<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut((GattInterface, GattcEvent)) + Send + 'd,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self) -> Result<(), EspError> {
+        SINGLETON.unsubscribe();
+
+        Ok(())
+    }
+
+    pub fn register_app(&self, app_id: AppId) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gattc_app_register(app_id) })
+    }
+
+    pub fn unregister_app(&self, gattc_if: GattInterface) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gattc_app_unregister(gattc_if) })
+    }
+
+    /// Open a connection to a remote device
+    ///
+    /// With `is_direct == false`, the connection is established in the background,
+    /// once the device is seen advertising.
+    pub fn open(
+        &self,
+        gattc_if: GattInterface,
+        addr: BdAddr,
+        addr_type: BleAddrType,
+        is_direct: bool,
+    ) -> Result<(), EspError> {
+        let mut addr = addr.raw();
+
+        esp!(unsafe { esp_ble_gattc_open(gattc_if, addr.as_mut_ptr(), addr_type as _, is_direct) })
+    }
+
+    pub fn close(&self, gattc_if: GattInterface, conn_id: ConnectionId) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gattc_close(gattc_if, conn_id) })
+    }
+
+    /// Request the MTU set with `set_local_mtu` to be exchanged with the server
+    pub fn mtu_req(&self, gattc_if: GattInterface, conn_id: ConnectionId) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gattc_send_mtu_req(gattc_if, conn_id) })
+    }
+
+    pub fn set_local_mtu(&self, mtu: u16) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gatt_set_local_mtu(mtu) })
+    }
+
+    /// Discover the services of the server, or only the one with the provided UUID
+    ///
+    /// The services are reported with `GattcEvent::SearchResult` events,
+    /// followed by `GattcEvent::SearchComplete`.
+    pub fn search_service(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        filter: Option<&BtUuid>,
+    ) -> Result<(), EspError> {
+        let mut filter = filter.map(|uuid| uuid.raw());
+
+        esp!(unsafe {
+            esp_ble_gattc_search_service(
+                gattc_if,
+                conn_id,
+                filter
+                    .as_mut()
+                    .map(|uuid| uuid as *mut _)
+                    .unwrap_or(core::ptr::null_mut()),
+            )
+        })
+    }
+
+    /// Return the characteristics within the provided handle range (i.e. of a service),
+    /// from the local GATT cache populated by `search_service`
+    pub fn get_characteristics(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        start_handle: Handle,
+        end_handle: Handle,
+    ) -> Result<Vec<GattcCharacteristic>, EspError> {
+        let count = self.get_attr_count(
+            gattc_if,
+            conn_id,
+            esp_gatt_db_attr_type_t_ESP_GATT_DB_CHARACTERISTIC,
+            start_handle,
+            end_handle,
+            0,
+        )?;
+
+        let mut elems = Vec::new();
+        elems.resize(count as _, Default::default());
+
+        let mut count = count;
+
+        if count > 0 {
+            check(unsafe {
+                esp_ble_gattc_get_all_char(
+                    gattc_if,
+                    conn_id,
+                    start_handle,
+                    end_handle,
+                    elems.as_mut_ptr(),
+                    &mut count,
+                    0,
+                )
+            })?;
+        }
+
+        Ok(elems
+            .iter()
+            .take(count as _)
+            .map(|elem: &esp_gattc_char_elem_t| GattcCharacteristic {
+                handle: elem.char_handle,
+                uuid: elem.uuid.into(),
+                properties: elem.properties as _,
+            })
+            .collect())
+    }
+
+    /// Return the descriptors of a characteristic, from the local GATT cache populated by `search_service`
+    pub fn get_descriptors(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        char_handle: Handle,
+    ) -> Result<Vec<GattcDescriptor>, EspError> {
+        let count = self.get_attr_count(
+            gattc_if,
+            conn_id,
+            esp_gatt_db_attr_type_t_ESP_GATT_DB_DESCRIPTOR,
+            0,
+            0,
+            char_handle,
+        )?;
+
+        let mut elems = Vec::new();
+        elems.resize(count as _, Default::default());
+
+        let mut count = count;
+
+        if count > 0 {
+            check(unsafe {
+                esp_ble_gattc_get_all_descr(
+                    gattc_if,
+                    conn_id,
+                    char_handle,
+                    elems.as_mut_ptr(),
+                    &mut count,
+                    0,
+                )
+            })?;
+        }
+
+        Ok(elems
+            .iter()
+            .take(count as _)
+            .map(|elem: &esp_gattc_descr_elem_t| GattcDescriptor {
+                handle: elem.handle,
+                uuid: elem.uuid.into(),
+            })
+            .collect())
+    }
+
+    pub fn read_characteristic(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        handle: Handle,
+        auth_req: GattAuthReq,
+    ) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gattc_read_char(gattc_if, conn_id, handle, auth_req as _) })
+    }
+
+    pub fn read_descriptor(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        handle: Handle,
+        auth_req: GattAuthReq,
+    ) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gattc_read_char_descr(gattc_if, conn_id, handle, auth_req as _) })
+    }
+
+    pub fn write_characteristic(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        handle: Handle,
+        data: &[u8],
+        write_type: GattWriteType,
+        auth_req: GattAuthReq,
+    ) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_ble_gattc_write_char(
+                gattc_if,
+                conn_id,
+                handle,
+                data.len() as _,
+                data.as_ptr() as *const _ as *mut _,
+                write_type as _,
+                auth_req as _,
+            )
+        })
+    }
+
+    pub fn write_descriptor(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        handle: Handle,
+        data: &[u8],
+        write_type: GattWriteType,
+        auth_req: GattAuthReq,
+    ) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_ble_gattc_write_char_descr(
+                gattc_if,
+                conn_id,
+                handle,
+                data.len() as _,
+                data.as_ptr() as *const _ as *mut _,
+                write_type as _,
+                auth_req as _,
+            )
+        })
+    }
+
+    /// Register for the notifications and indications of a characteristic
+    ///
+    /// Note that this only enables their delivery as `GattcEvent::Notify` events; the server also
+    /// needs to be told to send them, by writing to the Client Characteristic Configuration descriptor.
+    pub fn register_for_notify(
+        &self,
+        gattc_if: GattInterface,
+        addr: BdAddr,
+        handle: Handle,
+    ) -> Result<(), EspError> {
+        let mut addr = addr.raw();
+
+        esp!(unsafe { esp_ble_gattc_register_for_notify(gattc_if, addr.as_mut_ptr(), handle) })
+    }
+
+    pub fn unregister_for_notify(
+        &self,
+        gattc_if: GattInterface,
+        addr: BdAddr,
+        handle: Handle,
+    ) -> Result<(), EspError> {
+        let mut addr = addr.raw();
+
+        esp!(unsafe { esp_ble_gattc_unregister_for_notify(gattc_if, addr.as_mut_ptr(), handle) })
+    }
+
+    /// Drop the local GATT cache of a remote device, forcing a new discovery on the next `search_service`
+    pub fn cache_refresh(&self, addr: BdAddr) -> Result<(), EspError> {
+        let mut addr = addr.raw();
+
+        esp!(unsafe { esp_ble_gattc_cache_refresh(addr.as_mut_ptr()) })
+    }
+
+    fn get_attr_count(
+        &self,
+        gattc_if: GattInterface,
+        conn_id: ConnectionId,
+        attr_type: esp_gatt_db_attr_type_t,
+        start_handle: Handle,
+        end_handle: Handle,
+        char_handle: Handle,
+    ) -> Result<u16, EspError> {
+        let mut count = 0;
+
+        check(unsafe {
+            esp_ble_gattc_get_attr_count(
+                gattc_if,
+                conn_id,
+                attr_type,
+                start_handle,
+                end_handle,
+                char_handle,
+                &mut count,
+            )
+        })?;
+
+        Ok(count)
+    }
+
+    unsafe extern "C" fn event_handler(
+        event: esp_gattc_cb_event_t,
+        gattc_if: esp_gatt_if_t,
+        param: *mut esp_ble_gattc_cb_param_t,
+    ) {
+        let param = unsafe { param.as_ref() }.unwrap();
+        let event = GattcEvent::from((event, param));
+
+        debug!("Got event {{ {:#?} }}", event);
+
+        SINGLETON.call((gattc_if, event));
+    }
+}
+
+impl<'d, M, T> Drop for EspGattc<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    fn drop(&mut self) {
+        self.unsubscribe().unwrap();
+
+        esp!(unsafe { esp_ble_gattc_register_callback(None) }).unwrap();
+
+        SINGLETON.release().unwrap();
+    }
+}
+
+unsafe impl<'d, M, T> Send for EspGattc<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>> + Send,
+    M: BleEnabled,
+{
+}
+
+// Safe because the ESP IDF Bluedroid APIs all do message passing
+// to a dedicated Bluedroid task
+unsafe impl<'d, M, T> Sync for EspGattc<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>> + Send,
+    M: BleEnabled,
+{
+}
+
+// The local GATT cache APIs report a GATT status rather than an ESP error
+fn check(status: esp_gatt_status_t) -> Result<(), EspError> {
+    if status == esp_gatt_status_t_ESP_GATT_OK {
+        Ok(())
+    } else if status == esp_gatt_status_t_ESP_GATT_NOT_FOUND {
+        Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+    } else if status == esp_gatt_status_t_ESP_GATT_INVALID_HANDLE {
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+    } else {
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    }
+}
+
+static SINGLETON: BtSingleton<(GattInterface, GattcEvent), ()> = BtSingleton::new(());
@@ -0,0 +1,774 @@
+//! A high-level, async GATT client
+//!
+//! `EspGattClient` builds on top of `EspGattc` and turns the request / event pairs of the
+//! Bluedroid GATTC API into futures: connecting to a server, exchanging the MTU, discovering its
+//! services, reading and writing characteristics and descriptors all resolve once the
+//! corresponding event is received.
+//!
+//! The notifications and indications of the characteristics the client subscribed to are queued
+//! and returned by `recv_notification`.
+//!
+//! Only one operation can be in progress at any time; a concurrent operation fails with
+//! `ESP_ERR_INVALID_STATE`. The same applies to concurrent `recv_notification` calls.
+//!
+//! Note that the client subscribes to the events of the `EspGattc` instance it takes ownership of,
+//! so `EspGattc::subscribe` should not be used in conjunction with it.
+
+use core::borrow::Borrow;
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use log::warn;
+
+use crate::bt::ble::gap::BleAddrType;
+use crate::bt::{BdAddr, BleEnabled, BtDriver, BtUuid};
+use crate::hal::task::asynch::Notification;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::super::{GattInterface, GattStatus, Handle, Property};
+use super::{
+    AppId, ConnectionId, EspGattc, GattAuthReq, GattWriteType, GattcCharacteristic,
+    GattcDescriptor, GattcEvent,
+};
+
+/// The default ATT MTU, before a larger one is exchanged
+pub const DEFAULT_MTU: u16 = 23;
+
+const CCCD_UUID: u16 = ESP_GATT_UUID_CHAR_CLIENT_CONFIG as _;
+
+/// A service discovered on a server
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GattcService {
+    pub uuid: BtUuid,
+    pub start_handle: Handle,
+    pub end_handle: Handle,
+    pub is_primary: bool,
+}
+
+/// A connection to a server
+#[derive(Clone, Debug)]
+pub struct GattcConnection {
+    pub conn_id: ConnectionId,
+    pub addr: BdAddr,
+    pub mtu: u16,
+}
+
+/// A notification or indication received from a server
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GattNotification {
+    pub conn_id: ConnectionId,
+    pub addr: BdAddr,
+    /// The handle of the characteristic value
+    pub handle: Handle,
+    pub value: Vec<u8>,
+    /// `true` for notifications, `false` for indications
+    pub is_notify: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Pending {
+    None,
+    Open(BdAddr),
+    Mtu(ConnectionId),
+    Search(ConnectionId),
+    Read(ConnectionId, Handle),
+    Write(ConnectionId, Handle),
+    Notify(Handle),
+}
+
+enum Outcome {
+    Status(GattStatus),
+    Opened(ConnectionId),
+    Mtu(u16),
+    Read(Vec<u8>),
+}
+
+struct State {
+    app_id: AppId,
+    registration: Option<Result<GattInterface, GattStatus>>,
+    busy: bool,
+    pending: Pending,
+    outcome: Option<Outcome>,
+    services: Vec<GattcService>,
+    connections: Vec<GattcConnection>,
+    notifications: VecDeque<GattNotification>,
+    notifications_capacity: usize,
+    notifications_dropped: usize,
+    notifications_busy: bool,
+}
+
+impl State {
+    fn complete(&mut self, pending: Pending, outcome: Outcome) -> bool {
+        if self.pending == pending {
+            self.pending = Pending::None;
+            self.outcome = Some(outcome);
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    op_notification: Notification,
+    rx_notification: Notification,
+}
+
+/// An async GATT client
+pub struct EspGattClient<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    gattc: EspGattc<'d, M, T>,
+    shared: Arc<Shared>,
+}
+
+impl<'d, M, T> EspGattClient<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    /// Create a new GATT client, registering it as the GATTC application `app_id`
+    ///
+    /// Once the queue holds `notification_queue_len` notifications, newly received ones are dropped
+    /// until `recv_notification` is called.
+    pub fn new(
+        gattc: EspGattc<'d, M, T>,
+        app_id: AppId,
+        notification_queue_len: usize,
+    ) -> Result<Self, EspError> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                app_id,
+                registration: None,
+                busy: false,
+                pending: Pending::None,
+                outcome: None,
+                services: Vec::new(),
+                connections: Vec::new(),
+                notifications: VecDeque::new(),
+                notifications_capacity: notification_queue_len.max(1),
+                notifications_dropped: 0,
+                notifications_busy: false,
+            }),
+            op_notification: Notification::new(),
+            rx_notification: Notification::new(),
+        });
+
+        let handler = shared.clone();
+
+        gattc.subscribe(move |(gattc_if, event)| handler.on_gattc_event(gattc_if, event))?;
+        gattc.register_app(app_id)?;
+
+        Ok(Self { gattc, shared })
+    }
+
+    pub fn gattc(&self) -> &EspGattc<'d, M, T> {
+        &self.gattc
+    }
+
+    /// Connect to a server and return the ID of the new connection
+    pub async fn connect(
+        &self,
+        addr: BdAddr,
+        addr_type: BleAddrType,
+    ) -> Result<ConnectionId, EspError> {
+        let outcome = self
+            .execute(Pending::Open(addr), |gattc_if| {
+                self.gattc.open(gattc_if, addr, addr_type, true)
+            })
+            .await?;
+
+        match outcome {
+            Outcome::Opened(conn_id) => Ok(conn_id),
+            outcome => Err(Self::to_err(outcome)),
+        }
+    }
+
+    /// Close a connection
+    pub fn disconnect(&self, conn_id: ConnectionId) -> Result<(), EspError> {
+        let gattc_if = self.gattc_if()?;
+
+        self.gattc.close(gattc_if, conn_id)
+    }
+
+    /// Return the current connections
+    pub fn connections(&self) -> Vec<GattcConnection> {
+        self.shared.state.lock().connections.clone()
+    }
+
+    /// Return the MTU of a connection
+    pub fn mtu(&self, conn_id: ConnectionId) -> Option<u16> {
+        self.connection(conn_id).map(|connection| connection.mtu)
+    }
+
+    /// Exchange the MTU with the server, offering `mtu`, and return the resulting MTU
+    pub async fn exchange_mtu(&self, conn_id: ConnectionId, mtu: u16) -> Result<u16, EspError> {
+        self.gattc.set_local_mtu(mtu)?;
+
+        let outcome = self
+            .execute(Pending::Mtu(conn_id), |gattc_if| {
+                self.gattc.mtu_req(gattc_if, conn_id)
+            })
+            .await?;
+
+        match outcome {
+            Outcome::Mtu(mtu) => Ok(mtu),
+            outcome => Err(Self::to_err(outcome)),
+        }
+    }
+
+    /// Discover the services of the server, or only the one with the provided UUID
+    pub async fn discover_services(
+        &self,
+        conn_id: ConnectionId,
+        filter: Option<&BtUuid>,
+    ) -> Result<Vec<GattcService>, EspError> {
+        let outcome = self
+            .execute(Pending::Search(conn_id), |gattc_if| {
+                self.shared.state.lock().services.clear();
+
+                self.gattc.search_service(gattc_if, conn_id, filter)
+            })
+            .await?;
+
+        match outcome {
+            Outcome::Status(GattStatus::Ok) => {
+                Ok(core::mem::take(&mut self.shared.state.lock().services))
+            }
+            outcome => Err(Self::to_err(outcome)),
+        }
+    }
+
+    /// Return the characteristics of a service discovered with `discover_services`
+    pub fn characteristics(
+        &self,
+        conn_id: ConnectionId,
+        service: &GattcService,
+    ) -> Result<Vec<GattcCharacteristic>, EspError> {
+        self.gattc.get_characteristics(
+            self.gattc_if()?,
+            conn_id,
+            service.start_handle,
+            service.end_handle,
+        )
+    }
+
+    /// Return the descriptors of a characteristic, once its service is discovered with `discover_services`
+    pub fn descriptors(
+        &self,
+        conn_id: ConnectionId,
+        characteristic: &GattcCharacteristic,
+    ) -> Result<Vec<GattcDescriptor>, EspError> {
+        self.gattc
+            .get_descriptors(self.gattc_if()?, conn_id, characteristic.handle)
+    }
+
+    /// Read the value of a characteristic
+    pub async fn read(&self, conn_id: ConnectionId, handle: Handle) -> Result<Vec<u8>, EspError> {
+        let outcome = self
+            .execute(Pending::Read(conn_id, handle), |gattc_if| {
+                self.gattc
+                    .read_characteristic(gattc_if, conn_id, handle, GattAuthReq::None)
+            })
+            .await?;
+
+        Self::to_value(outcome)
+    }
+
+    /// Read the value of a descriptor
+    pub async fn read_descriptor(
+        &self,
+        conn_id: ConnectionId,
+        handle: Handle,
+    ) -> Result<Vec<u8>, EspError> {
+        let outcome = self
+            .execute(Pending::Read(conn_id, handle), |gattc_if| {
+                self.gattc
+                    .read_descriptor(gattc_if, conn_id, handle, GattAuthReq::None)
+            })
+            .await?;
+
+        Self::to_value(outcome)
+    }
+
+    /// Write the value of a characteristic
+    ///
+    /// With `GattWriteType::WithResponse`, the future resolves once the server acknowledged the write;
+    /// with `GattWriteType::NoResponse`, as soon as the write is queued.
+    pub async fn write(
+        &self,
+        conn_id: ConnectionId,
+        handle: Handle,
+        data: &[u8],
+        write_type: GattWriteType,
+    ) -> Result<(), EspError> {
+        if write_type == GattWriteType::NoResponse {
+            let gattc_if = self.gattc_if()?;
+
+            return self.gattc.write_characteristic(
+                gattc_if,
+                conn_id,
+                handle,
+                data,
+                write_type,
+                GattAuthReq::None,
+            );
+        }
+
+        let outcome = self
+            .execute(Pending::Write(conn_id, handle), |gattc_if| {
+                self.gattc.write_characteristic(
+                    gattc_if,
+                    conn_id,
+                    handle,
+                    data,
+                    write_type,
+                    GattAuthReq::None,
+                )
+            })
+            .await?;
+
+        Self::to_status(outcome)
+    }
+
+    /// Write the value of a descriptor
+    pub async fn write_descriptor(
+        &self,
+        conn_id: ConnectionId,
+        handle: Handle,
+        data: &[u8],
+    ) -> Result<(), EspError> {
+        let outcome = self
+            .execute(Pending::Write(conn_id, handle), |gattc_if| {
+                self.gattc.write_descriptor(
+                    gattc_if,
+                    conn_id,
+                    handle,
+                    data,
+                    GattWriteType::WithResponse,
+                    GattAuthReq::None,
+                )
+            })
+            .await?;
+
+        Self::to_status(outcome)
+    }
+
+    /// Subscribe to the notifications (`indicate == false`) or indications (`indicate == true`)
+    /// of a characteristic, which are then returned by `recv_notification`
+    pub async fn subscribe(
+        &self,
+        conn_id: ConnectionId,
+        characteristic: &GattcCharacteristic,
+        indicate: bool,
+    ) -> Result<(), EspError> {
+        let property = if indicate {
+            Property::Indicate
+        } else {
+            Property::Notify
+        };
+
+        if characteristic.properties & (1 << property as u8) == 0 {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())?;
+        }
+
+        self.set_subscription(
+            conn_id,
+            characteristic,
+            if indicate { 0x0002 } else { 0x0001 },
+        )
+        .await
+    }
+
+    /// Unsubscribe from the notifications and indications of a characteristic
+    pub async fn unsubscribe(
+        &self,
+        conn_id: ConnectionId,
+        characteristic: &GattcCharacteristic,
+    ) -> Result<(), EspError> {
+        self.set_subscription(conn_id, characteristic, 0).await
+    }
+
+    /// Wait for the next notification or indication
+    pub async fn recv_notification(&self) -> Result<GattNotification, EspError> {
+        {
+            let mut state = self.shared.state.lock();
+
+            if state.notifications_busy {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            state.notifications_busy = true;
+        }
+
+        let _guard = BusyGuard(&self.shared, |state| state.notifications_busy = false);
+
+        loop {
+            if let Some(notification) = self.try_recv_notification() {
+                return Ok(notification);
+            }
+
+            self.shared.rx_notification.wait().await;
+        }
+    }
+
+    /// Return the next notification or indication, if one is already queued
+    pub fn try_recv_notification(&self) -> Option<GattNotification> {
+        self.shared.state.lock().notifications.pop_front()
+    }
+
+    /// Return the number of notifications dropped so far because the queue was full
+    pub fn dropped_notifications(&self) -> usize {
+        self.shared.state.lock().notifications_dropped
+    }
+
+    async fn set_subscription(
+        &self,
+        conn_id: ConnectionId,
+        characteristic: &GattcCharacteristic,
+        value: u16,
+    ) -> Result<(), EspError> {
+        let addr = self
+            .connection(conn_id)
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?
+            .addr;
+
+        let cccd = self
+            .descriptors(conn_id, characteristic)?
+            .into_iter()
+            .find(|descriptor| descriptor.uuid == BtUuid::uuid16(CCCD_UUID))
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let handle = characteristic.handle;
+
+        let outcome = self
+            .execute(Pending::Notify(handle), |gattc_if| {
+                if value != 0 {
+                    self.gattc.register_for_notify(gattc_if, addr, handle)
+                } else {
+                    self.gattc.unregister_for_notify(gattc_if, addr, handle)
+                }
+            })
+            .await?;
+
+        Self::to_status(outcome)?;
+
+        self.write_descriptor(conn_id, cccd.handle, &value.to_le_bytes())
+            .await
+    }
+
+    async fn execute<F>(&self, pending: Pending, start: F) -> Result<Outcome, EspError>
+    where
+        F: FnOnce(GattInterface) -> Result<(), EspError>,
+    {
+        {
+            let mut state = self.shared.state.lock();
+
+            if state.busy {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            state.busy = true;
+        }
+
+        let _guard = BusyGuard(&self.shared, |state| {
+            state.busy = false;
+            state.pending = Pending::None;
+            state.outcome = None;
+        });
+
+        // Wait for the registration of the GATTC application to complete
+        let gattc_if = loop {
+            match self.shared.state.lock().registration {
+                Some(Ok(gattc_if)) => break gattc_if,
+                Some(Err(_)) => return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+                None => (),
+            }
+
+            self.shared.op_notification.wait().await;
+        };
+
+        {
+            let mut state = self.shared.state.lock();
+
+            state.pending = pending;
+            state.outcome = None;
+        }
+
+        start(gattc_if)?;
+
+        loop {
+            if let Some(outcome) = self.shared.state.lock().outcome.take() {
+                return Ok(outcome);
+            }
+
+            self.shared.op_notification.wait().await;
+        }
+    }
+
+    fn gattc_if(&self) -> Result<GattInterface, EspError> {
+        match self.shared.state.lock().registration {
+            Some(Ok(gattc_if)) => Ok(gattc_if),
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+        }
+    }
+
+    fn connection(&self, conn_id: ConnectionId) -> Option<GattcConnection> {
+        self.shared
+            .state
+            .lock()
+            .connections
+            .iter()
+            .find(|connection| connection.conn_id == conn_id)
+            .cloned()
+    }
+
+    fn to_value(outcome: Outcome) -> Result<Vec<u8>, EspError> {
+        match outcome {
+            Outcome::Read(value) => Ok(value),
+            outcome => Err(Self::to_err(outcome)),
+        }
+    }
+
+    fn to_status(outcome: Outcome) -> Result<(), EspError> {
+        match outcome {
+            Outcome::Status(GattStatus::Ok) => Ok(()),
+            outcome => Err(Self::to_err(outcome)),
+        }
+    }
+
+    fn to_err(outcome: Outcome) -> EspError {
+        let Outcome::Status(status) = outcome else {
+            return EspError::from_infallible::<ESP_FAIL>();
+        };
+
+        warn!("GATT operation failed: {:?}", status);
+
+        match status {
+            GattStatus::NotFound | GattStatus::InvalidHandle => {
+                EspError::from_infallible::<ESP_ERR_NOT_FOUND>()
+            }
+            GattStatus::ReadNotPermitted
+            | GattStatus::WriteNotPermitted
+            | GattStatus::InsufficientAuthentication
+            | GattStatus::InsufficientAuthorization
+            | GattStatus::InsufficientEncryption => {
+                EspError::from_infallible::<ESP_ERR_NOT_ALLOWED>()
+            }
+            GattStatus::InvalidAttrLen => EspError::from_infallible::<ESP_ERR_INVALID_SIZE>(),
+            GattStatus::ReqNotSupported => EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>(),
+            GattStatus::Busy | GattStatus::Congested => {
+                EspError::from_infallible::<ESP_ERR_INVALID_STATE>()
+            }
+            _ => EspError::from_infallible::<ESP_FAIL>(),
+        }
+    }
+}
+
+impl<'d, M, T> Drop for EspGattClient<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    fn drop(&mut self) {
+        self.gattc.unsubscribe().unwrap();
+
+        if let Some(Ok(gattc_if)) = self.shared.state.lock().registration {
+            let _ = self.gattc.unregister_app(gattc_if);
+        }
+    }
+}
+
+impl Shared {
+    fn on_gattc_event(&self, gattc_if: GattInterface, event: GattcEvent) {
+        let mut state = self.state.lock();
+
+        if let GattcEvent::ClientRegistered { status, app_id } = event {
+            if app_id == state.app_id {
+                state.registration = Some(if status == GattStatus::Ok {
+                    Ok(gattc_if)
+                } else {
+                    Err(status)
+                });
+
+                self.op_notification.notify_lsb();
+            }
+
+            return;
+        }
+
+        if state.registration != Some(Ok(gattc_if)) {
+            return;
+        }
+
+        let completed = match event {
+            GattcEvent::Open {
+                status,
+                conn_id,
+                addr,
+                mtu,
+            } => {
+                if status == GattStatus::Ok {
+                    state
+                        .connections
+                        .retain(|connection| connection.conn_id != conn_id);
+                    state
+                        .connections
+                        .push(GattcConnection { conn_id, addr, mtu });
+
+                    state.complete(Pending::Open(addr), Outcome::Opened(conn_id))
+                } else {
+                    state.complete(Pending::Open(addr), Outcome::Status(status))
+                }
+            }
+            GattcEvent::Disconnected { conn_id, addr, .. }
+            | GattcEvent::Close { conn_id, addr, .. } => {
+                state
+                    .connections
+                    .retain(|connection| connection.conn_id != conn_id);
+
+                let pending = state.pending;
+
+                // Fail the pending operation on the closed connection, if any
+                match pending {
+                    Pending::Open(pending_addr) if pending_addr == addr => {
+                        state.complete(pending, Outcome::Status(GattStatus::Error))
+                    }
+                    Pending::Mtu(id)
+                    | Pending::Search(id)
+                    | Pending::Read(id, _)
+                    | Pending::Write(id, _)
+                        if id == conn_id =>
+                    {
+                        state.complete(pending, Outcome::Status(GattStatus::Error))
+                    }
+                    _ => false,
+                }
+            }
+            GattcEvent::Mtu {
+                status,
+                conn_id,
+                mtu,
+            } => {
+                if status == GattStatus::Ok {
+                    if let Some(connection) = state
+                        .connections
+                        .iter_mut()
+                        .find(|connection| connection.conn_id == conn_id)
+                    {
+                        connection.mtu = mtu;
+                    }
+
+                    state.complete(Pending::Mtu(conn_id), Outcome::Mtu(mtu))
+                } else {
+                    state.complete(Pending::Mtu(conn_id), Outcome::Status(status))
+                }
+            }
+            GattcEvent::SearchResult {
+                conn_id,
+                start_handle,
+                end_handle,
+                srvc_id,
+                is_primary,
+            } => {
+                if state.pending == Pending::Search(conn_id) {
+                    state.services.push(GattcService {
+                        uuid: srvc_id.uuid,
+                        start_handle,
+                        end_handle,
+                        is_primary,
+                    });
+                }
+
+                false
+            }
+            GattcEvent::SearchComplete { status, conn_id } => {
+                state.complete(Pending::Search(conn_id), Outcome::Status(status))
+            }
+            GattcEvent::ReadCharacteristic {
+                status,
+                conn_id,
+                handle,
+                value,
+            }
+            | GattcEvent::ReadDescriptor {
+                status,
+                conn_id,
+                handle,
+                value,
+            } => state.complete(
+                Pending::Read(conn_id, handle),
+                if status == GattStatus::Ok {
+                    Outcome::Read(value.to_vec())
+                } else {
+                    Outcome::Status(status)
+                },
+            ),
+            GattcEvent::WriteCharacteristic {
+                status,
+                conn_id,
+                handle,
+                ..
+            }
+            | GattcEvent::WriteDescriptor {
+                status,
+                conn_id,
+                handle,
+                ..
+            } => state.complete(Pending::Write(conn_id, handle), Outcome::Status(status)),
+            GattcEvent::RegisteredForNotify { status, handle }
+            | GattcEvent::UnregisteredForNotify { status, handle } => {
+                state.complete(Pending::Notify(handle), Outcome::Status(status))
+            }
+            GattcEvent::Notify {
+                conn_id,
+                addr,
+                handle,
+                value,
+                is_notify,
+            } => {
+                if state.notifications.len() < state.notifications_capacity {
+                    state.notifications.push_back(GattNotification {
+                        conn_id,
+                        addr,
+                        handle,
+                        value: value.to_vec(),
+                        is_notify,
+                    });
+
+                    self.rx_notification.notify_lsb();
+                } else {
+                    state.notifications_dropped += 1;
+                }
+
+                false
+            }
+            _ => false,
+        };
+
+        if completed {
+            self.op_notification.notify_lsb();
+        }
+    }
+}
+
+// Resets the state of an operation or of a `recv_notification` call, even if its future is dropped early
+struct BusyGuard<'s, F>(&'s Shared, F)
+where
+    F: Fn(&mut State);
+
+impl<'s, F> Drop for BusyGuard<'s, F>
+where
+    F: Fn(&mut State),
+{
+    fn drop(&mut self) {
+        (self.1)(&mut self.0.state.lock());
+    }
+}
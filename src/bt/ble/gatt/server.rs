@@ -12,6 +12,8 @@ use super::{
     GattResponse, GattServiceId, GattStatus, Handle,
 };
 
+pub mod app;
+
 pub type AppId = u16;
 pub type ConnectionId = u16;
 pub type TransferId = u32;
@@ -0,0 +1,1192 @@
+//! A high-level GATT server
+//!
+//! `EspGattServer` builds on top of `EspGatts` and takes care of the Bluedroid
+//! registration dance: the services are declared upfront as `ServiceDefinition`s,
+//! each of them is then created as a single attribute table once the GATTS application
+//! is registered, and started once created.
+//!
+//! On top of that, the server:
+//! - Dispatches the read and write requests for characteristics and descriptors which are
+//!   responded to by the application (`AutoResponse::ByApp`) to user callbacks, including
+//!   the reassembly of long (prepared) writes
+//! - Adds a Client Characteristic Configuration descriptor to every characteristic with the
+//!   `Notify` or `Indicate` property, and tracks which connected clients subscribed to it
+//! - Tracks the connected clients and the MTU negotiated with each of them
+//!
+//! Note that the server subscribes to the events of the `EspGatts` instance it takes ownership of,
+//! so `EspGatts::subscribe` should not be used in conjunction with it.
+
+use core::borrow::Borrow;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use enumset::EnumSet;
+
+use log::{debug, warn};
+
+use crate::bt::{BdAddr, BleEnabled, BtDriver, BtUuid};
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::super::{
+    AutoResponse, GattCharacteristic, GattConnReason, GattDescriptor, GattInterface, GattResponse,
+    GattStatus, Handle, Permission, Property,
+};
+use super::{AppId, ConnectionId, EspGatts, GattsEvent, TransferId};
+
+/// The default ATT MTU, before a larger one is negotiated by the client
+pub const DEFAULT_MTU: u16 = 23;
+
+static PRIMARY_SERVICE_UUID: [u8; 2] = (ESP_GATT_UUID_PRI_SERVICE as u16).to_le_bytes();
+static SECONDARY_SERVICE_UUID: [u8; 2] = (ESP_GATT_UUID_SEC_SERVICE as u16).to_le_bytes();
+static CHAR_DECLARATION_UUID: [u8; 2] = (ESP_GATT_UUID_CHAR_DECLARE as u16).to_le_bytes();
+static CCCD_UUID: [u8; 2] = (ESP_GATT_UUID_CHAR_CLIENT_CONFIG as u16).to_le_bytes();
+
+const CCCD_NOTIFY: u16 = 0x0001;
+const CCCD_INDICATE: u16 = 0x0002;
+
+static CCCD_DEFAULT: [u8; 2] = [0; 2];
+
+/// A descriptor of a characteristic, along with its initial value
+#[derive(Debug, Clone)]
+pub struct DescriptorDefinition {
+    pub descriptor: GattDescriptor,
+    pub max_len: usize,
+    pub value: Vec<u8>,
+    pub auto_rsp: AutoResponse,
+}
+
+impl DescriptorDefinition {
+    pub fn new(descriptor: GattDescriptor, value: &[u8]) -> Self {
+        Self {
+            descriptor,
+            max_len: value.len(),
+            value: value.into(),
+            auto_rsp: AutoResponse::ByGatt,
+        }
+    }
+
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    pub fn auto_rsp(mut self, auto_rsp: AutoResponse) -> Self {
+        self.auto_rsp = auto_rsp;
+        self
+    }
+}
+
+/// A characteristic of a service, along with its initial value and descriptors
+#[derive(Debug, Clone)]
+pub struct CharacteristicDefinition {
+    pub characteristic: GattCharacteristic,
+    pub value: Vec<u8>,
+    pub descriptors: Vec<DescriptorDefinition>,
+}
+
+impl CharacteristicDefinition {
+    pub fn new(characteristic: GattCharacteristic) -> Self {
+        Self {
+            characteristic,
+            value: Vec::new(),
+            descriptors: Vec::new(),
+        }
+    }
+
+    pub fn value(mut self, value: &[u8]) -> Self {
+        self.value = value.into();
+        self
+    }
+
+    pub fn descriptor(mut self, descriptor: DescriptorDefinition) -> Self {
+        self.descriptors.push(descriptor);
+        self
+    }
+
+    fn needs_cccd(&self) -> bool {
+        !(self.characteristic.properties & (Property::Notify | Property::Indicate)).is_empty()
+            && !self
+                .descriptors
+                .iter()
+                .any(|descriptor| descriptor.descriptor.uuid.as_bytes() == CCCD_UUID)
+    }
+}
+
+/// A service, along with its characteristics
+#[derive(Debug, Clone)]
+pub struct ServiceDefinition {
+    pub uuid: BtUuid,
+    pub is_primary: bool,
+    pub characteristics: Vec<CharacteristicDefinition>,
+}
+
+impl ServiceDefinition {
+    pub fn new(uuid: BtUuid) -> Self {
+        Self {
+            uuid,
+            is_primary: true,
+            characteristics: Vec::new(),
+        }
+    }
+
+    pub fn secondary(mut self) -> Self {
+        self.is_primary = false;
+        self
+    }
+
+    pub fn characteristic(mut self, characteristic: CharacteristicDefinition) -> Self {
+        self.characteristics.push(characteristic);
+        self
+    }
+}
+
+/// A client connected to the server
+#[derive(Debug, Clone)]
+pub struct GattConnection {
+    pub conn_id: ConnectionId,
+    pub addr: BdAddr,
+    /// The MTU negotiated with the client
+    pub mtu: u16,
+}
+
+/// A read request for a characteristic or descriptor responded to by the application
+#[derive(Debug, Clone)]
+pub struct ReadRequest {
+    pub conn_id: ConnectionId,
+    pub addr: BdAddr,
+    pub handle: Handle,
+}
+
+/// A write request for a characteristic or descriptor
+///
+/// Long (prepared) writes are delivered once, after they are executed by the client,
+/// with the complete value.
+#[derive(Debug, Clone)]
+pub struct WriteRequest<'a> {
+    pub conn_id: ConnectionId,
+    pub addr: BdAddr,
+    pub handle: Handle,
+    pub value: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub enum GattServerEvent {
+    /// All services were created and started
+    Started,
+    Connected(GattConnection),
+    Disconnected {
+        conn_id: ConnectionId,
+        addr: BdAddr,
+        reason: GattConnReason,
+    },
+    MtuChanged {
+        conn_id: ConnectionId,
+        mtu: u16,
+    },
+    /// A client changed its subscription for the notifications and/or indications
+    /// of the characteristic with the provided value handle
+    Subscribed {
+        conn_id: ConnectionId,
+        handle: Handle,
+        notify: bool,
+        indicate: bool,
+    },
+    /// An indication was confirmed by the client, or a notification or indication could not be sent
+    Confirmed {
+        conn_id: ConnectionId,
+        handle: Handle,
+        status: GattStatus,
+    },
+}
+
+type ReadCallback = Box<dyn FnMut(&ReadRequest, &mut [u8]) -> Result<usize, GattStatus> + Send>;
+type WriteCallback = Box<dyn FnMut(&WriteRequest) -> Result<(), GattStatus> + Send>;
+type EventCallback = Box<dyn FnMut(&GattServerEvent) + Send>;
+
+#[derive(Copy, Clone, Debug)]
+enum AttributeKind {
+    Service,
+    Declaration,
+    Value,
+    Cccd,
+    Descriptor,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Attribute {
+    kind: AttributeKind,
+    /// Index of the characteristic within the service
+    characteristic: usize,
+    handle: Handle,
+    auto_rsp: AutoResponse,
+}
+
+struct Service {
+    definition: ServiceDefinition,
+    // The characteristic properties, referenced from the characteristic declarations
+    properties: Vec<u8>,
+    db: Vec<esp_gatts_attr_db_t>,
+    attributes: Vec<Attribute>,
+    started: bool,
+}
+
+impl Service {
+    fn new(definition: ServiceDefinition) -> Self {
+        let properties = definition
+            .characteristics
+            .iter()
+            .map(|characteristic| characteristic.characteristic.properties.as_repr())
+            .collect();
+
+        Self {
+            definition,
+            properties,
+            db: Vec::new(),
+            attributes: Vec::new(),
+            started: false,
+        }
+    }
+
+    // All pointers in the attribute table point into `definition` and `properties`,
+    // so the table should only be built once the service is at its final location
+    // and it should not be moved or modified afterwards
+    fn build_db(&mut self) {
+        let Self {
+            definition,
+            properties,
+            db,
+            attributes,
+            ..
+        } = self;
+
+        let mut push = |kind: AttributeKind,
+                        characteristic: usize,
+                        uuid: &[u8],
+                        perm: u16,
+                        max_len: usize,
+                        value: &[u8],
+                        auto_rsp: AutoResponse| {
+            db.push(esp_gatts_attr_db_t {
+                attr_control: esp_attr_control_t {
+                    auto_rsp: auto_rsp as _,
+                },
+                att_desc: esp_attr_desc_t {
+                    uuid_length: uuid.len() as _,
+                    uuid_p: uuid.as_ptr() as *mut _,
+                    perm,
+                    max_length: max_len.max(value.len()) as _,
+                    length: value.len() as _,
+                    value: if value.is_empty() {
+                        core::ptr::null_mut()
+                    } else {
+                        value.as_ptr() as *mut _
+                    },
+                },
+            });
+
+            attributes.push(Attribute {
+                kind,
+                characteristic,
+                handle: 0,
+                auto_rsp,
+            });
+        };
+
+        let read = EnumSet::only(Permission::Read).as_repr();
+
+        push(
+            AttributeKind::Service,
+            0,
+            if definition.is_primary {
+                &PRIMARY_SERVICE_UUID
+            } else {
+                &SECONDARY_SERVICE_UUID
+            },
+            read,
+            0,
+            definition.uuid.as_bytes(),
+            AutoResponse::ByGatt,
+        );
+
+        for (index, characteristic) in definition.characteristics.iter().enumerate() {
+            push(
+                AttributeKind::Declaration,
+                index,
+                &CHAR_DECLARATION_UUID,
+                read,
+                0,
+                core::slice::from_ref(&properties[index]),
+                AutoResponse::ByGatt,
+            );
+
+            push(
+                AttributeKind::Value,
+                index,
+                characteristic.characteristic.uuid.as_bytes(),
+                characteristic.characteristic.permissions.as_repr(),
+                characteristic.characteristic.max_len,
+                &characteristic.value,
+                characteristic.characteristic.auto_rsp,
+            );
+
+            if characteristic.needs_cccd() {
+                push(
+                    AttributeKind::Cccd,
+                    index,
+                    &CCCD_UUID,
+                    (Permission::Read | Permission::Write).as_repr(),
+                    0,
+                    &CCCD_DEFAULT,
+                    AutoResponse::ByApp,
+                );
+            }
+
+            for descriptor in &characteristic.descriptors {
+                let uuid = descriptor.descriptor.uuid.as_bytes();
+
+                // The subscriptions are tracked per client, so CCCDs are always responded to by the server
+                let (kind, auto_rsp) = if uuid == CCCD_UUID {
+                    (AttributeKind::Cccd, AutoResponse::ByApp)
+                } else {
+                    (AttributeKind::Descriptor, descriptor.auto_rsp)
+                };
+
+                push(
+                    kind,
+                    index,
+                    uuid,
+                    descriptor.descriptor.permissions.as_repr(),
+                    descriptor.max_len,
+                    &descriptor.value,
+                    auto_rsp,
+                );
+            }
+        }
+    }
+
+    fn attribute(&self, handle: Handle) -> Option<&Attribute> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.handle == handle && handle != 0)
+    }
+
+    fn value_handle(&self, cccd: &Attribute) -> Handle {
+        self.attributes
+            .iter()
+            .find(|attribute| {
+                matches!(attribute.kind, AttributeKind::Value)
+                    && attribute.characteristic == cccd.characteristic
+            })
+            .map(|attribute| attribute.handle)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Subscription {
+    conn_id: ConnectionId,
+    handle: Handle,
+    flags: u16,
+}
+
+struct PreparedWrite {
+    conn_id: ConnectionId,
+    handle: Handle,
+    data: Vec<u8>,
+}
+
+struct State {
+    app_id: AppId,
+    gatts_if: Option<GattInterface>,
+    services: Vec<Service>,
+    connections: Vec<GattConnection>,
+    subscriptions: Vec<Subscription>,
+    prepared: Vec<PreparedWrite>,
+}
+
+// Safe because the raw pointers in the attribute tables only point into
+// the service definitions, which are owned by the state itself
+unsafe impl Send for State {}
+
+impl State {
+    fn attribute(&self, handle: Handle) -> Option<(&Service, Attribute)> {
+        self.services.iter().find_map(|service| {
+            service
+                .attribute(handle)
+                .map(|attribute| (service, *attribute))
+        })
+    }
+
+    fn mtu(&self, conn_id: ConnectionId) -> u16 {
+        self.connections
+            .iter()
+            .find(|connection| connection.conn_id == conn_id)
+            .map(|connection| connection.mtu)
+            .unwrap_or(DEFAULT_MTU)
+    }
+
+    fn addr(&self, conn_id: ConnectionId) -> Option<BdAddr> {
+        self.connections
+            .iter()
+            .find(|connection| connection.conn_id == conn_id)
+            .map(|connection| connection.addr)
+    }
+
+    fn subscription(&self, conn_id: ConnectionId, handle: Handle) -> u16 {
+        self.subscriptions
+            .iter()
+            .find(|subscription| subscription.conn_id == conn_id && subscription.handle == handle)
+            .map(|subscription| subscription.flags)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Default)]
+struct Callbacks {
+    read: Option<ReadCallback>,
+    write: Option<WriteCallback>,
+    event: Option<EventCallback>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    callbacks: Mutex<Callbacks>,
+}
+
+/// A GATT server, serving a fixed set of services
+pub struct EspGattServer<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    gatts: EspGatts<'d, M, T>,
+    shared: Arc<Shared>,
+}
+
+impl<'d, M, T> EspGattServer<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    /// Create a new GATT server, registering it as the GATTS application `app_id`
+    ///
+    /// The services are created and started asynchronously; `GattServerEvent::Started`
+    /// is delivered once all of them are started.
+    pub fn new(
+        gatts: EspGatts<'d, M, T>,
+        app_id: AppId,
+        services: Vec<ServiceDefinition>,
+    ) -> Result<Self, EspError> {
+        if services.is_empty() {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                app_id,
+                gatts_if: None,
+                services: services.into_iter().map(Service::new).collect(),
+                connections: Vec::new(),
+                subscriptions: Vec::new(),
+                prepared: Vec::new(),
+            }),
+            callbacks: Mutex::new(Callbacks::default()),
+        });
+
+        let handler = shared.clone();
+
+        gatts.subscribe(move |(gatts_if, event)| handler.on_gatts_event(gatts_if, event))?;
+        gatts.register_app(app_id)?;
+
+        Ok(Self { gatts, shared })
+    }
+
+    pub fn gatts(&self) -> &EspGatts<'d, M, T> {
+        &self.gatts
+    }
+
+    /// Set the callback answering the read requests for the attributes responded to by the application
+    ///
+    /// The callback should fill the provided buffer with the complete attribute value and return its length;
+    /// the server takes care of serving the part requested by the client.
+    pub fn on_read<F>(&self, callback: F)
+    where
+        F: FnMut(&ReadRequest, &mut [u8]) -> Result<usize, GattStatus> + Send + 'static,
+    {
+        self.shared.callbacks.lock().read = Some(Box::new(callback));
+    }
+
+    /// Set the callback receiving the write requests for all characteristics and descriptors
+    /// except the Client Characteristic Configuration ones
+    ///
+    /// For attributes responded to by the application, the error returned by the callback
+    /// (if any) is reported to the client.
+    pub fn on_write<F>(&self, callback: F)
+    where
+        F: FnMut(&WriteRequest) -> Result<(), GattStatus> + Send + 'static,
+    {
+        self.shared.callbacks.lock().write = Some(Box::new(callback));
+    }
+
+    /// Set the callback receiving the connection, MTU and subscription events
+    pub fn on_event<F>(&self, callback: F)
+    where
+        F: FnMut(&GattServerEvent) + Send + 'static,
+    {
+        self.shared.callbacks.lock().event = Some(Box::new(callback));
+    }
+
+    /// Return `true` once all services are created and started
+    pub fn is_started(&self) -> bool {
+        let state = self.shared.state.lock();
+
+        state.gatts_if.is_some() && state.services.iter().all(|service| service.started)
+    }
+
+    /// Return the handle of the value of a characteristic, once its service is created
+    pub fn handle(&self, service_uuid: &BtUuid, char_uuid: &BtUuid) -> Option<Handle> {
+        let state = self.shared.state.lock();
+
+        let service = state
+            .services
+            .iter()
+            .find(|service| &service.definition.uuid == service_uuid)?;
+
+        let index = service
+            .definition
+            .characteristics
+            .iter()
+            .position(|characteristic| &characteristic.characteristic.uuid == char_uuid)?;
+
+        service
+            .attributes
+            .iter()
+            .find(|attribute| {
+                matches!(attribute.kind, AttributeKind::Value) && attribute.characteristic == index
+            })
+            .map(|attribute| attribute.handle)
+            .filter(|handle| *handle != 0)
+    }
+
+    /// Get the value of an attribute responded to by the stack (`AutoResponse::ByGatt`)
+    pub fn get_value(&self, handle: Handle, buf: &mut [u8]) -> Result<usize, EspError> {
+        self.gatts.get_attr(handle, buf)
+    }
+
+    /// Set the value of an attribute responded to by the stack (`AutoResponse::ByGatt`)
+    pub fn set_value(&self, handle: Handle, data: &[u8]) -> Result<(), EspError> {
+        self.gatts.set_attr(handle, data)
+    }
+
+    /// Return the currently connected clients
+    pub fn connections(&self) -> Vec<GattConnection> {
+        self.shared.state.lock().connections.clone()
+    }
+
+    /// Return the MTU negotiated with a connected client
+    pub fn mtu(&self, conn_id: ConnectionId) -> Option<u16> {
+        let state = self.shared.state.lock();
+
+        state.addr(conn_id).map(|_| state.mtu(conn_id))
+    }
+
+    /// Set the MTU which is offered to the clients during the MTU exchange
+    pub fn set_local_mtu(&self, mtu: u16) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gatt_set_local_mtu(mtu) })
+    }
+
+    /// Return `true` if the client subscribed to the notifications (`indicate == false`)
+    /// or indications (`indicate == true`) of the characteristic with the provided value handle
+    pub fn is_subscribed(&self, conn_id: ConnectionId, handle: Handle, indicate: bool) -> bool {
+        let flags = self.shared.state.lock().subscription(conn_id, handle);
+
+        flags & if indicate { CCCD_INDICATE } else { CCCD_NOTIFY } != 0
+    }
+
+    /// Notify all clients subscribed to the notifications of the characteristic with the provided value handle
+    ///
+    /// Returns the number of clients notified.
+    pub fn notify(&self, handle: Handle, data: &[u8]) -> Result<usize, EspError> {
+        self.send_to_subscribed(handle, data, false)
+    }
+
+    /// Send an indication to all clients subscribed to the indications of the characteristic
+    /// with the provided value handle
+    ///
+    /// Each client confirms the indication with a `GattServerEvent::Confirmed` event, and
+    /// the next indication to that client should only be sent afterwards.
+    ///
+    /// Returns the number of clients indicated.
+    pub fn indicate(&self, handle: Handle, data: &[u8]) -> Result<usize, EspError> {
+        self.send_to_subscribed(handle, data, true)
+    }
+
+    /// Notify a single client, regardless of its subscription
+    pub fn notify_conn(
+        &self,
+        conn_id: ConnectionId,
+        handle: Handle,
+        data: &[u8],
+    ) -> Result<(), EspError> {
+        let (gatts_if, mtu) = {
+            let state = self.shared.state.lock();
+
+            (Self::gatts_if(&state)?, state.mtu(conn_id))
+        };
+
+        Self::check_len(data, mtu)?;
+
+        self.gatts.notify(gatts_if, conn_id, handle, data)
+    }
+
+    /// Send an indication to a single client, regardless of its subscription
+    pub fn indicate_conn(
+        &self,
+        conn_id: ConnectionId,
+        handle: Handle,
+        data: &[u8],
+    ) -> Result<(), EspError> {
+        let (gatts_if, mtu) = {
+            let state = self.shared.state.lock();
+
+            (Self::gatts_if(&state)?, state.mtu(conn_id))
+        };
+
+        Self::check_len(data, mtu)?;
+
+        self.gatts.indicate(gatts_if, conn_id, handle, data)
+    }
+
+    /// Disconnect a client
+    pub fn disconnect(&self, conn_id: ConnectionId) -> Result<(), EspError> {
+        let gatts_if = Self::gatts_if(&self.shared.state.lock())?;
+
+        esp!(unsafe { esp_ble_gatts_close(gatts_if, conn_id) })
+    }
+
+    fn send_to_subscribed(
+        &self,
+        handle: Handle,
+        data: &[u8],
+        indicate: bool,
+    ) -> Result<usize, EspError> {
+        let flag = if indicate { CCCD_INDICATE } else { CCCD_NOTIFY };
+
+        let (gatts_if, targets) = {
+            let state = self.shared.state.lock();
+
+            let targets: Vec<_> = state
+                .subscriptions
+                .iter()
+                .filter(|subscription| {
+                    subscription.handle == handle && subscription.flags & flag != 0
+                })
+                .map(|subscription| (subscription.conn_id, state.mtu(subscription.conn_id)))
+                .collect();
+
+            (Self::gatts_if(&state)?, targets)
+        };
+
+        for (_, mtu) in &targets {
+            Self::check_len(data, *mtu)?;
+        }
+
+        for (conn_id, _) in &targets {
+            if indicate {
+                self.gatts.indicate(gatts_if, *conn_id, handle, data)?;
+            } else {
+                self.gatts.notify(gatts_if, *conn_id, handle, data)?;
+            }
+        }
+
+        Ok(targets.len())
+    }
+
+    fn gatts_if(state: &State) -> Result<GattInterface, EspError> {
+        state
+            .gatts_if
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    }
+
+    fn check_len(data: &[u8], mtu: u16) -> Result<(), EspError> {
+        if data.len() + 3 > mtu as usize {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'d, M, T> Drop for EspGattServer<'d, M, T>
+where
+    T: Borrow<BtDriver<'d, M>>,
+    M: BleEnabled,
+{
+    fn drop(&mut self) {
+        self.gatts.unsubscribe().unwrap();
+
+        if let Some(gatts_if) = self.shared.state.lock().gatts_if {
+            let _ = self.gatts.unregister_app(gatts_if);
+        }
+    }
+}
+
+impl Shared {
+    fn on_gatts_event(&self, gatts_if: GattInterface, event: GattsEvent) {
+        if let GattsEvent::ServiceRegistered { status, app_id } = event {
+            self.on_registered(gatts_if, status, app_id);
+            return;
+        }
+
+        if self.state.lock().gatts_if != Some(gatts_if) {
+            return;
+        }
+
+        let event = match event {
+            GattsEvent::Read {
+                conn_id,
+                trans_id,
+                addr,
+                handle,
+                offset,
+                need_rsp,
+                ..
+            } => {
+                if need_rsp {
+                    self.read(
+                        gatts_if,
+                        ReadRequest {
+                            conn_id,
+                            addr,
+                            handle,
+                        },
+                        trans_id,
+                        offset,
+                    );
+                }
+
+                None
+            }
+            GattsEvent::Write {
+                conn_id,
+                trans_id,
+                addr,
+                handle,
+                offset,
+                need_rsp,
+                is_prep,
+                value,
+            } => {
+                let request = WriteRequest {
+                    conn_id,
+                    addr,
+                    handle,
+                    value,
+                };
+
+                let (status, event) = self.write(&request, offset, is_prep);
+
+                if need_rsp {
+                    if is_prep {
+                        // Prepared writes are acknowledged by echoing the received value
+                        let mut response = GattResponse::new();
+                        response.attr_handle(handle).offset(offset);
+
+                        let status = if status == GattStatus::Ok && response.value(value).is_err() {
+                            GattStatus::InvalidAttrLen
+                        } else {
+                            status
+                        };
+
+                        Self::respond(gatts_if, conn_id, trans_id, status, Some(&response));
+                    } else {
+                        Self::respond(gatts_if, conn_id, trans_id, status, None);
+                    }
+                }
+
+                event
+            }
+            GattsEvent::ExecWrite {
+                conn_id,
+                trans_id,
+                addr,
+                canceled,
+            } => {
+                let prepared = {
+                    let mut state = self.state.lock();
+
+                    state
+                        .prepared
+                        .iter()
+                        .position(|prepared| prepared.conn_id == conn_id)
+                        .map(|index| state.prepared.swap_remove(index))
+                };
+
+                let status = match prepared {
+                    Some(prepared) if !canceled => self.call_write(&WriteRequest {
+                        conn_id,
+                        addr,
+                        handle: prepared.handle,
+                        value: &prepared.data,
+                    }),
+                    _ => GattStatus::Ok,
+                };
+
+                Self::respond(gatts_if, conn_id, trans_id, status, None);
+
+                None
+            }
+            event => self.update(event),
+        };
+
+        if let Some(event) = event {
+            debug!("Server event {:?}", event);
+
+            if let Some(callback) = self.callbacks.lock().event.as_mut() {
+                callback(&event);
+            }
+        }
+    }
+
+    fn on_registered(&self, gatts_if: GattInterface, status: GattStatus, app_id: AppId) {
+        let mut state = self.state.lock();
+
+        if app_id != state.app_id {
+            return;
+        }
+
+        if status != GattStatus::Ok {
+            warn!(
+                "Registering GATTS application {} failed: {:?}",
+                app_id, status
+            );
+            return;
+        }
+
+        state.gatts_if = Some(gatts_if);
+
+        for (index, service) in state.services.iter_mut().enumerate() {
+            if service.db.is_empty() {
+                service.build_db();
+            }
+
+            let result = esp!(unsafe {
+                esp_ble_gatts_create_attr_tab(
+                    service.db.as_ptr(),
+                    gatts_if,
+                    service.db.len() as _,
+                    index as _,
+                )
+            });
+
+            if let Err(err) = result {
+                warn!(
+                    "Creating service {:?} failed: {}",
+                    service.definition.uuid, err
+                );
+            }
+        }
+    }
+
+    fn update(&self, event: GattsEvent) -> Option<GattServerEvent> {
+        let mut state = self.state.lock();
+
+        match event {
+            GattsEvent::AttributeTableCreated {
+                status,
+                svc_inst_id,
+                handles,
+                ..
+            } => {
+                let service = state.services.get_mut(svc_inst_id as usize)?;
+
+                if status != GattStatus::Ok || handles.len() != service.attributes.len() {
+                    warn!(
+                        "Creating service {:?} failed: {:?}",
+                        service.definition.uuid, status
+                    );
+                    return None;
+                }
+
+                for (attribute, handle) in service.attributes.iter_mut().zip(handles) {
+                    attribute.handle = *handle;
+                }
+
+                if let Err(err) = esp!(unsafe { esp_ble_gatts_start_service(handles[0]) }) {
+                    warn!(
+                        "Starting service {:?} failed: {}",
+                        service.definition.uuid, err
+                    );
+                }
+
+                None
+            }
+            GattsEvent::ServiceStarted {
+                status: GattStatus::Ok,
+                service_handle,
+            } => {
+                let service = state
+                    .services
+                    .iter_mut()
+                    .find(|service| service.attributes[0].handle == service_handle)?;
+
+                service.started = true;
+
+                state
+                    .services
+                    .iter()
+                    .all(|service| service.started)
+                    .then_some(GattServerEvent::Started)
+            }
+            GattsEvent::PeerConnected { conn_id, addr, .. } => {
+                let connection = GattConnection {
+                    conn_id,
+                    addr,
+                    mtu: DEFAULT_MTU,
+                };
+
+                state
+                    .connections
+                    .retain(|connection| connection.conn_id != conn_id);
+                state.connections.push(connection.clone());
+
+                Some(GattServerEvent::Connected(connection))
+            }
+            GattsEvent::PeerDisconnected {
+                conn_id,
+                addr,
+                reason,
+            } => {
+                state
+                    .connections
+                    .retain(|connection| connection.conn_id != conn_id);
+                state
+                    .subscriptions
+                    .retain(|subscription| subscription.conn_id != conn_id);
+                state
+                    .prepared
+                    .retain(|prepared| prepared.conn_id != conn_id);
+
+                Some(GattServerEvent::Disconnected {
+                    conn_id,
+                    addr,
+                    reason,
+                })
+            }
+            GattsEvent::Mtu { conn_id, mtu } => {
+                if let Some(connection) = state
+                    .connections
+                    .iter_mut()
+                    .find(|connection| connection.conn_id == conn_id)
+                {
+                    connection.mtu = mtu;
+                }
+
+                Some(GattServerEvent::MtuChanged { conn_id, mtu })
+            }
+            GattsEvent::Confirm {
+                status,
+                conn_id,
+                handle,
+                ..
+            } => Some(GattServerEvent::Confirmed {
+                conn_id,
+                handle,
+                status,
+            }),
+            _ => None,
+        }
+    }
+
+    fn read(
+        &self,
+        gatts_if: GattInterface,
+        request: ReadRequest,
+        trans_id: TransferId,
+        offset: u16,
+    ) {
+        let (attribute, mtu, cccd_value) = {
+            let state = self.state.lock();
+
+            let attribute = state.attribute(request.handle);
+            let cccd_value = attribute
+                .map(|(service, attribute)| {
+                    state.subscription(request.conn_id, service.value_handle(&attribute))
+                })
+                .unwrap_or(0);
+
+            (
+                attribute.map(|(_, attribute)| attribute),
+                state.mtu(request.conn_id),
+                cccd_value,
+            )
+        };
+
+        let mut value = [0; ESP_GATT_MAX_ATTR_LEN as usize];
+
+        let result = match attribute {
+            None => Err(GattStatus::InvalidHandle),
+            Some(attribute) if matches!(attribute.kind, AttributeKind::Cccd) => {
+                value[..2].copy_from_slice(&cccd_value.to_le_bytes());
+                Ok(2)
+            }
+            Some(attribute) if attribute.auto_rsp == AutoResponse::ByGatt => {
+                Err(GattStatus::ReadNotPermitted)
+            }
+            Some(_) => {
+                let mut callbacks = self.callbacks.lock();
+
+                if let Some(read) = callbacks.read.as_mut() {
+                    read(&request, &mut value).map(|len| len.min(value.len()))
+                } else {
+                    Err(GattStatus::ReadNotPermitted)
+                }
+            }
+        };
+
+        match result {
+            Ok(len) if offset as usize > len => Self::respond(
+                gatts_if,
+                request.conn_id,
+                trans_id,
+                GattStatus::InvalidOffset,
+                None,
+            ),
+            Ok(len) => {
+                // A read response carries at most MTU - 1 bytes, the client reads the rest with a larger offset
+                let end = len.min(offset as usize + mtu as usize - 1);
+
+                let mut response = GattResponse::new();
+                response.attr_handle(request.handle).offset(offset);
+                let _ = response.value(&value[offset as usize..end]);
+
+                Self::respond(
+                    gatts_if,
+                    request.conn_id,
+                    trans_id,
+                    GattStatus::Ok,
+                    Some(&response),
+                );
+            }
+            Err(status) => Self::respond(gatts_if, request.conn_id, trans_id, status, None),
+        }
+    }
+
+    fn write(
+        &self,
+        request: &WriteRequest,
+        offset: u16,
+        is_prep: bool,
+    ) -> (GattStatus, Option<GattServerEvent>) {
+        let mut state = self.state.lock();
+
+        let Some((service, attribute)) = state.attribute(request.handle) else {
+            return (GattStatus::InvalidHandle, None);
+        };
+
+        if matches!(attribute.kind, AttributeKind::Cccd) {
+            if is_prep || offset != 0 || request.value.len() != 2 {
+                return (GattStatus::InvalidAttrLen, None);
+            }
+
+            let handle = service.value_handle(&attribute);
+            let flags = u16::from_le_bytes([request.value[0], request.value[1]]);
+
+            state.subscriptions.retain(|subscription| {
+                subscription.conn_id != request.conn_id || subscription.handle != handle
+            });
+
+            if flags != 0 {
+                state.subscriptions.push(Subscription {
+                    conn_id: request.conn_id,
+                    handle,
+                    flags,
+                });
+            }
+
+            (
+                GattStatus::Ok,
+                Some(GattServerEvent::Subscribed {
+                    conn_id: request.conn_id,
+                    handle,
+                    notify: flags & CCCD_NOTIFY != 0,
+                    indicate: flags & CCCD_INDICATE != 0,
+                }),
+            )
+        } else if is_prep {
+            (Self::prepare(&mut state, request, offset), None)
+        } else {
+            drop(state);
+
+            (self.call_write(request), None)
+        }
+    }
+
+    fn call_write(&self, request: &WriteRequest) -> GattStatus {
+        let mut callbacks = self.callbacks.lock();
+
+        match callbacks.write.as_mut().map(|write| write(request)) {
+            Some(Err(status)) => status,
+            _ => GattStatus::Ok,
+        }
+    }
+
+    fn prepare(state: &mut State, request: &WriteRequest, offset: u16) -> GattStatus {
+        let index = state
+            .prepared
+            .iter()
+            .position(|prepared| prepared.conn_id == request.conn_id);
+
+        let prepared = match index {
+            Some(index) => &mut state.prepared[index],
+            None => {
+                state.prepared.push(PreparedWrite {
+                    conn_id: request.conn_id,
+                    handle: request.handle,
+                    data: Vec::new(),
+                });
+
+                state.prepared.last_mut().unwrap()
+            }
+        };
+
+        if prepared.handle != request.handle {
+            GattStatus::ReqNotSupported
+        } else if offset as usize != prepared.data.len() {
+            GattStatus::InvalidOffset
+        } else if prepared.data.len() + request.value.len() > ESP_GATT_MAX_ATTR_LEN as usize {
+            GattStatus::InvalidAttrLen
+        } else {
+            prepared.data.extend_from_slice(request.value);
+
+            GattStatus::Ok
+        }
+    }
+
+    fn respond(
+        gatts_if: GattInterface,
+        conn_id: ConnectionId,
+        trans_id: TransferId,
+        status: GattStatus,
+        response: Option<&GattResponse>,
+    ) {
+        let result = esp!(unsafe {
+            esp_ble_gatts_send_response(
+                gatts_if,
+                conn_id,
+                trans_id,
+                status as _,
+                response
+                    .map(|response| &response.0 as *const _)
+                    .unwrap_or(core::ptr::null()) as *mut _,
+            )
+        });
+
+        if let Err(err) = result {
+            warn!("Sending GATT response failed: {}", err);
+        }
+    }
+}
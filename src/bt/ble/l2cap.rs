@@ -0,0 +1,697 @@
+//! BLE L2CAP connection-oriented channels (LE CoC), over NimBLE
+//!
+//! LE credit-based connection-oriented channels carry SDUs of up to `mtu` bytes between two
+//! connected devices, with a much higher throughput than GATT writes and notifications.
+//!
+//! `EspBleL2cap::connect` opens a channel to the PSM of a peer, over an existing connection
+//! identified by its NimBLE connection handle, and `EspBleL2cap::listen` accepts the channels
+//! opened to a local PSM. Both yield a `BleL2capChannel`, implementing the `embedded_svc::io`
+//! `Read` and `Write` traits:
+//!
+//! ```ignore
+//! let l2cap = EspBleL2cap::new(&bt)?;
+//!
+//! let server = l2cap.listen(0x0080, &Default::default())?;
+//!
+//! let mut channel = server.accept(None)?;
+//! channel.write_all(b"Hello")?;
+//! ```
+//!
+//! The flow control is credit-based: the peer can only send an SDU once it got credits, which
+//! NimBLE gives whenever a receive buffer is provided. A channel provides a new buffer after each
+//! SDU received, as long as the data not read yet is below `rx_buffer_len`, so that a slow reader
+//! throttles the peer. Conversely, `write` blocks while the peer gives no credits.
+//!
+//! Requires `CONFIG_BT_NIMBLE_L2CAP_COC_MAX_NUM` to be at least 1, or channels fail to open with
+//! `ESP_ERR_NOT_SUPPORTED`. Bluedroid does not expose LE CoC; see `bt::l2cap` for its Bluetooth
+//! Classic channels.
+
+use core::borrow::Borrow;
+use core::ffi::{c_int, c_void};
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+use core::ptr;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use embedded_svc::io::{ErrorType, Read, Write};
+
+use log::debug;
+
+use crate::bt::{BleEnabled, BtDriver};
+use crate::io::EspIOError;
+use crate::private::mutex::{Condvar, Mutex, MutexGuard};
+use crate::sys::*;
+
+/// A Protocol/Service Multiplexer; the dynamic LE PSMs range from 0x0080 to 0x00ff
+pub type Psm = u16;
+
+/// The configuration of the channels
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BleL2capConfiguration {
+    /// The largest SDU this device accepts
+    pub mtu: u16,
+    /// How much data received but not read yet is buffered before the peer gets no credits
+    pub rx_buffer_len: usize,
+}
+
+impl BleL2capConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            mtu: 512,
+            rx_buffer_len: 4096,
+        }
+    }
+}
+
+impl Default for BleL2capConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct EspBleL2cap<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    _driver: T,
+    _p: PhantomData<&'d ()>,
+    _m: PhantomData<M>,
+}
+
+impl<'d, M, T> EspBleL2cap<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    pub fn new(driver: T) -> Result<Self, EspError> {
+        Ok(Self {
+            _driver: driver,
+            _p: PhantomData,
+            _m: PhantomData,
+        })
+    }
+
+    /// Open a channel to the PSM of the peer of the connection `conn_handle`, waiting up to
+    /// `timeout` for the peer to accept it
+    pub fn connect(
+        &self,
+        conn_handle: u16,
+        psm: Psm,
+        conf: &BleL2capConfiguration,
+        timeout: Duration,
+    ) -> Result<BleL2capChannel, EspError> {
+        let channel = Arc::new(Channel::new(conf.clone()));
+
+        let sdu = unsafe { os_msys_get_pkthdr(conf.mtu, 0) };
+        if sdu.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        // Released by `client_event`, once the channel failed to open or is closed
+        let arg = Arc::into_raw(channel.clone());
+
+        // On failure, the receive buffer is freed by NimBLE along with the channel
+        let rc = unsafe {
+            ble_l2cap_connect(
+                conn_handle,
+                psm,
+                conf.mtu,
+                sdu,
+                Some(client_event),
+                arg as *mut _,
+            )
+        };
+
+        if rc != 0 {
+            drop(unsafe { Arc::from_raw(arg) });
+            return Err(error(rc));
+        }
+
+        let mut state = channel.state.lock();
+
+        while state.status.is_none() {
+            let (guard, timed_out) = channel.condvar.wait_timeout(state, timeout);
+            state = guard;
+
+            if timed_out && state.status.is_none() {
+                // Closed as soon as it opens
+                state.abandoned = true;
+
+                return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+            }
+        }
+
+        check(state.status.unwrap())?;
+
+        drop(state);
+
+        Ok(BleL2capChannel(channel))
+    }
+
+    /// Accept the channels opened to the local PSM
+    pub fn listen(
+        &self,
+        psm: Psm,
+        conf: &BleL2capConfiguration,
+    ) -> Result<BleL2capServer, EspError> {
+        let mut servers = SERVERS.lock();
+
+        if let Some((_, server)) = servers.iter().find(|(server_psm, _)| *server_psm == psm) {
+            let mut state = server.state.lock();
+
+            if state.listening {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            state.listening = true;
+            state.conf = conf.clone();
+
+            return Ok(BleL2capServer(server));
+        }
+
+        // NimBLE can not remove a server, so it is kept - and reused - once registered
+        let server: &'static Server = Box::leak(Box::new(Server {
+            state: Mutex::new(ServerState {
+                listening: true,
+                conf: conf.clone(),
+                channels: Vec::new(),
+                accepted: VecDeque::new(),
+            }),
+            condvar: Condvar::new(),
+        }));
+
+        let rc = unsafe {
+            ble_l2cap_create_server(
+                psm,
+                conf.mtu,
+                Some(server_event),
+                server as *const _ as *mut _,
+            )
+        };
+
+        if rc != 0 {
+            drop(unsafe { Box::from_raw(server as *const _ as *mut Server) });
+            return Err(error(rc));
+        }
+
+        servers.push((psm, server));
+
+        Ok(BleL2capServer(server))
+    }
+}
+
+unsafe impl<'d, M, T> Send for EspBleL2cap<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+// Safe because the NimBLE host APIs lock the host
+unsafe impl<'d, M, T> Sync for EspBleL2cap<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+/// A local PSM accepting channels
+///
+/// Once dropped, the channels opened to the PSM are refused.
+pub struct BleL2capServer(&'static Server);
+
+impl BleL2capServer {
+    /// Wait for a channel to be opened, up to `timeout` if any
+    pub fn accept(&self, timeout: Option<Duration>) -> Result<BleL2capChannel, EspError> {
+        let mut state = self.0.state.lock();
+
+        loop {
+            if let Some(channel) = state.accepted.pop_front() {
+                return Ok(BleL2capChannel(channel));
+            }
+
+            if let Some(timeout) = timeout {
+                let (guard, timed_out) = self.0.condvar.wait_timeout(state, timeout);
+                state = guard;
+
+                if timed_out && state.accepted.is_empty() {
+                    return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+                }
+            } else {
+                state = self.0.condvar.wait(state);
+            }
+        }
+    }
+}
+
+impl Drop for BleL2capServer {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+
+        state.listening = false;
+
+        // Close the channels opened but not accepted
+        for channel in state.accepted.drain(..) {
+            drop(BleL2capChannel(channel));
+        }
+    }
+}
+
+impl Debug for BleL2capServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BleL2capServer").finish_non_exhaustive()
+    }
+}
+
+/// An open LE L2CAP channel
+///
+/// The channel is closed when dropped.
+pub struct BleL2capChannel(Arc<Channel>);
+
+impl BleL2capChannel {
+    /// The largest SDU the peer accepts, i.e. the most data sent by a single `write`
+    pub fn peer_mtu(&self) -> u16 {
+        self.0.state.lock().peer_mtu
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.state.lock().closed
+    }
+
+    /// Read the data received on the channel, blocking until some data is available
+    ///
+    /// Returns 0 once the channel is closed.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.0.state.lock();
+
+        while state.rx.is_empty() && !state.closed {
+            state = self.0.condvar.wait(state);
+        }
+
+        let len = buf.len().min(state.rx.len());
+
+        for (dst, src) in buf.iter_mut().zip(state.rx.drain(..len)) {
+            *dst = src;
+        }
+
+        // Give credits to the peer again, if they were withheld
+        if state.rx_starved && !state.closed && state.rx.len() < self.0.conf.rx_buffer_len {
+            self.0.provide_rx(&mut state);
+        }
+
+        Ok(len)
+    }
+
+    /// Send one SDU with up to `peer_mtu` bytes of `buf`, blocking while the peer gives no
+    /// credits
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut state = self.0.wait_unstalled(self.0.state.lock())?;
+
+        let len = buf.len().min(state.peer_mtu as usize);
+
+        let sdu = unsafe { os_msys_get_pkthdr(len as _, 0) };
+        if sdu.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        if unsafe { os_mbuf_append(sdu, buf.as_ptr() as *const _, len as _) } != 0 {
+            unsafe { os_mbuf_free_chain(sdu) };
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        loop {
+            match unsafe { ble_l2cap_send(state.chan, sdu) } as u32 {
+                0 => break,
+                // Queued, but the next SDU has to wait for more credits
+                BLE_HS_ESTALLED => {
+                    state.stalled = true;
+                    break;
+                }
+                // Not queued, as the previous SDU is still waiting for credits
+                BLE_HS_EBUSY => {
+                    state.stalled = true;
+
+                    state = match self.0.wait_unstalled(state) {
+                        Ok(state) => state,
+                        Err(err) => {
+                            unsafe { os_mbuf_free_chain(sdu) };
+                            return Err(err);
+                        }
+                    };
+                }
+                rc => {
+                    unsafe { os_mbuf_free_chain(sdu) };
+                    return Err(error(rc as _));
+                }
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+impl Drop for BleL2capChannel {
+    fn drop(&mut self) {
+        let state = self.0.state.lock();
+
+        if !state.closed && !state.chan.is_null() {
+            // The channel is released once NimBLE reports it as closed
+            unsafe { ble_l2cap_disconnect(state.chan) };
+        }
+    }
+}
+
+impl Debug for BleL2capChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BleL2capChannel").finish_non_exhaustive()
+    }
+}
+
+impl ErrorType for BleL2capChannel {
+    type Error = EspIOError;
+}
+
+impl Read for BleL2capChannel {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let size = BleL2capChannel::read(self, buf)?;
+
+        Ok(size)
+    }
+}
+
+impl Write for BleL2capChannel {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let size = BleL2capChannel::write(self, buf)?;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+struct State {
+    chan: *mut ble_l2cap_chan,
+    /// The status of the opening, once reported
+    status: Option<c_int>,
+    peer_mtu: u16,
+    closed: bool,
+    /// Whether the peer gave no credits for the next SDU
+    stalled: bool,
+    rx: VecDeque<u8>,
+    /// Whether NimBLE has no receive buffer, so that the peer gets no credits
+    rx_starved: bool,
+    /// Whether the opening timed out, so that the channel is closed once open
+    abandoned: bool,
+}
+
+struct Channel {
+    state: Mutex<State>,
+    condvar: Condvar,
+    conf: BleL2capConfiguration,
+}
+
+unsafe impl Send for State {}
+
+impl Channel {
+    fn new(conf: BleL2capConfiguration) -> Self {
+        Self {
+            state: Mutex::new(State {
+                chan: ptr::null_mut(),
+                status: None,
+                peer_mtu: 0,
+                closed: false,
+                stalled: false,
+                rx: VecDeque::new(),
+                rx_starved: false,
+                abandoned: false,
+            }),
+            condvar: Condvar::new(),
+            conf,
+        }
+    }
+
+    /// Give NimBLE a receive buffer, i.e. credits to the peer
+    fn provide_rx(&self, state: &mut State) -> c_int {
+        let sdu = unsafe { os_msys_get_pkthdr(self.conf.mtu, 0) };
+        if sdu.is_null() {
+            state.rx_starved = true;
+            return BLE_HS_ENOMEM as _;
+        }
+
+        let rc = unsafe { ble_l2cap_recv_ready(state.chan, sdu) };
+
+        if rc != 0 {
+            unsafe { os_mbuf_free_chain(sdu) };
+        }
+
+        state.rx_starved = rc != 0;
+
+        rc
+    }
+
+    fn wait_unstalled<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, State>,
+    ) -> Result<MutexGuard<'a, State>, EspError> {
+        loop {
+            if state.closed || state.chan.is_null() {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+            }
+
+            if !state.stalled {
+                return Ok(state);
+            }
+
+            state = self.condvar.wait(state);
+        }
+    }
+
+    fn connected(&self, status: c_int, chan: *mut ble_l2cap_chan) {
+        let mut state = self.state.lock();
+
+        state.chan = chan;
+        state.status = Some(status);
+
+        if status == 0 {
+            let mut info: ble_l2cap_chan_info = Default::default();
+
+            if unsafe { ble_l2cap_get_chan_info(chan, &mut info) } == 0 {
+                state.peer_mtu = info.peer_coc_mtu;
+            }
+
+            if state.abandoned {
+                unsafe { ble_l2cap_disconnect(chan) };
+            }
+        } else {
+            state.closed = true;
+        }
+
+        self.condvar.notify_all();
+    }
+
+    fn disconnected(&self) {
+        let mut state = self.state.lock();
+
+        state.closed = true;
+        state.chan = ptr::null_mut();
+
+        self.condvar.notify_all();
+    }
+
+    fn received(&self, sdu: *mut os_mbuf) {
+        let mut state = self.state.lock();
+
+        let len = unsafe { os_mbuf_len(sdu) } as usize;
+        let mut data = alloc::vec![0_u8; len];
+
+        if unsafe { os_mbuf_copydata(sdu, 0, len as _, data.as_mut_ptr() as *mut _) } == 0 {
+            state.rx.extend(data);
+        }
+
+        unsafe { os_mbuf_free_chain(sdu) };
+
+        if state.rx.len() < self.conf.rx_buffer_len {
+            self.provide_rx(&mut state);
+        } else {
+            state.rx_starved = true;
+        }
+
+        self.condvar.notify_all();
+    }
+
+    fn unstalled(&self) {
+        self.state.lock().stalled = false;
+
+        self.condvar.notify_all();
+    }
+
+    /// Handle the events which do not open or close the channel
+    fn event(&self, event: &ble_l2cap_event) {
+        match event.type_ as u32 {
+            BLE_L2CAP_EVENT_COC_DATA_RECEIVED => {
+                self.received(unsafe { event.__bindgen_anon_1.receive.sdu_rx })
+            }
+            BLE_L2CAP_EVENT_COC_TX_UNSTALLED => self.unstalled(),
+            other => debug!("Ignoring L2CAP event {other}"),
+        }
+    }
+}
+
+struct ServerState {
+    listening: bool,
+    conf: BleL2capConfiguration,
+    /// The channels opened to the PSM, by NimBLE channel
+    channels: Vec<(usize, Arc<Channel>)>,
+    /// The channels opened but not accepted yet
+    accepted: VecDeque<Arc<Channel>>,
+}
+
+struct Server {
+    state: Mutex<ServerState>,
+    condvar: Condvar,
+}
+
+static SERVERS: Mutex<Vec<(Psm, &'static Server)>> = Mutex::new(Vec::new());
+
+unsafe extern "C" fn client_event(event: *mut ble_l2cap_event, arg: *mut c_void) -> c_int {
+    let event = unsafe { event.as_ref() }.unwrap();
+    let channel = arg as *const Channel;
+
+    match event.type_ as u32 {
+        BLE_L2CAP_EVENT_COC_CONNECTED => {
+            let connect = unsafe { event.__bindgen_anon_1.connect };
+
+            (*channel).connected(connect.status, connect.chan);
+
+            // No other event follows a failed opening
+            if connect.status != 0 {
+                drop(Arc::from_raw(channel));
+            }
+        }
+        BLE_L2CAP_EVENT_COC_DISCONNECTED => {
+            (*channel).disconnected();
+
+            drop(Arc::from_raw(channel));
+        }
+        _ => (*channel).event(event),
+    }
+
+    0
+}
+
+unsafe extern "C" fn server_event(event: *mut ble_l2cap_event, arg: *mut c_void) -> c_int {
+    let event = unsafe { event.as_ref() }.unwrap();
+    let server = (arg as *const Server).as_ref().unwrap();
+
+    let mut state = server.state.lock();
+
+    let find = |state: &ServerState, chan: *mut ble_l2cap_chan| {
+        state
+            .channels
+            .iter()
+            .position(|(channel_chan, _)| *channel_chan == chan as usize)
+    };
+
+    match event.type_ as u32 {
+        BLE_L2CAP_EVENT_COC_ACCEPT => {
+            if !state.listening {
+                return BLE_HS_ENOTSUP as _;
+            }
+
+            let accept = unsafe { event.__bindgen_anon_1.accept };
+
+            let channel = Arc::new(Channel::new(state.conf.clone()));
+
+            {
+                let mut channel_state = channel.state.lock();
+                channel_state.chan = accept.chan;
+
+                let rc = channel.provide_rx(&mut channel_state);
+                if rc != 0 {
+                    return rc;
+                }
+            }
+
+            state.channels.push((accept.chan as usize, channel));
+        }
+        BLE_L2CAP_EVENT_COC_CONNECTED => {
+            let connect = unsafe { event.__bindgen_anon_1.connect };
+
+            if let Some(index) = find(&state, connect.chan) {
+                let channel = state.channels[index].1.clone();
+
+                channel.connected(connect.status, connect.chan);
+
+                if connect.status == 0 {
+                    state.accepted.push_back(channel);
+                    server.condvar.notify_all();
+                } else {
+                    state.channels.swap_remove(index);
+                }
+            }
+        }
+        BLE_L2CAP_EVENT_COC_DISCONNECTED => {
+            let disconnect = unsafe { event.__bindgen_anon_1.disconnect };
+
+            if let Some(index) = find(&state, disconnect.chan) {
+                let (_, channel) = state.channels.swap_remove(index);
+
+                channel.disconnected();
+            }
+        }
+        _ => {
+            // The channel is at the same place in the events of all the types left
+            let chan = unsafe { event.__bindgen_anon_1.receive.chan };
+
+            if let Some(index) = find(&state, chan) {
+                let channel = state.channels[index].1.clone();
+
+                // Not to block the accepting while the event is handled
+                drop(state);
+
+                channel.event(event);
+            }
+        }
+    }
+
+    0
+}
+
+fn check(rc: c_int) -> Result<(), EspError> {
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(error(rc))
+    }
+}
+
+fn error(rc: c_int) -> EspError {
+    match rc as u32 {
+        BLE_HS_ENOMEM => EspError::from_infallible::<ESP_ERR_NO_MEM>(),
+        BLE_HS_ENOTSUP => EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>(),
+        BLE_HS_EINVAL => EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+        BLE_HS_ENOTCONN | BLE_HS_EALREADY => EspError::from_infallible::<ESP_ERR_INVALID_STATE>(),
+        BLE_HS_ETIMEOUT => EspError::from_infallible::<ESP_ERR_TIMEOUT>(),
+        _ => {
+            debug!("NimBLE L2CAP error {rc}");
+            EspError::from_infallible::<ESP_FAIL>()
+        }
+    }
+}
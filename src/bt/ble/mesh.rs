@@ -0,0 +1,1184 @@
+//! BLE Mesh node
+//!
+//! `EspBleMeshNode` wraps the ESP-IDF BLE Mesh stack in the node role: it is provisioned into a
+//! mesh network by a provisioner (i.e. the nRF Mesh or ESP BLE Mesh phone apps), and serves the
+//! models declared in its composition.
+//!
+//! The composition is a list of `Element`s. The primary (first) element always includes the
+//! Configuration Server model; each element can further include a Generic OnOff Server, a Generic
+//! Level Server, and vendor models with their own opcodes.
+//!
+//! The Generic servers answer the get / set messages by themselves, and report the state changes
+//! with `MeshEvent::OnOffChanged` / `MeshEvent::LevelChanged`. The messages received by vendor
+//! models are reported with `MeshEvent::VendorMessage`, and answered with
+//! `EspBleMeshNode::send_vendor_message`.
+//!
+//! ```ignore
+//! let node = EspBleMeshNode::new(
+//!     &bt,
+//!     &NodeConfiguration { uuid, ..Default::default() },
+//!     &[Element::new().onoff_server().vendor_model(0x02e5, 0x0001, &[vendor_opcode(0x01, 0x02e5)], 16)],
+//! )?;
+//!
+//! node.subscribe(|event| match event {
+//!     MeshEvent::OnOffChanged { onoff, .. } => set_light(onoff),
+//!     _ => (),
+//! })?;
+//!
+//! if !node.is_provisioned() {
+//!     node.enable_provisioning(ProvisioningBearer::Adv | ProvisioningBearer::Gatt)?;
+//! }
+//! ```
+//!
+//! The BLE Mesh stack uses the Bluedroid GAP by itself, so `EspBleGap` should not be used while
+//! the node is active. The Sensor Server and the other SIG models are not covered yet.
+
+use core::borrow::Borrow;
+use core::ffi;
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+use core::ptr;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use enumset::{EnumSet, EnumSetType};
+
+use log::debug;
+
+use crate::bt::{BleEnabled, BtDriver, BtSingleton};
+use crate::sys::*;
+
+/// The unassigned address
+pub const ADDR_UNASSIGNED: u16 = 0x0000;
+
+/// The company ID of the SIG models
+pub const CID_NVAL: u16 = 0xffff;
+
+const OP_APP_KEY_ADD: u32 = 0x00;
+const OP_MODEL_APP_BIND: u32 = 0x803d;
+const OP_MODEL_SUB_ADD: u32 = 0x801b;
+
+const OP_GEN_ONOFF_SET: u32 = 0x8202;
+const OP_GEN_ONOFF_SET_UNACK: u32 = 0x8203;
+const OP_GEN_LEVEL_SET: u32 = 0x8206;
+const OP_GEN_LEVEL_SET_UNACK: u32 = 0x8207;
+const OP_GEN_DELTA_SET: u32 = 0x8209;
+const OP_GEN_DELTA_SET_UNACK: u32 = 0x820a;
+const OP_GEN_MOVE_SET: u32 = 0x820b;
+const OP_GEN_MOVE_SET_UNACK: u32 = 0x820c;
+
+// Opcode (2 bytes) + Present, Target, Remaining Time
+const ONOFF_STATUS_MAX_LEN: usize = 2 + 3;
+const LEVEL_STATUS_MAX_LEN: usize = 2 + 5;
+
+/// Return the 3-byte opcode of a vendor model message
+pub const fn vendor_opcode(opcode: u8, company_id: u16) -> u32 {
+    ((opcode as u32) << 16) | 0xc00000 | company_id as u32
+}
+
+/// A provisioning bearer
+#[derive(Debug, EnumSetType)]
+#[enumset(repr = "u32")]
+pub enum ProvisioningBearer {
+    /// PB-ADV, provisioning over advertising packets
+    Adv = 0,
+    /// PB-GATT, provisioning over a GATT connection, as used by the phone apps
+    Gatt = 1,
+}
+
+impl ProvisioningBearer {
+    fn from_raw(bearer: esp_ble_mesh_prov_bearer_t) -> Self {
+        if bearer == esp_ble_mesh_prov_bearer_t_ESP_BLE_MESH_PROV_GATT {
+            Self::Gatt
+        } else {
+            Self::Adv
+        }
+    }
+}
+
+/// The Out-of-Band authentication used while being provisioned
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum ProvisioningOob {
+    /// No authentication
+    #[default]
+    None,
+    /// A static value shared with the provisioner
+    Static([u8; 16]),
+    /// A number of up to `digits` digits, reported with `MeshEvent::OutputNumber`
+    /// and entered by the user on the provisioner
+    OutputNumber { digits: u8 },
+}
+
+/// The Configuration Server and the provisioning data of a node
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NodeConfiguration {
+    /// The device UUID, advertised while unprovisioned
+    pub uuid: [u8; 16],
+    pub company_id: u16,
+    pub product_id: u16,
+    pub version_id: u16,
+    pub oob: ProvisioningOob,
+    pub relay: bool,
+    pub beacon: bool,
+    pub gatt_proxy: bool,
+    pub friend: bool,
+    pub default_ttl: u8,
+    /// The number of retransmissions (0 - 7) and their interval (10 - 320ms) of the network PDUs
+    pub net_transmit: (u8, u16),
+    /// The number of retransmissions (0 - 7) and their interval (10 - 320ms) of the relayed PDUs
+    pub relay_retransmit: (u8, u16),
+}
+
+impl Default for NodeConfiguration {
+    fn default() -> Self {
+        Self {
+            uuid: [0; 16],
+            company_id: 0x02e5,
+            product_id: 0,
+            version_id: 0,
+            oob: ProvisioningOob::None,
+            relay: false,
+            beacon: true,
+            gatt_proxy: true,
+            friend: false,
+            default_ttl: 7,
+            net_transmit: (2, 20),
+            relay_retransmit: (2, 20),
+        }
+    }
+}
+
+const fn transmit((count, interval_ms): (u8, u16)) -> u8 {
+    (count & 0x07) | ((((interval_ms / 10).saturating_sub(1)) as u8) << 3)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct VendorModel {
+    company_id: u16,
+    model_id: u16,
+    opcodes: Vec<u32>,
+    pub_msg_len: usize,
+}
+
+/// An element of the node composition
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Element {
+    location: u16,
+    onoff_server: bool,
+    level_server: bool,
+    vendor_models: Vec<VendorModel>,
+}
+
+impl Element {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the location descriptor (as per the GATT Namespace descriptors)
+    pub fn location(mut self, location: u16) -> Self {
+        self.location = location;
+        self
+    }
+
+    /// Add a Generic OnOff Server model
+    pub fn onoff_server(mut self) -> Self {
+        self.onoff_server = true;
+        self
+    }
+
+    /// Add a Generic Level Server model
+    pub fn level_server(mut self) -> Self {
+        self.level_server = true;
+        self
+    }
+
+    /// Add a vendor model, receiving the provided opcodes (see `vendor_opcode`) and publishing
+    /// messages of up to `pub_msg_len` bytes (including the opcode)
+    pub fn vendor_model(
+        mut self,
+        company_id: u16,
+        model_id: u16,
+        opcodes: &[u32],
+        pub_msg_len: usize,
+    ) -> Self {
+        self.vendor_models.push(VendorModel {
+            company_id,
+            model_id,
+            opcodes: opcodes.to_vec(),
+            pub_msg_len,
+        });
+        self
+    }
+}
+
+/// The context of a received message
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MessageContext {
+    pub net_idx: u16,
+    pub app_idx: u16,
+    /// The source address
+    pub src: u16,
+    /// The destination address: a unicast address of the node, a group or a virtual address
+    pub dst: u16,
+    pub rssi: i8,
+}
+
+impl From<&esp_ble_mesh_msg_ctx_t> for MessageContext {
+    fn from(ctx: &esp_ble_mesh_msg_ctx_t) -> Self {
+        Self {
+            net_idx: ctx.net_idx,
+            app_idx: ctx.app_idx,
+            src: ctx.addr,
+            dst: ctx.recv_dst,
+            rssi: ctx.recv_rssi,
+        }
+    }
+}
+
+pub enum MeshEvent<'a> {
+    Registered(Result<(), EspError>),
+    ProvisioningEnabled(Result<(), EspError>),
+    ProvisioningDisabled(Result<(), EspError>),
+    LinkOpened(ProvisioningBearer),
+    LinkClosed(ProvisioningBearer),
+    OutputNumber(u32),
+    Provisioned {
+        net_idx: u16,
+        addr: u16,
+        flags: u8,
+        iv_index: u32,
+    },
+    Reset,
+    AppKeyAdded {
+        net_idx: u16,
+        app_idx: u16,
+    },
+    ModelAppBound {
+        element_addr: u16,
+        app_idx: u16,
+        company_id: u16,
+        model_id: u16,
+    },
+    ModelSubscriptionAdded {
+        element_addr: u16,
+        group_addr: u16,
+        company_id: u16,
+        model_id: u16,
+    },
+    OnOffChanged {
+        element: u8,
+        onoff: bool,
+        ctx: MessageContext,
+    },
+    LevelChanged {
+        element: u8,
+        level: i16,
+        ctx: MessageContext,
+    },
+    VendorMessage {
+        element: u8,
+        company_id: u16,
+        model_id: u16,
+        opcode: u32,
+        ctx: MessageContext,
+        data: &'a [u8],
+    },
+    MessageSent {
+        opcode: u32,
+        result: Result<(), EspError>,
+    },
+    Other,
+}
+
+impl<'a> Debug for MeshEvent<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registered(result) => f.debug_tuple("Registered").field(result).finish(),
+            Self::ProvisioningEnabled(result) => {
+                f.debug_tuple("ProvisioningEnabled").field(result).finish()
+            }
+            Self::ProvisioningDisabled(result) => {
+                f.debug_tuple("ProvisioningDisabled").field(result).finish()
+            }
+            Self::LinkOpened(bearer) => f.debug_tuple("LinkOpened").field(bearer).finish(),
+            Self::LinkClosed(bearer) => f.debug_tuple("LinkClosed").field(bearer).finish(),
+            Self::OutputNumber(number) => f.debug_tuple("OutputNumber").field(number).finish(),
+            Self::Provisioned {
+                net_idx,
+                addr,
+                flags,
+                iv_index,
+            } => f
+                .debug_struct("Provisioned")
+                .field("net_idx", net_idx)
+                .field("addr", addr)
+                .field("flags", flags)
+                .field("iv_index", iv_index)
+                .finish(),
+            Self::Reset => write!(f, "Reset"),
+            Self::AppKeyAdded { net_idx, app_idx } => f
+                .debug_struct("AppKeyAdded")
+                .field("net_idx", net_idx)
+                .field("app_idx", app_idx)
+                .finish(),
+            Self::ModelAppBound {
+                element_addr,
+                app_idx,
+                company_id,
+                model_id,
+            } => f
+                .debug_struct("ModelAppBound")
+                .field("element_addr", element_addr)
+                .field("app_idx", app_idx)
+                .field("company_id", company_id)
+                .field("model_id", model_id)
+                .finish(),
+            Self::ModelSubscriptionAdded {
+                element_addr,
+                group_addr,
+                company_id,
+                model_id,
+            } => f
+                .debug_struct("ModelSubscriptionAdded")
+                .field("element_addr", element_addr)
+                .field("group_addr", group_addr)
+                .field("company_id", company_id)
+                .field("model_id", model_id)
+                .finish(),
+            Self::OnOffChanged {
+                element,
+                onoff,
+                ctx,
+            } => f
+                .debug_struct("OnOffChanged")
+                .field("element", element)
+                .field("onoff", onoff)
+                .field("ctx", ctx)
+                .finish(),
+            Self::LevelChanged {
+                element,
+                level,
+                ctx,
+            } => f
+                .debug_struct("LevelChanged")
+                .field("element", element)
+                .field("level", level)
+                .field("ctx", ctx)
+                .finish(),
+            Self::VendorMessage {
+                element,
+                company_id,
+                model_id,
+                opcode,
+                ctx,
+                data,
+            } => f
+                .debug_struct("VendorMessage")
+                .field("element", element)
+                .field("company_id", company_id)
+                .field("model_id", model_id)
+                .field("opcode", opcode)
+                .field("ctx", ctx)
+                .field("data", data)
+                .finish(),
+            Self::MessageSent { opcode, result } => f
+                .debug_struct("MessageSent")
+                .field("opcode", opcode)
+                .field("result", result)
+                .finish(),
+            Self::Other => write!(f, "Other"),
+        }
+    }
+}
+
+#[allow(non_upper_case_globals)]
+impl<'a>
+    From<(
+        esp_ble_mesh_prov_cb_event_t,
+        &'a esp_ble_mesh_prov_cb_param_t,
+    )> for MeshEvent<'a>
+{
+    fn from(
+        value: (
+            esp_ble_mesh_prov_cb_event_t,
+            &'a esp_ble_mesh_prov_cb_param_t,
+        ),
+    ) -> Self {
+        let (event, param) = value;
+
+        unsafe {
+            match event {
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_PROV_REGISTER_COMP_EVT => {
+                    Self::Registered(EspError::convert(param.prov_register_comp.err_code))
+                }
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_NODE_PROV_ENABLE_COMP_EVT => {
+                    Self::ProvisioningEnabled(EspError::convert(
+                        param.node_prov_enable_comp.err_code,
+                    ))
+                }
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_NODE_PROV_DISABLE_COMP_EVT => {
+                    Self::ProvisioningDisabled(EspError::convert(
+                        param.node_prov_disable_comp.err_code,
+                    ))
+                }
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_NODE_PROV_LINK_OPEN_EVT => {
+                    Self::LinkOpened(ProvisioningBearer::from_raw(
+                        param.node_prov_link_open.bearer,
+                    ))
+                }
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_NODE_PROV_LINK_CLOSE_EVT => {
+                    Self::LinkClosed(ProvisioningBearer::from_raw(
+                        param.node_prov_link_close.bearer,
+                    ))
+                }
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_NODE_PROV_OUTPUT_NUMBER_EVT => {
+                    Self::OutputNumber(param.node_prov_output_num.number)
+                }
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_NODE_PROV_COMPLETE_EVT => {
+                    Self::Provisioned {
+                        net_idx: param.node_prov_complete.net_idx,
+                        addr: param.node_prov_complete.addr,
+                        flags: param.node_prov_complete.flags,
+                        iv_index: param.node_prov_complete.iv_index,
+                    }
+                }
+                esp_ble_mesh_prov_cb_event_t_ESP_BLE_MESH_NODE_PROV_RESET_EVT => Self::Reset,
+                _ => Self::Other,
+            }
+        }
+    }
+}
+
+#[allow(non_upper_case_globals)]
+impl<'a>
+    From<(
+        esp_ble_mesh_cfg_server_cb_event_t,
+        &'a esp_ble_mesh_cfg_server_cb_param_t,
+    )> for MeshEvent<'a>
+{
+    fn from(
+        value: (
+            esp_ble_mesh_cfg_server_cb_event_t,
+            &'a esp_ble_mesh_cfg_server_cb_param_t,
+        ),
+    ) -> Self {
+        let (event, param) = value;
+
+        if event != esp_ble_mesh_cfg_server_cb_event_t_ESP_BLE_MESH_CFG_SERVER_STATE_CHANGE_EVT {
+            return Self::Other;
+        }
+
+        unsafe {
+            let state = &param.value.state_change;
+
+            match param.ctx.recv_op {
+                OP_APP_KEY_ADD => Self::AppKeyAdded {
+                    net_idx: state.appkey_add.net_idx,
+                    app_idx: state.appkey_add.app_idx,
+                },
+                OP_MODEL_APP_BIND => Self::ModelAppBound {
+                    element_addr: state.mod_app_bind.element_addr,
+                    app_idx: state.mod_app_bind.app_idx,
+                    company_id: state.mod_app_bind.company_id,
+                    model_id: state.mod_app_bind.model_id,
+                },
+                OP_MODEL_SUB_ADD => Self::ModelSubscriptionAdded {
+                    element_addr: state.mod_sub_add.element_addr,
+                    group_addr: state.mod_sub_add.sub_addr,
+                    company_id: state.mod_sub_add.company_id,
+                    model_id: state.mod_sub_add.model_id,
+                },
+                _ => Self::Other,
+            }
+        }
+    }
+}
+
+#[allow(non_upper_case_globals)]
+impl<'a>
+    From<(
+        esp_ble_mesh_generic_server_cb_event_t,
+        &'a esp_ble_mesh_generic_server_cb_param_t,
+    )> for MeshEvent<'a>
+{
+    fn from(
+        value: (
+            esp_ble_mesh_generic_server_cb_event_t,
+            &'a esp_ble_mesh_generic_server_cb_param_t,
+        ),
+    ) -> Self {
+        let (event, param) = value;
+
+        if event
+            != esp_ble_mesh_generic_server_cb_event_t_ESP_BLE_MESH_GENERIC_SERVER_STATE_CHANGE_EVT
+        {
+            return Self::Other;
+        }
+
+        let element = unsafe { param.model.as_ref() }
+            .map(|model| model.element_idx)
+            .unwrap_or(0);
+        let ctx = (&param.ctx).into();
+
+        unsafe {
+            let state = &param.value.state_change;
+
+            match param.ctx.recv_op {
+                OP_GEN_ONOFF_SET | OP_GEN_ONOFF_SET_UNACK => Self::OnOffChanged {
+                    element,
+                    onoff: state.onoff_set.onoff != 0,
+                    ctx,
+                },
+                OP_GEN_LEVEL_SET | OP_GEN_LEVEL_SET_UNACK => Self::LevelChanged {
+                    element,
+                    level: state.level_set.level,
+                    ctx,
+                },
+                OP_GEN_DELTA_SET | OP_GEN_DELTA_SET_UNACK => Self::LevelChanged {
+                    element,
+                    level: state.delta_set.level,
+                    ctx,
+                },
+                OP_GEN_MOVE_SET | OP_GEN_MOVE_SET_UNACK => Self::LevelChanged {
+                    element,
+                    level: state.move_set.level,
+                    ctx,
+                },
+                _ => Self::Other,
+            }
+        }
+    }
+}
+
+#[allow(non_upper_case_globals)]
+impl<'a>
+    From<(
+        esp_ble_mesh_model_cb_event_t,
+        &'a esp_ble_mesh_model_cb_param_t,
+    )> for MeshEvent<'a>
+{
+    fn from(
+        value: (
+            esp_ble_mesh_model_cb_event_t,
+            &'a esp_ble_mesh_model_cb_param_t,
+        ),
+    ) -> Self {
+        let (event, param) = value;
+
+        unsafe {
+            match event {
+                esp_ble_mesh_model_cb_event_t_ESP_BLE_MESH_MODEL_OPERATION_EVT => {
+                    let op = &param.model_operation;
+
+                    let (Some(model), Some(ctx)) = (op.model.as_ref(), op.ctx.as_ref()) else {
+                        return Self::Other;
+                    };
+
+                    Self::VendorMessage {
+                        element: model.element_idx,
+                        company_id: model.__bindgen_anon_1.vnd.company_id,
+                        model_id: model.__bindgen_anon_1.vnd.model_id,
+                        opcode: op.opcode,
+                        ctx: ctx.into(),
+                        data: if op.msg.is_null() {
+                            &[]
+                        } else {
+                            core::slice::from_raw_parts(op.msg, op.length as _)
+                        },
+                    }
+                }
+                esp_ble_mesh_model_cb_event_t_ESP_BLE_MESH_MODEL_SEND_COMP_EVT => {
+                    Self::MessageSent {
+                        opcode: param.model_send_comp.opcode,
+                        result: EspError::convert(param.model_send_comp.err_code),
+                    }
+                }
+                esp_ble_mesh_model_cb_event_t_ESP_BLE_MESH_MODEL_PUBLISH_COMP_EVT => {
+                    Self::MessageSent {
+                        opcode: 0,
+                        result: EspError::convert(param.model_publish_comp.err_code),
+                    }
+                }
+                _ => Self::Other,
+            }
+        }
+    }
+}
+
+// The BLE Mesh stack keeps pointers to all of these until it is de-initialized,
+// so they are heap-allocated and never touched after `esp_ble_mesh_init`
+struct Composition {
+    prov: Box<esp_ble_mesh_prov_t>,
+    uuid: Box<[u8; 16]>,
+    static_oob: Box<[u8; 16]>,
+    comp: Box<esp_ble_mesh_comp_t>,
+    elements: Vec<esp_ble_mesh_elem_t>,
+    sig_models: Vec<Vec<esp_ble_mesh_model_t>>,
+    vnd_models: Vec<Vec<esp_ble_mesh_model_t>>,
+    cfg_srv: Box<esp_ble_mesh_cfg_srv_t>,
+    onoff_srvs: Vec<Box<esp_ble_mesh_gen_onoff_srv_t>>,
+    level_srvs: Vec<Box<esp_ble_mesh_gen_level_srv_t>>,
+    pubs: Vec<Box<esp_ble_mesh_model_pub_t>>,
+    pub_bufs: Vec<(Box<net_buf_simple>, Vec<u8>)>,
+    ops: Vec<Vec<esp_ble_mesh_model_op_t>>,
+}
+
+impl Composition {
+    fn new(conf: &NodeConfiguration, elements: &[Element]) -> Result<Box<Self>, EspError> {
+        if elements.is_empty() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut this = Box::new(Self {
+            prov: Box::new(unsafe { core::mem::zeroed() }),
+            uuid: Box::new(conf.uuid),
+            static_oob: Box::new([0; 16]),
+            comp: Box::new(unsafe { core::mem::zeroed() }),
+            elements: Vec::new(),
+            sig_models: Vec::new(),
+            vnd_models: Vec::new(),
+            cfg_srv: Box::new(unsafe { core::mem::zeroed() }),
+            onoff_srvs: Vec::new(),
+            level_srvs: Vec::new(),
+            pubs: Vec::new(),
+            pub_bufs: Vec::new(),
+            ops: Vec::new(),
+        });
+
+        this.prov.uuid = this.uuid.as_ptr();
+
+        match conf.oob {
+            ProvisioningOob::None => (),
+            ProvisioningOob::Static(value) => {
+                *this.static_oob = value;
+                this.prov.static_val = this.static_oob.as_ptr();
+                this.prov.static_val_len = value.len() as _;
+            }
+            ProvisioningOob::OutputNumber { digits } => {
+                if !(1..=8).contains(&digits) {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+                }
+
+                this.prov.output_size = digits as _;
+                this.prov.output_actions =
+                    esp_ble_mesh_output_action_t_ESP_BLE_MESH_DISPLAY_NUMBER as _;
+            }
+        }
+
+        this.cfg_srv.net_transmit = transmit(conf.net_transmit);
+        this.cfg_srv.relay = if conf.relay {
+            ESP_BLE_MESH_RELAY_ENABLED
+        } else {
+            ESP_BLE_MESH_RELAY_DISABLED
+        } as _;
+        this.cfg_srv.relay_retransmit = transmit(conf.relay_retransmit);
+        this.cfg_srv.beacon = if conf.beacon {
+            ESP_BLE_MESH_BEACON_ENABLED
+        } else {
+            ESP_BLE_MESH_BEACON_DISABLED
+        } as _;
+        this.cfg_srv.gatt_proxy = if conf.gatt_proxy {
+            ESP_BLE_MESH_GATT_PROXY_ENABLED
+        } else {
+            ESP_BLE_MESH_GATT_PROXY_DISABLED
+        } as _;
+        this.cfg_srv.friend_state = if conf.friend {
+            ESP_BLE_MESH_FRIEND_ENABLED
+        } else {
+            ESP_BLE_MESH_FRIEND_DISABLED
+        } as _;
+        this.cfg_srv.default_ttl = conf.default_ttl;
+
+        for (index, element) in elements.iter().enumerate() {
+            let mut sig_models = Vec::new();
+
+            if index == 0 {
+                let user_data = &mut *this.cfg_srv as *mut _ as *mut _;
+                sig_models.push(Self::model(
+                    ESP_BLE_MESH_MODEL_ID_CONFIG_SRV as _,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    user_data,
+                ));
+            }
+
+            if element.onoff_server {
+                let mut srv: Box<esp_ble_mesh_gen_onoff_srv_t> =
+                    Box::new(unsafe { core::mem::zeroed() });
+                srv.rsp_ctrl.get_auto_rsp = ESP_BLE_MESH_SERVER_AUTO_RSP as _;
+                srv.rsp_ctrl.set_auto_rsp = ESP_BLE_MESH_SERVER_AUTO_RSP as _;
+
+                let user_data = &mut *srv as *mut _ as *mut _;
+                let publication = this.publication(ONOFF_STATUS_MAX_LEN);
+                this.onoff_srvs.push(srv);
+
+                sig_models.push(Self::model(
+                    ESP_BLE_MESH_MODEL_ID_GEN_ONOFF_SRV as _,
+                    publication,
+                    ptr::null_mut(),
+                    user_data,
+                ));
+            }
+
+            if element.level_server {
+                let mut srv: Box<esp_ble_mesh_gen_level_srv_t> =
+                    Box::new(unsafe { core::mem::zeroed() });
+                srv.rsp_ctrl.get_auto_rsp = ESP_BLE_MESH_SERVER_AUTO_RSP as _;
+                srv.rsp_ctrl.set_auto_rsp = ESP_BLE_MESH_SERVER_AUTO_RSP as _;
+
+                let user_data = &mut *srv as *mut _ as *mut _;
+                let publication = this.publication(LEVEL_STATUS_MAX_LEN);
+                this.level_srvs.push(srv);
+
+                sig_models.push(Self::model(
+                    ESP_BLE_MESH_MODEL_ID_GEN_LEVEL_SRV as _,
+                    publication,
+                    ptr::null_mut(),
+                    user_data,
+                ));
+            }
+
+            let mut vnd_models = Vec::new();
+
+            for vendor in &element.vendor_models {
+                let mut ops: Vec<esp_ble_mesh_model_op_t> = vendor
+                    .opcodes
+                    .iter()
+                    .map(|opcode| {
+                        let mut op: esp_ble_mesh_model_op_t = unsafe { core::mem::zeroed() };
+                        op.opcode = *opcode;
+                        op
+                    })
+                    .collect();
+                // ESP_BLE_MESH_MODEL_OP_END
+                ops.push(unsafe { core::mem::zeroed() });
+
+                let op = ops.as_mut_ptr();
+                this.ops.push(ops);
+
+                let publication = this.publication(vendor.pub_msg_len);
+
+                let mut model = Self::model(0, publication, op, ptr::null_mut());
+                model.__bindgen_anon_1.vnd.company_id = vendor.company_id;
+                model.__bindgen_anon_1.vnd.model_id = vendor.model_id;
+
+                vnd_models.push(model);
+            }
+
+            let mut elem: esp_ble_mesh_elem_t = unsafe { core::mem::zeroed() };
+            elem.location = element.location;
+            elem.sig_model_count = sig_models.len() as _;
+            elem.sig_models = sig_models.as_mut_ptr();
+            elem.vnd_model_count = vnd_models.len() as _;
+            elem.vnd_models = if vnd_models.is_empty() {
+                ptr::null_mut()
+            } else {
+                vnd_models.as_mut_ptr()
+            };
+
+            this.sig_models.push(sig_models);
+            this.vnd_models.push(vnd_models);
+            this.elements.push(elem);
+        }
+
+        this.comp.cid = conf.company_id;
+        this.comp.pid = conf.product_id;
+        this.comp.vid = conf.version_id;
+        this.comp.element_count = this.elements.len() as _;
+        this.comp.elements = this.elements.as_mut_ptr();
+
+        Ok(this)
+    }
+
+    fn model(
+        model_id: u16,
+        publication: *mut esp_ble_mesh_model_pub_t,
+        op: *mut esp_ble_mesh_model_op_t,
+        user_data: *mut ffi::c_void,
+    ) -> esp_ble_mesh_model_t {
+        let mut model: esp_ble_mesh_model_t = unsafe { core::mem::zeroed() };
+
+        model.__bindgen_anon_1.model_id = model_id;
+        model.keys.fill(ESP_BLE_MESH_KEY_UNUSED as _);
+        model.groups.fill(ADDR_UNASSIGNED);
+        model.pub_ = publication;
+        model.op = op;
+        model.user_data = user_data;
+
+        model
+    }
+
+    fn publication(&mut self, msg_len: usize) -> *mut esp_ble_mesh_model_pub_t {
+        let mut data = alloc::vec![0; msg_len];
+
+        let mut buf = Box::new(net_buf_simple {
+            data: data.as_mut_ptr(),
+            len: 0,
+            size: msg_len as _,
+            __buf: data.as_mut_ptr(),
+        });
+
+        let mut publication: Box<esp_ble_mesh_model_pub_t> =
+            Box::new(unsafe { core::mem::zeroed() });
+        publication.msg = &mut *buf;
+        publication.dev_role = ROLE_NODE as _;
+
+        let ptr = &mut *publication as *mut _;
+
+        self.pub_bufs.push((buf, data));
+        self.pubs.push(publication);
+
+        ptr
+    }
+
+    fn model_ptr(
+        &mut self,
+        element: usize,
+        model_id: u16,
+        company_id: u16,
+    ) -> Result<*mut esp_ble_mesh_model_t, EspError> {
+        let models = if company_id == CID_NVAL {
+            self.sig_models.get_mut(element)
+        } else {
+            self.vnd_models.get_mut(element)
+        }
+        .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        models
+            .iter_mut()
+            .find(|model| unsafe {
+                if company_id == CID_NVAL {
+                    model.__bindgen_anon_1.model_id == model_id
+                } else {
+                    model.__bindgen_anon_1.vnd.company_id == company_id
+                        && model.__bindgen_anon_1.vnd.model_id == model_id
+                }
+            })
+            .map(|model| model as *mut _)
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+    }
+}
+
+pub struct EspBleMeshNode<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    _driver: T,
+    composition: crate::private::mutex::Mutex<Box<Composition>>,
+    _p: PhantomData<&'d ()>,
+    _m: PhantomData<M>,
+}
+
+impl<'d, M, T> EspBleMeshNode<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    /// Initialize the BLE Mesh stack with the provided composition
+    ///
+    /// The provisioning data (and thus the network membership) of a provisioned node is restored from
+    /// NVS, provided that `CONFIG_BLE_MESH_SETTINGS` is enabled.
+    pub fn new(
+        driver: T,
+        conf: &NodeConfiguration,
+        elements: &[Element],
+    ) -> Result<Self, EspError> {
+        let mut composition = Composition::new(conf, elements)?;
+
+        SINGLETON.take()?;
+
+        let result = (|| {
+            esp!(unsafe { esp_ble_mesh_register_prov_callback(Some(Self::prov_event_handler)) })?;
+            esp!(unsafe {
+                esp_ble_mesh_register_config_server_callback(Some(Self::cfg_server_event_handler))
+            })?;
+            esp!(unsafe {
+                esp_ble_mesh_register_generic_server_callback(Some(
+                    Self::generic_server_event_handler,
+                ))
+            })?;
+            esp!(unsafe {
+                esp_ble_mesh_register_custom_model_callback(Some(Self::model_event_handler))
+            })?;
+
+            esp!(unsafe { esp_ble_mesh_init(&mut *composition.prov, &mut *composition.comp) })
+        })();
+
+        if let Err(err) = result {
+            SINGLETON.release()?;
+            return Err(err);
+        }
+
+        debug!("BLE Mesh initialized");
+
+        Ok(Self {
+            _driver: driver,
+            composition: crate::private::mutex::Mutex::new(composition),
+            _p: PhantomData,
+            _m: PhantomData,
+        })
+    }
+
+    pub fn subscribe<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut(MeshEvent) + Send + 'static,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// This method - in contrast to method `subscribe` - allows the user to pass
+    /// a non-static callback/closure. This enables users to borrow
+    /// - in the closure - variables that live on the stack - or more generally - in the same
+    ///   scope where the service is created.
+    ///
+    /// HOWEVER: care should be taken NOT to call `core::mem::forget()` on the service,
+    /// as that would immediately lead to an UB (crash).
+    /// Also note that forgetting the service might happen with `Rc` and `Arc`
+    /// when circular references are introduced: https://github.com/rust-lang/rust/issues/24456
+    ///
+    /// The reason is that the closure is actually sent to a hidden ESP IDF thread.
+    /// This means that if the service is forgotten, Rust is free to e.g. unwind the stack
+    /// and the closure now owned by this other thread will end up with references to variables that no longer exist.
+    ///
+    /// The destructor of the service takes care - prior to the service being dropped and e.g.
+    /// the stack being unwind - to remove the closure from the hidden thread and destroy it.
+    /// Unfortunately, when the service is forgotten, the un-subscription does not happen
+    /// and invalid references are left dangling.
+    ///
+    /// This "local borrowing" will only be possible to express in a safe way once/if `!Leak` types
+    /// are introduced to Rust (i.e. the impossibility to "forget" a type and thus not call its destructor).
+    pub unsafe fn subscribe_nonstatic<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut(MeshEvent) + Send + 'd,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self) -> Result<(), EspError> {
+        SINGLETON.unsubscribe();
+
+        Ok(())
+    }
+
+    /// Return `true` if the node is part of a mesh network
+    pub fn is_provisioned(&self) -> bool {
+        unsafe { esp_ble_mesh_node_is_provisioned() }
+    }
+
+    /// Start advertising the unprovisioned device beacon (PB-ADV) and / or the mesh provisioning
+    /// service (PB-GATT)
+    ///
+    /// The completion is reported with `MeshEvent::ProvisioningEnabled`.
+    pub fn enable_provisioning(
+        &self,
+        bearers: EnumSet<ProvisioningBearer>,
+    ) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_mesh_node_prov_enable(bearers.as_repr() as _) })
+    }
+
+    /// The completion is reported with `MeshEvent::ProvisioningDisabled`.
+    pub fn disable_provisioning(
+        &self,
+        bearers: EnumSet<ProvisioningBearer>,
+    ) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_mesh_node_prov_disable(bearers.as_repr() as _) })
+    }
+
+    /// Leave the mesh network, erasing the provisioning data
+    ///
+    /// The completion is reported with `MeshEvent::Reset`.
+    pub fn reset(&self) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_mesh_node_local_reset() })
+    }
+
+    /// Update the state of the Generic OnOff Server of an element, e.g. after a local action
+    ///
+    /// The new state is published, if the publication of the model is configured.
+    pub fn set_onoff(&self, element: u8, onoff: bool) -> Result<(), EspError> {
+        let mut composition = self.composition.lock();
+        let model = composition.model_ptr(
+            element as _,
+            ESP_BLE_MESH_MODEL_ID_GEN_ONOFF_SRV as _,
+            CID_NVAL,
+        )?;
+
+        let mut value: esp_ble_mesh_server_state_value_t = unsafe { core::mem::zeroed() };
+        value.gen_onoff.onoff = onoff as _;
+
+        esp!(unsafe {
+            esp_ble_mesh_server_model_update_state(
+                model,
+                esp_ble_mesh_server_state_type_t_ESP_BLE_MESH_GENERIC_ONOFF_STATE,
+                &mut value,
+            )
+        })
+    }
+
+    /// Update the state of the Generic Level Server of an element, e.g. after a local action
+    ///
+    /// The new state is published, if the publication of the model is configured.
+    pub fn set_level(&self, element: u8, level: i16) -> Result<(), EspError> {
+        let mut composition = self.composition.lock();
+        let model = composition.model_ptr(
+            element as _,
+            ESP_BLE_MESH_MODEL_ID_GEN_LEVEL_SRV as _,
+            CID_NVAL,
+        )?;
+
+        let mut value: esp_ble_mesh_server_state_value_t = unsafe { core::mem::zeroed() };
+        value.gen_level.level = level;
+
+        esp!(unsafe {
+            esp_ble_mesh_server_model_update_state(
+                model,
+                esp_ble_mesh_server_state_type_t_ESP_BLE_MESH_GENERIC_LEVEL_STATE,
+                &mut value,
+            )
+        })
+    }
+
+    /// Send a message from a vendor model, i.e. a reply to the source of a received message
+    ///
+    /// The completion is reported with `MeshEvent::MessageSent`.
+    pub fn send_vendor_message(
+        &self,
+        element: u8,
+        company_id: u16,
+        model_id: u16,
+        ctx: &MessageContext,
+        opcode: u32,
+        data: &[u8],
+    ) -> Result<(), EspError> {
+        let mut composition = self.composition.lock();
+        let model = composition.model_ptr(element as _, model_id, company_id)?;
+
+        let mut raw_ctx: esp_ble_mesh_msg_ctx_t = unsafe { core::mem::zeroed() };
+        raw_ctx.net_idx = ctx.net_idx;
+        raw_ctx.app_idx = ctx.app_idx;
+        raw_ctx.addr = ctx.src;
+        raw_ctx.send_ttl = ESP_BLE_MESH_TTL_DEFAULT as _;
+
+        esp!(unsafe {
+            esp_ble_mesh_server_model_send_msg(
+                model,
+                &mut raw_ctx,
+                opcode,
+                data.len() as _,
+                data.as_ptr() as *mut _,
+            )
+        })
+    }
+
+    /// Publish a message from a vendor model to its configured publish address
+    pub fn publish_vendor_message(
+        &self,
+        element: u8,
+        company_id: u16,
+        model_id: u16,
+        opcode: u32,
+        data: &[u8],
+    ) -> Result<(), EspError> {
+        let mut composition = self.composition.lock();
+        let model = composition.model_ptr(element as _, model_id, company_id)?;
+
+        esp!(unsafe {
+            esp_ble_mesh_model_publish(
+                model,
+                opcode,
+                data.len() as _,
+                data.as_ptr() as *mut _,
+                ROLE_NODE as _,
+            )
+        })
+    }
+
+    unsafe extern "C" fn prov_event_handler(
+        event: esp_ble_mesh_prov_cb_event_t,
+        param: *mut esp_ble_mesh_prov_cb_param_t,
+    ) {
+        let param = unsafe { param.as_ref() }.unwrap();
+        let event = MeshEvent::from((event, param));
+
+        debug!("Got event {{ {:#?} }}", event);
+
+        SINGLETON.call(event);
+    }
+
+    unsafe extern "C" fn cfg_server_event_handler(
+        event: esp_ble_mesh_cfg_server_cb_event_t,
+        param: *mut esp_ble_mesh_cfg_server_cb_param_t,
+    ) {
+        let param = unsafe { param.as_ref() }.unwrap();
+        let event = MeshEvent::from((event, param));
+
+        debug!("Got event {{ {:#?} }}", event);
+
+        SINGLETON.call(event);
+    }
+
+    unsafe extern "C" fn generic_server_event_handler(
+        event: esp_ble_mesh_generic_server_cb_event_t,
+        param: *mut esp_ble_mesh_generic_server_cb_param_t,
+    ) {
+        let param = unsafe { param.as_ref() }.unwrap();
+        let event = MeshEvent::from((event, param));
+
+        debug!("Got event {{ {:#?} }}", event);
+
+        SINGLETON.call(event);
+    }
+
+    unsafe extern "C" fn model_event_handler(
+        event: esp_ble_mesh_model_cb_event_t,
+        param: *mut esp_ble_mesh_model_cb_param_t,
+    ) {
+        let param = unsafe { param.as_ref() }.unwrap();
+        let event = MeshEvent::from((event, param));
+
+        debug!("Got event {{ {:#?} }}", event);
+
+        SINGLETON.call(event);
+    }
+}
+
+impl<'d, M, T> Drop for EspBleMeshNode<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    fn drop(&mut self) {
+        self.unsubscribe().unwrap();
+
+        let mut param = esp_ble_mesh_deinit_param_t { erase_flash: false };
+
+        esp!(unsafe { esp_ble_mesh_deinit(&mut param) }).unwrap();
+
+        SINGLETON.release().unwrap();
+    }
+}
+
+unsafe impl<'d, M, T> Send for EspBleMeshNode<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+// Safe because the ESP IDF Bluedroid APIs all do message passing
+// to a dedicated Bluedroid task
+unsafe impl<'d, M, T> Sync for EspBleMeshNode<'d, M, T>
+where
+    M: BleEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+static SINGLETON: BtSingleton<MeshEvent, ()> = BtSingleton::new(());
@@ -1,5 +1,22 @@
 #![allow(non_upper_case_globals)]
 
+/// Hands-Free Profile client (the HF unit)
+///
+/// `EspHfpc` connects to an Audio Gateway (a phone), first establishing the service-level
+/// connection (`ConnectionStatus::SlcConnected`, reporting the features of the gateway), then
+/// the audio connection with `EspHfpc::connect_audio`, using either CVSD (8 kHz) or
+/// mSBC (16 kHz) as reported in `HfpcEvent::AudioState`.
+///
+/// The call state is reported with the `CallState`, `CallSetupState`, `CallHeld` and
+/// `CurrentCall` events, and calls are controlled with `answer`, `reject`, `dial` and `call_hold`.
+/// Speaker and microphone gains are kept in sync with the gateway through the `VolumeControl`
+/// event and `update_volume`.
+///
+/// With the HCI audio data path (`CONFIG_BT_HFP_AUDIO_DATA_PATH_HCI`), the decoded PCM frames
+/// of the gateway are delivered with `HfpcEvent::RecvData` - typically written to an I2S
+/// driver - while the frames to send are requested with `HfpcEvent::SendData`, after notifying
+/// the stack with `EspHfpc::request_outgoing_data_ready`. The callback returns the number of
+/// bytes written into the `SendData` buffer.
 #[cfg(esp_idf_bt_hfp_client_enable)]
 pub mod client {
     use core::borrow::Borrow;
@@ -10,6 +27,8 @@ pub mod client {
 
     use crate::sys::*;
 
+    use enumset::{EnumSet, EnumSetType};
+
     use log::{debug, info};
 
     use num_enum::TryFromPrimitive;
@@ -19,12 +38,17 @@ pub mod client {
         private::cstr::{from_cstr_ptr, to_cstring_arg},
     };
 
+    /// A speaker or microphone gain, between 0 and `Volume::MAX`
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub enum Volume {
         Speaker(u8),
         Microphone(u8),
     }
 
+    impl Volume {
+        pub const MAX: u8 = 15;
+    }
+
     #[cfg(esp_idf_bt_hfp_audio_data_path_hci)]
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
     pub struct Source {
@@ -54,6 +78,93 @@ pub mod client {
         ConnectedMsbc = esp_hf_client_audio_state_t_ESP_HF_CLIENT_AUDIO_STATE_CONNECTED_MSBC,
     }
 
+    impl AudioStatus {
+        /// The sample rate of the PCM frames of the audio connection, if connected:
+        /// 8 kHz for CVSD and 16 kHz for mSBC (wide band speech)
+        pub fn sample_rate_hz(&self) -> Option<u32> {
+            match self {
+                Self::Connected => Some(8000),
+                Self::ConnectedMsbc => Some(16000),
+                _ => None,
+            }
+        }
+    }
+
+    /// A feature of the Audio Gateway, reported once the service-level connection is established
+    #[derive(Debug, EnumSetType)]
+    #[enumset(repr = "u32")]
+    pub enum AgFeature {
+        ThreeWayCalling = 0,
+        EchoCancellation = 1,
+        VoiceRecognition = 2,
+        InBandRingTone = 3,
+        VoiceTag = 4,
+        RejectCall = 5,
+        EnhancedCallStatus = 6,
+        EnhancedCallControl = 7,
+        ExtendedErrorCodes = 8,
+        CodecNegotiation = 9,
+    }
+
+    /// A call hold and multiparty command supported by the Audio Gateway
+    #[derive(Debug, EnumSetType)]
+    #[enumset(repr = "u32")]
+    pub enum CallHoldFeature {
+        Release = 0,
+        ReleaseAndAccept = 1,
+        ReleaseSpecified = 2,
+        HoldAndAccept = 3,
+        PrivateConsultation = 4,
+        Merge = 5,
+        MergeAndDetach = 6,
+    }
+
+    /// A call hold and multiparty command (`AT+CHLD`)
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub enum CallHoldCommand {
+        /// Release all held calls, or reject the waiting call
+        Release,
+        /// Release all active calls, and accept the held or waiting call
+        ReleaseAndAccept,
+        /// Hold all active calls, and accept the held or waiting call
+        HoldAndAccept,
+        /// Add the held call to the conversation
+        Merge,
+        /// Connect the two calls, and disconnect from both
+        MergeAndDetach,
+        /// Release the call with the provided index
+        ReleaseSpecified(usize),
+        /// Place all calls on hold, except the call with the provided index
+        PrivateConsultation(usize),
+    }
+
+    impl CallHoldCommand {
+        fn raw(&self) -> (esp_hf_chld_type_t, ffi::c_int) {
+            match self {
+                Self::Release => (esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_REL, 0),
+                Self::ReleaseAndAccept => (esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_REL_ACC, 0),
+                Self::HoldAndAccept => (esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_HOLD_ACC, 0),
+                Self::Merge => (esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_MERGE, 0),
+                Self::MergeAndDetach => (esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_MERGE_DETACH, 0),
+                Self::ReleaseSpecified(index) => {
+                    (esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_REL_X, *index as _)
+                }
+                Self::PrivateConsultation(index) => {
+                    (esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_PRIV_X, *index as _)
+                }
+            }
+        }
+    }
+
+    /// A response and hold command (`AT+BTRH`)
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[repr(u32)]
+    pub enum HoldCommand {
+        Hold = esp_hf_btrh_cmd_t_ESP_HF_BTRH_CMD_HOLD,
+        Accept = esp_hf_btrh_cmd_t_ESP_HF_BTRH_CMD_ACCEPT,
+        Reject = esp_hf_btrh_cmd_t_ESP_HF_BTRH_CMD_REJECT,
+    }
+
     #[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
     #[repr(u32)]
     pub enum CallSetupStatus {
@@ -160,8 +271,8 @@ pub mod client {
         ConnectionState {
             bd_addr: BdAddr,
             status: ConnectionStatus,
-            peer_features: u32,
-            child_features: u32,
+            peer_features: EnumSet<AgFeature>,
+            chld_features: EnumSet<CallHoldFeature>,
         },
         AudioState {
             bd_addr: BdAddr,
@@ -190,7 +301,8 @@ pub mod client {
         VolumeControl(Volume),
         AtResponse {
             code: AtResponseCode,
-            extended_code: AudioGatewayResponseCode,
+            /// The extended error of an `AtResponseCode::AudioGatewayErr` response
+            extended_code: Option<AudioGatewayResponseCode>,
         },
         SubscriberInfo {
             number: &'a str,
@@ -219,8 +331,8 @@ pub mod client {
                     esp_hf_client_cb_event_t_ESP_HF_CLIENT_CONNECTION_STATE_EVT => Self::ConnectionState {
                         bd_addr: param.conn_stat.remote_bda.into(),
                         status: param.conn_stat.state.try_into().unwrap(),
-                        peer_features: param.conn_stat.peer_feat,
-                        child_features: param.conn_stat.chld_feat,
+                        peer_features: EnumSet::from_repr_truncated(param.conn_stat.peer_feat),
+                        chld_features: EnumSet::from_repr_truncated(param.conn_stat.chld_feat),
                     },
                     esp_hf_client_cb_event_t_ESP_HF_CLIENT_AUDIO_STATE_EVT => Self::AudioState {
                         bd_addr: param.audio_stat.remote_bda.into(),
@@ -258,7 +370,9 @@ pub mod client {
                     }),
                     esp_hf_client_cb_event_t_ESP_HF_CLIENT_AT_RESPONSE_EVT => Self::AtResponse {
                         code: param.at_response.code.try_into().unwrap(),
-                        extended_code: param.at_response.cme.try_into().unwrap(),
+                        extended_code: (param.at_response.code == esp_hf_at_response_code_t_ESP_HF_AT_RESPONSE_CODE_CME)
+                            .then(|| param.at_response.cme.try_into().ok())
+                            .flatten(),
                     },
                     esp_hf_client_cb_event_t_ESP_HF_CLIENT_CNUM_EVT => Self::SubscriberInfo {
                         number: from_cstr_ptr(param.cnum.number),
@@ -390,19 +504,19 @@ pub mod client {
             Ok(())
         }
 
-        pub fn connect(&self, bd_addr: &esp_bd_addr_t) -> Result<(), EspError> {
+        pub fn connect(&self, bd_addr: &BdAddr) -> Result<(), EspError> {
             esp!(unsafe { esp_hf_client_connect(bd_addr as *const _ as *mut _) })
         }
 
-        pub fn disconnect(&self, bd_addr: &esp_bd_addr_t) -> Result<(), EspError> {
+        pub fn disconnect(&self, bd_addr: &BdAddr) -> Result<(), EspError> {
             esp!(unsafe { esp_hf_client_disconnect(bd_addr as *const _ as *mut _) })
         }
 
-        pub fn connect_audio(&self, bd_addr: &esp_bd_addr_t) -> Result<(), EspError> {
+        pub fn connect_audio(&self, bd_addr: &BdAddr) -> Result<(), EspError> {
             esp!(unsafe { esp_hf_client_connect_audio(bd_addr as *const _ as *mut _) })
         }
 
-        pub fn disconnect_audio(&self, bd_addr: &esp_bd_addr_t) -> Result<(), EspError> {
+        pub fn disconnect_audio(&self, bd_addr: &BdAddr) -> Result<(), EspError> {
             esp!(unsafe { esp_hf_client_disconnect_audio(bd_addr as *const _ as *mut _) })
         }
 
@@ -414,6 +528,9 @@ pub mod client {
             esp!(unsafe { esp_hf_client_stop_voice_recognition() })
         }
 
+        /// Report a change of the speaker or microphone gain to the Audio Gateway
+        ///
+        /// Returns `ESP_ERR_INVALID_ARG` for gains above `Volume::MAX`.
         pub fn update_volume(&self, volume: Volume) -> Result<(), EspError> {
             let (volume_type, gain) = match volume {
                 Volume::Speaker(gain) => (
@@ -426,6 +543,10 @@ pub mod client {
                 ),
             };
 
+            if gain > Volume::MAX as _ {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+
             esp!(unsafe { esp_hf_client_volume_update(volume_type, gain) })
         }
 
@@ -439,16 +560,26 @@ pub mod client {
             esp!(unsafe { esp_hf_client_dial_memory(location as _) })
         }
 
-        // pub fn hold(&self, location: usize) -> Result<(), EspError> {
-        //     esp!(unsafe { esp_hf_client_send_chld_cmd(location as _) })
-        // }
+        /// Send a call hold and multiparty command, as supported by the Audio Gateway
+        /// (see `CallHoldFeature`)
+        pub fn call_hold(&self, command: CallHoldCommand) -> Result<(), EspError> {
+            let (chld, index) = command.raw();
+
+            esp!(unsafe { esp_hf_client_send_chld_cmd(chld, index) })
+        }
 
-        pub fn reply_hold(&self, location: usize) -> Result<(), EspError> {
-            esp!(unsafe { esp_hf_client_send_btrh_cmd(location as _) })
+        /// Send a response and hold command, reported with `HfpcEvent::CallResponseAndHold`
+        pub fn reply_hold(&self, command: HoldCommand) -> Result<(), EspError> {
+            esp!(unsafe { esp_hf_client_send_btrh_cmd(command as _) })
         }
 
-        pub fn dtmf(&self, location: usize) -> Result<(), EspError> {
-            esp!(unsafe { esp_hf_client_send_dtmf(location as _) })
+        /// Send a DTMF tone during an ongoing call; one of `0-9`, `*`, `#` and `A-D`
+        pub fn dtmf(&self, code: char) -> Result<(), EspError> {
+            if !matches!(code, '0'..='9' | '*' | '#' | 'A'..='D') {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+
+            esp!(unsafe { esp_hf_client_send_dtmf(code as u8 as _) })
         }
 
         // pub fn apple(&self, location: usize) -> Result<(), EspError> {
@@ -487,6 +618,11 @@ pub mod client {
             esp!(unsafe { esp_hf_client_send_nrec() })
         }
 
+        /// Notify the stack that outgoing audio is available, which is then requested with
+        /// `HfpcEvent::SendData`
+        ///
+        /// Typically called periodically - e.g. every 7.5ms for mSBC - while the audio
+        /// connection is established.
         #[cfg(esp_idf_bt_hfp_audio_data_path_hci)]
         pub fn request_outgoing_data_ready(&self) {
             unsafe {
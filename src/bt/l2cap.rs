@@ -0,0 +1,391 @@
+//! Bluetooth Classic L2CAP connection-oriented channels
+//!
+//! Bluedroid does not expose the LE credit-based connection-oriented channels (LE CoC) to
+//! applications - these are in `ble::l2cap`, over NimBLE. What it does expose is its Bluetooth
+//! Classic L2CAP API, which provides connection-oriented channels between two devices, with a much
+//! higher throughput than what is possible with GATT writes and notifications.
+//!
+//! `EspL2cap` opens channels to a remote PSM (`connect`) or accepts them on a local PSM (`start_server`).
+//! Each channel is exposed by Bluedroid as a VFS file descriptor, reported by `L2capEvent::Opened`,
+//! which can be wrapped in an `L2capChannel` implementing the `embedded_svc::io` `Read` and `Write` traits.
+//! Flow control (the credits of the peer) is handled by Bluedroid: writes block while the channel is congested.
+
+use core::borrow::Borrow;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+
+use embedded_svc::io::{ErrorType, Read, Write};
+
+use log::debug;
+
+use num_enum::TryFromPrimitive;
+
+use crate::bt::{BdAddr, BtClassicEnabled, BtDriver, BtSingleton};
+use crate::io::EspIOError;
+use crate::sys::*;
+
+/// A Protocol/Service Multiplexer
+pub type Psm = u16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u32)]
+pub enum L2capStatus {
+    Success = esp_bt_l2cap_status_t_ESP_BT_L2CAP_SUCCESS,
+    Failure = esp_bt_l2cap_status_t_ESP_BT_L2CAP_FAILURE,
+    Busy = esp_bt_l2cap_status_t_ESP_BT_L2CAP_BUSY,
+    NoResource = esp_bt_l2cap_status_t_ESP_BT_L2CAP_NO_RESOURCE,
+    NeedInit = esp_bt_l2cap_status_t_ESP_BT_L2CAP_NEED_INIT,
+    NeedDeinit = esp_bt_l2cap_status_t_ESP_BT_L2CAP_NEED_DEINIT,
+    NoConnection = esp_bt_l2cap_status_t_ESP_BT_L2CAP_NO_CONNECTION,
+    NoServer = esp_bt_l2cap_status_t_ESP_BT_L2CAP_NO_SERVER,
+}
+
+/// The security requirements of a channel
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum L2capSecurity {
+    #[default]
+    None = ESP_BT_L2CAP_SEC_NONE,
+    Authorize = ESP_BT_L2CAP_SEC_AUTHORIZE,
+    Authenticate = ESP_BT_L2CAP_SEC_AUTHENTICATE,
+    /// Authenticate and encrypt
+    Encrypt = ESP_BT_L2CAP_SEC_AUTHENTICATE | ESP_BT_L2CAP_SEC_ENCRYPT,
+}
+
+pub struct EventRawData<'a>(pub &'a esp_bt_l2cap_cb_param_t);
+
+impl<'a> Debug for EventRawData<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EventRawData").finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum L2capEvent<'a> {
+    Initialized(L2capStatus),
+    Deinitialized(L2capStatus),
+    Opened {
+        status: L2capStatus,
+        handle: u32,
+        /// The VFS file descriptor of the channel, to be wrapped with `L2capChannel::new`
+        fd: i32,
+        addr: BdAddr,
+        peer_mtu: i32,
+    },
+    Closed {
+        status: L2capStatus,
+        handle: u32,
+        /// `true` if the channel was closed by the peer or because of a link loss
+        is_async: bool,
+    },
+    ServerStarted {
+        status: L2capStatus,
+        handle: u32,
+        sec_id: u8,
+    },
+    ClientInitiated {
+        status: L2capStatus,
+        handle: u32,
+        sec_id: u8,
+    },
+    ServerStopped {
+        status: L2capStatus,
+        psm: u8,
+    },
+    VfsRegistered(L2capStatus),
+    VfsUnregistered(L2capStatus),
+    Other {
+        raw_event: esp_bt_l2cap_cb_event_t,
+        raw_data: EventRawData<'a>,
+    },
+}
+
+#[allow(non_upper_case_globals)]
+impl<'a> From<(esp_bt_l2cap_cb_event_t, &'a esp_bt_l2cap_cb_param_t)> for L2capEvent<'a> {
+    fn from(value: (esp_bt_l2cap_cb_event_t, &'a esp_bt_l2cap_cb_param_t)) -> Self {
+        let (event, param) = value;
+
+        unsafe {
+            match event {
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_INIT_EVT => {
+                    Self::Initialized(param.init.status.try_into().unwrap())
+                }
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_UNINIT_EVT => {
+                    Self::Deinitialized(param.uninit.status.try_into().unwrap())
+                }
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_OPEN_EVT => Self::Opened {
+                    status: param.open.status.try_into().unwrap(),
+                    handle: param.open.handle,
+                    fd: param.open.fd,
+                    addr: param.open.rem_bda.into(),
+                    peer_mtu: param.open.peer_mtu,
+                },
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_CLOSE_EVT => Self::Closed {
+                    status: param.close.status.try_into().unwrap(),
+                    handle: param.close.handle,
+                    is_async: param.close.async_,
+                },
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_START_EVT => Self::ServerStarted {
+                    status: param.start.status.try_into().unwrap(),
+                    handle: param.start.handle,
+                    sec_id: param.start.sec_id,
+                },
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_CL_INIT_EVT => Self::ClientInitiated {
+                    status: param.cl_init.status.try_into().unwrap(),
+                    handle: param.cl_init.handle,
+                    sec_id: param.cl_init.sec_id,
+                },
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_SRV_STOP_EVT => Self::ServerStopped {
+                    status: param.srv_stop.status.try_into().unwrap(),
+                    psm: param.srv_stop.psm,
+                },
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_VFS_REGISTER_EVT => {
+                    Self::VfsRegistered(param.vfs_register.status.try_into().unwrap())
+                }
+                esp_bt_l2cap_cb_event_t_ESP_BT_L2CAP_VFS_UNREGISTER_EVT => {
+                    Self::VfsUnregistered(param.vfs_unregister.status.try_into().unwrap())
+                }
+                _ => Self::Other {
+                    raw_event: event,
+                    raw_data: EventRawData(param),
+                },
+            }
+        }
+    }
+}
+
+pub struct EspL2cap<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    _driver: T,
+    _p: PhantomData<&'d ()>,
+    _m: PhantomData<M>,
+}
+
+impl<'d, M, T> EspL2cap<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    pub fn new(driver: T) -> Result<Self, EspError> {
+        SINGLETON.take()?;
+
+        esp!(unsafe { esp_bt_l2cap_register_callback(Some(Self::event_handler)) })?;
+        esp!(unsafe { esp_bt_l2cap_init() })?;
+        esp!(unsafe { esp_bt_l2cap_vfs_register() })?;
+
+        Ok(Self {
+            _driver: driver,
+            _p: PhantomData,
+            _m: PhantomData,
+        })
+    }
+
+    pub fn subscribe<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut(L2capEvent) + Send + 'static,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// This method - in contrast to method `subscribe` - allows the user to pass
+    /// a non-static callback/closure. This enables users to borrow
+    /// - in the closure - variables that live on the stack - or more generally - in the same
+    ///   scope where the service is created.
+    ///
+    /// HOWEVER: care should be taken NOT to call `core::mem::forget()` on the service,
+    /// as that would immediately lead to an UB (crash).
+    /// Also note that forgetting the service might happen with `Rc` and `Arc`
+    /// when circular references are introduced: https://github.com/rust-lang/rust/issues/24456
+    ///
+    /// The reason is that the closure is actually sent to a hidden ESP IDF thread.
+    /// This means that if the service is forgotten, Rust is free to e.g. unwind the stack
+    /// and the closure now owned by this other thread will end up with references to variables that no longer exist.
+    ///
+    /// The destructor of the service takes care - prior to the service being dropped and e.g.
+    /// the stack being unwind - to remove the closure from the hidden thread and destroy it.
+    /// Unfortunately, when the service is forgotten, the un-subscription does not happen
+    /// and invalid references are left dangling.
+    ///
+    /// This "local borrowing" will only be possible to express in a safe way once/if `!Leak` types
+    /// are introduced to Rust (i.e. the impossibility to "forget" a type and thus not call its destructor).
+    pub unsafe fn subscribe_nonstatic<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut(L2capEvent) + Send + 'd,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self) -> Result<(), EspError> {
+        SINGLETON.unsubscribe();
+
+        Ok(())
+    }
+
+    /// Open a channel to the remote PSM of a peer
+    ///
+    /// The channel is reported with `L2capEvent::Opened`.
+    pub fn connect(
+        &self,
+        addr: &BdAddr,
+        remote_psm: Psm,
+        security: L2capSecurity,
+    ) -> Result<(), EspError> {
+        esp!(unsafe { esp_bt_l2cap_connect(security as _, remote_psm, addr as *const _ as *mut _) })
+    }
+
+    /// Accept channels on the local PSM
+    ///
+    /// Each accepted channel is reported with `L2capEvent::Opened`.
+    pub fn start_server(&self, local_psm: Psm, security: L2capSecurity) -> Result<(), EspError> {
+        esp!(unsafe { esp_bt_l2cap_start_srv(security as _, local_psm) })
+    }
+
+    pub fn stop_server(&self, local_psm: Psm) -> Result<(), EspError> {
+        esp!(unsafe { esp_bt_l2cap_stop_srv(local_psm) })
+    }
+
+    pub fn stop_all_servers(&self) -> Result<(), EspError> {
+        esp!(unsafe { esp_bt_l2cap_stop_all_srv() })
+    }
+
+    unsafe extern "C" fn event_handler(
+        event: esp_bt_l2cap_cb_event_t,
+        param: *mut esp_bt_l2cap_cb_param_t,
+    ) {
+        let param = unsafe { param.as_ref() }.unwrap();
+        let event = L2capEvent::from((event, param));
+
+        debug!("Got event {{ {:#?} }}", event);
+
+        SINGLETON.call(event);
+    }
+}
+
+impl<'d, M, T> Drop for EspL2cap<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    fn drop(&mut self) {
+        self.unsubscribe().unwrap();
+
+        esp!(unsafe { esp_bt_l2cap_vfs_unregister() }).unwrap();
+        esp!(unsafe { esp_bt_l2cap_deinit() }).unwrap();
+
+        SINGLETON.release().unwrap();
+    }
+}
+
+unsafe impl<'d, M, T> Send for EspL2cap<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+// Safe because the ESP IDF Bluedroid APIs all do message passing
+// to a dedicated Bluedroid task
+unsafe impl<'d, M, T> Sync for EspL2cap<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+/// An open L2CAP channel
+///
+/// The channel is closed when dropped.
+pub struct L2capChannel {
+    fd: i32,
+}
+
+impl L2capChannel {
+    /// Wrap the file descriptor of a channel, as reported by `L2capEvent::Opened`
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be the file descriptor of an open channel, not owned by anything else.
+    pub unsafe fn new(fd: i32) -> Self {
+        Self { fd }
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Read the data received on the channel, blocking until some data is available
+    ///
+    /// Returns 0 once the channel is closed.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = unsafe { crate::sys::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+
+        if len < 0 {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            Ok(len as _)
+        }
+    }
+
+    /// Write data to the channel, blocking while the channel is congested
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = unsafe { crate::sys::write(self.fd, buf.as_ptr() as *const _, buf.len()) };
+
+        if len < 0 {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            Ok(len as _)
+        }
+    }
+}
+
+impl Drop for L2capChannel {
+    fn drop(&mut self) {
+        unsafe {
+            crate::sys::close(self.fd);
+        }
+    }
+}
+
+impl ErrorType for L2capChannel {
+    type Error = EspIOError;
+}
+
+impl Read for L2capChannel {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let size = L2capChannel::read(self, buf)?;
+
+        Ok(size)
+    }
+}
+
+impl Write for L2capChannel {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let size = L2capChannel::write(self, buf)?;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+static SINGLETON: BtSingleton<L2capEvent, ()> = BtSingleton::new(());
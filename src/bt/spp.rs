@@ -0,0 +1,545 @@
+//! Bluetooth Classic Serial Port Profile
+//!
+//! `EspSpp` accepts incoming connections on a local server channel (`start_server`) or connects to the
+//! server channel of a peer discovered with `start_discovery` (`connect`).
+//!
+//! In `SppMode::Vfs`, each connection is exposed by Bluedroid as a VFS file descriptor, reported by
+//! `SppEvent::Opened` / `SppEvent::ServerOpened`, which can be wrapped in an `SppStream` implementing
+//! the `embedded_svc::io` `Read` and `Write` traits. Writes block while the connection is congested.
+//!
+//! In `SppMode::Callback`, the received data is reported with `SppEvent::DataReceived` instead, and
+//! data is sent with `EspSpp::write`. The application should stop writing after a write reported
+//! the connection as congested, until `SppEvent::Congestion` reports it as uncongested again.
+
+use core::borrow::Borrow;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+
+use embedded_svc::io::{ErrorType, Read, Write};
+
+use log::debug;
+
+use num_enum::TryFromPrimitive;
+
+use crate::bt::{BdAddr, BtClassicEnabled, BtDriver, BtSingleton};
+use crate::io::EspIOError;
+use crate::private::cstr::to_cstring_arg;
+use crate::sys::*;
+
+/// A connection handle
+pub type Handle = u32;
+
+/// A server channel number
+pub type Scn = u8;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum SppMode {
+    /// The data is exchanged with `SppEvent::DataReceived` and `EspSpp::write`
+    #[default]
+    Callback,
+    /// The data is exchanged with the `SppStream` over the file descriptor of each connection
+    Vfs,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SppConfiguration {
+    pub mode: SppMode,
+    pub enable_l2cap_ertm: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u32)]
+pub enum SppStatus {
+    Success = esp_spp_status_t_ESP_SPP_SUCCESS,
+    Failure = esp_spp_status_t_ESP_SPP_FAILURE,
+    Busy = esp_spp_status_t_ESP_SPP_BUSY,
+    NoData = esp_spp_status_t_ESP_SPP_NO_DATA,
+    NoResource = esp_spp_status_t_ESP_SPP_NO_RESOURCE,
+    NeedInit = esp_spp_status_t_ESP_SPP_NEED_INIT,
+    NeedDeinit = esp_spp_status_t_ESP_SPP_NEED_DEINIT,
+    NoConnection = esp_spp_status_t_ESP_SPP_NO_CONNECTION,
+    NoServer = esp_spp_status_t_ESP_SPP_NO_SERVER,
+}
+
+/// The security requirements of a connection
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum SppSecurity {
+    #[default]
+    None = ESP_SPP_SEC_NONE,
+    Authorize = ESP_SPP_SEC_AUTHORIZE,
+    Authenticate = ESP_SPP_SEC_AUTHENTICATE,
+    /// Authenticate and encrypt
+    Encrypt = ESP_SPP_SEC_AUTHENTICATE | ESP_SPP_SEC_ENCRYPT,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum SppRole {
+    #[default]
+    Master = esp_spp_role_t_ESP_SPP_ROLE_MASTER,
+    Slave = esp_spp_role_t_ESP_SPP_ROLE_SLAVE,
+}
+
+pub struct EventRawData<'a>(pub &'a esp_spp_cb_param_t);
+
+impl<'a> Debug for EventRawData<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EventRawData").finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum SppEvent<'a> {
+    Initialized(SppStatus),
+    Deinitialized(SppStatus),
+    DiscoveryComplete {
+        status: SppStatus,
+        /// The server channels found on the peer
+        scns: &'a [Scn],
+    },
+    /// A connection initiated with `EspSpp::connect` is open
+    Opened {
+        status: SppStatus,
+        handle: Handle,
+        /// The VFS file descriptor of the connection in `SppMode::Vfs`, to be wrapped with `SppStream::new`
+        fd: i32,
+        addr: BdAddr,
+    },
+    /// A connection to the server started with `EspSpp::start_server` is open
+    ServerOpened {
+        status: SppStatus,
+        handle: Handle,
+        new_listen_handle: Handle,
+        /// The VFS file descriptor of the connection in `SppMode::Vfs`, to be wrapped with `SppStream::new`
+        fd: i32,
+        addr: BdAddr,
+    },
+    Closed {
+        status: SppStatus,
+        handle: Handle,
+        /// `true` if the connection was closed by the peer or because of a link loss
+        is_async: bool,
+    },
+    ServerStarted {
+        status: SppStatus,
+        handle: Handle,
+        sec_id: u8,
+        scn: Scn,
+    },
+    ServerStopped {
+        status: SppStatus,
+        scn: Scn,
+    },
+    ClientInitiated {
+        status: SppStatus,
+        handle: Handle,
+        sec_id: u8,
+    },
+    /// Data received in `SppMode::Callback`
+    DataReceived {
+        status: SppStatus,
+        handle: Handle,
+        data: &'a [u8],
+    },
+    /// The congestion status of a connection changed, in `SppMode::Callback`
+    Congestion {
+        status: SppStatus,
+        handle: Handle,
+        congested: bool,
+    },
+    /// A write with `EspSpp::write` completed
+    Written {
+        status: SppStatus,
+        handle: Handle,
+        len: usize,
+        /// `true` if the connection is now congested
+        congested: bool,
+    },
+    VfsRegistered(SppStatus),
+    VfsUnregistered(SppStatus),
+    Other {
+        raw_event: esp_spp_cb_event_t,
+        raw_data: EventRawData<'a>,
+    },
+}
+
+#[allow(non_upper_case_globals)]
+impl<'a> From<(esp_spp_cb_event_t, &'a esp_spp_cb_param_t)> for SppEvent<'a> {
+    fn from(value: (esp_spp_cb_event_t, &'a esp_spp_cb_param_t)) -> Self {
+        let (event, param) = value;
+
+        unsafe {
+            match event {
+                esp_spp_cb_event_t_ESP_SPP_INIT_EVT => {
+                    Self::Initialized(param.init.status.try_into().unwrap())
+                }
+                esp_spp_cb_event_t_ESP_SPP_UNINIT_EVT => {
+                    Self::Deinitialized(param.uninit.status.try_into().unwrap())
+                }
+                esp_spp_cb_event_t_ESP_SPP_DISCOVERY_COMP_EVT => Self::DiscoveryComplete {
+                    status: param.disc_comp.status.try_into().unwrap(),
+                    scns: &param.disc_comp.scn
+                        [..(param.disc_comp.scn_num as usize).min(param.disc_comp.scn.len())],
+                },
+                esp_spp_cb_event_t_ESP_SPP_OPEN_EVT => Self::Opened {
+                    status: param.open.status.try_into().unwrap(),
+                    handle: param.open.handle,
+                    fd: param.open.fd,
+                    addr: param.open.rem_bda.into(),
+                },
+                esp_spp_cb_event_t_ESP_SPP_SRV_OPEN_EVT => Self::ServerOpened {
+                    status: param.srv_open.status.try_into().unwrap(),
+                    handle: param.srv_open.handle,
+                    new_listen_handle: param.srv_open.new_listen_handle,
+                    fd: param.srv_open.fd,
+                    addr: param.srv_open.rem_bda.into(),
+                },
+                esp_spp_cb_event_t_ESP_SPP_CLOSE_EVT => Self::Closed {
+                    status: param.close.status.try_into().unwrap(),
+                    handle: param.close.handle,
+                    is_async: param.close.async_,
+                },
+                esp_spp_cb_event_t_ESP_SPP_START_EVT => Self::ServerStarted {
+                    status: param.start.status.try_into().unwrap(),
+                    handle: param.start.handle,
+                    sec_id: param.start.sec_id,
+                    scn: param.start.scn,
+                },
+                esp_spp_cb_event_t_ESP_SPP_SRV_STOP_EVT => Self::ServerStopped {
+                    status: param.srv_stop.status.try_into().unwrap(),
+                    scn: param.srv_stop.scn,
+                },
+                esp_spp_cb_event_t_ESP_SPP_CL_INIT_EVT => Self::ClientInitiated {
+                    status: param.cl_init.status.try_into().unwrap(),
+                    handle: param.cl_init.handle,
+                    sec_id: param.cl_init.sec_id,
+                },
+                esp_spp_cb_event_t_ESP_SPP_DATA_IND_EVT => Self::DataReceived {
+                    status: param.data_ind.status.try_into().unwrap(),
+                    handle: param.data_ind.handle,
+                    data: if param.data_ind.data.is_null() {
+                        &[]
+                    } else {
+                        core::slice::from_raw_parts(param.data_ind.data, param.data_ind.len as _)
+                    },
+                },
+                esp_spp_cb_event_t_ESP_SPP_CONG_EVT => Self::Congestion {
+                    status: param.cong.status.try_into().unwrap(),
+                    handle: param.cong.handle,
+                    congested: param.cong.cong,
+                },
+                esp_spp_cb_event_t_ESP_SPP_WRITE_EVT => Self::Written {
+                    status: param.write.status.try_into().unwrap(),
+                    handle: param.write.handle,
+                    len: param.write.len as _,
+                    congested: param.write.cong,
+                },
+                esp_spp_cb_event_t_ESP_SPP_VFS_REGISTER_EVT => {
+                    Self::VfsRegistered(param.vfs_register.status.try_into().unwrap())
+                }
+                esp_spp_cb_event_t_ESP_SPP_VFS_UNREGISTER_EVT => {
+                    Self::VfsUnregistered(param.vfs_unregister.status.try_into().unwrap())
+                }
+                _ => Self::Other {
+                    raw_event: event,
+                    raw_data: EventRawData(param),
+                },
+            }
+        }
+    }
+}
+
+pub struct EspSpp<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    _driver: T,
+    mode: SppMode,
+    _p: PhantomData<&'d ()>,
+    _m: PhantomData<M>,
+}
+
+impl<'d, M, T> EspSpp<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    pub fn new(driver: T, conf: &SppConfiguration) -> Result<Self, EspError> {
+        SINGLETON.take()?;
+
+        esp!(unsafe { esp_spp_register_callback(Some(Self::event_handler)) })?;
+
+        let cfg = esp_spp_cfg_t {
+            mode: match conf.mode {
+                SppMode::Callback => esp_spp_mode_t_ESP_SPP_MODE_CB,
+                SppMode::Vfs => esp_spp_mode_t_ESP_SPP_MODE_VFS,
+            },
+            enable_l2cap_ertm: conf.enable_l2cap_ertm,
+            ..Default::default()
+        };
+
+        esp!(unsafe { esp_spp_enhanced_init(&cfg) })?;
+
+        if conf.mode == SppMode::Vfs {
+            esp!(unsafe { esp_spp_vfs_register() })?;
+        }
+
+        Ok(Self {
+            _driver: driver,
+            mode: conf.mode,
+            _p: PhantomData,
+            _m: PhantomData,
+        })
+    }
+
+    pub fn subscribe<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut(SppEvent) + Send + 'static,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// This method - in contrast to method `subscribe` - allows the user to pass
+    /// a non-static callback/closure. This enables users to borrow
+    /// - in the closure - variables that live on the stack - or more generally - in the same
+    ///   scope where the service is created.
+    ///
+    /// HOWEVER: care should be taken NOT to call `core::mem::forget()` on the service,
+    /// as that would immediately lead to an UB (crash).
+    /// Also note that forgetting the service might happen with `Rc` and `Arc`
+    /// when circular references are introduced: https://github.com/rust-lang/rust/issues/24456
+    ///
+    /// The reason is that the closure is actually sent to a hidden ESP IDF thread.
+    /// This means that if the service is forgotten, Rust is free to e.g. unwind the stack
+    /// and the closure now owned by this other thread will end up with references to variables that no longer exist.
+    ///
+    /// The destructor of the service takes care - prior to the service being dropped and e.g.
+    /// the stack being unwind - to remove the closure from the hidden thread and destroy it.
+    /// Unfortunately, when the service is forgotten, the un-subscription does not happen
+    /// and invalid references are left dangling.
+    ///
+    /// This "local borrowing" will only be possible to express in a safe way once/if `!Leak` types
+    /// are introduced to Rust (i.e. the impossibility to "forget" a type and thus not call its destructor).
+    pub unsafe fn subscribe_nonstatic<F>(&self, events_cb: F) -> Result<(), EspError>
+    where
+        F: FnMut(SppEvent) + Send + 'd,
+    {
+        SINGLETON.subscribe(events_cb);
+
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self) -> Result<(), EspError> {
+        SINGLETON.unsubscribe();
+
+        Ok(())
+    }
+
+    /// Discover the SPP server channels of a peer
+    ///
+    /// The channels are reported with `SppEvent::DiscoveryComplete`.
+    pub fn start_discovery(&self, addr: &BdAddr) -> Result<(), EspError> {
+        esp!(unsafe { esp_spp_start_discovery(addr as *const _ as *mut _) })
+    }
+
+    /// Connect to a server channel of a peer
+    pub fn connect(
+        &self,
+        addr: &BdAddr,
+        remote_scn: Scn,
+        security: SppSecurity,
+        role: SppRole,
+    ) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_spp_connect(
+                security as _,
+                role as _,
+                remote_scn,
+                addr as *const _ as *mut _,
+            )
+        })
+    }
+
+    pub fn disconnect(&self, handle: Handle) -> Result<(), EspError> {
+        esp!(unsafe { esp_spp_disconnect(handle) })
+    }
+
+    /// Accept connections on a local server channel, or on any free channel if `local_scn` is `None`
+    ///
+    /// The channel number is reported with `SppEvent::ServerStarted`.
+    pub fn start_server(
+        &self,
+        name: &str,
+        local_scn: Option<Scn>,
+        security: SppSecurity,
+        role: SppRole,
+    ) -> Result<(), EspError> {
+        let name = to_cstring_arg(name)?;
+
+        esp!(unsafe {
+            esp_spp_start_srv(
+                security as _,
+                role as _,
+                local_scn.unwrap_or(0),
+                name.as_ptr(),
+            )
+        })
+    }
+
+    pub fn stop_server(&self, local_scn: Scn) -> Result<(), EspError> {
+        esp!(unsafe { esp_spp_stop_srv_scn(local_scn) })
+    }
+
+    pub fn stop_all_servers(&self) -> Result<(), EspError> {
+        esp!(unsafe { esp_spp_stop_srv() })
+    }
+
+    /// Send data over a connection, in `SppMode::Callback`
+    ///
+    /// The completion is reported with `SppEvent::Written`.
+    pub fn write(&self, handle: Handle, data: &[u8]) -> Result<(), EspError> {
+        if self.mode != SppMode::Callback {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        esp!(unsafe { esp_spp_write(handle, data.len() as _, data.as_ptr() as *mut _) })
+    }
+
+    unsafe extern "C" fn event_handler(event: esp_spp_cb_event_t, param: *mut esp_spp_cb_param_t) {
+        let param = unsafe { param.as_ref() }.unwrap();
+        let event = SppEvent::from((event, param));
+
+        debug!("Got event {{ {:#?} }}", event);
+
+        SINGLETON.call(event);
+    }
+}
+
+impl<'d, M, T> Drop for EspSpp<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>>,
+{
+    fn drop(&mut self) {
+        self.unsubscribe().unwrap();
+
+        if self.mode == SppMode::Vfs {
+            esp!(unsafe { esp_spp_vfs_unregister() }).unwrap();
+        }
+
+        esp!(unsafe { esp_spp_deinit() }).unwrap();
+
+        SINGLETON.release().unwrap();
+    }
+}
+
+unsafe impl<'d, M, T> Send for EspSpp<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+// Safe because the ESP IDF Bluedroid APIs all do message passing
+// to a dedicated Bluedroid task
+unsafe impl<'d, M, T> Sync for EspSpp<'d, M, T>
+where
+    M: BtClassicEnabled,
+    T: Borrow<BtDriver<'d, M>> + Send,
+{
+}
+
+/// An open SPP connection in `SppMode::Vfs`
+///
+/// The connection is closed when dropped.
+pub struct SppStream {
+    fd: i32,
+}
+
+impl SppStream {
+    /// Wrap the file descriptor of a connection, as reported by `SppEvent::Opened` or `SppEvent::ServerOpened`
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be the file descriptor of an open connection, not owned by anything else.
+    pub unsafe fn new(fd: i32) -> Self {
+        Self { fd }
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// Read the data received on the connection, blocking until some data is available
+    ///
+    /// Returns 0 once the connection is closed.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = unsafe { crate::sys::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+
+        if len < 0 {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            Ok(len as _)
+        }
+    }
+
+    /// Write data to the connection, blocking while the connection is congested
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = unsafe { crate::sys::write(self.fd, buf.as_ptr() as *const _, buf.len()) };
+
+        if len < 0 {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            Ok(len as _)
+        }
+    }
+}
+
+impl Drop for SppStream {
+    fn drop(&mut self) {
+        unsafe {
+            crate::sys::close(self.fd);
+        }
+    }
+}
+
+impl ErrorType for SppStream {
+    type Error = EspIOError;
+}
+
+impl Read for SppStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let size = SppStream::read(self, buf)?;
+
+        Ok(size)
+    }
+}
+
+impl Write for SppStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let size = SppStream::write(self, buf)?;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+static SINGLETON: BtSingleton<SppEvent, ()> = BtSingleton::new(());
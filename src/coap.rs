@@ -0,0 +1,826 @@
+//! CoAP client and server, over UDP or DTLS
+//!
+//! A pure Rust implementation of the Constrained Application Protocol (RFC 7252), with resource
+//! observation (RFC 7641) and block-wise transfers (RFC 7959), for e.g. LwM2M or
+//! Thread-adjacent deployments.
+//!
+//! `CoapClient` sends confirmable requests - retransmitted with exponential back-off until
+//! acknowledged - and handles both piggybacked and separate responses. Payloads larger than
+//! the block size are transferred block-wise, in both directions.
+//!
+//! `CoapServer` dispatches the requests to the handlers of its resources, by path. It can serve
+//! a plain UDP socket (`CoapServer::serve_udp`) or a DTLS session (`CoapServer::serve`).
+//!
+//! Both run on top of a `Transport`: a connected `std::net::UdpSocket`, or a `tls::dtls::EspDtls`
+//! session for CoAPs.
+
+use core::fmt::{self, Debug};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
+
+use crate::sys::*;
+
+pub use message::*;
+
+pub mod message;
+
+/// The default port of CoAP
+pub const PORT: u16 = 5683;
+
+/// The default port of CoAPs
+pub const SECURE_PORT: u16 = 5684;
+
+// The largest datagram accepted
+const MAX_DATAGRAM_LEN: usize = 1280;
+
+/// A datagram transport, connected to the peer
+pub trait Transport {
+    fn send(&mut self, data: &[u8]) -> Result<(), EspError>;
+
+    /// Receive a datagram, returning `None` if none was received within `timeout`
+    fn recv(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<Option<usize>, EspError>;
+}
+
+impl Transport for UdpSocket {
+    fn send(&mut self, data: &[u8]) -> Result<(), EspError> {
+        UdpSocket::send(self, data)
+            .map(|_| ())
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())
+    }
+
+    fn recv(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<Option<usize>, EspError> {
+        if timeout == Some(Duration::ZERO) {
+            return Ok(None);
+        }
+
+        self.set_read_timeout(timeout)
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        match UdpSocket::recv(self, buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(_) => Err(EspError::from_infallible::<ESP_FAIL>()),
+        }
+    }
+}
+
+#[cfg(all(
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_mbedtls_ssl_proto_dtls,
+    not(esp_idf_version_major = "4")
+))]
+impl Transport for crate::tls::dtls::EspDtls {
+    fn send(&mut self, data: &[u8]) -> Result<(), EspError> {
+        self.write(data).map(|_| ())
+    }
+
+    fn recv(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<Option<usize>, EspError> {
+        if timeout == Some(Duration::ZERO) {
+            return Ok(None);
+        }
+
+        self.set_read_timeout(timeout);
+
+        match self.read(buf) {
+            // The peer closed the session
+            Ok(0) => Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+            Ok(len) => Ok(Some(len)),
+            Err(err) if err.code() == ESP_ERR_TIMEOUT => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The transmission parameters of a `CoapClient`
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// The initial timeout of the acknowledgement of a confirmable request, which is randomized
+    /// up to 1.5 times and doubled on each retransmission
+    pub ack_timeout: Duration,
+    /// The number of retransmissions, before giving up
+    pub max_retransmit: u8,
+    /// The timeout of a separate response, once the request is acknowledged
+    pub response_timeout: Duration,
+    /// The preferred size of the blocks of the block-wise transfers, between 16 and 1024 bytes
+    pub block_size: usize,
+}
+
+impl ClientConfig {
+    pub const fn new() -> Self {
+        Self {
+            ack_timeout: Duration::from_secs(2),
+            max_retransmit: 4,
+            response_timeout: Duration::from_secs(30),
+            block_size: 1024,
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A CoAP client, for the server its transport is connected to
+pub struct CoapClient<T> {
+    transport: T,
+    conf: ClientConfig,
+    next_message_id: u16,
+    buf: Box<[u8; MAX_DATAGRAM_LEN]>,
+}
+
+impl<T> CoapClient<T>
+where
+    T: Transport,
+{
+    pub fn new(transport: T, conf: &ClientConfig) -> Self {
+        Self {
+            transport,
+            conf: conf.clone(),
+            next_message_id: unsafe { esp_random() } as u16,
+            buf: Box::new([0; MAX_DATAGRAM_LEN]),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn release(self) -> T {
+        self.transport
+    }
+
+    /// Fetch a resource, e.g. `sensors/temperature`
+    pub fn get(&mut self, path: &str) -> Result<Message, EspError> {
+        self.send(Message::request(Code::GET, path))
+    }
+
+    /// Post the payload to a resource
+    pub fn post(
+        &mut self,
+        path: &str,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> Result<Message, EspError> {
+        self.send(Self::with_payload(
+            Code::POST,
+            path,
+            content_format,
+            payload,
+        ))
+    }
+
+    /// Create or replace a resource with the payload
+    pub fn put(
+        &mut self,
+        path: &str,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> Result<Message, EspError> {
+        self.send(Self::with_payload(Code::PUT, path, content_format, payload))
+    }
+
+    pub fn delete(&mut self, path: &str) -> Result<Message, EspError> {
+        self.send(Message::request(Code::DELETE, path))
+    }
+
+    /// Send a request and return its response, transferring the request and response payloads
+    /// block-wise when larger than the block size
+    ///
+    /// # Errors
+    ///
+    /// * `ESP_ERR_TIMEOUT` if the server did not respond
+    /// * `ESP_ERR_INVALID_RESPONSE` if the server reset the request, or a
+    ///   block-wise transfer failed
+    pub fn send(&mut self, mut request: Message) -> Result<Message, EspError> {
+        let block_size = Block::new(0, false, self.conf.block_size).size();
+
+        let mut response = if request.payload.len() > block_size {
+            self.send_block1(request.clone(), block_size)?
+        } else {
+            self.exchange(request.clone())?
+        };
+
+        // Fetch the remaining blocks of the response, if any
+        let mut payload = Vec::new();
+
+        while let Some(block) = response.block2() {
+            if !response.code.is_success() || block.offset() != payload.len() {
+                break;
+            }
+
+            payload.extend_from_slice(&response.payload);
+
+            if !block.more {
+                response.payload = core::mem::take(&mut payload);
+                response.remove_option(option::BLOCK2);
+                break;
+            }
+
+            request.payload.clear();
+            request.remove_option(option::BLOCK1);
+            request.set_uint_option(
+                option::BLOCK2,
+                Block::new(block.num + 1, false, block.size()).encode(),
+            );
+
+            response = self.exchange(request.clone())?;
+        }
+
+        Ok(response)
+    }
+
+    /// Observe a resource: `f` is called with the current representation, and then with each
+    /// notification, until it returns `false`, after which the observation is cancelled
+    ///
+    /// Returns when the observation ended, i.e. `f` returned `false` or the server responded
+    /// without the Observe option - e.g. with an error.
+    pub fn observe(
+        &mut self,
+        path: &str,
+        mut f: impl FnMut(&Message) -> bool,
+    ) -> Result<(), EspError> {
+        let mut request = Message::request(Code::GET, path);
+        request.token = Self::new_token();
+        request.add_uint_option(option::OBSERVE, 0);
+
+        let response = self.exchange(request.clone())?;
+
+        let mut observing = response.observe().is_some();
+        let mut keep = f(&response);
+
+        while observing && keep {
+            let Some(len) = self.transport.recv(&mut self.buf[..], None)? else {
+                continue;
+            };
+
+            let Ok(notification) = Message::decode(&self.buf[..len]) else {
+                continue;
+            };
+
+            if notification.token != request.token {
+                self.reject(&notification)?;
+                continue;
+            }
+
+            if notification.mtype == MessageType::Confirmable {
+                self.acknowledge(&notification)?;
+            }
+
+            observing = notification.observe().is_some();
+            keep = f(&notification);
+        }
+
+        if observing {
+            // Deregister
+            request.set_uint_option(option::OBSERVE, 1);
+            self.exchange(request)?;
+        }
+
+        Ok(())
+    }
+
+    fn with_payload(
+        code: Code,
+        path: &str,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> Message {
+        let mut request = Message::request(code, path);
+
+        if let Some(content_format) = content_format {
+            request.set_content_format(content_format);
+        }
+
+        request.payload = payload.to_vec();
+
+        request
+    }
+
+    fn send_block1(&mut self, request: Message, block_size: usize) -> Result<Message, EspError> {
+        let blocks = request.payload.chunks(block_size).collect::<Vec<_>>();
+
+        for (num, chunk) in blocks.iter().enumerate() {
+            let more = num + 1 < blocks.len();
+
+            let mut block = request.clone();
+            block.payload = chunk.to_vec();
+            block.set_uint_option(
+                option::BLOCK1,
+                Block::new(num as _, more, block_size).encode(),
+            );
+
+            if num == 0 {
+                block.set_uint_option(option::SIZE1, request.payload.len() as _);
+            }
+
+            let response = self.exchange(block)?;
+
+            if !more || response.code != Code::CONTINUE {
+                // Either the final response, or an error
+                return Ok(response);
+            }
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())
+    }
+
+    // Send a confirmable request, and wait for its response
+    fn exchange(&mut self, mut request: Message) -> Result<Message, EspError> {
+        request.mtype = MessageType::Confirmable;
+        request.message_id = self.next_message_id();
+
+        if request.token.is_empty() {
+            request.token = Self::new_token();
+        }
+
+        let data = request.encode()?;
+
+        // Randomized between ACK_TIMEOUT and 1.5 times ACK_TIMEOUT
+        let mut timeout =
+            self.conf.ack_timeout + self.conf.ack_timeout * (unsafe { esp_random() } % 500) / 1000;
+
+        for _ in 0..=self.conf.max_retransmit {
+            self.transport.send(&data)?;
+
+            let deadline = Instant::now() + timeout;
+
+            while let Some(message) = self.recv_until(deadline)? {
+                match message.mtype {
+                    MessageType::Acknowledgement
+                        if message.message_id == request.message_id
+                            && (message.code == Code::EMPTY || message.token == request.token) =>
+                    {
+                        if message.code == Code::EMPTY {
+                            // The response follows separately
+                            return self.recv_separate(&request);
+                        }
+
+                        return Ok(message);
+                    }
+                    MessageType::Reset if message.message_id == request.message_id => {
+                        return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+                    }
+                    MessageType::Confirmable | MessageType::NonConfirmable
+                        if message.token == request.token && !message.code.is_request() =>
+                    {
+                        // A separate response, whose empty acknowledgement was lost
+                        if message.mtype == MessageType::Confirmable {
+                            self.acknowledge(&message)?;
+                        }
+
+                        return Ok(message);
+                    }
+                    _ => self.reject(&message)?,
+                }
+            }
+
+            timeout *= 2;
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    fn recv_separate(&mut self, request: &Message) -> Result<Message, EspError> {
+        let deadline = Instant::now() + self.conf.response_timeout;
+
+        while let Some(message) = self.recv_until(deadline)? {
+            if message.token == request.token
+                && matches!(
+                    message.mtype,
+                    MessageType::Confirmable | MessageType::NonConfirmable
+                )
+            {
+                if message.mtype == MessageType::Confirmable {
+                    self.acknowledge(&message)?;
+                }
+
+                return Ok(message);
+            }
+
+            self.reject(&message)?;
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    // Receive the next valid message, or `None` once the deadline passed
+    fn recv_until(&mut self, deadline: Instant) -> Result<Option<Message>, EspError> {
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            let Some(len) = self.transport.recv(&mut self.buf[..], Some(timeout))? else {
+                return Ok(None);
+            };
+
+            if let Ok(message) = Message::decode(&self.buf[..len]) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    fn acknowledge(&mut self, message: &Message) -> Result<(), EspError> {
+        let ack = Message::empty(MessageType::Acknowledgement, message.message_id);
+
+        self.transport.send(&ack.encode()?)
+    }
+
+    // Reset the unexpected confirmable messages, e.g. notifications of a cancelled observation
+    fn reject(&mut self, message: &Message) -> Result<(), EspError> {
+        if message.mtype == MessageType::Confirmable {
+            let reset = Message::empty(MessageType::Reset, message.message_id);
+
+            self.transport.send(&reset.encode()?)?;
+        }
+
+        Ok(())
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.next_message_id
+    }
+
+    fn new_token() -> Vec<u8> {
+        unsafe { esp_random() }.to_be_bytes().to_vec()
+    }
+}
+
+impl<T> Debug for CoapClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CoapClient")
+            .field("conf", &self.conf)
+            .finish_non_exhaustive()
+    }
+}
+
+type Handler = Box<dyn FnMut(&Message) -> Message + Send + 'static>;
+
+// The responses to the recent confirmable requests, to answer their retransmissions
+const DEDUP_LEN: usize = 8;
+
+// The block-wise requests received at once, beyond which the oldest is evicted
+const MAX_PARTIAL: usize = 4;
+
+// How long a block-wise request is kept without receiving its next block
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A block-wise request being received
+struct Partial {
+    peer: Option<SocketAddr>,
+    path: String,
+    payload: Vec<u8>,
+    updated: Instant,
+}
+
+/// A CoAP server, dispatching the requests to its resources
+///
+/// The handlers are called with the complete request - i.e. once all the blocks of a block-wise
+/// request are received - and return the complete response, created with `Message::response`.
+/// Responses larger than the block size are sent block-wise.
+///
+/// The payloads of the block-wise requests being received are limited to `max_request_len`
+/// bytes altogether: larger requests are answered with 4.13 (Request Entity Too Large), and the
+/// oldest requests are evicted to make room for the others, as are those whose next block does
+/// not come within a minute.
+pub struct CoapServer {
+    resources: Vec<(String, Handler)>,
+    block_size: usize,
+    max_request_len: usize,
+    partial: Vec<Partial>,
+    recent: heapless::Deque<(Option<SocketAddr>, u16, Vec<u8>), DEDUP_LEN>,
+    buf: Box<[u8; MAX_DATAGRAM_LEN]>,
+}
+
+impl CoapServer {
+    pub fn new() -> Self {
+        Self {
+            resources: Vec::new(),
+            block_size: 1024,
+            max_request_len: 16 * 1024,
+            partial: Vec::new(),
+            recent: heapless::Deque::new(),
+            buf: Box::new([0; MAX_DATAGRAM_LEN]),
+        }
+    }
+
+    /// Set the size of the blocks of the block-wise responses, between 16 and 1024 bytes
+    pub fn set_block_size(&mut self, size: usize) {
+        self.block_size = Block::new(0, false, size).size();
+    }
+
+    /// Set the largest payload of the block-wise requests, 16 KiB by default
+    pub fn set_max_request_len(&mut self, len: usize) {
+        self.max_request_len = len;
+    }
+
+    /// Add a resource, e.g. `sensors/temperature`, replacing any previous one with the same path
+    pub fn resource<F>(&mut self, path: &str, handler: F)
+    where
+        F: FnMut(&Message) -> Message + Send + 'static,
+    {
+        let path = path.trim_matches('/');
+
+        self.resources.retain(|(other, _)| other != path);
+        self.resources.push((path.into(), Box::new(handler)));
+    }
+
+    /// Return the resources in the CoRE Link Format, e.g. as served at `.well-known/core`
+    pub fn link_format(&self) -> String {
+        let mut links = String::new();
+
+        for (path, _) in &self.resources {
+            if !links.is_empty() {
+                links.push(',');
+            }
+
+            links.push_str("</");
+            links.push_str(path);
+            links.push('>');
+        }
+
+        links
+    }
+
+    /// Serve the requests of any peer on a bound UDP socket, until an error occurs
+    pub fn serve_udp(&mut self, socket: &UdpSocket) -> Result<(), EspError> {
+        loop {
+            let (len, peer) = socket
+                .recv_from(&mut self.buf[..])
+                .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+            let request = self.buf[..len].to_vec();
+
+            if let Some(response) = self.handle(Some(peer), &request) {
+                socket
+                    .send_to(&response, peer)
+                    .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+            }
+        }
+    }
+
+    /// Serve the requests of the peer of a connected transport - e.g. a DTLS session - until
+    /// an error occurs, e.g. the peer closed the session
+    pub fn serve<T>(&mut self, transport: &mut T) -> Result<(), EspError>
+    where
+        T: Transport,
+    {
+        loop {
+            let Some(len) = transport.recv(&mut self.buf[..], None)? else {
+                continue;
+            };
+
+            let request = self.buf[..len].to_vec();
+
+            if let Some(response) = self.handle(None, &request) {
+                transport.send(&response)?;
+            }
+        }
+    }
+
+    /// Handle a datagram received from `peer`, returning the datagram to respond with, if any
+    pub fn handle(&mut self, peer: Option<SocketAddr>, data: &[u8]) -> Option<Vec<u8>> {
+        let request = Message::decode(data).ok()?;
+
+        match request.mtype {
+            MessageType::Confirmable | MessageType::NonConfirmable if request.code.is_request() => {
+            }
+            MessageType::Confirmable => {
+                // An empty message, i.e. a CoAP ping, or an unexpected response
+                return Message::empty(MessageType::Reset, request.message_id)
+                    .encode()
+                    .ok();
+            }
+            _ => return None,
+        }
+
+        if request.mtype == MessageType::Confirmable {
+            if let Some((_, _, response)) = self
+                .recent
+                .iter()
+                .find(|(other, id, _)| *other == peer && *id == request.message_id)
+            {
+                return Some(response.clone());
+            }
+        }
+
+        let response = self.respond(peer, &request).encode().ok()?;
+
+        if request.mtype == MessageType::Confirmable {
+            if self.recent.is_full() {
+                self.recent.pop_front();
+            }
+
+            let _ = self
+                .recent
+                .push_back((peer, request.message_id, response.clone()));
+        }
+
+        Some(response)
+    }
+
+    fn respond(&mut self, peer: Option<SocketAddr>, request: &Message) -> Message {
+        let path = request.uri_path();
+
+        let Some(index) = self.resources.iter().position(|(other, _)| *other == path) else {
+            if path == ".well-known/core" && request.code == Code::GET {
+                let mut response = Message::response(request, Code::CONTENT);
+                response.set_content_format(content_format::LINK_FORMAT);
+                response.payload = self.link_format().into_bytes();
+
+                return self.block2(request, response);
+            }
+
+            return Message::response(request, Code::NOT_FOUND);
+        };
+
+        // Reassemble the block-wise requests
+        let whole;
+        let mut block1 = None;
+        let request = if let Some(block) = request.block1() {
+            let now = Instant::now();
+
+            self.partial
+                .retain(|partial| now.duration_since(partial.updated) < PARTIAL_TIMEOUT);
+
+            let position = self
+                .partial
+                .iter()
+                .position(|partial| partial.peer == peer && partial.path == path);
+
+            let mut partial = match (block.num, position) {
+                (0, Some(position)) => {
+                    let mut partial = self.partial.swap_remove(position);
+                    partial.payload.clear();
+                    partial
+                }
+                (0, None) => Partial {
+                    peer,
+                    path: path.clone(),
+                    payload: Vec::new(),
+                    updated: now,
+                },
+                (_, Some(position)) if self.partial[position].payload.len() == block.offset() => {
+                    self.partial.swap_remove(position)
+                }
+                _ => return Message::response(request, Code::REQUEST_ENTITY_INCOMPLETE),
+            };
+
+            if partial.payload.len() + request.payload.len() > self.max_request_len {
+                let mut response = Message::response(request, Code::REQUEST_ENTITY_TOO_LARGE);
+                response.set_uint_option(option::SIZE1, self.max_request_len as _);
+
+                return response;
+            }
+
+            partial.payload.extend_from_slice(&request.payload);
+            partial.updated = now;
+
+            if block.more {
+                // Make room for the request, evicting the oldest
+                while self.partial.len() >= MAX_PARTIAL
+                    || self
+                        .partial
+                        .iter()
+                        .map(|other| other.payload.len())
+                        .sum::<usize>()
+                        + partial.payload.len()
+                        > self.max_request_len
+                {
+                    let Some(oldest) = self
+                        .partial
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, other)| other.updated)
+                        .map(|(index, _)| index)
+                    else {
+                        break;
+                    };
+
+                    self.partial.swap_remove(oldest);
+                }
+
+                self.partial.push(partial);
+
+                let mut response = Message::response(request, Code::CONTINUE);
+                response.set_uint_option(option::BLOCK1, block.encode());
+
+                return response;
+            }
+
+            let mut complete = request.clone();
+            complete.payload = partial.payload;
+            complete.remove_option(option::BLOCK1);
+
+            whole = complete;
+            block1 = Some(block);
+
+            &whole
+        } else {
+            request
+        };
+
+        let mut response = (self.resources[index].1)(request);
+        response.message_id = request.message_id;
+        response.token.clone_from(&request.token);
+
+        if let Some(block) = block1 {
+            response.set_uint_option(option::BLOCK1, block.encode());
+        }
+
+        self.block2(request, response)
+    }
+
+    // Slice the response to the block requested, if larger than a block
+    fn block2(&self, request: &Message, mut response: Message) -> Message {
+        let requested = request.block2();
+
+        let block_size = requested
+            .map(|block| block.size().min(self.block_size))
+            .unwrap_or(self.block_size);
+
+        if response.payload.len() <= block_size && requested.is_none() {
+            return response;
+        }
+
+        let num = requested
+            .map(|block| (block.offset() / block_size) as u32)
+            .unwrap_or(0);
+        let block = Block::new(num, false, block_size);
+
+        let total = response.payload.len();
+        if block.offset() > total {
+            return Message::response(request, Code::BAD_OPTION);
+        }
+
+        let end = (block.offset() + block_size).min(total);
+
+        response.payload = response.payload[block.offset()..end].to_vec();
+        response.set_uint_option(
+            option::BLOCK2,
+            Block {
+                more: end < total,
+                ..block
+            }
+            .encode(),
+        );
+
+        if num == 0 {
+            response.set_uint_option(option::SIZE2, total as _);
+        }
+
+        response
+    }
+}
+
+impl Default for CoapServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for CoapServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CoapServer")
+            .field(
+                "resources",
+                &self
+                    .resources
+                    .iter()
+                    .map(|(path, _)| path)
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,484 @@
+//! The CoAP message format, as per RFC 7252, and the block options of RFC 7959
+
+use core::fmt::{self, Display};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::sys::{EspError, ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_SIZE};
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xff;
+const MAX_TOKEN_LEN: usize = 8;
+
+/// The option numbers of RFC 7252, RFC 7641 (Observe) and RFC 7959 (block-wise transfers)
+pub mod option {
+    pub const IF_MATCH: u16 = 1;
+    pub const URI_HOST: u16 = 3;
+    pub const ETAG: u16 = 4;
+    pub const IF_NONE_MATCH: u16 = 5;
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const LOCATION_PATH: u16 = 8;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const LOCATION_QUERY: u16 = 20;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    pub const SIZE2: u16 = 28;
+    pub const PROXY_URI: u16 = 35;
+    pub const SIZE1: u16 = 60;
+}
+
+/// The registered content formats most commonly used
+pub mod content_format {
+    pub const TEXT_PLAIN: u16 = 0;
+    pub const LINK_FORMAT: u16 = 40;
+    pub const XML: u16 = 41;
+    pub const OCTET_STREAM: u16 = 42;
+    pub const JSON: u16 = 50;
+    pub const CBOR: u16 = 60;
+    pub const SENML_JSON: u16 = 110;
+    pub const SENML_CBOR: u16 = 112;
+    pub const LWM2M_TLV: u16 = 11542;
+    pub const LWM2M_JSON: u16 = 11543;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+/// The code of a request (method) or of a response, as `class.detail`
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Code(pub u8);
+
+impl Code {
+    pub const EMPTY: Self = Self::new(0, 0);
+
+    pub const GET: Self = Self::new(0, 1);
+    pub const POST: Self = Self::new(0, 2);
+    pub const PUT: Self = Self::new(0, 3);
+    pub const DELETE: Self = Self::new(0, 4);
+
+    pub const CREATED: Self = Self::new(2, 1);
+    pub const DELETED: Self = Self::new(2, 2);
+    pub const VALID: Self = Self::new(2, 3);
+    pub const CHANGED: Self = Self::new(2, 4);
+    pub const CONTENT: Self = Self::new(2, 5);
+    pub const CONTINUE: Self = Self::new(2, 31);
+
+    pub const BAD_REQUEST: Self = Self::new(4, 0);
+    pub const UNAUTHORIZED: Self = Self::new(4, 1);
+    pub const BAD_OPTION: Self = Self::new(4, 2);
+    pub const FORBIDDEN: Self = Self::new(4, 3);
+    pub const NOT_FOUND: Self = Self::new(4, 4);
+    pub const METHOD_NOT_ALLOWED: Self = Self::new(4, 5);
+    pub const NOT_ACCEPTABLE: Self = Self::new(4, 6);
+    pub const REQUEST_ENTITY_INCOMPLETE: Self = Self::new(4, 8);
+    pub const PRECONDITION_FAILED: Self = Self::new(4, 12);
+    pub const REQUEST_ENTITY_TOO_LARGE: Self = Self::new(4, 13);
+    pub const UNSUPPORTED_CONTENT_FORMAT: Self = Self::new(4, 15);
+
+    pub const INTERNAL_SERVER_ERROR: Self = Self::new(5, 0);
+    pub const NOT_IMPLEMENTED: Self = Self::new(5, 1);
+    pub const SERVICE_UNAVAILABLE: Self = Self::new(5, 3);
+
+    pub const fn new(class: u8, detail: u8) -> Self {
+        Self((class << 5) | (detail & 0x1f))
+    }
+
+    pub const fn class(&self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub const fn detail(&self) -> u8 {
+        self.0 & 0x1f
+    }
+
+    /// Return `true` for the request codes, i.e. the methods
+    pub const fn is_request(&self) -> bool {
+        self.class() == 0 && self.detail() != 0
+    }
+
+    /// Return `true` for the 2.xx response codes
+    pub const fn is_success(&self) -> bool {
+        self.class() == 2
+    }
+}
+
+impl Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.class(), self.detail())
+    }
+}
+
+/// The value of a Block1 or Block2 option
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Block {
+    /// The number of the block
+    pub num: u32,
+    /// Whether more blocks follow
+    pub more: bool,
+    /// The size of the blocks, as its base 2 logarithm minus 4, between 0 (16 bytes) and
+    /// 6 (1024 bytes)
+    pub szx: u8,
+}
+
+impl Block {
+    pub const MAX_SZX: u8 = 6;
+
+    /// Return the block of `num` of the given size - which is rounded down to a power of 2 -
+    /// between 16 and 1024 bytes
+    pub fn new(num: u32, more: bool, size: usize) -> Self {
+        let size = size.clamp(16, 1024);
+
+        Self {
+            num,
+            more,
+            szx: (usize::BITS - 1 - size.leading_zeros()) as u8 - 4,
+        }
+    }
+
+    pub const fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// The offset of the block in the whole payload
+    pub const fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    pub fn decode(value: u32) -> Result<Self, EspError> {
+        let szx = (value & 0x07) as u8;
+
+        if szx > Self::MAX_SZX {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(Self {
+            num: value >> 4,
+            more: value & 0x08 != 0,
+            szx,
+        })
+    }
+
+    pub const fn encode(&self) -> u32 {
+        (self.num << 4) | ((self.more as u32) << 3) | self.szx as u32
+    }
+}
+
+/// A CoAP request or response
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Message {
+    pub mtype: MessageType,
+    pub code: Code,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    // Sorted by option number, as required by the encoding
+    options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub const fn new(mtype: MessageType, code: Code) -> Self {
+        Self {
+            mtype,
+            code,
+            message_id: 0,
+            token: Vec::new(),
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// Create a confirmable request for the given path, e.g. `sensors/temperature`
+    pub fn request(code: Code, path: &str) -> Self {
+        let mut request = Self::new(MessageType::Confirmable, code);
+        request.set_uri_path(path);
+
+        request
+    }
+
+    /// Create the response to a request, piggybacked in its acknowledgement if confirmable
+    pub fn response(request: &Message, code: Code) -> Self {
+        let mtype = if request.mtype == MessageType::Confirmable {
+            MessageType::Acknowledgement
+        } else {
+            MessageType::NonConfirmable
+        };
+
+        let mut response = Self::new(mtype, code);
+        response.message_id = request.message_id;
+        response.token.clone_from(&request.token);
+
+        response
+    }
+
+    /// Create the empty acknowledgement or reset of a message
+    pub fn empty(mtype: MessageType, message_id: u16) -> Self {
+        let mut message = Self::new(mtype, Code::EMPTY);
+        message.message_id = message_id;
+
+        message
+    }
+
+    /// Add an option, after the options with the same number
+    pub fn add_option(&mut self, number: u16, value: &[u8]) {
+        let index = self.options.partition_point(|(other, _)| *other <= number);
+
+        self.options.insert(index, (number, value.to_vec()));
+    }
+
+    /// Add an option with an unsigned integer value
+    pub fn add_uint_option(&mut self, number: u16, value: u32) {
+        let bytes = value.to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+
+        self.add_option(number, &bytes[skip..]);
+    }
+
+    /// Replace all the options with the given number with a single option
+    pub fn set_option(&mut self, number: u16, value: &[u8]) {
+        self.remove_option(number);
+        self.add_option(number, value);
+    }
+
+    /// Replace all the options with the given number with a single unsigned integer option
+    pub fn set_uint_option(&mut self, number: u16, value: u32) {
+        self.remove_option(number);
+        self.add_uint_option(number, value);
+    }
+
+    pub fn remove_option(&mut self, number: u16) {
+        self.options.retain(|(other, _)| *other != number);
+    }
+
+    /// Return the value of the first option with the given number
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options(number).next()
+    }
+
+    /// Return the values of all the options with the given number
+    pub fn options(&self, number: u16) -> impl Iterator<Item = &[u8]> {
+        self.options
+            .iter()
+            .filter(move |(other, _)| *other == number)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Return the value of the first option with the given number, as an unsigned integer
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.option(number).and_then(|value| {
+            (value.len() <= 4).then(|| value.iter().fold(0, |acc, byte| (acc << 8) | *byte as u32))
+        })
+    }
+
+    /// Return all the options, in order
+    pub fn all_options(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.options
+            .iter()
+            .map(|(number, value)| (*number, value.as_slice()))
+    }
+
+    /// Return the Uri-Path options, joined with `/`
+    pub fn uri_path(&self) -> String {
+        Self::join(self.options(option::URI_PATH))
+    }
+
+    /// Set the Uri-Path options from a path, e.g. `sensors/temperature`
+    pub fn set_uri_path(&mut self, path: &str) {
+        self.remove_option(option::URI_PATH);
+
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            self.add_option(option::URI_PATH, segment.as_bytes());
+        }
+    }
+
+    /// Return the Uri-Query options, e.g. `ep=device-1`
+    pub fn uri_queries(&self) -> impl Iterator<Item = &str> {
+        self.options(option::URI_QUERY)
+            .filter_map(|value| core::str::from_utf8(value).ok())
+    }
+
+    /// Return the Location-Path options, joined with `/`
+    pub fn location_path(&self) -> String {
+        Self::join(self.options(option::LOCATION_PATH))
+    }
+
+    pub fn content_format(&self) -> Option<u16> {
+        self.uint_option(option::CONTENT_FORMAT)
+            .map(|format| format as u16)
+    }
+
+    pub fn set_content_format(&mut self, format: u16) {
+        self.set_uint_option(option::CONTENT_FORMAT, format as _);
+    }
+
+    pub fn observe(&self) -> Option<u32> {
+        self.uint_option(option::OBSERVE)
+    }
+
+    pub fn block1(&self) -> Option<Block> {
+        self.uint_option(option::BLOCK1)
+            .and_then(|value| Block::decode(value).ok())
+    }
+
+    pub fn block2(&self) -> Option<Block> {
+        self.uint_option(option::BLOCK2)
+            .and_then(|value| Block::decode(value).ok())
+    }
+
+    /// Encode the message into a datagram
+    pub fn encode(&self) -> Result<Vec<u8>, EspError> {
+        if self.token.len() > MAX_TOKEN_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut buf = Vec::with_capacity(4 + self.token.len() + self.payload.len() + 32);
+
+        buf.push((VERSION << 6) | ((self.mtype as u8) << 4) | self.token.len() as u8);
+        buf.push(self.code.0);
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(&self.token);
+
+        let mut previous = 0;
+
+        for (number, value) in &self.options {
+            let (delta, delta_ext) = Self::encode_nibble((number - previous) as usize);
+            let (len, len_ext) = Self::encode_nibble(value.len());
+
+            buf.push((delta << 4) | len);
+            buf.extend_from_slice(delta_ext.as_slice());
+            buf.extend_from_slice(len_ext.as_slice());
+            buf.extend_from_slice(value);
+
+            previous = *number;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(PAYLOAD_MARKER);
+            buf.extend_from_slice(&self.payload);
+        }
+
+        Ok(buf)
+    }
+
+    /// Decode a datagram into a message
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the datagram is not a well-formed CoAP message.
+    pub fn decode(data: &[u8]) -> Result<Self, EspError> {
+        let invalid = || EspError::from_infallible::<ESP_ERR_INVALID_ARG>();
+
+        if data.len() < 4 || data[0] >> 6 != VERSION {
+            return Err(invalid());
+        }
+
+        let mtype = match (data[0] >> 4) & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        };
+
+        let token_len = (data[0] & 0x0f) as usize;
+        if token_len > MAX_TOKEN_LEN || data.len() < 4 + token_len {
+            return Err(invalid());
+        }
+
+        let mut message = Self::new(mtype, Code(data[1]));
+        message.message_id = u16::from_be_bytes([data[2], data[3]]);
+        message.token = data[4..4 + token_len].to_vec();
+
+        let mut rest = &data[4 + token_len..];
+        let mut number = 0_usize;
+
+        while let Some((&header, tail)) = rest.split_first() {
+            if header == PAYLOAD_MARKER {
+                if tail.is_empty() {
+                    return Err(invalid());
+                }
+
+                message.payload = tail.to_vec();
+                break;
+            }
+
+            rest = tail;
+
+            let delta = Self::decode_nibble(header >> 4, &mut rest).ok_or_else(invalid)?;
+            let len = Self::decode_nibble(header & 0x0f, &mut rest).ok_or_else(invalid)?;
+
+            number += delta;
+            if number > u16::MAX as usize || rest.len() < len {
+                return Err(invalid());
+            }
+
+            message.options.push((number as u16, rest[..len].to_vec()));
+            rest = &rest[len..];
+        }
+
+        Ok(message)
+    }
+
+    fn encode_nibble(value: usize) -> (u8, heapless::Vec<u8, 2>) {
+        let mut ext = heapless::Vec::new();
+
+        let nibble = if value < 13 {
+            value as u8
+        } else if value < 269 {
+            ext.push((value - 13) as u8).unwrap();
+            13
+        } else {
+            ext.extend_from_slice(&((value - 269) as u16).to_be_bytes())
+                .unwrap();
+            14
+        };
+
+        (nibble, ext)
+    }
+
+    fn decode_nibble(nibble: u8, rest: &mut &[u8]) -> Option<usize> {
+        match nibble {
+            0..=12 => Some(nibble as usize),
+            13 => {
+                let (&ext, tail) = rest.split_first()?;
+                *rest = tail;
+
+                Some(ext as usize + 13)
+            }
+            14 => {
+                if rest.len() < 2 {
+                    return None;
+                }
+
+                let ext = u16::from_be_bytes([rest[0], rest[1]]);
+                *rest = &rest[2..];
+
+                Some(ext as usize + 269)
+            }
+            _ => None,
+        }
+    }
+
+    fn join<'a>(segments: impl Iterator<Item = &'a [u8]>) -> String {
+        let mut path = String::new();
+
+        for segment in segments {
+            if !path.is_empty() {
+                path.push('/');
+            }
+
+            path.push_str(&String::from_utf8_lossy(segment));
+        }
+
+        path
+    }
+}
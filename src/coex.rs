@@ -0,0 +1,141 @@
+//! Wi-Fi and Bluetooth coexistence
+//!
+//! On chips with a single 2.4 GHz radio, Wi-Fi and Bluetooth share the antenna in time slices
+//! arbitrated by the coexistence scheme. With both active, each one only gets part of the air
+//! time, which commonly shows up as BLE connections or scans timing out while Wi-Fi is busy.
+//!
+//! The arbitration can be influenced with:
+//! - `set_preference`, favoring the throughput of Wi-Fi, of Bluetooth, or neither
+//! - `set_status` / `clear_status`, informing the scheme of the Bluetooth activity
+//!   (A2DP streaming, BLE Mesh provisioning or traffic), so that it reserves more air time for it
+//!
+//! With the Bluedroid BLE host, `tune_scan_params` and `tune_adv_params` adjust the scan windows
+//! and advertising intervals to values that leave room for Wi-Fi, as per the recommendations
+//! of the ESP-IDF coexistence documentation.
+
+use crate::private::cstr::from_cstr_ptr;
+use crate::sys::*;
+
+/// Which radio the coexistence scheme favors
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Preference {
+    Wifi = esp_coex_prefer_t_ESP_COEX_PREFER_WIFI,
+    Bluetooth = esp_coex_prefer_t_ESP_COEX_PREFER_BT,
+    #[default]
+    Balance = esp_coex_prefer_t_ESP_COEX_PREFER_BALANCE,
+}
+
+/// A Bluetooth activity reported to the coexistence scheme
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// BLE Mesh provisioning and configuration is in progress
+    BleMeshConfig,
+    /// BLE Mesh messages are being sent and received
+    BleMeshTraffic,
+    /// The BLE Mesh node is idle
+    BleMeshStandby,
+    /// A2DP audio is streaming
+    A2dpStreaming,
+    /// A2DP audio is paused
+    A2dpPaused,
+}
+
+impl Status {
+    fn raw(&self) -> (esp_coex_status_type_t, u32) {
+        match self {
+            Self::BleMeshConfig => (
+                esp_coex_status_type_t_ESP_COEX_ST_TYPE_BLE,
+                ESP_COEX_BLE_ST_MESH_CONFIG,
+            ),
+            Self::BleMeshTraffic => (
+                esp_coex_status_type_t_ESP_COEX_ST_TYPE_BLE,
+                ESP_COEX_BLE_ST_MESH_TRAFFIC,
+            ),
+            Self::BleMeshStandby => (
+                esp_coex_status_type_t_ESP_COEX_ST_TYPE_BLE,
+                ESP_COEX_BLE_ST_MESH_STANDBY,
+            ),
+            Self::A2dpStreaming => (
+                esp_coex_status_type_t_ESP_COEX_ST_TYPE_BT,
+                ESP_COEX_BT_ST_A2DP_STREAMING,
+            ),
+            Self::A2dpPaused => (
+                esp_coex_status_type_t_ESP_COEX_ST_TYPE_BT,
+                ESP_COEX_BT_ST_A2DP_PAUSED,
+            ),
+        }
+    }
+}
+
+/// Set which radio the coexistence scheme favors
+///
+/// Note that recent ESP-IDF versions arbitrate dynamically, based on the reported `Status`,
+/// and may ignore the preference.
+pub fn set_preference(preference: Preference) -> Result<(), EspError> {
+    esp!(unsafe { esp_coex_preference_set(preference as _) })
+}
+
+/// Report that a Bluetooth activity started
+pub fn set_status(status: Status) -> Result<(), EspError> {
+    let (status_type, bit) = status.raw();
+
+    esp!(unsafe { esp_coex_status_bit_set(status_type, bit) })
+}
+
+/// Report that a Bluetooth activity ended
+pub fn clear_status(status: Status) -> Result<(), EspError> {
+    let (status_type, bit) = status.raw();
+
+    esp!(unsafe { esp_coex_status_bit_clear(status_type, bit) })
+}
+
+/// Return the version of the coexistence library
+pub fn version() -> &'static str {
+    unsafe { from_cstr_ptr(esp_coex_version_get()) }
+}
+
+#[cfg(all(
+    not(esp32s2),
+    esp_idf_bt_enabled,
+    esp_idf_bt_bluedroid_enabled,
+    feature = "alloc",
+    feature = "experimental"
+))]
+pub use ble::*;
+
+#[cfg(all(
+    not(esp32s2),
+    esp_idf_bt_enabled,
+    esp_idf_bt_bluedroid_enabled,
+    feature = "alloc",
+    feature = "experimental"
+))]
+mod ble {
+    use crate::bt::ble::gap::adv::AdvParams;
+    use crate::bt::ble::gap::scan::ScanParams;
+
+    /// The minimum advertising interval leaving room for Wi-Fi
+    pub const MIN_ADV_INTERVAL_MS: u32 = 100;
+
+    /// The minimum scan interval leaving room for Wi-Fi
+    pub const MIN_SCAN_INTERVAL_MS: u32 = 100;
+
+    /// Adjust the scan parameters for concurrent Wi-Fi operation
+    ///
+    /// The scan interval is raised to at least `MIN_SCAN_INTERVAL_MS`, and the scan window is
+    /// capped to half of the interval, so that Wi-Fi gets the radio for the rest of it.
+    pub fn tune_scan_params(params: &mut ScanParams) {
+        params.interval_ms = params.interval_ms.max(MIN_SCAN_INTERVAL_MS);
+        params.window_ms = params.window_ms.clamp(1, params.interval_ms / 2);
+    }
+
+    /// Adjust the advertising parameters for concurrent Wi-Fi operation
+    ///
+    /// The advertising intervals are raised to at least `MIN_ADV_INTERVAL_MS`, as shorter
+    /// intervals mostly result in advertising events being skipped while Wi-Fi is active.
+    pub fn tune_adv_params(params: &mut AdvParams) {
+        params.interval_min_ms = params.interval_min_ms.max(MIN_ADV_INTERVAL_MS);
+        params.interval_max_ms = params.interval_max_ms.max(params.interval_min_ms);
+    }
+}
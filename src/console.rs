@@ -0,0 +1,463 @@
+//! Interactive console (REPL)
+//!
+//! `EspConsole` runs the line-editing REPL of the ESP-IDF `console` component - with history
+//! and completion of the command names - over the UART, the USB Serial/JTAG controller or
+//! USB CDC, with commands implemented as Rust closures:
+//!
+//! ```ignore
+//! use esp_idf_svc::console::{ConsoleConfiguration, ConsoleDevice, EspConsole};
+//!
+//! let mut console = EspConsole::new(ConsoleDevice::default(), &ConsoleConfiguration::default())?;
+//!
+//! console.register_builtin_commands()?;
+//! console.register("echo", "Print the arguments", |args| {
+//!     println!("{}", args.positional().collect::<Vec<_>>().join(" "));
+//!     Ok::<_, &str>(())
+//! })?;
+//!
+//! console.start()?;
+//! ```
+//!
+//! The commands run on the REPL task, and print their output with `println!`.
+
+use core::ffi::{c_char, c_int, CStr};
+use core::fmt::Display;
+use core::ptr;
+use core::str::FromStr;
+
+extern crate alloc;
+use alloc::ffi::CString;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::private::cstr::to_cstring_arg;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+type Command = Arc<dyn Fn(&Args) -> Result<(), String> + Send + Sync>;
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+// The commands registered, by name; also keeps the C strings of the names and help texts
+// alive, as `esp_console_cmd_register` does not copy them
+#[allow(clippy::type_complexity)]
+static COMMANDS: Mutex<Vec<(CString, CString, Command)>> = Mutex::new(Vec::new());
+
+/// The device the console runs on
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConsoleDevice {
+    /// A UART, with the pins of the console UART of the `sdkconfig`
+    Uart { channel: u8, baud_rate: u32 },
+    /// The USB Serial/JTAG controller
+    #[cfg(esp_idf_soc_usb_serial_jtag_supported)]
+    UsbSerialJtag,
+    /// USB CDC, with `CONFIG_ESP_CONSOLE_USB_CDC`
+    #[cfg(esp_idf_esp_console_usb_cdc)]
+    UsbCdc,
+}
+
+impl Default for ConsoleDevice {
+    /// UART0 at 115200 baud
+    fn default() -> Self {
+        Self::Uart {
+            channel: 0,
+            baud_rate: 115200,
+        }
+    }
+}
+
+/// The configuration of the console
+#[derive(Clone, Debug)]
+pub struct ConsoleConfiguration<'a> {
+    pub prompt: &'a str,
+    /// The number of command lines kept in the history
+    pub max_history_len: usize,
+    /// A file of a mounted filesystem to keep the history in, across reboots
+    pub history_save_path: Option<&'a str>,
+    /// The maximum length of a command line, 0 for the default of 256
+    pub max_cmdline_length: usize,
+    pub task_stack_size: usize,
+    pub task_priority: u8,
+}
+
+impl ConsoleConfiguration<'_> {
+    /// Create a new default configuration
+    pub const fn new() -> Self {
+        Self {
+            prompt: "esp> ",
+            max_history_len: 32,
+            history_save_path: None,
+            max_cmdline_length: 0,
+            task_stack_size: 4096,
+            task_priority: 2,
+        }
+    }
+}
+
+impl Default for ConsoleConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The arguments of a command
+///
+/// Flags are given as `--name`, options as `--name value` or `--name=value`, and all the
+/// other arguments are positional.
+#[derive(Debug)]
+pub struct Args<'a>(&'a [&'a str]);
+
+impl<'a> Args<'a> {
+    /// The name of the command
+    pub fn command(&self) -> &'a str {
+        self.0[0]
+    }
+
+    /// All the arguments, excluding the command name
+    pub fn all(&self) -> &'a [&'a str] {
+        &self.0[1..]
+    }
+
+    /// The positional arguments, i.e. the ones which are not flags nor options, nor the value
+    /// of an option given as `--name value`
+    pub fn positional(&self) -> impl Iterator<Item = &'a str> + '_ {
+        let mut skip_value = false;
+
+        self.all().iter().copied().filter(move |arg| {
+            if core::mem::take(&mut skip_value) {
+                return false;
+            }
+
+            if let Some(option) = arg.strip_prefix("--") {
+                skip_value = !option.contains('=') && !option.is_empty();
+
+                false
+            } else {
+                true
+            }
+        })
+    }
+
+    /// The positional argument at `index`
+    pub fn get(&self, index: usize) -> Option<&'a str> {
+        self.positional().nth(index)
+    }
+
+    /// Whether the flag `--name` is given
+    pub fn flag(&self, name: &str) -> bool {
+        self.all()
+            .iter()
+            .any(|arg| arg.strip_prefix("--") == Some(name))
+    }
+
+    /// The value of the option `--name`
+    pub fn value(&self, name: &str) -> Option<&'a str> {
+        let all = self.all();
+
+        all.iter().enumerate().find_map(|(index, arg)| {
+            let option = arg.strip_prefix("--")?;
+
+            if option == name {
+                all.get(index + 1).copied()
+            } else {
+                option.strip_prefix(name)?.strip_prefix('=')
+            }
+        })
+    }
+
+    /// The value of the option `--name`, parsed
+    pub fn parse<T>(&self, name: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.value(name)
+            .map(|value| value.parse().map_err(|err| format!("--{name}: {err}")))
+            .transpose()
+    }
+}
+
+/// The console REPL
+pub struct EspConsole {
+    repl: *mut esp_console_repl_t,
+    started: bool,
+    // Not copied by `esp_console_new_repl_*`
+    _prompt: CString,
+    _history_save_path: Option<CString>,
+}
+
+impl EspConsole {
+    pub fn new(device: ConsoleDevice, config: &ConsoleConfiguration) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let prompt = to_cstring_arg(config.prompt)?;
+        let history_save_path = config.history_save_path.map(to_cstring_arg).transpose()?;
+
+        #[allow(clippy::needless_update)]
+        let repl_config = esp_console_repl_config_t {
+            max_history_len: config.max_history_len as _,
+            history_save_path: history_save_path
+                .as_ref()
+                .map_or(ptr::null(), |path| path.as_ptr()),
+            task_stack_size: config.task_stack_size as _,
+            task_priority: config.task_priority as _,
+            prompt: prompt.as_ptr(),
+            max_cmdline_length: config.max_cmdline_length as _,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            task_core_id: tskNO_AFFINITY as _,
+            ..Default::default()
+        };
+
+        let mut repl = ptr::null_mut();
+
+        match device {
+            ConsoleDevice::Uart { channel, baud_rate } => {
+                #[allow(clippy::needless_update)]
+                let dev_config = esp_console_dev_uart_config_t {
+                    channel: channel as _,
+                    baud_rate: baud_rate as _,
+                    tx_gpio_num: -1,
+                    rx_gpio_num: -1,
+                    ..Default::default()
+                };
+
+                esp!(unsafe { esp_console_new_repl_uart(&dev_config, &repl_config, &mut repl) })?;
+            }
+            #[cfg(esp_idf_soc_usb_serial_jtag_supported)]
+            ConsoleDevice::UsbSerialJtag => {
+                let dev_config: esp_console_dev_usb_serial_jtag_config_t = Default::default();
+
+                esp!(unsafe {
+                    esp_console_new_repl_usb_serial_jtag(&dev_config, &repl_config, &mut repl)
+                })?;
+            }
+            #[cfg(esp_idf_esp_console_usb_cdc)]
+            ConsoleDevice::UsbCdc => {
+                let dev_config: esp_console_dev_usb_cdc_config_t = Default::default();
+
+                esp!(unsafe {
+                    esp_console_new_repl_usb_cdc(&dev_config, &repl_config, &mut repl)
+                })?;
+            }
+        }
+
+        esp!(unsafe { esp_console_register_help_command() })?;
+
+        *taken = true;
+
+        Ok(Self {
+            repl,
+            started: false,
+            _prompt: prompt,
+            _history_save_path: history_save_path,
+        })
+    }
+
+    /// Register a command
+    ///
+    /// The command fails - i.e. the console prints its error - when the closure returns an
+    /// error.
+    pub fn register<F, E>(&mut self, name: &str, help: &str, command: F) -> Result<(), EspError>
+    where
+        F: Fn(&Args) -> Result<(), E> + Send + Sync + 'static,
+        E: Display,
+    {
+        let name = to_cstring_arg(name)?;
+        let help = to_cstring_arg(help)?;
+
+        #[allow(clippy::needless_update)]
+        let cmd = esp_console_cmd_t {
+            command: name.as_ptr(),
+            help: help.as_ptr(),
+            func: Some(Self::dispatch),
+            ..Default::default()
+        };
+
+        esp!(unsafe { esp_console_cmd_register(&cmd) })?;
+
+        let command: Command =
+            Arc::new(move |args: &Args| command(args).map_err(|err| err.to_string()));
+
+        let mut commands = COMMANDS.lock();
+
+        commands.retain(|(other, _, _)| *other != name);
+        commands.push((name, help, command));
+
+        Ok(())
+    }
+
+    /// Register the built-in commands:
+    /// - `heap`: the free heap, the minimum free heap so far and the largest free block
+    /// - `tasks`: the FreeRTOS tasks, with `CONFIG_FREERTOS_USE_TRACE_FACILITY` and
+    ///   `CONFIG_FREERTOS_USE_STATS_FORMATTING_FUNCTIONS`
+    /// - `nvs [namespace]`: the keys of the default NVS partition
+    /// - `log [spec]`: show or set the log levels, e.g. `log wifi=debug,*=info`
+    /// - `restart`: restart the chip
+    pub fn register_builtin_commands(&mut self) -> Result<(), EspError> {
+        self.register("heap", "Show the heap usage", |_| {
+            unsafe {
+                println!(
+                    "free: {} bytes, minimum free: {} bytes, largest free block: {} bytes",
+                    esp_get_free_heap_size(),
+                    esp_get_minimum_free_heap_size(),
+                    heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT),
+                );
+            }
+
+            Ok::<_, &str>(())
+        })?;
+
+        #[cfg(all(
+            esp_idf_freertos_use_trace_facility,
+            esp_idf_freertos_use_stats_formatting_functions
+        ))]
+        self.register("tasks", "List the tasks", |_| {
+            // About 40 bytes per task, as documented by `vTaskList`
+            let mut buf = alloc::vec![0_u8; unsafe { uxTaskGetNumberOfTasks() } as usize * 48 + 1];
+
+            unsafe { vTaskList(buf.as_mut_ptr() as *mut c_char) };
+
+            let list = unsafe { CStr::from_ptr(buf.as_ptr() as *const c_char) };
+
+            println!("Name\t\tState\tPrio\tStack\tNum");
+            print!("{}", list.to_string_lossy());
+
+            Ok::<_, &str>(())
+        })?;
+
+        #[cfg(all(esp_idf_comp_nvs_flash_enabled, not(esp_idf_version_major = "4")))]
+        self.register("nvs", "List the NVS keys: nvs [namespace]", |args| {
+            let namespace = args
+                .get(0)
+                .map(to_cstring_arg)
+                .transpose()
+                .map_err(|e| e.to_string())?;
+
+            let mut iterator = ptr::null_mut();
+
+            let ret = unsafe {
+                nvs_entry_find(
+                    b"nvs\0".as_ptr() as *const _,
+                    namespace.as_ref().map_or(ptr::null(), |ns| ns.as_ptr()),
+                    nvs_type_t_NVS_TYPE_ANY,
+                    &mut iterator,
+                )
+            };
+
+            if ret == ESP_ERR_NVS_NOT_FOUND {
+                return Ok(());
+            }
+
+            esp!(ret).map_err(|e| e.to_string())?;
+
+            while !iterator.is_null() {
+                let mut info: nvs_entry_info_t = Default::default();
+                unsafe { nvs_entry_info(iterator, &mut info) };
+
+                let namespace = unsafe { CStr::from_ptr(info.namespace_name.as_ptr()) };
+                let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+
+                println!(
+                    "{}\t{}\t0x{:02x}",
+                    namespace.to_string_lossy(),
+                    key.to_string_lossy(),
+                    info.type_
+                );
+
+                if unsafe { nvs_entry_next(&mut iterator) } != ESP_OK {
+                    break;
+                }
+            }
+
+            unsafe { nvs_release_iterator(iterator) };
+
+            Ok(())
+        })?;
+
+        self.register(
+            "log",
+            "Show or set the log levels: log [target=level,...]",
+            |args| {
+                if let Some(spec) = args.get(0) {
+                    crate::log::set_target_levels(spec).map_err(|e| e.to_string())
+                } else {
+                    for (target, level) in crate::log::target_levels() {
+                        println!("{target}={level}");
+                    }
+
+                    Ok(())
+                }
+            },
+        )?;
+
+        self.register("restart", "Restart the chip", |_| -> Result<(), &str> {
+            unsafe { esp_restart() }
+        })?;
+
+        Ok(())
+    }
+
+    /// Start the REPL task
+    pub fn start(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_console_start_repl(self.repl) })?;
+
+        self.started = true;
+
+        Ok(())
+    }
+
+    unsafe extern "C" fn dispatch(argc: c_int, argv: *mut *mut c_char) -> c_int {
+        let args = (0..argc as usize)
+            .map(|index| CStr::from_ptr(*argv.add(index)).to_str().unwrap_or(""))
+            .collect::<Vec<_>>();
+
+        let command = {
+            let commands = COMMANDS.lock();
+
+            commands
+                .iter()
+                .find(|(name, _, _)| name.to_str() == Ok(args[0]))
+                .map(|(_, _, command)| command.clone())
+        };
+
+        let Some(command) = command else {
+            return 1;
+        };
+
+        match command(&Args(&args)) {
+            Ok(()) => 0,
+            Err(err) => {
+                println!("{}: {err}", args[0]);
+
+                1
+            }
+        }
+    }
+}
+
+impl Drop for EspConsole {
+    fn drop(&mut self) {
+        let mut taken = TAKEN.lock();
+
+        if self.started {
+            // Also deinitializes the console
+            if let Some(del) = unsafe { (*self.repl).del } {
+                esp!(unsafe { del(self.repl) }).unwrap();
+            }
+        } else {
+            // The REPL can only be deleted once started
+            unsafe { esp_console_deinit() };
+        }
+
+        COMMANDS.lock().clear();
+
+        *taken = false;
+    }
+}
+
+unsafe impl Send for EspConsole {}
@@ -0,0 +1,189 @@
+//! Core dumps stored in flash
+//!
+//! With `CONFIG_ESP_COREDUMP_ENABLE_TO_FLASH` and the ELF format, a panic stores a core dump
+//! in the `coredump` partition. `EspCoreDump` checks for one after boot, gives its summary -
+//! the crashed task, the PC, the backtrace and the panic reason - streams it out as an ELF
+//! file over any `Write` - a UART, an HTTP response, an MQTT publication - for
+//! `idf.py coredump-info --core <file> --core-format elf`, and erases it, so that crashes
+//! in the field can be collected remotely:
+//!
+//! ```ignore
+//! use esp_idf_svc::coredump::EspCoreDump;
+//!
+//! if let Some(core_dump) = EspCoreDump::get()? {
+//!     let summary = core_dump.summary()?;
+//!     log::warn!("Crashed in task {} at {:#x}", summary.task(), summary.pc());
+//!
+//!     core_dump.write_elf(&mut response)?;
+//!     core_dump.erase()?;
+//! }
+//! ```
+
+use core::cmp::min;
+use core::ptr;
+
+use embedded_svc::io::Write;
+
+use crate::sys::*;
+
+// The image in flash is the `core_dump_header_t` of the ESP-IDF - 5 words - followed by the
+// ELF file and the checksum
+const HEADER_SIZE: usize = 20;
+
+#[cfg(esp_idf_esp_coredump_checksum_sha256)]
+const CHECKSUM_SIZE: usize = 32;
+#[cfg(not(esp_idf_esp_coredump_checksum_sha256))]
+const CHECKSUM_SIZE: usize = 4;
+
+const BUF_SIZE: usize = 256;
+
+/// The summary of the crash of a core dump
+#[derive(Clone, Debug)]
+pub struct CrashSummary(esp_core_dump_summary_t);
+
+impl CrashSummary {
+    /// The name of the task which crashed
+    pub fn task(&self) -> &str {
+        let name = unsafe {
+            core::slice::from_raw_parts(
+                self.0.exc_task.as_ptr() as *const u8,
+                self.0.exc_task.len(),
+            )
+        };
+
+        crate::private::cstr::from_cstr(name)
+    }
+
+    /// The program counter of the crash
+    pub fn pc(&self) -> u32 {
+        self.0.exc_pc
+    }
+
+    /// The backtrace of the crashed task, as program counters
+    #[cfg(target_arch = "xtensa")]
+    pub fn backtrace(&self) -> &[u32] {
+        let info = &self.0.exc_bt_info;
+
+        &info.bt[..min(info.depth as usize, info.bt.len())]
+    }
+
+    /// Whether the backtrace is corrupted, and so incomplete
+    #[cfg(target_arch = "xtensa")]
+    pub fn is_backtrace_corrupted(&self) -> bool {
+        self.0.exc_bt_info.corrupted
+    }
+
+    /// The SHA256 of the ELF file of the application which crashed, as a hex string, to match
+    /// the core dump with the firmware build
+    pub fn app_elf_sha256(&self) -> &str {
+        let sha256 = unsafe {
+            core::slice::from_raw_parts(
+                self.0.app_elf_sha256.as_ptr() as *const u8,
+                self.0.app_elf_sha256.len(),
+            )
+        };
+
+        crate::private::cstr::from_cstr(sha256)
+    }
+}
+
+/// The core dump stored in flash
+pub struct EspCoreDump {
+    address: usize,
+    size: usize,
+}
+
+impl EspCoreDump {
+    /// Get the stored core dump, or `None` if there is none
+    ///
+    /// Fails with `ESP_ERR_INVALID_CRC` if the core dump is corrupted.
+    pub fn get() -> Result<Option<Self>, EspError> {
+        match esp!(unsafe { esp_core_dump_image_check() }) {
+            Ok(()) => (),
+            Err(err) if err.code() == ESP_ERR_NOT_FOUND || err.code() == ESP_ERR_INVALID_SIZE => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        }
+
+        let mut address = 0;
+        let mut size = 0;
+
+        esp!(unsafe { esp_core_dump_image_get(&mut address, &mut size) })?;
+
+        Ok(Some(Self {
+            address: address as _,
+            size: size as _,
+        }))
+    }
+
+    /// The size of the ELF file
+    pub fn elf_size(&self) -> usize {
+        self.size.saturating_sub(HEADER_SIZE + CHECKSUM_SIZE)
+    }
+
+    /// The summary of the crash
+    pub fn summary(&self) -> Result<CrashSummary, EspError> {
+        let mut summary: esp_core_dump_summary_t = Default::default();
+
+        esp!(unsafe { esp_core_dump_get_summary(&mut summary) })?;
+
+        Ok(CrashSummary(summary))
+    }
+
+    /// The panic reason, e.g. `LoadProhibited` or the message of a Rust panic, into `buf`
+    #[cfg(not(any(esp_idf_version_major = "4", esp_idf_version = "5.0")))]
+    pub fn panic_reason<'a>(&self, buf: &'a mut [u8]) -> Result<&'a str, EspError> {
+        esp!(unsafe { esp_core_dump_get_panic_reason(buf.as_mut_ptr() as *mut _, buf.len()) })?;
+
+        Ok(crate::private::cstr::from_cstr(buf))
+    }
+
+    /// Read the ELF file at `offset` into `buf`, returning the number of bytes read; 0 at the
+    /// end of the file
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, EspError> {
+        let len = min(buf.len(), self.elf_size().saturating_sub(offset));
+
+        if len > 0 {
+            esp!(unsafe {
+                esp_flash_read(
+                    ptr::null_mut(),
+                    buf.as_mut_ptr() as *mut _,
+                    (self.address + HEADER_SIZE + offset) as _,
+                    len as _,
+                )
+            })?;
+        }
+
+        Ok(len)
+    }
+
+    /// Write the ELF file to `write`
+    pub fn write_elf<W>(&self, mut write: W) -> Result<(), W::Error>
+    where
+        W: Write,
+        W::Error: From<EspError>,
+    {
+        let mut buf = [0; BUF_SIZE];
+        let mut offset = 0;
+
+        loop {
+            let len = self.read(offset, &mut buf)?;
+
+            if len == 0 {
+                break;
+            }
+
+            write.write_all(&buf[..len])?;
+
+            offset += len;
+        }
+
+        write.flush()
+    }
+
+    /// Erase the core dump, e.g. once collected
+    pub fn erase(self) -> Result<(), EspError> {
+        esp!(unsafe { esp_core_dump_image_erase() })
+    }
+}
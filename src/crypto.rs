@@ -0,0 +1,134 @@
+//! Cryptography helpers on top of Mbed TLS
+//!
+//! The Mbed TLS port of the ESP IDF uses the hardware accelerators of the chip (SHA, AES,
+//! RSA / ECC) and its hardware random number generator.
+//!
+//! `hash`, `hmac` and `aes` wrap its SHA, HMAC and AES-GCM / AES-CTR implementations - and thus
+//! the accelerators - with streaming APIs, so that applications do not need the much slower
+//! software implementations of other crates:
+//!
+//! ```ignore
+//! let mut sha = Sha256::new()?;
+//! sha.update(b"Hello, ")?;
+//! sha.update(b"world")?;
+//! let hash = sha.finalize()?;
+//!
+//! let tag = HmacSha256::mac(key, b"message")?;
+//! ```
+//!
+//! With the `digest` feature, the hashes implement the `Digest` trait of the RustCrypto
+//! `digest` crate, and the HMACs its `Mac` trait, so they can be used with the crates generic
+//! over these traits.
+
+use core::ffi::{c_int, c_void};
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use crate::sys::*;
+
+#[cfg(feature = "alloc")]
+pub mod aes;
+#[cfg(feature = "alloc")]
+pub mod hash;
+#[cfg(feature = "alloc")]
+pub mod hmac;
+#[cfg(all(feature = "alloc", not(esp_idf_version_major = "4")))]
+pub mod x509;
+
+/// The random number generator callback of the Mbed TLS APIs, backed by the hardware RNG
+#[allow(unused)]
+pub(crate) unsafe extern "C" fn mbedtls_rng(_ctx: *mut c_void, buf: *mut u8, len: usize) -> c_int {
+    crate::rng::fill(core::slice::from_raw_parts_mut(buf, len));
+
+    0
+}
+
+/// A streaming message digest or HMAC, with the message digest API of Mbed TLS
+#[cfg(feature = "alloc")]
+pub(crate) struct Md {
+    ctx: alloc::boxed::Box<mbedtls_md_context_t>,
+    hmac: bool,
+}
+
+#[cfg(feature = "alloc")]
+impl Md {
+    pub(crate) fn new(
+        md_type: mbedtls_md_type_t,
+        hmac_key: Option<&[u8]>,
+    ) -> Result<Self, EspError> {
+        let mut ctx =
+            alloc::boxed::Box::new(unsafe { core::mem::zeroed::<mbedtls_md_context_t>() });
+
+        unsafe { mbedtls_md_init(ctx.as_mut()) };
+
+        // Freed on drop from now on, even if the setup fails
+        let mut this = Self {
+            ctx,
+            hmac: hmac_key.is_some(),
+        };
+
+        check(unsafe {
+            mbedtls_md_setup(
+                this.ctx.as_mut(),
+                mbedtls_md_info_from_type(md_type),
+                this.hmac as _,
+            )
+        })?;
+
+        match hmac_key {
+            Some(key) => check(unsafe {
+                mbedtls_md_hmac_starts(this.ctx.as_mut(), key.as_ptr(), key.len())
+            })?,
+            None => check(unsafe { mbedtls_md_starts(this.ctx.as_mut()) })?,
+        }
+
+        Ok(this)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) -> Result<(), EspError> {
+        check(unsafe {
+            if self.hmac {
+                mbedtls_md_hmac_update(self.ctx.as_mut(), data.as_ptr(), data.len())
+            } else {
+                mbedtls_md_update(self.ctx.as_mut(), data.as_ptr(), data.len())
+            }
+        })
+    }
+
+    /// Write the digest to `out`, which has to be as long as the digest
+    pub(crate) fn finish(&mut self, out: &mut [u8]) -> Result<(), EspError> {
+        check(unsafe {
+            if self.hmac {
+                mbedtls_md_hmac_finish(self.ctx.as_mut(), out.as_mut_ptr())
+            } else {
+                mbedtls_md_finish(self.ctx.as_mut(), out.as_mut_ptr())
+            }
+        })
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Drop for Md {
+    fn drop(&mut self) {
+        unsafe { mbedtls_md_free(self.ctx.as_mut()) };
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl Send for Md {}
+
+#[cfg(feature = "alloc")]
+pub(crate) fn check(ret: c_int) -> Result<(), EspError> {
+    if ret != 0 {
+        Err(EspError::from_infallible::<ESP_FAIL>())
+    } else {
+        Ok(())
+    }
+}
+
+/// Compare two byte strings in constant time, e.g. a received tag with the expected one
+#[cfg(feature = "alloc")]
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
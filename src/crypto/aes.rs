@@ -0,0 +1,231 @@
+//! AES-GCM and AES-CTR
+//!
+//! Computed by the AES accelerator of the chip when Mbed TLS is configured to use it, which
+//! is the default. Both modes encrypt and decrypt in place, in as many `update` calls as
+//! needed:
+//!
+//! ```ignore
+//! let mut gcm = AesGcm::encryptor(&key, &nonce, b"header")?;
+//! gcm.update(&mut chunk1)?;
+//! gcm.update(&mut chunk2)?;
+//! let tag = gcm.finalize()?;
+//!
+//! let mut gcm = AesGcm::decryptor(&key, &nonce, b"header")?;
+//! gcm.update(&mut chunk1)?;
+//! gcm.update(&mut chunk2)?;
+//! gcm.verify(&tag)?;
+//! ```
+//!
+//! Note that a GCM decryptor yields the plaintext before the tag is verified: the plaintext
+//! should not be used before `verify` succeeds.
+
+use core::fmt::{self, Debug};
+use core::mem;
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+use crate::sys::*;
+
+use super::check;
+#[cfg(all(esp_idf_mbedtls_gcm_c, not(esp_idf_version_major = "4")))]
+use super::ct_eq;
+
+/// The length of an AES block, and of a GCM tag
+pub const BLOCK_LEN: usize = 16;
+
+fn check_key(key: &[u8]) -> Result<(), EspError> {
+    if matches!(key.len(), 16 | 24 | 32) {
+        Ok(())
+    } else {
+        Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+    }
+}
+
+/// AES in counter mode
+pub struct AesCtr {
+    ctx: Box<mbedtls_aes_context>,
+    counter: [u8; BLOCK_LEN],
+    stream_block: [u8; BLOCK_LEN],
+    offset: usize,
+}
+
+impl AesCtr {
+    /// Create a cipher with a 128, 192 or 256 bits key, starting with the counter block
+    /// `counter` - typically a nonce followed by a zero counter
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` for keys of other lengths.
+    pub fn new(key: &[u8], counter: &[u8; BLOCK_LEN]) -> Result<Self, EspError> {
+        check_key(key)?;
+
+        let mut ctx = Box::new(unsafe { mem::zeroed() });
+
+        unsafe { mbedtls_aes_init(&mut *ctx) };
+
+        // Freed on drop from now on, even if setting the key fails
+        let mut this = Self {
+            ctx,
+            counter: *counter,
+            stream_block: [0; BLOCK_LEN],
+            offset: 0,
+        };
+
+        // The key stream is the encryption of the counter blocks, in both directions
+        check(unsafe {
+            mbedtls_aes_setkey_enc(&mut *this.ctx, key.as_ptr(), key.len() as u32 * 8)
+        })?;
+
+        Ok(this)
+    }
+
+    /// Encrypt or decrypt `data` in place, continuing the key stream
+    pub fn update(&mut self, data: &mut [u8]) -> Result<(), EspError> {
+        check(unsafe {
+            mbedtls_aes_crypt_ctr(
+                &mut *self.ctx,
+                data.len(),
+                &mut self.offset,
+                self.counter.as_mut_ptr(),
+                self.stream_block.as_mut_ptr(),
+                data.as_ptr(),
+                data.as_mut_ptr(),
+            )
+        })
+    }
+}
+
+impl Drop for AesCtr {
+    fn drop(&mut self) {
+        unsafe { mbedtls_aes_free(&mut *self.ctx) };
+    }
+}
+
+impl Debug for AesCtr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AesCtr").finish_non_exhaustive()
+    }
+}
+
+unsafe impl Send for AesCtr {}
+
+/// AES in Galois/Counter mode, encrypting or decrypting one message
+#[cfg(all(esp_idf_mbedtls_gcm_c, not(esp_idf_version_major = "4")))]
+pub struct AesGcm {
+    ctx: Box<mbedtls_gcm_context>,
+}
+
+#[cfg(all(esp_idf_mbedtls_gcm_c, not(esp_idf_version_major = "4")))]
+impl AesGcm {
+    /// Start encrypting a message with a 128, 192 or 256 bits key, a nonce - which must never
+    /// be reused with the same key, and is typically 12 bytes long - and additional
+    /// authenticated data
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` for keys of other lengths.
+    pub fn encryptor(key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Self, EspError> {
+        Self::new(MBEDTLS_GCM_ENCRYPT, key, nonce, aad)
+    }
+
+    /// Start decrypting a message, with the key, nonce and additional authenticated data it
+    /// was encrypted with
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` for keys of other lengths than 128, 192 or 256 bits.
+    pub fn decryptor(key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Self, EspError> {
+        Self::new(MBEDTLS_GCM_DECRYPT, key, nonce, aad)
+    }
+
+    fn new(mode: u32, key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Self, EspError> {
+        check_key(key)?;
+
+        let mut ctx = Box::new(unsafe { mem::zeroed() });
+
+        unsafe { mbedtls_gcm_init(&mut *ctx) };
+
+        // Freed on drop from now on, even if the setup fails
+        let mut this = Self { ctx };
+
+        check(unsafe {
+            mbedtls_gcm_setkey(
+                &mut *this.ctx,
+                mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+                key.as_ptr(),
+                key.len() as u32 * 8,
+            )
+        })?;
+        check(unsafe {
+            mbedtls_gcm_starts(&mut *this.ctx, mode as _, nonce.as_ptr(), nonce.len())
+        })?;
+        check(unsafe { mbedtls_gcm_update_ad(&mut *this.ctx, aad.as_ptr(), aad.len()) })?;
+
+        Ok(this)
+    }
+
+    /// Encrypt or decrypt the next part of the message in place
+    pub fn update(&mut self, data: &mut [u8]) -> Result<(), EspError> {
+        let mut len = 0;
+
+        check(unsafe {
+            mbedtls_gcm_update(
+                &mut *self.ctx,
+                data.as_ptr(),
+                data.len(),
+                data.as_mut_ptr(),
+                data.len(),
+                &mut len,
+            )
+        })
+    }
+
+    /// Finish encrypting the message, returning its tag
+    pub fn finalize(mut self) -> Result<[u8; BLOCK_LEN], EspError> {
+        let mut tag = [0; BLOCK_LEN];
+        let mut len = 0;
+
+        check(unsafe {
+            mbedtls_gcm_finish(
+                &mut *self.ctx,
+                core::ptr::null_mut(),
+                0,
+                &mut len,
+                tag.as_mut_ptr(),
+                tag.len(),
+            )
+        })?;
+
+        Ok(tag)
+    }
+
+    /// Finish decrypting the message, checking in constant time that `tag` is its tag
+    ///
+    /// Returns `ESP_ERR_INVALID_CRC` if it is not, i.e. the message is not authentic.
+    pub fn verify(self, tag: &[u8]) -> Result<(), EspError> {
+        // Tags can be truncated, down to 4 bytes
+        let len = tag.len();
+
+        if !(4..=BLOCK_LEN).contains(&len) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        if ct_eq(&self.finalize()?[..len], tag) {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>())
+        }
+    }
+}
+
+#[cfg(all(esp_idf_mbedtls_gcm_c, not(esp_idf_version_major = "4")))]
+impl Drop for AesGcm {
+    fn drop(&mut self) {
+        unsafe { mbedtls_gcm_free(&mut *self.ctx) };
+    }
+}
+
+#[cfg(all(esp_idf_mbedtls_gcm_c, not(esp_idf_version_major = "4")))]
+impl Debug for AesGcm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AesGcm").finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(esp_idf_mbedtls_gcm_c, not(esp_idf_version_major = "4")))]
+unsafe impl Send for AesGcm {}
@@ -0,0 +1,114 @@
+//! SHA-1 and SHA-2 hashes
+//!
+//! Computed by the SHA accelerator of the chip when Mbed TLS is configured to use it, which
+//! is the default. SHA-1 is only provided for the legacy protocols which require it.
+
+use core::fmt::{self, Debug};
+
+use crate::sys::*;
+
+use super::Md;
+
+macro_rules! hash {
+    ($(#[$meta:meta])* $name:ident, $md_type:ident, $len:literal, $output_size:ident) => {
+        $(#[$meta])*
+        pub struct $name(Md);
+
+        impl $name {
+            /// The length of the digest, in bytes
+            pub const OUTPUT_LEN: usize = $len;
+
+            pub fn new() -> Result<Self, EspError> {
+                Md::new($md_type, None).map(Self)
+            }
+
+            /// Hash `data` in one go
+            pub fn digest(data: &[u8]) -> Result<[u8; $len], EspError> {
+                let mut hash = Self::new()?;
+
+                hash.update(data)?;
+                hash.finalize()
+            }
+
+            pub fn update(&mut self, data: &[u8]) -> Result<(), EspError> {
+                self.0.update(data)
+            }
+
+            pub fn finalize(mut self) -> Result<[u8; $len], EspError> {
+                let mut out = [0; $len];
+
+                self.0.finish(&mut out)?;
+
+                Ok(out)
+            }
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct(stringify!($name)).finish_non_exhaustive()
+            }
+        }
+
+        // The `digest` traits are infallible; Mbed TLS only fails here when out of memory
+        #[cfg(feature = "digest")]
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new().unwrap()
+            }
+        }
+
+        #[cfg(feature = "digest")]
+        impl digest::HashMarker for $name {}
+
+        #[cfg(feature = "digest")]
+        impl digest::OutputSizeUser for $name {
+            type OutputSize = digest::consts::$output_size;
+        }
+
+        #[cfg(feature = "digest")]
+        impl digest::Update for $name {
+            fn update(&mut self, data: &[u8]) {
+                $name::update(self, data).unwrap()
+            }
+        }
+
+        #[cfg(feature = "digest")]
+        impl digest::FixedOutput for $name {
+            fn finalize_into(self, out: &mut digest::Output<Self>) {
+                out.copy_from_slice(&$name::finalize(self).unwrap())
+            }
+        }
+    };
+}
+
+hash!(
+    /// A streaming SHA-1; not collision resistant
+    Sha1,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA1,
+    20,
+    U20
+);
+
+hash!(
+    /// A streaming SHA-256
+    Sha256,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+    32,
+    U32
+);
+
+hash!(
+    /// A streaming SHA-384
+    Sha384,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA384,
+    48,
+    U48
+);
+
+hash!(
+    /// A streaming SHA-512
+    Sha512,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA512,
+    64,
+    U64
+);
@@ -0,0 +1,135 @@
+//! HMACs
+//!
+//! Computed in software on top of the SHA accelerator of the chip. Note that the keys of the
+//! HMAC peripheral, burned in the eFuses, are used by `secure_store` instead.
+
+use core::fmt::{self, Debug};
+
+use crate::sys::*;
+
+use super::{ct_eq, Md};
+
+macro_rules! hmac {
+    (
+        $(#[$meta:meta])*
+        $name:ident, $md_type:ident, $len:literal, $output_size:ident, $block_size:ident
+    ) => {
+        $(#[$meta])*
+        pub struct $name(Md);
+
+        impl $name {
+            /// The length of the tag, in bytes
+            pub const OUTPUT_LEN: usize = $len;
+
+            pub fn new(key: &[u8]) -> Result<Self, EspError> {
+                Md::new($md_type, Some(key)).map(Self)
+            }
+
+            /// Compute the tag of `data` under `key` in one go
+            pub fn mac(key: &[u8], data: &[u8]) -> Result<[u8; $len], EspError> {
+                let mut hmac = Self::new(key)?;
+
+                hmac.update(data)?;
+                hmac.finalize()
+            }
+
+            pub fn update(&mut self, data: &[u8]) -> Result<(), EspError> {
+                self.0.update(data)
+            }
+
+            pub fn finalize(mut self) -> Result<[u8; $len], EspError> {
+                let mut out = [0; $len];
+
+                self.0.finish(&mut out)?;
+
+                Ok(out)
+            }
+
+            /// Check, in constant time, that `tag` is the tag of the data
+            pub fn verify(self, tag: &[u8]) -> Result<bool, EspError> {
+                Ok(ct_eq(&self.finalize()?, tag))
+            }
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_struct(stringify!($name)).finish_non_exhaustive()
+            }
+        }
+
+        #[cfg(feature = "digest")]
+        impl digest::MacMarker for $name {}
+
+        #[cfg(feature = "digest")]
+        impl digest::crypto_common::KeySizeUser for $name {
+            type KeySize = digest::consts::$block_size;
+        }
+
+        // The `digest` traits are infallible; Mbed TLS only fails here when out of memory
+        #[cfg(feature = "digest")]
+        impl digest::KeyInit for $name {
+            fn new(key: &digest::Key<Self>) -> Self {
+                $name::new(key).unwrap()
+            }
+
+            fn new_from_slice(key: &[u8]) -> Result<Self, digest::InvalidLength> {
+                $name::new(key).map_err(|_| digest::InvalidLength)
+            }
+        }
+
+        #[cfg(feature = "digest")]
+        impl digest::OutputSizeUser for $name {
+            type OutputSize = digest::consts::$output_size;
+        }
+
+        #[cfg(feature = "digest")]
+        impl digest::Update for $name {
+            fn update(&mut self, data: &[u8]) {
+                $name::update(self, data).unwrap()
+            }
+        }
+
+        #[cfg(feature = "digest")]
+        impl digest::FixedOutput for $name {
+            fn finalize_into(self, out: &mut digest::Output<Self>) {
+                out.copy_from_slice(&$name::finalize(self).unwrap())
+            }
+        }
+    };
+}
+
+hmac!(
+    /// A streaming HMAC-SHA-1
+    HmacSha1,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA1,
+    20,
+    U20,
+    U64
+);
+
+hmac!(
+    /// A streaming HMAC-SHA-256
+    HmacSha256,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+    32,
+    U32,
+    U64
+);
+
+hmac!(
+    /// A streaming HMAC-SHA-384
+    HmacSha384,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA384,
+    48,
+    U48,
+    U128
+);
+
+hmac!(
+    /// A streaming HMAC-SHA-512
+    HmacSha512,
+    mbedtls_md_type_t_MBEDTLS_MD_SHA512,
+    64,
+    U64,
+    U128
+);
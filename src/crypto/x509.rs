@@ -0,0 +1,561 @@
+//! X.509 certificates, key pairs and certificate signing requests
+//!
+//! `Certificate` parses a PEM or DER certificate - e.g. the one presented by a TLS peer, as
+//! returned by `EspTls::peer_certificate` - into its subject, issuer, validity and subject
+//! alternative names.
+//!
+//! For device identity enrollment, `KeyPair::generate` generates a key pair on the device with
+//! the hardware random number generator, so that the private key never leaves it, and
+//! `KeyPair::csr_pem` / `KeyPair::csr_der` create the certificate signing request (CSR) to have
+//! its public key certified by the CA of the enrollment service:
+//!
+//! ```ignore
+//! let key = KeyPair::generate(KeyType::EcP256)?;
+//!
+//! let csr = key.csr_pem(&CsrParams {
+//!     subject: "CN=device-1,O=Acme",
+//!     dns_names: &["device-1.local"],
+//! })?;
+//! ```
+//!
+//! Note that the keys of the Digital Signature peripheral (`tls::ds::DsKey`) are generated on
+//! the host by `configure_ds.py`, along with their CSR, as the peripheral cannot export the
+//! public key.
+
+use core::ffi::{c_int, c_uint};
+use core::fmt::{self, Debug};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::ipv4::{Ipv4Addr, Ipv6Addr};
+use crate::sys::*;
+use crate::tls::X509;
+
+use super::mbedtls_rng;
+
+// The OID of the subjectAltName extension, 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = b"\x55\x1D\x11";
+
+// The context-specific tags of the `GeneralName` choices
+const SAN_RFC822_NAME: u8 = 1;
+const SAN_DNS_NAME: u8 = 2;
+const SAN_URI: u8 = 6;
+const SAN_IP_ADDRESS: u8 = 7;
+
+/// A point in time of a certificate validity period, in UTC
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Time {
+    /// Return the time as the duration since the UNIX epoch, or `Duration::ZERO` for
+    /// earlier times
+    pub fn as_unix(&self) -> Duration {
+        // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let month = self.month as i64;
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let doy =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+
+        Duration::from_secs(secs.max(0) as u64)
+    }
+
+    fn from_raw(raw: &mbedtls_x509_time) -> Self {
+        Self {
+            year: raw.year as _,
+            month: raw.mon as _,
+            day: raw.day as _,
+            hour: raw.hour as _,
+            minute: raw.min as _,
+            second: raw.sec as _,
+        }
+    }
+}
+
+/// A subject alternative name of a certificate
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+}
+
+/// A parsed X.509 certificate
+pub struct Certificate(Box<mbedtls_x509_crt>);
+
+impl Certificate {
+    /// Parse a PEM or DER certificate
+    ///
+    /// For a chain of PEM certificates, the accessors return the data of the first one.
+    /// Returns `ESP_ERR_INVALID_ARG` if the certificate cannot be parsed.
+    pub fn parse(cert: X509) -> Result<Self, EspError> {
+        let mut crt = Box::new(unsafe { core::mem::zeroed() });
+
+        unsafe { mbedtls_x509_crt_init(&mut *crt) };
+
+        // From now on, freed on drop
+        let mut crt = Self(crt);
+
+        let ret =
+            unsafe { mbedtls_x509_crt_parse(&mut *crt.0, cert.data().as_ptr(), cert.data().len()) };
+
+        if ret == 0 {
+            Ok(crt)
+        } else {
+            log::warn!("Failed to parse certificate (error {ret})");
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        }
+    }
+
+    /// The DER encoding of the certificate
+    pub fn der(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.0.raw.p, self.0.raw.len) }
+    }
+
+    /// The distinguished name of the subject, e.g. `C=NL, O=Acme, CN=device-1`
+    pub fn subject(&self) -> String {
+        Self::dn(&self.0.subject)
+    }
+
+    /// The distinguished name of the issuer
+    pub fn issuer(&self) -> String {
+        Self::dn(&self.0.issuer)
+    }
+
+    /// The serial number, as big endian bytes
+    pub fn serial_number(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.0.serial.p, self.0.serial.len) }
+    }
+
+    /// The start of the validity period
+    pub fn not_before(&self) -> Time {
+        Time::from_raw(&self.0.valid_from)
+    }
+
+    /// The end of the validity period
+    pub fn not_after(&self) -> Time {
+        Time::from_raw(&self.0.valid_to)
+    }
+
+    /// Return `true` if `now` - the duration since the UNIX epoch, e.g. as returned by
+    /// `EspSystemTime::now` once the time is synchronized - is within the validity period
+    pub fn is_valid_at(&self, now: Duration) -> bool {
+        self.not_before().as_unix() <= now && now <= self.not_after().as_unix()
+    }
+
+    /// The subject alternative names; the unsupported kinds of names are skipped
+    pub fn subject_alt_names(&self) -> Vec<SubjectAltName> {
+        let mut names = Vec::new();
+
+        let mut seq: *const mbedtls_x509_sequence = &self.0.subject_alt_names;
+
+        while let Some(entry) = unsafe { seq.as_ref() } {
+            if !entry.buf.p.is_null() {
+                let data = unsafe { core::slice::from_raw_parts(entry.buf.p, entry.buf.len) };
+                let text = || String::from_utf8_lossy(data).into_owned();
+
+                let name = match (entry.buf.tag as u8) & 0x1f {
+                    SAN_DNS_NAME => Some(SubjectAltName::Dns(text())),
+                    SAN_RFC822_NAME => Some(SubjectAltName::Email(text())),
+                    SAN_URI => Some(SubjectAltName::Uri(text())),
+                    SAN_IP_ADDRESS => match data.len() {
+                        4 => Some(SubjectAltName::Ipv4(Ipv4Addr::from(
+                            <[u8; 4]>::try_from(data).unwrap(),
+                        ))),
+                        16 => Some(SubjectAltName::Ipv6(Ipv6Addr::from(
+                            <[u8; 16]>::try_from(data).unwrap(),
+                        ))),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+
+                names.extend(name);
+            }
+
+            seq = entry.next;
+        }
+
+        names
+    }
+
+    /// The SHA-256 fingerprint of the DER `SubjectPublicKeyInfo`, as used for pinning
+    pub fn spki_sha256(&self) -> Result<[u8; 32], EspError> {
+        spki_sha256(&self.0.pk)
+    }
+
+    fn dn(name: &mbedtls_x509_name) -> String {
+        let mut buf = [0_u8; 256];
+
+        let len = unsafe { mbedtls_x509_dn_gets(buf.as_mut_ptr() as _, buf.len(), name) };
+
+        if len >= 0 {
+            String::from_utf8_lossy(&buf[..len as usize]).into_owned()
+        } else {
+            String::new()
+        }
+    }
+}
+
+impl Drop for Certificate {
+    fn drop(&mut self) {
+        unsafe { mbedtls_x509_crt_free(&mut *self.0) };
+    }
+}
+
+impl Debug for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificate")
+            .field("subject", &self.subject())
+            .field("issuer", &self.issuer())
+            .field("not_before", &self.not_before())
+            .field("not_after", &self.not_after())
+            .finish_non_exhaustive()
+    }
+}
+
+// The parsed certificate is immutable
+unsafe impl Send for Certificate {}
+unsafe impl Sync for Certificate {}
+
+/// Return the SHA-256 fingerprint of the DER `SubjectPublicKeyInfo` of a public key
+pub(crate) fn spki_sha256(pk: &mbedtls_pk_context) -> Result<[u8; 32], EspError> {
+    let mut buf = alloc::vec![0; 1024];
+
+    let len = unsafe { mbedtls_pk_write_pubkey_der(pk, buf.as_mut_ptr(), buf.len()) };
+    if len < 0 {
+        return Err(EspError::from(len).unwrap());
+    }
+
+    // Written at the end of the buffer
+    let spki = &buf[buf.len() - len as usize..];
+
+    let mut hash = [0; 32];
+    let ret = unsafe { mbedtls_sha256(spki.as_ptr(), spki.len(), hash.as_mut_ptr(), 0) };
+    if ret != 0 {
+        return Err(EspError::from(ret).unwrap());
+    }
+
+    Ok(hash)
+}
+
+/// The type of a generated key pair
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyType {
+    /// ECDSA on the NIST P-256 curve
+    EcP256,
+    /// ECDSA on the NIST P-384 curve
+    EcP384,
+    /// RSA with the given key length in bits, e.g. 2048. Note that generating RSA keys takes
+    /// seconds, if not tens of seconds for long keys
+    #[cfg(esp_idf_mbedtls_genprime)]
+    Rsa(u32),
+}
+
+/// The parameters of a certificate signing request
+#[derive(Clone, Debug, Default)]
+pub struct CsrParams<'a> {
+    /// The distinguished name of the subject, e.g. `CN=device-1,O=Acme,C=NL`
+    pub subject: &'a str,
+    /// The DNS names of the subject alternative name extension, if any
+    pub dns_names: &'a [&'a str],
+}
+
+/// A private key with its public key
+pub struct KeyPair(Box<mbedtls_pk_context>);
+
+impl KeyPair {
+    /// Generate a key pair with the hardware random number generator
+    pub fn generate(key_type: KeyType) -> Result<Self, EspError> {
+        let pk_type = match key_type {
+            KeyType::EcP256 | KeyType::EcP384 => mbedtls_pk_type_t_MBEDTLS_PK_ECKEY,
+            #[cfg(esp_idf_mbedtls_genprime)]
+            KeyType::Rsa(_) => mbedtls_pk_type_t_MBEDTLS_PK_RSA,
+        };
+
+        let mut key = Self::new();
+
+        Self::check(unsafe { mbedtls_pk_setup(&mut *key.0, mbedtls_pk_info_from_type(pk_type)) })?;
+
+        let ret = match key_type {
+            KeyType::EcP256 | KeyType::EcP384 => {
+                let group = if key_type == KeyType::EcP256 {
+                    mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1
+                } else {
+                    mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP384R1
+                };
+
+                unsafe {
+                    mbedtls_ecp_gen_key(
+                        group,
+                        key.0.private_pk_ctx as *mut mbedtls_ecp_keypair,
+                        Some(mbedtls_rng),
+                        core::ptr::null_mut(),
+                    )
+                }
+            }
+            #[cfg(esp_idf_mbedtls_genprime)]
+            KeyType::Rsa(bits) => unsafe {
+                mbedtls_rsa_gen_key(
+                    key.0.private_pk_ctx as *mut mbedtls_rsa_context,
+                    Some(mbedtls_rng),
+                    core::ptr::null_mut(),
+                    bits as c_uint,
+                    65537,
+                )
+            },
+        };
+
+        Self::check(ret)?;
+
+        Ok(key)
+    }
+
+    /// Parse a PEM or DER private key, e.g. previously exported with `private_key_der`
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the key cannot be parsed, or the password is wrong.
+    pub fn parse(key: X509, password: Option<&str>) -> Result<Self, EspError> {
+        let mut pk = Self::new();
+
+        let password = password.unwrap_or_default();
+
+        let ret = unsafe {
+            mbedtls_pk_parse_key(
+                &mut *pk.0,
+                key.data().as_ptr(),
+                key.data().len(),
+                password.as_ptr(),
+                password.len(),
+                Some(mbedtls_rng),
+                core::ptr::null_mut(),
+            )
+        };
+
+        if ret == 0 {
+            Ok(pk)
+        } else {
+            log::warn!("Failed to parse the private key (error {ret})");
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        }
+    }
+
+    /// The length of the key, in bits
+    pub fn bits(&self) -> usize {
+        unsafe { mbedtls_pk_get_bitlen(&*self.0) }
+    }
+
+    /// Export the private key in DER, e.g. to store it in encrypted NVS
+    pub fn private_key_der(&self) -> Result<Vec<u8>, EspError> {
+        self.write_der(|pk, buf, len| unsafe { mbedtls_pk_write_key_der(pk, buf, len) })
+    }
+
+    /// Export the public key in DER, as a `SubjectPublicKeyInfo`
+    pub fn public_key_der(&self) -> Result<Vec<u8>, EspError> {
+        self.write_der(|pk, buf, len| unsafe { mbedtls_pk_write_pubkey_der(pk, buf, len) })
+    }
+
+    /// Export the private key in PEM
+    #[cfg(esp_idf_mbedtls_pem_write_c)]
+    pub fn private_key_pem(&self) -> Result<String, EspError> {
+        self.write_pem(|pk, buf, len| unsafe { mbedtls_pk_write_key_pem(pk, buf, len) })
+    }
+
+    /// Export the public key in PEM
+    #[cfg(esp_idf_mbedtls_pem_write_c)]
+    pub fn public_key_pem(&self) -> Result<String, EspError> {
+        self.write_pem(|pk, buf, len| unsafe { mbedtls_pk_write_pubkey_pem(pk, buf, len) })
+    }
+
+    /// Create a certificate signing request for the public key, signed with SHA-256
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the subject cannot be parsed.
+    pub fn csr_der(&self, params: &CsrParams) -> Result<Vec<u8>, EspError> {
+        self.write_csr(params, |csr, buf, len| unsafe {
+            mbedtls_x509write_csr_der(csr, buf, len, Some(mbedtls_rng), core::ptr::null_mut())
+        })
+        .map(|(buf, len)| buf[buf.len() - len..].to_vec())
+    }
+
+    /// Create a certificate signing request for the public key, signed with SHA-256, in PEM
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the subject cannot be parsed.
+    #[cfg(esp_idf_mbedtls_pem_write_c)]
+    pub fn csr_pem(&self, params: &CsrParams) -> Result<String, EspError> {
+        self.write_csr(params, |csr, buf, len| unsafe {
+            mbedtls_x509write_csr_pem(csr, buf, len, Some(mbedtls_rng), core::ptr::null_mut())
+        })
+        .and_then(|(buf, _)| Self::pem_to_string(buf))
+    }
+
+    pub(crate) fn as_raw(&self) -> *mut mbedtls_pk_context {
+        &*self.0 as *const _ as *mut _
+    }
+
+    fn new() -> Self {
+        let mut pk = Box::new(unsafe { core::mem::zeroed() });
+
+        unsafe { mbedtls_pk_init(&mut *pk) };
+
+        Self(pk)
+    }
+
+    fn write_csr(
+        &self,
+        params: &CsrParams,
+        write: impl FnOnce(*mut mbedtls_x509write_csr, *mut u8, usize) -> c_int,
+    ) -> Result<(Vec<u8>, usize), EspError> {
+        let subject = CString::new(params.subject)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        let san = Self::encode_dns_names(params.dns_names);
+
+        let mut csr: mbedtls_x509write_csr = unsafe { core::mem::zeroed() };
+
+        unsafe {
+            mbedtls_x509write_csr_init(&mut csr);
+            mbedtls_x509write_csr_set_key(&mut csr, self.as_raw());
+            mbedtls_x509write_csr_set_md_alg(&mut csr, mbedtls_md_type_t_MBEDTLS_MD_SHA256);
+        }
+
+        let result = (|| {
+            if unsafe { mbedtls_x509write_csr_set_subject_name(&mut csr, subject.as_ptr()) } != 0 {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+
+            if let Some(san) = &san {
+                Self::check(unsafe {
+                    mbedtls_x509write_csr_set_extension(
+                        &mut csr,
+                        OID_SUBJECT_ALT_NAME.as_ptr() as _,
+                        OID_SUBJECT_ALT_NAME.len(),
+                        0,
+                        san.as_ptr(),
+                        san.len(),
+                    )
+                })?;
+            }
+
+            let mut buf = alloc::vec![0; self.buf_len()];
+            let len = Self::check(write(&mut csr, buf.as_mut_ptr(), buf.len()))?;
+
+            Ok((buf, len as usize))
+        })();
+
+        unsafe { mbedtls_x509write_csr_free(&mut csr) };
+
+        result
+    }
+
+    // The DER `GeneralNames` sequence of the subject alternative name extension
+    fn encode_dns_names(dns_names: &[&str]) -> Option<Vec<u8>> {
+        fn push_len(out: &mut Vec<u8>, len: usize) {
+            if len < 0x80 {
+                out.push(len as u8);
+            } else if len < 0x100 {
+                out.extend_from_slice(&[0x81, len as u8]);
+            } else {
+                out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+            }
+        }
+
+        if dns_names.is_empty() {
+            return None;
+        }
+
+        let mut names = Vec::new();
+
+        for name in dns_names {
+            names.push(0x80 | SAN_DNS_NAME);
+            push_len(&mut names, name.len());
+            names.extend_from_slice(name.as_bytes());
+        }
+
+        // SEQUENCE
+        let mut san = alloc::vec![0x30];
+        push_len(&mut san, names.len());
+        san.extend_from_slice(&names);
+
+        Some(san)
+    }
+
+    // The DER functions of Mbed TLS write at the end of the buffer
+    fn write_der(
+        &self,
+        write: impl FnOnce(*const mbedtls_pk_context, *mut u8, usize) -> c_int,
+    ) -> Result<Vec<u8>, EspError> {
+        let mut buf = alloc::vec![0; self.buf_len()];
+
+        let len = Self::check(write(&*self.0, buf.as_mut_ptr(), buf.len()))? as usize;
+
+        Ok(buf[buf.len() - len..].to_vec())
+    }
+
+    #[cfg(esp_idf_mbedtls_pem_write_c)]
+    fn write_pem(
+        &self,
+        write: impl FnOnce(*const mbedtls_pk_context, *mut u8, usize) -> c_int,
+    ) -> Result<String, EspError> {
+        let mut buf = alloc::vec![0; self.buf_len()];
+
+        Self::check(write(&*self.0, buf.as_mut_ptr(), buf.len()))?;
+
+        Self::pem_to_string(buf)
+    }
+
+    #[cfg(esp_idf_mbedtls_pem_write_c)]
+    fn pem_to_string(mut buf: Vec<u8>) -> Result<String, EspError> {
+        let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        buf.truncate(len);
+
+        String::from_utf8(buf).map_err(|_| EspError::from_infallible::<ESP_FAIL>())
+    }
+
+    // Enough for the PEM encoding of the private key, or of a CSR
+    fn buf_len(&self) -> usize {
+        self.bits() + 1024
+    }
+
+    fn check(ret: c_int) -> Result<c_int, EspError> {
+        if ret >= 0 {
+            Ok(ret)
+        } else {
+            Err(EspError::from(ret).unwrap())
+        }
+    }
+}
+
+impl Drop for KeyPair {
+    fn drop(&mut self) {
+        unsafe { mbedtls_pk_free(&mut *self.0) };
+    }
+}
+
+impl Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("bits", &self.bits())
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl Send for KeyPair {}
@@ -0,0 +1,9 @@
+//! Diagnostics of long-running applications
+pub mod heap;
+#[cfg(all(feature = "std", esp_idf_comp_nvs_flash_enabled))]
+pub mod panic;
+pub mod reset;
+#[cfg(all(feature = "alloc", esp_idf_freertos_use_trace_facility))]
+mod task;
+#[cfg(all(feature = "alloc", esp_idf_freertos_use_trace_facility))]
+pub use task::*;
@@ -0,0 +1,437 @@
+//! Heap diagnostics
+//!
+//! - `info` reports the usage and the fragmentation of the heap regions with given
+//!   capabilities
+//! - `task_usage` reports the heap used by each task, with `CONFIG_HEAP_TASK_TRACKING`
+//! - `HeapMonitor` samples the free heap periodically, and posts a `HeapEvent` to the system
+//!   event loop when it crosses one of the thresholds configured
+//! - `HeapTrace` records the allocations, with `CONFIG_HEAP_TRACING_STANDALONE`, to hunt
+//!   leaks:
+//!
+//! ```ignore
+//! use esp_idf_svc::diag::heap::{HeapTrace, TraceMode};
+//!
+//! let mut trace = HeapTrace::take(100)?;
+//!
+//! trace.start(TraceMode::Leaks)?;
+//! // The code leaking
+//! trace.stop()?;
+//!
+//! trace.dump();
+//! ```
+
+use core::ffi::CStr;
+
+use crate::sys::*;
+
+/// The heap regions to report on
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Memory {
+    /// The regions of the default allocator, i.e. `malloc` and the Rust global allocator
+    Default,
+    /// The internal RAM
+    Internal,
+    /// The external PSRAM
+    Spiram,
+    /// The regions usable for DMA
+    Dma,
+    /// The regions with executable memory
+    Exec,
+}
+
+impl Memory {
+    fn caps(&self) -> u32 {
+        match self {
+            Self::Default => MALLOC_CAP_DEFAULT,
+            Self::Internal => MALLOC_CAP_INTERNAL,
+            Self::Spiram => MALLOC_CAP_SPIRAM,
+            Self::Dma => MALLOC_CAP_DMA,
+            Self::Exec => MALLOC_CAP_EXEC,
+        }
+    }
+}
+
+/// The usage of heap regions
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct HeapInfo {
+    pub free_bytes: usize,
+    pub allocated_bytes: usize,
+    /// The largest block which can be allocated
+    pub largest_free_block: usize,
+    /// The lowest free bytes since boot
+    pub minimum_free_bytes: usize,
+    pub allocated_blocks: usize,
+    pub free_blocks: usize,
+}
+
+impl HeapInfo {
+    /// The fragmentation of the free memory, from 0 - a single free block - to 100 percent
+    pub fn fragmentation(&self) -> u8 {
+        if self.free_bytes == 0 {
+            0
+        } else {
+            (100 - self.largest_free_block as u64 * 100 / self.free_bytes as u64) as _
+        }
+    }
+}
+
+/// Get the usage of the heap regions
+pub fn info(memory: Memory) -> HeapInfo {
+    let mut info: multi_heap_info_t = Default::default();
+
+    unsafe {
+        heap_caps_get_info(&mut info, memory.caps());
+    }
+
+    HeapInfo {
+        free_bytes: info.total_free_bytes as _,
+        allocated_bytes: info.total_allocated_bytes as _,
+        largest_free_block: info.largest_free_block as _,
+        minimum_free_bytes: info.minimum_free_bytes as _,
+        allocated_blocks: info.allocated_blocks as _,
+        free_blocks: info.free_blocks as _,
+    }
+}
+
+/// Check the integrity of all the heap regions, logging the errors found
+pub fn check_integrity() -> bool {
+    unsafe { heap_caps_check_integrity_all(true) }
+}
+
+/// The heap used by a task
+#[cfg(esp_idf_heap_task_tracking)]
+#[derive(Copy, Clone, Debug)]
+pub struct TaskHeapUsage {
+    /// The task, or null for the memory allocated before the scheduler started
+    pub task: TaskHandle_t,
+    /// The bytes allocated in byte-addressable memory
+    pub bytes: usize,
+    /// The blocks allocated in byte-addressable memory
+    pub blocks: usize,
+}
+
+/// Get the heap used by each task into `usage`, returning the number of tasks reported
+#[cfg(esp_idf_heap_task_tracking)]
+pub fn task_usage(usage: &mut [TaskHeapUsage]) -> usize {
+    const MAX_TASKS: usize = 32;
+
+    let mut totals: [heap_task_totals_t; MAX_TASKS] = Default::default();
+    let mut num_totals = 0;
+
+    #[allow(clippy::needless_update)]
+    let mut params = heap_task_info_params_t {
+        caps: [MALLOC_CAP_8BIT, 0],
+        mask: [MALLOC_CAP_8BIT, 0],
+        totals: totals.as_mut_ptr(),
+        num_totals: &mut num_totals,
+        max_totals: core::cmp::min(usage.len(), MAX_TASKS) as _,
+        ..Default::default()
+    };
+
+    unsafe {
+        heap_caps_get_per_task_info(&mut params);
+    }
+
+    let len = num_totals as usize;
+
+    for (usage, totals) in usage.iter_mut().zip(&totals[..len]) {
+        *usage = TaskHeapUsage {
+            task: totals.task,
+            bytes: totals.size[0] as _,
+            blocks: totals.count[0] as _,
+        };
+    }
+
+    len
+}
+
+/// A heap event, posted by `HeapMonitor`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HeapEvent {
+    /// The free heap dropped below the threshold
+    BelowThreshold { threshold: usize, free_bytes: usize },
+    /// The free heap went back above the threshold
+    AboveThreshold { threshold: usize, free_bytes: usize },
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_event_enabled))]
+unsafe impl crate::eventloop::EspEventSource for HeapEvent {
+    fn source() -> Option<&'static CStr> {
+        Some(CStr::from_bytes_with_nul(b"ESP-SVC-HEAP\0").unwrap())
+    }
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_event_enabled))]
+impl crate::eventloop::EspEventSerializer for HeapEvent {
+    type Data<'a> = HeapEvent;
+
+    fn serialize<F, R>(event: &Self::Data<'_>, f: F) -> R
+    where
+        F: FnOnce(&crate::eventloop::EspEventPostData) -> R,
+    {
+        f(&unsafe {
+            crate::eventloop::EspEventPostData::new(
+                Self::source().unwrap(),
+                Self::event_id(),
+                event,
+            )
+        })
+    }
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_event_enabled))]
+impl crate::eventloop::EspEventDeserializer for HeapEvent {
+    type Data<'a> = HeapEvent;
+
+    fn deserialize<'a>(data: &crate::eventloop::EspEvent<'a>) -> Self::Data<'a> {
+        *unsafe { data.as_payload::<HeapEvent>() }
+    }
+}
+
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_esp_timer_enabled
+))]
+pub use monitor::*;
+
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_esp_timer_enabled
+))]
+mod monitor {
+    use core::time::Duration;
+
+    extern crate alloc;
+    use alloc::vec;
+
+    use log::warn;
+
+    use crate::eventloop::EspSystemEventLoop;
+    use crate::sys::EspError;
+    use crate::timer::{EspTimer, EspTimerService, EspTimerServiceType};
+
+    use super::{info, HeapEvent, Memory};
+
+    /// The configuration of `HeapMonitor`
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub struct HeapMonitorConfiguration<'a> {
+        /// The period of the sampling of the free heap
+        pub interval: Duration,
+        /// The heap regions monitored
+        pub memory: Memory,
+        /// The thresholds of free bytes, in any order
+        pub thresholds: &'a [usize],
+    }
+
+    impl HeapMonitorConfiguration<'_> {
+        pub const fn new() -> Self {
+            Self {
+                interval: Duration::from_secs(10),
+                memory: Memory::Default,
+                thresholds: &[],
+            }
+        }
+    }
+
+    impl Default for HeapMonitorConfiguration<'_> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// Samples the free heap periodically, posting a `HeapEvent` to the system event loop
+    /// whenever the free heap crosses one of the thresholds; stopped when dropped
+    pub struct HeapMonitor {
+        _timer: EspTimer<'static>,
+    }
+
+    impl HeapMonitor {
+        pub fn new<T>(
+            timer_service: &EspTimerService<T>,
+            sys_loop: EspSystemEventLoop,
+            conf: &HeapMonitorConfiguration,
+        ) -> Result<Self, EspError>
+        where
+            T: EspTimerServiceType,
+        {
+            let memory = conf.memory;
+
+            let mut thresholds = conf.thresholds.to_vec();
+            thresholds.sort_unstable();
+            thresholds.dedup();
+
+            // The thresholds currently above the free heap
+            let mut below = vec![false; thresholds.len()];
+
+            let timer = timer_service.timer(move || {
+                let free_bytes = info(memory).free_bytes;
+
+                for (threshold, below) in thresholds.iter().zip(below.iter_mut()) {
+                    let now_below = free_bytes < *threshold;
+
+                    if now_below != *below {
+                        *below = now_below;
+
+                        let event = if now_below {
+                            HeapEvent::BelowThreshold {
+                                threshold: *threshold,
+                                free_bytes,
+                            }
+                        } else {
+                            HeapEvent::AboveThreshold {
+                                threshold: *threshold,
+                                free_bytes,
+                            }
+                        };
+
+                        // Never block the timer task
+                        if !matches!(sys_loop.post::<HeapEvent>(&event, 0), Ok(true)) {
+                            warn!("Heap event dropped: {event:?}");
+                        }
+                    }
+                }
+            })?;
+
+            timer.every(conf.interval)?;
+
+            Ok(Self { _timer: timer })
+        }
+    }
+}
+
+/// What `HeapTrace` records
+#[cfg(esp_idf_heap_tracing_standalone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceMode {
+    /// All the allocations and frees
+    All,
+    /// Only the allocations not freed yet, i.e. the potential leaks
+    Leaks,
+}
+
+/// An allocation recorded by `HeapTrace`
+#[cfg(esp_idf_heap_tracing_standalone)]
+#[derive(Copy, Clone, Debug)]
+pub struct TraceRecord {
+    pub address: usize,
+    pub size: usize,
+    /// The CPU cycle count when allocated
+    pub ccount: u32,
+    /// The return addresses of the callers of the allocation, innermost first
+    pub callers: [usize; CONFIG_HEAP_TRACING_STACK_DEPTH as usize],
+}
+
+#[cfg(esp_idf_heap_tracing_standalone)]
+static TRACE_TAKEN: crate::private::mutex::Mutex<bool> = crate::private::mutex::Mutex::new(false);
+
+/// The standalone heap tracing, recording the allocations into a buffer of internal RAM
+#[cfg(esp_idf_heap_tracing_standalone)]
+pub struct HeapTrace {
+    records: *mut heap_trace_record_t,
+}
+
+#[cfg(esp_idf_heap_tracing_standalone)]
+impl HeapTrace {
+    /// Allocate a buffer of `capacity` records for the tracing
+    pub fn take(capacity: usize) -> Result<Self, EspError> {
+        let mut taken = TRACE_TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let records = unsafe {
+            heap_caps_calloc(
+                capacity,
+                core::mem::size_of::<heap_trace_record_t>(),
+                MALLOC_CAP_INTERNAL | MALLOC_CAP_8BIT,
+            )
+        } as *mut heap_trace_record_t;
+
+        if records.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        if let Err(err) = esp!(unsafe { heap_trace_init_standalone(records, capacity as _) }) {
+            unsafe { heap_caps_free(records as *mut _) };
+
+            return Err(err);
+        }
+
+        *taken = true;
+
+        Ok(Self { records })
+    }
+
+    /// Start the tracing, clearing the records
+    pub fn start(&mut self, mode: TraceMode) -> Result<(), EspError> {
+        let mode = match mode {
+            TraceMode::All => heap_trace_mode_t_HEAP_TRACE_ALL,
+            TraceMode::Leaks => heap_trace_mode_t_HEAP_TRACE_LEAKS,
+        };
+
+        esp!(unsafe { heap_trace_start(mode) })
+    }
+
+    /// Stop the tracing, keeping the records
+    pub fn stop(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { heap_trace_stop() })
+    }
+
+    /// Resume the tracing, keeping the records
+    pub fn resume(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { heap_trace_resume() })
+    }
+
+    /// The number of records
+    pub fn len(&self) -> usize {
+        unsafe { heap_trace_get_count() as _ }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get a record
+    pub fn get(&self, index: usize) -> Result<TraceRecord, EspError> {
+        let mut record: heap_trace_record_t = Default::default();
+
+        esp!(unsafe { heap_trace_get(index as _, &mut record) })?;
+
+        Ok(TraceRecord {
+            address: record.address as _,
+            size: record.size as _,
+            ccount: record.ccount,
+            callers: record.alloc_stack.map(|caller| caller as _),
+        })
+    }
+
+    /// Iterate over the records
+    pub fn records(&self) -> impl Iterator<Item = TraceRecord> + '_ {
+        (0..self.len()).filter_map(|index| self.get(index).ok())
+    }
+
+    /// Log the records, and a summary
+    pub fn dump(&self) {
+        unsafe {
+            heap_trace_dump();
+        }
+    }
+}
+
+#[cfg(esp_idf_heap_tracing_standalone)]
+impl Drop for HeapTrace {
+    fn drop(&mut self) {
+        let mut taken = TRACE_TAKEN.lock();
+
+        unsafe {
+            heap_trace_stop();
+            heap_trace_init_standalone(core::ptr::null_mut(), 0);
+            heap_caps_free(self.records as *mut _);
+        }
+
+        *taken = false;
+    }
+}
@@ -0,0 +1,168 @@
+//! Persisting Rust panics across the reboot
+//!
+//! The core dump of the ESP-IDF gives the state of the crashed task, but not the message of
+//! a Rust panic. `install` sets an (opt-in) panic hook which stores the message, the location
+//! and - on Xtensa - the backtrace of the panic in NVS before the reboot, and `take` reads
+//! and clears them on the next boot, e.g. to report them with the telemetry:
+//!
+//! ```ignore
+//! use esp_idf_svc::diag::panic;
+//! use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+//!
+//! let partition = EspDefaultNvsPartition::take()?;
+//!
+//! let mut nvs = EspNvs::new(partition.clone(), "panic", true)?;
+//! if let Some(record) = panic::take(&mut nvs)? {
+//!     log::error!("Previous panic: {}", record.message);
+//! }
+//!
+//! panic::install(nvs);
+//! ```
+
+use std::panic;
+use std::sync::Mutex;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::nvs::{EspNvs, NvsPartitionId};
+use crate::sys::*;
+
+const MESSAGE_KEY: &str = "panic_msg";
+const BACKTRACE_KEY: &str = "panic_bt";
+
+/// The maximum length of the message stored
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+/// The maximum number of addresses of the backtrace stored
+pub const MAX_BACKTRACE_LEN: usize = 32;
+
+/// A panic stored by the panic hook
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PanicRecord {
+    /// The message and the location of the panic, possibly truncated
+    pub message: String,
+    /// The program counters of the backtrace of the panicking task, innermost first; empty on
+    /// RISC-V, where the backtrace can not be walked at runtime
+    pub backtrace: Vec<u32>,
+}
+
+/// Set the panic hook storing the panics in `nvs`, keeping the previous hook - which prints
+/// the panic - installed after it
+pub fn install<T>(nvs: EspNvs<T>)
+where
+    T: NvsPartitionId + 'static,
+{
+    let nvs = Mutex::new(nvs);
+    let previous = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        let mut message = info.to_string();
+        truncate(&mut message, MAX_MESSAGE_LEN);
+
+        let backtrace = backtrace();
+
+        // The panic may have happened with the lock held
+        if let Ok(mut nvs) = nvs.try_lock() {
+            let _ = nvs.set_str(MESSAGE_KEY, &message);
+            let _ = nvs.set_blob(BACKTRACE_KEY, &to_bytes(&backtrace));
+        }
+
+        previous(info);
+    }));
+}
+
+/// Read and clear the panic stored by the panic hook, if any
+pub fn take<T>(nvs: &mut EspNvs<T>) -> Result<Option<PanicRecord>, EspError>
+where
+    T: NvsPartitionId,
+{
+    let mut buf = [0; MAX_MESSAGE_LEN + 1];
+
+    let Some(message) = nvs.get_str(MESSAGE_KEY, &mut buf)?.map(str::to_string) else {
+        return Ok(None);
+    };
+
+    let mut buf = [0; MAX_BACKTRACE_LEN * 4];
+
+    let backtrace = nvs
+        .get_blob(BACKTRACE_KEY, &mut buf)?
+        .map(|bytes| {
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    clear(nvs)?;
+
+    Ok(Some(PanicRecord { message, backtrace }))
+}
+
+/// Clear the panic stored by the panic hook
+pub fn clear<T>(nvs: &mut EspNvs<T>) -> Result<(), EspError>
+where
+    T: NvsPartitionId,
+{
+    nvs.remove(MESSAGE_KEY)?;
+    nvs.remove(BACKTRACE_KEY)?;
+
+    Ok(())
+}
+
+fn truncate(message: &mut String, max_len: usize) {
+    if message.len() > max_len {
+        let mut len = max_len;
+
+        while !message.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        message.truncate(len);
+    }
+}
+
+fn to_bytes(backtrace: &[u32]) -> Vec<u8> {
+    backtrace
+        .iter()
+        .flat_map(|address| address.to_le_bytes())
+        .collect()
+}
+
+#[cfg(target_arch = "xtensa")]
+fn backtrace() -> Vec<u32> {
+    let mut frame: esp_backtrace_frame_t = Default::default();
+    let mut backtrace = Vec::with_capacity(MAX_BACKTRACE_LEN);
+
+    unsafe {
+        esp_backtrace_get_start(&mut frame.pc, &mut frame.sp, &mut frame.next_pc);
+    }
+
+    while backtrace.len() < MAX_BACKTRACE_LEN {
+        backtrace.push(process_stack_pc(frame.pc));
+
+        if frame.next_pc == 0 || !unsafe { esp_backtrace_get_next_frame(&mut frame) } {
+            break;
+        }
+    }
+
+    backtrace
+}
+
+// As `esp_cpu_process_stack_pc`: the return addresses have the window increment in their top
+// bits, and point after the call instruction
+#[cfg(target_arch = "xtensa")]
+fn process_stack_pc(mut pc: u32) -> u32 {
+    if pc & 0x8000_0000 != 0 {
+        pc = (pc & 0x3fff_ffff) | 0x4000_0000;
+    }
+
+    pc.wrapping_sub(3)
+}
+
+#[cfg(not(target_arch = "xtensa"))]
+fn backtrace() -> Vec<u32> {
+    Vec::new()
+}
@@ -0,0 +1,277 @@
+//! Reset reasons and reboot history
+//!
+//! - `ResetReason::get` gives the typed reason of the last reset, to tell crashes, brownouts
+//!   and watchdogs from normal reboots in the telemetry
+//! - `reboot_history` keeps the reasons of the last resets in RTC memory, which survives
+//!   the resets but not a power loss
+//! - `count_boot` keeps a boot counter in NVS, which survives a power loss
+
+use core::fmt;
+
+use crate::sys::*;
+
+pub use crate::sleep::WakeupCause;
+
+/// The reason of a reset
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetReason {
+    Unknown,
+    /// Power on, or reset by the EN pin
+    PowerOn,
+    /// External pin reset
+    External,
+    /// Software reset, e.g. `esp_restart`
+    Software,
+    /// Reset after a panic or an exception
+    Panic,
+    /// The interrupt watchdog
+    InterruptWatchdog,
+    /// The task watchdog
+    TaskWatchdog,
+    /// Another watchdog, e.g. the RTC one
+    OtherWatchdog,
+    /// Wakeup from deep sleep
+    DeepSleep,
+    /// Brownout of the supply voltage
+    Brownout,
+    /// Reset over SDIO
+    Sdio,
+    Other(u32),
+}
+
+impl ResetReason {
+    /// Get the reason of the last reset
+    pub fn get() -> Self {
+        Self::from_raw(unsafe { esp_reset_reason() } as _)
+    }
+
+    /// Whether the reset is due to a crash: a panic or a watchdog
+    pub fn is_crash(&self) -> bool {
+        matches!(
+            self,
+            Self::Panic | Self::InterruptWatchdog | Self::TaskWatchdog | Self::OtherWatchdog
+        )
+    }
+
+    #[cfg(any(esp_idf_soc_rtc_slow_mem_supported, esp_idf_soc_rtc_fast_mem_supported))]
+    fn to_raw(self) -> u32 {
+        (match self {
+            Self::Unknown => esp_reset_reason_t_ESP_RST_UNKNOWN,
+            Self::PowerOn => esp_reset_reason_t_ESP_RST_POWERON,
+            Self::External => esp_reset_reason_t_ESP_RST_EXT,
+            Self::Software => esp_reset_reason_t_ESP_RST_SW,
+            Self::Panic => esp_reset_reason_t_ESP_RST_PANIC,
+            Self::InterruptWatchdog => esp_reset_reason_t_ESP_RST_INT_WDT,
+            Self::TaskWatchdog => esp_reset_reason_t_ESP_RST_TASK_WDT,
+            Self::OtherWatchdog => esp_reset_reason_t_ESP_RST_WDT,
+            Self::DeepSleep => esp_reset_reason_t_ESP_RST_DEEPSLEEP,
+            Self::Brownout => esp_reset_reason_t_ESP_RST_BROWNOUT,
+            Self::Sdio => esp_reset_reason_t_ESP_RST_SDIO,
+            Self::Other(other) => other as _,
+        }) as _
+    }
+
+    fn from_raw(raw: u32) -> Self {
+        #[allow(non_upper_case_globals)]
+        match raw as esp_reset_reason_t {
+            esp_reset_reason_t_ESP_RST_UNKNOWN => Self::Unknown,
+            esp_reset_reason_t_ESP_RST_POWERON => Self::PowerOn,
+            esp_reset_reason_t_ESP_RST_EXT => Self::External,
+            esp_reset_reason_t_ESP_RST_SW => Self::Software,
+            esp_reset_reason_t_ESP_RST_PANIC => Self::Panic,
+            esp_reset_reason_t_ESP_RST_INT_WDT => Self::InterruptWatchdog,
+            esp_reset_reason_t_ESP_RST_TASK_WDT => Self::TaskWatchdog,
+            esp_reset_reason_t_ESP_RST_WDT => Self::OtherWatchdog,
+            esp_reset_reason_t_ESP_RST_DEEPSLEEP => Self::DeepSleep,
+            esp_reset_reason_t_ESP_RST_BROWNOUT => Self::Brownout,
+            esp_reset_reason_t_ESP_RST_SDIO => Self::Sdio,
+            other => Self::Other(other as _),
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "unknown"),
+            Self::PowerOn => write!(f, "power on"),
+            Self::External => write!(f, "external"),
+            Self::Software => write!(f, "software"),
+            Self::Panic => write!(f, "panic"),
+            Self::InterruptWatchdog => write!(f, "interrupt watchdog"),
+            Self::TaskWatchdog => write!(f, "task watchdog"),
+            Self::OtherWatchdog => write!(f, "watchdog"),
+            Self::DeepSleep => write!(f, "deep sleep"),
+            Self::Brownout => write!(f, "brownout"),
+            Self::Sdio => write!(f, "SDIO"),
+            Self::Other(other) => write!(f, "other ({other})"),
+        }
+    }
+}
+
+/// The cause of the wakeup, when the last reset is a wakeup from deep sleep
+pub fn wakeup_cause() -> Option<WakeupCause> {
+    (ResetReason::get() == ResetReason::DeepSleep).then(WakeupCause::get)
+}
+
+/// The low-level reset reason of a core, as reported by the ROM - e.g. to tell the RTC
+/// watchdog from the brownout detector, or the core which triggered the reset
+pub fn rtc_reset_reason(core: u32) -> u32 {
+    unsafe { esp_rom_get_reset_reason(core as _) as _ }
+}
+
+/// The number of resets kept by `reboot_history`
+pub const REBOOT_HISTORY_LEN: usize = 8;
+
+/// The reasons of the last resets
+#[derive(Clone, Debug)]
+pub struct RebootHistory {
+    /// The boots since the RTC memory was lost, i.e. since the last power loss, including this
+    /// one
+    pub boot_count: u32,
+    reasons: [u32; REBOOT_HISTORY_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl RebootHistory {
+    /// The reasons of the last resets, the most recent - of this boot - first
+    pub fn reasons(&self) -> impl Iterator<Item = ResetReason> + '_ {
+        (0..self.len).map(move |index| {
+            ResetReason::from_raw(
+                self.reasons[(self.head + REBOOT_HISTORY_LEN - index) % REBOOT_HISTORY_LEN],
+            )
+        })
+    }
+
+    /// The number of crashes in the history
+    pub fn crashes(&self) -> usize {
+        self.reasons().filter(ResetReason::is_crash).count()
+    }
+}
+
+#[cfg(any(esp_idf_soc_rtc_slow_mem_supported, esp_idf_soc_rtc_fast_mem_supported))]
+mod rtc {
+    use core::mem::MaybeUninit;
+
+    use crate::private::mutex::Mutex;
+
+    use super::{RebootHistory, ResetReason, REBOOT_HISTORY_LEN};
+
+    const MAGIC: u32 = 0x5254_4831;
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct History {
+        magic: u32,
+        boot_count: u32,
+        head: u32,
+        len: u32,
+        reasons: [u32; REBOOT_HISTORY_LEN],
+        checksum: u32,
+    }
+
+    impl History {
+        fn checksum(&self) -> u32 {
+            let words = [self.magic, self.boot_count, self.head, self.len];
+
+            words
+                .iter()
+                .chain(self.reasons.iter())
+                .fold(0x811c_9dc5_u32, |hash, word| {
+                    (hash ^ word).wrapping_mul(0x0100_0193)
+                })
+        }
+
+        fn is_valid(&self) -> bool {
+            self.magic == MAGIC
+                && self.head < REBOOT_HISTORY_LEN as u32
+                && self.len <= REBOOT_HISTORY_LEN as u32
+                && self.checksum == self.checksum()
+        }
+    }
+
+    // Not initialized on boot, so that it survives the resets
+    #[link_section = ".rtc_noinit"]
+    static mut HISTORY: MaybeUninit<History> = MaybeUninit::uninit();
+
+    static RECORDED: Mutex<bool> = Mutex::new(false);
+
+    /// Get the history of the resets kept in RTC memory, recording the reset of this boot on
+    /// the first call
+    pub fn reboot_history() -> RebootHistory {
+        let mut recorded = RECORDED.lock();
+
+        // Only accessed with `RECORDED` locked
+        let history = unsafe { &mut *core::ptr::addr_of_mut!(HISTORY) };
+
+        if !*recorded {
+            let mut current = unsafe { history.assume_init_read() };
+
+            if !current.is_valid() {
+                current = History {
+                    magic: MAGIC,
+                    boot_count: 0,
+                    head: (REBOOT_HISTORY_LEN - 1) as _,
+                    len: 0,
+                    reasons: [0; REBOOT_HISTORY_LEN],
+                    checksum: 0,
+                };
+            }
+
+            current.boot_count = current.boot_count.wrapping_add(1);
+            current.head = (current.head + 1) % REBOOT_HISTORY_LEN as u32;
+            current.reasons[current.head as usize] = ResetReason::get().to_raw();
+            current.len = core::cmp::min(current.len + 1, REBOOT_HISTORY_LEN as _);
+            current.checksum = current.checksum();
+
+            history.write(current);
+
+            *recorded = true;
+        }
+
+        let current = unsafe { history.assume_init_ref() };
+
+        RebootHistory {
+            boot_count: current.boot_count,
+            reasons: current.reasons,
+            head: current.head as _,
+            len: current.len as _,
+        }
+    }
+
+    /// Clear the history kept in RTC memory
+    pub fn clear_reboot_history() {
+        let _recorded = RECORDED.lock();
+
+        unsafe {
+            (*core::ptr::addr_of_mut!(HISTORY)).write(History {
+                magic: 0,
+                boot_count: 0,
+                head: 0,
+                len: 0,
+                reasons: [0; REBOOT_HISTORY_LEN],
+                checksum: 0,
+            });
+        }
+    }
+}
+
+#[cfg(any(esp_idf_soc_rtc_slow_mem_supported, esp_idf_soc_rtc_fast_mem_supported))]
+pub use rtc::*;
+
+/// Increment the boot counter kept in NVS under `key`, returning it
+///
+/// Unlike the `boot_count` of `reboot_history`, it survives a power loss; it should be
+/// called once per boot.
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+pub fn count_boot<T>(nvs: &mut crate::nvs::EspNvs<T>, key: &str) -> Result<u32, EspError>
+where
+    T: crate::nvs::NvsPartitionId,
+{
+    let count = nvs.get_u32(key)?.unwrap_or(0).wrapping_add(1);
+
+    nvs.set_u32(key, count)?;
+
+    Ok(count)
+}
@@ -0,0 +1,162 @@
+use core::ffi::CStr;
+use core::fmt;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::sys::*;
+
+/// The state of a task
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Blocked,
+    Suspended,
+    Deleted,
+}
+
+impl TaskState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Ready => "ready",
+            Self::Blocked => "blocked",
+            Self::Suspended => "suspended",
+            Self::Deleted => "deleted",
+        }
+    }
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The information of a FreeRTOS task
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub handle: TaskHandle_t,
+    pub name: heapless::String<{ CONFIG_FREERTOS_MAX_TASK_NAME_LEN as usize }>,
+    /// The unique number of the task
+    pub number: u32,
+    pub state: TaskState,
+    /// The priority, possibly raised by priority inheritance
+    pub priority: u32,
+    pub base_priority: u32,
+    /// The minimum free stack since the task started, in bytes
+    pub stack_high_watermark: usize,
+    /// The time spent running, in ticks of the run time stats clock, with
+    /// `CONFIG_FREERTOS_GENERATE_RUN_TIME_STATS`
+    #[cfg(esp_idf_freertos_generate_run_time_stats)]
+    pub runtime: u32,
+    /// The share of the time spent running since boot in percent, as reported by
+    /// `vTaskGetRunTimeStats`
+    #[cfg(esp_idf_freertos_generate_run_time_stats)]
+    pub runtime_percent: u8,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:<9} prio {:>2} stack {:>5}",
+            self.name, self.state, self.priority, self.stack_high_watermark
+        )?;
+
+        #[cfg(esp_idf_freertos_generate_run_time_stats)]
+        write!(f, " cpu {:>3}%", self.runtime_percent)?;
+
+        Ok(())
+    }
+}
+
+/// Get the information of all the tasks, with `CONFIG_FREERTOS_USE_TRACE_FACILITY`
+///
+/// The scheduler is suspended meanwhile, so this should not be called too often.
+pub fn tasks() -> Vec<TaskInfo> {
+    // Room for the tasks created meanwhile
+    let capacity = unsafe { uxTaskGetNumberOfTasks() } as usize + 4;
+
+    let mut statuses = Vec::<TaskStatus_t>::with_capacity(capacity);
+    let mut total_runtime = 0;
+
+    let len = unsafe {
+        let len = uxTaskGetSystemState(statuses.as_mut_ptr(), capacity as _, &mut total_runtime);
+        statuses.set_len(len as _);
+
+        len
+    };
+
+    let mut tasks = Vec::with_capacity(len as _);
+
+    for status in &statuses {
+        #[allow(non_upper_case_globals)]
+        let state = match status.eCurrentState {
+            eTaskState_eRunning => TaskState::Running,
+            eTaskState_eReady => TaskState::Ready,
+            eTaskState_eBlocked => TaskState::Blocked,
+            eTaskState_eSuspended => TaskState::Suspended,
+            _ => TaskState::Deleted,
+        };
+
+        let name = unsafe { CStr::from_ptr(status.pcTaskName) };
+
+        let mut task_name = heapless::String::new();
+        let _ = task_name.push_str(name.to_str().unwrap_or("?"));
+
+        tasks.push(TaskInfo {
+            handle: status.xHandle,
+            name: task_name,
+            number: status.xTaskNumber as _,
+            state,
+            priority: status.uxCurrentPriority as _,
+            base_priority: status.uxBasePriority as _,
+            stack_high_watermark: status.usStackHighWaterMark as _,
+            #[cfg(esp_idf_freertos_generate_run_time_stats)]
+            runtime: status.ulRunTimeCounter as _,
+            #[cfg(esp_idf_freertos_generate_run_time_stats)]
+            runtime_percent: if total_runtime > 0 {
+                (status.ulRunTimeCounter as u64 * 100 / total_runtime as u64) as _
+            } else {
+                0
+            },
+        });
+    }
+
+    tasks
+}
+
+/// Log the information of the tasks, one task per line
+pub fn log_tasks(tasks: &[TaskInfo]) {
+    for task in tasks {
+        ::log::info!("{task}");
+    }
+}
+
+/// Reports the information of the tasks periodically to a callback - e.g. `log_tasks`, or
+/// a closure publishing them over MQTT or HTTP; stopped when dropped
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+pub struct TaskReporter {
+    _timer: crate::timer::EspTimer<'static>,
+}
+
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+impl TaskReporter {
+    pub fn new<T, F>(
+        timer_service: &crate::timer::EspTimerService<T>,
+        interval: core::time::Duration,
+        mut callback: F,
+    ) -> Result<Self, EspError>
+    where
+        T: crate::timer::EspTimerServiceType,
+        F: FnMut(&[TaskInfo]) + Send + 'static,
+    {
+        let timer = timer_service.timer(move || callback(&tasks()))?;
+
+        timer.every(interval)?;
+
+        Ok(Self { _timer: timer })
+    }
+}
@@ -0,0 +1,7 @@
+//! DNS utilities
+
+#[cfg(feature = "std")]
+pub mod resolver;
+#[cfg(feature = "std")]
+pub mod responder;
+pub mod server;
@@ -0,0 +1,378 @@
+//! A DNS resolver with per-query options
+//!
+//! In contrast to the system resolver (lwIP's `getaddrinfo`, used by `std::net::ToSocketAddrs`),
+//! which always uses the DNS servers configured for the network interfaces, `resolve`
+//! allows selecting the nameserver and the timeout per query.
+//!
+//! Queries can also be sent as DNS-over-HTTPS ([RFC 8484](https://datatracker.ietf.org/doc/html/rfc8484))
+//! requests, using the crate's HTTP client, for deployments where port 53 is blocked or spoofed.
+//!
+//! ```ignore
+//! let addrs = resolve(
+//!     "example.com",
+//!     &ResolverConfiguration {
+//!         nameserver: Nameserver::Https("https://cloudflare-dns.com/dns-query"),
+//!         crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+//!         ..Default::default()
+//!     },
+//! )?;
+//! ```
+
+use core::time::Duration;
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Instant;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::ipv4::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::sys::{esp_err_t, esp_random};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NAME_ERROR: u8 = 3;
+
+const HEADER_LEN: usize = 12;
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// The nameserver a query is sent to
+#[derive(Clone, Debug, Default)]
+pub enum Nameserver<'a> {
+    /// The system resolver and its configured DNS servers. The timeout is not applied
+    #[default]
+    System,
+    /// A DNS server reachable over UDP, usually on port 53
+    Udp(SocketAddr),
+    /// A DNS-over-HTTPS endpoint, e.g. `https://cloudflare-dns.com/dns-query`.
+    /// Requires the ESP-IDF HTTP client and TLS components
+    Https(&'a str),
+}
+
+/// The address families to resolve
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum AddressFamily {
+    /// A records only
+    Ipv4,
+    /// AAAA records only
+    Ipv6,
+    /// Both A and AAAA records
+    #[default]
+    Any,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolverConfiguration<'a> {
+    pub nameserver: Nameserver<'a>,
+    pub family: AddressFamily,
+    /// The timeout of each query
+    pub timeout: Duration,
+    /// How many times a UDP query is retried when no response arrives
+    pub retries: u8,
+    /// Used to validate the certificate of a DNS-over-HTTPS endpoint
+    pub use_global_ca_store: bool,
+    /// Used to validate the certificate of a DNS-over-HTTPS endpoint
+    pub crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut core::ffi::c_void) -> esp_err_t>,
+}
+
+impl<'a> Default for ResolverConfiguration<'a> {
+    fn default() -> Self {
+        Self {
+            nameserver: Nameserver::System,
+            family: AddressFamily::Any,
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            use_global_ca_store: false,
+            crt_bundle_attach: None,
+        }
+    }
+}
+
+/// Resolve `name` into a list of addresses
+///
+/// Returns an error of kind `io::ErrorKind::NotFound` if the name does not exist.
+pub fn resolve(name: &str, conf: &ResolverConfiguration) -> io::Result<Vec<IpAddr>> {
+    let qtypes: &[u16] = match conf.family {
+        AddressFamily::Ipv4 => &[TYPE_A],
+        AddressFamily::Ipv6 => &[TYPE_AAAA],
+        AddressFamily::Any => &[TYPE_A, TYPE_AAAA],
+    };
+
+    let mut addrs = Vec::new();
+
+    match &conf.nameserver {
+        Nameserver::System => {
+            for addr in (name, 0).to_socket_addrs()? {
+                let addr = addr.ip();
+
+                let wanted = match conf.family {
+                    AddressFamily::Ipv4 => addr.is_ipv4(),
+                    AddressFamily::Ipv6 => addr.is_ipv6(),
+                    AddressFamily::Any => true,
+                };
+
+                if wanted && !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        Nameserver::Udp(server) => {
+            for qtype in qtypes {
+                query_udp(*server, name, *qtype, conf, &mut addrs)?;
+            }
+        }
+        #[cfg(all(esp_idf_comp_esp_http_client_enabled, esp_idf_comp_esp_tls_enabled))]
+        Nameserver::Https(url) => {
+            for qtype in qtypes {
+                doh::query(url, name, *qtype, conf, &mut addrs)?;
+            }
+        }
+        #[cfg(not(all(esp_idf_comp_esp_http_client_enabled, esp_idf_comp_esp_tls_enabled)))]
+        Nameserver::Https(_) => return Err(io::ErrorKind::Unsupported.into()),
+    }
+
+    Ok(addrs)
+}
+
+fn query_udp(
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+    conf: &ResolverConfiguration,
+    addrs: &mut Vec<IpAddr>,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+
+    socket.connect(server)?;
+
+    let mut response = [0; MAX_MESSAGE_LEN];
+
+    for _ in 0..=conf.retries {
+        let (id, request) = encode_query(name, qtype)?;
+
+        socket.send(&request)?;
+
+        let deadline = Instant::now() + conf.timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            socket.set_read_timeout(Some(remaining))?;
+
+            let len = match socket.recv(&mut response) {
+                Ok(len) => len,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(err) => return Err(err),
+            };
+
+            // Ignore stray or spoofed responses with a wrong ID
+            if len >= 2 && u16::from_be_bytes([response[0], response[1]]) == id {
+                return decode_response(&response[..len], qtype, addrs);
+            }
+        }
+    }
+
+    Err(io::ErrorKind::TimedOut.into())
+}
+
+fn encode_query(name: &str, qtype: u16) -> io::Result<(u16, Vec<u8>)> {
+    let id = unsafe { esp_random() } as u16;
+
+    let mut request = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+
+    request.extend_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired
+    request.extend_from_slice(&[0x01, 0x00]);
+    // One question, no other records
+    request.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.strip_suffix('.').unwrap_or(name).split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+
+    request.push(0);
+    request.extend_from_slice(&qtype.to_be_bytes());
+    request.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok((id, request))
+}
+
+fn decode_response(response: &[u8], qtype: u16, addrs: &mut Vec<IpAddr>) -> io::Result<()> {
+    let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+
+    if response.len() < HEADER_LEN || response[2] & 0x80 == 0 {
+        return Err(invalid());
+    }
+
+    match response[3] & 0x0f {
+        0 => (),
+        RCODE_NAME_ERROR => return Err(io::ErrorKind::NotFound.into()),
+        _ => return Err(io::ErrorKind::ConnectionRefused.into()),
+    }
+
+    let qdcount = u16::from_be_bytes([response[4], response[5]]);
+    let ancount = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut offset = HEADER_LEN;
+
+    for _ in 0..qdcount {
+        offset = skip_name(response, offset).ok_or_else(invalid)? + 4;
+    }
+
+    for _ in 0..ancount {
+        offset = skip_name(response, offset).ok_or_else(invalid)?;
+
+        let fields = response.get(offset..offset + 10).ok_or_else(invalid)?;
+
+        let rtype = u16::from_be_bytes([fields[0], fields[1]]);
+        let rclass = u16::from_be_bytes([fields[2], fields[3]]);
+        let rdlen = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+
+        offset += 10;
+
+        let rdata = response.get(offset..offset + rdlen).ok_or_else(invalid)?;
+
+        offset += rdlen;
+
+        // CNAME records are skipped, as recursive servers include the records of the canonical name anyway
+        if rclass != CLASS_IN || rtype != qtype {
+            continue;
+        }
+
+        let addr = match (rtype, rdata.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(rdata);
+
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(invalid()),
+        };
+
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    Ok(())
+}
+
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+
+        if len & 0xc0 == 0xc0 {
+            // Compression pointer terminates the name
+            return Some(offset + 2);
+        }
+
+        offset += 1;
+
+        if len == 0 {
+            return Some(offset);
+        }
+
+        offset += len;
+    }
+}
+
+#[cfg(all(esp_idf_comp_esp_http_client_enabled, esp_idf_comp_esp_tls_enabled))]
+mod doh {
+    use std::io;
+
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    use crate::http::client::{Configuration, EspHttpConnection};
+    use crate::http::Method;
+    use crate::ipv4::IpAddr;
+
+    use super::{decode_response, encode_query, ResolverConfiguration, MAX_MESSAGE_LEN};
+
+    pub(super) fn query(
+        url: &str,
+        name: &str,
+        qtype: u16,
+        conf: &ResolverConfiguration,
+        addrs: &mut Vec<IpAddr>,
+    ) -> io::Result<()> {
+        let (_, mut request) = encode_query(name, qtype)?;
+
+        // RFC 8484 recommends an ID of 0, for better HTTP cache friendliness
+        request[0] = 0;
+        request[1] = 0;
+
+        let mut connection = EspHttpConnection::new(&Configuration {
+            timeout: Some(conf.timeout),
+            use_global_ca_store: conf.use_global_ca_store,
+            crt_bundle_attach: conf.crt_bundle_attach,
+            ..Default::default()
+        })
+        .map_err(io::Error::other)?;
+
+        let content_len = request.len().to_string();
+
+        connection
+            .initiate_request(
+                Method::Post,
+                url,
+                &[
+                    ("Content-Type", "application/dns-message"),
+                    ("Accept", "application/dns-message"),
+                    ("Content-Length", &content_len),
+                ],
+            )
+            .map_err(io::Error::other)?;
+
+        connection.write_all(&request).map_err(io::Error::other)?;
+        connection.initiate_response().map_err(io::Error::other)?;
+
+        if connection.status() != 200 {
+            return Err(io::Error::other(format!(
+                "DoH server responded with status {}",
+                connection.status()
+            )));
+        }
+
+        // DoH responses are not limited to 512 bytes, but A/AAAA answers practically are
+        let mut response = [0; MAX_MESSAGE_LEN * 2];
+        let mut len = 0;
+
+        while len < response.len() {
+            let read = connection
+                .read(&mut response[len..])
+                .map_err(io::Error::other)?;
+
+            if read == 0 {
+                break;
+            }
+
+            len += read;
+        }
+
+        decode_response(&response[..len], qtype, addrs)
+    }
+}
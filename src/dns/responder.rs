@@ -0,0 +1,309 @@
+//! LLMNR and NetBIOS name responders
+//!
+//! Windows networks do not always resolve mDNS (`.local`) names. To make the device reachable
+//! as `http://devicename` or `\\devicename` there as well, `EspNameResponder` answers
+//! - LLMNR ([RFC 4795](https://datatracker.ietf.org/doc/html/rfc4795)) queries,
+//!   received on the multicast group `224.0.0.252`, port 5355
+//! - NetBIOS name service ([RFC 1002](https://datatracker.ietf.org/doc/html/rfc1002)) name queries,
+//!   received as broadcasts on port 137
+//!
+//! for its own host name. Usually, the same host name as the one set with `EspMdns::set_hostname` is used.
+//!
+//! Only queries are answered; neither a name registration nor conflict detection is performed.
+
+use core::time::Duration;
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::{debug, info, warn};
+
+use crate::ipv4::{Ipv4Addr, Ipv6Addr};
+
+use super::server::DnsTable;
+
+const LLMNR_PORT: u16 = 5355;
+const LLMNR_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+
+const NETBIOS_NS_PORT: u16 = 137;
+const NETBIOS_NAME_LEN: usize = 15;
+const NETBIOS_TYPE_NB: u16 = 0x0020;
+const NETBIOS_SUFFIX_WORKSTATION: u8 = 0x00;
+const NETBIOS_SUFFIX_SERVER: u8 = 0x20;
+
+#[derive(Clone, Debug)]
+pub struct NameResponderConfiguration<'a> {
+    /// The host name to respond to, without any domain
+    pub hostname: &'a str,
+    pub ipv4: Ipv4Addr,
+    /// Also reported in LLMNR AAAA answers, if set
+    pub ipv6: Option<Ipv6Addr>,
+    pub llmnr: bool,
+    pub netbios: bool,
+    pub ttl: Duration,
+    pub task_stack_size: usize,
+}
+
+impl<'a> Default for NameResponderConfiguration<'a> {
+    fn default() -> Self {
+        Self {
+            hostname: "",
+            ipv4: Ipv4Addr::UNSPECIFIED,
+            ipv6: None,
+            llmnr: true,
+            netbios: true,
+            ttl: Duration::from_secs(30),
+            task_stack_size: 4096,
+        }
+    }
+}
+
+struct Names {
+    hostname: String,
+    ipv4: Ipv4Addr,
+    ipv6: Option<Ipv6Addr>,
+    ttl: Duration,
+    table: DnsTable,
+}
+
+impl Names {
+    fn new(hostname: &str, ipv4: Ipv4Addr, ipv6: Option<Ipv6Addr>, ttl: Duration) -> Self {
+        let mut table = DnsTable::new();
+
+        table.set_ttl(ttl);
+        table.add(hostname, ipv4);
+
+        if let Some(ipv6) = ipv6 {
+            table.add(hostname, ipv6);
+        }
+
+        Self {
+            hostname: hostname.into(),
+            ipv4,
+            ipv6,
+            ttl,
+            table,
+        }
+    }
+}
+
+/// Answers LLMNR and NetBIOS name queries for the host name of the device
+///
+/// Each enabled protocol is served by its own thread. The threads are stopped once the responder is dropped.
+pub struct EspNameResponder {
+    names: Arc<Mutex<Names>>,
+    quit: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl EspNameResponder {
+    pub fn new(conf: &NameResponderConfiguration) -> io::Result<Self> {
+        let names = Arc::new(Mutex::new(Names::new(
+            conf.hostname,
+            conf.ipv4,
+            conf.ipv6,
+            conf.ttl,
+        )));
+
+        let mut this = Self {
+            names,
+            quit: Arc::new(AtomicBool::new(false)),
+            threads: Vec::new(),
+        };
+
+        if conf.llmnr {
+            let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], LLMNR_PORT)))?;
+            socket.join_multicast_v4(&LLMNR_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+
+            this.spawn("llmnr", conf.task_stack_size, socket, handle_llmnr)?;
+        }
+
+        if conf.netbios {
+            let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], NETBIOS_NS_PORT)))?;
+            socket.set_broadcast(true)?;
+
+            this.spawn("netbios-ns", conf.task_stack_size, socket, handle_netbios)?;
+        }
+
+        info!("Name responder started for {}", conf.hostname);
+
+        Ok(this)
+    }
+
+    /// Change the host name to respond to
+    pub fn set_hostname(&self, hostname: &str) {
+        let mut names = self.names.lock().unwrap();
+
+        *names = Names::new(hostname, names.ipv4, names.ipv6, names.ttl);
+    }
+
+    /// Change the addresses reported in the answers, e.g. after the DHCP lease changed
+    pub fn set_addrs(&self, ipv4: Ipv4Addr, ipv6: Option<Ipv6Addr>) {
+        let mut names = self.names.lock().unwrap();
+
+        *names = Names::new(&names.hostname, ipv4, ipv6, names.ttl);
+    }
+
+    fn spawn(
+        &mut self,
+        name: &str,
+        stack_size: usize,
+        socket: UdpSocket,
+        handler: fn(&Names, &[u8], &mut [u8]) -> Option<usize>,
+    ) -> io::Result<()> {
+        // So that the thread notices when the responder is dropped
+        socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+        let names = self.names.clone();
+        let quit = self.quit.clone();
+
+        let thread = thread::Builder::new()
+            .name(name.into())
+            .stack_size(stack_size)
+            .spawn(move || {
+                let mut request = [0; 512];
+                let mut response = [0; 512];
+
+                while !quit.load(Ordering::SeqCst) {
+                    let (len, remote) = match socket.recv_from(&mut request) {
+                        Ok(received) => received,
+                        Err(err)
+                            if matches!(
+                                err.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) =>
+                        {
+                            continue
+                        }
+                        Err(err) => {
+                            warn!("Name responder receive failed: {}", err);
+                            thread::sleep(Duration::from_millis(200));
+                            continue;
+                        }
+                    };
+
+                    let len = handler(&names.lock().unwrap(), &request[..len], &mut response);
+
+                    if let Some(len) = len {
+                        debug!("Answering name query from {}", remote);
+
+                        if let Err(err) = socket.send_to(&response[..len], remote) {
+                            warn!("Name responder send to {} failed: {}", remote, err);
+                        }
+                    }
+                }
+            })?;
+
+        self.threads.push(thread);
+
+        Ok(())
+    }
+}
+
+impl Drop for EspNameResponder {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::SeqCst);
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+
+        info!("Name responder stopped");
+    }
+}
+
+fn handle_llmnr(names: &Names, request: &[u8], response: &mut [u8]) -> Option<usize> {
+    // The message format of LLMNR is the one of DNS
+    let len = names.table.handle(request, response)?;
+
+    // LLMNR responders stay silent for names they are not authoritative for
+    if response[3] & 0x0f != 0 {
+        return None;
+    }
+
+    // The bits used by DNS for AA and RD are the LLMNR C and T flags, which must be cleared
+    response[2] &= !0x07;
+
+    Some(len)
+}
+
+fn handle_netbios(names: &Names, request: &[u8], response: &mut [u8]) -> Option<usize> {
+    // Header, followed by a single question with an encoded name of 32 characters
+    const QUESTION_END: usize = 12 + 34 + 4;
+
+    if request.len() < QUESTION_END
+        || request[2] & 0xf8 != 0
+        || u16::from_be_bytes([request[4], request[5]]) != 1
+        || request[12] != 32
+        || request[45] != 0
+        || u16::from_be_bytes([request[46], request[47]]) != NETBIOS_TYPE_NB
+    {
+        // Not a name query
+        return None;
+    }
+
+    let name = decode_netbios_name(&request[13..45])?;
+
+    if !matches!(
+        name[NETBIOS_NAME_LEN],
+        NETBIOS_SUFFIX_WORKSTATION | NETBIOS_SUFFIX_SERVER
+    ) || !netbios_name_matches(&name[..NETBIOS_NAME_LEN], &names.hostname)
+    {
+        return None;
+    }
+
+    let response = response.get_mut(..12 + 34 + 10 + 6)?;
+
+    // Transaction ID
+    response[0] = request[0];
+    response[1] = request[1];
+    // Response, name query, authoritative answer, recursion desired
+    response[2] = 0x85;
+    response[3] = 0x00;
+    // No questions, one answer
+    response[4..12].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+    // The name, as in the question
+    response[12..46].copy_from_slice(&request[12..46]);
+    response[46..48].copy_from_slice(&NETBIOS_TYPE_NB.to_be_bytes());
+    response[48..50].copy_from_slice(&1_u16.to_be_bytes());
+    response[50..54].copy_from_slice(&(names.ttl.as_secs() as u32).to_be_bytes());
+    response[54..56].copy_from_slice(&6_u16.to_be_bytes());
+    // Unique name, B-node
+    response[56..58].copy_from_slice(&0_u16.to_be_bytes());
+    response[58..62].copy_from_slice(&names.ipv4.octets());
+
+    Some(response.len())
+}
+
+/// Decode a NetBIOS "first level encoded" name, where each byte is encoded as two characters `A` - `P`
+fn decode_netbios_name(encoded: &[u8]) -> Option<[u8; NETBIOS_NAME_LEN + 1]> {
+    let mut name = [0; NETBIOS_NAME_LEN + 1];
+
+    for (index, pair) in encoded.chunks_exact(2).enumerate() {
+        let high = pair[0].checked_sub(b'A').filter(|nibble| *nibble < 16)?;
+        let low = pair[1].checked_sub(b'A').filter(|nibble| *nibble < 16)?;
+
+        name[index] = (high << 4) | low;
+    }
+
+    Some(name)
+}
+
+fn netbios_name_matches(name: &[u8], hostname: &str) -> bool {
+    // NetBIOS names are upper case, padded with spaces and limited to 15 characters
+    let len = name
+        .iter()
+        .rposition(|c| *c != b' ')
+        .map_or(0, |pos| pos + 1);
+    let name = &name[..len];
+    let hostname = &hostname.as_bytes()[..hostname.len().min(NETBIOS_NAME_LEN)];
+
+    !hostname.is_empty() && name.eq_ignore_ascii_case(hostname)
+}
@@ -0,0 +1,468 @@
+//! A minimal DNS server
+//!
+//! Answers A and AAAA queries from a static `DnsTable`. With a wildcard entry
+//! (see `DnsTable::wildcard`), every query is answered with the device's own IP,
+//! which is what captive portals need.
+//!
+//! The protocol handling (`DnsTable::handle`) is decoupled from the networking,
+//! so it can be driven either by `EspDnsServer`, which runs on its own thread,
+//! or by any async UDP socket, e.g.:
+//!
+//! ```ignore
+//! let socket = async_io::Async::<std::net::UdpSocket>::bind(([0, 0, 0, 0], 53))?;
+//! let table = DnsTable::wildcard(Ipv4Addr::new(192, 168, 71, 1));
+//!
+//! let mut request = [0; 512];
+//! let mut response = [0; 512];
+//!
+//! loop {
+//!     let (len, remote) = socket.recv_from(&mut request).await?;
+//!
+//!     if let Some(len) = table.handle(&request[..len], &mut response) {
+//!         socket.send_to(&response[..len], remote).await?;
+//!     }
+//! }
+//! ```
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::ipv4::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NO_ERROR: u8 = 0;
+const RCODE_FORMAT_ERROR: u8 = 1;
+const RCODE_NAME_ERROR: u8 = 3;
+const RCODE_NOT_IMPLEMENTED: u8 = 4;
+
+const HEADER_LEN: usize = 12;
+
+/// A table of host names and their addresses
+///
+/// Names are matched case-insensitively. Besides plain names, an entry can be
+/// `*.<domain>`, which matches all subdomains of `<domain>`, or `*`, which matches all names.
+/// Exact entries take precedence over wildcard ones.
+#[derive(Clone, Debug)]
+pub struct DnsTable {
+    entries: Vec<(String, IpAddr)>,
+    ttl: Duration,
+}
+
+impl DnsTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            ttl: Duration::from_secs(60),
+        }
+    }
+
+    /// Create a table answering all queries with the provided address
+    pub fn wildcard(addr: impl Into<IpAddr>) -> Self {
+        let mut this = Self::new();
+
+        this.add("*", addr);
+
+        this
+    }
+
+    /// Add an address for `name`. A name can have multiple addresses, both IPv4 and IPv6
+    pub fn add(&mut self, name: &str, addr: impl Into<IpAddr>) -> &mut Self {
+        self.entries
+            .push((normalize(name).to_ascii_lowercase(), addr.into()));
+
+        self
+    }
+
+    /// Remove all addresses of `name`
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        let name = normalize(name);
+
+        self.entries
+            .retain(|(entry, _)| !entry.eq_ignore_ascii_case(name));
+
+        self
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The time-to-live reported in the answers, 60 seconds by default
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl = ttl;
+
+        self
+    }
+
+    /// Return `true` if `name` matches any entry of the table
+    pub fn contains(&self, name: &str) -> bool {
+        let name = normalize(name);
+
+        self.entries
+            .iter()
+            .any(|(pattern, _)| matches(pattern, name))
+    }
+
+    /// Return the addresses of `name`
+    pub fn resolve<'a>(&'a self, name: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
+        let name = normalize(name);
+
+        let exact = self
+            .entries
+            .iter()
+            .any(|(pattern, _)| pattern.eq_ignore_ascii_case(name));
+
+        self.entries
+            .iter()
+            .filter(move |(pattern, _)| {
+                if exact {
+                    pattern.eq_ignore_ascii_case(name)
+                } else {
+                    matches(pattern, name)
+                }
+            })
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Handle a DNS request, writing the response into `response`
+    ///
+    /// Returns the length of the response, or `None` if the request is malformed
+    /// beyond being answerable, or if `response` is too small.
+    pub fn handle(&self, request: &[u8], response: &mut [u8]) -> Option<usize> {
+        if request.len() < HEADER_LEN || request[2] & 0x80 != 0 {
+            // Too short, or not a query
+            return None;
+        }
+
+        let opcode = (request[2] >> 3) & 0x0f;
+        let qdcount = u16::from_be_bytes([request[4], request[5]]);
+
+        let question = if opcode == 0 && qdcount == 1 {
+            parse_question(request)
+        } else {
+            None
+        };
+
+        let Some((name, qtype, qclass, question_end)) = question else {
+            let rcode = if opcode != 0 {
+                RCODE_NOT_IMPLEMENTED
+            } else {
+                RCODE_FORMAT_ERROR
+            };
+
+            return write_header(request, response, rcode, 0, 0);
+        };
+
+        let question = &request[HEADER_LEN..question_end];
+
+        if response.len() < HEADER_LEN + question.len() {
+            return None;
+        }
+
+        response[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+
+        let mut offset = HEADER_LEN + question.len();
+        let mut answers = 0_u16;
+
+        let rcode = if !self.contains(&name) {
+            RCODE_NAME_ERROR
+        } else {
+            if qclass == CLASS_IN {
+                for addr in self.resolve(&name) {
+                    offset = match addr {
+                        IpAddr::V4(addr) if qtype == TYPE_A || qtype == TYPE_ANY => {
+                            write_answer(response, offset, TYPE_A, self.ttl, &addr.octets())?
+                        }
+                        IpAddr::V6(addr) if qtype == TYPE_AAAA || qtype == TYPE_ANY => {
+                            write_answer(response, offset, TYPE_AAAA, self.ttl, &addr.octets())?
+                        }
+                        _ => continue,
+                    };
+
+                    answers += 1;
+                }
+            }
+
+            // No answers for an existing name is a valid NODATA response
+            RCODE_NO_ERROR
+        };
+
+        write_header(request, response, rcode, 1, answers)?;
+
+        Some(offset)
+    }
+}
+
+impl Default for DnsTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn normalize(name: &str) -> &str {
+    name.strip_suffix('.').unwrap_or(name)
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    if pattern == "*" {
+        true
+    } else if let Some(domain) = pattern.strip_prefix("*.") {
+        name.len() > domain.len() + 1
+            && name[name.len() - domain.len()..].eq_ignore_ascii_case(domain)
+            && name.as_bytes()[name.len() - domain.len() - 1] == b'.'
+    } else {
+        pattern.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Parse the (single) question of the request, returning the name, type, class
+/// and the offset of the end of the question
+fn parse_question(request: &[u8]) -> Option<(String, u16, u16, usize)> {
+    let mut name = String::new();
+    let mut offset = HEADER_LEN;
+
+    loop {
+        let len = *request.get(offset)? as usize;
+        offset += 1;
+
+        if len == 0 {
+            break;
+        }
+
+        if len & 0xc0 != 0 {
+            // Compression is not expected in the question of a request
+            return None;
+        }
+
+        let label = core::str::from_utf8(request.get(offset..offset + len)?).ok()?;
+
+        if !name.is_empty() {
+            name.push('.');
+        }
+
+        name.push_str(label);
+
+        offset += len;
+    }
+
+    let fields = request.get(offset..offset + 4)?;
+
+    let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+    let qclass = u16::from_be_bytes([fields[2], fields[3]]);
+
+    Some((name, qtype, qclass, offset + 4))
+}
+
+fn write_header(
+    request: &[u8],
+    response: &mut [u8],
+    rcode: u8,
+    qdcount: u16,
+    ancount: u16,
+) -> Option<usize> {
+    let header = response.get_mut(..HEADER_LEN)?;
+
+    // ID
+    header[0] = request[0];
+    header[1] = request[1];
+    // QR = 1, opcode copied, AA = 1, RD copied
+    header[2] = 0x80 | (request[2] & 0x78) | 0x04 | (request[2] & 0x01);
+    // RA = 0
+    header[3] = rcode & 0x0f;
+
+    header[4..6].copy_from_slice(&qdcount.to_be_bytes());
+    header[6..8].copy_from_slice(&ancount.to_be_bytes());
+    header[8..12].fill(0);
+
+    Some(HEADER_LEN)
+}
+
+fn write_answer(
+    response: &mut [u8],
+    offset: usize,
+    rtype: u16,
+    ttl: Duration,
+    rdata: &[u8],
+) -> Option<usize> {
+    let answer = response.get_mut(offset..offset + 12 + rdata.len())?;
+
+    // Pointer to the name in the question
+    answer[0..2].copy_from_slice(&[0xc0, HEADER_LEN as u8]);
+    answer[2..4].copy_from_slice(&rtype.to_be_bytes());
+    answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+    answer[6..10].copy_from_slice(&(ttl.as_secs() as u32).to_be_bytes());
+    answer[10..12].copy_from_slice(&(rdata.len() as u16).to_be_bytes());
+    answer[12..].copy_from_slice(rdata);
+
+    Some(offset + answer.len())
+}
+
+impl From<(&str, Ipv4Addr)> for DnsTable {
+    fn from((name, addr): (&str, Ipv4Addr)) -> Self {
+        let mut this = Self::new();
+
+        this.add(name, addr);
+
+        this
+    }
+}
+
+impl From<(&str, Ipv6Addr)> for DnsTable {
+    fn from((name, addr): (&str, Ipv6Addr)) -> Self {
+        let mut this = Self::new();
+
+        this.add(name, addr);
+
+        this
+    }
+}
+
+#[cfg(feature = "std")]
+pub use esp_server::*;
+
+#[cfg(feature = "std")]
+mod esp_server {
+    use core::time::Duration;
+
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+
+    use ::log::{debug, info, warn};
+
+    use super::DnsTable;
+
+    #[derive(Clone, Debug)]
+    pub struct DnsServerConfiguration {
+        pub bind_addr: SocketAddr,
+        pub task_stack_size: usize,
+    }
+
+    impl Default for DnsServerConfiguration {
+        fn default() -> Self {
+            Self {
+                bind_addr: SocketAddr::from(([0, 0, 0, 0], 53)),
+                task_stack_size: 4096,
+            }
+        }
+    }
+
+    /// A DNS server running on its own thread
+    ///
+    /// The server is stopped once dropped.
+    pub struct EspDnsServer {
+        table: Arc<Mutex<DnsTable>>,
+        quit: Arc<AtomicBool>,
+        local_addr: SocketAddr,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl EspDnsServer {
+        pub fn new(conf: &DnsServerConfiguration, table: DnsTable) -> io::Result<Self> {
+            let socket = UdpSocket::bind(conf.bind_addr)?;
+
+            // So that the thread notices when the server is dropped
+            socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+
+            let local_addr = socket.local_addr()?;
+
+            let table = Arc::new(Mutex::new(table));
+            let quit = Arc::new(AtomicBool::new(false));
+
+            let thread = {
+                let table = table.clone();
+                let quit = quit.clone();
+
+                thread::Builder::new()
+                    .name("dns-server".into())
+                    .stack_size(conf.task_stack_size)
+                    .spawn(move || Self::run(socket, table, quit))?
+            };
+
+            info!("DNS server started on {}", local_addr);
+
+            Ok(Self {
+                table,
+                quit,
+                local_addr,
+                thread: Some(thread),
+            })
+        }
+
+        pub fn local_addr(&self) -> SocketAddr {
+            self.local_addr
+        }
+
+        /// Replace the table the server answers from
+        pub fn set_table(&self, table: DnsTable) {
+            *self.table.lock().unwrap() = table;
+        }
+
+        /// Modify the table the server answers from
+        pub fn update_table<F, R>(&self, f: F) -> R
+        where
+            F: FnOnce(&mut DnsTable) -> R,
+        {
+            f(&mut self.table.lock().unwrap())
+        }
+
+        fn run(socket: UdpSocket, table: Arc<Mutex<DnsTable>>, quit: Arc<AtomicBool>) {
+            let mut request = [0; 512];
+            let mut response = [0; 512];
+
+            while !quit.load(Ordering::SeqCst) {
+                let (len, remote) = match socket.recv_from(&mut request) {
+                    Ok(received) => received,
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(err) => {
+                        warn!("DNS server receive failed: {}", err);
+                        thread::sleep(Duration::from_millis(200));
+                        continue;
+                    }
+                };
+
+                let len = table.lock().unwrap().handle(&request[..len], &mut response);
+
+                if let Some(len) = len {
+                    debug!("Answering DNS query from {}", remote);
+
+                    if let Err(err) = socket.send_to(&response[..len], remote) {
+                        warn!("DNS server send to {} failed: {}", remote, err);
+                    }
+                }
+            }
+        }
+    }
+
+    impl Drop for EspDnsServer {
+        fn drop(&mut self) {
+            self.quit.store(true, Ordering::SeqCst);
+
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+
+            info!("DNS server stopped");
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! eFuses
+//!
+//! Reading the MAC addresses, the chip revision and the security flags burned in the eFuses,
+//! and reading and burning the user blocks, for manufacturing and provisioning flows.
+//!
+//! Burning eFuses is irreversible: a bit burned can never be cleared, and a mistake can
+//! brick the chip. `burn_block` therefore takes an explicit `Irreversible` confirmation, and
+//! refuses to burn a block whose bits already burned conflict with the data. With
+//! `CONFIG_EFUSE_VIRTUAL`, the eFuses are emulated in RAM, to rehearse a provisioning flow.
+//!
+//! ```ignore
+//! use esp_idf_svc::efuse::{self, Block, Irreversible};
+//!
+//! let serial = b"SN-000123";
+//!
+//! efuse::burn_block(Block::USER_DATA, 0, serial, Irreversible::confirm())?;
+//! ```
+
+use core::ptr;
+
+use crate::sys::*;
+
+/// The size of a MAC address, in bytes
+pub const MAC_LEN: usize = 6;
+
+/// Get the base MAC address burned by Espressif, from which the MAC addresses of the
+/// interfaces are derived
+pub fn base_mac() -> Result<[u8; MAC_LEN], EspError> {
+    let mut mac = [0; MAC_LEN];
+
+    esp!(unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) })?;
+
+    Ok(mac)
+}
+
+/// Get the custom MAC address burned in the user eFuses, if any
+pub fn custom_mac() -> Result<Option<[u8; MAC_LEN]>, EspError> {
+    let mut mac = [0; MAC_LEN];
+
+    match esp!(unsafe { esp_efuse_mac_get_custom(mac.as_mut_ptr()) }) {
+        Ok(()) => Ok(Some(mac)),
+        Err(err) if err.code() == ESP_ERR_INVALID_VERSION || err.code() == ESP_ERR_INVALID_CRC => {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The revision of the chip, as `major * 100 + minor`: e.g. 301 for v3.1
+pub fn chip_revision() -> u16 {
+    let mut info: esp_chip_info_t = Default::default();
+
+    unsafe {
+        esp_chip_info(&mut info);
+    }
+
+    info.revision
+}
+
+/// The package version of the chip
+pub fn package_version() -> u32 {
+    unsafe { esp_efuse_get_pkg_ver() }
+}
+
+/// The security features burned in the eFuses
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SecurityFlags {
+    pub secure_boot: bool,
+    pub flash_encryption: bool,
+    /// Whether the JTAG interface is disabled
+    pub jtag_disabled: bool,
+    /// Whether the UART download mode of the ROM is disabled
+    pub download_mode_disabled: bool,
+}
+
+/// Get the security features burned in the eFuses
+pub fn security_flags() -> SecurityFlags {
+    #[cfg(esp32)]
+    let (jtag, download) = unsafe {
+        (
+            ptr::addr_of!(ESP_EFUSE_DISABLE_JTAG),
+            ptr::addr_of!(ESP_EFUSE_UART_DOWNLOAD_DIS),
+        )
+    };
+
+    #[cfg(not(esp32))]
+    let (jtag, download) = unsafe {
+        (
+            ptr::addr_of!(ESP_EFUSE_DIS_PAD_JTAG),
+            ptr::addr_of!(ESP_EFUSE_DIS_DOWNLOAD_MODE),
+        )
+    };
+
+    SecurityFlags {
+        secure_boot: unsafe { esp_secure_boot_enabled() },
+        flash_encryption: unsafe { esp_flash_encryption_enabled() },
+        jtag_disabled: unsafe { esp_efuse_read_field_bit(jtag as *mut _) },
+        download_mode_disabled: unsafe { esp_efuse_read_field_bit(download as *mut _) },
+    }
+}
+
+/// An eFuse block
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Block(esp_efuse_block_t);
+
+impl Block {
+    /// The block free for the user data, e.g. a serial number or a custom MAC address
+    pub const USER_DATA: Self = Self(esp_efuse_block_t_EFUSE_BLK3);
+
+    /// One of the key blocks, 0 to 5; the blocks which are not used by secure boot or flash
+    /// encryption can hold user data
+    #[cfg(not(esp32))]
+    pub const fn key(index: u8) -> Option<Self> {
+        if index < 6 {
+            Some(Self(
+                esp_efuse_block_t_EFUSE_BLK_KEY0 + index as esp_efuse_block_t,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// The size of the block, in bytes
+    pub fn size(&self) -> usize {
+        // With the 3/4 coding scheme of the ESP32, a quarter of the bits are used by the coding
+        #[cfg(esp32)]
+        if unsafe { esp_efuse_get_coding_scheme(self.0) }
+            == esp_efuse_coding_scheme_t_EFUSE_CODING_SCHEME_3_4
+        {
+            return 24;
+        }
+
+        32
+    }
+
+    /// Whether the block is write-protected, so that it can not be burned anymore
+    pub fn is_write_protected(&self) -> bool {
+        // Only the ESP32 has the user data block among its key blocks
+        #[cfg(not(esp32))]
+        if *self == Self::USER_DATA {
+            let field = unsafe { ptr::addr_of!(ESP_EFUSE_WR_DIS_BLK_USR_DATA) };
+
+            return unsafe { esp_efuse_read_field_bit(field as *mut _) };
+        }
+
+        unsafe { esp_efuse_get_key_dis_write(self.0) }
+    }
+}
+
+/// Read `buf.len()` bytes of the block at byte `offset`
+pub fn read_block(block: Block, offset: usize, buf: &mut [u8]) -> Result<(), EspError> {
+    if offset + buf.len() > block.size() {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+    }
+
+    esp!(unsafe {
+        esp_efuse_read_block(
+            block.0,
+            buf.as_mut_ptr() as *mut _,
+            offset * 8,
+            buf.len() * 8,
+        )
+    })
+}
+
+/// The confirmation that burning eFuses is irreversible
+#[derive(Debug)]
+pub struct Irreversible(());
+
+impl Irreversible {
+    /// Confirm that the eFuse bits burned can never be cleared
+    pub const fn confirm() -> Self {
+        Self(())
+    }
+}
+
+/// Burn `data` in the block at byte `offset`
+///
+/// Fails with `ESP_ERR_INVALID_STATE` when the block is write-protected, or when bits
+/// already burned are clear in the data - so that the block always ends up holding exactly
+/// the data rather than the OR of both. Burning the same data twice is thus a no-op, and
+/// data which only sets more bits - e.g. filling bytes still blank - is burned.
+pub fn burn_block(
+    block: Block,
+    offset: usize,
+    data: &[u8],
+    _confirmation: Irreversible,
+) -> Result<(), EspError> {
+    if block.is_write_protected() {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+    }
+
+    if offset + data.len() > block.size() {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+    }
+
+    let mut current = [0; 32];
+    let current = &mut current[..data.len()];
+
+    read_block(block, offset, current)?;
+
+    if current == data {
+        return Ok(());
+    }
+
+    if current
+        .iter()
+        .zip(data)
+        .any(|(current, new)| current & !new != 0)
+    {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+    }
+
+    esp!(unsafe {
+        esp_efuse_write_block(
+            block.0,
+            data.as_ptr() as *const _,
+            offset * 8,
+            data.len() * 8,
+        )
+    })
+}
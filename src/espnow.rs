@@ -11,23 +11,36 @@ use core::marker::PhantomData;
 use ::log::info;
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use crate::sys::*;
+use crate::wifi::WifiDeviceId;
 
 use crate::private::mutex::Mutex;
 
+#[cfg(all(
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_esp_timer_enabled,
+    esp_idf_comp_mbedtls_enabled
+))]
+pub mod pairing;
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+pub mod relay;
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+pub mod reliable;
+
 type Singleton<T> = Mutex<Option<Box<T>>>;
 
 pub const BROADCAST: [u8; 6] = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
 
 #[allow(clippy::type_complexity)]
-static RECV_CALLBACK: Singleton<dyn FnMut(&[u8], &[u8]) + Send + 'static> = Mutex::new(None);
+static RECV_CALLBACK: Singleton<dyn FnMut(&ReceiveInfo, &[u8]) + Send + 'static> = Mutex::new(None);
 #[allow(clippy::type_complexity)]
 static SEND_CALLBACK: Singleton<dyn FnMut(&[u8], SendStatus) + Send + 'static> = Mutex::new(None);
 
 static TAKEN: Mutex<bool> = Mutex::new(false);
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum SendStatus {
     SUCCESS = 0,
     FAIL,
@@ -45,6 +58,123 @@ impl From<u32> for SendStatus {
 
 pub type PeerInfo = esp_now_peer_info_t;
 
+/// The metadata of a received ESP-NOW frame
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReceiveInfo {
+    pub src_addr: [u8; 6],
+    /// The destination address of the frame. Not available on ESP-IDF 4
+    pub dst_addr: Option<[u8; 6]>,
+    /// The RSSI of the frame, in dBm. Not available on ESP-IDF 4
+    pub rssi: Option<i8>,
+}
+
+/// The maximum payload length of an ESP-NOW v1 frame
+pub const MAX_DATA_LEN: usize = ESP_NOW_MAX_DATA_LEN as _;
+
+/// The maximum payload length of an ESP-NOW v2 frame
+#[cfg(not(any(
+    esp_idf_version_major = "4",
+    all(
+        esp_idf_version_major = "5",
+        any(
+            esp_idf_version_minor = "0",
+            esp_idf_version_minor = "1",
+            esp_idf_version_minor = "2",
+            esp_idf_version_minor = "3"
+        )
+    ),
+)))]
+pub const MAX_DATA_LEN_V2: usize = ESP_NOW_MAX_DATA_LEN_V2 as _;
+
+/// The transmission rate used for a peer
+#[cfg(not(any(
+    esp_idf_version_major = "4",
+    all(
+        esp_idf_version_major = "5",
+        any(
+            esp_idf_version_minor = "0",
+            esp_idf_version_minor = "1",
+            esp_idf_version_minor = "2",
+            esp_idf_version_minor = "3"
+        )
+    ),
+)))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PeerRateConfig {
+    /// The PHY mode, one of the `wifi_phy_mode_t_WIFI_PHY_MODE_*` constants
+    pub phy_mode: wifi_phy_mode_t,
+    /// The PHY rate, one of the `wifi_phy_rate_t_WIFI_PHY_RATE_*` constants.
+    /// Lower rates (e.g. `WIFI_PHY_RATE_LORA_250K`) increase the range
+    pub rate: wifi_phy_rate_t,
+    /// Use the extended range SU (single user) format, 802.11ax only
+    pub ersu: bool,
+    /// Use dual carrier modulation, 802.11ax only
+    pub dcm: bool,
+}
+
+/// The length of the primary master key (PMK) and the local master keys (LMK)
+pub const KEY_LEN: usize = ESP_NOW_KEY_LEN as _;
+
+/// An ESP-NOW peer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Peer {
+    pub mac: [u8; 6],
+    /// The WiFi channel used to communicate with the peer, or 0 for the current channel
+    pub channel: u8,
+    pub interface: WifiDeviceId,
+    /// The local master key used to encrypt the frames exchanged with the peer
+    pub lmk: [u8; KEY_LEN],
+    /// Whether the frames exchanged with the peer are encrypted with `lmk`
+    pub encrypt: bool,
+}
+
+impl Peer {
+    /// An unencrypted peer on the current channel
+    pub const fn new(mac: [u8; 6]) -> Self {
+        Self {
+            mac,
+            channel: 0,
+            interface: WifiDeviceId::Sta,
+            lmk: [0; KEY_LEN],
+            encrypt: false,
+        }
+    }
+
+    /// An encrypted peer on the current channel
+    pub const fn encrypted(mac: [u8; 6], lmk: [u8; KEY_LEN]) -> Self {
+        Self {
+            lmk,
+            encrypt: true,
+            ..Self::new(mac)
+        }
+    }
+}
+
+impl From<&Peer> for PeerInfo {
+    fn from(peer: &Peer) -> Self {
+        Self {
+            peer_addr: peer.mac,
+            lmk: peer.lmk,
+            channel: peer.channel,
+            ifidx: peer.interface.into(),
+            encrypt: peer.encrypt,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&PeerInfo> for Peer {
+    fn from(info: &PeerInfo) -> Self {
+        Self {
+            mac: info.peer_addr,
+            channel: info.channel,
+            interface: info.ifidx.into(),
+            lmk: info.lmk,
+            encrypt: info.encrypt,
+        }
+    }
+}
+
 pub struct EspNow<'a>(PhantomData<&'a ()>);
 
 impl EspNow<'static> {
@@ -154,26 +284,149 @@ impl<'a> EspNow<'a> {
         Ok(peer_info)
     }
 
+    /// Add the peer, or modify it if it already exists
+    pub fn set_peer(&self, peer: &Peer) -> Result<(), EspError> {
+        let peer_info: PeerInfo = peer.into();
+
+        if self.peer_exists(peer.mac)? {
+            self.mod_peer(peer_info)
+        } else {
+            self.add_peer(peer_info)
+        }
+    }
+
+    /// Return the peer with the provided MAC address, or `None` if there is no such peer
+    pub fn peer(&self, peer_addr: [u8; 6]) -> Result<Option<Peer>, EspError> {
+        match self.get_peer(peer_addr) {
+            Ok(peer_info) => Ok(Some((&peer_info).into())),
+            Err(err) if err.code() == ESP_ERR_ESPNOW_NOT_FOUND => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Remove the peer with the provided MAC address
+    ///
+    /// Returns `false` if there was no such peer.
+    pub fn remove_peer(&self, peer_addr: [u8; 6]) -> Result<bool, EspError> {
+        match self.del_peer(peer_addr) {
+            Ok(()) => Ok(true),
+            Err(err) if err.code() == ESP_ERR_ESPNOW_NOT_FOUND => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Return all peers
+    pub fn peers(&self) -> Result<Vec<Peer>, EspError> {
+        let mut peers = Vec::new();
+        let mut from_head = true;
+
+        loop {
+            match self.fetch_peer(from_head) {
+                Ok(peer_info) => peers.push((&peer_info).into()),
+                Err(err) if err.code() == ESP_ERR_ESPNOW_NOT_FOUND => break,
+                Err(err) => return Err(err),
+            }
+
+            from_head = false;
+        }
+
+        Ok(peers)
+    }
+
     pub fn set_pmk(&self, pmk: &[u8]) -> Result<(), EspError> {
         esp!(unsafe { esp_now_set_pmk(pmk.as_ptr()) })?;
 
         Ok(())
     }
 
+    /// The maximum payload length of a frame, depending on the ESP-NOW version in use
+    pub fn max_data_len(&self) -> Result<usize, EspError> {
+        #[cfg(not(any(
+            esp_idf_version_major = "4",
+            all(
+                esp_idf_version_major = "5",
+                any(
+                    esp_idf_version_minor = "0",
+                    esp_idf_version_minor = "1",
+                    esp_idf_version_minor = "2",
+                    esp_idf_version_minor = "3"
+                )
+            ),
+        )))]
+        if self.get_version()? >= 2 {
+            return Ok(MAX_DATA_LEN_V2);
+        }
+
+        Ok(MAX_DATA_LEN)
+    }
+
+    /// Set the transmission rate used for the peer with the provided MAC address
+    #[cfg(not(any(
+        esp_idf_version_major = "4",
+        all(
+            esp_idf_version_major = "5",
+            any(
+                esp_idf_version_minor = "0",
+                esp_idf_version_minor = "1",
+                esp_idf_version_minor = "2",
+                esp_idf_version_minor = "3"
+            )
+        ),
+    )))]
+    pub fn set_peer_rate_config(
+        &self,
+        peer_addr: [u8; 6],
+        config: &PeerRateConfig,
+    ) -> Result<(), EspError> {
+        let mut rate_config = esp_now_rate_config_t {
+            phymode: config.phy_mode,
+            rate: config.rate,
+            ersu: config.ersu,
+            dcm: config.dcm,
+        };
+
+        esp!(unsafe { esp_now_set_peer_rate_config(peer_addr.as_ptr(), &mut rate_config) })
+    }
+
+    /// Set how long the chip stays awake in each wake interval, in microseconds,
+    /// when the station is not connected and power saving is enabled
+    ///
+    /// Together with `set_wake_interval`, this allows trading the power consumption
+    /// of an ESP-NOW receiver against its reachability.
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn set_wake_window(&self, window: u16) -> Result<(), EspError> {
+        esp!(unsafe { esp_now_set_wake_window(window) })
+    }
+
+    /// Set the wake interval of the connectionless modules (i.e. ESP-NOW), in milliseconds
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn set_wake_interval(&self, interval: u16) -> Result<(), EspError> {
+        esp!(unsafe { esp_wifi_connectionless_module_set_wake_interval(interval) })
+    }
+
     pub fn get_version(&self) -> Result<u32, EspError> {
         let mut version: u32 = 0;
         esp!(unsafe { esp_now_get_version(&mut version as *mut u32) })?;
         Ok(version)
     }
 
-    pub fn register_recv_cb<F>(&self, callback: F) -> Result<(), EspError>
+    pub fn register_recv_cb<F>(&self, mut callback: F) -> Result<(), EspError>
     where
         F: FnMut(&[u8], &[u8]) + Send + 'a,
+    {
+        self.register_recv_info_cb(move |info, data| callback(&info.src_addr, data))
+    }
+
+    /// Same as `register_recv_cb`, but the callback also receives the destination address
+    /// and the RSSI of each frame
+    pub fn register_recv_info_cb<F>(&self, callback: F) -> Result<(), EspError>
+    where
+        F: FnMut(&ReceiveInfo, &[u8]) + Send + 'a,
     {
         #[allow(clippy::type_complexity)]
-        let callback: Box<dyn FnMut(&[u8], &[u8]) + Send + 'a> = Box::new(callback);
+        let callback: Box<dyn FnMut(&ReceiveInfo, &[u8]) + Send + 'a> = Box::new(callback);
         #[allow(clippy::type_complexity)]
-        let callback: Box<dyn FnMut(&[u8], &[u8]) + Send + 'static> =
+        let callback: Box<dyn FnMut(&ReceiveInfo, &[u8]) + Send + 'static> =
             unsafe { core::mem::transmute(callback) };
 
         *RECV_CALLBACK.lock() = Some(Box::new(callback));
@@ -228,13 +481,28 @@ impl<'a> EspNow<'a> {
         data: *const u8,
         data_len: core::ffi::c_int,
     ) {
-        #[cfg(not(any(esp_idf_version_major = "4")))]
-        let mac_addr = unsafe { *esp_now_info }.src_addr.cast_const();
-        let c_mac = unsafe { core::slice::from_raw_parts(mac_addr, 6usize) };
+        #[cfg(esp_idf_version_major = "4")]
+        let info = ReceiveInfo {
+            src_addr: unsafe { *(mac_addr as *const [u8; 6]) },
+            dst_addr: None,
+            rssi: None,
+        };
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let info = {
+            let esp_now_info = unsafe { &*esp_now_info };
+
+            ReceiveInfo {
+                src_addr: unsafe { *(esp_now_info.src_addr as *const [u8; 6]) },
+                dst_addr: unsafe { (esp_now_info.des_addr as *const [u8; 6]).as_ref() }.copied(),
+                rssi: unsafe { esp_now_info.rx_ctrl.as_ref() }.map(|rx_ctrl| rx_ctrl.rssi() as i8),
+            }
+        };
+
         let c_data = unsafe { core::slice::from_raw_parts(data, data_len as usize) };
 
         if let Some(ref mut callback) = *RECV_CALLBACK.lock() {
-            callback(c_mac, c_data)
+            callback(&info, c_data)
         } else {
             panic!("EspNow callback not available");
         }
@@ -256,3 +524,205 @@ impl<'a> Drop for EspNow<'a> {
         *taken = false;
     }
 }
+
+pub use asynch::*;
+
+mod asynch {
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use crate::hal::task::asynch::Notification;
+    use crate::private::mutex::Mutex;
+    use crate::sys::*;
+
+    use super::{EspNow, ReceiveInfo, SendStatus};
+
+    /// A received ESP-NOW frame
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub struct ReceivedFrame {
+        pub info: ReceiveInfo,
+        pub data: Vec<u8>,
+    }
+
+    struct State {
+        rx: VecDeque<ReceivedFrame>,
+        rx_capacity: usize,
+        rx_dropped: usize,
+        rx_busy: bool,
+        tx_busy: bool,
+        tx_sent: u32,
+        tx_completed: u32,
+        tx_status: SendStatus,
+    }
+
+    struct Shared {
+        state: Mutex<State>,
+        rx_notification: Notification,
+        tx_notification: Notification,
+    }
+
+    /// An async wrapper around `EspNow`
+    ///
+    /// `send` resolves with the delivery status reported by the ESP-NOW send callback, and
+    /// `recv` returns the received frames, which are buffered in a queue of a fixed length.
+    ///
+    /// At most one `send` and one `recv` can be in progress at any time; a concurrent
+    /// call of the same method fails with `ESP_ERR_INVALID_STATE`.
+    pub struct EspNowAsync<'a> {
+        espnow: EspNow<'a>,
+        shared: Arc<Shared>,
+    }
+
+    impl<'a> EspNowAsync<'a> {
+        /// Wrap the provided `EspNow` instance, taking over its send and receive callbacks
+        ///
+        /// Once the queue holds `rx_queue_len` frames, newly received frames are dropped until `recv` is called.
+        pub fn new(espnow: EspNow<'a>, rx_queue_len: usize) -> Result<Self, EspError> {
+            let shared = Arc::new(Shared {
+                state: Mutex::new(State {
+                    rx: VecDeque::new(),
+                    rx_capacity: rx_queue_len.max(1),
+                    rx_dropped: 0,
+                    rx_busy: false,
+                    tx_busy: false,
+                    tx_sent: 0,
+                    tx_completed: 0,
+                    tx_status: SendStatus::FAIL,
+                }),
+                rx_notification: Notification::new(),
+                tx_notification: Notification::new(),
+            });
+
+            {
+                let shared = shared.clone();
+
+                espnow.register_recv_info_cb(move |info, data| {
+                    let mut state = shared.state.lock();
+
+                    if state.rx.len() < state.rx_capacity {
+                        state.rx.push_back(ReceivedFrame {
+                            info: info.clone(),
+                            data: data.to_vec(),
+                        });
+
+                        shared.rx_notification.notify_lsb();
+                    } else {
+                        state.rx_dropped += 1;
+                    }
+                })?;
+            }
+
+            {
+                let shared = shared.clone();
+
+                espnow.register_send_cb(move |_, status| {
+                    let mut state = shared.state.lock();
+
+                    state.tx_completed = state.tx_completed.wrapping_add(1);
+                    state.tx_status = status;
+
+                    shared.tx_notification.notify_lsb();
+                })?;
+            }
+
+            Ok(Self { espnow, shared })
+        }
+
+        /// The wrapped `EspNow` instance, e.g. for managing the peers
+        ///
+        /// Note that (un)registering callbacks on it breaks `send` and `recv`.
+        pub fn espnow(&self) -> &EspNow<'a> {
+            &self.espnow
+        }
+
+        /// Send `data` to the peer and wait for its delivery status
+        pub async fn send(&self, peer_addr: [u8; 6], data: &[u8]) -> Result<SendStatus, EspError> {
+            let seq = {
+                let mut state = self.shared.state.lock();
+
+                if state.tx_busy {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+                }
+
+                state.tx_busy = true;
+
+                state.tx_sent
+            };
+
+            let _guard = BusyGuard(&self.shared, |state| state.tx_busy = false);
+
+            self.espnow.send(peer_addr, data)?;
+
+            self.shared.state.lock().tx_sent = seq.wrapping_add(1);
+
+            loop {
+                {
+                    let state = self.shared.state.lock();
+
+                    // Completions of previously cancelled sends are counted as well, but as sends
+                    // are not overlapping, ours is always the last one
+                    if state.tx_completed.wrapping_sub(seq) as i32 > 0 {
+                        return Ok(state.tx_status);
+                    }
+                }
+
+                self.shared.tx_notification.wait().await;
+            }
+        }
+
+        /// Wait for the next received frame
+        pub async fn recv(&self) -> Result<ReceivedFrame, EspError> {
+            {
+                let mut state = self.shared.state.lock();
+
+                if state.rx_busy {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+                }
+
+                state.rx_busy = true;
+            }
+
+            let _guard = BusyGuard(&self.shared, |state| state.rx_busy = false);
+
+            loop {
+                if let Some(frame) = self.try_recv() {
+                    return Ok(frame);
+                }
+
+                self.shared.rx_notification.wait().await;
+            }
+        }
+
+        /// Return the next received frame, if one is already queued
+        pub fn try_recv(&self) -> Option<ReceivedFrame> {
+            self.shared.state.lock().rx.pop_front()
+        }
+
+        /// Return the number of frames dropped so far because the receive queue was full
+        pub fn dropped(&self) -> usize {
+            self.shared.state.lock().rx_dropped
+        }
+    }
+
+    impl<'a> Drop for EspNowAsync<'a> {
+        fn drop(&mut self) {
+            let _ = self.espnow.unregister_recv_cb();
+            let _ = self.espnow.unregister_send_cb();
+        }
+    }
+
+    // Clears the "busy" flag of a `send` or `recv` call, even if its future is dropped early
+    struct BusyGuard<'s, F>(&'s Shared, F)
+    where
+        F: Fn(&mut State);
+
+    impl<'s, F> Drop for BusyGuard<'s, F>
+    where
+        F: Fn(&mut State),
+    {
+        fn drop(&mut self) {
+            (self.1)(&mut self.0.state.lock());
+        }
+    }
+}
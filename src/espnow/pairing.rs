@@ -0,0 +1,659 @@
+//! ESP-NOW pairing
+//!
+//! An opt-in pairing handshake for products consisting of a hub and one or more
+//! remotes (e.g. a light and its switches):
+//! 1. The remote broadcasts a probe with a random nonce, its name and its capabilities
+//!    (an application-defined bitmask)
+//! 2. The hub answers with its own nonce, name and capabilities, authenticated with
+//!    an HMAC over a pairing secret shared by all devices of the product
+//! 3. The remote verifies the answer and confirms, with an HMAC as well
+//!
+//! Both sides then derive the local master key (LMK) of the link from the secret and the
+//! two nonces, so the key itself is never transmitted. The paired peer is registered with
+//! ESP-NOW as an encrypted peer and persisted in NVS, from where `EspNowPairing::restore`
+//! re-registers it after a reboot.
+//!
+//! Note that all ESP-NOW frames received while a handshake is in progress are consumed by it.
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use embassy_futures::select::{select, Either};
+
+use ::log::{info, warn};
+
+use crate::nvs::{EspNvs, NvsPartitionId};
+use crate::sys::*;
+use crate::timer::EspAsyncTimer;
+
+use super::{EspNowAsync, Peer, PeerInfo, ReceivedFrame, SendStatus, BROADCAST, KEY_LEN};
+
+const MAGIC: [u8; 4] = *b"ENP1";
+
+const MSG_PROBE: u8 = 1;
+const MSG_RESPONSE: u8 = 2;
+const MSG_CONFIRM: u8 = 3;
+
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+/// The maximum length of the name of a device
+pub const MAX_NAME_LEN: usize = 32;
+
+/// The maximum number of peers which can be persisted
+pub const MAX_PAIRED_PEERS: usize = 20;
+
+const NVS_PEERS_KEY: &str = "peers";
+
+#[derive(Clone, Debug)]
+pub struct PairingConfiguration<'a> {
+    /// The secret shared by all devices which are allowed to pair with each other
+    pub secret: &'a [u8],
+    /// The name of this device, sent to the other side. At most `MAX_NAME_LEN` bytes
+    pub name: &'a str,
+    /// Application-defined capabilities of this device, sent to the other side
+    pub capabilities: u32,
+    /// The WiFi channel used for pairing and for the paired peers, or 0 for the current channel
+    pub channel: u8,
+    /// How long `pair` and `accept` wait for the other side
+    pub timeout: Duration,
+    /// How often `pair` repeats its probe
+    pub probe_interval: Duration,
+}
+
+impl<'a> Default for PairingConfiguration<'a> {
+    fn default() -> Self {
+        Self {
+            secret: &[],
+            name: "",
+            capabilities: 0,
+            channel: 0,
+            timeout: Duration::from_secs(30),
+            probe_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A paired peer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PairedPeer {
+    pub mac: [u8; 6],
+    pub channel: u8,
+    pub lmk: [u8; KEY_LEN],
+    pub capabilities: u32,
+    pub name: String,
+}
+
+impl PairedPeer {
+    /// The encrypted ESP-NOW peer for this paired peer
+    pub fn peer(&self) -> Peer {
+        Peer {
+            channel: self.channel,
+            ..Peer::encrypted(self.mac, self.lmk)
+        }
+    }
+
+    fn nvs_key(mac: &[u8; 6]) -> String {
+        let mut key = String::from("p");
+
+        for byte in mac {
+            key.push_str(&alloc::format!("{:02x}", byte));
+        }
+
+        key
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + KEY_LEN + 4 + 1 + self.name.len());
+
+        buf.push(self.channel);
+        buf.extend_from_slice(&self.lmk);
+        buf.extend_from_slice(&self.capabilities.to_le_bytes());
+        buf.push(self.name.len() as _);
+        buf.extend_from_slice(self.name.as_bytes());
+
+        buf
+    }
+
+    fn decode(mac: [u8; 6], buf: &[u8]) -> Option<Self> {
+        let channel = *buf.first()?;
+        let lmk = buf.get(1..1 + KEY_LEN)?.try_into().ok()?;
+        let capabilities = u32::from_le_bytes(buf.get(1 + KEY_LEN..5 + KEY_LEN)?.try_into().ok()?);
+        let name_len = *buf.get(5 + KEY_LEN)? as usize;
+        let name = core::str::from_utf8(buf.get(6 + KEY_LEN..6 + KEY_LEN + name_len)?).ok()?;
+
+        Some(Self {
+            mac,
+            channel,
+            lmk,
+            capabilities,
+            name: name.into(),
+        })
+    }
+}
+
+// A remote which answered a hub, and is registered unencrypted until it confirms the pairing
+struct PendingPairing {
+    mac: [u8; 6],
+    previous: Option<PeerInfo>,
+    nonce_r: [u8; NONCE_LEN],
+    nonce_h: [u8; NONCE_LEN],
+    capabilities: u32,
+    name: String,
+}
+
+/// The pairing handshake, with persistence of the paired peers in an NVS namespace
+pub struct EspNowPairing<'d, 'a, T>
+where
+    T: NvsPartitionId,
+{
+    espnow: &'d EspNowAsync<'a>,
+    nvs: EspNvs<T>,
+    timer: EspAsyncTimer,
+    secret: Vec<u8>,
+    name: String,
+    capabilities: u32,
+    channel: u8,
+    timeout: Duration,
+    probe_interval: Duration,
+}
+
+impl<'d, 'a, T> EspNowPairing<'d, 'a, T>
+where
+    T: NvsPartitionId,
+{
+    /// Create the pairing helper
+    ///
+    /// `nvs` should be opened read-write, on a namespace dedicated to the paired peers.
+    pub fn new(
+        espnow: &'d EspNowAsync<'a>,
+        nvs: EspNvs<T>,
+        timer: EspAsyncTimer,
+        conf: &PairingConfiguration,
+    ) -> Result<Self, EspError> {
+        if conf.secret.is_empty() || conf.name.len() > MAX_NAME_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        espnow.espnow().set_peer(&Peer {
+            channel: conf.channel,
+            ..Peer::new(BROADCAST)
+        })?;
+
+        Ok(Self {
+            espnow,
+            nvs,
+            timer,
+            secret: conf.secret.into(),
+            name: conf.name.into(),
+            capabilities: conf.capabilities,
+            channel: conf.channel,
+            timeout: conf.timeout,
+            probe_interval: conf.probe_interval,
+        })
+    }
+
+    /// Pair with a hub, acting as a remote
+    ///
+    /// Fails with `ESP_ERR_TIMEOUT` if no hub answered within the configured timeout.
+    pub async fn pair(&mut self) -> Result<PairedPeer, EspError> {
+        let nonce_r = random_nonce();
+
+        let mut probe = Vec::new();
+        message(&mut probe, MSG_PROBE);
+        probe.extend_from_slice(&nonce_r);
+        probe.extend_from_slice(&self.capabilities.to_le_bytes());
+        push_name(&mut probe, &self.name);
+
+        let attempts = (self.timeout.as_millis() / self.probe_interval.as_millis().max(1)).max(1);
+
+        for _ in 0..attempts {
+            self.espnow.send(BROADCAST, &probe).await?;
+
+            loop {
+                let frame =
+                    match select(self.espnow.recv(), self.timer.after(self.probe_interval)).await {
+                        Either::First(frame) => frame?,
+                        Either::Second(_) => break,
+                    };
+
+                let Some((nonce_h, capabilities, name)) = self.parse_response(&frame, &nonce_r)
+                else {
+                    continue;
+                };
+
+                let mac = frame.info.src_addr;
+
+                let mut confirm = Vec::new();
+                message(&mut confirm, MSG_CONFIRM);
+                confirm.extend_from_slice(&nonce_r);
+                confirm.extend_from_slice(&nonce_h);
+                confirm.extend_from_slice(&self.tag(&confirm[MAGIC.len()..])?);
+
+                let previous = self.add_handshake_peer(mac)?;
+
+                let paired = match self.espnow.send(mac, &confirm).await {
+                    Ok(SendStatus::SUCCESS) => {
+                        self.paired(mac, &nonce_r, &nonce_h, capabilities, &name)
+                    }
+                    Ok(_) => {
+                        warn!("Confirming the pairing with {:02x?} failed", mac);
+                        self.remove_handshake_peer(mac, previous);
+                        continue;
+                    }
+                    Err(err) => Err(err),
+                };
+
+                if paired.is_err() {
+                    self.remove_handshake_peer(mac, previous);
+                }
+
+                return paired;
+            }
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    /// Accept the pairing request of a remote, acting as a hub
+    ///
+    /// Fails with `ESP_ERR_TIMEOUT` if no remote completed the handshake within the configured timeout.
+    pub async fn accept(&mut self) -> Result<PairedPeer, EspError> {
+        let mut pending = None;
+
+        let accepted = self.accept_pending(&mut pending).await;
+
+        // The remote answered last did not confirm the pairing
+        if let Some(pending) = pending {
+            self.remove_handshake_peer(pending.mac, pending.previous);
+        }
+
+        accepted
+    }
+
+    async fn accept_pending(
+        &mut self,
+        pending: &mut Option<PendingPairing>,
+    ) -> Result<PairedPeer, EspError> {
+        let attempts = (self.timeout.as_millis() / self.probe_interval.as_millis().max(1)).max(1);
+
+        for _ in 0..attempts {
+            loop {
+                let frame =
+                    match select(self.espnow.recv(), self.timer.after(self.probe_interval)).await {
+                        Either::First(frame) => frame?,
+                        Either::Second(_) => break,
+                    };
+
+                let mac = frame.info.src_addr;
+
+                match frame.data.get(MAGIC.len()) {
+                    Some(&MSG_PROBE) if frame.data.starts_with(&MAGIC) => {
+                        let Some((nonce_r, capabilities, name)) = parse_probe(&frame.data) else {
+                            continue;
+                        };
+
+                        let nonce_h = random_nonce();
+
+                        let mut response = Vec::new();
+                        message(&mut response, MSG_RESPONSE);
+                        response.extend_from_slice(&nonce_r);
+                        response.extend_from_slice(&nonce_h);
+                        response.extend_from_slice(&self.capabilities.to_le_bytes());
+                        push_name(&mut response, &self.name);
+
+                        let tag = self.tag(&response[MAGIC.len()..])?;
+                        response.extend_from_slice(&tag);
+
+                        // The probe is not authenticated: only the remote probing last is
+                        // registered as a peer, until it confirms the pairing
+                        let previous = match pending.take() {
+                            Some(pending) if pending.mac == mac => pending.previous,
+                            Some(pending) => {
+                                self.remove_handshake_peer(pending.mac, pending.previous);
+                                self.add_handshake_peer(mac)?
+                            }
+                            None => self.add_handshake_peer(mac)?,
+                        };
+
+                        *pending = Some(PendingPairing {
+                            mac,
+                            previous,
+                            nonce_r,
+                            nonce_h,
+                            capabilities,
+                            name: name.into(),
+                        });
+
+                        if self.espnow.send(mac, &response).await? != SendStatus::SUCCESS {
+                            if let Some(pending) = pending.take() {
+                                self.remove_handshake_peer(pending.mac, pending.previous);
+                            }
+                        }
+                    }
+                    Some(&MSG_CONFIRM) if frame.data.starts_with(&MAGIC) => {
+                        let Some(confirmed) = pending.as_ref() else {
+                            continue;
+                        };
+
+                        if confirmed.mac != mac
+                            || !self.verify_confirm(
+                                &frame.data,
+                                &confirmed.nonce_r,
+                                &confirmed.nonce_h,
+                            )
+                        {
+                            continue;
+                        }
+
+                        let confirmed = pending.take().unwrap();
+
+                        let paired = self.paired(
+                            mac,
+                            &confirmed.nonce_r,
+                            &confirmed.nonce_h,
+                            confirmed.capabilities,
+                            &confirmed.name,
+                        );
+
+                        if paired.is_err() {
+                            self.remove_handshake_peer(mac, confirmed.previous);
+                        }
+
+                        return paired;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    /// Return the paired peers persisted in NVS
+    pub fn paired_peers(&self) -> Result<Vec<PairedPeer>, EspError> {
+        let mut peers = Vec::new();
+        let mut buf = [0; 1 + KEY_LEN + 4 + 1 + MAX_NAME_LEN];
+
+        for mac in self.stored_macs()? {
+            if let Some(data) = self.nvs.get_blob(&PairedPeer::nvs_key(&mac), &mut buf)? {
+                if let Some(peer) = PairedPeer::decode(mac, data) {
+                    peers.push(peer);
+                }
+            }
+        }
+
+        Ok(peers)
+    }
+
+    /// Register all paired peers persisted in NVS with ESP-NOW
+    pub fn restore(&self) -> Result<Vec<PairedPeer>, EspError> {
+        let peers = self.paired_peers()?;
+
+        for peer in &peers {
+            self.espnow.espnow().set_peer(&peer.peer())?;
+        }
+
+        info!("Restored {} paired ESP-NOW peers", peers.len());
+
+        Ok(peers)
+    }
+
+    /// Remove a paired peer from NVS and from ESP-NOW
+    ///
+    /// Returns `false` if there was no such peer.
+    pub fn forget(&mut self, mac: [u8; 6]) -> Result<bool, EspError> {
+        let mut macs = self.stored_macs()?;
+
+        let Some(index) = macs.iter().position(|stored| *stored == mac) else {
+            return Ok(false);
+        };
+
+        macs.remove(index);
+
+        self.nvs.remove(&PairedPeer::nvs_key(&mac))?;
+        self.store_macs(&macs)?;
+
+        self.espnow.espnow().remove_peer(mac)?;
+
+        Ok(true)
+    }
+
+    fn paired(
+        &mut self,
+        mac: [u8; 6],
+        nonce_r: &[u8; NONCE_LEN],
+        nonce_h: &[u8; NONCE_LEN],
+        capabilities: u32,
+        name: &str,
+    ) -> Result<PairedPeer, EspError> {
+        let mut input = Vec::new();
+        input.extend_from_slice(b"lmk");
+        input.extend_from_slice(nonce_r);
+        input.extend_from_slice(nonce_h);
+
+        let mut lmk = [0; KEY_LEN];
+        lmk.copy_from_slice(&hmac_sha256(&self.secret, &input)?[..KEY_LEN]);
+
+        let peer = PairedPeer {
+            mac,
+            channel: self.channel,
+            lmk,
+            capabilities,
+            name: name.into(),
+        };
+
+        self.espnow.espnow().set_peer(&peer.peer())?;
+
+        let mut macs = self.stored_macs()?;
+
+        if !macs.contains(&mac) {
+            if macs.len() >= MAX_PAIRED_PEERS {
+                return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+            }
+
+            macs.push(mac);
+        }
+
+        self.nvs
+            .set_blob(&PairedPeer::nvs_key(&mac), &peer.encode())?;
+        self.store_macs(&macs)?;
+
+        info!("Paired with ESP-NOW peer {:02x?} ({})", mac, peer.name);
+
+        Ok(peer)
+    }
+
+    /// Register `mac` as an unencrypted peer for the handshake, returning the peer it replaces
+    fn add_handshake_peer(&self, mac: [u8; 6]) -> Result<Option<PeerInfo>, EspError> {
+        let espnow = self.espnow.espnow();
+
+        let previous = if espnow.peer_exists(mac)? {
+            Some(espnow.get_peer(mac)?)
+        } else {
+            None
+        };
+
+        espnow.set_peer(&Peer {
+            channel: self.channel,
+            ..Peer::new(mac)
+        })?;
+
+        Ok(previous)
+    }
+
+    /// Undo `add_handshake_peer`, after a failed handshake
+    fn remove_handshake_peer(&self, mac: [u8; 6], previous: Option<PeerInfo>) {
+        let espnow = self.espnow.espnow();
+
+        let result = match previous {
+            Some(previous) => espnow.mod_peer(previous),
+            None => espnow.del_peer(mac),
+        };
+
+        if let Err(err) = result {
+            warn!("Removing the handshake peer {:02x?} failed: {}", mac, err);
+        }
+    }
+
+    fn stored_macs(&self) -> Result<Vec<[u8; 6]>, EspError> {
+        let mut buf = [0; 6 * MAX_PAIRED_PEERS];
+
+        let macs = self
+            .nvs
+            .get_blob(NVS_PEERS_KEY, &mut buf)?
+            .map(|data| {
+                data.chunks_exact(6)
+                    .map(|mac| mac.try_into().unwrap())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(macs)
+    }
+
+    fn store_macs(&mut self, macs: &[[u8; 6]]) -> Result<(), EspError> {
+        self.nvs.set_blob(NVS_PEERS_KEY, &macs.concat())
+    }
+
+    fn tag(&self, data: &[u8]) -> Result<[u8; TAG_LEN], EspError> {
+        let mut tag = [0; TAG_LEN];
+        tag.copy_from_slice(&hmac_sha256(&self.secret, data)?[..TAG_LEN]);
+
+        Ok(tag)
+    }
+
+    fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        self.tag(data)
+            .map(|expected| {
+                // Constant-time comparison
+                expected
+                    .iter()
+                    .zip(tag)
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+                    && tag.len() == TAG_LEN
+            })
+            .unwrap_or(false)
+    }
+
+    fn parse_response(
+        &self,
+        frame: &ReceivedFrame,
+        nonce_r: &[u8; NONCE_LEN],
+    ) -> Option<([u8; NONCE_LEN], u32, String)> {
+        let data = &frame.data;
+
+        if !data.starts_with(&MAGIC) || *data.get(MAGIC.len())? != MSG_RESPONSE {
+            return None;
+        }
+
+        let mut offset = MAGIC.len() + 1;
+
+        if data.get(offset..offset + NONCE_LEN)? != nonce_r {
+            return None;
+        }
+
+        offset += NONCE_LEN;
+
+        let nonce_h = data.get(offset..offset + NONCE_LEN)?.try_into().ok()?;
+        offset += NONCE_LEN;
+
+        let capabilities = u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?);
+        offset += 4;
+
+        let (name, name_end) = parse_name(data, offset)?;
+
+        if !self.verify(&data[MAGIC.len()..name_end], data.get(name_end..)?) {
+            warn!(
+                "Ignoring pairing response with an invalid tag from {:02x?}",
+                frame.info.src_addr
+            );
+            return None;
+        }
+
+        Some((nonce_h, capabilities, name.into()))
+    }
+
+    fn verify_confirm(
+        &self,
+        data: &[u8],
+        nonce_r: &[u8; NONCE_LEN],
+        nonce_h: &[u8; NONCE_LEN],
+    ) -> bool {
+        let nonces_end = MAGIC.len() + 1 + 2 * NONCE_LEN;
+
+        data.len() > nonces_end
+            && &data[MAGIC.len() + 1..MAGIC.len() + 1 + NONCE_LEN] == nonce_r
+            && &data[MAGIC.len() + 1 + NONCE_LEN..nonces_end] == nonce_h
+            && self.verify(&data[MAGIC.len()..nonces_end], &data[nonces_end..])
+    }
+}
+
+fn message(buf: &mut Vec<u8>, msg_type: u8) {
+    buf.extend_from_slice(&MAGIC);
+    buf.push(msg_type);
+}
+
+fn push_name(buf: &mut Vec<u8>, name: &str) {
+    buf.push(name.len() as _);
+    buf.extend_from_slice(name.as_bytes());
+}
+
+fn parse_name(data: &[u8], offset: usize) -> Option<(&str, usize)> {
+    let len = *data.get(offset)? as usize;
+
+    if len > MAX_NAME_LEN {
+        return None;
+    }
+
+    let name = core::str::from_utf8(data.get(offset + 1..offset + 1 + len)?).ok()?;
+
+    Some((name, offset + 1 + len))
+}
+
+fn parse_probe(data: &[u8]) -> Option<([u8; NONCE_LEN], u32, &str)> {
+    let mut offset = MAGIC.len() + 1;
+
+    let nonce_r = data.get(offset..offset + NONCE_LEN)?.try_into().ok()?;
+    offset += NONCE_LEN;
+
+    let capabilities = u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?);
+    offset += 4;
+
+    let (name, _) = parse_name(data, offset)?;
+
+    Some((nonce_r, capabilities, name))
+}
+
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+
+    crate::rng::fill(&mut nonce);
+
+    nonce
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; 32], EspError> {
+    let mut output = [0; 32];
+
+    let result = unsafe {
+        mbedtls_md_hmac(
+            mbedtls_md_info_from_type(mbedtls_md_type_t_MBEDTLS_MD_SHA256),
+            key.as_ptr(),
+            key.len(),
+            data.as_ptr(),
+            data.len(),
+            output.as_mut_ptr(),
+        )
+    };
+
+    if result != 0 {
+        return Err(EspError::from_infallible::<ESP_FAIL>());
+    }
+
+    Ok(output)
+}
@@ -0,0 +1,352 @@
+//! ESP-NOW flooding relay
+//!
+//! A lightweight alternative to a full WiFi mesh: messages are broadcast with a time-to-live,
+//! and every node running an `EspNowRelay` re-broadcasts the messages it has not seen yet,
+//! decrementing their TTL, so that they reach nodes out of the direct range of the sender.
+//!
+//! Messages are identified by their originator and a sequence number, which are
+//! kept in a de-duplication cache of a fixed length. The retransmissions of `send_acked` keep
+//! the identity of the message, so that they are delivered only once, but carry the number of
+//! the attempt, so that they are relayed again.
+//!
+//! Messages can be addressed to a single node or to all nodes (`BROADCAST`), and
+//! unicast messages can optionally be acknowledged by their destination (`send_acked`).
+//!
+//! Note that the relay consumes all ESP-NOW frames it receives; non-relay frames are dropped.
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use embassy_futures::select::{select, Either};
+
+use ::log::debug;
+
+use crate::sys::*;
+use crate::timer::EspAsyncTimer;
+
+use super::{EspNowAsync, Peer, BROADCAST, MAX_DATA_LEN};
+
+const MAGIC: [u8; 4] = *b"ENR1";
+
+const FLAG_ACK_REQUESTED: u8 = 0x01;
+const FLAG_ACK: u8 = 0x02;
+
+// The upper bits of the flags hold the attempt number of the message
+const ATTEMPT_SHIFT: u8 = 4;
+const MAX_ATTEMPT: u8 = 0x0f;
+
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 6 + 4 + 6;
+
+/// The maximum payload length of a relayed message
+pub const MAX_PAYLOAD_LEN: usize = MAX_DATA_LEN - HEADER_LEN;
+
+#[derive(Clone, Debug)]
+pub struct RelayConfiguration {
+    /// The number of hops a message sent by this node can make
+    pub ttl: u8,
+    /// How many recently seen messages are remembered for de-duplication
+    pub dedup_cache_len: usize,
+    /// How long `send_acked` waits for an acknowledgment before retrying
+    pub ack_timeout: Duration,
+    /// How many times `send_acked` retries; the retries after the 15th are not relayed
+    pub retries: u8,
+    /// How many received messages are buffered while `send_acked` waits for an acknowledgment
+    pub rx_queue_len: usize,
+}
+
+impl Default for RelayConfiguration {
+    fn default() -> Self {
+        Self {
+            ttl: 4,
+            dedup_cache_len: 32,
+            ack_timeout: Duration::from_millis(500),
+            retries: 3,
+            rx_queue_len: 8,
+        }
+    }
+}
+
+/// A message received over the relay
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RelayMessage {
+    /// The node which originally sent the message
+    pub origin: [u8; 6],
+    /// The destination of the message, either this node or `BROADCAST`
+    pub dst: [u8; 6],
+    /// The remaining TTL of the message when it was received
+    pub ttl: u8,
+    pub data: Vec<u8>,
+}
+
+struct Header {
+    flags: u8,
+    ttl: u8,
+    origin: [u8; 6],
+    id: u32,
+    dst: [u8; 6],
+}
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+
+        frame.extend_from_slice(&MAGIC);
+        frame.push(self.flags);
+        frame.push(self.ttl);
+        frame.extend_from_slice(&self.origin);
+        frame.extend_from_slice(&self.id.to_le_bytes());
+        frame.extend_from_slice(&self.dst);
+        frame.extend_from_slice(payload);
+
+        frame
+    }
+
+    fn decode(frame: &[u8]) -> Option<(Self, &[u8])> {
+        if frame.len() < HEADER_LEN || !frame.starts_with(&MAGIC) {
+            return None;
+        }
+
+        let header = Self {
+            flags: frame[4],
+            ttl: frame[5],
+            origin: frame[6..12].try_into().ok()?,
+            id: u32::from_le_bytes(frame[12..16].try_into().ok()?),
+            dst: frame[16..22].try_into().ok()?,
+        };
+
+        Some((header, &frame[HEADER_LEN..]))
+    }
+}
+
+/// A flooding relay over ESP-NOW broadcasts
+pub struct EspNowRelay<'d, 'a> {
+    espnow: &'d EspNowAsync<'a>,
+    timer: EspAsyncTimer,
+    mac: [u8; 6],
+    next_id: u32,
+    seen: VecDeque<([u8; 6], u32, u8)>,
+    pending: VecDeque<RelayMessage>,
+    acked: Option<([u8; 6], u32)>,
+    conf: RelayConfiguration,
+}
+
+impl<'d, 'a> EspNowRelay<'d, 'a> {
+    pub fn new(
+        espnow: &'d EspNowAsync<'a>,
+        timer: EspAsyncTimer,
+        conf: &RelayConfiguration,
+    ) -> Result<Self, EspError> {
+        let mut mac = [0; 6];
+        esp!(unsafe { esp_wifi_get_mac(wifi_interface_t_WIFI_IF_STA, mac.as_mut_ptr()) })?;
+
+        espnow.espnow().set_peer(&Peer::new(BROADCAST))?;
+
+        Ok(Self {
+            espnow,
+            timer,
+            mac,
+            // Start from a random ID, so that nodes rebooting do not get their messages de-duplicated
+            next_id: unsafe { esp_random() },
+            seen: VecDeque::new(),
+            pending: VecDeque::new(),
+            acked: None,
+            conf: conf.clone(),
+        })
+    }
+
+    /// Send a message to `dst` (or to all nodes, with `BROADCAST`) without waiting for an acknowledgment
+    pub async fn send(&mut self, dst: [u8; 6], data: &[u8]) -> Result<(), EspError> {
+        let id = self.next_id();
+
+        self.send_message(dst, data, 0, id).await
+    }
+
+    /// Send a message to `dst`, and wait for its acknowledgment, retrying as configured
+    ///
+    /// Returns `false` if the message was not acknowledged. Messages received in the meantime
+    /// are relayed as usual and buffered for `recv`.
+    pub async fn send_acked(&mut self, dst: [u8; 6], data: &[u8]) -> Result<bool, EspError> {
+        if dst == BROADCAST {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        // All the attempts send the same message, so that it is delivered only once
+        let id = self.next_id();
+
+        for attempt in 0..=self.conf.retries {
+            let flags = FLAG_ACK_REQUESTED | (attempt.min(MAX_ATTEMPT) << ATTEMPT_SHIFT);
+
+            self.send_message(dst, data, flags, id).await?;
+
+            self.acked = None;
+
+            loop {
+                let frame =
+                    match select(self.espnow.recv(), self.timer.after(self.conf.ack_timeout)).await
+                    {
+                        Either::First(frame) => frame?,
+                        Either::Second(_) => break,
+                    };
+
+                if let Some(message) = self.process(&frame.data).await? {
+                    if self.pending.len() >= self.conf.rx_queue_len {
+                        self.pending.pop_front();
+                    }
+
+                    self.pending.push_back(message);
+                }
+
+                if self.acked == Some((dst, id)) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Wait for the next message addressed to this node or broadcast, relaying other messages meanwhile
+    pub async fn recv(&mut self) -> Result<RelayMessage, EspError> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+
+        loop {
+            let frame = self.espnow.recv().await?;
+
+            if let Some(message) = self.process(&frame.data).await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        id
+    }
+
+    async fn send_message(
+        &mut self,
+        dst: [u8; 6],
+        data: &[u8],
+        flags: u8,
+        id: u32,
+    ) -> Result<(), EspError> {
+        if data.len() > MAX_PAYLOAD_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let header = Header {
+            flags,
+            ttl: self.conf.ttl,
+            origin: self.mac,
+            id,
+            dst,
+        };
+
+        // Our own messages relayed back by the neighbours should be ignored
+        self.remember(self.mac, id, flags >> ATTEMPT_SHIFT);
+
+        self.espnow.send(BROADCAST, &header.encode(data)).await?;
+
+        Ok(())
+    }
+
+    /// Process a received frame: relay it if necessary, acknowledge it if requested,
+    /// and return it if it is addressed to this node
+    async fn process(&mut self, frame: &[u8]) -> Result<Option<RelayMessage>, EspError> {
+        let Some((header, payload)) = Header::decode(frame) else {
+            return Ok(None);
+        };
+
+        let attempt = header.flags >> ATTEMPT_SHIFT;
+
+        if self.is_seen(&header.origin, header.id, Some(attempt)) {
+            return Ok(None);
+        }
+
+        // A retransmission of a message already received, whose acknowledgment got lost
+        let delivered = self.is_seen(&header.origin, header.id, None);
+
+        self.remember(header.origin, header.id, attempt);
+
+        let for_us = header.dst == self.mac;
+
+        if !for_us && header.ttl > 1 {
+            debug!(
+                "Relaying message {} from {:02x?}, TTL {}",
+                header.id, header.origin, header.ttl
+            );
+
+            let relayed = Header {
+                ttl: header.ttl - 1,
+                ..header
+            };
+
+            self.espnow
+                .send(BROADCAST, &relayed.encode(payload))
+                .await?;
+        }
+
+        if header.flags & FLAG_ACK != 0 {
+            if for_us && payload.len() == 4 {
+                // The payload of an acknowledgment is the ID of the acknowledged message
+                self.acked = Some((
+                    header.origin,
+                    u32::from_le_bytes(payload.try_into().unwrap()),
+                ));
+            }
+
+            return Ok(None);
+        }
+
+        if for_us && header.flags & FLAG_ACK_REQUESTED != 0 {
+            let ack_id = self.next_id();
+
+            let ack = Header {
+                flags: FLAG_ACK,
+                ttl: self.conf.ttl,
+                origin: self.mac,
+                id: ack_id,
+                dst: header.origin,
+            };
+
+            self.remember(self.mac, ack_id, 0);
+
+            self.espnow
+                .send(BROADCAST, &ack.encode(&header.id.to_le_bytes()))
+                .await?;
+        }
+
+        if !delivered && (for_us || header.dst == BROADCAST) {
+            Ok(Some(RelayMessage {
+                origin: header.origin,
+                dst: header.dst,
+                ttl: header.ttl,
+                data: payload.into(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether the message was seen, in the provided attempt or in any attempt
+    fn is_seen(&self, origin: &[u8; 6], id: u32, attempt: Option<u8>) -> bool {
+        self.seen.iter().any(|seen| {
+            seen.0 == *origin && seen.1 == id && attempt.map(|a| seen.2 == a).unwrap_or(true)
+        })
+    }
+
+    fn remember(&mut self, origin: [u8; 6], id: u32, attempt: u8) {
+        if self.seen.len() >= self.conf.dedup_cache_len.max(1) {
+            self.seen.pop_front();
+        }
+
+        self.seen.push_back((origin, id, attempt));
+    }
+}
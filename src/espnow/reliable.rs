@@ -0,0 +1,348 @@
+//! Reliable transport over ESP-NOW
+//!
+//! `EspNowChannel` is a byte stream between two peers, implementing the `embedded_svc::io`
+//! async `Read` and `Write` traits on top of ESP-NOW unicast frames:
+//! - each `write` sends one message of at most `MAX_MESSAGE_LEN` bytes, split into
+//!   fragments which fit a single frame
+//! - each fragment carries a sequence number and is acknowledged by the receiver;
+//!   unacknowledged fragments are retransmitted with an exponential backoff
+//! - duplicated fragments are detected and only acknowledged again
+//! - the receiver makes the fragments available to `read` as they arrive, in order, and stops
+//!   acknowledging while its receive buffer is full, which throttles the sender; a message can
+//!   thus be longer than the receive buffer
+//!
+//! Both peers need to create an `EspNowChannel` for each other. Note that the channel consumes
+//! all ESP-NOW frames it receives; frames of other peers are dropped.
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use embassy_futures::select::{select, Either};
+
+use embedded_svc::io::{asynch, ErrorType};
+
+use ::log::debug;
+
+use crate::io::EspIOError;
+use crate::sys::*;
+use crate::timer::EspAsyncTimer;
+
+use super::{EspNowAsync, Peer, ReceivedFrame, MAX_DATA_LEN};
+
+const MAGIC: [u8; 4] = *b"ENT1";
+
+const TYPE_DATA: u8 = 1;
+const TYPE_ACK: u8 = 2;
+
+/// Set on the fragments of the first message sent by a channel, so that
+/// the receiver synchronizes its expected sequence number
+const FLAG_SYNC: u8 = 0x01;
+
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 2 + 1 + 1;
+
+/// The maximum payload length of a single fragment
+pub const MAX_FRAGMENT_LEN: usize = MAX_DATA_LEN - HEADER_LEN;
+
+/// The maximum length of a message, i.e. of the data sent by a single `write`
+pub const MAX_MESSAGE_LEN: usize = MAX_FRAGMENT_LEN * u8::MAX as usize;
+
+#[derive(Clone, Debug)]
+pub struct ReliableConfiguration {
+    /// How long to wait for the acknowledgment of the first transmission of a fragment.
+    /// The timeout doubles with each retransmission
+    pub ack_timeout: Duration,
+    /// How many times a fragment is retransmitted before `write` fails with `ESP_ERR_TIMEOUT`
+    pub max_retries: u8,
+    /// The size of the receive buffer, in bytes
+    pub rx_buffer_len: usize,
+}
+
+impl Default for ReliableConfiguration {
+    fn default() -> Self {
+        Self {
+            ack_timeout: Duration::from_millis(50),
+            max_retries: 5,
+            rx_buffer_len: 4096,
+        }
+    }
+}
+
+struct Header {
+    frame_type: u8,
+    flags: u8,
+    seq: u16,
+    index: u8,
+    count: u8,
+}
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+
+        frame.extend_from_slice(&MAGIC);
+        frame.push(self.frame_type);
+        frame.push(self.flags);
+        frame.extend_from_slice(&self.seq.to_le_bytes());
+        frame.push(self.index);
+        frame.push(self.count);
+        frame.extend_from_slice(payload);
+
+        frame
+    }
+
+    fn decode(frame: &[u8]) -> Option<(Self, &[u8])> {
+        if frame.len() < HEADER_LEN || !frame.starts_with(&MAGIC) {
+            return None;
+        }
+
+        let header = Self {
+            frame_type: frame[4],
+            flags: frame[5],
+            seq: u16::from_le_bytes([frame[6], frame[7]]),
+            index: frame[8],
+            count: frame[9],
+        };
+
+        Some((header, &frame[HEADER_LEN..]))
+    }
+}
+
+/// A reliable byte stream with a single ESP-NOW peer
+pub struct EspNowChannel<'d, 'a> {
+    espnow: &'d EspNowAsync<'a>,
+    timer: EspAsyncTimer,
+    peer: [u8; 6],
+    tx_seq: u16,
+    tx_synced: bool,
+    acked: Option<(u16, u8)>,
+    rx_seq: Option<u16>,
+    rx_index: u8,
+    rx: VecDeque<u8>,
+    conf: ReliableConfiguration,
+}
+
+impl<'d, 'a> EspNowChannel<'d, 'a> {
+    /// Create a channel with the provided peer, registering it with ESP-NOW
+    pub fn new(
+        espnow: &'d EspNowAsync<'a>,
+        timer: EspAsyncTimer,
+        peer: &Peer,
+        conf: &ReliableConfiguration,
+    ) -> Result<Self, EspError> {
+        espnow.espnow().set_peer(peer)?;
+
+        Ok(Self {
+            espnow,
+            timer,
+            peer: peer.mac,
+            tx_seq: unsafe { esp_random() } as _,
+            tx_synced: false,
+            acked: None,
+            rx_seq: None,
+            rx_index: 0,
+            rx: VecDeque::new(),
+            conf: conf.clone(),
+        })
+    }
+
+    /// The MAC address of the peer
+    pub fn peer(&self) -> [u8; 6] {
+        self.peer
+    }
+
+    /// Send a message of at most `MAX_MESSAGE_LEN` bytes, waiting until all of its fragments are acknowledged
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), EspError> {
+        if data.len() > MAX_MESSAGE_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let count = data.len().div_ceil(MAX_FRAGMENT_LEN).max(1) as u8;
+        let seq = self.tx_seq;
+
+        for index in 0..count {
+            let start = index as usize * MAX_FRAGMENT_LEN;
+            let end = (start + MAX_FRAGMENT_LEN).min(data.len());
+
+            let frame = Header {
+                frame_type: TYPE_DATA,
+                flags: if self.tx_synced { 0 } else { FLAG_SYNC },
+                seq,
+                index,
+                count,
+            }
+            .encode(&data[start..end]);
+
+            if let Err(err) = self.send_fragment(seq, index, &frame).await {
+                // The peer may have got part of the message, or may have restarted: the next
+                // message gets a new sequence number, and resynchronizes the peer
+                self.tx_seq = self.tx_seq.wrapping_add(1);
+                self.tx_synced = false;
+
+                return Err(err);
+            }
+        }
+
+        self.tx_seq = self.tx_seq.wrapping_add(1);
+        self.tx_synced = true;
+
+        Ok(())
+    }
+
+    /// Receive data into `buf`, waiting until at least one byte is available
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.rx.is_empty() {
+            let frame = self.espnow.recv().await?;
+
+            self.handle(&frame).await?;
+        }
+
+        let len = buf.len().min(self.rx.len());
+
+        for (dst, src) in buf.iter_mut().zip(self.rx.drain(..len)) {
+            *dst = src;
+        }
+
+        Ok(len)
+    }
+
+    async fn send_fragment(&mut self, seq: u16, index: u8, frame: &[u8]) -> Result<(), EspError> {
+        let mut timeout = self.conf.ack_timeout;
+
+        for attempt in 0..=self.conf.max_retries {
+            if attempt > 0 {
+                debug!(
+                    "Retransmitting fragment {}/{} to {:02x?}",
+                    seq, index, self.peer
+                );
+            }
+
+            self.acked = None;
+
+            // The delivery status is not checked, as only the acknowledgment counts
+            self.espnow.send(self.peer, frame).await?;
+
+            loop {
+                let frame = match select(self.espnow.recv(), self.timer.after(timeout)).await {
+                    Either::First(frame) => frame?,
+                    Either::Second(_) => break,
+                };
+
+                self.handle(&frame).await?;
+
+                if self.acked == Some((seq, index)) {
+                    return Ok(());
+                }
+            }
+
+            timeout *= 2;
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    async fn handle(&mut self, frame: &ReceivedFrame) -> Result<(), EspError> {
+        if frame.info.src_addr != self.peer {
+            return Ok(());
+        }
+
+        let Some((header, payload)) = Header::decode(&frame.data) else {
+            return Ok(());
+        };
+
+        match header.frame_type {
+            TYPE_ACK => {
+                self.acked = Some((header.seq, header.index));
+            }
+            TYPE_DATA => {
+                // A retransmission of the last message received, e.g. of its first message
+                // whose acknowledgment got lost; not to be mistaken for a restart of the peer
+                let duplicate_message = self
+                    .rx_seq
+                    .map(|rx_seq| header.seq == rx_seq.wrapping_sub(1))
+                    .unwrap_or(false);
+
+                if header.flags & FLAG_SYNC != 0
+                    && header.index == 0
+                    && self.rx_seq != Some(header.seq)
+                    && !duplicate_message
+                {
+                    // The peer (re)started: resynchronize
+                    self.rx_seq = Some(header.seq);
+                    self.rx_index = 0;
+                }
+
+                let Some(rx_seq) = self.rx_seq else {
+                    // Not synchronized yet
+                    return Ok(());
+                };
+
+                let duplicate =
+                    (header.seq == rx_seq && header.index < self.rx_index) || duplicate_message;
+
+                if header.seq == rx_seq && header.index == self.rx_index {
+                    if self.rx.len() + payload.len() > self.conf.rx_buffer_len {
+                        // No space left: do not acknowledge, so the peer retransmits later
+                        return Ok(());
+                    }
+
+                    self.rx.extend(payload);
+                    self.rx_index += 1;
+
+                    if self.rx_index >= header.count {
+                        self.rx_seq = Some(rx_seq.wrapping_add(1));
+                        self.rx_index = 0;
+                    }
+                } else if !duplicate {
+                    return Ok(());
+                }
+
+                let ack = Header {
+                    frame_type: TYPE_ACK,
+                    flags: 0,
+                    seq: header.seq,
+                    index: header.index,
+                    count: header.count,
+                };
+
+                self.espnow.send(self.peer, &ack.encode(&[])).await?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+impl<'d, 'a> ErrorType for EspNowChannel<'d, 'a> {
+    type Error = EspIOError;
+}
+
+impl<'d, 'a> asynch::Read for EspNowChannel<'d, 'a> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        EspNowChannel::recv(self, buf).await.map_err(EspIOError)
+    }
+}
+
+impl<'d, 'a> asynch::Write for EspNowChannel<'d, 'a> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(MAX_MESSAGE_LEN);
+
+        EspNowChannel::send(self, &buf[..len])
+            .await
+            .map_err(EspIOError)?;
+
+        Ok(len)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        // `write` only returns once all data is acknowledged
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@
 
 use core::fmt::Debug;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use core::time::Duration;
 use core::{ffi, mem, ptr, slice};
 
@@ -28,6 +29,10 @@ use crate::private::zerocopy::{Channel, QuitOnDrop, Receiver};
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_timer_enabled))]
 pub use async_wait::*;
 
+pub mod bridge;
+#[cfg(esp_idf_esp_event_post_from_isr)]
+pub mod isr;
+
 pub type EspSystemSubscription<'a> = EspSubscription<'a, System>;
 pub type EspBackgroundSubscription<'a> = EspSubscription<'a, User<Background>>;
 pub type EspExplicitSubscription<'a> = EspSubscription<'a, User<Explicit>>;
@@ -305,6 +310,119 @@ impl<'a> UnsafeCallback<'a> {
     }
 }
 
+/// A snapshot of the metrics collected for an event loop.
+///
+/// All the events are only counted after `EspEventLoop::enable_metrics` had been called.
+///
+/// Note that `posted` and `dropped` only account for events posted via the
+/// `post*` methods of `EspEventLoop`, as ESP-IDF does not expose these counters.
+/// For the system event loop, events posted by ESP-IDF drivers are therefore
+/// only visible in `dispatched`.
+#[derive(Debug, Clone, Default)]
+pub struct EspEventLoopMetrics {
+    /// Number of events successfully posted to the loop queue
+    pub posted: u32,
+    /// Number of events which could not be posted because the loop queue was full
+    pub dropped: u32,
+    /// Number of events dispatched by the loop so far
+    pub dispatched: u32,
+    system: bool,
+}
+
+impl EspEventLoopMetrics {
+    /// An estimate of the number of events currently sitting in the loop queue, as the events
+    /// posted through this crate minus those dispatched, since `enable_metrics`
+    ///
+    /// ESP-IDF does not expose the queue of an event loop, so the estimate is approximate: an
+    /// event is counted until the handler counting the dispatched events is called for it, and
+    /// the events posted with the ESP-IDF APIs directly are counted as dispatched only, which
+    /// lowers the estimate. `None` for the system event loop, most of whose events are posted
+    /// by ESP-IDF itself.
+    pub fn estimated_queue_usage(&self) -> Option<u32> {
+        (!self.system).then(|| self.posted.saturating_sub(self.dispatched))
+    }
+}
+
+/// Execution time statistics of a single event handler.
+///
+/// Only collected for subscriptions created while handler profiling is enabled
+/// on the event loop with `EspEventLoop::set_handler_profiling`.
+#[derive(Debug, Clone, Default)]
+pub struct EspHandlerStats {
+    pub invocations: u32,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+struct HandlerProfile(mutex::Mutex<EspHandlerStats>);
+
+impl HandlerProfile {
+    fn new() -> Self {
+        Self(mutex::Mutex::new(EspHandlerStats::default()))
+    }
+
+    fn record(&self, time: Duration) {
+        let mut stats = self.0.lock();
+
+        stats.invocations += 1;
+        stats.total_time += time;
+        stats.max_time = stats.max_time.max(time);
+    }
+
+    fn stats(&self) -> EspHandlerStats {
+        self.0.lock().clone()
+    }
+}
+
+struct LoopMetrics {
+    // Whether `enable_metrics` was called, so that the posted and dispatched events are
+    // counted from the same point
+    enabled: AtomicBool,
+    posted: AtomicU32,
+    dropped: AtomicU32,
+    dispatched: AtomicU32,
+    // 0 means "handler profiling disabled"
+    stall_threshold_us: AtomicU32,
+    dispatch_subscription: mutex::Mutex<Option<esp_event_handler_instance_t>>,
+}
+
+impl Debug for LoopMetrics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("LoopMetrics")
+            .field("enabled", &self.enabled)
+            .field("posted", &self.posted)
+            .field("dropped", &self.dropped)
+            .field("dispatched", &self.dispatched)
+            .field("stall_threshold_us", &self.stall_threshold_us)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LoopMetrics {
+    fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            posted: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+            dispatched: AtomicU32::new(0),
+            stall_threshold_us: AtomicU32::new(0),
+            dispatch_subscription: mutex::Mutex::new(None),
+        }
+    }
+
+    fn record_post(&self, posted: bool) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if posted {
+            self.posted.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
 enum EventLoopHandleRef<T>
 where
     T: EspEventLoopType,
@@ -339,6 +457,7 @@ where
     handler_instance: esp_event_handler_instance_t,
     source: Option<&'static ffi::CStr>,
     event_id: i32,
+    profile: Option<Arc<HandlerProfile>>,
     #[allow(clippy::type_complexity)]
     _callback: Box<Box<dyn FnMut(EspEvent) + Send + 'a>>,
 }
@@ -351,6 +470,13 @@ where
         self.event_loop_handle.make_weak();
     }
 
+    /// Return the execution time statistics of this subscription's handler,
+    /// or `None` if handler profiling was not enabled on the event loop
+    /// at the time the subscription was created.
+    pub fn stats(&self) -> Option<EspHandlerStats> {
+        self.profile.as_ref().map(|profile| profile.stats())
+    }
+
     extern "C" fn handle(
         event_handler_arg: *mut ffi::c_void,
         event_base: esp_event_base_t,
@@ -435,6 +561,10 @@ where
         self.subscription.make_weak();
     }
 
+    pub fn stats(&self) -> Option<EspHandlerStats> {
+        self.subscription.stats()
+    }
+
     pub async fn recv(&mut self) -> Result<D::Data<'_>, EspError> {
         if self.given {
             self.receiver.done();
@@ -442,7 +572,13 @@ where
         }
 
         while let Some(data) = self.receiver.get_shared_async().await {
-            if Some(data.source) != D::source() {
+            if D::source()
+                .map(|source| source != data.source)
+                .unwrap_or(false)
+                || D::event_id()
+                    .map(|event_id| event_id != data.event_id)
+                    .unwrap_or(false)
+            {
                 self.receiver.done();
                 continue;
             }
@@ -487,7 +623,7 @@ where
 }
 
 #[derive(Debug)]
-struct EventLoopHandle<T>(T)
+struct EventLoopHandle<T>(T, LoopMetrics)
 where
     T: EspEventLoopType;
 
@@ -503,7 +639,7 @@ impl EventLoopHandle<System> {
 
         *taken = true;
 
-        Ok(Self(System))
+        Ok(Self(System, LoopMetrics::new()))
     }
 }
 
@@ -513,7 +649,7 @@ impl<T> EventLoopHandle<User<T>> {
 
         esp!(unsafe { esp_event_loop_create(conf as *const _, &mut handle as _) })?;
 
-        Ok(Self(User(handle, PhantomData)))
+        Ok(Self(User(handle, PhantomData), LoopMetrics::new()))
     }
 }
 
@@ -536,6 +672,30 @@ where
     T: EspEventLoopType,
 {
     fn drop(&mut self) {
+        if let Some(handler_instance) = self.1.dispatch_subscription.lock().take() {
+            unsafe {
+                if T::is_system() {
+                    esp!(esp_event_handler_instance_unregister(
+                        ptr::null(),
+                        ESP_EVENT_ANY_ID,
+                        handler_instance
+                    ))
+                    .unwrap();
+                } else {
+                    let handle: &T = &self.0;
+                    let user: &User<Background> = mem::transmute(handle);
+
+                    esp!(esp_event_handler_instance_unregister_with(
+                        user.0,
+                        ptr::null(),
+                        ESP_EVENT_ANY_ID,
+                        handler_instance
+                    ))
+                    .unwrap();
+                }
+            }
+        }
+
         if T::is_system() {
             let mut taken = TAKEN.lock();
 
@@ -590,6 +750,137 @@ where
         })
     }
 
+    /// Return a snapshot of the metrics collected for this event loop.
+    pub fn metrics(&self) -> EspEventLoopMetrics {
+        let metrics = &self.0 .1;
+
+        EspEventLoopMetrics {
+            posted: metrics.posted.load(Ordering::Relaxed),
+            dropped: metrics.dropped.load(Ordering::Relaxed),
+            dispatched: metrics.dispatched.load(Ordering::Relaxed),
+            system: T::is_system(),
+        }
+    }
+
+    /// Start counting the events posted, dropped and dispatched by this event loop, for
+    /// `metrics` to return meaningful values.
+    ///
+    /// Calling this method more than once has no effect.
+    pub fn enable_metrics(&self) -> Result<(), EspError> {
+        let metrics = &self.0 .1;
+
+        let mut dispatch_subscription = metrics.dispatch_subscription.lock();
+
+        if dispatch_subscription.is_none() {
+            let mut handler_instance: esp_event_handler_instance_t = ptr::null_mut();
+
+            // The counter is owned by the event loop handle, which outlives the handler
+            // (the handler is unregistered when the event loop handle is dropped)
+            let counter = &metrics.dispatched as *const _ as *mut ffi::c_void;
+
+            if T::is_system() {
+                esp!(unsafe {
+                    esp_event_handler_instance_register(
+                        ptr::null(),
+                        ESP_EVENT_ANY_ID,
+                        Some(Self::count_dispatched),
+                        counter,
+                        &mut handler_instance as *mut _,
+                    )
+                })?;
+            } else {
+                esp!(unsafe {
+                    let handle: &T = &self.0 .0;
+                    let user: &User<Background> = mem::transmute(handle);
+
+                    esp_event_handler_instance_register_with(
+                        user.0,
+                        ptr::null(),
+                        ESP_EVENT_ANY_ID,
+                        Some(Self::count_dispatched),
+                        counter,
+                        &mut handler_instance as *mut _,
+                    )
+                })?;
+            }
+
+            *dispatch_subscription = Some(handler_instance);
+
+            metrics.enabled.store(true, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Enable or disable execution time profiling for handlers subscribed
+    /// _after_ this call.
+    ///
+    /// When enabled, every invocation of a handler is timed and the statistics
+    /// can be retrieved with `EspSubscription::stats`. Invocations taking longer than
+    /// `stall_threshold` are additionally reported with a warning log message, which is
+    /// useful for detecting handlers stalling the event loop task.
+    ///
+    /// Passing `None` disables the profiling.
+    pub fn set_handler_profiling(&self, stall_threshold: Option<Duration>) {
+        let threshold_us = stall_threshold
+            .map(|threshold| (threshold.as_micros().min(u32::MAX as _) as u32).max(1))
+            .unwrap_or(0);
+
+        let metrics = &self.0 .1;
+
+        metrics
+            .stall_threshold_us
+            .store(threshold_us, Ordering::Relaxed);
+    }
+
+    extern "C" fn count_dispatched(
+        event_handler_arg: *mut ffi::c_void,
+        _event_base: esp_event_base_t,
+        _event_id: i32,
+        _event_data: *mut ffi::c_void,
+    ) {
+        let counter = unsafe { (event_handler_arg as *const AtomicU32).as_ref() }.unwrap();
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Subscribes to all events posted to this event loop, regardless of their
+    /// event base and event ID.
+    ///
+    /// The callback receives the raw event - i.e. its event base, event ID and untyped payload.
+    /// Useful for diagnostic logging of all events flowing through the loop, or for bridging
+    /// them elsewhere without having to subscribe for each event type separately.
+    pub fn subscribe_any<F>(&self, callback: F) -> Result<EspSubscription<'static, T>, EspError>
+    where
+        F: for<'a> FnMut(EspEvent<'a>) + Send + 'static,
+    {
+        self.subscribe_raw::<EspEvent, _>(callback)
+    }
+
+    /// Same as `subscribe_any`, but returns an async subscription which yields
+    /// all events posted to this event loop.
+    pub fn subscribe_any_async(
+        &self,
+    ) -> Result<EspAsyncSubscription<EspEvent<'static>, T>, EspError> {
+        self.subscribe_async::<EspEvent<'static>>()
+    }
+
+    /// Subscribes a callback which logs - at the provided log level - the event base, event ID
+    /// and payload address of every event posted to this event loop.
+    ///
+    /// Meant for debugging only.
+    pub fn tap(&self, level: Level) -> Result<EspSubscription<'static, T>, EspError> {
+        self.subscribe_any(move |event| {
+            log!(
+                level,
+                "Event: base={:?}, id={}, payload={:?}",
+                event.source,
+                event.event_id,
+                event.payload.map(|payload| payload as *const _)
+            );
+        })
+    }
+
     pub fn subscribe<D, F>(&self, mut callback: F) -> Result<EspSubscription<'static, T>, EspError>
     where
         D: EspEventDeserializer,
@@ -660,14 +951,47 @@ where
         }
     }
 
-    fn subscribe_raw<'a, S, F>(&self, callback: F) -> Result<EspSubscription<'a, T>, EspError>
+    fn subscribe_raw<'a, S, F>(&self, mut callback: F) -> Result<EspSubscription<'a, T>, EspError>
     where
         S: EspEventSource,
         F: FnMut(EspEvent) + Send + 'a,
     {
         let mut handler_instance: esp_event_handler_instance_t = ptr::null_mut();
 
-        let callback: Box<dyn FnMut(EspEvent) + Send + 'a> = Box::new(callback);
+        let stall_threshold_us = self.0 .1.stall_threshold_us.load(Ordering::Relaxed);
+
+        let (profile, callback): (_, Box<dyn FnMut(EspEvent) + Send + 'a>) =
+            if stall_threshold_us > 0 {
+                let profile = Arc::new(HandlerProfile::new());
+
+                let handler_profile = profile.clone();
+
+                (
+                    Some(profile),
+                    Box::new(move |event: EspEvent| {
+                        let source = event.source;
+                        let event_id = event.event_id;
+
+                        let start = unsafe { esp_timer_get_time() };
+
+                        callback(event);
+
+                        let time_us = (unsafe { esp_timer_get_time() } - start) as u32;
+
+                        handler_profile.record(Duration::from_micros(time_us as _));
+
+                        if time_us >= stall_threshold_us {
+                            warn!(
+                                "Handler for event {:?}/{} took {}us (threshold: {}us)",
+                                source, event_id, time_us, stall_threshold_us
+                            );
+                        }
+                    }),
+                )
+            } else {
+                (None, Box::new(callback))
+            };
+
         let mut callback = Box::new(callback);
 
         let unsafe_callback = UnsafeCallback::from(&mut callback);
@@ -703,6 +1027,7 @@ where
             handler_instance,
             source: S::source(),
             event_id: S::event_id().unwrap_or(ESP_EVENT_ANY_ID),
+            profile,
             _callback: callback,
         })
     }
@@ -734,11 +1059,17 @@ where
             }
         };
 
-        if result == ESP_ERR_TIMEOUT {
+        let result = if result == ESP_ERR_TIMEOUT {
             Ok(false)
         } else {
             esp_result!(result, true)
+        };
+
+        if let Ok(posted) = result {
+            self.0 .1.record_post(posted);
         }
+
+        result
     }
 
     #[cfg(esp_idf_esp_event_post_from_isr)]
@@ -775,13 +1106,17 @@ where
             crate::hal::task::do_yield();
         }
 
-        if result == ESP_FAIL {
-            Ok(false)
+        let posted = if result == ESP_FAIL {
+            false
         } else {
             esp!(result)?;
 
-            Ok(true)
-        }
+            true
+        };
+
+        self.0 .1.record_post(posted);
+
+        Ok(posted)
     }
 }
 
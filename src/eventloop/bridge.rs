@@ -0,0 +1,273 @@
+//! Event bridge
+//!
+//! A utility which republishes selected events posted to an event loop
+//! over MQTT, a WebSocket connection, or any other user-provided `EventSink`.
+//!
+//! Useful for remote diagnostics, where a fleet of devices reports
+//! e.g. their WiFi or IP events to a central broker.
+//!
+//! As event loop payloads are untyped, the length of the payload of each
+//! bridged event needs to be provided in its `EventFilter`.
+
+use core::ffi;
+use core::fmt::{Debug, Write};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::mqtt::client::{Enqueue, QoS};
+use embedded_svc::ws::{FrameType, Sender};
+
+use crate::sys::EspError;
+
+use super::{EspEvent, EspEventLoop, EspEventLoopType, EspSubscription};
+
+/// Selects events to be bridged
+#[derive(Debug, Clone)]
+pub struct EventFilter {
+    /// The event base of the event
+    pub source: &'static ffi::CStr,
+    /// The event ID of the event, or `None` for all events of `source`
+    pub event_id: Option<i32>,
+    /// The length of the event payload which should be forwarded.
+    /// Use 0 to forward only the event base and ID
+    pub payload_len: usize,
+}
+
+impl EventFilter {
+    pub const fn new(source: &'static ffi::CStr) -> Self {
+        Self {
+            source,
+            event_id: None,
+            payload_len: 0,
+        }
+    }
+
+    pub const fn event_id(self, event_id: i32) -> Self {
+        Self {
+            event_id: Some(event_id),
+            ..self
+        }
+    }
+
+    pub const fn payload_len(self, payload_len: usize) -> Self {
+        Self {
+            payload_len,
+            ..self
+        }
+    }
+
+    fn matches(&self, event: &EspEvent) -> bool {
+        self.source == event.source
+            && self
+                .event_id
+                .map(|event_id| event_id == event.event_id)
+                .unwrap_or(true)
+    }
+}
+
+/// The wire format of the bridged events
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EventEncoding {
+    /// A JSON object of the form `{"base":"WIFI_EVENT","id":4,"payload":"<hex>"}`
+    Json,
+    /// The [postcard](https://docs.rs/postcard) encoding of the tuple
+    /// `(base: &str, id: i32, payload: &[u8])`
+    Postcard,
+}
+
+impl EventEncoding {
+    /// Encode the event with the provided payload into `buf`
+    pub fn encode(&self, event: &EspEvent, payload: &[u8], buf: &mut Vec<u8>) {
+        buf.clear();
+
+        let base = event.source.to_str().unwrap_or("?");
+
+        match self {
+            Self::Json => {
+                let mut json = String::new();
+
+                json.push_str("{\"base\":\"");
+
+                for c in base.chars() {
+                    if c == '"' || c == '\\' {
+                        json.push('\\');
+                    }
+
+                    json.push(c);
+                }
+
+                write!(&mut json, "\",\"id\":{},\"payload\":\"", event.event_id).unwrap();
+
+                for byte in payload {
+                    write!(&mut json, "{:02x}", byte).unwrap();
+                }
+
+                json.push_str("\"}");
+
+                buf.extend_from_slice(json.as_bytes());
+            }
+            Self::Postcard => {
+                write_varint(buf, base.len() as _);
+                buf.extend_from_slice(base.as_bytes());
+
+                // Postcard encodes signed integers as zig-zag varints
+                write_varint(
+                    buf,
+                    ((event.event_id << 1) ^ (event.event_id >> 31)) as u32 as _,
+                );
+
+                write_varint(buf, payload.len() as _);
+                buf.extend_from_slice(payload);
+            }
+        }
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+
+        value >>= 7;
+
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+
+        buf.push(byte | 0x80);
+    }
+}
+
+/// A destination for bridged events
+///
+/// Note that `send` is called from within the context of the event loop task
+/// and should therefore not block.
+pub trait EventSink {
+    type Error: Debug;
+
+    fn send(&mut self, encoding: EventEncoding, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// An `EventSink` which enqueues the bridged events for publishing to an MQTT topic
+pub struct MqttEventSink<C> {
+    client: C,
+    topic: String,
+    qos: QoS,
+}
+
+impl<C> MqttEventSink<C>
+where
+    C: Enqueue,
+{
+    pub fn new(client: C, topic: &str, qos: QoS) -> Self {
+        Self {
+            client,
+            topic: topic.into(),
+            qos,
+        }
+    }
+
+    pub fn release(self) -> C {
+        self.client
+    }
+}
+
+impl<C> EventSink for MqttEventSink<C>
+where
+    C: Enqueue,
+{
+    type Error = C::Error;
+
+    fn send(&mut self, _encoding: EventEncoding, data: &[u8]) -> Result<(), Self::Error> {
+        self.client.enqueue(&self.topic, self.qos, false, data)?;
+
+        Ok(())
+    }
+}
+
+/// An `EventSink` which sends the bridged events as WebSocket frames -
+/// text frames for JSON and binary frames for postcard
+pub struct WsEventSink<S>(S);
+
+impl<S> WsEventSink<S>
+where
+    S: Sender,
+{
+    pub const fn new(sender: S) -> Self {
+        Self(sender)
+    }
+
+    pub fn release(self) -> S {
+        self.0
+    }
+}
+
+impl<S> EventSink for WsEventSink<S>
+where
+    S: Sender,
+{
+    type Error = S::Error;
+
+    fn send(&mut self, encoding: EventEncoding, data: &[u8]) -> Result<(), Self::Error> {
+        let frame_type = match encoding {
+            EventEncoding::Json => FrameType::Text(false),
+            EventEncoding::Postcard => FrameType::Binary(false),
+        };
+
+        self.0.send(frame_type, data)
+    }
+}
+
+/// Republishes the events matching the provided filters to an `EventSink`
+/// for as long as the bridge is alive.
+pub struct EspEventBridge<T>
+where
+    T: EspEventLoopType,
+{
+    _subscription: EspSubscription<'static, T>,
+}
+
+impl<T> EspEventBridge<T>
+where
+    T: EspEventLoopType,
+{
+    pub fn new<S>(
+        event_loop: &EspEventLoop<T>,
+        filters: &[EventFilter],
+        encoding: EventEncoding,
+        mut sink: S,
+    ) -> Result<Self, EspError>
+    where
+        S: EventSink + Send + 'static,
+    {
+        let filters: Vec<EventFilter> = filters.to_vec();
+        let mut buf = Vec::new();
+
+        let subscription = event_loop.subscribe_any(move |event| {
+            if let Some(filter) = filters.iter().find(|filter| filter.matches(&event)) {
+                let payload = if filter.payload_len > 0 {
+                    unsafe { event.as_raw_payload(filter.payload_len) }.unwrap_or(&[])
+                } else {
+                    &[]
+                };
+
+                encoding.encode(&event, payload, &mut buf);
+
+                if let Err(err) = sink.send(encoding, &buf) {
+                    warn!(
+                        "Bridging event {:?}/{} failed: {:?}",
+                        event.source, event.event_id, err
+                    );
+                }
+            }
+        })?;
+
+        Ok(Self {
+            _subscription: subscription,
+        })
+    }
+}
@@ -0,0 +1,232 @@
+//! Posting typed events from ISRs
+//!
+//! `esp_event_isr_post` can neither allocate nor block, and only carries payloads up to the
+//! size of an `int`. Larger payloads are thus copied into the slots of an
+//! `EspIsrEventPool` allocated statically, and only the token of their slot is posted; the
+//! subscribers registered with `EspEventLoop::subscribe_from_isr` then get the payload back,
+//! which frees its slot:
+//!
+//! ```ignore
+//! #[derive(Copy, Clone, Debug)]
+//! struct ButtonPressed { pin: i32, at: u64 }
+//!
+//! unsafe impl EspEventSource for ButtonPressed {
+//!     fn source() -> Option<&'static CStr> {
+//!         Some(c"BUTTON")
+//!     }
+//! }
+//!
+//! static POOL: EspIsrEventPool<ButtonPressed, 8> = EspIsrEventPool::new();
+//!
+//! let _subscription = sysloop.subscribe_from_isr::<ButtonPressed, _, 8, _>(&POOL, |event| {
+//!     info!("{event:?}");
+//! })?;
+//!
+//! // In the GPIO ISR
+//! sysloop.post_from_isr::<ButtonPressed, _, 8>(&POOL, ButtonPressed { pin: 0, at })?;
+//! ```
+//!
+//! The payloads are bound to `MAX_ISR_PAYLOAD_LEN` bytes and the pools to 32 slots, both
+//! checked when the pool is instantiated. Each event posted should have exactly one subscriber:
+//! the slot of an event without subscriber is never freed, and the second subscriber of an
+//! event does not get its payload. A full pool drops the events, like a full event queue.
+
+use core::cell::UnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::hal::delay;
+use crate::hal::interrupt;
+
+use crate::sys::*;
+
+use super::{
+    EspEvent, EspEventLoop, EspEventLoopType, EspEventPostData, EspEventSource, EspSubscription,
+};
+
+/// The maximum length of the payload of an event posted from an ISR
+pub const MAX_ISR_PAYLOAD_LEN: usize = 64;
+
+const MAX_SLOTS: usize = 32;
+
+const INDEX_BITS: u32 = 5;
+
+/// A pool of `N` payloads of type `P`, to be posted from ISRs
+pub struct EspIsrEventPool<P, const N: usize> {
+    slots: UnsafeCell<[MaybeUninit<P>; N]>,
+    used: AtomicU32,
+    generations: [AtomicU32; N],
+}
+
+impl<P, const N: usize> EspIsrEventPool<P, N>
+where
+    P: Copy + Send + 'static,
+{
+    const BOUNDS: () = {
+        assert!(
+            mem::size_of::<P>() <= MAX_ISR_PAYLOAD_LEN,
+            "The payload is larger than MAX_ISR_PAYLOAD_LEN"
+        );
+        assert!(
+            N > 0 && N <= MAX_SLOTS,
+            "The pool should have 1 to 32 slots"
+        );
+    };
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const GENERATION: AtomicU32 = AtomicU32::new(0);
+
+    #[allow(clippy::let_unit_value)]
+    pub const fn new() -> Self {
+        let _ = Self::BOUNDS;
+
+        Self {
+            slots: UnsafeCell::new([MaybeUninit::uninit(); N]),
+            used: AtomicU32::new(0),
+            generations: [Self::GENERATION; N],
+        }
+    }
+
+    /// The number of slots not holding a payload
+    pub fn free_slots(&self) -> usize {
+        N - self.used.load(Ordering::Acquire).count_ones() as usize
+    }
+
+    /// Take the payload of an event posted with `EspEventLoop::post_from_isr`, freeing its slot
+    ///
+    /// Returns `None` if the event does not hold a token of this pool, or if the payload was
+    /// already taken.
+    pub fn take(&self, event: &EspEvent) -> Option<P> {
+        let token = unsafe { *(event.payload? as *const _ as *const u32) };
+
+        let index = (token & ((1 << INDEX_BITS) - 1)) as usize;
+        let generation = token >> INDEX_BITS;
+
+        if index >= N
+            || self.generations[index].load(Ordering::Acquire) != generation
+            || self.used.load(Ordering::Acquire) & (1 << index) == 0
+        {
+            return None;
+        }
+
+        let payload = unsafe { (*self.slots.get())[index].assume_init() };
+
+        // Only the first taker frees the slot, and gets the payload
+        if self.used.fetch_and(!(1 << index), Ordering::AcqRel) & (1 << index) == 0 {
+            return None;
+        }
+
+        Some(payload)
+    }
+
+    /// Copy `payload` into a free slot, returning the token of the slot
+    fn put(&self, payload: P) -> Option<u32> {
+        let mut used = self.used.load(Ordering::Relaxed);
+
+        let index = loop {
+            let index = (!used).trailing_zeros() as usize;
+
+            if index >= N {
+                return None;
+            }
+
+            match self.used.compare_exchange_weak(
+                used,
+                used | (1 << index),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break index,
+                Err(current) => used = current,
+            }
+        };
+
+        unsafe { (*self.slots.get())[index] = MaybeUninit::new(payload) };
+
+        let generation = self.generations[index]
+            .load(Ordering::Relaxed)
+            .wrapping_add(1)
+            & (u32::MAX >> INDEX_BITS);
+
+        self.generations[index].store(generation, Ordering::Release);
+
+        Some((generation << INDEX_BITS) | index as u32)
+    }
+
+    fn release(&self, token: u32) {
+        let index = token & ((1 << INDEX_BITS) - 1);
+
+        self.used.fetch_and(!(1 << index), Ordering::Release);
+    }
+}
+
+impl<P, const N: usize> Default for EspIsrEventPool<P, N>
+where
+    P: Copy + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The slots are only written while reserved, and only read by the taker of their token
+unsafe impl<P, const N: usize> Sync for EspIsrEventPool<P, N> where P: Send {}
+
+impl<T> EspEventLoop<T>
+where
+    T: EspEventLoopType,
+{
+    /// Post a typed event with a payload from `pool`, without allocating nor blocking
+    ///
+    /// Callable from an ISR as well as from a task. Returns `false` if the pool or the event
+    /// queue is full.
+    pub fn post_from_isr<S, P, const N: usize>(
+        &self,
+        pool: &'static EspIsrEventPool<P, N>,
+        payload: P,
+    ) -> Result<bool, EspError>
+    where
+        S: EspEventSource,
+        P: Copy + Send + 'static,
+    {
+        let source = S::source().ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        let Some(token) = pool.put(payload) else {
+            self.0 .1.record_post(false);
+
+            return Ok(false);
+        };
+
+        let data = unsafe { EspEventPostData::new(source, S::event_id(), &token) };
+
+        let result = if interrupt::active() {
+            self.isr_post_raw(&data)
+        } else {
+            self.post_raw(&data, delay::NON_BLOCK)
+        };
+
+        if !matches!(result, Ok(true)) {
+            pool.release(token);
+        }
+
+        result
+    }
+
+    /// Subscribe to the events of `S` posted with `post_from_isr` and `pool`
+    pub fn subscribe_from_isr<S, P, const N: usize, F>(
+        &self,
+        pool: &'static EspIsrEventPool<P, N>,
+        mut callback: F,
+    ) -> Result<EspSubscription<'static, T>, EspError>
+    where
+        S: EspEventSource,
+        P: Copy + Send + 'static,
+        F: FnMut(P) + Send + 'static,
+    {
+        self.subscribe_raw::<S, _>(move |event| {
+            if let Some(payload) = pool.take(&event) {
+                callback(payload);
+            }
+        })
+    }
+}
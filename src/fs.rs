@@ -1,4 +1,29 @@
 #[cfg(all(feature = "alloc", esp_idf_comp_fatfs_enabled))]
 pub mod fatfs;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_fatfs_enabled,
+    esp_idf_comp_wear_levelling_enabled,
+    esp_idf_comp_vfs_enabled,
+    not(esp_idf_version_major = "4")
+))]
+pub mod flash_fat;
+#[cfg(all(feature = "alloc", esp_idf_comp_joltwallet__littlefs_enabled))]
+pub mod littlefs;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_fatfs_enabled,
+    esp_idf_comp_vfs_enabled
+))]
+pub mod sdcard;
 #[cfg(all(feature = "alloc", esp_idf_comp_spiffs_enabled))]
 pub mod spiffs;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_soc_usb_otg_supported,
+    esp_idf_comp_espressif__esp_tinyusb_enabled,
+    esp_idf_tinyusb_msc_enabled,
+    esp_idf_comp_fatfs_enabled,
+    esp_idf_comp_wear_levelling_enabled
+))]
+pub mod usb_msc;
@@ -0,0 +1,252 @@
+//! FAT filesystems on the internal flash, with wear levelling
+//!
+//! The FAT filesystem sits on top of the wear-levelling layer of the ESP-IDF, which spreads the
+//! writes of the FAT sectors over all the flash sectors of the partition, so that the flash is
+//! not worn out by frequent writes - e.g. by a data logger.
+
+use core::ffi::{c_char, CStr};
+
+use alloc::ffi::CString;
+use alloc::vec;
+
+use crate::sys::*;
+
+extern crate alloc;
+
+/// Configuration for mounting a wear-levelled FAT partition.
+#[derive(Clone, Debug)]
+pub struct FlashFatConfiguration<'a> {
+    /// The path to mount the filesystem at.
+    pub base_path: &'a str,
+    /// The maximum number of files open at the same time.
+    pub max_files: usize,
+    /// Whether to format the partition if it cannot be mounted, e.g. on first boot.
+    pub format_if_mount_failed: bool,
+    /// The allocation unit (cluster) size in bytes when formatting, a multiple of the
+    /// wear-levelling sector size (`CONFIG_WL_SECTOR_SIZE`).
+    pub allocation_unit_size: usize,
+}
+
+impl FlashFatConfiguration<'_> {
+    /// Create a new default configuration
+    pub const fn new() -> Self {
+        Self {
+            base_path: "/fat",
+            max_files: 5,
+            format_if_mount_failed: false,
+            allocation_unit_size: 4096,
+        }
+    }
+}
+
+impl Default for FlashFatConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The usage of a FAT filesystem, in bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FatUsage {
+    pub total: u64,
+    pub free: u64,
+}
+
+/// The status of the wear-levelling layer of a partition.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WearLevellingStatus {
+    /// The size available to the filesystem, in bytes; the rest of the partition holds the
+    /// wear-levelling state and the spare sector.
+    pub size: usize,
+    /// The sector size, i.e. the granularity of the writes and erases.
+    pub sector_size: usize,
+}
+
+/// Represents a wear-levelled FAT partition of the internal flash, mounted with the VFS, so
+/// that it can be used with `std::fs`.
+/// The filesystem is automatically unmounted when the instance is dropped.
+pub struct EspFlashFat {
+    partition: CString,
+    base_path: CString,
+    handle: wl_handle_t,
+}
+
+impl EspFlashFat {
+    /// Mount a wear-levelled FAT partition.
+    ///
+    /// # Arguments
+    /// - `partition_label`: The label of the partition, of subtype `fat`.
+    /// - `config`: The mount configuration.
+    pub fn mount(partition_label: &str, config: &FlashFatConfiguration) -> Result<Self, EspError> {
+        let partition = crate::private::cstr::to_cstring_arg(partition_label)?;
+        let base_path = crate::private::cstr::to_cstring_arg(config.base_path)?;
+
+        #[allow(clippy::needless_update)]
+        let mount_config = esp_vfs_fat_mount_config_t {
+            format_if_mount_failed: config.format_if_mount_failed,
+            max_files: config.max_files as _,
+            allocation_unit_size: config.allocation_unit_size as _,
+            ..Default::default()
+        };
+
+        let mut handle = WL_INVALID_HANDLE;
+
+        esp!(unsafe {
+            esp_vfs_fat_spiflash_mount_rw_wl(
+                base_path.as_ptr(),
+                partition.as_ptr(),
+                &mount_config,
+                &mut handle,
+            )
+        })?;
+
+        Ok(Self {
+            partition,
+            base_path,
+            handle,
+        })
+    }
+
+    /// Get the path the filesystem is mounted at.
+    pub fn base_path(&self) -> &CStr {
+        &self.base_path
+    }
+
+    /// Format the partition, erasing all the files; it stays mounted.
+    #[cfg(not(all(esp_idf_version_major = "5", esp_idf_version_minor = "0")))]
+    pub fn format(&mut self) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_vfs_fat_spiflash_format_rw_wl(self.base_path.as_ptr(), self.partition.as_ptr())
+        })
+    }
+
+    /// Get the usage of the filesystem.
+    pub fn usage(&self) -> Result<FatUsage, EspError> {
+        let (fs, free_clusters) = self.fatfs()?;
+        let cluster_size = self.cluster_size(fs);
+
+        Ok(FatUsage {
+            total: (fs.n_fatent as u64 - 2) * cluster_size,
+            free: free_clusters as u64 * cluster_size,
+        })
+    }
+
+    /// Get the status of the wear-levelling layer.
+    pub fn wear_levelling(&self) -> WearLevellingStatus {
+        WearLevellingStatus {
+            size: unsafe { wl_size(self.handle) } as _,
+            sector_size: unsafe { wl_sector_size(self.handle) } as _,
+        }
+    }
+
+    /// Erase the free clusters of the filesystem, e.g. after removing files with sensitive
+    /// data, which FAT only marks as free.
+    ///
+    /// The files open for writing must be closed beforehand, and no file may be written
+    /// meanwhile, so that the FAT on flash is up to date. Only FAT16 and FAT32 are supported:
+    /// FAT12 - used by the partitions smaller than about 16MB with 4K clusters - returns
+    /// `ESP_ERR_NOT_SUPPORTED`.
+    pub fn trim(&mut self) -> Result<(), EspError> {
+        let (fs, _) = self.fatfs()?;
+
+        if fs.wflag != 0 {
+            // The cached FAT sector is not written back yet
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+        }
+
+        let entry_size = match fs.fs_type as u32 {
+            FS_FAT16 => 2,
+            FS_FAT32 => 4,
+            _ => Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())?,
+        };
+
+        let sector_size = unsafe { wl_sector_size(self.handle) };
+        let cluster_size = self.cluster_size(fs);
+        let clusters = fs.n_fatent as usize;
+
+        let mut sector = vec![0_u8; sector_size];
+        let mut loaded = None;
+
+        // The first free cluster of the current run of free clusters
+        let mut run = None;
+
+        for cluster in 2..=clusters {
+            let free = cluster < clusters && {
+                let offset = cluster * entry_size;
+                let fat_sector = offset / sector_size;
+
+                if loaded != Some(fat_sector) {
+                    esp!(unsafe {
+                        wl_read(
+                            self.handle,
+                            (fs.fatbase as usize + fat_sector) * sector_size,
+                            sector.as_mut_ptr() as *mut _,
+                            sector_size,
+                        )
+                    })?;
+
+                    loaded = Some(fat_sector);
+                }
+
+                let entry = &sector[offset % sector_size..][..entry_size];
+
+                if entry_size == 2 {
+                    u16::from_le_bytes([entry[0], entry[1]]) == 0
+                } else {
+                    u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) & 0x0fff_ffff == 0
+                }
+            };
+
+            match (free, run) {
+                (true, None) => run = Some(cluster),
+                (false, Some(first)) => {
+                    let start =
+                        fs.database as u64 * sector_size as u64 + (first as u64 - 2) * cluster_size;
+
+                    esp!(unsafe {
+                        wl_erase_range(
+                            self.handle,
+                            start as _,
+                            ((cluster - first) as u64 * cluster_size) as _,
+                        )
+                    })?;
+
+                    run = None;
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fatfs(&self) -> Result<(&FATFS, u32), EspError> {
+        let drive_path = self.drive_path();
+
+        let mut free_clusters = 0;
+        let mut fs = core::ptr::null_mut();
+
+        if unsafe { f_getfree(drive_path.as_ptr(), &mut free_clusters, &mut fs) } != FRESULT_FR_OK {
+            Err(EspError::from_infallible::<ESP_FAIL>())?;
+        }
+
+        Ok((unsafe { &*fs }, free_clusters as _))
+    }
+
+    fn cluster_size(&self, fs: &FATFS) -> u64 {
+        fs.csize as u64 * unsafe { wl_sector_size(self.handle) } as u64
+    }
+
+    fn drive_path(&self) -> [c_char; 3] {
+        let drive = unsafe { ff_diskio_get_pdrv_wl(self.handle) };
+
+        [(b'0' + drive) as _, b':' as _, 0]
+    }
+}
+
+impl Drop for EspFlashFat {
+    fn drop(&mut self) {
+        esp!(unsafe { esp_vfs_fat_spiflash_unmount_rw_wl(self.base_path.as_ptr(), self.handle) })
+            .unwrap();
+    }
+}
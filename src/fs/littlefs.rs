@@ -0,0 +1,335 @@
+use core::ffi::CStr;
+use core::time::Duration;
+
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::string::String;
+
+use config::Configuration;
+
+use crate::sys::*;
+
+extern crate alloc;
+
+pub mod config {
+    /// Configuration for mounting a LittleFS partition with the VFS.
+    #[derive(Clone, Debug)]
+    pub struct Configuration<'a> {
+        /// The path to mount the filesystem at.
+        pub base_path: &'a str,
+        /// Whether to format the partition if it cannot be mounted, e.g. on first boot.
+        pub format_if_mount_failed: bool,
+        /// Whether to mount the filesystem read-only.
+        pub read_only: bool,
+        /// Whether to grow the filesystem to the size of the partition, if the partition was
+        /// enlarged since the filesystem was formatted.
+        pub grow_on_mount: bool,
+    }
+
+    impl Configuration<'_> {
+        /// Create a new default configuration
+        pub const fn new() -> Self {
+            Self {
+                base_path: "/littlefs",
+                format_if_mount_failed: false,
+                read_only: false,
+                grow_on_mount: false,
+            }
+        }
+    }
+
+    impl Default for Configuration<'_> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+/// The size and usage of a LittleFS partition, in bytes.
+///
+/// LittleFS levels the wear dynamically over all the free blocks, so the free space is also the
+/// room left for wear levelling.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LittleFsInfo {
+    pub total: usize,
+    pub used: usize,
+}
+
+/// The type of a directory entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FileType {
+    File,
+    Dir,
+}
+
+/// The metadata of a file or directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// The size in bytes, for files.
+    pub size: u64,
+    /// The last modification time, since the Unix epoch.
+    ///
+    /// Only available with `CONFIG_LITTLEFS_USE_MTIME` enabled.
+    pub modified: Option<Duration>,
+}
+
+/// An entry of a directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// An iterator over the entries of a directory, other than `.` and `..`.
+pub struct ReadDir(*mut DIR);
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = unsafe { readdir(self.0) };
+            if entry.is_null() {
+                return None;
+            }
+
+            let entry = unsafe { &*entry };
+            let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) }.to_string_lossy();
+
+            if name == "." || name == ".." {
+                continue;
+            }
+
+            return Some(DirEntry {
+                name: name.into_owned(),
+                file_type: if entry.d_type as u32 == DT_DIR {
+                    FileType::Dir
+                } else {
+                    FileType::File
+                },
+            });
+        }
+    }
+}
+
+impl Drop for ReadDir {
+    fn drop(&mut self) {
+        unsafe {
+            closedir(self.0);
+        }
+    }
+}
+
+/// Represents a LittleFS partition mounted with the VFS, so that it can be used with `std::fs`.
+/// The filesystem is automatically unmounted when the instance is dropped.
+///
+/// The paths taken by the methods below are relative to the mount point.
+pub struct EspLittleFs {
+    partition: CString,
+    base_path: CString,
+}
+
+impl EspLittleFs {
+    /// Mount a LittleFS partition.
+    ///
+    /// # Arguments
+    /// - `partition_label`: The label of the partition.
+    /// - `config`: The mount configuration.
+    pub fn mount(partition_label: &str, config: &Configuration) -> Result<Self, EspError> {
+        let partition = crate::private::cstr::to_cstring_arg(partition_label)?;
+        let base_path = crate::private::cstr::to_cstring_arg(config.base_path)?;
+
+        let mut conf = esp_vfs_littlefs_conf_t {
+            base_path: base_path.as_ptr(),
+            partition_label: partition.as_ptr(),
+            ..Default::default()
+        };
+
+        conf.set_format_if_mount_failed(config.format_if_mount_failed as _);
+        conf.set_read_only(config.read_only as _);
+        conf.set_grow_on_mount(config.grow_on_mount as _);
+
+        esp!(unsafe { esp_vfs_littlefs_register(&conf) })?;
+
+        Ok(Self {
+            partition,
+            base_path,
+        })
+    }
+
+    /// Get the path the filesystem is mounted at.
+    pub fn base_path(&self) -> &CStr {
+        &self.base_path
+    }
+
+    /// Get the size and usage of the partition.
+    pub fn info(&self) -> Result<LittleFsInfo, EspError> {
+        let mut total = 0;
+        let mut used = 0;
+
+        esp!(unsafe { esp_littlefs_info(self.partition.as_ptr(), &mut total, &mut used) })?;
+
+        Ok(LittleFsInfo {
+            total: total as _,
+            used: used as _,
+        })
+    }
+
+    /// Format the partition, erasing all the files.
+    pub fn format(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_littlefs_format(self.partition.as_ptr()) })
+    }
+
+    /// Iterate over the entries of a directory.
+    pub fn read_dir(&self, path: &str) -> Result<ReadDir, EspError> {
+        let path = self.path(path)?;
+
+        let dir = unsafe { opendir(path.as_ptr()) };
+        if dir.is_null() {
+            return Err(last_error());
+        }
+
+        Ok(ReadDir(dir))
+    }
+
+    /// Walk a directory and all its subdirectories, calling `f` with the path - relative to the
+    /// mount point - and the entry of each file and directory found.
+    ///
+    /// The directories are visited before their content.
+    pub fn walk<F>(&self, path: &str, mut f: F) -> Result<(), EspError>
+    where
+        F: FnMut(&str, &DirEntry),
+    {
+        self.walk_dir(path.trim_end_matches('/'), &mut f)
+    }
+
+    /// Get the metadata of a file or directory.
+    pub fn metadata(&self, path: &str) -> Result<Metadata, EspError> {
+        let path = self.path(path)?;
+
+        let mut st: stat = Default::default();
+        if unsafe { stat(path.as_ptr(), &mut st) } != 0 {
+            return Err(last_error());
+        }
+
+        let file_type = if st.st_mode & S_IFMT == S_IFDIR {
+            FileType::Dir
+        } else {
+            FileType::File
+        };
+
+        #[cfg(all(esp_idf_littlefs_use_mtime, not(esp_idf_version_major = "4")))]
+        let modified = Some(Duration::from_secs(st.st_mtim.tv_sec as _));
+        #[cfg(not(all(esp_idf_littlefs_use_mtime, not(esp_idf_version_major = "4"))))]
+        let modified = None;
+
+        Ok(Metadata {
+            file_type,
+            size: st.st_size as _,
+            modified,
+        })
+    }
+
+    /// Whether a file or directory exists.
+    pub fn exists(&self, path: &str) -> Result<bool, EspError> {
+        match self.metadata(path) {
+            Ok(_) => Ok(true),
+            Err(err) if err.code() == ESP_ERR_NOT_FOUND => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Rename or move a file or directory, replacing `to` if it is an existing file.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), EspError> {
+        let from = self.path(from)?;
+        let to = self.path(to)?;
+
+        check(unsafe { rename(from.as_ptr(), to.as_ptr()) })
+    }
+
+    /// Remove a file.
+    pub fn remove_file(&self, path: &str) -> Result<(), EspError> {
+        let path = self.path(path)?;
+
+        check(unsafe { unlink(path.as_ptr()) })
+    }
+
+    /// Create a directory.
+    pub fn create_dir(&self, path: &str) -> Result<(), EspError> {
+        let path = self.path(path)?;
+
+        check(unsafe { mkdir(path.as_ptr(), 0o775) })
+    }
+
+    /// Remove an empty directory.
+    pub fn remove_dir(&self, path: &str) -> Result<(), EspError> {
+        let path = self.path(path)?;
+
+        check(unsafe { rmdir(path.as_ptr()) })
+    }
+
+    /// Remove a directory with all its content.
+    pub fn remove_dir_all(&self, path: &str) -> Result<(), EspError> {
+        let path = path.trim_end_matches('/');
+
+        for entry in self.read_dir(path)? {
+            let child = format!("{path}/{}", entry.name);
+
+            match entry.file_type {
+                FileType::Dir => self.remove_dir_all(&child)?,
+                FileType::File => self.remove_file(&child)?,
+            }
+        }
+
+        self.remove_dir(path)
+    }
+
+    fn walk_dir(&self, path: &str, f: &mut dyn FnMut(&str, &DirEntry)) -> Result<(), EspError> {
+        for entry in self.read_dir(path)? {
+            let child = format!("{path}/{}", entry.name);
+
+            f(&child, &entry);
+
+            if entry.file_type == FileType::Dir {
+                self.walk_dir(&child, f)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn path(&self, path: &str) -> Result<CString, EspError> {
+        let base_path = self.base_path.to_str().unwrap();
+
+        crate::private::cstr::to_cstring_arg(&format!(
+            "{}/{}",
+            base_path.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        ))
+    }
+}
+
+impl Drop for EspLittleFs {
+    fn drop(&mut self) {
+        esp!(unsafe { esp_vfs_littlefs_unregister(self.partition.as_ptr()) }).unwrap();
+    }
+}
+
+fn check(ret: i32) -> Result<(), EspError> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(last_error())
+    }
+}
+
+fn last_error() -> EspError {
+    match unsafe { *__errno() } as u32 {
+        ENOENT => EspError::from_infallible::<ESP_ERR_NOT_FOUND>(),
+        EEXIST | ENOTEMPTY => EspError::from_infallible::<ESP_ERR_INVALID_STATE>(),
+        ENOSPC => EspError::from_infallible::<ESP_ERR_NO_MEM>(),
+        EINVAL | ENAMETOOLONG => EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+        _ => EspError::from_infallible::<ESP_FAIL>(),
+    }
+}
@@ -0,0 +1,349 @@
+//! FAT filesystems on SD cards, mounted with the VFS
+//!
+//! The SD card is driven with `hal::sd::SdCardDriver`, over an SDMMC host (1 or 4-bit bus) or
+//! an SPI host; the bus frequency, and the CD and WP pins the host driver should check, are
+//! configured there. `EspSdCard` mounts the FAT filesystem of the card with the VFS, so that
+//! it can be used with `std::fs`, and `CardDetect` reports the insertion and removal of the
+//! card with a card-detect switch, so that it can be remounted safely.
+
+use core::borrow::BorrowMut;
+use core::ffi::{c_char, CStr};
+use core::marker::PhantomData;
+
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec;
+
+use log::warn;
+
+use crate::hal::gpio::{Input, InputPin, PinDriver};
+use crate::hal::peripheral::Peripheral;
+use crate::hal::sd::SdCardDriver;
+use crate::sys::*;
+
+extern crate alloc;
+
+// The size of the work buffer of `f_mkfs`, i.e. `FF_MAX_SS`
+const FORMAT_BUF_SIZE: usize = 4096;
+
+/// Configuration for mounting the FAT filesystem of an SD card.
+#[derive(Clone, Debug)]
+pub struct SdMountConfiguration<'a> {
+    /// The path to mount the filesystem at.
+    pub base_path: &'a str,
+    /// The maximum number of files open at the same time.
+    pub max_files: usize,
+    /// Whether to format the card if it has no FAT filesystem.
+    pub format_if_mount_failed: bool,
+    /// The allocation unit (cluster) size in bytes when formatting, a power of 2 between the
+    /// sector size and 128 * the sector size; 0 for the default of the card size.
+    pub allocation_unit_size: u32,
+}
+
+impl SdMountConfiguration<'_> {
+    /// Create a new default configuration
+    pub const fn new() -> Self {
+        Self {
+            base_path: "/sdcard",
+            max_files: 5,
+            format_if_mount_failed: false,
+            allocation_unit_size: 16 * 1024,
+        }
+    }
+}
+
+impl Default for SdMountConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The identification of an SD card, from its CID register.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CardId {
+    pub manufacturer_id: u8,
+    pub oem_id: u16,
+    pub name: String,
+    pub revision: u8,
+    pub serial: u32,
+    /// The manufacturing date, as `(year, month)`
+    pub date: (u16, u8),
+}
+
+/// The information of an SD card.
+///
+/// The speed class of the card is not reported, as the SD driver of the ESP-IDF does not read
+/// it; `max_freq_khz` tells whether the card supports the high-speed mode (50 MHz) instead.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CardInfo {
+    pub id: CardId,
+    /// The capacity in bytes.
+    pub capacity: u64,
+    pub sector_size: u32,
+    /// The maximum bus frequency supported by the card, in kHz.
+    pub max_freq_khz: u32,
+    /// Whether the card is an MMC rather than an SD card.
+    pub is_mmc: bool,
+}
+
+/// Represents the FAT filesystem of an SD card, mounted with the VFS.
+/// The filesystem is automatically unmounted when the instance is dropped.
+pub struct EspSdCard<T, H>
+where
+    T: BorrowMut<SdCardDriver<H>>,
+{
+    driver: Option<T>,
+    fatfs: *mut FATFS,
+    drive: u8,
+    base_path: CString,
+    _host: PhantomData<fn() -> H>,
+}
+
+impl<T, H> EspSdCard<T, H>
+where
+    T: BorrowMut<SdCardDriver<H>>,
+{
+    /// Mount the FAT filesystem of the card.
+    ///
+    /// # Arguments
+    /// - `driver`: The SD card driver, with the card initialized.
+    /// - `config`: The mount configuration.
+    pub fn mount(mut driver: T, config: &SdMountConfiguration) -> Result<Self, EspError> {
+        let base_path = crate::private::cstr::to_cstring_arg(config.base_path)?;
+
+        let mut drive = 0xff;
+        esp!(unsafe { ff_diskio_get_drive(&mut drive) })?;
+
+        if drive == 0xff {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        unsafe {
+            ff_diskio_register_sdmmc(drive, driver.borrow_mut().card() as *const _ as *mut _);
+        }
+
+        let drive_path = Self::drive_path(drive);
+        let mut fatfs = core::ptr::null_mut();
+
+        if let Err(err) = esp!(unsafe {
+            esp_vfs_fat_register(
+                base_path.as_ptr(),
+                drive_path.as_ptr(),
+                config.max_files as _,
+                &mut fatfs,
+            )
+        }) {
+            unsafe { ff_diskio_register(drive, core::ptr::null()) };
+
+            return Err(err);
+        }
+
+        let mut this = Self {
+            driver: Some(driver),
+            fatfs,
+            drive,
+            base_path,
+            _host: PhantomData,
+        };
+
+        this.mount_fatfs(config)?;
+
+        Ok(this)
+    }
+
+    /// Get the path the filesystem is mounted at.
+    pub fn base_path(&self) -> &CStr {
+        &self.base_path
+    }
+
+    /// Get the information of the card.
+    pub fn info(&self) -> CardInfo {
+        let card = self.driver.as_ref().unwrap().borrow().card();
+        let cid = &card.cid;
+
+        let name = unsafe { CStr::from_ptr(cid.name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        CardInfo {
+            id: CardId {
+                manufacturer_id: cid.mfg_id as _,
+                oem_id: cid.oem_id as _,
+                name,
+                revision: cid.revision as _,
+                serial: cid.serial as _,
+                date: (2000 + (cid.date >> 4) as u16, (cid.date & 0xf) as u8),
+            },
+            capacity: card.csd.capacity as u64 * card.csd.sector_size as u64,
+            sector_size: card.csd.sector_size as _,
+            max_freq_khz: card.max_freq_khz as _,
+            is_mmc: card.is_mmc() != 0,
+        }
+    }
+
+    /// Check that the card still responds, e.g. after a card-detect event.
+    pub fn status(&self) -> Result<(), EspError> {
+        let card = self.driver.as_ref().unwrap().borrow().card();
+
+        esp!(unsafe { sdmmc_get_status(card as *const _ as *mut _) })
+    }
+
+    /// Unmount the filesystem and return the SD card driver, e.g. to initialize a newly
+    /// inserted card with a new driver before mounting it again.
+    pub fn unmount(mut self) -> Result<T, EspError> {
+        self.unmount_fatfs()?;
+
+        Ok(self.driver.take().unwrap())
+    }
+
+    fn mount_fatfs(&mut self, config: &SdMountConfiguration) -> Result<(), EspError> {
+        let drive_path = Self::drive_path(self.drive);
+
+        let mut res = unsafe { f_mount(self.fatfs, drive_path.as_ptr(), 1) };
+
+        if res == FRESULT_FR_NO_FILESYSTEM && config.format_if_mount_failed {
+            warn!("No FAT filesystem on the SD card, formatting");
+
+            res = self.format(config.allocation_unit_size);
+
+            if res == FRESULT_FR_OK {
+                res = unsafe { f_mount(self.fatfs, drive_path.as_ptr(), 1) };
+            }
+        }
+
+        if res != FRESULT_FR_OK {
+            warn!("Mount failed: {res}");
+
+            self.unmount_fatfs()?;
+
+            Err(EspError::from_infallible::<ESP_FAIL>())?
+        }
+
+        Ok(())
+    }
+
+    fn format(&mut self, allocation_unit_size: u32) -> FRESULT {
+        let drive_path = Self::drive_path(self.drive);
+        let mut buf = vec![0_u8; FORMAT_BUF_SIZE];
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        {
+            let opt = MKFS_PARM {
+                fmt: FM_ANY as _,
+                au_size: allocation_unit_size,
+                n_fat: 1,
+                n_root: 0,
+                align: 0,
+            };
+
+            unsafe {
+                f_mkfs(
+                    drive_path.as_ptr(),
+                    &opt,
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len() as _,
+                )
+            }
+        }
+
+        #[cfg(esp_idf_version_major = "4")]
+        unsafe {
+            f_mkfs(
+                drive_path.as_ptr(),
+                FM_ANY as _,
+                allocation_unit_size,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as _,
+            )
+        }
+    }
+
+    fn unmount_fatfs(&mut self) -> Result<(), EspError> {
+        if self.fatfs.is_null() {
+            return Ok(());
+        }
+
+        let drive_path = Self::drive_path(self.drive);
+
+        unsafe {
+            f_mount(core::ptr::null_mut(), drive_path.as_ptr(), 0);
+            ff_diskio_register(self.drive, core::ptr::null());
+        }
+
+        self.fatfs = core::ptr::null_mut();
+
+        esp!(unsafe { esp_vfs_fat_unregister_path(self.base_path.as_ptr()) })
+    }
+
+    fn drive_path(drive: u8) -> [c_char; 3] {
+        [(b'0' + drive) as _, b':' as _, 0]
+    }
+}
+
+impl<T, H> Drop for EspSdCard<T, H>
+where
+    T: BorrowMut<SdCardDriver<H>>,
+{
+    fn drop(&mut self) {
+        self.unmount_fatfs().unwrap();
+    }
+}
+
+unsafe impl<T, H> Send for EspSdCard<T, H> where T: BorrowMut<SdCardDriver<H>> + Send {}
+
+/// A change of the card-detect switch.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CardEvent {
+    Inserted,
+    Removed,
+}
+
+/// The card-detect switch of an SD card slot, to handle the hot plugging of cards.
+///
+/// Do not pass the same pin as CD pin to the host driver. The pin needs a pull-up or pull-down,
+/// usually on the board.
+pub struct CardDetect<'d, P: InputPin> {
+    pin: PinDriver<'d, P, Input>,
+    active_low: bool,
+}
+
+impl<'d, P: InputPin> CardDetect<'d, P> {
+    /// Create the card-detect input.
+    ///
+    /// # Arguments
+    /// - `pin`: The card-detect pin.
+    /// - `active_low`: Whether the pin is low when a card is inserted, as with most slots.
+    pub fn new(pin: impl Peripheral<P = P> + 'd, active_low: bool) -> Result<Self, EspError> {
+        Ok(Self {
+            pin: PinDriver::input(pin)?,
+            active_low,
+        })
+    }
+
+    /// Whether a card is inserted.
+    pub fn is_inserted(&self) -> bool {
+        self.pin.is_low() == self.active_low
+    }
+
+    /// Wait until a card is inserted or removed.
+    ///
+    /// The switch may bounce, so wait a bit - e.g. 100ms - and check `is_inserted` before
+    /// initializing a newly inserted card.
+    pub async fn wait_for_change(&mut self) -> Result<CardEvent, EspError> {
+        let inserted = self.is_inserted();
+
+        loop {
+            self.pin.wait_for_any_edge().await?;
+
+            if self.is_inserted() != inserted {
+                break;
+            }
+        }
+
+        Ok(if inserted {
+            CardEvent::Removed
+        } else {
+            CardEvent::Inserted
+        })
+    }
+}
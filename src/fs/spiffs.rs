@@ -2,10 +2,133 @@ use core::ffi::CStr;
 
 use alloc::ffi::CString;
 
+use config::Configuration;
+
 use crate::sys::*;
 
 extern crate alloc;
 
+pub mod config {
+    /// Configuration for mounting a SPIFFS partition with the VFS.
+    #[derive(Clone, Debug)]
+    pub struct Configuration<'a> {
+        /// The path to mount the filesystem at.
+        pub base_path: &'a str,
+        /// The maximum number of files open at the same time.
+        pub max_files: usize,
+        /// Whether to format the partition if it cannot be mounted, e.g. on first boot.
+        pub format_if_mount_failed: bool,
+    }
+
+    impl Configuration<'_> {
+        /// Create a new default configuration
+        pub const fn new() -> Self {
+            Self {
+                base_path: "/spiffs",
+                max_files: 5,
+                format_if_mount_failed: false,
+            }
+        }
+    }
+
+    impl Default for Configuration<'_> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+/// The size and usage of a SPIFFS partition, in bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SpiffsInfo {
+    pub total: usize,
+    pub used: usize,
+}
+
+/// Represents a SPIFFS partition mounted with the VFS, so that it can be used with `std::fs`.
+/// The filesystem is automatically unmounted when the instance is dropped.
+pub struct EspSpiffs {
+    partition: Option<CString>,
+    base_path: CString,
+}
+
+impl EspSpiffs {
+    /// Mount a SPIFFS partition.
+    ///
+    /// # Arguments
+    /// - `partition_label`: The label of the partition, or `None` for the first SPIFFS
+    ///   partition in the partition table.
+    /// - `config`: The mount configuration.
+    pub fn mount(partition_label: Option<&str>, config: &Configuration) -> Result<Self, EspError> {
+        let partition = partition_label
+            .map(crate::private::cstr::to_cstring_arg)
+            .transpose()?;
+        let base_path = crate::private::cstr::to_cstring_arg(config.base_path)?;
+
+        esp!(unsafe {
+            esp_vfs_spiffs_register(&esp_vfs_spiffs_conf_t {
+                base_path: base_path.as_ptr(),
+                partition_label: partition
+                    .as_ref()
+                    .map_or(core::ptr::null(), |partition| partition.as_ptr()),
+                max_files: config.max_files as _,
+                format_if_mount_failed: config.format_if_mount_failed,
+            })
+        })?;
+
+        Ok(Self {
+            partition,
+            base_path,
+        })
+    }
+
+    /// Get the path the filesystem is mounted at.
+    pub fn base_path(&self) -> &CStr {
+        &self.base_path
+    }
+
+    /// Get the size and usage of the partition.
+    pub fn info(&self) -> Result<SpiffsInfo, EspError> {
+        let mut total = 0;
+        let mut used = 0;
+
+        esp!(unsafe { esp_spiffs_info(self.partition_ptr(), &mut total, &mut used) })?;
+
+        Ok(SpiffsInfo {
+            total: total as _,
+            used: used as _,
+        })
+    }
+
+    /// Check the filesystem for errors.
+    pub fn check(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_spiffs_check(self.partition_ptr()) })
+    }
+
+    /// Format the partition, erasing all the files.
+    pub fn format(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_spiffs_format(self.partition_ptr()) })
+    }
+
+    /// Garbage collect the filesystem, until at least `size_to_gc` bytes are free.
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn gc(&mut self, size_to_gc: usize) -> Result<(), EspError> {
+        esp!(unsafe { esp_spiffs_gc(self.partition_ptr(), size_to_gc) })
+    }
+
+    fn partition_ptr(&self) -> *const core::ffi::c_char {
+        self.partition
+            .as_ref()
+            .map_or(core::ptr::null(), |partition| partition.as_ptr())
+    }
+}
+
+impl Drop for EspSpiffs {
+    fn drop(&mut self) {
+        esp!(unsafe { esp_vfs_spiffs_unregister(self.partition_ptr()) }).unwrap();
+    }
+}
+
 /// Represents a Spiffs filesystem.
 pub struct Spiffs {
     partition: CString,
@@ -0,0 +1,285 @@
+//! USB Mass Storage exposure of a FAT filesystem
+//!
+//! On the chips with USB-OTG, `EspUsbMsc` exposes a wear-levelled FAT partition of the internal
+//! flash, or the FAT filesystem of an SD card, as a USB Mass Storage device, so that the data
+//! logged by the firmware can be pulled by plugging in a cable. It is built on the
+//! `espressif/esp_tinyusb` component, with `CONFIG_TINYUSB_MSC_ENABLED`; MTP is not supported
+//! by TinyUSB.
+//!
+//! The filesystem is either mounted with the VFS - at the base path given - for the firmware,
+//! or exposed to the USB host, never both: FAT is not safe for concurrent writers. It is
+//! exposed to the host when the host mounts it, and mounted back with the VFS when the host
+//! ejects it; `EspUsbMsc::set_mount_callback` reports these changes to the firmware.
+
+use core::borrow::BorrowMut;
+use core::ffi::CStr;
+use core::marker::PhantomData;
+
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+
+use crate::hal::sd::SdCardDriver;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+extern crate alloc;
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+#[allow(clippy::type_complexity)]
+static CALLBACK: Mutex<Option<Box<dyn FnMut(bool) + Send>>> = Mutex::new(None);
+
+/// Configuration of the USB Mass Storage device.
+#[derive(Clone, Debug)]
+pub struct UsbMscConfiguration<'a> {
+    /// The path to mount the filesystem at, while the firmware uses it.
+    pub base_path: &'a str,
+    /// The maximum number of files open at the same time by the firmware.
+    pub max_files: usize,
+    /// Whether to format the storage if it has no FAT filesystem.
+    pub format_if_mount_failed: bool,
+    /// The allocation unit (cluster) size in bytes when formatting.
+    pub allocation_unit_size: usize,
+}
+
+impl UsbMscConfiguration<'_> {
+    /// Create a new default configuration
+    pub const fn new() -> Self {
+        Self {
+            base_path: "/usb",
+            max_files: 5,
+            format_if_mount_failed: false,
+            allocation_unit_size: 4096,
+        }
+    }
+}
+
+impl Default for UsbMscConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+enum Storage {
+    Flash(wl_handle_t),
+    SdCard,
+}
+
+/// A FAT filesystem exposed as a USB Mass Storage device.
+///
+/// `T` is the SD card driver owned, or `()` for a partition of the internal flash.
+pub struct EspUsbMsc<T = (), H = ()> {
+    storage: Storage,
+    base_path: CString,
+    _driver: T,
+    _host: PhantomData<fn() -> H>,
+}
+
+impl EspUsbMsc<(), ()> {
+    /// Expose a wear-levelled FAT partition of the internal flash.
+    ///
+    /// The partition must not be mounted otherwise, e.g. with `flash_fat::EspFlashFat`.
+    ///
+    /// # Arguments
+    /// - `partition_label`: The label of the partition, of subtype `fat`.
+    /// - `config`: The configuration.
+    pub fn new_flash(
+        partition_label: &str,
+        config: &UsbMscConfiguration,
+    ) -> Result<Self, EspError> {
+        let partition_label = crate::private::cstr::to_cstring_arg(partition_label)?;
+        let base_path = crate::private::cstr::to_cstring_arg(config.base_path)?;
+
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_FAT,
+                partition_label.as_ptr(),
+            )
+        };
+
+        if partition.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        }
+
+        let _taken = Self::take()?;
+
+        let mut handle = WL_INVALID_HANDLE;
+        esp!(unsafe { wl_mount(partition, &mut handle) })?;
+
+        let msc_config = tinyusb_msc_spiflash_config_t {
+            wl_handle: handle,
+            callback_mount_changed: Some(Self::mount_changed),
+            mount_config: Self::mount_config(config),
+            ..Default::default()
+        };
+
+        if let Err(err) = esp!(unsafe { tinyusb_msc_storage_init_spiflash(&msc_config) }) {
+            unsafe { wl_unmount(handle) };
+
+            return Err(err);
+        }
+
+        Self::start(Storage::Flash(handle), base_path, (), _taken)
+    }
+}
+
+impl<T, H> EspUsbMsc<T, H>
+where
+    T: BorrowMut<SdCardDriver<H>>,
+{
+    /// Expose the FAT filesystem of an SD card.
+    ///
+    /// # Arguments
+    /// - `driver`: The SD card driver, with the card initialized.
+    /// - `config`: The configuration.
+    pub fn new_sdcard(mut driver: T, config: &UsbMscConfiguration) -> Result<Self, EspError> {
+        let base_path = crate::private::cstr::to_cstring_arg(config.base_path)?;
+
+        let _taken = Self::take()?;
+
+        let msc_config = tinyusb_msc_sdmmc_config_t {
+            card: driver.borrow_mut().card() as *const _ as *mut _,
+            callback_mount_changed: Some(Self::mount_changed),
+            mount_config: Self::mount_config(config),
+            ..Default::default()
+        };
+
+        esp!(unsafe { tinyusb_msc_storage_init_sdmmc(&msc_config) })?;
+
+        Self::start(Storage::SdCard, base_path, driver, _taken)
+    }
+}
+
+impl<T, H> EspUsbMsc<T, H> {
+    /// Get the path the filesystem is mounted at, while the firmware uses it.
+    pub fn base_path(&self) -> &CStr {
+        &self.base_path
+    }
+
+    /// Whether the filesystem is exposed to the USB host, and so not accessible by the
+    /// firmware.
+    pub fn is_exposed(&self) -> bool {
+        unsafe { tinyusb_msc_storage_in_use_by_usb_host() }
+    }
+
+    /// Unmount the filesystem from the VFS, and expose it to the USB host.
+    ///
+    /// All the files must be closed beforehand.
+    pub fn expose(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { tinyusb_msc_storage_unmount() })
+    }
+
+    /// Mount the filesystem back with the VFS, for the firmware. The USB host sees the storage
+    /// as ejected.
+    pub fn reclaim(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { tinyusb_msc_storage_mount(self.base_path.as_ptr()) })
+    }
+
+    /// The size of the storage, in bytes.
+    pub fn capacity(&self) -> u64 {
+        unsafe {
+            tinyusb_msc_storage_get_sector_count() as u64
+                * tinyusb_msc_storage_get_sector_size() as u64
+        }
+    }
+
+    /// Set the callback called when the filesystem is mounted for the firmware (`true`) or
+    /// exposed to the USB host (`false`).
+    ///
+    /// The callback is called from the TinyUSB task, so it should not block.
+    pub fn set_mount_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(bool) + Send + 'static,
+    {
+        *CALLBACK.lock() = Some(Box::new(callback));
+    }
+
+    fn take() -> Result<Taken, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        *taken = true;
+
+        Ok(Taken(()))
+    }
+
+    fn start(
+        storage: Storage,
+        base_path: CString,
+        driver: T,
+        taken: Taken,
+    ) -> Result<Self, EspError> {
+        let this = Self {
+            storage,
+            base_path,
+            _driver: driver,
+            _host: PhantomData,
+        };
+
+        // From now on, released by `Drop` of `this`
+        core::mem::forget(taken);
+
+        this.install()?;
+
+        Ok(this)
+    }
+
+    fn install(&self) -> Result<(), EspError> {
+        esp!(unsafe { tinyusb_msc_storage_mount(self.base_path.as_ptr()) })?;
+
+        // With the descriptors of the component, from the Kconfig settings
+        esp!(unsafe { tinyusb_driver_install(&Default::default()) })
+    }
+
+    fn mount_config(config: &UsbMscConfiguration) -> esp_vfs_fat_mount_config_t {
+        #[allow(clippy::needless_update)]
+        esp_vfs_fat_mount_config_t {
+            format_if_mount_failed: config.format_if_mount_failed,
+            max_files: config.max_files as _,
+            allocation_unit_size: config.allocation_unit_size as _,
+            ..Default::default()
+        }
+    }
+
+    unsafe extern "C" fn mount_changed(event: *mut tinyusb_msc_event_t) {
+        let mounted = (*event).__bindgen_anon_1.mount_changed_data.is_mounted;
+
+        if let Some(callback) = CALLBACK.lock().as_mut() {
+            callback(mounted);
+        }
+    }
+}
+
+impl<T, H> Drop for EspUsbMsc<T, H> {
+    fn drop(&mut self) {
+        unsafe {
+            tinyusb_driver_uninstall();
+
+            if !tinyusb_msc_storage_in_use_by_usb_host() {
+                tinyusb_msc_storage_unmount();
+            }
+
+            tinyusb_msc_storage_deinit();
+
+            if let Storage::Flash(handle) = self.storage {
+                wl_unmount(handle);
+            }
+        }
+
+        *CALLBACK.lock() = None;
+        *TAKEN.lock() = false;
+    }
+}
+
+// Releases the singleton if the construction fails
+struct Taken(());
+
+impl Drop for Taken {
+    fn drop(&mut self) {
+        *TAKEN.lock() = false;
+    }
+}
@@ -33,6 +33,9 @@ pub use embedded_svc::http::client::{Connection, Request, Response};
 
 pub use super::*;
 
+#[cfg(feature = "json")]
+pub mod rest;
+
 impl From<Method> for Newtype<(esp_http_client_method_t, ())> {
     fn from(method: Method) -> Self {
         Self((
@@ -83,6 +86,24 @@ pub struct Configuration {
     pub follow_redirects_policy: FollowRedirectsPolicy,
     pub client_certificate: Option<X509<'static>>,
     pub private_key: Option<X509<'static>>,
+    /// Use the private key held by the ATECC608 secure element (ESP IDF 5.1+)
+    #[cfg(all(
+        not(esp_idf_version_major = "4"),
+        any(not(esp_idf_version_major = "5"), not(esp_idf_version_minor = "0")),
+        esp_idf_esp_tls_use_secure_element
+    ))]
+    pub use_secure_element: bool,
+    /// Use the private key held by the Digital Signature peripheral, instead of `private_key`
+    /// (ESP IDF 5.1+)
+    #[cfg(all(
+        not(esp_idf_version_major = "4"),
+        any(not(esp_idf_version_major = "5"), not(esp_idf_version_minor = "0")),
+        esp_idf_comp_esp_tls_enabled,
+        esp_idf_esp_tls_using_mbedtls,
+        esp_idf_esp_tls_use_ds_peripheral,
+        feature = "alloc"
+    ))]
+    pub ds_key: Option<&'static crate::tls::ds::DsKey>,
     pub use_global_ca_store: bool,
     pub crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut core::ffi::c_void) -> esp_err_t>,
     pub raw_request_body: bool,
@@ -147,6 +168,31 @@ impl EspHttpConnection {
             native_config.client_key_len = private_key.as_esp_idf_raw_len();
         }
 
+        #[cfg(all(
+            not(esp_idf_version_major = "4"),
+            any(not(esp_idf_version_major = "5"), not(esp_idf_version_minor = "0")),
+            esp_idf_esp_tls_use_secure_element
+        ))]
+        {
+            native_config.use_secure_element = configuration.use_secure_element;
+        }
+
+        #[cfg(all(
+            not(esp_idf_version_major = "4"),
+            any(not(esp_idf_version_major = "5"), not(esp_idf_version_minor = "0")),
+            esp_idf_comp_esp_tls_enabled,
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_esp_tls_use_ds_peripheral,
+            feature = "alloc"
+        ))]
+        if let (Some(cert), Some(ds_key)) = (configuration.client_certificate, configuration.ds_key)
+        {
+            native_config.client_cert_pem = cert.as_esp_idf_raw_ptr() as _;
+            native_config.client_cert_len = cert.as_esp_idf_raw_len();
+
+            native_config.ds_data = ds_key.as_ds_data();
+        }
+
         let raw_client = unsafe { esp_http_client_init(&native_config) };
         if raw_client.is_null() {
             Err(EspError::from_infallible::<ESP_FAIL>())
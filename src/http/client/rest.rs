@@ -0,0 +1,273 @@
+//! Typed REST client
+//!
+//! `Client` wraps an `EspHttpConnection` for JSON APIs: the request bodies are serialized and the
+//! response bodies deserialized with `serde`, the `Content-Type` and `Accept` headers are set,
+//! and the responses with a non-2xx status are mapped to an error carrying the body:
+//!
+//! ```ignore
+//! let mut client = rest::Client::new(&Configuration {
+//!     crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+//!     ..Default::default()
+//! })?;
+//!
+//! client.set_header("Authorization", "Bearer ...");
+//!
+//! let device: Device = client.get_json("https://api.example.com/devices/42")?;
+//! let created: Device = client.post_json("https://api.example.com/devices", &new_device)?;
+//! ```
+//!
+//! A response with an empty body deserializes as JSON `null`, i.e. into `()` or `None`.
+
+use core::fmt::{self, Debug, Display};
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::sys::EspError;
+
+use super::{Configuration, EspHttpConnection, Method};
+
+const JSON: &str = "application/json";
+
+/// An error of a REST request
+#[derive(Debug)]
+pub enum RestError {
+    /// The request failed
+    Esp(EspError),
+    /// The server answered with a non-2xx status; `body` holds the start of the response body,
+    /// up to `max_error_body_len` bytes
+    Status { status: u16, body: Vec<u8> },
+    /// The response body is larger than `max_body_len`
+    TooLarge,
+    /// The request body could not be serialized, or the response body deserialized
+    Json(serde_json::Error),
+}
+
+impl RestError {
+    /// The status of the response, if the server answered with a non-2xx status
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Esp(err) => write!(f, "Request failed: {err}"),
+            Self::Status { status, body } => write!(
+                f,
+                "Status {status}: {}",
+                String::from_utf8_lossy(body.as_slice())
+            ),
+            Self::TooLarge => write!(f, "Response body too large"),
+            Self::Json(err) => write!(f, "JSON error: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RestError {}
+
+impl From<EspError> for RestError {
+    fn from(err: EspError) -> Self {
+        Self::Esp(err)
+    }
+}
+
+impl From<serde_json::Error> for RestError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+/// A REST client, for JSON APIs
+pub struct Client {
+    connection: EspHttpConnection,
+    headers: Vec<(String, String)>,
+    /// The maximum length of a response body
+    pub max_body_len: usize,
+    /// The maximum length of the response body captured in `RestError::Status`
+    pub max_error_body_len: usize,
+}
+
+impl Client {
+    pub fn new(conf: &Configuration) -> Result<Self, EspError> {
+        Ok(Self::wrap(EspHttpConnection::new(conf)?))
+    }
+
+    pub fn wrap(connection: EspHttpConnection) -> Self {
+        Self {
+            connection,
+            headers: Vec::new(),
+            max_body_len: 16384,
+            max_error_body_len: 512,
+        }
+    }
+
+    /// Set a header sent with every request, e.g. `Authorization`, replacing the previous value
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(other, _)| !other.eq_ignore_ascii_case(name));
+    }
+
+    pub fn get_json<T>(&mut self, url: &str) -> Result<T, RestError>
+    where
+        T: DeserializeOwned,
+    {
+        self.request_json::<(), T>(Method::Get, url, None)
+    }
+
+    pub fn post_json<S, T>(&mut self, url: &str, body: &S) -> Result<T, RestError>
+    where
+        S: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        self.request_json(Method::Post, url, Some(body))
+    }
+
+    pub fn put_json<S, T>(&mut self, url: &str, body: &S) -> Result<T, RestError>
+    where
+        S: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        self.request_json(Method::Put, url, Some(body))
+    }
+
+    pub fn patch_json<S, T>(&mut self, url: &str, body: &S) -> Result<T, RestError>
+    where
+        S: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        self.request_json(Method::Patch, url, Some(body))
+    }
+
+    pub fn delete_json<T>(&mut self, url: &str) -> Result<T, RestError>
+    where
+        T: DeserializeOwned,
+    {
+        self.request_json::<(), T>(Method::Delete, url, None)
+    }
+
+    /// Send a request, with the JSON body if any, and deserialize the JSON body of the response
+    pub fn request_json<S, T>(
+        &mut self,
+        method: Method,
+        url: &str,
+        body: Option<&S>,
+    ) -> Result<T, RestError>
+    where
+        S: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let body = body.map(serde_json::to_vec).transpose()?;
+
+        let (status, response) = self.request(method, url, body.as_deref())?;
+
+        if !(200..300).contains(&status) {
+            return Err(RestError::Status {
+                status,
+                body: response,
+            });
+        }
+
+        if response.is_empty() {
+            Ok(serde_json::from_slice(b"null")?)
+        } else {
+            Ok(serde_json::from_slice(&response)?)
+        }
+    }
+
+    /// The underlying connection, e.g. for the headers of the last response
+    pub fn connection(&mut self) -> &mut EspHttpConnection {
+        &mut self.connection
+    }
+
+    pub fn release(self) -> EspHttpConnection {
+        self.connection
+    }
+
+    fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> Result<(u16, Vec<u8>), RestError> {
+        let content_len = body.map(|body| body.len().to_string());
+
+        let mut headers = Vec::with_capacity(self.headers.len() + 3);
+        headers.push(("Accept", JSON));
+
+        if let Some(content_len) = &content_len {
+            headers.push(("Content-Type", JSON));
+            headers.push(("Content-Length", content_len.as_str()));
+        }
+
+        headers.extend(
+            self.headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+
+        self.connection.initiate_request(method, url, &headers)?;
+
+        if let Some(body) = body {
+            self.connection.write_all(body)?;
+        }
+
+        self.connection.initiate_response()?;
+
+        let status = self.connection.status();
+
+        let max_len = if (200..300).contains(&status) {
+            self.max_body_len
+        } else {
+            self.max_error_body_len
+        };
+
+        let mut response = Vec::new();
+        let mut buf = [0; 256];
+
+        loop {
+            let len = self.connection.read(&mut buf)?;
+
+            if len == 0 {
+                break;
+            }
+
+            if response.len() + len > max_len {
+                if (200..300).contains(&status) {
+                    return Err(RestError::TooLarge);
+                }
+
+                // Truncate the body of the error
+                response.extend_from_slice(&buf[..max_len - response.len()]);
+                break;
+            }
+
+            response.extend_from_slice(&buf[..len]);
+        }
+
+        Ok((status, response))
+    }
+}
+
+impl Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("max_body_len", &self.max_body_len)
+            .field("max_error_body_len", &self.max_error_body_len)
+            .finish_non_exhaustive()
+    }
+}
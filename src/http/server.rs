@@ -835,6 +835,8 @@ pub struct EspHttpConnection<'a> {
     request: EspHttpRawConnection<'a>,
     headers: Option<UnsafeCell<EspHttpHeaders>>,
     response_headers: Option<Vec<CString>>,
+    status: Option<u16>,
+    request_id: Option<u32>,
 }
 
 /// Represents the two-way connection between an HTTP request and its response.
@@ -844,6 +846,8 @@ impl<'a> EspHttpConnection<'a> {
             request: EspHttpRawConnection(raw_req),
             headers: Some(UnsafeCell::new(EspHttpHeaders::new())),
             response_headers: None,
+            status: None,
+            request_id: None,
         }
     }
 
@@ -936,6 +940,8 @@ impl<'a> EspHttpConnection<'a> {
 
         let mut c_headers = Vec::new();
 
+        self.status = Some(status);
+
         let status = if let Some(message) = message {
             format!("{status} {message}")
         } else {
@@ -977,6 +983,22 @@ impl<'a> EspHttpConnection<'a> {
             }
         }
 
+        if let Some(request_id) = self.request_id {
+            let name = to_cstring_arg("X-Request-Id")?;
+            let value = to_cstring_arg(&format!("{request_id:08x}"))?;
+
+            esp!(unsafe {
+                httpd_resp_set_hdr(
+                    self.request.0,
+                    name.as_c_str().as_ptr() as _,
+                    value.as_c_str().as_ptr() as _,
+                )
+            })?;
+
+            c_headers.push(name);
+            c_headers.push(value);
+        }
+
         self.response_headers = Some(c_headers);
         self.headers = None;
 
@@ -988,6 +1010,56 @@ impl<'a> EspHttpConnection<'a> {
         self.headers.is_none()
     }
 
+    /// Returns the status of the response, once initiated.
+    pub fn response_status(&self) -> Option<u16> {
+        self.status
+    }
+
+    /// Returns the ID of the request, if assigned by an `AccessLog` middleware.
+    ///
+    /// The ID is also returned to the client, in the `X-Request-Id` header of the response.
+    pub fn request_id(&self) -> Option<u32> {
+        self.request_id
+    }
+
+    /// Returns the address of the HTTP client.
+    pub fn peer_addr(&self) -> Option<core::net::SocketAddr> {
+        let fd = unsafe { httpd_req_to_sockfd(self.request.0 as *const _ as *mut _) };
+
+        let mut storage: sockaddr_storage = unsafe { core::mem::zeroed() };
+        let mut len = core::mem::size_of::<sockaddr_storage>() as socklen_t;
+
+        if unsafe { lwip_getpeername(fd, &mut storage as *mut _ as *mut _, &mut len) } < 0 {
+            return None;
+        }
+
+        match storage.ss_family as u32 {
+            AF_INET => {
+                let sin = unsafe { &*(&storage as *const _ as *const sockaddr_in) };
+
+                Some(core::net::SocketAddr::new(
+                    core::net::Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()).into(),
+                    u16::from_be(sin.sin_port),
+                ))
+            }
+            #[cfg(esp_idf_lwip_ipv6)]
+            AF_INET6 => {
+                let sin6 = unsafe { &*(&storage as *const _ as *const sockaddr_in6) };
+
+                let ip = core::net::Ipv6Addr::from(unsafe { sin6.sin6_addr.un.u8_addr });
+
+                // Present the IPv4 clients of a dual-stack server with their IPv4 address
+                let ip = ip
+                    .to_ipv4_mapped()
+                    .map(core::net::IpAddr::V4)
+                    .unwrap_or(core::net::IpAddr::V6(ip));
+
+                Some(core::net::SocketAddr::new(ip, u16::from_be(sin6.sin6_port)))
+            }
+            _ => None,
+        }
+    }
+
     /// Reads bytes from the body of the HTTP request.
     ///
     /// This is typically used whenever the HTTP server has to parse the body
@@ -1207,6 +1279,13 @@ impl<'b> Connection for EspHttpConnection<'b> {
     }
 }
 
+pub mod access_log;
+#[cfg(all(feature = "std", esp_idf_comp_mbedtls_enabled))]
+pub mod files;
+#[cfg(feature = "json")]
+pub mod jsonrpc;
+pub mod rate_limit;
+
 #[cfg(esp_idf_httpd_ws_support)]
 pub mod ws {
     use core::ffi;
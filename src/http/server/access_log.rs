@@ -0,0 +1,172 @@
+//! Access logging middleware
+//!
+//! `AccessLog` records the method, URI, status, latency and client address of each request of
+//! the handlers it is composed with, to the `log` facade or to any other `AccessLogSink`:
+//!
+//! ```ignore
+//! server.handler(
+//!     "/status",
+//!     Method::Get,
+//!     AccessLog::new().compose(fn_handler(|mut request| {
+//!         let id = request.connection().request_id();
+//!         info!("Handling request {id:08x?}");
+//!
+//!         request.into_ok_response()?.write_all(b"OK")
+//!     })),
+//! )?;
+//! ```
+//!
+//! Each request gets an ID, which the handlers can get with `EspHttpConnection::request_id` to
+//! correlate their own logs, and which is returned to the client in the `X-Request-Id` header.
+
+use core::fmt::{self, Display};
+use core::net::SocketAddr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+
+use embedded_svc::http::server::{Handler, Middleware};
+use embedded_svc::http::Method;
+
+use crate::sys::esp_timer_get_time;
+
+use super::EspHttpConnection;
+
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(1);
+
+/// A request, as logged once handled
+#[derive(Clone, Debug)]
+pub struct AccessLogEntry<'a> {
+    pub request_id: u32,
+    pub method: Method,
+    pub uri: &'a str,
+    /// The status of the response; 500 for a handler which failed before responding
+    pub status: u16,
+    /// The time taken to handle the request
+    pub latency: Duration,
+    pub peer: Option<SocketAddr>,
+}
+
+impl Display for AccessLogEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "{}", peer.ip())?,
+            None => write!(f, "-")?,
+        }
+
+        write!(
+            f,
+            " [{:08x}] \"{:?} {}\" {} {}ms",
+            self.request_id,
+            self.method,
+            self.uri,
+            self.status,
+            self.latency.as_millis()
+        )
+    }
+}
+
+/// Where `AccessLog` records the requests
+pub trait AccessLogSink: Send + Sync {
+    fn log(&self, entry: &AccessLogEntry<'_>);
+}
+
+impl<F> AccessLogSink for F
+where
+    F: Fn(&AccessLogEntry<'_>) + Send + Sync,
+{
+    fn log(&self, entry: &AccessLogEntry<'_>) {
+        self(entry)
+    }
+}
+
+/// Records the requests to the `log` facade, with the `access` target
+#[derive(Copy, Clone, Debug)]
+pub struct LogSink(pub ::log::Level);
+
+impl Default for LogSink {
+    fn default() -> Self {
+        Self(::log::Level::Info)
+    }
+}
+
+impl AccessLogSink for LogSink {
+    fn log(&self, entry: &AccessLogEntry<'_>) {
+        ::log::log!(target: "access", self.0, "{entry}");
+    }
+}
+
+/// A middleware recording each request to an `AccessLogSink`
+#[derive(Clone, Debug)]
+pub struct AccessLog<S = LogSink> {
+    sink: S,
+}
+
+impl AccessLog {
+    /// Record the requests to the `log` facade
+    pub fn new() -> Self {
+        Self::with_sink(LogSink::default())
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> AccessLog<S>
+where
+    S: AccessLogSink,
+{
+    pub fn with_sink(sink: S) -> Self {
+        Self { sink }
+    }
+}
+
+impl<'r, H, S> Middleware<EspHttpConnection<'r>, H> for AccessLog<S>
+where
+    H: Handler<EspHttpConnection<'r>>,
+    S: AccessLogSink,
+{
+    type Error = H::Error;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        connection.request_id = Some(request_id);
+
+        let method = connection.method();
+        let uri: String = connection.uri().to_string();
+        let peer = connection.peer_addr();
+
+        let start = unsafe { esp_timer_get_time() };
+
+        let result = handler.handle(connection);
+
+        let latency = Duration::from_micros((unsafe { esp_timer_get_time() } - start) as _);
+
+        // The server responds with a 200 when a handler does not, and with a 500 when it fails
+        let status = match (connection.response_status(), &result) {
+            (Some(status), _) => status,
+            (None, Ok(_)) => 200,
+            (None, Err(_)) => 500,
+        };
+
+        self.sink.log(&AccessLogEntry {
+            request_id,
+            method,
+            uri: &uri,
+            status,
+            latency,
+            peer,
+        });
+
+        result
+    }
+}
@@ -0,0 +1,370 @@
+//! A minimal file server, for exchanging files with a device over HTTP
+//!
+//! `register` serves a directory of the VFS - e.g. an SD card or a FAT partition mounted at
+//! `/sdcard` - under a URI prefix of an `EspHttpServer`, with a small WebDAV-like protocol
+//! which works with plain `curl`:
+//!
+//! - `GET <prefix>/<path>` downloads a file, or lists a directory - one entry per line, the
+//!   directories suffixed with `/`
+//! - `PUT <prefix>/<path>` uploads a file, replacing it if it exists
+//! - `DELETE <prefix>/<path>` removes a file or an empty directory, other than the root
+//! - `MKCOL <prefix>/<path>` creates a directory
+//!
+//! E.g. `curl -u admin:secret -T app.log http://device/files/logs/app.log`.
+//!
+//! The server must be created with `uri_match_wildcard` enabled. When credentials are set,
+//! the requests must be authenticated with HTTP Basic authentication, which does not encrypt
+//! the credentials: use an HTTPS server outside of trusted networks.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use embedded_svc::http::server::Request;
+use embedded_svc::http::{Headers, Method};
+use embedded_svc::io::{Read, Write};
+
+use log::info;
+
+use crate::crypto::ct_eq;
+use crate::io::EspIOError;
+use crate::private::base64;
+use crate::sys::*;
+
+use super::{EspHttpConnection, EspHttpServer};
+
+const BUF_SIZE: usize = 1024;
+
+/// The configuration of the file server.
+#[derive(Clone, Debug)]
+pub struct FileServerConfiguration<'a> {
+    /// The VFS directory served, e.g. `/sdcard`.
+    pub root: &'a str,
+    /// The user name and password of HTTP Basic authentication, or `None` for no
+    /// authentication.
+    pub credentials: Option<(&'a str, &'a str)>,
+    /// Whether only downloads are allowed.
+    pub read_only: bool,
+    /// The maximum size of an uploaded file, in bytes.
+    pub max_upload_size: Option<u64>,
+}
+
+impl FileServerConfiguration<'_> {
+    /// Create a new default configuration
+    pub const fn new() -> Self {
+        Self {
+            root: "/",
+            credentials: None,
+            read_only: false,
+            max_upload_size: None,
+        }
+    }
+}
+
+impl Default for FileServerConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct FileServer {
+    prefix: String,
+    root: PathBuf,
+    // The expected value of the `Authorization` header
+    authorization: Option<String>,
+    read_only: bool,
+    max_upload_size: Option<u64>,
+}
+
+/// Serve the directory `conf.root` under `uri_prefix`, e.g. `/files`.
+pub fn register(
+    server: &mut EspHttpServer<'_>,
+    uri_prefix: &str,
+    conf: &FileServerConfiguration,
+) -> Result<(), EspError> {
+    let prefix = uri_prefix.trim_end_matches('/');
+
+    let files = Arc::new(FileServer {
+        prefix: prefix.to_string(),
+        root: PathBuf::from(conf.root),
+        authorization: conf.credentials.map(|(user, password)| {
+            format!(
+                "Basic {}",
+                base64::encode(format!("{user}:{password}").as_bytes())
+            )
+        }),
+        read_only: conf.read_only,
+        max_upload_size: conf.max_upload_size,
+    });
+
+    let uri = format!("{prefix}/*");
+
+    for method in [Method::Get, Method::Put, Method::Delete, Method::MkCol] {
+        let files = files.clone();
+
+        server.fn_handler(&uri, method, move |request| files.handle(request))?;
+    }
+
+    info!("Serving files of {} at {uri}", conf.root);
+
+    Ok(())
+}
+
+impl FileServer {
+    fn handle(&self, request: Request<&mut EspHttpConnection>) -> Result<(), EspIOError> {
+        if let Some(authorization) = &self.authorization {
+            let authorized = request
+                .header("Authorization")
+                .map(|header| ct_eq(header.as_bytes(), authorization.as_bytes()))
+                .unwrap_or(false);
+
+            if !authorized {
+                request.into_response(
+                    401,
+                    Some("Unauthorized"),
+                    &[("WWW-Authenticate", "Basic realm=\"files\"")],
+                )?;
+
+                return Ok(());
+            }
+        }
+
+        let method = request.method();
+
+        if self.read_only && method != Method::Get {
+            return status(request, 405, "Method Not Allowed");
+        }
+
+        let Some(path) = self.path(request.uri()) else {
+            return status(request, 400, "Bad Request");
+        };
+
+        match method {
+            Method::Get if path.is_dir() => self.list(request, &path),
+            Method::Get => self.download(request, &path),
+            Method::Put => self.upload(request, &path),
+            Method::Delete if path == self.root => status(request, 403, "Forbidden"),
+            Method::Delete => {
+                let result = if path.is_dir() {
+                    fs::remove_dir(&path)
+                } else {
+                    fs::remove_file(&path)
+                };
+
+                fs_status(request, result)
+            }
+            Method::MkCol => fs_status(request, fs::create_dir(&path)),
+            _ => status(request, 405, "Method Not Allowed"),
+        }
+    }
+
+    fn list(
+        &self,
+        request: Request<&mut EspHttpConnection>,
+        path: &Path,
+    ) -> Result<(), EspIOError> {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) => return fs_status(request, Err(err)),
+        };
+
+        let mut response =
+            request.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?;
+
+        let mut line = String::new();
+
+        for entry in entries.flatten() {
+            line.clear();
+            line.push_str(&entry.file_name().to_string_lossy());
+
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                line.push('/');
+            }
+
+            line.push('\n');
+
+            response.write_all(line.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn download(
+        &self,
+        request: Request<&mut EspHttpConnection>,
+        path: &Path,
+    ) -> Result<(), EspIOError> {
+        let mut file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) => return fs_status(request, Err(err)),
+        };
+
+        let len = file.metadata().map(|m| m.len()).unwrap_or(0).to_string();
+
+        let mut response = request.into_response(
+            200,
+            Some("OK"),
+            &[
+                ("Content-Type", content_type(path)),
+                ("Content-Length", &len),
+            ],
+        )?;
+
+        let mut buf = [0; BUF_SIZE];
+
+        loop {
+            let len = std::io::Read::read(&mut file, &mut buf)
+                .map_err(|_| EspIOError(EspError::from_infallible::<ESP_FAIL>()))?;
+
+            if len == 0 {
+                break;
+            }
+
+            response.write_all(&buf[..len])?;
+        }
+
+        Ok(())
+    }
+
+    fn upload(
+        &self,
+        mut request: Request<&mut EspHttpConnection>,
+        path: &Path,
+    ) -> Result<(), EspIOError> {
+        if let (Some(max), Some(len)) = (self.max_upload_size, request.content_len()) {
+            if len > max {
+                return status(request, 413, "Payload Too Large");
+            }
+        }
+
+        // Written aside first, so that a failed upload does not leave a truncated file
+        let mut partial = path.as_os_str().to_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+
+        let mut file = match fs::File::create(&partial) {
+            Ok(file) => file,
+            Err(err) => return fs_status(request, Err(err)),
+        };
+
+        let mut buf = [0; BUF_SIZE];
+        let mut total = 0;
+
+        let result = loop {
+            let len = match request.read(&mut buf) {
+                Ok(len) => len,
+                Err(err) => break Err(err),
+            };
+
+            if len == 0 {
+                break Ok(());
+            }
+
+            total += len as u64;
+
+            if self.max_upload_size.map(|max| total > max).unwrap_or(false) {
+                drop(file);
+                let _ = fs::remove_file(&partial);
+
+                return status(request, 413, "Payload Too Large");
+            }
+
+            if let Err(err) = std::io::Write::write_all(&mut file, &buf[..len]) {
+                drop(file);
+                let _ = fs::remove_file(&partial);
+
+                return fs_status(request, Err(err));
+            }
+        };
+
+        drop(file);
+
+        if let Err(err) = result {
+            let _ = fs::remove_file(&partial);
+
+            return Err(err);
+        }
+
+        // FAT does not replace an existing file on rename
+        let _ = fs::remove_file(path);
+
+        fs_status(request, fs::rename(&partial, path))
+    }
+
+    // Maps the URI to a path under the root, refusing to leave it
+    fn path(&self, uri: &str) -> Option<PathBuf> {
+        let uri = uri.split(['?', '#']).next().unwrap();
+        let relative = uri.strip_prefix(self.prefix.as_str())?;
+
+        let mut path = self.root.clone();
+
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = percent_decode(segment)?;
+
+            if segment == "." || segment == ".." || segment.contains('/') {
+                return None;
+            }
+
+            path.push(OsStr::new(&segment));
+        }
+
+        Some(path)
+    }
+}
+
+fn status(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    message: &str,
+) -> Result<(), EspIOError> {
+    request.into_response(status, Some(message), &[])?;
+
+    Ok(())
+}
+
+fn fs_status(
+    request: Request<&mut EspHttpConnection>,
+    result: std::io::Result<()>,
+) -> Result<(), EspIOError> {
+    match result {
+        Ok(()) => status(request, 200, "OK"),
+        Err(err) => match err.kind() {
+            std::io::ErrorKind::NotFound => status(request, 404, "Not Found"),
+            std::io::ErrorKind::AlreadyExists => status(request, 409, "Conflict"),
+            std::io::ErrorKind::PermissionDenied => status(request, 403, "Forbidden"),
+            _ => status(request, 500, "Internal Server Error"),
+        },
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("txt" | "log" | "csv") => "text/plain",
+        Some("json") => "application/json",
+        Some("html" | "htm") => "text/html",
+        _ => "application/octet-stream",
+    }
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut chars = segment.bytes();
+
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            let hex = core::str::from_utf8(&hex).ok()?;
+
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
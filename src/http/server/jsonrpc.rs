@@ -0,0 +1,316 @@
+//! JSON-RPC 2.0 endpoint
+//!
+//! `JsonRpc` dispatches the JSON-RPC 2.0 requests POSTed to a URI of an `EspHttpServer` to the
+//! Rust functions registered under their method names, the parameters and results of which are
+//! (de)serialized with `serde`:
+//!
+//! ```ignore
+//! let mut rpc = JsonRpc::new();
+//!
+//! rpc.method("add", |(a, b): (i32, i32)| Ok(a + b))
+//!     .method("led.set", |on: bool| {
+//!         led.lock().unwrap().set_state(on.into()).map_err(RpcError::internal_error)
+//!     });
+//!
+//! rpc.register(&mut server, "/rpc")?;
+//! ```
+//!
+//! E.g. `curl -d '{"jsonrpc":"2.0","method":"add","params":[1,2],"id":1}' http://device/rpc`
+//! answers `{"jsonrpc":"2.0","result":3,"id":1}`.
+//!
+//! The positional parameters deserialize into tuples or sequences, the named ones into structs
+//! or maps, and the absent ones into `()` or `None`. Batch requests are supported, and the
+//! notifications - the requests without an `id` - are not answered: a request or a batch of
+//! notifications only gets a `204 No Content`.
+
+use core::fmt::{self, Debug, Display};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use embedded_svc::http::server::Request;
+use embedded_svc::http::{Headers, Method};
+use embedded_svc::io::{Read, Write};
+
+use log::info;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::io::EspIOError;
+use crate::sys::EspError;
+
+use super::{EspHttpConnection, EspHttpServer};
+
+type RpcMethod = Box<dyn Fn(Value) -> Result<Value, RpcError> + Send + Sync + 'static>;
+
+/// A JSON-RPC error, as returned by a method
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    /// The request is not valid JSON
+    pub const PARSE_ERROR: i32 = -32700;
+    /// The request is not a valid request object
+    pub const INVALID_REQUEST: i32 = -32600;
+    pub const METHOD_NOT_FOUND: i32 = -32601;
+    pub const INVALID_PARAMS: i32 = -32602;
+    pub const INTERNAL_ERROR: i32 = -32603;
+
+    /// An error; the codes from -32768 to -32000 are reserved for the predefined errors
+    pub fn new(code: i32, message: impl Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    /// The same error, with additional information about it
+    pub fn with_data(self, data: Value) -> Self {
+        Self {
+            data: Some(data),
+            ..self
+        }
+    }
+
+    pub fn invalid_params(message: impl Display) -> Self {
+        Self::new(Self::INVALID_PARAMS, message)
+    }
+
+    pub fn internal_error(message: impl Display) -> Self {
+        Self::new(Self::INTERNAL_ERROR, message)
+    }
+
+    fn to_value(&self) -> Value {
+        let mut error = json!({
+            "code": self.code,
+            "message": self.message,
+        });
+
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+
+        error
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "JSON-RPC error {}: {}", self.code, self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RpcError {}
+
+impl From<EspError> for RpcError {
+    fn from(err: EspError) -> Self {
+        Self::internal_error(err)
+    }
+}
+
+/// The methods of a JSON-RPC endpoint
+pub struct JsonRpc {
+    methods: BTreeMap<String, RpcMethod>,
+    /// The maximum length of a request body
+    pub max_request_len: usize,
+    /// The maximum number of requests in a batch
+    pub max_batch_len: usize,
+}
+
+impl JsonRpc {
+    pub fn new() -> Self {
+        Self {
+            methods: BTreeMap::new(),
+            max_request_len: 4096,
+            max_batch_len: 16,
+        }
+    }
+
+    /// Register a method, replacing the method of the same name if any
+    ///
+    /// Parameters which do not deserialize into `P` are answered with an `INVALID_PARAMS` error.
+    pub fn method<P, R, F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(P) -> Result<R, RpcError> + Send + Sync + 'static,
+    {
+        let method = move |params: Value| {
+            let params = serde_json::from_value(params).map_err(RpcError::invalid_params)?;
+            let result = f(params)?;
+
+            serde_json::to_value(result).map_err(RpcError::internal_error)
+        };
+
+        self.methods.insert(name.to_string(), Box::new(method));
+
+        self
+    }
+
+    /// Handle the body of a request; return the body of the response, or `None` if there is none
+    /// to send, i.e. the request only had notifications
+    pub fn handle_body(&self, body: &[u8]) -> Option<Value> {
+        let request = match serde_json::from_slice::<Value>(body) {
+            Ok(request) => request,
+            Err(err) => {
+                return Some(error_response(
+                    Value::Null,
+                    &RpcError::new(RpcError::PARSE_ERROR, err),
+                ))
+            }
+        };
+
+        match request {
+            Value::Array(batch) if batch.is_empty() => Some(error_response(
+                Value::Null,
+                &RpcError::new(RpcError::INVALID_REQUEST, "Empty batch"),
+            )),
+            Value::Array(batch) if batch.len() > self.max_batch_len => Some(error_response(
+                Value::Null,
+                &RpcError::new(RpcError::INVALID_REQUEST, "Batch too large"),
+            )),
+            Value::Array(batch) => {
+                let responses = batch
+                    .into_iter()
+                    .filter_map(|request| self.call(request))
+                    .collect::<Vec<_>>();
+
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            request => self.call(request),
+        }
+    }
+
+    /// Handle the requests POSTed to `uri`
+    pub fn register(self, server: &mut EspHttpServer<'_>, uri: &str) -> Result<(), EspError> {
+        let rpc = Arc::new(self);
+
+        server.fn_handler(uri, Method::Post, move |request| rpc.handle(request))?;
+
+        info!("Serving JSON-RPC at {uri}");
+
+        Ok(())
+    }
+
+    fn handle(&self, mut request: Request<&mut EspHttpConnection>) -> Result<(), EspIOError> {
+        if request
+            .content_len()
+            .is_some_and(|len| len > self.max_request_len as u64)
+        {
+            request.into_response(413, Some("Payload Too Large"), &[])?;
+            return Ok(());
+        }
+
+        let mut body = Vec::new();
+        let mut buf = [0; 256];
+
+        loop {
+            let len = request.read(&mut buf)?;
+
+            if len == 0 {
+                break;
+            }
+
+            if body.len() + len > self.max_request_len {
+                request.into_response(413, Some("Payload Too Large"), &[])?;
+                return Ok(());
+            }
+
+            body.extend_from_slice(&buf[..len]);
+        }
+
+        match self.handle_body(&body) {
+            Some(response) => {
+                let response = response.to_string();
+
+                request
+                    .into_response(200, None, &[("Content-Type", "application/json")])?
+                    .write_all(response.as_bytes())?;
+            }
+            None => {
+                request.into_response(204, Some("No Content"), &[])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Call the method of a request; return its response, or `None` for a notification
+    fn call(&self, request: Value) -> Option<Value> {
+        let Value::Object(mut request) = request else {
+            return Some(error_response(
+                Value::Null,
+                &RpcError::new(RpcError::INVALID_REQUEST, "Not a request object"),
+            ));
+        };
+
+        // A notification has no `id`; an invalid `id` is answered with a `null` one
+        let id = match request.remove("id") {
+            None => None,
+            Some(id @ (Value::Null | Value::Number(_) | Value::String(_))) => Some(id),
+            Some(_) => Some(Value::Null),
+        };
+
+        let params = request.remove("params").unwrap_or(Value::Null);
+
+        let result = match (request.get("jsonrpc"), request.get("method")) {
+            (Some(Value::String(version)), Some(Value::String(method))) if version == "2.0" => {
+                if !matches!(params, Value::Null | Value::Array(_) | Value::Object(_)) {
+                    Err(RpcError::new(RpcError::INVALID_REQUEST, "Invalid params"))
+                } else if let Some(f) = self.methods.get(method.as_str()) {
+                    f(params)
+                } else {
+                    Err(RpcError::new(
+                        RpcError::METHOD_NOT_FOUND,
+                        "Method not found",
+                    ))
+                }
+            }
+            _ => Err(RpcError::new(RpcError::INVALID_REQUEST, "Invalid request")),
+        };
+
+        // The invalid requests are answered, even without an `id`
+        let id = match (id, &result) {
+            (Some(id), _) => id,
+            (None, Err(err)) if err.code == RpcError::INVALID_REQUEST => Value::Null,
+            (None, _) => return None,
+        };
+
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(err) => error_response(id, &err),
+        })
+    }
+}
+
+impl Default for JsonRpc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for JsonRpc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonRpc")
+            .field("methods", &self.methods.keys().collect::<Vec<_>>())
+            .field("max_request_len", &self.max_request_len)
+            .field("max_batch_len", &self.max_batch_len)
+            .finish()
+    }
+}
+
+fn error_response(id: Value, err: &RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": err.to_value(), "id": id })
+}
@@ -0,0 +1,281 @@
+//! Rate limiting middleware
+//!
+//! `RateLimit` throttles the clients of the handlers it is composed with, so that a device
+//! survives misbehaving clients and scanners: each client address has a token bucket of `burst`
+//! requests, refilled with a token every `interval`. With `per_uri`, each path requested by a
+//! client also has a bucket of `uri_burst` requests, so that a client hammering a path is
+//! throttled before using up its whole bucket. The requests of a client with an empty bucket are
+//! answered with a `429 Too Many Requests`, and a `Retry-After` header telling when its next
+//! request will be accepted.
+//!
+//! ```ignore
+//! // Bursts of 10 requests, then 2 requests per second
+//! let limit = RateLimit::new(&RateLimitConfiguration {
+//!     burst: 10,
+//!     interval: Duration::from_millis(500),
+//!     ..Default::default()
+//! });
+//!
+//! server.handler("/api", Method::Post, limit.clone().compose(api_handler))?;
+//! server.handler("/", Method::Get, limit.compose(index_handler))?;
+//! ```
+//!
+//! The clones of a `RateLimit` share their buckets, so a client is throttled across all the
+//! handlers composed with them. The buckets are kept for at most `max_clients` clients, those
+//! of the clients idle for the longest being dropped first.
+
+use core::fmt::{self, Debug, Display};
+use core::net::IpAddr;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::debug;
+
+use embedded_svc::http::server::{Handler, Middleware};
+
+use crate::private::mutex::Mutex;
+use crate::sys::{esp_timer_get_time, EspError};
+
+use super::EspHttpConnection;
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfiguration {
+    /// The number of requests a client can send in a burst
+    pub burst: u32,
+    /// The time after which a client can send one more request
+    pub interval: Duration,
+    /// Whether the requests to each path - the URI without its query - are also limited
+    /// separately, the bucket of the client still applying to all its requests
+    pub per_uri: bool,
+    /// The number of requests a client can send to a path in a burst, with `per_uri`
+    pub uri_burst: u32,
+    /// The maximum number of clients tracked at once
+    pub max_clients: usize,
+}
+
+impl RateLimitConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            burst: 10,
+            interval: Duration::from_secs(1),
+            per_uri: false,
+            uri_burst: 5,
+            max_clients: 32,
+        }
+    }
+}
+
+impl Default for RateLimitConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The error of a rate limited handler
+#[derive(Debug)]
+pub enum RateLimitError<E> {
+    Handler(E),
+    /// Sending the `429 Too Many Requests` response failed
+    Response(EspError),
+}
+
+impl<E> Display for RateLimitError<E>
+where
+    E: Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Handler(err) => write!(f, "{err}"),
+            Self::Response(err) => write!(f, "Rate limiting response failed: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for RateLimitError<E> where E: std::error::Error {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct BucketKey {
+    ip: IpAddr,
+    uri: Option<u32>,
+}
+
+/// A token bucket, as its theoretical arrival time: the bucket is full when it is in the past,
+/// and empty when it is `burst` intervals in the future
+#[derive(Copy, Clone, Debug)]
+struct Bucket {
+    key: BucketKey,
+    tat: i64,
+}
+
+struct Limiter {
+    conf: RateLimitConfiguration,
+    buckets: Vec<Bucket>,
+}
+
+impl Limiter {
+    /// Take a token from each of the buckets of `keys`, with their burst, or return the delay
+    /// until all of them have a token
+    fn take(&mut self, keys: &[(BucketKey, u32)], now: i64) -> Result<(), Duration> {
+        let interval = self.conf.interval.as_micros() as i64;
+
+        // The tokens are only taken if all the buckets have one
+        let mut retry_after = 0;
+
+        for (key, burst) in keys {
+            let tolerance = interval * ((*burst).max(1) as i64 - 1);
+            let tat = self.bucket(*key, now).tat.max(now);
+
+            retry_after = retry_after.max(tat - now - tolerance);
+        }
+
+        if retry_after > 0 {
+            return Err(Duration::from_micros(retry_after as _));
+        }
+
+        for (key, _) in keys {
+            let bucket = self.bucket(*key, now);
+
+            bucket.tat = bucket.tat.max(now) + interval;
+        }
+
+        Ok(())
+    }
+
+    fn bucket(&mut self, key: BucketKey, now: i64) -> &mut Bucket {
+        match self.buckets.iter().position(|bucket| bucket.key == key) {
+            Some(index) => &mut self.buckets[index],
+            None => {
+                // Full buckets are the same as no bucket
+                self.buckets.retain(|bucket| bucket.tat > now);
+
+                if self.buckets.len() >= self.conf.max_clients.max(1) {
+                    let oldest = self
+                        .buckets
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, bucket)| bucket.tat)
+                        .map(|(index, _)| index)
+                        .unwrap();
+
+                    self.buckets.swap_remove(oldest);
+                }
+
+                self.buckets.push(Bucket { key, tat: now });
+                self.buckets.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+/// A middleware throttling the clients, with a token bucket per client address
+#[derive(Clone)]
+pub struct RateLimit(Arc<Mutex<Limiter>>);
+
+impl RateLimit {
+    pub fn new(conf: &RateLimitConfiguration) -> Self {
+        Self(Arc::new(Mutex::new(Limiter {
+            conf: conf.clone(),
+            buckets: Vec::new(),
+        })))
+    }
+
+    /// The number of clients currently tracked
+    pub fn clients(&self) -> usize {
+        let now = unsafe { esp_timer_get_time() };
+
+        self.0
+            .lock()
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.tat > now)
+            .count()
+    }
+
+    /// Forget all the clients, i.e. refill all the buckets
+    pub fn reset(&self) {
+        self.0.lock().buckets.clear();
+    }
+}
+
+impl Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("conf", &self.0.lock().conf)
+            .finish()
+    }
+}
+
+impl<'r, H> Middleware<EspHttpConnection<'r>, H> for RateLimit
+where
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = RateLimitError<H::Error>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        let Some(peer) = connection.peer_addr() else {
+            return handler.handle(connection).map_err(RateLimitError::Handler);
+        };
+
+        let mut limiter = self.0.lock();
+
+        let client = BucketKey {
+            ip: peer.ip(),
+            uri: None,
+        };
+
+        let now = unsafe { esp_timer_get_time() };
+
+        let result = if limiter.conf.per_uri {
+            let uri = connection.uri();
+            let path = uri.split_once('?').map(|(path, _)| path).unwrap_or(uri);
+
+            let path = BucketKey {
+                uri: Some(fnv1a(path)),
+                ..client
+            };
+
+            let (burst, uri_burst) = (limiter.conf.burst, limiter.conf.uri_burst);
+
+            limiter.take(&[(client, burst), (path, uri_burst)], now)
+        } else {
+            let burst = limiter.conf.burst;
+
+            limiter.take(&[(client, burst)], now)
+        };
+
+        drop(limiter);
+
+        match result {
+            Ok(()) => handler.handle(connection).map_err(RateLimitError::Handler),
+            Err(retry_after) => {
+                debug!("Throttling {}, retry after {:?}", peer.ip(), retry_after);
+
+                // The delay is rounded up, as `Retry-After` is in seconds
+                let retry_after = retry_after.as_micros().div_ceil(1_000_000).to_string();
+
+                connection
+                    .initiate_response(
+                        429,
+                        Some("Too Many Requests"),
+                        &[("Retry-After", &retry_after)],
+                    )
+                    .map_err(RateLimitError::Response)
+            }
+        }
+    }
+}
+
+fn fnv1a(s: &str) -> u32 {
+    s.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
@@ -0,0 +1,253 @@
+//! Device identity
+//!
+//! A single source for the identity of the device, so that the hostname, the MQTT client ID
+//! and the mDNS instance name all derive from the same ID:
+//! - `device_id` is derived from the base MAC address burned in the eFuses, and so is stable
+//!   across OTA updates and NVS erases; `device_id_from_nvs` is a random UUID generated on
+//!   the first boot and stored in NVS, for the fleets which do not want to disclose the MAC
+//! - `hostname`, `client_id` and `instance_name` format them for WiFi / DHCP, MQTT and mDNS
+//! - `ClaimCredentials` holds the claim certificate and key provisioned in the factory, e.g.
+//!   for the fleet provisioning of a cloud IoT service
+//!
+//! ```ignore
+//! use esp_idf_svc::identity;
+//!
+//! let id = identity::device_id()?;
+//!
+//! let hostname = identity::hostname(&id, "sensor")?; // E.g. `sensor-a1b2c3`
+//! let client_id = identity::client_id(&id, "sensor")?; // E.g. `sensor-246f28a1b2c3`
+//! ```
+
+use core::fmt::{self, Write};
+
+use crate::sys::*;
+
+/// The maximum length of a hostname; the limit of the ESP-IDF netif
+pub const MAX_HOSTNAME_LEN: usize = 32;
+
+/// The maximum length of a client ID
+pub const MAX_CLIENT_ID_LEN: usize = 64;
+
+/// The ID of the device: the base MAC address, or a random UUID
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct DeviceId {
+    bytes: [u8; 16],
+    len: usize,
+}
+
+impl DeviceId {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// The last 3 bytes of the ID, in hex: unique enough to tell apart the devices of a site
+    pub fn short(&self) -> heapless::String<6> {
+        let mut short = heapless::String::new();
+
+        for byte in &self.as_bytes()[self.len - 3..] {
+            write!(&mut short, "{byte:02x}").unwrap();
+        }
+
+        short
+    }
+}
+
+impl fmt::Display for DeviceId {
+    /// The ID in hex, hyphenated as a UUID for a UUID
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, byte) in self.as_bytes().iter().enumerate() {
+            if self.len == 16 && matches!(index, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the ID derived from the base MAC address burned in the eFuses
+pub fn device_id() -> Result<DeviceId, EspError> {
+    let mut bytes = [0; 16];
+
+    esp!(unsafe { esp_efuse_mac_get_default(bytes.as_mut_ptr()) })?;
+
+    Ok(DeviceId { bytes, len: 6 })
+}
+
+/// Get the random UUID stored in NVS under `key`, generating it on the first call
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+pub fn device_id_from_nvs<T>(
+    nvs: &mut crate::nvs::EspNvs<T>,
+    key: &str,
+) -> Result<DeviceId, EspError>
+where
+    T: crate::nvs::NvsPartitionId,
+{
+    let mut bytes = [0; 16];
+
+    if nvs.get_blob(key, &mut bytes)?.map(|id| id.len()) != Some(16) {
+        crate::rng::fill(&mut bytes);
+
+        // A version 4 UUID, as per RFC 4122
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        nvs.set_blob(key, &bytes)?;
+    }
+
+    Ok(DeviceId { bytes, len: 16 })
+}
+
+/// Format a hostname - `<prefix>-<short ID>` - valid for DHCP and mDNS
+///
+/// The characters of the prefix which are not valid in a hostname are replaced with `-`.
+pub fn hostname(
+    id: &DeviceId,
+    prefix: &str,
+) -> Result<heapless::String<MAX_HOSTNAME_LEN>, EspError> {
+    let mut hostname = heapless::String::new();
+
+    for c in prefix.chars() {
+        let c = if c.is_ascii_alphanumeric() {
+            c.to_ascii_lowercase()
+        } else {
+            '-'
+        };
+
+        hostname
+            .push(c)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+    }
+
+    write!(&mut hostname, "-{}", id.short())
+        .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+
+    Ok(hostname)
+}
+
+/// Format a client ID - `<prefix>-<ID>` - unique across a fleet, e.g. for MQTT
+pub fn client_id(
+    id: &DeviceId,
+    prefix: &str,
+) -> Result<heapless::String<MAX_CLIENT_ID_LEN>, EspError> {
+    let mut client_id = heapless::String::new();
+
+    write!(&mut client_id, "{prefix}-")
+        .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+
+    for byte in id.as_bytes() {
+        write!(&mut client_id, "{byte:02x}")
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+    }
+
+    Ok(client_id)
+}
+
+/// Format a human readable instance name - `<prefix> <short ID>` - e.g. for mDNS
+pub fn instance_name(
+    id: &DeviceId,
+    prefix: &str,
+) -> Result<heapless::String<MAX_CLIENT_ID_LEN>, EspError> {
+    let mut name = heapless::String::new();
+
+    write!(&mut name, "{prefix} ")
+        .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+
+    for byte in &id.as_bytes()[id.len - 3..] {
+        write!(&mut name, "{byte:02X}")
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())?;
+    }
+
+    Ok(name)
+}
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+pub use claim::*;
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+mod claim {
+    extern crate alloc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use crate::nvs::{EspNvs, NvsPartitionId};
+    use crate::sys::EspError;
+
+    const CERTIFICATE_KEY: &str = "claim_cert";
+    const PRIVATE_KEY_KEY: &str = "claim_key";
+
+    /// The claim certificate and private key provisioned in the factory, in PEM - NUL
+    /// terminated, as `tls::X509::pem_until_nul` expects - or in DER
+    ///
+    /// They are usually stored in a dedicated NVS partition - possibly encrypted - which is
+    /// not erased with the application data.
+    #[derive(Clone, Eq, PartialEq)]
+    pub struct ClaimCredentials {
+        pub certificate: Vec<u8>,
+        pub private_key: Vec<u8>,
+    }
+
+    impl ClaimCredentials {
+        /// Load the credentials, if provisioned
+        pub fn load<T>(nvs: &EspNvs<T>) -> Result<Option<Self>, EspError>
+        where
+            T: NvsPartitionId,
+        {
+            let (Some(certificate), Some(private_key)) =
+                (read(nvs, CERTIFICATE_KEY)?, read(nvs, PRIVATE_KEY_KEY)?)
+            else {
+                return Ok(None);
+            };
+
+            Ok(Some(Self {
+                certificate,
+                private_key,
+            }))
+        }
+
+        /// Store the credentials, e.g. from a factory provisioning tool
+        pub fn store<T>(&self, nvs: &mut EspNvs<T>) -> Result<(), EspError>
+        where
+            T: NvsPartitionId,
+        {
+            nvs.set_blob(CERTIFICATE_KEY, &self.certificate)?;
+            nvs.set_blob(PRIVATE_KEY_KEY, &self.private_key)
+        }
+
+        /// Remove the credentials, e.g. once exchanged for the credentials of the device
+        pub fn remove<T>(nvs: &mut EspNvs<T>) -> Result<(), EspError>
+        where
+            T: NvsPartitionId,
+        {
+            nvs.remove(CERTIFICATE_KEY)?;
+            nvs.remove(PRIVATE_KEY_KEY)?;
+
+            Ok(())
+        }
+    }
+
+    impl core::fmt::Debug for ClaimCredentials {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("ClaimCredentials")
+                .field("certificate", &self.certificate.len())
+                .field("private_key", &"<redacted>")
+                .finish()
+        }
+    }
+
+    fn read<T>(nvs: &EspNvs<T>, key: &str) -> Result<Option<Vec<u8>>, EspError>
+    where
+        T: NvsPartitionId,
+    {
+        let Some(len) = nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        nvs.get_blob(key, &mut buf)?;
+
+        Ok(Some(buf))
+    }
+}
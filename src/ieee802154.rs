@@ -0,0 +1,336 @@
+//! Raw IEEE 802.15.4 radio
+//!
+//! On the chips with an 802.15.4 radio - the ESP32-C6 and the ESP32-H2 - `EspIeee802154`
+//! sends and receives raw MAC frames, for custom low-power protocols or sniffers:
+//!
+//! ```ignore
+//! use esp_idf_svc::ieee802154::{EspIeee802154, Ieee802154Configuration};
+//!
+//! let mut radio = EspIeee802154::new(&Ieee802154Configuration {
+//!     channel: 15,
+//!     promiscuous: true,
+//!     ..Default::default()
+//! })?;
+//!
+//! loop {
+//!     if let Some(frame) = radio.receive(esp_idf_svc::sys::portMAX_DELAY) {
+//!         log::info!("{} bytes, RSSI {}", frame.data().len(), frame.rssi);
+//!     }
+//! }
+//! ```
+//!
+//! The frames are given without their PHY header and FCS, which the radio adds and checks.
+//!
+//! The radio driver calls back the application, so this module is not available when the
+//! OpenThread or the Zigbee stack - which drive the radio themselves - are enabled.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+use ::log::info;
+
+use crate::hal::task::queue::Queue;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+/// The maximum size of a frame, without its FCS
+pub const MAX_FRAME_LEN: usize = 127 - FCS_LEN;
+
+// The size of the FCS appended by the radio
+const FCS_LEN: usize = 2;
+
+// The frames received and not yet taken by `receive`; those received while the queue is
+// full are dropped
+const RX_QUEUE_LEN: usize = 8;
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+static RX_QUEUE: AtomicPtr<Queue<ReceivedFrame>> = AtomicPtr::new(ptr::null_mut());
+static TX_QUEUE: AtomicPtr<Queue<Result<bool, TxError>>> = AtomicPtr::new(ptr::null_mut());
+
+/// The configuration of the radio
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ieee802154Configuration {
+    /// The channel, 11 to 26
+    pub channel: u8,
+    /// The transmit power, in dBm
+    pub tx_power: i8,
+    pub pan_id: u16,
+    pub short_address: u16,
+    /// The extended address, in the byte order of the frames; the one derived from the base
+    /// MAC address when `None`
+    pub extended_address: Option<[u8; 8]>,
+    /// Whether the frames which are not for the PAN ID and the addresses are received too
+    pub promiscuous: bool,
+    /// Whether the frames requesting an ACK are acknowledged by the radio
+    pub auto_ack: bool,
+    /// Whether the radio receives when not transmitting
+    pub rx_when_idle: bool,
+}
+
+impl Ieee802154Configuration {
+    pub const fn new() -> Self {
+        Self {
+            channel: 11,
+            tx_power: 10,
+            pan_id: 0xffff,
+            short_address: 0xfffe,
+            extended_address: None,
+            promiscuous: false,
+            auto_ack: true,
+            rx_when_idle: true,
+        }
+    }
+}
+
+impl Default for Ieee802154Configuration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A frame received
+#[derive(Copy, Clone)]
+pub struct ReceivedFrame {
+    data: [u8; MAX_FRAME_LEN],
+    len: u8,
+    /// The RSSI of the frame, in dBm
+    pub rssi: i8,
+    /// The link quality indication of the frame
+    pub lqi: u8,
+    pub channel: u8,
+    /// Whether the ACK sent for the frame had the frame pending bit set
+    pub pending: bool,
+}
+
+impl ReceivedFrame {
+    /// The MAC frame, without the FCS
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    unsafe fn from_raw(frame: *const u8, info: &esp_ieee802154_frame_info_t) -> Self {
+        let len = (*frame as usize).saturating_sub(FCS_LEN).min(MAX_FRAME_LEN);
+
+        let mut data = [0; MAX_FRAME_LEN];
+        ptr::copy_nonoverlapping(frame.add(1), data.as_mut_ptr(), len);
+
+        Self {
+            data,
+            len: len as _,
+            rssi: info.rssi,
+            lqi: info.lqi,
+            channel: info.channel,
+            pending: info.pending,
+        }
+    }
+}
+
+impl core::fmt::Debug for ReceivedFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ReceivedFrame")
+            .field("data", &self.data())
+            .field("rssi", &self.rssi)
+            .field("lqi", &self.lqi)
+            .field("channel", &self.channel)
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+/// Why a frame could not be transmitted
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TxError {
+    /// The clear channel assessment found the channel busy
+    ChannelBusy,
+    /// The frame requested an ACK, which was not received
+    NoAck,
+    /// The transmission was aborted, e.g. by the coexistence with WiFi or BLE
+    Aborted,
+    /// Another error, by driver code
+    Other(u32),
+}
+
+impl TxError {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(error: esp_ieee802154_tx_error_t) -> Self {
+        match error {
+            esp_ieee802154_tx_error_t_ESP_IEEE802154_TX_ERR_CCA_BUSY => Self::ChannelBusy,
+            esp_ieee802154_tx_error_t_ESP_IEEE802154_TX_ERR_NO_ACK
+            | esp_ieee802154_tx_error_t_ESP_IEEE802154_TX_ERR_INVALID_ACK => Self::NoAck,
+            esp_ieee802154_tx_error_t_ESP_IEEE802154_TX_ERR_ABORT
+            | esp_ieee802154_tx_error_t_ESP_IEEE802154_TX_ERR_COEXIST => Self::Aborted,
+            other => Self::Other(other as _),
+        }
+    }
+}
+
+/// The IEEE 802.15.4 radio
+pub struct EspIeee802154 {
+    rx_queue: Box<Queue<ReceivedFrame>>,
+    tx_queue: Box<Queue<Result<bool, TxError>>>,
+    // The frame being transmitted, with its PHY header; the driver reads it until the
+    // transmission is done
+    tx_frame: Box<[u8; 128]>,
+}
+
+impl EspIeee802154 {
+    pub fn new(conf: &Ieee802154Configuration) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut rx_queue = Box::new(Queue::new(RX_QUEUE_LEN));
+        let mut tx_queue = Box::new(Queue::new(1));
+
+        RX_QUEUE.store(&mut *rx_queue, Ordering::Release);
+        TX_QUEUE.store(&mut *tx_queue, Ordering::Release);
+
+        if let Err(err) = esp!(unsafe { esp_ieee802154_enable() }) {
+            RX_QUEUE.store(ptr::null_mut(), Ordering::Release);
+            TX_QUEUE.store(ptr::null_mut(), Ordering::Release);
+
+            return Err(err);
+        }
+
+        let mut radio = Self {
+            rx_queue,
+            tx_queue,
+            tx_frame: Box::new([0; 128]),
+        };
+
+        *taken = true;
+
+        drop(taken);
+
+        radio.set_configuration(conf)?;
+
+        info!("Enabled");
+
+        Ok(radio)
+    }
+
+    pub fn set_configuration(&mut self, conf: &Ieee802154Configuration) -> Result<(), EspError> {
+        let extended_address = match conf.extended_address {
+            Some(address) => address,
+            None => {
+                let mut address = [0; 8];
+                esp!(unsafe {
+                    esp_read_mac(address.as_mut_ptr(), esp_mac_type_t_ESP_MAC_IEEE802154)
+                })?;
+
+                address
+            }
+        };
+
+        esp!(unsafe { esp_ieee802154_set_channel(conf.channel) })?;
+        esp!(unsafe { esp_ieee802154_set_txpower(conf.tx_power) })?;
+        esp!(unsafe { esp_ieee802154_set_panid(conf.pan_id) })?;
+        esp!(unsafe { esp_ieee802154_set_short_address(conf.short_address) })?;
+        esp!(unsafe { esp_ieee802154_set_extended_address(extended_address.as_ptr()) })?;
+        esp!(unsafe { esp_ieee802154_set_promiscuous(conf.promiscuous) })?;
+        esp!(unsafe { esp_ieee802154_set_auto_ack_rx(conf.auto_ack) })?;
+        esp!(unsafe { esp_ieee802154_set_auto_ack_tx(conf.auto_ack) })?;
+        esp!(unsafe { esp_ieee802154_set_rx_when_idle(conf.rx_when_idle) })?;
+
+        if conf.rx_when_idle {
+            esp!(unsafe { esp_ieee802154_receive() })?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the channel, 11 to 26, e.g. to hop channels when sniffing
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), EspError> {
+        esp!(unsafe { esp_ieee802154_set_channel(channel) })
+    }
+
+    /// Wait up to `timeout` ticks for a frame
+    pub fn receive(&mut self, timeout: TickType_t) -> Option<ReceivedFrame> {
+        self.rx_queue.recv_front(timeout).map(|(frame, _)| frame)
+    }
+
+    /// Transmit a MAC frame - without its FCS - and wait until it is transmitted, with
+    /// whether an ACK was received
+    ///
+    /// With `cca`, the frame is transmitted only if the clear channel assessment finds the
+    /// channel free.
+    pub fn transmit(&mut self, frame: &[u8], cca: bool) -> Result<Result<bool, TxError>, EspError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        // A result of a transmission which timed out previously
+        let _ = self.tx_queue.recv_front(0);
+
+        self.tx_frame[0] = (frame.len() + FCS_LEN) as _;
+        self.tx_frame[1..=frame.len()].copy_from_slice(frame);
+
+        esp!(unsafe { esp_ieee802154_transmit(self.tx_frame.as_ptr(), cca) })?;
+
+        self.tx_queue
+            .recv_front(portMAX_DELAY)
+            .map(|(result, _)| result)
+            .ok_or(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+}
+
+impl Drop for EspIeee802154 {
+    fn drop(&mut self) {
+        esp!(unsafe { esp_ieee802154_disable() }).unwrap();
+
+        RX_QUEUE.store(ptr::null_mut(), Ordering::Release);
+        TX_QUEUE.store(ptr::null_mut(), Ordering::Release);
+
+        *TAKEN.lock() = false;
+
+        info!("Disabled");
+    }
+}
+
+unsafe impl Send for EspIeee802154 {}
+
+// The callbacks of the driver, which it expects the application to define; they are called
+// in the ISR of the radio
+
+#[no_mangle]
+unsafe extern "C" fn esp_ieee802154_receive_done(
+    frame: *mut u8,
+    frame_info: *mut esp_ieee802154_frame_info_t,
+) {
+    if let Some(queue) = RX_QUEUE.load(Ordering::Acquire).as_ref() {
+        let _ = queue.send_back(ReceivedFrame::from_raw(frame, &*frame_info), 0);
+    }
+
+    esp_ieee802154_receive_handle_done(frame);
+}
+
+#[no_mangle]
+unsafe extern "C" fn esp_ieee802154_transmit_done(
+    _frame: *const u8,
+    ack: *mut u8,
+    _ack_frame_info: *mut esp_ieee802154_frame_info_t,
+) {
+    if let Some(queue) = TX_QUEUE.load(Ordering::Acquire).as_ref() {
+        let _ = queue.send_back(Ok(!ack.is_null()), 0);
+    }
+
+    if !ack.is_null() {
+        esp_ieee802154_receive_handle_done(ack);
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn esp_ieee802154_transmit_failed(
+    _frame: *const u8,
+    error: esp_ieee802154_tx_error_t,
+) {
+    if let Some(queue) = TX_QUEUE.load(Ordering::Acquire).as_ref() {
+        let _ = queue.send_back(Err(TxError::from_raw(error)), 0);
+    }
+}
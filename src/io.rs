@@ -1,6 +1,33 @@
 pub use embedded_svc::utils::io as utils;
 pub use esp_idf_hal::io::*;
 
+#[cfg(esp_idf_comp_vfs_enabled)]
+pub mod file;
+
+#[cfg(all(feature = "std", esp_idf_comp_vfs_enabled, esp_idf_vfs_support_select))]
+pub mod reactor;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_vfs_enabled,
+    esp_idf_vfs_support_select
+))]
+mod socket;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_vfs_enabled,
+    esp_idf_vfs_support_select
+))]
+pub mod tcp;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_lwip_enabled,
+    esp_idf_comp_vfs_enabled,
+    esp_idf_vfs_support_select
+))]
+pub mod udp;
+
 #[cfg(esp_idf_comp_vfs_enabled)]
 pub mod vfs {
     use crate::sys;
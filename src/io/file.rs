@@ -0,0 +1,308 @@
+//! Files and file descriptors of the VFS, as `embedded_io` and `std::io` streams
+//!
+//! `EspFile` wraps a file descriptor of the VFS - a file of a mounted filesystem, but also
+//! e.g. a UART or a socket - and implements both the `embedded_io` traits and - with `std` -
+//! the `std::io` traits, so that library code can be written against one set of traits. It
+//! converts from and to raw file descriptors, `std::fs::File` and C `FILE` streams.
+
+use core::ffi::{c_int, CStr};
+
+use embedded_svc::io::{ErrorType, Read, Seek, SeekFrom, Write};
+
+use crate::sys::*;
+
+use super::EspIOError;
+
+/// The options of opening a file, as with `std::fs::OpenOptions`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Whether all the writes go to the end of the file.
+    pub append: bool,
+    /// Whether to create the file if it does not exist.
+    pub create: bool,
+    /// Whether to truncate the file to 0 length if it exists.
+    pub truncate: bool,
+}
+
+impl OpenOptions {
+    fn flags(&self) -> c_int {
+        let mut flags = match (self.read, self.write || self.append) {
+            (true, true) => O_RDWR,
+            (false, true) => O_WRONLY,
+            _ => O_RDONLY,
+        };
+
+        if self.append {
+            flags |= O_APPEND;
+        }
+
+        if self.create {
+            flags |= O_CREAT;
+        }
+
+        if self.truncate {
+            flags |= O_TRUNC;
+        }
+
+        flags as _
+    }
+}
+
+/// A file descriptor of the VFS, closed when dropped.
+#[derive(Debug)]
+pub struct EspFile(c_int);
+
+impl EspFile {
+    /// Open a file for reading.
+    pub fn open(path: &CStr) -> Result<Self, EspError> {
+        Self::open_with(
+            path,
+            &OpenOptions {
+                read: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Create a file for writing, truncating it if it exists.
+    pub fn create(path: &CStr) -> Result<Self, EspError> {
+        Self::open_with(
+            path,
+            &OpenOptions {
+                write: true,
+                create: true,
+                truncate: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Open a file, e.g. `/spiffs/log.txt` or `/dev/uart/0`, with the options.
+    pub fn open_with(path: &CStr, options: &OpenOptions) -> Result<Self, EspError> {
+        let fd = unsafe { open(path.as_ptr(), options.flags(), 0o666) };
+
+        if fd < 0 {
+            Err(last_error())
+        } else {
+            Ok(Self(fd))
+        }
+    }
+
+    /// Take the ownership of a file descriptor.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor, not owned elsewhere.
+    pub unsafe fn from_raw_fd(fd: c_int) -> Self {
+        Self(fd)
+    }
+
+    /// Take the ownership of the file descriptor of a C `FILE` stream, closing the stream
+    /// without closing its file descriptor.
+    ///
+    /// # Safety
+    ///
+    /// `file` must be an open stream, not used afterwards.
+    pub unsafe fn from_file_ptr(file: *mut FILE) -> Result<Self, EspError> {
+        fflush(file);
+
+        let fd = dup(fileno(file));
+        fclose(file);
+
+        if fd < 0 {
+            Err(last_error())
+        } else {
+            Ok(Self(fd))
+        }
+    }
+
+    /// Return a C `FILE` stream owning the file descriptor, e.g. for C libraries; `mode` - e.g.
+    /// `c"r"` - must match the mode the file was opened with.
+    ///
+    /// The stream must be closed with `fclose`.
+    pub fn into_file_ptr(self, mode: &CStr) -> Result<*mut FILE, EspError> {
+        let file = unsafe { fdopen(self.0, mode.as_ptr()) };
+
+        if file.is_null() {
+            Err(last_error())
+        } else {
+            // Owned by the stream from now on
+            core::mem::forget(self);
+
+            Ok(file)
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> c_int {
+        self.0
+    }
+
+    /// Release the ownership of the file descriptor.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptor must be closed elsewhere.
+    pub unsafe fn into_raw_fd(self) -> c_int {
+        let fd = self.0;
+        core::mem::forget(self);
+
+        fd
+    }
+
+    /// Flush the data written to the storage.
+    pub fn sync(&self) -> Result<(), EspError> {
+        if unsafe { fsync(self.0) } < 0 {
+            Err(last_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn raw_read(&self, buf: &mut [u8]) -> isize {
+        unsafe { read(self.0, buf.as_mut_ptr() as *mut _, buf.len()) as _ }
+    }
+
+    fn raw_write(&self, buf: &[u8]) -> isize {
+        unsafe { write(self.0, buf.as_ptr() as *const _, buf.len()) as _ }
+    }
+
+    fn raw_seek(&self, pos: SeekFrom) -> i64 {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (offset as i64, SEEK_SET),
+            SeekFrom::End(offset) => (offset, SEEK_END),
+            SeekFrom::Current(offset) => (offset, SEEK_CUR),
+        };
+
+        unsafe { lseek(self.0, offset as _, whence as _) as _ }
+    }
+}
+
+impl Drop for EspFile {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0);
+        }
+    }
+}
+
+impl ErrorType for EspFile {
+    type Error = EspIOError;
+}
+
+impl Read for EspFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self.raw_read(buf) {
+            len if len < 0 => Err(EspIOError(last_error())),
+            len => Ok(len as _),
+        }
+    }
+}
+
+impl Write for EspFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        match self.raw_write(buf) {
+            len if len < 0 => Err(EspIOError(last_error())),
+            len => Ok(len as _),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl Seek for EspFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        match self.raw_seek(pos) {
+            offset if offset < 0 => Err(EspIOError(last_error())),
+            offset => Ok(offset as _),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod std_io {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+    use super::EspFile;
+
+    impl io::Read for EspFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.raw_read(buf) {
+                len if len < 0 => Err(io::Error::last_os_error()),
+                len => Ok(len as _),
+            }
+        }
+    }
+
+    impl io::Write for EspFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.raw_write(buf) {
+                len if len < 0 => Err(io::Error::last_os_error()),
+                len => Ok(len as _),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Seek for EspFile {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            let pos = match pos {
+                io::SeekFrom::Start(offset) => super::SeekFrom::Start(offset),
+                io::SeekFrom::End(offset) => super::SeekFrom::End(offset),
+                io::SeekFrom::Current(offset) => super::SeekFrom::Current(offset),
+            };
+
+            match self.raw_seek(pos) {
+                offset if offset < 0 => Err(io::Error::last_os_error()),
+                offset => Ok(offset as _),
+            }
+        }
+    }
+
+    impl AsRawFd for EspFile {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0
+        }
+    }
+
+    impl FromRawFd for EspFile {
+        unsafe fn from_raw_fd(fd: RawFd) -> Self {
+            Self(fd)
+        }
+    }
+
+    impl IntoRawFd for EspFile {
+        fn into_raw_fd(self) -> RawFd {
+            unsafe { EspFile::into_raw_fd(self) }
+        }
+    }
+
+    impl From<std::fs::File> for EspFile {
+        fn from(file: std::fs::File) -> Self {
+            Self(file.into_raw_fd())
+        }
+    }
+
+    impl From<EspFile> for std::fs::File {
+        fn from(file: EspFile) -> Self {
+            unsafe { std::fs::File::from_raw_fd(file.into_raw_fd()) }
+        }
+    }
+}
+
+fn last_error() -> EspError {
+    match unsafe { *__errno() } as u32 {
+        EAGAIN => EspError::from_infallible::<ESP_ERR_TIMEOUT>(),
+        ENOENT => EspError::from_infallible::<ESP_ERR_NOT_FOUND>(),
+        ENOMEM | ENOSPC => EspError::from_infallible::<ESP_ERR_NO_MEM>(),
+        EINVAL | EBADF => EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+        _ => EspError::from_infallible::<ESP_FAIL>(),
+    }
+}
@@ -0,0 +1,387 @@
+//! A reactor waking the tasks waiting for file descriptors to become readable or writable
+//!
+//! A single thread waits with `poll` for the readiness of all the registered file descriptors,
+//! plus an eventfd used to interrupt the wait when a new registration is made. This allows
+//! awaiting sockets, UART drivers registered with the VFS and eventfds from any executor -
+//! e.g. embassy - without busy polling.
+//!
+//! The reactor registers the eventfd VFS with a few file descriptors on first use, unless it is
+//! already registered; mount `MountedEventfs` beforehand to allow more `EventFd`s.
+
+use core::ffi::{c_int, c_void};
+use core::task::{Poll, Waker};
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+const STACK_SIZE: usize = 3072;
+
+// The eventfds registered if the eventfd VFS is not registered yet
+const EVENTFD_MAX_FDS: usize = 5;
+
+/// The readiness a task waits for
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interest {
+    Readable,
+    Writable,
+}
+
+impl Interest {
+    fn events(&self) -> i16 {
+        match self {
+            Self::Readable => POLLIN as _,
+            Self::Writable => POLLOUT as _,
+        }
+    }
+}
+
+struct Registration {
+    fd: c_int,
+    interest: Interest,
+    // All the tasks waiting for the same readiness of the file descriptor
+    wakers: Vec<Waker>,
+}
+
+/// The reactor thread, shared by all the file descriptors awaited
+pub struct Reactor {
+    registrations: Mutex<Vec<Registration>>,
+    wake_fd: c_int,
+}
+
+impl Reactor {
+    /// Return the reactor, starting it on first use
+    pub fn get() -> Result<&'static Self, EspError> {
+        static REACTOR: Mutex<Option<&'static Reactor>> = Mutex::new(None);
+
+        let mut reactor = REACTOR.lock();
+
+        if let Some(reactor) = *reactor {
+            return Ok(reactor);
+        }
+
+        let started: &'static Self = Box::leak(Box::new(Self::new()?));
+
+        std::thread::Builder::new()
+            .name("reactor".into())
+            .stack_size(STACK_SIZE)
+            .spawn(move || started.run())
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        *reactor = Some(started);
+
+        Ok(started)
+    }
+
+    /// Wait until `fd` is ready for `interest`
+    ///
+    /// Note that the wake-ups can be spurious, so the operation should be retried until it does
+    /// not return `EWOULDBLOCK` anymore.
+    pub async fn wait(&self, fd: RawFd, interest: Interest) {
+        let mut registered = false;
+
+        core::future::poll_fn(|ctx| {
+            if registered {
+                Poll::Ready(())
+            } else {
+                self.register(fd, interest, ctx.waker());
+                registered = true;
+
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    fn new() -> Result<Self, EspError> {
+        #[allow(clippy::needless_update)]
+        let ret = unsafe {
+            esp_vfs_eventfd_register(&esp_vfs_eventfd_config_t {
+                max_fds: EVENTFD_MAX_FDS as _,
+                ..Default::default()
+            })
+        };
+
+        // Already registered, e.g. with `MountedEventfs`
+        if ret != ESP_ERR_INVALID_STATE {
+            esp!(ret)?;
+        }
+
+        let wake_fd = unsafe { eventfd(0, 0) };
+        if wake_fd < 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        Ok(Self {
+            registrations: Mutex::new(Vec::new()),
+            wake_fd,
+        })
+    }
+
+    fn register(&self, fd: c_int, interest: Interest, waker: &Waker) {
+        {
+            let mut registrations = self.registrations.lock();
+
+            if let Some(registration) = registrations
+                .iter_mut()
+                .find(|registration| registration.fd == fd && registration.interest == interest)
+            {
+                if !registration
+                    .wakers
+                    .iter()
+                    .any(|other| other.will_wake(waker))
+                {
+                    registration.wakers.push(waker.clone());
+                }
+            } else {
+                registrations.push(Registration {
+                    fd,
+                    interest,
+                    wakers: alloc::vec![waker.clone()],
+                });
+            }
+        }
+
+        let value: u64 = 1;
+        unsafe { write(self.wake_fd, &value as *const _ as *const c_void, 8) };
+    }
+
+    fn run(&self) -> ! {
+        let mut fds = Vec::new();
+
+        loop {
+            fds.clear();
+            fds.push(pollfd {
+                fd: self.wake_fd,
+                events: POLLIN as _,
+                revents: 0,
+            });
+
+            fds.extend(self.registrations.lock().iter().map(|registration| pollfd {
+                fd: registration.fd,
+                events: registration.interest.events(),
+                revents: 0,
+            }));
+
+            if unsafe { poll(fds.as_mut_ptr(), fds.len() as _, -1) } < 0 {
+                // E.g. a registered file descriptor was closed meanwhile. `poll` then fails
+                // without telling which one, so the invalid ones are looked up, to wake their
+                // tasks below
+                for fd in &mut fds[1..] {
+                    if unsafe { fcntl(fd.fd, F_GETFL as _) } < 0 {
+                        fd.revents = POLLNVAL as _;
+                    }
+                }
+
+                if fds.iter().all(|fd| fd.revents == 0) {
+                    std::thread::sleep(core::time::Duration::from_millis(10));
+                }
+            }
+
+            if fds[0].revents != 0 {
+                let mut value: u64 = 0;
+                unsafe { read(self.wake_fd, &mut value as *mut _ as *mut c_void, 8) };
+            }
+
+            let failed = (POLLERR | POLLHUP | POLLNVAL) as i16;
+
+            self.registrations.lock().retain_mut(|registration| {
+                let ready = fds[1..].iter().any(|fd| {
+                    fd.fd == registration.fd
+                        && fd.events == registration.interest.events()
+                        && fd.revents & (fd.events | failed) != 0
+                });
+
+                if ready {
+                    for waker in registration.wakers.drain(..) {
+                        waker.wake();
+                    }
+                }
+
+                !ready
+            });
+        }
+    }
+}
+
+/// Run a non-blocking operation until it does not return `WouldBlock` anymore, waiting for the
+/// readiness of `fd` in between
+pub(crate) async fn async_io<R>(
+    fd: RawFd,
+    interest: Interest,
+    mut op: impl FnMut() -> io::Result<R>,
+) -> io::Result<R> {
+    loop {
+        match op() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                Reactor::get()
+                    .map_err(io::Error::other)?
+                    .wait(fd, interest)
+                    .await
+            }
+            result => break result,
+        }
+    }
+}
+
+/// A non-blocking file descriptor, e.g. of a UART driver registered with the VFS and opened
+/// with `O_NONBLOCK`, whose readiness can be awaited
+#[derive(Debug)]
+pub struct AsyncFd<T: AsRawFd>(T);
+
+impl<T: AsRawFd> AsyncFd<T> {
+    /// Wrap `inner`, which must already be in non-blocking mode
+    pub const fn new(inner: T) -> Self {
+        Self(inner)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.0
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+
+    /// Wait until the file descriptor is likely readable
+    pub async fn readable(&self) -> io::Result<()> {
+        Reactor::get()
+            .map_err(io::Error::other)?
+            .wait(self.0.as_raw_fd(), Interest::Readable)
+            .await;
+
+        Ok(())
+    }
+
+    /// Wait until the file descriptor is likely writable
+    pub async fn writable(&self) -> io::Result<()> {
+        Reactor::get()
+            .map_err(io::Error::other)?
+            .wait(self.0.as_raw_fd(), Interest::Writable)
+            .await;
+
+        Ok(())
+    }
+
+    /// Run `op` until it does not return `WouldBlock` anymore, waiting for the file descriptor
+    /// to become ready for `interest` in between
+    pub async fn io<R>(
+        &self,
+        interest: Interest,
+        mut op: impl FnMut(&T) -> io::Result<R>,
+    ) -> io::Result<R> {
+        async_io(self.0.as_raw_fd(), interest, || op(&self.0)).await
+    }
+
+    /// As `io`, with mutable access to the wrapped value
+    pub async fn io_mut<R>(
+        &mut self,
+        interest: Interest,
+        mut op: impl FnMut(&mut T) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let fd = self.0.as_raw_fd();
+
+        async_io(fd, interest, || op(&mut self.0)).await
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for AsyncFd<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// An eventfd: a counter which can be notified from any task - or from an ISR, if created with
+/// `EventFd::new_isr` - and awaited
+#[derive(Debug)]
+pub struct EventFd(OwnedFd);
+
+impl EventFd {
+    /// Create an eventfd with the initial value of its counter
+    pub fn new(initial: u32) -> Result<Self, EspError> {
+        Self::create(initial, 0)
+    }
+
+    /// Create an eventfd which can also be notified from an ISR, with `notify`
+    pub fn new_isr(initial: u32) -> Result<Self, EspError> {
+        Self::create(initial, EFD_SUPPORT_ISR as _)
+    }
+
+    /// Add `value` to the counter, waking the task awaiting it
+    pub fn notify(&self, value: u64) -> Result<(), EspError> {
+        let ret = unsafe {
+            write(
+                self.0.as_raw_fd(),
+                &value as *const _ as *const c_void,
+                core::mem::size_of::<u64>(),
+            )
+        };
+
+        if ret < 0 {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return and reset the counter, or `None` if it is zero
+    pub fn try_wait(&self) -> Option<u64> {
+        let mut ready = pollfd {
+            fd: self.0.as_raw_fd(),
+            events: POLLIN as _,
+            revents: 0,
+        };
+
+        if unsafe { poll(&mut ready, 1, 0) } <= 0 {
+            return None;
+        }
+
+        let mut value: u64 = 0;
+        let ret = unsafe {
+            read(
+                self.0.as_raw_fd(),
+                &mut value as *mut _ as *mut c_void,
+                core::mem::size_of::<u64>(),
+            )
+        };
+
+        (ret > 0 && value != 0).then_some(value)
+    }
+
+    /// Wait until the counter is not zero, then return and reset it
+    pub async fn wait(&self) -> Result<u64, EspError> {
+        async_io(self.0.as_raw_fd(), Interest::Readable, || {
+            self.try_wait().ok_or(io::ErrorKind::WouldBlock.into())
+        })
+        .await
+        .map_err(|_| EspError::from_infallible::<ESP_FAIL>())
+    }
+
+    fn create(initial: u32, flags: c_int) -> Result<Self, EspError> {
+        // Registers the eventfd VFS, if necessary
+        Reactor::get()?;
+
+        let fd = unsafe { eventfd(initial as _, flags) };
+        if fd < 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
@@ -0,0 +1,165 @@
+//! The socket options and helpers shared by the UDP and TCP sockets
+
+use core::ffi::{c_int, c_void};
+
+use std::io;
+use std::net::SocketAddr;
+
+use crate::sys::*;
+
+/// The options applied to a socket before it is bound
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    /// Allow binding to an address still in use, e.g. by a TCP socket in `TIME_WAIT`, or by
+    /// another receiver of the same multicast group (`SO_REUSEADDR`)
+    #[cfg(esp_idf_lwip_so_reuse)]
+    pub reuse_address: bool,
+    /// Allow sending to broadcast addresses (`SO_BROADCAST`), for UDP sockets
+    pub broadcast: bool,
+    /// The time-to-live of the unicast packets (`IP_TTL`)
+    pub ttl: Option<u8>,
+    /// The size of the receive buffer, in bytes (`SO_RCVBUF`)
+    #[cfg(esp_idf_lwip_so_rcvbuf)]
+    pub recv_buffer_size: Option<usize>,
+    /// Only accept IPv6 traffic on an IPv6 socket bound to the unspecified address
+    /// (`IPV6_V6ONLY`)
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub ipv6_only: bool,
+}
+
+/// Create a socket with the options, bound to `addr`
+pub(crate) fn bind(
+    addr: &SocketAddr,
+    sock_type: u32,
+    protocol: u32,
+    options: &SocketOptions,
+) -> io::Result<c_int> {
+    let fd = socket(addr, sock_type, protocol)?;
+
+    let result = (|| {
+        #[cfg(esp_idf_lwip_so_reuse)]
+        if options.reuse_address {
+            setsockopt(fd, SOL_SOCKET, SO_REUSEADDR, 1_i32)?;
+        }
+
+        if options.broadcast {
+            setsockopt(fd, SOL_SOCKET, SO_BROADCAST, 1_i32)?;
+        }
+
+        if let Some(ttl) = options.ttl {
+            setsockopt(fd, IPPROTO_IP, IP_TTL, ttl as c_int)?;
+        }
+
+        #[cfg(esp_idf_lwip_so_rcvbuf)]
+        if let Some(size) = options.recv_buffer_size {
+            setsockopt(fd, SOL_SOCKET, SO_RCVBUF, size as c_int)?;
+        }
+
+        #[cfg(esp_idf_lwip_ipv6)]
+        if options.ipv6_only && addr.is_ipv6() {
+            setsockopt(fd, IPPROTO_IPV6, IPV6_V6ONLY, 1_i32)?;
+        }
+
+        let (sockaddr, len) = to_sockaddr(addr);
+
+        if unsafe { lwip_bind(fd, &sockaddr as *const _ as *const sockaddr, len) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    })();
+
+    if let Err(err) = result {
+        unsafe { lwip_close(fd) };
+
+        return Err(err);
+    }
+
+    Ok(fd)
+}
+
+/// Create an unbound socket for the address family of `addr`
+pub(crate) fn socket(addr: &SocketAddr, sock_type: u32, protocol: u32) -> io::Result<c_int> {
+    let domain = if addr.is_ipv4() { AF_INET } else { AF_INET6 };
+
+    let fd = unsafe { lwip_socket(domain as _, sock_type as _, protocol as _) };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd)
+    }
+}
+
+pub(crate) fn setsockopt<T>(fd: c_int, level: u32, name: u32, value: T) -> io::Result<()> {
+    let ret = unsafe {
+        lwip_setsockopt(
+            fd,
+            level as _,
+            name as _,
+            &value as *const _ as *const c_void,
+            core::mem::size_of::<T>() as _,
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn getsockopt<T: Default>(fd: c_int, level: u32, name: u32) -> io::Result<T> {
+    let mut value = T::default();
+    let mut len = core::mem::size_of::<T>() as socklen_t;
+
+    let ret = unsafe {
+        lwip_getsockopt(
+            fd,
+            level as _,
+            name as _,
+            &mut value as *mut _ as *mut c_void,
+            &mut len,
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(value)
+    }
+}
+
+pub(crate) fn to_sockaddr(addr: &SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let mut storage: sockaddr_storage = unsafe { core::mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut sockaddr_in) };
+
+            sin.sin_len = core::mem::size_of::<sockaddr_in>() as _;
+            sin.sin_family = AF_INET as _;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+
+            core::mem::size_of::<sockaddr_in>()
+        }
+        #[cfg(esp_idf_lwip_ipv6)]
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut sockaddr_in6) };
+
+            sin6.sin6_len = core::mem::size_of::<sockaddr_in6>() as _;
+            sin6.sin6_family = AF_INET6 as _;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo().to_be();
+            sin6.sin6_addr.un.u8_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+
+            core::mem::size_of::<sockaddr_in6>()
+        }
+        #[cfg(not(esp_idf_lwip_ipv6))]
+        SocketAddr::V6(_) => 0,
+    };
+
+    (storage, len as _)
+}
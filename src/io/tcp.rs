@@ -0,0 +1,272 @@
+//! Non-blocking TCP sockets, with async connect, accept, read and write
+//!
+//! `TcpListener` and `TcpStream` wrap the non-blocking `std::net` sockets: the `try_*`
+//! operations return `WouldBlock` instead of blocking, while the async operations wait for the
+//! readiness of the socket without busy polling, so that they can be awaited from any executor.
+//! `TcpStream` also implements the `embedded_io_async` traits.
+
+use core::ffi::c_int;
+use core::time::Duration;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use embedded_svc::io::{self as svc_io, asynch};
+
+use crate::sys::*;
+
+use super::reactor::{async_io, Interest};
+use super::socket;
+
+pub use super::socket::SocketOptions;
+
+// The default length of the queue of the pending connections
+const BACKLOG: c_int = 5;
+
+/// The TCP keep-alive probes of a connection
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KeepAlive {
+    /// The idle time before the first probe
+    pub idle: Duration,
+    /// The interval between the probes
+    pub interval: Duration,
+    /// The number of unanswered probes after which the connection is dropped
+    pub count: u32,
+}
+
+/// A non-blocking TCP listener
+#[derive(Debug)]
+pub struct TcpListener(std::net::TcpListener);
+
+impl TcpListener {
+    /// Create a listener bound to `addr`, e.g. `0.0.0.0:8080`
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with(addr, &Default::default())
+    }
+
+    /// Create a listener with the options, bound to `addr`; use `reuse_address` to restart
+    /// listening while previous connections are in `TIME_WAIT`
+    pub fn bind_with(addr: SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let fd = socket::bind(&addr, SOCK_STREAM, IPPROTO_TCP, options)?;
+
+        // Owned, and closed on error, from now on
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+
+        if unsafe { lwip_listen(fd, BACKLOG) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Self::from_std(listener)
+    }
+
+    /// Wrap a `std::net::TcpListener`, switching it to non-blocking mode
+    pub fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+
+        Ok(Self(listener))
+    }
+
+    pub fn std(&self) -> &std::net::TcpListener {
+        &self.0
+    }
+
+    pub fn into_std(self) -> std::net::TcpListener {
+        self.0
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    /// Wait for the next connection
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) =
+            async_io(self.0.as_raw_fd(), Interest::Readable, || self.0.accept()).await?;
+
+        Ok((TcpStream::from_std(stream)?, addr))
+    }
+
+    pub fn try_accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = self.0.accept()?;
+
+        Ok((TcpStream::from_std(stream)?, addr))
+    }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// A non-blocking TCP connection
+#[derive(Debug)]
+pub struct TcpStream(std::net::TcpStream);
+
+impl TcpStream {
+    /// Connect to `addr`, without blocking while the connection is established
+    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let fd = socket::socket(&addr, SOCK_STREAM, IPPROTO_TCP)?;
+
+        // Owned, and closed on error, from now on
+        let stream = Self::from_std(unsafe { std::net::TcpStream::from_raw_fd(fd) })?;
+
+        let (sockaddr, len) = socket::to_sockaddr(&addr);
+
+        if unsafe { lwip_connect(fd, &sockaddr as *const _ as *const sockaddr, len) } < 0 {
+            let err = io::Error::last_os_error();
+
+            if err.raw_os_error() != Some(EINPROGRESS as _) {
+                return Err(err);
+            }
+
+            // Writable once connected, or failed
+            async_io(fd, Interest::Writable, || {
+                let mut pending = pollfd {
+                    fd,
+                    events: POLLOUT as _,
+                    revents: 0,
+                };
+
+                if unsafe { poll(&mut pending, 1, 0) } > 0 {
+                    Ok(())
+                } else {
+                    Err(io::ErrorKind::WouldBlock.into())
+                }
+            })
+            .await?;
+
+            let error: c_int = socket::getsockopt(fd, SOL_SOCKET, SO_ERROR)?;
+            if error != 0 {
+                return Err(io::Error::from_raw_os_error(error));
+            }
+        }
+
+        Ok(stream)
+    }
+
+    /// Wrap a `std::net::TcpStream`, switching it to non-blocking mode
+    pub fn from_std(stream: std::net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+
+        Ok(Self(stream))
+    }
+
+    pub fn std(&self) -> &std::net::TcpStream {
+        &self.0
+    }
+
+    pub fn into_std(self) -> std::net::TcpStream {
+        self.0
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = self.0.as_raw_fd();
+
+        async_io(fd, Interest::Readable, || self.0.read(buf)).await
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fd = self.0.as_raw_fd();
+
+        async_io(fd, Interest::Writable, || self.0.write(buf)).await
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                len => buf = &buf[len..],
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+
+    pub fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+
+    /// Disable the Nagle algorithm, i.e. send the small writes immediately
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.0.set_nodelay(nodelay)
+    }
+
+    /// Enable or disable the keep-alive probes
+    pub fn set_keepalive(&self, keepalive: Option<KeepAlive>) -> io::Result<()> {
+        let fd = self.0.as_raw_fd();
+
+        socket::setsockopt(fd, SOL_SOCKET, SO_KEEPALIVE, keepalive.is_some() as c_int)?;
+
+        if let Some(keepalive) = keepalive {
+            socket::setsockopt(
+                fd,
+                IPPROTO_TCP,
+                TCP_KEEPIDLE,
+                keepalive.idle.as_secs() as c_int,
+            )?;
+            socket::setsockopt(
+                fd,
+                IPPROTO_TCP,
+                TCP_KEEPINTVL,
+                keepalive.interval.as_secs() as c_int,
+            )?;
+            socket::setsockopt(fd, IPPROTO_TCP, TCP_KEEPCNT, keepalive.count as c_int)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.0.set_ttl(ttl)
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for TcpStream {
+    fn into_raw_fd(self) -> RawFd {
+        self.0.into_raw_fd()
+    }
+}
+
+impl svc_io::ErrorType for TcpStream {
+    type Error = io::Error;
+}
+
+impl asynch::Read for TcpStream {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        TcpStream::read(self, buf).await
+    }
+}
+
+impl asynch::Write for TcpStream {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        TcpStream::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
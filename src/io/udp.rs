@@ -0,0 +1,191 @@
+//! Non-blocking UDP sockets, with async send and receive
+//!
+//! `UdpSocket` wraps a non-blocking `std::net::UdpSocket`: the `try_*` operations return
+//! `WouldBlock` instead of blocking, while the async operations wait for the readiness of
+//! the socket without busy polling, so that they can be awaited from any executor.
+//!
+//! The options which must be set before binding - e.g. `SO_REUSEADDR` for several receivers of
+//! the same multicast group - are passed with `SocketOptions` to `UdpSocket::bind_with`.
+
+use core::ffi::c_int;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use crate::sys::*;
+
+use super::reactor::{async_io, Interest};
+use super::socket;
+
+pub use super::socket::SocketOptions;
+
+/// A non-blocking UDP socket
+#[derive(Debug)]
+pub struct UdpSocket(std::net::UdpSocket);
+
+impl UdpSocket {
+    /// Create a socket bound to `addr`, e.g. `0.0.0.0:5683`
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with(addr, &Default::default())
+    }
+
+    /// Create a socket with the options, bound to `addr`
+    pub fn bind_with(addr: SocketAddr, options: &SocketOptions) -> io::Result<Self> {
+        let fd = socket::bind(&addr, SOCK_DGRAM, IPPROTO_UDP, options)?;
+
+        Self::from_std(unsafe { std::net::UdpSocket::from_raw_fd(fd) })
+    }
+
+    /// Wrap a `std::net::UdpSocket`, switching it to non-blocking mode
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+
+        Ok(Self(socket))
+    }
+
+    /// Return the wrapped socket, e.g. for the options not covered here
+    pub fn std(&self) -> &std::net::UdpSocket {
+        &self.0
+    }
+
+    pub fn into_std(self) -> std::net::UdpSocket {
+        self.0
+    }
+
+    /// Set the default destination of `send`, and only receive from it with `recv`
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.0.connect(addr)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        async_io(self.fd(), Interest::Writable, || self.0.send_to(buf, addr)).await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        async_io(self.fd(), Interest::Readable, || self.0.recv_from(buf)).await
+    }
+
+    /// Send to the address the socket is connected to
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        async_io(self.fd(), Interest::Writable, || self.0.send(buf)).await
+    }
+
+    /// Receive from the address the socket is connected to
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        async_io(self.fd(), Interest::Readable, || self.0.recv(buf)).await
+    }
+
+    /// Receive without removing the datagram from the queue, e.g. to learn its sender
+    pub async fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        async_io(self.fd(), Interest::Readable, || self.0.peek_from(buf)).await
+    }
+
+    pub fn try_send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.0.send_to(buf, addr)
+    }
+
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf)
+    }
+
+    pub fn try_send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    pub fn try_recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    /// Join an IPv4 multicast group on the interface with the given address, or on the
+    /// default interface with `Ipv4Addr::UNSPECIFIED`
+    pub fn join_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        self.0.join_multicast_v4(&group, &interface)
+    }
+
+    pub fn leave_multicast_v4(&self, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+        self.0.leave_multicast_v4(&group, &interface)
+    }
+
+    /// Join an IPv6 multicast group on the interface with the given index, or on the default
+    /// interface with 0
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn join_multicast_v6(&self, group: std::net::Ipv6Addr, interface: u32) -> io::Result<()> {
+        self.0.join_multicast_v6(&group, interface)
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn leave_multicast_v6(&self, group: std::net::Ipv6Addr, interface: u32) -> io::Result<()> {
+        self.0.leave_multicast_v6(&group, interface)
+    }
+
+    /// Set the time-to-live of the IPv4 multicast packets sent, 1 by default - i.e. the local
+    /// network only
+    pub fn set_multicast_ttl_v4(&self, ttl: u32) -> io::Result<()> {
+        self.0.set_multicast_ttl_v4(ttl)
+    }
+
+    /// Set whether the IPv4 multicast packets sent are looped back to the local receivers
+    pub fn set_multicast_loop_v4(&self, enable: bool) -> io::Result<()> {
+        self.0.set_multicast_loop_v4(enable)
+    }
+
+    /// Send the IPv4 multicast packets from the interface with the given address
+    pub fn set_multicast_interface_v4(&self, interface: Ipv4Addr) -> io::Result<()> {
+        socket::setsockopt(
+            self.fd(),
+            IPPROTO_IP,
+            IP_MULTICAST_IF,
+            in_addr {
+                s_addr: u32::from_ne_bytes(interface.octets()),
+            },
+        )
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.0.set_ttl(ttl)
+    }
+
+    pub fn set_broadcast(&self, enable: bool) -> io::Result<()> {
+        self.0.set_broadcast(enable)
+    }
+
+    /// Return the pending error of the socket, e.g. an ICMP port unreachable
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let error: c_int = socket::getsockopt(self.fd(), SOL_SOCKET, SO_ERROR)?;
+
+        Ok((error != 0).then(|| io::Error::from_raw_os_error(error)))
+    }
+
+    fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for UdpSocket {
+    fn into_raw_fd(self) -> RawFd {
+        self.0.into_raw_fd()
+    }
+}
+
+impl TryFrom<std::net::UdpSocket> for UdpSocket {
+    type Error = io::Error;
+
+    fn try_from(socket: std::net::UdpSocket) -> Result<Self, Self::Error> {
+        Self::from_std(socket)
+    }
+}
@@ -0,0 +1,139 @@
+//! Cross-core calls
+//!
+//! On the dual-core chips, `call_blocking` and `call` run a closure on a given core, in the
+//! IPC task of that core - e.g. to allocate an interrupt pinned to core 1, as the interrupts
+//! are allocated on the core calling `esp_intr_alloc`:
+//!
+//! ```ignore
+//! use esp_idf_svc::hal::cpu::Core;
+//! use esp_idf_svc::ipc;
+//!
+//! let core = ipc::call_blocking(Core::Core1, || esp_idf_svc::hal::cpu::core())?;
+//!
+//! let result = ipc::call(Core::Core1, || 6 * 7)?.await;
+//! ```
+//!
+//! The IPC tasks run at the highest priority, with a small stack -
+//! `CONFIG_ESP_IPC_TASK_STACK_SIZE` - so the closures should be short and not block.
+
+use core::ffi::c_void;
+use core::future::Future;
+use core::num::NonZeroU32;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use esp_idf_hal::task::asynch::Notification;
+
+use crate::hal::cpu::Core;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+/// Run `f` on `core`, waiting for its completion
+pub fn call_blocking<F, R>(core: Core, f: F) -> Result<R, EspError>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    let mut state = (Some(f), None);
+
+    esp!(unsafe {
+        esp_ipc_call_blocking(
+            core as _,
+            Some(blocking_trampoline::<F, R>),
+            &mut state as *mut _ as *mut c_void,
+        )
+    })?;
+
+    Ok(state.1.unwrap())
+}
+
+unsafe extern "C" fn blocking_trampoline<F, R>(arg: *mut c_void)
+where
+    F: FnOnce() -> R,
+{
+    let state = &mut *(arg as *mut (Option<F>, Option<R>));
+
+    state.1 = Some((state.0.take().unwrap())());
+}
+
+/// Run `f` on `core`, without waiting for its completion: the returned `IpcCall` resolves
+/// to the result of `f` once it completed
+pub fn call<F, R>(core: Core, f: F) -> Result<IpcCall<R>, EspError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let shared = Arc::new(Shared {
+        result: Mutex::new(None),
+        notification: Notification::new(),
+    });
+
+    let call: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new({
+        let shared = shared.clone();
+
+        move || {
+            let result = f();
+
+            *shared.result.lock() = Some(result);
+            shared.notification.notify(NonZeroU32::new(1).unwrap());
+        }
+    }));
+
+    let arg = Box::into_raw(call);
+
+    if let Err(err) = esp!(unsafe { esp_ipc_call(core as _, Some(trampoline), arg as *mut _) }) {
+        drop(unsafe { Box::from_raw(arg) });
+
+        return Err(err);
+    }
+
+    Ok(IpcCall(shared))
+}
+
+unsafe extern "C" fn trampoline(arg: *mut c_void) {
+    let call = Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>);
+
+    call();
+}
+
+struct Shared<R> {
+    result: Mutex<Option<R>>,
+    notification: Notification,
+}
+
+/// A call running on another core, resolving to its result
+pub struct IpcCall<R>(Arc<Shared<R>>);
+
+impl<R> IpcCall<R> {
+    /// Whether the call completed
+    pub fn is_complete(&self) -> bool {
+        self.0.result.lock().is_some()
+    }
+
+    /// Take the result of the call, if it completed
+    pub fn try_take(&mut self) -> Option<R> {
+        self.0.result.lock().take()
+    }
+}
+
+impl<R> Future for IpcCall<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(result) = self.0.result.lock().take() {
+            return Poll::Ready(result);
+        }
+
+        if self.0.notification.poll_wait(cx).is_ready() {
+            if let Some(result) = self.0.result.lock().take() {
+                return Poll::Ready(result);
+            }
+        }
+
+        Poll::Pending
+    }
+}
@@ -26,14 +26,42 @@ extern crate std;
 #[macro_use]
 extern crate alloc;
 
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_timer_enabled))]
+pub mod at;
 #[cfg(not(esp32s2))]
 #[cfg(all(
     esp_idf_bt_enabled,
-    esp_idf_bt_bluedroid_enabled,
+    any(esp_idf_bt_bluedroid_enabled, esp_idf_bt_nimble_enabled),
     feature = "alloc",
     feature = "experimental"
 ))]
 pub mod bt;
+#[cfg(feature = "std")]
+pub mod coap;
+#[cfg(all(
+    any(esp_idf_comp_esp_coex_enabled, esp_idf_comp_esp_wifi_enabled),
+    any(
+        esp_idf_esp_coex_sw_coexist_enable,
+        esp_idf_esp32_wifi_sw_coexist_enable,
+        esp_idf_sw_coexist_enable
+    )
+))]
+pub mod coex;
+#[cfg(all(feature = "std", esp_idf_comp_console_enabled))]
+pub mod console;
+#[cfg(all(
+    not(esp_idf_version_major = "4"),
+    esp_idf_esp_coredump_enable_to_flash,
+    esp_idf_esp_coredump_data_format_elf
+))]
+pub mod coredump;
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+pub mod crypto;
+pub mod diag;
+#[cfg(feature = "alloc")]
+pub mod dns;
+#[cfg(all(esp_idf_comp_efuse_enabled, not(esp_idf_version_major = "4")))]
+pub mod efuse;
 #[cfg(all(
     not(esp32h2),
     feature = "alloc",
@@ -64,15 +92,46 @@ pub mod hal;
 pub mod handle;
 #[cfg(feature = "alloc")]
 pub mod http;
+#[cfg(esp_idf_comp_efuse_enabled)]
+pub mod identity;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_ieee802154_enabled,
+    esp_idf_soc_ieee802154_supported,
+    not(esp_idf_openthread_enabled),
+    not(esp_idf_comp_espressif__esp_zigbee_lib_enabled),
+    not(esp_idf_version_major = "4"),
+    not(esp_idf_version = "5.0")
+))]
+pub mod ieee802154;
 pub mod io;
+#[cfg(all(
+    feature = "alloc",
+    not(esp_idf_freertos_unicore),
+    any(esp32, esp32s3, esp32p4)
+))]
+pub mod ipc;
 pub mod ipv4;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_esp_local_ctrl_enabled,
+    esp_idf_comp_esp_http_server_enabled,
+    not(esp_idf_version_major = "4")
+))]
+pub mod local_ctrl;
 #[cfg(feature = "alloc")]
 pub mod log;
+#[cfg(feature = "std")]
+pub mod lwm2m;
+#[cfg(all(feature = "alloc", esp_idf_comp_espressif__esp_matter_enabled))]
+pub mod matter;
 #[cfg(all(
     feature = "alloc",
     any(esp_idf_comp_mdns_enabled, esp_idf_comp_espressif__mdns_enabled)
 ))]
 pub mod mdns;
+#[cfg(all(feature = "alloc", esp_idf_comp_espressif__esp_modbus_enabled))]
+pub mod modbus;
 #[cfg(all(
     feature = "alloc",
     esp_idf_comp_mqtt_enabled,
@@ -83,19 +142,70 @@ pub mod mqtt;
 pub mod napt;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
 pub mod netif;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_esp_netif_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub mod netmgr;
 #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
 pub mod nvs;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_openthread_enabled,
+    esp_idf_openthread_enabled,
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_nvs_flash_enabled,
+    not(esp_idf_version_major = "4"),
+    not(esp_idf_version = "5.0")
+))]
+pub mod openthread;
 #[cfg(all(esp_idf_comp_app_update_enabled, esp_idf_comp_spi_flash_enabled))]
 pub mod ota;
 #[cfg(esp_idf_comp_esp_netif_enabled)]
 pub mod ping;
+#[cfg(all(esp_idf_pm_enable, not(esp_idf_version_major = "4")))]
+pub mod pm;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_protocomm_enabled,
+    not(esp_idf_version_major = "4")
+))]
+pub mod protocomm;
+pub mod rng;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled,
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_soc_hmac_supported
+))]
+pub mod secure_store;
+#[cfg(all(esp_idf_comp_efuse_enabled, not(esp_idf_version_major = "4")))]
+pub mod security;
+pub mod sleep;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
 pub mod sntp;
 pub mod sys;
 pub mod systime;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_soc_temp_sensor_supported,
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_esp_timer_enabled,
+    not(esp_idf_version_major = "4")
+))]
+pub mod temp_sensor;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_timer_enabled))]
 pub mod timer;
 pub mod tls;
+#[cfg(all(
+    esp_idf_ulp_coproc_enabled,
+    any(esp32, esp32s2, esp32s3),
+    not(esp_idf_version_major = "4")
+))]
+pub mod ulp;
+#[cfg(not(esp_idf_version_major = "4"))]
+pub mod watchdog;
 #[cfg(all(
     not(esp32h2),
     feature = "alloc",
@@ -104,5 +214,12 @@ pub mod tls;
 ))]
 pub mod wifi;
 pub mod ws;
+#[cfg(all(
+    feature = "std",
+    esp_idf_comp_espressif__esp_zigbee_lib_enabled,
+    esp_idf_comp_esp_event_enabled,
+    not(esp_idf_version_major = "4")
+))]
+pub mod zigbee;
 
 mod private;
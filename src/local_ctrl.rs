@@ -0,0 +1,516 @@
+//! Local control, as per the ESP-IDF `esp_local_ctrl` component
+//!
+//! `EspLocalCtrl` exposes typed properties of the device - e.g. a lamp's brightness - to the
+//! local control tooling of Espressif (e.g. `esp_local_ctrl.py` or the phone apps built on the
+//! `esp-idf-provisioning` libraries), over HTTPS or BLE, with protocomm sessions secured with a
+//! proof-of-possession (`Security1`) or with SRP6a (`Security2`):
+//!
+//! ```ignore
+//! let mut ctrl = EspLocalCtrl::new(&LocalCtrlConfiguration {
+//!     transport: LocalCtrlTransport::Https(Default::default()),
+//!     security: LocalCtrlSecurity::Security1 { pop: Some("abcd1234") },
+//!     ..Default::default()
+//! })?;
+//!
+//! ctrl.add_readonly_property("uptime", || Ok(uptime_secs() as i32))?;
+//! ctrl.add_property("brightness", move || Ok(lamp.brightness() as i32), move |value: i32| {
+//!     lamp.set_brightness(value as _)
+//! })?;
+//! ```
+//!
+//! The values of the properties implement `PropertyValue`, which is implemented for `i32`,
+//! `bool`, `String` and - with the `json` feature - for any `serde` type wrapped in `Json`.
+
+use core::ffi;
+use core::fmt::{self, Debug};
+use core::ptr;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::info;
+
+use crate::private::cstr::*;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+#[cfg(esp_idf_esp_https_server_enable)]
+use crate::http::server::Configuration as HttpServerConfiguration;
+#[cfg(esp_idf_esp_https_server_enable)]
+use crate::private::common::Newtype;
+
+/// The type of a timestamp property, in microseconds, as known by the local control tooling
+pub const PROP_TYPE_TIMESTAMP: u32 = 0;
+/// The type of a 32-bit little-endian integer property, as known by the local control tooling
+pub const PROP_TYPE_INT32: u32 = 1;
+/// The type of a boolean property, as known by the local control tooling
+pub const PROP_TYPE_BOOLEAN: u32 = 2;
+/// The type of a UTF-8 string property, as known by the local control tooling
+pub const PROP_TYPE_STRING: u32 = 3;
+
+/// The flag of the read-only properties, as known by the local control tooling
+pub const PROP_FLAG_READONLY: u32 = 1 << 0;
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+/// The transport over which the properties are exposed
+#[derive(Clone, Debug)]
+pub enum LocalCtrlTransport {
+    /// An HTTPS server, on the HTTPS port of the configuration
+    #[cfg(esp_idf_esp_https_server_enable)]
+    Https(HttpServerConfiguration),
+    /// A GATT service, advertised with the device name
+    #[cfg(esp_idf_bt_enabled)]
+    Ble {
+        device_name: String,
+        /// The 128-bit UUID of the GATT service, in little endian byte order
+        service_uuid: [u8; 16],
+    },
+}
+
+/// The security of the local control sessions
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LocalCtrlSecurity<'a> {
+    /// No encryption and no authentication
+    None,
+    /// Curve25519 key exchange and AES-CTR encryption, with an optional proof-of-possession
+    Security1 { pop: Option<&'a str> },
+    /// SRP6a key exchange and AES-GCM encryption; the salt and the verifier are derived
+    /// from the username and the password entered in the tooling
+    Security2 { salt: &'a [u8], verifier: &'a [u8] },
+}
+
+impl LocalCtrlSecurity<'_> {
+    fn raw(&self) -> esp_local_ctrl_proto_sec_t {
+        match self {
+            Self::None => esp_local_ctrl_proto_sec_t_PROTOCOM_SEC0,
+            Self::Security1 { .. } => esp_local_ctrl_proto_sec_t_PROTOCOM_SEC1,
+            Self::Security2 { .. } => esp_local_ctrl_proto_sec_t_PROTOCOM_SEC2,
+        }
+    }
+}
+
+/// The configuration of `EspLocalCtrl`
+#[derive(Clone, Debug)]
+pub struct LocalCtrlConfiguration<'a> {
+    pub transport: LocalCtrlTransport,
+    pub security: LocalCtrlSecurity<'a>,
+    /// The maximum number of properties
+    pub max_properties: usize,
+}
+
+#[cfg(esp_idf_esp_https_server_enable)]
+impl Default for LocalCtrlConfiguration<'_> {
+    fn default() -> Self {
+        Self {
+            transport: LocalCtrlTransport::Https(Default::default()),
+            security: LocalCtrlSecurity::Security1 { pop: None },
+            max_properties: 10,
+        }
+    }
+}
+
+/// The value of a property, as exchanged with the local control tooling
+pub trait PropertyValue: Sized {
+    /// The type of the property, e.g. `PROP_TYPE_INT32`
+    const TYPE: u32;
+
+    fn encode(&self) -> Result<Vec<u8>, EspError>;
+
+    fn decode(data: &[u8]) -> Result<Self, EspError>;
+}
+
+impl PropertyValue for i32 {
+    const TYPE: u32 = PROP_TYPE_INT32;
+
+    fn encode(&self) -> Result<Vec<u8>, EspError> {
+        Ok(self.to_le_bytes().to_vec())
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, EspError> {
+        Ok(i32::from_le_bytes(data.try_into().map_err(|_| {
+            EspError::from_infallible::<ESP_ERR_INVALID_SIZE>()
+        })?))
+    }
+}
+
+impl PropertyValue for bool {
+    const TYPE: u32 = PROP_TYPE_BOOLEAN;
+
+    fn encode(&self) -> Result<Vec<u8>, EspError> {
+        Ok(alloc::vec![*self as u8])
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, EspError> {
+        match data {
+            [value] => Ok(*value != 0),
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>()),
+        }
+    }
+}
+
+impl PropertyValue for String {
+    const TYPE: u32 = PROP_TYPE_STRING;
+
+    fn encode(&self) -> Result<Vec<u8>, EspError> {
+        Ok(self.as_bytes().to_vec())
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, EspError> {
+        core::str::from_utf8(data)
+            .map(Into::into)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+    }
+}
+
+/// A `serde` value, exchanged as a JSON string property
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<T> PropertyValue for Json<T>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    const TYPE: u32 = PROP_TYPE_STRING;
+
+    fn encode(&self) -> Result<Vec<u8>, EspError> {
+        serde_json::to_vec(&self.0).map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, EspError> {
+        serde_json::from_slice(data)
+            .map(Json)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+    }
+}
+
+type Getter = Box<dyn FnMut() -> Result<Vec<u8>, EspError> + Send + 'static>;
+type Setter = Box<dyn FnMut(&[u8]) -> Result<(), EspError> + Send + 'static>;
+
+struct Property {
+    name: CString,
+    getter: Getter,
+    setter: Option<Setter>,
+}
+
+/// The local control service
+///
+/// Dropping it stops the service.
+pub struct EspLocalCtrl {
+    // The properties, by their context pointer; `esp_local_ctrl` keeps pointers to them
+    // until it is stopped
+    properties: Vec<Box<Mutex<Property>>>,
+    // Protocomm keeps pointers to the security parameters until the service is stopped
+    _pop: Option<Box<(CString, protocomm_security1_params_t)>>,
+    _security2: Option<Box<(Vec<u8>, Vec<u8>, protocomm_security2_params_t)>>,
+}
+
+impl EspLocalCtrl {
+    /// Start the local control service
+    pub fn new(conf: &LocalCtrlConfiguration) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut pop = None;
+        let mut security2 = None;
+
+        let sec_params: *const ffi::c_void = match conf.security {
+            LocalCtrlSecurity::None => ptr::null(),
+            LocalCtrlSecurity::Security1 { pop: None } => ptr::null(),
+            LocalCtrlSecurity::Security1 { pop: Some(value) } => {
+                let value = to_cstring_arg(value)?;
+
+                let mut params = Box::new((
+                    value,
+                    protocomm_security1_params_t {
+                        data: ptr::null(),
+                        len: 0,
+                    },
+                ));
+
+                params.1.data = params.0.as_ptr() as *const _;
+                params.1.len = params.0.as_bytes().len() as _;
+
+                let params_ptr = &params.1 as *const _ as *const ffi::c_void;
+                pop = Some(params);
+
+                params_ptr
+            }
+            LocalCtrlSecurity::Security2 { salt, verifier } => {
+                if salt.len() > u16::MAX as usize || verifier.len() > u16::MAX as usize {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+                }
+
+                let mut params = Box::new((
+                    salt.to_vec(),
+                    verifier.to_vec(),
+                    protocomm_security2_params_t {
+                        salt: ptr::null(),
+                        salt_len: salt.len() as _,
+                        verifier: ptr::null(),
+                        verifier_len: verifier.len() as _,
+                    },
+                ));
+
+                params.2.salt = params.0.as_ptr() as *const _;
+                params.2.verifier = params.1.as_ptr() as *const _;
+
+                let params_ptr = &params.2 as *const _ as *const ffi::c_void;
+                security2 = Some(params);
+
+                params_ptr
+            }
+        };
+
+        let handlers = esp_local_ctrl_handlers_t {
+            get_prop_values: Some(Self::get_prop_values),
+            set_prop_values: Some(Self::set_prop_values),
+            usr_ctx: ptr::null_mut(),
+            usr_ctx_free_fn: None,
+        };
+
+        let proto_sec = esp_local_ctrl_proto_sec_cfg_t {
+            version: conf.security.raw(),
+            custom_handle: ptr::null_mut(),
+            sec_params,
+        };
+
+        match conf.transport {
+            #[cfg(esp_idf_esp_https_server_enable)]
+            LocalCtrlTransport::Https(ref https_conf) => {
+                let mut https: Newtype<httpd_ssl_config_t> = https_conf.into();
+
+                if let (Some(cert), Some(private_key)) =
+                    (https_conf.server_certificate, https_conf.private_key)
+                {
+                    https.0.servercert = cert.as_esp_idf_raw_ptr() as _;
+                    https.0.servercert_len = cert.as_esp_idf_raw_len();
+                    https.0.prvtkey_pem = private_key.as_esp_idf_raw_ptr() as _;
+                    https.0.prvtkey_len = private_key.as_esp_idf_raw_len();
+                }
+
+                let config = esp_local_ctrl_config_t {
+                    transport: unsafe { esp_local_ctrl_get_transport_httpd() },
+                    transport_config: esp_local_ctrl_transport_config_t {
+                        httpd: &mut https.0,
+                    },
+                    proto_sec,
+                    handlers,
+                    max_properties: conf.max_properties,
+                };
+
+                esp!(unsafe { esp_local_ctrl_start(&config) })?;
+            }
+            #[cfg(esp_idf_bt_enabled)]
+            LocalCtrlTransport::Ble {
+                ref device_name,
+                service_uuid,
+            } => {
+                let mut ble: esp_local_ctrl_transport_config_ble_t = Default::default();
+
+                set_str(c_char_to_u8_slice_mut(&mut ble.device_name), device_name)?;
+                ble.service_uuid = service_uuid;
+
+                let config = esp_local_ctrl_config_t {
+                    transport: unsafe { esp_local_ctrl_get_transport_ble() },
+                    transport_config: esp_local_ctrl_transport_config_t { ble: &mut ble },
+                    proto_sec,
+                    handlers,
+                    max_properties: conf.max_properties,
+                };
+
+                esp!(unsafe { esp_local_ctrl_start(&config) })?;
+            }
+        }
+
+        *taken = true;
+
+        info!("Local control started");
+
+        Ok(Self {
+            properties: Vec::new(),
+            _pop: pop,
+            _security2: security2,
+        })
+    }
+
+    /// Add a read-only property, whose value is returned by `get`
+    pub fn add_readonly_property<V, G>(&mut self, name: &str, mut get: G) -> Result<(), EspError>
+    where
+        V: PropertyValue,
+        G: FnMut() -> Result<V, EspError> + Send + 'static,
+    {
+        self.add_raw_property(
+            name,
+            V::TYPE,
+            PROP_FLAG_READONLY,
+            move || get()?.encode(),
+            None::<fn(&[u8]) -> Result<(), EspError>>,
+        )
+    }
+
+    /// Add a property, whose value is returned by `get` and changed by `set`
+    pub fn add_property<V, G, S>(
+        &mut self,
+        name: &str,
+        mut get: G,
+        mut set: S,
+    ) -> Result<(), EspError>
+    where
+        V: PropertyValue,
+        G: FnMut() -> Result<V, EspError> + Send + 'static,
+        S: FnMut(V) -> Result<(), EspError> + Send + 'static,
+    {
+        self.add_raw_property(
+            name,
+            V::TYPE,
+            0,
+            move || get()?.encode(),
+            Some(move |data: &[u8]| set(V::decode(data)?)),
+        )
+    }
+
+    /// Add a property exchanged as raw bytes, with a type and flags of the application; the
+    /// property is read-only without `set`
+    pub fn add_raw_property<G, S>(
+        &mut self,
+        name: &str,
+        prop_type: u32,
+        flags: u32,
+        get: G,
+        set: Option<S>,
+    ) -> Result<(), EspError>
+    where
+        G: FnMut() -> Result<Vec<u8>, EspError> + Send + 'static,
+        S: FnMut(&[u8]) -> Result<(), EspError> + Send + 'static,
+    {
+        let flags = if set.is_none() {
+            flags | PROP_FLAG_READONLY
+        } else {
+            flags
+        };
+
+        let name = to_cstring_arg(name)?;
+
+        let property = Box::new(Mutex::new(Property {
+            name: name.clone(),
+            getter: Box::new(get),
+            setter: set.map(|set| Box::new(set) as Setter),
+        }));
+
+        // `esp_local_ctrl` copies the name
+        let raw = esp_local_ctrl_prop_t {
+            name: name.as_ptr() as *mut _,
+            type_: prop_type,
+            size: 0,
+            flags,
+            ctx: &*property as *const _ as *mut ffi::c_void,
+            ctx_free_fn: None,
+        };
+
+        esp!(unsafe { esp_local_ctrl_add_property(&raw) })?;
+
+        self.properties.push(property);
+
+        Ok(())
+    }
+
+    /// The names of the properties
+    pub fn properties(&self) -> impl Iterator<Item = String> + '_ {
+        self.properties
+            .iter()
+            .map(|property| property.lock().name.to_string_lossy().into_owned())
+    }
+
+    unsafe extern "C" fn get_prop_values(
+        props_count: usize,
+        props: *const esp_local_ctrl_prop_t,
+        prop_values: *mut esp_local_ctrl_prop_val_t,
+        _usr_ctx: *mut ffi::c_void,
+    ) -> esp_err_t {
+        let props = core::slice::from_raw_parts(props, props_count);
+        let prop_values = core::slice::from_raw_parts_mut(prop_values, props_count);
+
+        for (prop, prop_value) in props.iter().zip(prop_values.iter_mut()) {
+            let property = (prop.ctx as *const Mutex<Property>).as_ref().unwrap();
+
+            let value = match (property.lock().getter)() {
+                Ok(value) => value,
+                Err(err) => return err.code(),
+            };
+
+            // `esp_local_ctrl` frees the value with its `free_fn`
+            let data = malloc(value.len().max(1) as _) as *mut u8;
+            if data.is_null() {
+                return ESP_ERR_NO_MEM;
+            }
+
+            ptr::copy_nonoverlapping(value.as_ptr(), data, value.len());
+
+            prop_value.data = data as *mut _;
+            prop_value.size = value.len();
+            prop_value.free_fn = Some(free);
+        }
+
+        ESP_OK
+    }
+
+    unsafe extern "C" fn set_prop_values(
+        props_count: usize,
+        props: *const esp_local_ctrl_prop_t,
+        prop_values: *const esp_local_ctrl_prop_val_t,
+        _usr_ctx: *mut ffi::c_void,
+    ) -> esp_err_t {
+        let props = core::slice::from_raw_parts(props, props_count);
+        let prop_values = core::slice::from_raw_parts(prop_values, props_count);
+
+        for (prop, prop_value) in props.iter().zip(prop_values.iter()) {
+            let property = (prop.ctx as *const Mutex<Property>).as_ref().unwrap();
+            let mut property = property.lock();
+
+            let Some(setter) = property.setter.as_mut() else {
+                return ESP_ERR_INVALID_ARG;
+            };
+
+            let value = if prop_value.data.is_null() {
+                &[]
+            } else {
+                core::slice::from_raw_parts(prop_value.data as *const u8, prop_value.size)
+            };
+
+            if let Err(err) = setter(value) {
+                return err.code();
+            }
+        }
+
+        ESP_OK
+    }
+}
+
+impl Drop for EspLocalCtrl {
+    fn drop(&mut self) {
+        esp!(unsafe { esp_local_ctrl_stop() }).unwrap();
+
+        *TAKEN.lock() = false;
+
+        info!("Local control stopped");
+    }
+}
+
+unsafe impl Send for EspLocalCtrl {}
+
+impl Debug for EspLocalCtrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspLocalCtrl")
+            .field("properties", &self.properties().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
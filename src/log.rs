@@ -1,8 +1,11 @@
 //! Logging
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::vec::Vec;
 
 use ::log::{Level, LevelFilter, Metadata, Record};
 
@@ -13,6 +16,15 @@ use crate::sys::*;
 
 extern crate alloc;
 
+pub mod capture;
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+pub mod deferred;
+#[cfg(feature = "std")]
+pub mod remote;
+pub mod sink;
+
+use sink::LogSink;
+
 /// Exposes the newlib stdout file descriptor to allow writing formatted
 /// messages to stdout without a std dependency or allocation
 ///
@@ -121,14 +133,74 @@ impl From<Level> for Newtype<esp_log_level_t> {
 
 static LOGGER: EspLogger = EspLogger::new();
 
+/// A set of tasks, each of them in the middle of some logging which must not be re-entered
+struct TaskGuards(Mutex<Vec<usize>>);
+
+impl TaskGuards {
+    const fn new() -> Self {
+        Self(Mutex::new(Vec::new()))
+    }
+
+    fn current() -> usize {
+        crate::hal::task::current()
+            .map(|task| task as usize)
+            .unwrap_or(0)
+    }
+
+    fn is_entered(&self) -> bool {
+        self.0.lock().contains(&Self::current())
+    }
+
+    /// Mark the current task as entered, or return `None` if it already is
+    fn enter(&self) -> Option<TaskGuard<'_>> {
+        let task = Self::current();
+
+        let mut tasks = self.0.lock();
+
+        if tasks.contains(&task) {
+            None
+        } else {
+            tasks.push(task);
+
+            Some(TaskGuard(self, task))
+        }
+    }
+}
+
+struct TaskGuard<'a>(&'a TaskGuards, usize);
+
+impl Drop for TaskGuard<'_> {
+    fn drop(&mut self) {
+        let mut tasks = self.0 .0.lock();
+
+        if let Some(index) = tasks.iter().position(|task| *task == self.1) {
+            tasks.swap_remove(index);
+        }
+    }
+}
+
+// The tasks running the log sinks. The records they log - e.g. when a sink calls an ESP-IDF API
+// which logs, and these logs are captured - are dropped, as the sinks are locked meanwhile
+static SINK_TASKS: TaskGuards = TaskGuards::new();
+
 pub struct EspLogger {
     // esp-idf function `esp_log_level_get` builds a cache using the address
     // of the target and not doing a string compare. This means we need to
     // build a cache of our own mapping the str value to a consistant
     // Cstr value.
     cache: Mutex<BTreeMap<String, CString>>,
+    // The levels explicitly set via `set_target_level`, so that these can be
+    // queried, saved and restored
+    levels: Mutex<BTreeMap<String, LevelFilter>>,
+    stdout: AtomicBool,
+    sinks: Mutex<Vec<(LogSinkId, Box<dyn LogSink>)>>,
+    next_sink_id: AtomicU32,
 }
 
+/// The ID of a log sink registered with `EspLogger::add_sink`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct LogSinkId(u32);
+
 unsafe impl Send for EspLogger {}
 unsafe impl Sync for EspLogger {}
 
@@ -137,6 +209,10 @@ impl EspLogger {
     pub const fn new() -> Self {
         Self {
             cache: Mutex::new(BTreeMap::new()),
+            levels: Mutex::new(BTreeMap::new()),
+            stdout: AtomicBool::new(true),
+            sinks: Mutex::new(Vec::new()),
+            next_sink_id: AtomicU32::new(0),
         }
     }
 
@@ -180,9 +256,151 @@ impl EspLogger {
             );
         }
 
+        self.levels.lock().insert(target.into(), level_filter);
+
         Ok(())
     }
 
+    /// Return the level which was explicitly set for the provided target
+    /// with `set_target_level`, if any
+    pub fn target_level(&self, target: impl AsRef<str>) -> Option<LevelFilter> {
+        self.levels.lock().get(target.as_ref()).copied()
+    }
+
+    /// Return all targets and their levels which were explicitly set with `set_target_level`
+    pub fn target_levels(&self) -> Vec<(String, LevelFilter)> {
+        self.levels
+            .lock()
+            .iter()
+            .map(|(target, level)| (target.clone(), *level))
+            .collect()
+    }
+
+    /// Set the levels of multiple targets at once, using a textual specification
+    /// of the form `target1=level1,target2=level2,...`, e.g. `esp_idf_svc::wifi=debug,*=warn`.
+    ///
+    /// An entry without a target (i.e. just `level`) sets the level of all targets (`*`).
+    /// Level names are case-insensitive and are the ones of `log::LevelFilter`
+    /// (`off`, `error`, `warn`, `info`, `debug`, `trace`).
+    ///
+    /// The format is suitable for changing the log verbosity from e.g. a console command.
+    pub fn set_target_levels(&self, spec: &str) -> Result<(), EspError> {
+        let levels = Self::parse_target_levels(spec)?;
+
+        for (target, level) in levels {
+            self.set_target_level(target, level)?;
+        }
+
+        Ok(())
+    }
+
+    /// Format all explicitly set target levels using the textual specification
+    /// accepted by `set_target_levels`
+    pub fn format_target_levels(&self) -> String {
+        let mut spec = String::new();
+
+        for (target, level) in self.levels.lock().iter() {
+            if !spec.is_empty() {
+                spec.push(',');
+            }
+
+            write!(&mut spec, "{}={}", target, level).unwrap();
+        }
+
+        spec
+    }
+
+    /// Persist all explicitly set target levels in NVS under the provided key
+    #[cfg(esp_idf_comp_nvs_flash_enabled)]
+    pub fn save_target_levels<T>(
+        &self,
+        nvs: &mut crate::nvs::EspNvs<T>,
+        key: &str,
+    ) -> Result<(), EspError>
+    where
+        T: crate::nvs::NvsPartitionId,
+    {
+        nvs.set_str(key, &self.format_target_levels())
+    }
+
+    /// Load and apply the target levels previously persisted in NVS with `save_target_levels`
+    ///
+    /// Returns `false` if there is nothing stored under the provided key.
+    #[cfg(esp_idf_comp_nvs_flash_enabled)]
+    pub fn load_target_levels<T>(
+        &self,
+        nvs: &crate::nvs::EspNvs<T>,
+        key: &str,
+    ) -> Result<bool, EspError>
+    where
+        T: crate::nvs::NvsPartitionId,
+    {
+        let Some(len) = nvs.str_len(key)? else {
+            return Ok(false);
+        };
+
+        let mut buf = vec![0; len];
+
+        if let Some(spec) = nvs.get_str(key, &mut buf)? {
+            self.set_target_levels(spec)?;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn parse_target_levels(spec: &str) -> Result<Vec<(&str, LevelFilter)>, EspError> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (target, level) = entry
+                    .rsplit_once('=')
+                    .map(|(target, level)| (target.trim(), level.trim()))
+                    .unwrap_or(("*", entry));
+
+                let level = level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+                Ok((target, level))
+            })
+            .collect()
+    }
+
+    /// Register an additional sink, which will receive all log records
+    /// passing the level filtering of the logger
+    pub fn add_sink<S>(&self, sink: S) -> LogSinkId
+    where
+        S: LogSink + 'static,
+    {
+        let id = LogSinkId(self.next_sink_id.fetch_add(1, Ordering::Relaxed));
+
+        self.sinks.lock().push((id, Box::new(sink)));
+
+        id
+    }
+
+    /// Unregister a sink previously registered with `add_sink`
+    ///
+    /// Returns `false` if no sink with that ID is registered.
+    pub fn remove_sink(&self, id: LogSinkId) -> bool {
+        let mut sinks = self.sinks.lock();
+
+        let len = sinks.len();
+        sinks.retain(|(sink_id, _)| *sink_id != id);
+
+        sinks.len() != len
+    }
+
+    /// Enable or disable printing the log records to stdout (the default console, usually UART).
+    ///
+    /// Enabled by default. Disabling it does not affect the registered sinks.
+    pub fn set_stdout_enabled(&self, enabled: bool) {
+        self.stdout.store(enabled, Ordering::Relaxed);
+    }
+
     fn get_marker(level: Level) -> &'static str {
         match level {
             Level::Error => "E",
@@ -247,28 +465,47 @@ impl ::log::Log for EspLogger {
         let metadata = record.metadata();
 
         if self.enabled(metadata) && self.should_log(record) {
-            let marker = Self::get_marker(metadata.level());
+            let Some(_guard) = SINK_TASKS.enter() else {
+                return;
+            };
+
             let timestamp = unsafe { esp_log_timestamp() };
-            let target = record.metadata().target();
-            let args = record.args();
-            let color = Self::get_color(record.level());
-
-            let mut stdout = EspStdout::new();
-
-            if let Some(color) = color {
-                writeln!(
-                    stdout,
-                    "\x1b[0;{}m{} ({}) {}: {}\x1b[0m",
-                    color, marker, timestamp, target, args
-                )
-                .unwrap();
-            } else {
-                writeln!(stdout, "{} ({}) {}: {}", marker, timestamp, target, args).unwrap();
+
+            if self.stdout.load(Ordering::Relaxed) {
+                let marker = Self::get_marker(metadata.level());
+                let target = record.metadata().target();
+                let args = record.args();
+                let color = Self::get_color(record.level());
+
+                let mut stdout = EspStdout::new();
+
+                if let Some(color) = color {
+                    writeln!(
+                        stdout,
+                        "\x1b[0;{}m{} ({}) {}: {}\x1b[0m",
+                        color, marker, timestamp, target, args
+                    )
+                    .unwrap();
+                } else {
+                    writeln!(stdout, "{} ({}) {}: {}", marker, timestamp, target, args).unwrap();
+                }
+            }
+
+            for (_, sink) in self.sinks.lock().iter_mut() {
+                sink.log(record, timestamp);
             }
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        let Some(_guard) = SINK_TASKS.enter() else {
+            return;
+        };
+
+        for (_, sink) in self.sinks.lock().iter_mut() {
+            sink.flush();
+        }
+    }
 }
 
 pub fn set_target_level(
@@ -277,3 +514,26 @@ pub fn set_target_level(
 ) -> Result<(), EspError> {
     LOGGER.set_target_level(target, level_filter)
 }
+
+pub fn set_target_levels(spec: &str) -> Result<(), EspError> {
+    LOGGER.set_target_levels(spec)
+}
+
+pub fn target_levels() -> Vec<(String, LevelFilter)> {
+    LOGGER.target_levels()
+}
+
+pub fn add_sink<S>(sink: S) -> LogSinkId
+where
+    S: LogSink + 'static,
+{
+    LOGGER.add_sink(sink)
+}
+
+pub fn remove_sink(id: LogSinkId) -> bool {
+    LOGGER.remove_sink(id)
+}
+
+pub fn set_stdout_enabled(enabled: bool) {
+    LOGGER.set_stdout_enabled(enabled)
+}
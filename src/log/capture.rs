@@ -0,0 +1,153 @@
+//! Capturing of the ESP-IDF C-side logs
+//!
+//! Once `start` is called, the output of the ESP-IDF `ESP_LOGx` macros is intercepted
+//! (via `esp_log_set_vprintf`), the level and tag of each line are parsed, and the line is
+//! re-emitted through the Rust `log` facade, using the tag - optionally prefixed - as the
+//! log target.
+//!
+//! This way, the filtering and sinks of the Rust logger apply uniformly to both C and Rust logs.
+//!
+//! Lines which do not look like ESP-IDF log lines are written to stdout unchanged.
+
+use core::ffi;
+use core::fmt::Write;
+
+extern crate alloc;
+use alloc::string::String;
+
+use ::log::{Level, Record};
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::{TaskGuards, SINK_TASKS};
+
+const LINE_BUF_LEN: usize = 256;
+
+struct State {
+    previous: vprintf_like_t,
+    target_prefix: &'static str,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+// The tasks currently re-emitting a captured line, to detect re-entrancy (e.g. a logger of
+// another crate which calls into an ESP-IDF API that itself logs)
+static EMITTING_TASKS: TaskGuards = TaskGuards::new();
+
+/// Start capturing the ESP-IDF C-side logs into the Rust `log` facade
+///
+/// The tag of each captured line, prefixed with `target_prefix` (e.g. `"idf::"`), is used as the log target.
+/// Calling `start` while the capturing is already active only updates the prefix.
+pub fn start(target_prefix: &'static str) {
+    let mut state = STATE.lock();
+
+    if let Some(state) = state.as_mut() {
+        state.target_prefix = target_prefix;
+    } else {
+        let previous = unsafe { esp_log_set_vprintf(Some(capture_vprintf)) };
+
+        *state = Some(State {
+            previous,
+            target_prefix,
+        });
+    }
+}
+
+/// Stop capturing the ESP-IDF C-side logs and restore the previous log output function
+pub fn stop() {
+    let mut state = STATE.lock();
+
+    if let Some(state) = state.take() {
+        unsafe {
+            esp_log_set_vprintf(state.previous);
+        }
+    }
+}
+
+/// Return `true` if the ESP-IDF C-side logs are currently being captured
+pub fn is_started() -> bool {
+    STATE.lock().is_some()
+}
+
+unsafe extern "C" fn capture_vprintf(format: *const ffi::c_char, args: va_list) -> ffi::c_int {
+    let mut buf = [0_u8; LINE_BUF_LEN];
+
+    let len = vsnprintf(buf.as_mut_ptr() as *mut _, buf.len() as _, format, args);
+
+    if len < 0 {
+        return len;
+    }
+
+    let line_len = (len as usize).min(buf.len() - 1);
+
+    let target_prefix = STATE
+        .lock()
+        .as_ref()
+        .map(|state| state.target_prefix)
+        .unwrap_or("");
+
+    // Lines logged from within the log sinks are passed through, as they would be dropped
+    let guard = if SINK_TASKS.is_entered() {
+        None
+    } else {
+        EMITTING_TASKS.enter()
+    };
+
+    let parsed = guard
+        .as_ref()
+        .and_then(|_| core::str::from_utf8(&buf[..line_len]).ok())
+        .and_then(parse);
+
+    if let Some((level, tag, message)) = parsed {
+        let mut target = String::with_capacity(target_prefix.len() + tag.len());
+        let _ = write!(&mut target, "{}{}", target_prefix, tag);
+
+        ::log::logger().log(
+            &Record::builder()
+                .level(level)
+                .target(&target)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    } else {
+        // Not an ESP-IDF log line (or a re-entrant call): pass it through unchanged
+        let line = String::from_utf8_lossy(&buf[..line_len]);
+
+        let _ = super::EspStdout::new().write_str(&line);
+    }
+
+    len
+}
+
+/// Parse an ESP-IDF log line of the form `[<color>]L (<timestamp>) <tag>: <message>[<color reset>]\n`
+fn parse(line: &str) -> Option<(Level, &str, &str)> {
+    let line = strip_color(line.trim_end_matches(['\r', '\n']));
+
+    let mut chars = line.chars();
+
+    let level = match chars.next()? {
+        'E' => Level::Error,
+        'W' => Level::Warn,
+        'I' => Level::Info,
+        'D' => Level::Debug,
+        'V' => Level::Trace,
+        _ => return None,
+    };
+
+    let rest = chars.as_str().strip_prefix(" (")?;
+    let (_timestamp, rest) = rest.split_once(") ")?;
+    let (tag, message) = rest.split_once(": ")?;
+
+    Some((level, tag, strip_color(message)))
+}
+
+fn strip_color(line: &str) -> &str {
+    let line = if line.starts_with("\x1b[") {
+        line.split_once('m').map(|(_, rest)| rest).unwrap_or(line)
+    } else {
+        line
+    };
+
+    line.strip_suffix("\x1b[0m").unwrap_or(line)
+}
@@ -0,0 +1,342 @@
+//! Deferred binary logging
+//!
+//! An opt-in logging backend for timing-sensitive code paths, where even formatting a log
+//! message is too expensive. Instead of formatting, `DeferredLogger::log` only records a compact
+//! binary record - a timestamp, the level, a reference to the `'static` format string and up to
+//! `MAX_ARGS` primitive arguments - into a fixed-size RAM buffer.
+//!
+//! The records are formatted later, when the application calls `flush` (which re-emits them
+//! through the `log` facade) or `drain` (which calls a user callback). Alternatively, `encode`
+//! produces a raw binary representation which can be shipped elsewhere and decoded on the host,
+//! by resolving the format string addresses against the application ELF file.
+//!
+//! Format strings use `{}` as a placeholder for each argument.
+//!
+//! ```ignore
+//! static TRACE: DeferredLogger<256> = DeferredLogger::new();
+//!
+//! TRACE.log(Level::Debug, "sample {} took {}us", &[index.into(), elapsed.into()]);
+//!
+//! // Later, outside of the timing-sensitive code path
+//! TRACE.flush("trace");
+//! ```
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Display};
+
+use heapless::Deque;
+
+use ::log::{Level, Record};
+
+use crate::hal::task::CriticalSection;
+
+use crate::sys::esp_timer_get_time;
+
+/// The maximum number of arguments of a deferred record
+pub const MAX_ARGS: usize = 4;
+
+/// An argument of a deferred log record
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DeferredArg {
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    Bool(bool),
+    Char(char),
+    Str(&'static str),
+}
+
+impl DeferredArg {
+    fn tag(&self) -> u8 {
+        match self {
+            Self::U32(_) => 0,
+            Self::I32(_) => 1,
+            Self::U64(_) => 2,
+            Self::I64(_) => 3,
+            Self::F32(_) => 4,
+            Self::Bool(_) => 5,
+            Self::Char(_) => 6,
+            Self::Str(_) => 7,
+        }
+    }
+
+    fn raw(&self) -> u64 {
+        match self {
+            Self::U32(value) => *value as _,
+            Self::I32(value) => *value as u32 as _,
+            Self::U64(value) => *value,
+            Self::I64(value) => *value as _,
+            Self::F32(value) => value.to_bits() as _,
+            Self::Bool(value) => *value as _,
+            Self::Char(value) => *value as u32 as _,
+            Self::Str(value) => value.as_ptr() as usize as _,
+        }
+    }
+}
+
+impl Display for DeferredArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::U32(value) => write!(f, "{}", value),
+            Self::I32(value) => write!(f, "{}", value),
+            Self::U64(value) => write!(f, "{}", value),
+            Self::I64(value) => write!(f, "{}", value),
+            Self::F32(value) => write!(f, "{}", value),
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Char(value) => write!(f, "{}", value),
+            Self::Str(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+macro_rules! deferred_arg_from {
+    ($ty:ty, $variant:ident, $as:ty) => {
+        impl From<$ty> for DeferredArg {
+            fn from(value: $ty) -> Self {
+                Self::$variant(value as $as)
+            }
+        }
+    };
+}
+
+deferred_arg_from!(u8, U32, u32);
+deferred_arg_from!(u16, U32, u32);
+deferred_arg_from!(u32, U32, u32);
+deferred_arg_from!(usize, U32, u32);
+deferred_arg_from!(i8, I32, i32);
+deferred_arg_from!(i16, I32, i32);
+deferred_arg_from!(i32, I32, i32);
+deferred_arg_from!(isize, I32, i32);
+deferred_arg_from!(u64, U64, u64);
+deferred_arg_from!(i64, I64, i64);
+deferred_arg_from!(f32, F32, f32);
+
+impl From<bool> for DeferredArg {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<char> for DeferredArg {
+    fn from(value: char) -> Self {
+        Self::Char(value)
+    }
+}
+
+impl From<&'static str> for DeferredArg {
+    fn from(value: &'static str) -> Self {
+        Self::Str(value)
+    }
+}
+
+/// A deferred log record
+#[derive(Debug, Clone)]
+pub struct DeferredRecord {
+    /// Microseconds since boot
+    pub timestamp: u64,
+    pub level: Level,
+    pub format: &'static str,
+    args: [DeferredArg; MAX_ARGS],
+    args_len: u8,
+}
+
+impl DeferredRecord {
+    pub fn args(&self) -> &[DeferredArg] {
+        &self.args[..self.args_len as usize]
+    }
+
+    /// The length of the binary encoding of this record, as produced by `encode`
+    pub fn encoded_len(&self) -> usize {
+        8 + 4 + 4 + 1 + 1 + self.args().len() * 9
+    }
+
+    /// Encode the record in the following little-endian binary format:
+    /// - timestamp: u64
+    /// - format string address: u32
+    /// - format string length: u32
+    /// - level: u8 (1 = error ... 5 = trace)
+    /// - number of arguments: u8
+    /// - for each argument: type tag: u8, followed by its raw value: u64
+    ///   (type tags: 0 = u32, 1 = i32, 2 = u64, 3 = i64, 4 = f32 bits, 5 = bool, 6 = char,
+    ///   7 = string address, for strings the length is not recorded)
+    ///
+    /// Returns the number of bytes written, or `None` if `buf` is too small.
+    pub fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = self.encoded_len();
+
+        if buf.len() < len {
+            return None;
+        }
+
+        buf[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[8..12].copy_from_slice(&(self.format.as_ptr() as usize as u32).to_le_bytes());
+        buf[12..16].copy_from_slice(&(self.format.len() as u32).to_le_bytes());
+        buf[16] = self.level as u8;
+        buf[17] = self.args_len;
+
+        for (index, arg) in self.args().iter().enumerate() {
+            let offset = 18 + index * 9;
+
+            buf[offset] = arg.tag();
+            buf[offset + 1..offset + 9].copy_from_slice(&arg.raw().to_le_bytes());
+        }
+
+        Some(len)
+    }
+}
+
+impl Display for DeferredRecord {
+    /// Formats the record by substituting each `{}` in the format string with the next argument
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut args = self.args().iter();
+        let mut format = self.format;
+
+        while let Some(index) = format.find("{}") {
+            f.write_str(&format[..index])?;
+
+            if let Some(arg) = args.next() {
+                write!(f, "{}", arg)?;
+            } else {
+                f.write_str("{}")?;
+            }
+
+            format = &format[index + 2..];
+        }
+
+        f.write_str(format)
+    }
+}
+
+/// A buffer of at most `N` deferred log records
+///
+/// When the buffer is full, the oldest records are overwritten.
+pub struct DeferredLogger<const N: usize> {
+    records: UnsafeCell<Deque<DeferredRecord, N>>,
+    dropped: UnsafeCell<u32>,
+    cs: CriticalSection,
+}
+
+impl<const N: usize> DeferredLogger<N> {
+    pub const fn new() -> Self {
+        Self {
+            records: UnsafeCell::new(Deque::new()),
+            dropped: UnsafeCell::new(0),
+            cs: CriticalSection::new(),
+        }
+    }
+
+    /// Record a log message without formatting it
+    ///
+    /// Arguments beyond `MAX_ARGS` are ignored.
+    pub fn log(&self, level: Level, format: &'static str, args: &[DeferredArg]) {
+        let mut record = DeferredRecord {
+            timestamp: unsafe { esp_timer_get_time() } as _,
+            level,
+            format,
+            args: [DeferredArg::U32(0); MAX_ARGS],
+            args_len: args.len().min(MAX_ARGS) as _,
+        };
+
+        record.args[..record.args_len as usize].copy_from_slice(&args[..record.args_len as usize]);
+
+        let _guard = self.cs.enter();
+
+        let records = unsafe { self.records.get().as_mut() }.unwrap();
+
+        if records.is_full() {
+            records.pop_front();
+            *unsafe { self.dropped.get().as_mut() }.unwrap() += 1;
+        }
+
+        let _ = records.push_back(record);
+    }
+
+    /// Return the number of records currently buffered
+    pub fn len(&self) -> usize {
+        let _guard = self.cs.enter();
+
+        unsafe { self.records.get().as_ref() }.unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the number of records overwritten so far because the buffer was full
+    pub fn dropped(&self) -> u32 {
+        let _guard = self.cs.enter();
+
+        *unsafe { self.dropped.get().as_ref() }.unwrap()
+    }
+
+    /// Remove the oldest buffered record, if any
+    pub fn pop(&self) -> Option<DeferredRecord> {
+        let _guard = self.cs.enter();
+
+        unsafe { self.records.get().as_mut() }.unwrap().pop_front()
+    }
+
+    /// Remove all buffered records, oldest first, passing each one to the provided callback
+    ///
+    /// The callback is called outside of the critical section, so it is allowed to block
+    /// or to log.
+    pub fn drain<F>(&self, mut f: F)
+    where
+        F: FnMut(DeferredRecord),
+    {
+        while let Some(record) = self.pop() {
+            f(record);
+        }
+    }
+
+    /// Remove all buffered records and re-emit them through the `log` facade, using the provided target
+    pub fn flush(&self, target: &str) {
+        self.drain(|record| {
+            ::log::logger().log(
+                &Record::builder()
+                    .level(record.level)
+                    .target(target)
+                    .args(format_args!("[{}] {}", record.timestamp, record))
+                    .build(),
+            );
+        });
+    }
+
+    /// Remove as many buffered records as fit into `buf`, encoding them with `DeferredRecord::encode`
+    ///
+    /// Returns the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut offset = 0;
+
+        loop {
+            let _guard = self.cs.enter();
+
+            let records = unsafe { self.records.get().as_mut() }.unwrap();
+
+            let Some(record) = records.front() else {
+                break;
+            };
+
+            let Some(len) = record.encode(&mut buf[offset..]) else {
+                break;
+            };
+
+            records.pop_front();
+
+            offset += len;
+        }
+
+        offset
+    }
+}
+
+impl<const N: usize> Default for DeferredLogger<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const N: usize> Send for DeferredLogger<N> {}
+unsafe impl<const N: usize> Sync for DeferredLogger<N> {}
@@ -0,0 +1,442 @@
+//! Remote logging
+//!
+//! A `LogSink` sending log records to a remote syslog server, formatted as per
+//! [RFC 5424](https://datatracker.ietf.org/doc/html/rfc5424).
+//!
+//! Besides the message itself, every record carries structured data with the
+//! log target, the name of the FreeRTOS task which emitted it and - when available -
+//! the source file and line, which allows journald-style filtering on the server side.
+//!
+//! Records are sent from a dedicated thread, so logging never blocks on the network.
+//! While the server is unreachable (e.g. WiFi is not connected yet), records are buffered
+//! up to a configurable limit, after which the oldest ones are dropped.
+
+use core::fmt::Write;
+use core::{ffi, ptr};
+
+use std::collections::VecDeque;
+use std::io::{self, Write as _};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+extern crate alloc;
+use alloc::string::String;
+
+use ::log::{Level, Record};
+
+use crate::sys::pcTaskGetName;
+
+use super::sink::LogSink;
+
+/// The syslog facility used for all records
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Facility {
+    Kernel = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Debug, Clone)]
+pub enum SyslogTransport {
+    /// Plain UDP, as per RFC 5426. Records which do not fit a datagram are truncated by the network stack
+    Udp,
+    /// Plain TCP with octet-counting framing, as per RFC 6587
+    Tcp,
+    /// TLS with octet-counting framing, as per RFC 5425
+    #[cfg(all(
+        esp_idf_comp_esp_tls_enabled,
+        any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+    ))]
+    Tls(crate::tls::Config<'static>),
+}
+
+#[derive(Debug, Clone)]
+pub struct SyslogConfiguration<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub transport: SyslogTransport,
+    /// The HOSTNAME field of the records
+    pub hostname: &'a str,
+    /// The APP-NAME field of the records
+    pub app_name: &'a str,
+    pub facility: Facility,
+    /// The SD-ID of the structured data element carrying the target, task, file and line
+    pub sd_id: &'a str,
+    /// How many records to buffer while the server is not reachable
+    pub max_buffered: usize,
+    /// How long to wait before trying to reconnect to the server
+    pub reconnect_interval: Duration,
+    pub task_stack_size: usize,
+}
+
+impl<'a> Default for SyslogConfiguration<'a> {
+    fn default() -> Self {
+        Self {
+            host: "",
+            port: 514,
+            transport: SyslogTransport::Udp,
+            hostname: "esp32",
+            app_name: "esp-idf-svc",
+            facility: Facility::User,
+            sd_id: "meta@32473",
+            max_buffered: 64,
+            reconnect_interval: Duration::from_secs(5),
+            task_stack_size: 4096,
+        }
+    }
+}
+
+struct Queue {
+    records: VecDeque<String>,
+    max_buffered: usize,
+    dropped: usize,
+    quit: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    cvar: Condvar,
+}
+
+/// A `LogSink` sending the log records to a remote syslog server
+///
+/// Register it with `EspLogger::add_sink`. The sender thread is stopped
+/// once the sink is dropped (i.e. removed from the logger).
+pub struct SyslogSink {
+    shared: Arc<Shared>,
+    hostname: String,
+    app_name: String,
+    sd_id: String,
+    facility: Facility,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SyslogSink {
+    pub fn new(conf: &SyslogConfiguration) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                records: VecDeque::new(),
+                max_buffered: conf.max_buffered.max(1),
+                dropped: 0,
+                quit: false,
+            }),
+            cvar: Condvar::new(),
+        });
+
+        let mut sender = Sender {
+            shared: shared.clone(),
+            host: conf.host.into(),
+            port: conf.port,
+            transport: conf.transport.clone(),
+            reconnect_interval: conf.reconnect_interval,
+        };
+
+        let thread = thread::Builder::new()
+            .name("syslog".into())
+            .stack_size(conf.task_stack_size)
+            .spawn(move || sender.run())?;
+
+        Ok(Self {
+            shared,
+            hostname: conf.hostname.into(),
+            app_name: conf.app_name.into(),
+            sd_id: conf.sd_id.into(),
+            facility: conf.facility,
+            thread: Some(thread),
+        })
+    }
+
+    /// Return the number of records dropped so far because the buffer was full
+    pub fn dropped(&self) -> usize {
+        self.shared.queue.lock().unwrap().dropped
+    }
+
+    fn severity(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+
+    fn format(&self, record: &Record) -> Result<String, core::fmt::Error> {
+        let mut line = String::new();
+
+        write!(
+            &mut line,
+            "<{}>1 ",
+            (self.facility as u8) * 8 + Self::severity(record.level())
+        )?;
+
+        format_timestamp(&mut line)?;
+
+        write!(
+            &mut line,
+            " {} {} - - [{} target=\"",
+            nil_if_empty(&self.hostname),
+            nil_if_empty(&self.app_name),
+            self.sd_id
+        )?;
+
+        write_sd_value(&mut line, record.target())?;
+
+        line.push_str("\" task=\"");
+        write_sd_value(&mut line, current_task_name())?;
+        line.push('"');
+
+        if let Some(file) = record.file() {
+            line.push_str(" file=\"");
+            write_sd_value(&mut line, file)?;
+            line.push('"');
+        }
+
+        if let Some(lineno) = record.line() {
+            write!(&mut line, " line=\"{}\"", lineno)?;
+        }
+
+        write!(&mut line, "] {}", record.args())?;
+
+        Ok(line)
+    }
+}
+
+impl LogSink for SyslogSink {
+    fn log(&mut self, record: &Record, _timestamp: u32) {
+        if let Ok(line) = self.format(record) {
+            let mut queue = self.shared.queue.lock().unwrap();
+
+            if queue.records.len() >= queue.max_buffered {
+                queue.records.pop_front();
+                queue.dropped += 1;
+            }
+
+            queue.records.push_back(line);
+
+            self.shared.cvar.notify_one();
+        }
+    }
+}
+
+impl Drop for SyslogSink {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().quit = true;
+        self.shared.cvar.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    #[cfg(all(
+        esp_idf_comp_esp_tls_enabled,
+        any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+    ))]
+    Tls(crate::tls::EspTls<crate::tls::InternalSocket>),
+}
+
+impl Connection {
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            Self::Tcp(stream) => {
+                write!(stream, "{} ", line.len())?;
+                stream.write_all(line.as_bytes())
+            }
+            #[cfg(all(
+                esp_idf_comp_esp_tls_enabled,
+                any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+            ))]
+            Self::Tls(tls) => {
+                let prefix = format!("{} ", line.len());
+
+                tls.write_all(prefix.as_bytes())
+                    .and_then(|_| tls.write_all(line.as_bytes()))
+                    .map_err(io::Error::other)
+            }
+        }
+    }
+}
+
+struct Sender {
+    shared: Arc<Shared>,
+    host: String,
+    port: u16,
+    transport: SyslogTransport,
+    reconnect_interval: Duration,
+}
+
+impl Sender {
+    fn run(&mut self) {
+        let mut connection = None;
+
+        loop {
+            let line = {
+                let mut queue = self.shared.queue.lock().unwrap();
+
+                loop {
+                    if queue.quit {
+                        return;
+                    }
+
+                    if let Some(line) = queue.records.pop_front() {
+                        break line;
+                    }
+
+                    queue = self.shared.cvar.wait(queue).unwrap();
+                }
+            };
+
+            loop {
+                if connection.is_none() {
+                    connection = self.connect().ok();
+                }
+
+                if let Some(conn) = connection.as_mut() {
+                    if conn.send(&line).is_ok() {
+                        break;
+                    }
+
+                    connection = None;
+                }
+
+                // Server not reachable: retry sending the record later
+                let queue = self.shared.queue.lock().unwrap();
+
+                let (queue, _) = self
+                    .shared
+                    .cvar
+                    .wait_timeout_while(queue, self.reconnect_interval, |queue| !queue.quit)
+                    .unwrap();
+
+                if queue.quit {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(io::ErrorKind::NotFound)?;
+
+        match &self.transport {
+            SyslogTransport::Udp => {
+                let socket = UdpSocket::bind(if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                })?;
+
+                socket.connect(addr)?;
+
+                Ok(Connection::Udp(socket))
+            }
+            SyslogTransport::Tcp => Ok(Connection::Tcp(TcpStream::connect(addr)?)),
+            #[cfg(all(
+                esp_idf_comp_esp_tls_enabled,
+                any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+            ))]
+            SyslogTransport::Tls(conf) => {
+                let mut tls = crate::tls::EspTls::new().map_err(io::Error::other)?;
+
+                tls.connect(&self.host, self.port, conf)
+                    .map_err(io::Error::other)?;
+
+                Ok(Connection::Tls(tls))
+            }
+        }
+    }
+}
+
+fn nil_if_empty(value: &str) -> &str {
+    if value.is_empty() {
+        "-"
+    } else {
+        value
+    }
+}
+
+fn write_sd_value(line: &mut String, value: &str) -> core::fmt::Result {
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            line.push('\\');
+        }
+
+        line.push(c);
+    }
+
+    Ok(())
+}
+
+fn current_task_name() -> &'static str {
+    let name = unsafe { pcTaskGetName(ptr::null_mut()) };
+
+    if name.is_null() {
+        "-"
+    } else {
+        unsafe { ffi::CStr::from_ptr(name) }.to_str().unwrap_or("-")
+    }
+}
+
+fn format_timestamp(line: &mut String) -> core::fmt::Result {
+    // Until the system time is synchronized (i.e. before 2020), use the NILVALUE
+    const MIN_VALID_SECS: u64 = 1_577_836_800;
+
+    let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+        line.push('-');
+        return Ok(());
+    };
+
+    let secs = now.as_secs();
+
+    if secs < MIN_VALID_SECS {
+        line.push('-');
+        return Ok(());
+    }
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let tod = secs % 86400;
+
+    write!(
+        line,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        tod / 3600,
+        (tod % 3600) / 60,
+        tod % 60,
+        now.subsec_micros()
+    )
+}
@@ -0,0 +1,369 @@
+//! Additional log sinks
+//!
+//! Besides printing to stdout (usually the UART console), `EspLogger` can forward
+//! all log records which pass its level filtering to a set of registered `LogSink`s.
+//!
+//! This module contains a few ready-made sinks:
+//! - `RingBufferSink` - keeps the most recent log output in a fixed-size RAM buffer,
+//!   which survives a software reset or a crash if placed in non-initialized memory
+//! - `FileSink` - appends the log output to a file on a mounted VFS filesystem
+//!   (FAT, LittleFS, SPIFFS), rotating it when it grows too large
+//! - `UdpSink` - sends each log line as a UDP datagram
+
+use core::fmt::{self, Write};
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::Record;
+
+use crate::private::mutex::Mutex;
+
+/// A destination for log records
+///
+/// Sinks are called with the sinks locked, yet a sink logging itself does not deadlock: the
+/// records it logs with the `log` macros are dropped, and the logs of the ESP-IDF APIs it calls
+/// go to the console only - passed through unchanged if captured with `log::capture`.
+pub trait LogSink: Send {
+    /// Consume a log record. `timestamp` is the ESP-IDF log timestamp in milliseconds.
+    fn log(&mut self, record: &Record, timestamp: u32);
+
+    fn flush(&mut self) {}
+}
+
+impl<S> LogSink for alloc::boxed::Box<S>
+where
+    S: LogSink + ?Sized,
+{
+    fn log(&mut self, record: &Record, timestamp: u32) {
+        (**self).log(record, timestamp)
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+}
+
+/// Format a log record the same way `EspLogger` formats it for stdout, minus the colors
+pub fn format_record<W>(writer: &mut W, record: &Record, timestamp: u32) -> fmt::Result
+where
+    W: Write,
+{
+    writeln!(
+        writer,
+        "{} ({}) {}: {}",
+        super::EspLogger::get_marker(record.level()),
+        timestamp,
+        record.target(),
+        record.args()
+    )
+}
+
+const RING_BUFFER_MAGIC: u32 = 0x4c4f4752; // "LOGR"
+const RING_BUFFER_HEADER_LEN: usize = 12;
+
+struct RingBuffer(&'static mut [u8]);
+
+impl RingBuffer {
+    fn new(buf: &'static mut [u8], preserve: bool) -> Self {
+        assert!(buf.len() > RING_BUFFER_HEADER_LEN);
+
+        let mut this = Self(buf);
+
+        let valid = preserve
+            && this.header(0) == RING_BUFFER_MAGIC
+            && (this.header(1) as usize) < this.capacity()
+            && (this.header(2) as usize) <= this.capacity();
+
+        if !valid {
+            this.set_header(0, RING_BUFFER_MAGIC);
+            this.set_header(1, 0);
+            this.set_header(2, 0);
+        }
+
+        this
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.len() - RING_BUFFER_HEADER_LEN
+    }
+
+    fn header(&self, index: usize) -> u32 {
+        let offset = index * 4;
+
+        u32::from_le_bytes(self.0[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_header(&mut self, index: usize, value: u32) {
+        let offset = index * 4;
+
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let capacity = self.capacity();
+
+        // Only the tail of oversized writes fits
+        let data = &data[data.len().saturating_sub(capacity)..];
+
+        let mut head = self.header(1) as usize;
+
+        for byte in data {
+            self.0[RING_BUFFER_HEADER_LEN + head] = *byte;
+            head = (head + 1) % capacity;
+        }
+
+        let len = (self.header(2) as usize + data.len()).min(capacity);
+
+        self.set_header(1, head as _);
+        self.set_header(2, len as _);
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        let capacity = self.capacity();
+        let head = self.header(1) as usize;
+        let len = self.header(2) as usize;
+
+        let start = (head + capacity - len) % capacity;
+
+        let data = &self.0[RING_BUFFER_HEADER_LEN..];
+
+        let mut contents = Vec::with_capacity(len);
+
+        if start + len <= capacity {
+            contents.extend_from_slice(&data[start..start + len]);
+        } else {
+            contents.extend_from_slice(&data[start..]);
+            contents.extend_from_slice(&data[..len - (capacity - start)]);
+        }
+
+        contents
+    }
+
+    fn clear(&mut self) {
+        self.set_header(1, 0);
+        self.set_header(2, 0);
+    }
+}
+
+/// A sink keeping the most recent log output in a fixed-size RAM buffer
+///
+/// The sink can be cloned; all clones share the same buffer, so one clone can be
+/// registered with the logger while another one is kept for retrieving the log output.
+///
+/// To retrieve the log output written before a crash or a software reset, place the
+/// buffer in memory which is not initialized on boot and create the sink with
+/// `preserve` set to `true`, e.g.:
+///
+/// ```ignore
+/// #[link_section = ".noinit"]
+/// static mut LOG_BUF: [u8; 4096] = [0; 4096];
+///
+/// let sink = RingBufferSink::new(unsafe { &mut *core::ptr::addr_of_mut!(LOG_BUF) }, true);
+/// let previous_boot_logs = sink.contents();
+/// ```
+#[derive(Clone)]
+pub struct RingBufferSink(Arc<Mutex<RingBuffer>>);
+
+impl RingBufferSink {
+    /// Create a new ring buffer sink over the provided buffer
+    ///
+    /// If `preserve` is `true` and the buffer already contains log output
+    /// from a previous sink (e.g. before a reset), this output is kept.
+    pub fn new(buf: &'static mut [u8], preserve: bool) -> Self {
+        Self(Arc::new(Mutex::new(RingBuffer::new(buf, preserve))))
+    }
+
+    /// Return the log output currently stored in the buffer, oldest first
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().contents()
+    }
+
+    /// Same as `contents`, but lossily converted to a string
+    pub fn contents_str(&self) -> String {
+        String::from_utf8_lossy(&self.contents()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().clear()
+    }
+}
+
+impl LogSink for RingBufferSink {
+    fn log(&mut self, record: &Record, timestamp: u32) {
+        let mut line = String::new();
+
+        if format_record(&mut line, record, timestamp).is_ok() {
+            self.0.lock().write(line.as_bytes());
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use file::*;
+
+#[cfg(feature = "std")]
+mod file {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+
+    use ::log::Record;
+
+    use super::{format_record, LogSink};
+
+    extern crate alloc;
+    use alloc::string::String;
+
+    /// A sink appending the log output to a file on a mounted VFS filesystem
+    ///
+    /// Once the file grows beyond `max_size` bytes, it is rotated: `<path>` is renamed
+    /// to `<path>.1`, `<path>.1` to `<path>.2` and so on, keeping at most `max_files`
+    /// rotated files.
+    pub struct FileSink {
+        path: PathBuf,
+        max_size: u64,
+        max_files: usize,
+        file: Option<File>,
+        size: u64,
+    }
+
+    impl FileSink {
+        pub fn new(path: impl AsRef<Path>, max_size: u64, max_files: usize) -> io::Result<Self> {
+            let mut this = Self {
+                path: path.as_ref().to_path_buf(),
+                max_size,
+                max_files,
+                file: None,
+                size: 0,
+            };
+
+            this.open()?;
+
+            Ok(this)
+        }
+
+        fn open(&mut self) -> io::Result<()> {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+
+            Ok(())
+        }
+
+        fn rotated_path(&self, index: usize) -> PathBuf {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{index}"));
+
+            path.into()
+        }
+
+        fn rotate(&mut self) -> io::Result<()> {
+            self.file = None;
+
+            if self.max_files == 0 {
+                fs::remove_file(&self.path)?;
+            } else {
+                let _ = fs::remove_file(self.rotated_path(self.max_files));
+
+                for index in (1..self.max_files).rev() {
+                    let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+                }
+
+                fs::rename(&self.path, self.rotated_path(1))?;
+            }
+
+            self.open()
+        }
+
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            if self.file.is_none() {
+                self.open()?;
+            }
+
+            if self.size > 0 && self.size + data.len() as u64 > self.max_size {
+                self.rotate()?;
+            }
+
+            if let Some(file) = self.file.as_mut() {
+                file.write_all(data)?;
+                self.size += data.len() as u64;
+            }
+
+            Ok(())
+        }
+    }
+
+    impl LogSink for FileSink {
+        fn log(&mut self, record: &Record, timestamp: u32) {
+            let mut line = String::new();
+
+            if format_record(&mut line, record, timestamp).is_ok()
+                && self.write(line.as_bytes()).is_err()
+            {
+                // Retry opening the file on the next record
+                self.file = None;
+            }
+        }
+
+        fn flush(&mut self) {
+            if let Some(file) = self.file.as_mut() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use udp::*;
+
+#[cfg(feature = "std")]
+mod udp {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    use ::log::Record;
+
+    use super::{format_record, LogSink};
+
+    extern crate alloc;
+    use alloc::string::String;
+
+    /// A sink sending each formatted log line as a UDP datagram to a remote host
+    ///
+    /// Datagrams which cannot be sent (e.g. because the network is down) are dropped.
+    pub struct UdpSink {
+        socket: UdpSocket,
+        remote: SocketAddr,
+    }
+
+    impl UdpSink {
+        pub fn new(remote: SocketAddr) -> io::Result<Self> {
+            let socket = UdpSocket::bind(if remote.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            })?;
+
+            socket.set_nonblocking(true)?;
+
+            Ok(Self { socket, remote })
+        }
+    }
+
+    impl LogSink for UdpSink {
+        fn log(&mut self, record: &Record, timestamp: u32) {
+            let mut line = String::new();
+
+            if format_record(&mut line, record, timestamp).is_ok() {
+                let _ = self.socket.send_to(line.as_bytes(), self.remote);
+            }
+        }
+    }
+}
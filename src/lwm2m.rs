@@ -0,0 +1,1308 @@
+//! LwM2M 1.1 client, over CoAP
+//!
+//! `Lwm2mClient` registers the objects of the device with a LwM2M server - e.g. Leshan or a
+//! cloud device management service - keeps the registration alive, and serves the reads,
+//! writes, executes and observations of the server.
+//!
+//! It runs on top of a `coap::Transport`: a connected `std::net::UdpSocket`, or a
+//! `tls::dtls::EspDtls` session with the PSK or the certificate given by the bootstrap server.
+//!
+//! The standard objects are in `objects`: `Device`, `FirmwareUpdate` - wired to `EspOta` - and
+//! `Temperature`; other objects implement `Object`.
+//!
+//! ```ignore
+//! use esp_idf_svc::lwm2m::objects::{Device, FirmwareUpdate, Temperature};
+//! use esp_idf_svc::lwm2m::{Lwm2mClient, Lwm2mConfiguration};
+//! use esp_idf_svc::ota::EspOta;
+//!
+//! let socket = UdpSocket::bind("0.0.0.0:0")?;
+//! socket.connect("leshan.eclipseprojects.io:5683")?;
+//!
+//! let mut client = Lwm2mClient::new(
+//!     socket,
+//!     &Lwm2mConfiguration {
+//!         endpoint: "sensor-a1b2c3".into(),
+//!         ..Default::default()
+//!     },
+//! );
+//!
+//! client.add_object(Device::new("Acme", "Sensor", "a1b2c3"));
+//! client.add_object(FirmwareUpdate::new(EspOta::new()?));
+//! client.add_object(Temperature::new(|| read_temperature()));
+//!
+//! client.run()?;
+//! ```
+//!
+//! The resources are exchanged in plain text - or as opaque bytes - and the object instances
+//! in the OMA-TLV format.
+
+use core::fmt::{self, Debug};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use std::time::Instant;
+
+use ::log::{info, warn};
+
+use crate::coap::{content_format, option, Block, Code, Message, MessageType, Transport};
+use crate::sys::*;
+
+use tlv::{Tlv, TlvKind};
+
+pub mod objects;
+pub mod tlv;
+
+// The largest datagram accepted
+const MAX_DATAGRAM_LEN: usize = 1280;
+
+// The responses to the recent confirmable requests, to answer their retransmissions
+const DEDUP_LEN: usize = 8;
+
+// How long the bootstrap server has to finish the bootstrap
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The type of a resource
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Kind {
+    Int,
+    Float,
+    Bool,
+    String,
+    Opaque,
+    /// A Unix timestamp, in seconds
+    Time,
+}
+
+/// The value of a resource
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Opaque(Vec<u8>),
+    Time(i64),
+}
+
+impl Value {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Self::Int(_) => Kind::Int,
+            Self::Float(_) => Kind::Float,
+            Self::Bool(_) => Kind::Bool,
+            Self::String(_) => Kind::String,
+            Self::Opaque(_) => Kind::Opaque,
+            Self::Time(_) => Kind::Time,
+        }
+    }
+
+    /// Encode the value in plain text - or as bytes, for an opaque value
+    pub fn encode_text(&self) -> Vec<u8> {
+        match self {
+            Self::Int(value) | Self::Time(value) => value.to_string().into_bytes(),
+            Self::Float(value) => value.to_string().into_bytes(),
+            Self::Bool(value) => if *value { b"1" } else { b"0" }.to_vec(),
+            Self::String(value) => value.as_bytes().to_vec(),
+            Self::Opaque(value) => value.clone(),
+        }
+    }
+
+    /// Decode a value of `kind` from plain text - or from bytes, for an opaque value
+    pub fn decode_text(kind: Kind, data: &[u8]) -> Result<Self, Code> {
+        if kind == Kind::Opaque {
+            return Ok(Self::Opaque(data.to_vec()));
+        }
+
+        let text = core::str::from_utf8(data).map_err(|_| Code::BAD_REQUEST)?;
+
+        Ok(match kind {
+            Kind::Int => Self::Int(text.trim().parse().map_err(|_| Code::BAD_REQUEST)?),
+            Kind::Time => Self::Time(text.trim().parse().map_err(|_| Code::BAD_REQUEST)?),
+            Kind::Float => Self::Float(text.trim().parse().map_err(|_| Code::BAD_REQUEST)?),
+            Kind::Bool => match text.trim() {
+                "1" | "true" => Self::Bool(true),
+                "0" | "false" => Self::Bool(false),
+                _ => return Err(Code::BAD_REQUEST),
+            },
+            Kind::String | Kind::Opaque => Self::String(text.into()),
+        })
+    }
+
+    /// Encode the value as the value of a TLV
+    pub fn encode_tlv(&self) -> Vec<u8> {
+        match self {
+            Self::Int(value) | Self::Time(value) => {
+                let value = *value;
+
+                if i8::try_from(value).is_ok() {
+                    (value as i8).to_be_bytes().to_vec()
+                } else if i16::try_from(value).is_ok() {
+                    (value as i16).to_be_bytes().to_vec()
+                } else if i32::try_from(value).is_ok() {
+                    (value as i32).to_be_bytes().to_vec()
+                } else {
+                    value.to_be_bytes().to_vec()
+                }
+            }
+            Self::Float(value) => value.to_be_bytes().to_vec(),
+            Self::Bool(value) => vec![*value as u8],
+            Self::String(value) => value.as_bytes().to_vec(),
+            Self::Opaque(value) => value.clone(),
+        }
+    }
+
+    /// Decode a value of `kind` from the value of a TLV
+    pub fn decode_tlv(kind: Kind, data: &[u8]) -> Result<Self, Code> {
+        let int = || -> Result<i64, Code> {
+            Ok(match data.len() {
+                1 => data[0] as i8 as i64,
+                2 => i16::from_be_bytes(data.try_into().unwrap()) as i64,
+                4 => i32::from_be_bytes(data.try_into().unwrap()) as i64,
+                8 => i64::from_be_bytes(data.try_into().unwrap()),
+                _ => return Err(Code::BAD_REQUEST),
+            })
+        };
+
+        Ok(match kind {
+            Kind::Int => Self::Int(int()?),
+            Kind::Time => Self::Time(int()?),
+            Kind::Float => match data.len() {
+                4 => Self::Float(f32::from_be_bytes(data.try_into().unwrap()) as f64),
+                8 => Self::Float(f64::from_be_bytes(data.try_into().unwrap())),
+                _ => return Err(Code::BAD_REQUEST),
+            },
+            Kind::Bool => match data {
+                [0] => Self::Bool(false),
+                [1] => Self::Bool(true),
+                _ => return Err(Code::BAD_REQUEST),
+            },
+            Kind::String => Self::String(
+                core::str::from_utf8(data)
+                    .map_err(|_| Code::BAD_REQUEST)?
+                    .into(),
+            ),
+            Kind::Opaque => Self::Opaque(data.to_vec()),
+        })
+    }
+}
+
+/// The operations a resource supports
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Operations {
+    Read,
+    Write,
+    ReadWrite,
+    Execute,
+}
+
+impl Operations {
+    pub const fn is_readable(&self) -> bool {
+        matches!(self, Self::Read | Self::ReadWrite)
+    }
+
+    pub const fn is_writable(&self) -> bool {
+        matches!(self, Self::Write | Self::ReadWrite)
+    }
+
+    pub const fn is_executable(&self) -> bool {
+        matches!(self, Self::Execute)
+    }
+}
+
+/// A resource of an object
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Resource {
+    pub id: u16,
+    pub kind: Kind,
+    pub operations: Operations,
+}
+
+impl Resource {
+    pub const fn new(id: u16, kind: Kind, operations: Operations) -> Self {
+        Self {
+            id,
+            kind,
+            operations,
+        }
+    }
+}
+
+/// A LwM2M object, with its instances
+///
+/// The errors are the CoAP codes responded to the server, e.g. `Code::NOT_FOUND` for an
+/// instance which does not exist.
+pub trait Object: Send {
+    /// The ID of the object, e.g. 3 for Device
+    fn id(&self) -> u16;
+
+    /// The resources of the object
+    fn resources(&self) -> &[Resource];
+
+    /// The instances of the object
+    fn instances(&self) -> Vec<u16> {
+        vec![0]
+    }
+
+    fn read(&mut self, instance: u16, resource: u16) -> Result<Value, Code>;
+
+    fn write(&mut self, instance: u16, resource: u16, value: Value) -> Result<(), Code> {
+        let _ = (instance, resource, value);
+
+        Err(Code::METHOD_NOT_ALLOWED)
+    }
+
+    /// Write a block of a block-wise write of a resource - e.g. of a firmware package - at
+    /// `offset` in the value; `more` is `false` for the last block
+    fn write_block(
+        &mut self,
+        instance: u16,
+        resource: u16,
+        offset: usize,
+        data: &[u8],
+        more: bool,
+    ) -> Result<(), Code> {
+        let _ = (instance, resource, offset, data, more);
+
+        Err(Code::REQUEST_ENTITY_TOO_LARGE)
+    }
+
+    fn execute(&mut self, instance: u16, resource: u16, args: &[u8]) -> Result<(), Code> {
+        let _ = (instance, resource, args);
+
+        Err(Code::METHOD_NOT_ALLOWED)
+    }
+
+    /// Called regularly by the client, once the responses to the server are sent: e.g. to
+    /// reboot once the response to the Reboot execute is sent
+    fn poll(&mut self) {}
+}
+
+/// The configuration of a `Lwm2mClient`
+#[derive(Clone, Debug)]
+pub struct Lwm2mConfiguration {
+    /// The name of the endpoint, unique across the devices of the server
+    pub endpoint: String,
+    /// The lifetime of the registration, which is updated after three quarters of it
+    pub lifetime: Duration,
+    /// The initial timeout of the acknowledgement of a request, doubled on each retransmission
+    pub ack_timeout: Duration,
+    /// The number of retransmissions, before giving up
+    pub max_retransmit: u8,
+    /// The minimum period between two notifications of an observation
+    pub min_notify_period: Duration,
+    /// The maximum period between two notifications of an observation, even when unchanged
+    pub max_notify_period: Duration,
+}
+
+impl Lwm2mConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            endpoint: String::new(),
+            lifetime: Duration::from_secs(300),
+            ack_timeout: Duration::from_secs(2),
+            max_retransmit: 4,
+            min_notify_period: Duration::from_secs(1),
+            max_notify_period: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Default for Lwm2mConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The path of an object, an object instance or a resource
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Path {
+    pub object: u16,
+    pub instance: Option<u16>,
+    pub resource: Option<u16>,
+}
+
+impl Path {
+    fn parse(path: &str) -> Option<Self> {
+        let mut segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| segment.parse::<u16>().ok());
+
+        let object = segments.next()??;
+        let instance = segments.next().map(|id| id.ok_or(())).transpose().ok()?;
+        let resource = segments.next().map(|id| id.ok_or(())).transpose().ok()?;
+
+        if segments.next().is_some() || (instance.is_none() && resource.is_some()) {
+            return None;
+        }
+
+        Some(Self {
+            object,
+            instance,
+            resource,
+        })
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.object)?;
+
+        if let Some(instance) = self.instance {
+            write!(f, "/{instance}")?;
+        }
+
+        if let Some(resource) = self.resource {
+            write!(f, "/{resource}")?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Observation {
+    token: Vec<u8>,
+    path: Path,
+    accept: Option<u16>,
+    seq: u32,
+    last: Vec<u8>,
+    notified_at: Instant,
+    message_id: u16,
+}
+
+/// What the bootstrap server provisioned, for the LwM2M server
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct BootstrapInfo {
+    /// The URI of the server, e.g. `coaps://lwm2m.example.com:5684`
+    pub server_uri: String,
+    /// The security mode: 0 for PSK, 1 for a raw public key, 2 for a certificate and 3 for
+    /// none
+    pub security_mode: i64,
+    /// The PSK identity, or the certificate, of the device
+    pub identity: Vec<u8>,
+    /// The PSK, or the private key, of the device
+    pub secret_key: Vec<u8>,
+    pub short_server_id: u16,
+    /// The lifetime of the registration, if provisioned
+    pub lifetime: Option<Duration>,
+}
+
+impl Debug for BootstrapInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BootstrapInfo")
+            .field("server_uri", &self.server_uri)
+            .field("security_mode", &self.security_mode)
+            .field("identity", &self.identity.len())
+            .field("secret_key", &"<redacted>")
+            .field("short_server_id", &self.short_server_id)
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
+
+// The resources of the Security and the Server objects, written by the bootstrap server
+const SECURITY_RESOURCES: &[Resource] = &[
+    Resource::new(0, Kind::String, Operations::Write),
+    Resource::new(1, Kind::Bool, Operations::Write),
+    Resource::new(2, Kind::Int, Operations::Write),
+    Resource::new(3, Kind::Opaque, Operations::Write),
+    Resource::new(5, Kind::Opaque, Operations::Write),
+    Resource::new(10, Kind::Int, Operations::Write),
+];
+
+const SERVER_RESOURCES: &[Resource] = &[
+    Resource::new(0, Kind::Int, Operations::Write),
+    Resource::new(1, Kind::Int, Operations::Write),
+];
+
+#[derive(Default)]
+struct SecurityInstance {
+    bootstrap: bool,
+    info: BootstrapInfo,
+}
+
+// The Security and Server instances written by the bootstrap server
+#[derive(Default)]
+struct Bootstrap {
+    instances: Vec<(u16, SecurityInstance)>,
+    lifetime: Option<Duration>,
+}
+
+impl Bootstrap {
+    fn write(&mut self, path: Path, values: Vec<(u16, Value)>) {
+        if path.object == 1 {
+            for (resource, value) in values {
+                if let (1, Value::Int(secs)) = (resource, value) {
+                    self.lifetime = Some(Duration::from_secs(secs.max(0) as _));
+                }
+            }
+
+            return;
+        }
+
+        let id = path.instance.unwrap_or(0);
+
+        let index = match self.instances.iter().position(|(other, _)| *other == id) {
+            Some(index) => index,
+            None => {
+                self.instances.push((id, Default::default()));
+                self.instances.len() - 1
+            }
+        };
+
+        let instance = &mut self.instances[index].1;
+
+        for (resource, value) in values {
+            match (resource, value) {
+                (0, Value::String(uri)) => instance.info.server_uri = uri,
+                (1, Value::Bool(bootstrap)) => instance.bootstrap = bootstrap,
+                (2, Value::Int(mode)) => instance.info.security_mode = mode,
+                (3, Value::Opaque(identity)) => instance.info.identity = identity,
+                (5, Value::Opaque(key)) => instance.info.secret_key = key,
+                (10, Value::Int(id)) => instance.info.short_server_id = id as _,
+                _ => (),
+            }
+        }
+    }
+
+    // The LwM2M server provisioned - i.e. not the bootstrap server itself
+    fn finish(self) -> Option<BootstrapInfo> {
+        let mut info = self
+            .instances
+            .into_iter()
+            .map(|(_, instance)| instance)
+            .find(|instance| !instance.bootstrap)?
+            .info;
+
+        info.lifetime = self.lifetime;
+
+        Some(info)
+    }
+}
+
+/// A LwM2M client, for the server its transport is connected to
+pub struct Lwm2mClient<T> {
+    transport: T,
+    conf: Lwm2mConfiguration,
+    objects: Vec<Box<dyn Object>>,
+    location: Option<String>,
+    registered_at: Option<Instant>,
+    observations: Vec<Observation>,
+    next_message_id: u16,
+    recent: heapless::Deque<(u16, Vec<u8>), DEDUP_LEN>,
+    // The block-wise write being received: its path, the offset of its next block and the
+    // offset of its next block in the value written
+    block1: Option<(Path, usize, usize)>,
+    buf: Box<[u8; MAX_DATAGRAM_LEN]>,
+}
+
+impl<T> Lwm2mClient<T>
+where
+    T: Transport,
+{
+    pub fn new(transport: T, conf: &Lwm2mConfiguration) -> Self {
+        Self {
+            transport,
+            conf: conf.clone(),
+            objects: Vec::new(),
+            location: None,
+            registered_at: None,
+            observations: Vec::new(),
+            next_message_id: unsafe { esp_random() } as u16,
+            recent: heapless::Deque::new(),
+            block1: None,
+            buf: Box::new([0; MAX_DATAGRAM_LEN]),
+        }
+    }
+
+    /// Add an object, replacing any previous one with the same ID
+    pub fn add_object<O>(&mut self, object: O)
+    where
+        O: Object + 'static,
+    {
+        self.objects.retain(|other| other.id() != object.id());
+        self.objects.push(Box::new(object));
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn release(self) -> T {
+        self.transport
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.location.is_some()
+    }
+
+    /// Request the bootstrap from the bootstrap server the transport is connected to, and
+    /// serve its writes until it finishes the bootstrap
+    pub fn bootstrap(&mut self) -> Result<BootstrapInfo, EspError> {
+        let mut request = Message::request(Code::POST, "bs");
+        request.add_option(
+            option::URI_QUERY,
+            format!("ep={}", self.conf.endpoint).as_bytes(),
+        );
+
+        let response = self.exchange(request)?;
+
+        if !response.code.is_success() {
+            warn!("Bootstrap request refused: {}", response.code);
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+        }
+
+        let mut bootstrap = Bootstrap::default();
+
+        let deadline = Instant::now() + BOOTSTRAP_TIMEOUT;
+
+        while let Some(request) = self.recv_until(deadline)? {
+            if !request.code.is_request() {
+                continue;
+            }
+
+            let path = request.uri_path();
+
+            if path == "bs" && request.code == Code::POST {
+                let response = Message::response(&request, Code::CHANGED);
+                self.transport.send(&response.encode()?)?;
+
+                let info = bootstrap
+                    .finish()
+                    .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+                info!("Bootstrapped, server {}", info.server_uri);
+
+                return Ok(info);
+            }
+
+            let code = match (request.code, Path::parse(&path)) {
+                (Code::DELETE, Some(path)) if path.object == 0 => {
+                    bootstrap.instances.clear();
+
+                    Code::DELETED
+                }
+                (Code::DELETE, None) if path.is_empty() => {
+                    bootstrap.instances.clear();
+
+                    Code::DELETED
+                }
+                (Code::DELETE, _) => Code::DELETED,
+                (Code::PUT | Code::POST, Some(path)) if path.object == 0 || path.object == 1 => {
+                    match Self::bootstrap_values(&request, path) {
+                        Ok(values) => {
+                            bootstrap.write(path, values);
+
+                            Code::CHANGED
+                        }
+                        Err(code) => code,
+                    }
+                }
+                // The other objects of the device are not provisioned by the bootstrap
+                (Code::PUT | Code::POST, Some(_)) => Code::CHANGED,
+                _ => Code::METHOD_NOT_ALLOWED,
+            };
+
+            let response = Message::response(&request, code);
+            self.transport.send(&response.encode()?)?;
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    // The values of a write of the bootstrap server, by resource
+    fn bootstrap_values(request: &Message, path: Path) -> Result<Vec<(u16, Value)>, Code> {
+        let resources = if path.object == 0 {
+            SECURITY_RESOURCES
+        } else {
+            SERVER_RESOURCES
+        };
+
+        let kind = |id: u16| {
+            resources
+                .iter()
+                .find(|resource| resource.id == id)
+                .map(|resource| resource.kind)
+        };
+
+        if let Some(resource) = path.resource {
+            let Some(kind) = kind(resource) else {
+                return Ok(Vec::new());
+            };
+
+            let value = if request.content_format() == Some(content_format::LWM2M_TLV) {
+                let tlvs = Tlv::decode_all(&request.payload).map_err(|_| Code::BAD_REQUEST)?;
+                let tlv = tlvs.first().ok_or(Code::BAD_REQUEST)?;
+
+                Value::decode_tlv(kind, tlv.value)?
+            } else {
+                Value::decode_text(kind, &request.payload)?
+            };
+
+            return Ok(vec![(resource, value)]);
+        }
+
+        let mut values = Vec::new();
+
+        for tlv in Tlv::decode_all(&request.payload).map_err(|_| Code::BAD_REQUEST)? {
+            // A write of the object, with the TLVs of its instances
+            let resource_tlvs = if tlv.kind == TlvKind::ObjectInstance {
+                Tlv::decode_all(tlv.value).map_err(|_| Code::BAD_REQUEST)?
+            } else {
+                vec![tlv]
+            };
+
+            for tlv in resource_tlvs {
+                if let Some(kind) = kind(tlv.id) {
+                    values.push((tlv.id, Value::decode_tlv(kind, tlv.value)?));
+                }
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Register with the server
+    pub fn register(&mut self) -> Result<(), EspError> {
+        let mut request = Message::request(Code::POST, "rd");
+
+        for query in [
+            format!("ep={}", self.conf.endpoint),
+            format!("lt={}", self.conf.lifetime.as_secs()),
+            "lwm2m=1.1".into(),
+            "b=U".into(),
+        ] {
+            request.add_option(option::URI_QUERY, query.as_bytes());
+        }
+
+        request.set_content_format(content_format::LINK_FORMAT);
+        request.payload = self.links().into_bytes();
+
+        let response = self.exchange(request)?;
+
+        if response.code != Code::CREATED {
+            warn!("Registration refused: {}", response.code);
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+        }
+
+        let location = response.location_path();
+
+        info!("Registered at {location}");
+
+        self.location = Some(location);
+        self.registered_at = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Update the registration, registering again if the server does not know it anymore
+    pub fn update(&mut self) -> Result<(), EspError> {
+        let Some(location) = self.location.clone() else {
+            return self.register();
+        };
+
+        let response = self.exchange(Message::request(Code::POST, &location))?;
+
+        if response.code == Code::CHANGED {
+            self.registered_at = Some(Instant::now());
+
+            Ok(())
+        } else {
+            warn!(
+                "Registration update refused: {}, registering again",
+                response.code
+            );
+
+            self.location = None;
+            self.observations.clear();
+
+            self.register()
+        }
+    }
+
+    /// Deregister from the server
+    pub fn deregister(&mut self) -> Result<(), EspError> {
+        if let Some(location) = self.location.take() {
+            self.observations.clear();
+            self.exchange(Message::request(Code::DELETE, &location))?;
+
+            info!("Deregistered");
+        }
+
+        Ok(())
+    }
+
+    /// Register, and serve the server until an error occurs
+    pub fn run(&mut self) -> Result<(), EspError> {
+        if !self.is_registered() {
+            self.register()?;
+        }
+
+        loop {
+            self.poll(None)?;
+        }
+    }
+
+    /// Serve the requests received within `timeout` - or until the next registration update
+    /// or notification is due - then update the registration and notify the observers, if due
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<(), EspError> {
+        let mut wait = self.conf.min_notify_period;
+
+        if let Some(registered_at) = self.registered_at {
+            let update_at = registered_at + self.conf.lifetime * 3 / 4;
+            wait = wait.min(update_at.saturating_duration_since(Instant::now()));
+        }
+
+        if let Some(timeout) = timeout {
+            wait = wait.min(timeout);
+        }
+
+        let deadline = Instant::now() + wait;
+
+        while let Some(message) = self.recv_until(deadline)? {
+            self.handle(message)?;
+        }
+
+        for object in &mut self.objects {
+            object.poll();
+        }
+
+        if let Some(registered_at) = self.registered_at {
+            if registered_at.elapsed() >= self.conf.lifetime * 3 / 4 {
+                self.update()?;
+            }
+        }
+
+        self.notify()
+    }
+
+    fn links(&self) -> String {
+        let mut links = String::from("</>;rt=\"oma.lwm2m\";ct=11542");
+
+        for object in &self.objects {
+            for instance in object.instances() {
+                links.push_str(&format!(",</{}/{}>", object.id(), instance));
+            }
+        }
+
+        links
+    }
+
+    // Handle a message received outside of an exchange
+    fn handle(&mut self, message: Message) -> Result<(), EspError> {
+        match message.mtype {
+            MessageType::Reset => {
+                // A reset of a notification cancels its observation
+                self.observations
+                    .retain(|observation| observation.message_id != message.message_id);
+            }
+            MessageType::Confirmable | MessageType::NonConfirmable if message.code.is_request() => {
+                if message.mtype == MessageType::Confirmable {
+                    if let Some((_, response)) =
+                        self.recent.iter().find(|(id, _)| *id == message.message_id)
+                    {
+                        let response = response.clone();
+                        return self.transport.send(&response);
+                    }
+                }
+
+                let response = self.respond(&message).encode()?;
+
+                if message.mtype == MessageType::Confirmable {
+                    if self.recent.is_full() {
+                        self.recent.pop_front();
+                    }
+
+                    let _ = self
+                        .recent
+                        .push_back((message.message_id, response.clone()));
+                }
+
+                self.transport.send(&response)?;
+            }
+            MessageType::Confirmable => {
+                // A CoAP ping
+                let reset = Message::empty(MessageType::Reset, message.message_id);
+                self.transport.send(&reset.encode()?)?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn respond(&mut self, request: &Message) -> Message {
+        let Some(path) = Path::parse(&request.uri_path()) else {
+            return Message::response(request, Code::NOT_FOUND);
+        };
+
+        let result = match request.code {
+            Code::GET => return self.respond_read(request, path),
+            Code::PUT | Code::POST => self.respond_write(request, path),
+            _ => Err(Code::METHOD_NOT_ALLOWED),
+        };
+
+        match result {
+            Ok(response) => response,
+            Err(code) => Message::response(request, code),
+        }
+    }
+
+    fn respond_read(&mut self, request: &Message, path: Path) -> Message {
+        let accept = request
+            .uint_option(option::ACCEPT)
+            .map(|accept| accept as u16);
+
+        if accept == Some(content_format::LINK_FORMAT) {
+            // Discover
+            return match self.discover(path) {
+                Ok(links) => {
+                    let mut response = Message::response(request, Code::CONTENT);
+                    response.set_content_format(content_format::LINK_FORMAT);
+                    response.payload = links.into_bytes();
+
+                    response
+                }
+                Err(code) => Message::response(request, code),
+            };
+        }
+
+        let (format, payload) = match self.read(path, accept) {
+            Ok(read) => read,
+            Err(code) => return Message::response(request, code),
+        };
+
+        let mut response = Message::response(request, Code::CONTENT);
+        response.set_content_format(format);
+
+        match request.observe() {
+            Some(0) => {
+                self.observations
+                    .retain(|observation| observation.token != request.token);
+                self.observations.push(Observation {
+                    token: request.token.clone(),
+                    path,
+                    accept,
+                    seq: 0,
+                    last: payload.clone(),
+                    notified_at: Instant::now(),
+                    message_id: request.message_id,
+                });
+
+                response.set_uint_option(option::OBSERVE, 0);
+            }
+            Some(1) => self
+                .observations
+                .retain(|observation| observation.token != request.token),
+            _ => (),
+        }
+
+        response.payload = payload;
+
+        // Larger reads are transferred block-wise
+        if let Some(block) = request
+            .block2()
+            .or_else(|| (response.payload.len() > 1024).then(|| Block::new(0, false, 1024)))
+        {
+            let total = response.payload.len();
+
+            if block.offset() > total {
+                return Message::response(request, Code::BAD_OPTION);
+            }
+
+            let end = (block.offset() + block.size()).min(total);
+
+            response.payload = response.payload[block.offset()..end].to_vec();
+            response.set_uint_option(
+                option::BLOCK2,
+                Block {
+                    more: end < total,
+                    ..block
+                }
+                .encode(),
+            );
+        }
+
+        response
+    }
+
+    fn respond_write(&mut self, request: &Message, path: Path) -> Result<Message, Code> {
+        let index = self.object_index(path.object)?;
+        let object = &mut self.objects[index];
+
+        let instance = path.instance.ok_or(Code::METHOD_NOT_ALLOWED)?;
+
+        if !object.instances().contains(&instance) {
+            return Err(Code::NOT_FOUND);
+        }
+
+        if let Some(resource_id) = path.resource {
+            let resource = *object
+                .resources()
+                .iter()
+                .find(|resource| resource.id == resource_id)
+                .ok_or(Code::NOT_FOUND)?;
+
+            if request.code == Code::POST && resource.operations.is_executable() {
+                object.execute(instance, resource_id, &request.payload)?;
+
+                return Ok(Message::response(request, Code::CHANGED));
+            }
+
+            if !resource.operations.is_writable() {
+                return Err(Code::METHOD_NOT_ALLOWED);
+            }
+
+            if let Some(block) = request.block1() {
+                let (expected, value_offset) = match self.block1 {
+                    Some((other, offset, value_offset)) if other == path => (offset, value_offset),
+                    _ => (0, 0),
+                };
+
+                if block.offset() != expected {
+                    return Err(Code::REQUEST_ENTITY_INCOMPLETE);
+                }
+
+                let data = if request.content_format() == Some(content_format::LWM2M_TLV)
+                    && block.num == 0
+                {
+                    // The TLV header of the resource is in the first block
+                    let (_, _, header_len, _) =
+                        Tlv::decode_header(&request.payload).map_err(|_| Code::BAD_REQUEST)?;
+
+                    &request.payload[header_len..]
+                } else {
+                    &request.payload[..]
+                };
+
+                let result =
+                    object.write_block(instance, resource_id, value_offset, data, block.more);
+
+                self.block1 = (result.is_ok() && block.more).then_some((
+                    path,
+                    expected + request.payload.len(),
+                    value_offset + data.len(),
+                ));
+
+                result?;
+
+                let mut response = Message::response(
+                    request,
+                    if block.more {
+                        Code::CONTINUE
+                    } else {
+                        Code::CHANGED
+                    },
+                );
+                response.set_uint_option(option::BLOCK1, block.encode());
+
+                return Ok(response);
+            }
+
+            let value = if request.content_format() == Some(content_format::LWM2M_TLV) {
+                let tlvs = Tlv::decode_all(&request.payload).map_err(|_| Code::BAD_REQUEST)?;
+                let tlv = tlvs.first().ok_or(Code::BAD_REQUEST)?;
+
+                Value::decode_tlv(resource.kind, tlv.value)?
+            } else {
+                Value::decode_text(resource.kind, &request.payload)?
+            };
+
+            object.write(instance, resource_id, value)?;
+        } else {
+            // A write of the instance, with the TLVs of its resources
+            if request.content_format() != Some(content_format::LWM2M_TLV) {
+                return Err(Code::UNSUPPORTED_CONTENT_FORMAT);
+            }
+
+            let tlvs = Tlv::decode_all(&request.payload).map_err(|_| Code::BAD_REQUEST)?;
+            let resources = object.resources().to_vec();
+
+            for tlv in tlvs.iter().filter(|tlv| tlv.kind == TlvKind::Resource) {
+                let resource = resources
+                    .iter()
+                    .find(|resource| resource.id == tlv.id)
+                    .ok_or(Code::NOT_FOUND)?;
+
+                if !resource.operations.is_writable() {
+                    return Err(Code::METHOD_NOT_ALLOWED);
+                }
+
+                object.write(
+                    instance,
+                    tlv.id,
+                    Value::decode_tlv(resource.kind, tlv.value)?,
+                )?;
+            }
+        }
+
+        Ok(Message::response(request, Code::CHANGED))
+    }
+
+    // Read a path, returning its content format and its payload
+    fn read(&mut self, path: Path, accept: Option<u16>) -> Result<(u16, Vec<u8>), Code> {
+        let index = self.object_index(path.object)?;
+        let object = &mut self.objects[index];
+
+        let resources = object.resources().to_vec();
+        let instances = object.instances();
+
+        if let Some(instance) = path.instance {
+            if !instances.contains(&instance) {
+                return Err(Code::NOT_FOUND);
+            }
+        }
+
+        if let (Some(instance), Some(resource_id)) = (path.instance, path.resource) {
+            let resource = resources
+                .iter()
+                .find(|resource| resource.id == resource_id)
+                .ok_or(Code::NOT_FOUND)?;
+
+            if !resource.operations.is_readable() {
+                return Err(Code::METHOD_NOT_ALLOWED);
+            }
+
+            let value = object.read(instance, resource_id)?;
+
+            return match accept {
+                Some(content_format::LWM2M_TLV) => {
+                    let mut payload = Vec::new();
+                    Tlv::new(TlvKind::Resource, resource_id, &value.encode_tlv())
+                        .encode(&mut payload);
+
+                    Ok((content_format::LWM2M_TLV, payload))
+                }
+                None | Some(content_format::TEXT_PLAIN) | Some(content_format::OCTET_STREAM) => {
+                    let format = if resource.kind == Kind::Opaque {
+                        content_format::OCTET_STREAM
+                    } else {
+                        content_format::TEXT_PLAIN
+                    };
+
+                    Ok((format, value.encode_text()))
+                }
+                _ => Err(Code::NOT_ACCEPTABLE),
+            };
+        }
+
+        if !matches!(accept, None | Some(content_format::LWM2M_TLV)) {
+            return Err(Code::NOT_ACCEPTABLE);
+        }
+
+        let mut read_instance = |instance: u16| -> Result<Vec<u8>, Code> {
+            let mut payload = Vec::new();
+
+            for resource in resources
+                .iter()
+                .filter(|resource| resource.operations.is_readable())
+            {
+                match object.read(instance, resource.id) {
+                    Ok(value) => Tlv::new(TlvKind::Resource, resource.id, &value.encode_tlv())
+                        .encode(&mut payload),
+                    // An optional resource not supported by the instance
+                    Err(Code::NOT_FOUND) => (),
+                    Err(code) => return Err(code),
+                }
+            }
+
+            Ok(payload)
+        };
+
+        let payload = if let Some(instance) = path.instance {
+            read_instance(instance)?
+        } else {
+            let mut payload = Vec::new();
+
+            for instance in instances {
+                Tlv::new(TlvKind::ObjectInstance, instance, &read_instance(instance)?)
+                    .encode(&mut payload);
+            }
+
+            payload
+        };
+
+        Ok((content_format::LWM2M_TLV, payload))
+    }
+
+    fn discover(&mut self, path: Path) -> Result<String, Code> {
+        let index = self.object_index(path.object)?;
+        let object = &self.objects[index];
+
+        let mut links = format!("</{}>", object.id());
+
+        for instance in object.instances() {
+            if path.instance.is_some_and(|other| other != instance) {
+                continue;
+            }
+
+            links.push_str(&format!(",</{}/{}>", object.id(), instance));
+
+            for resource in object.resources() {
+                if path.resource.is_some_and(|other| other != resource.id) {
+                    continue;
+                }
+
+                links.push_str(&format!(",</{}/{}/{}>", object.id(), instance, resource.id));
+            }
+        }
+
+        Ok(links)
+    }
+
+    fn object_index(&self, id: u16) -> Result<usize, Code> {
+        self.objects
+            .iter()
+            .position(|object| object.id() == id)
+            .ok_or(Code::NOT_FOUND)
+    }
+
+    // Notify the observers of the paths which changed, if the minimum period elapsed
+    fn notify(&mut self) -> Result<(), EspError> {
+        for index in 0..self.observations.len() {
+            let observation = &self.observations[index];
+
+            if observation.notified_at.elapsed() < self.conf.min_notify_period {
+                continue;
+            }
+
+            let (path, accept) = (observation.path, observation.accept);
+
+            let Ok((format, payload)) = self.read(path, accept) else {
+                continue;
+            };
+
+            let observation = &self.observations[index];
+
+            if payload == observation.last
+                && observation.notified_at.elapsed() < self.conf.max_notify_period
+            {
+                continue;
+            }
+
+            let message_id = self.next_message_id();
+
+            let observation = &mut self.observations[index];
+            observation.seq = observation.seq.wrapping_add(1) & 0x00ff_ffff;
+            observation.notified_at = Instant::now();
+            observation.message_id = message_id;
+
+            let mut notification = Message::new(MessageType::NonConfirmable, Code::CONTENT);
+            notification.message_id = message_id;
+            notification.token.clone_from(&observation.token);
+            notification.set_uint_option(option::OBSERVE, observation.seq);
+            notification.set_content_format(format);
+            notification.payload.clone_from(&payload);
+
+            observation.last = payload;
+
+            self.transport.send(&notification.encode()?)?;
+        }
+
+        Ok(())
+    }
+
+    // Send a confirmable request, and wait for its response, serving the requests of the
+    // server meanwhile
+    fn exchange(&mut self, mut request: Message) -> Result<Message, EspError> {
+        request.mtype = MessageType::Confirmable;
+        request.message_id = self.next_message_id();
+        request.token = unsafe { esp_random() }.to_be_bytes().to_vec();
+
+        let data = request.encode()?;
+
+        let mut timeout = self.conf.ack_timeout;
+
+        for _ in 0..=self.conf.max_retransmit {
+            self.transport.send(&data)?;
+
+            let deadline = Instant::now() + timeout;
+
+            while let Some(message) = self.recv_until(deadline)? {
+                match message.mtype {
+                    MessageType::Acknowledgement if message.message_id == request.message_id => {
+                        if message.code != Code::EMPTY {
+                            return Ok(message);
+                        }
+
+                        // The response follows separately
+                        let deadline = Instant::now() + self.conf.ack_timeout * 8;
+
+                        while let Some(message) = self.recv_until(deadline)? {
+                            if message.token == request.token && !message.code.is_request() {
+                                if message.mtype == MessageType::Confirmable {
+                                    let ack = Message::empty(
+                                        MessageType::Acknowledgement,
+                                        message.message_id,
+                                    );
+                                    self.transport.send(&ack.encode()?)?;
+                                }
+
+                                return Ok(message);
+                            }
+
+                            self.handle(message)?;
+                        }
+
+                        return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+                    }
+                    MessageType::Reset if message.message_id == request.message_id => {
+                        return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+                    }
+                    _ => self.handle(message)?,
+                }
+            }
+
+            timeout *= 2;
+        }
+
+        Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+    }
+
+    // Receive the next valid message, or `None` once the deadline passed
+    fn recv_until(&mut self, deadline: Instant) -> Result<Option<Message>, EspError> {
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            let Some(len) = self.transport.recv(&mut self.buf[..], Some(timeout))? else {
+                return Ok(None);
+            };
+
+            if let Ok(message) = Message::decode(&self.buf[..len]) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.next_message_id
+    }
+}
+
+impl<T> Debug for Lwm2mClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lwm2mClient")
+            .field("conf", &self.conf)
+            .field("location", &self.location)
+            .field(
+                "objects",
+                &self
+                    .objects
+                    .iter()
+                    .map(|object| object.id())
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
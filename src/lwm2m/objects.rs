@@ -0,0 +1,461 @@
+//! The standard LwM2M objects: Device, Firmware Update and Temperature
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+
+use ::log::info;
+
+use crate::coap::Code;
+use crate::private::cstr::from_cstr_ptr;
+use crate::sys::*;
+
+use super::{Kind, Object, Operations, Resource, Value};
+
+/// The Device object (3), with the identity of the device and its Reboot resource
+pub struct Device {
+    manufacturer: String,
+    model: String,
+    serial: String,
+    reboot: bool,
+}
+
+impl Device {
+    pub const ID: u16 = 3;
+
+    const RESOURCES: &'static [Resource] = &[
+        Resource::new(0, Kind::String, Operations::Read),
+        Resource::new(1, Kind::String, Operations::Read),
+        Resource::new(2, Kind::String, Operations::Read),
+        Resource::new(3, Kind::String, Operations::Read),
+        Resource::new(4, Kind::Opaque, Operations::Execute),
+        Resource::new(10, Kind::Int, Operations::Read),
+        Resource::new(13, Kind::Time, Operations::Read),
+        Resource::new(16, Kind::String, Operations::Read),
+    ];
+
+    /// Create the object; the firmware version is the version of the running application
+    pub fn new(manufacturer: &str, model: &str, serial: &str) -> Self {
+        Self {
+            manufacturer: manufacturer.into(),
+            model: model.into(),
+            serial: serial.into(),
+            reboot: false,
+        }
+    }
+
+    fn firmware_version() -> String {
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let app_desc = unsafe { esp_app_get_description().as_ref() };
+        #[cfg(esp_idf_version_major = "4")]
+        let app_desc = unsafe { esp_ota_get_app_description().as_ref() };
+
+        app_desc
+            .map(|app_desc| unsafe { from_cstr_ptr(&app_desc.version as *const _) }.into())
+            .unwrap_or_default()
+    }
+}
+
+impl Object for Device {
+    fn id(&self) -> u16 {
+        Self::ID
+    }
+
+    fn resources(&self) -> &[Resource] {
+        Self::RESOURCES
+    }
+
+    fn read(&mut self, instance: u16, resource: u16) -> Result<Value, Code> {
+        if instance != 0 {
+            return Err(Code::NOT_FOUND);
+        }
+
+        Ok(match resource {
+            0 => Value::String(self.manufacturer.clone()),
+            1 => Value::String(self.model.clone()),
+            2 => Value::String(self.serial.clone()),
+            3 => Value::String(Self::firmware_version()),
+            10 => Value::Int((unsafe { esp_get_free_heap_size() } / 1024) as _),
+            13 => Value::Time(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|time| time.as_secs() as _)
+                    .unwrap_or(0),
+            ),
+            16 => Value::String("U".into()),
+            _ => return Err(Code::NOT_FOUND),
+        })
+    }
+
+    fn execute(&mut self, instance: u16, resource: u16, _args: &[u8]) -> Result<(), Code> {
+        match (instance, resource) {
+            (0, 4) => {
+                // Rebooted once the response is sent
+                self.reboot = true;
+
+                Ok(())
+            }
+            (0, _) => Err(Code::METHOD_NOT_ALLOWED),
+            _ => Err(Code::NOT_FOUND),
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.reboot {
+            info!("Rebooting, as requested by the server");
+
+            unsafe { esp_restart() };
+        }
+    }
+}
+
+/// The Temperature object (3303), reading a temperature sensor
+pub struct Temperature {
+    read: Box<dyn FnMut() -> f32 + Send>,
+    min: Option<f32>,
+    max: Option<f32>,
+}
+
+impl Temperature {
+    pub const ID: u16 = 3303;
+
+    const RESOURCES: &'static [Resource] = &[
+        Resource::new(5700, Kind::Float, Operations::Read),
+        Resource::new(5701, Kind::String, Operations::Read),
+        Resource::new(5601, Kind::Float, Operations::Read),
+        Resource::new(5602, Kind::Float, Operations::Read),
+        Resource::new(5605, Kind::Opaque, Operations::Execute),
+    ];
+
+    /// Create the object, with the closure reading the temperature, in degrees Celsius
+    pub fn new<F>(read: F) -> Self
+    where
+        F: FnMut() -> f32 + Send + 'static,
+    {
+        Self {
+            read: Box::new(read),
+            min: None,
+            max: None,
+        }
+    }
+
+    fn measure(&mut self) -> f32 {
+        let value = (self.read)();
+
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+
+        value
+    }
+}
+
+impl Object for Temperature {
+    fn id(&self) -> u16 {
+        Self::ID
+    }
+
+    fn resources(&self) -> &[Resource] {
+        Self::RESOURCES
+    }
+
+    fn read(&mut self, instance: u16, resource: u16) -> Result<Value, Code> {
+        if instance != 0 {
+            return Err(Code::NOT_FOUND);
+        }
+
+        Ok(match resource {
+            5700 => Value::Float(self.measure() as _),
+            5701 => Value::String("Cel".into()),
+            5601 => Value::Float(self.min.unwrap_or_else(|| self.measure()) as _),
+            5602 => Value::Float(self.max.unwrap_or_else(|| self.measure()) as _),
+            _ => return Err(Code::NOT_FOUND),
+        })
+    }
+
+    fn execute(&mut self, instance: u16, resource: u16, _args: &[u8]) -> Result<(), Code> {
+        match (instance, resource) {
+            (0, 5605) => {
+                self.min = None;
+                self.max = None;
+
+                Ok(())
+            }
+            (0, _) => Err(Code::METHOD_NOT_ALLOWED),
+            _ => Err(Code::NOT_FOUND),
+        }
+    }
+}
+
+#[cfg(all(esp_idf_comp_app_update_enabled, esp_idf_comp_spi_flash_enabled))]
+pub use firmware::*;
+
+#[cfg(all(esp_idf_comp_app_update_enabled, esp_idf_comp_spi_flash_enabled))]
+mod firmware {
+    use core::mem;
+
+    use ::log::{info, warn};
+
+    use crate::coap::Code;
+    use crate::ota::{EspOta, EspOtaUpdate, EspOtaUpdateFinished};
+    use crate::sys::*;
+
+    use super::super::{Kind, Object, Operations, Resource, Value};
+
+    const STATE_IDLE: i64 = 0;
+    const STATE_DOWNLOADING: i64 = 1;
+    const STATE_DOWNLOADED: i64 = 2;
+    const STATE_UPDATING: i64 = 3;
+
+    const RESULT_INITIAL: i64 = 0;
+    const RESULT_SUCCESS: i64 = 1;
+    const RESULT_NOT_ENOUGH_FLASH: i64 = 2;
+    const RESULT_INTEGRITY_FAILURE: i64 = 5;
+    const RESULT_UPDATE_FAILED: i64 = 8;
+    const RESULT_UNSUPPORTED_PROTOCOL: i64 = 9;
+
+    /// The Firmware Update object (5), writing the package pushed by the server - in one
+    /// write, or block-wise - to the update slot of `EspOta`
+    ///
+    /// The new firmware is activated, and the device rebooted, when the server executes the
+    /// Update resource. Once it checks it works - and marks its slot as valid - the new firmware
+    /// reports the outcome to the server with `set_update_succeeded`.
+    ///
+    /// The Package URI resource - for the device to pull the package - is not supported.
+    pub struct FirmwareUpdate {
+        // Declared before `ota`, so as to be dropped before it
+        update: Option<EspOtaUpdate<'static>>,
+        finished: Option<EspOtaUpdateFinished<'static>>,
+        ota: EspOta,
+        state: i64,
+        result: i64,
+        activate: bool,
+    }
+
+    impl FirmwareUpdate {
+        pub const ID: u16 = 5;
+
+        const RESOURCES: &'static [Resource] = &[
+            Resource::new(0, Kind::Opaque, Operations::Write),
+            Resource::new(1, Kind::String, Operations::ReadWrite),
+            Resource::new(2, Kind::Opaque, Operations::Execute),
+            Resource::new(3, Kind::Int, Operations::Read),
+            Resource::new(5, Kind::Int, Operations::Read),
+            Resource::new(9, Kind::Int, Operations::Read),
+        ];
+
+        pub fn new(ota: EspOta) -> Self {
+            Self {
+                update: None,
+                finished: None,
+                ota,
+                state: STATE_IDLE,
+                result: RESULT_INITIAL,
+                activate: false,
+            }
+        }
+
+        /// Report the outcome of an update to the server, once the new firmware checked it
+        /// works - or once the bootloader rolled it back
+        pub fn set_update_succeeded(&mut self, succeeded: bool) {
+            self.result = if succeeded {
+                RESULT_SUCCESS
+            } else {
+                RESULT_UPDATE_FAILED
+            };
+        }
+
+        pub fn release(mut self) -> EspOta {
+            self.reset();
+
+            self.ota
+        }
+
+        fn reset(&mut self) {
+            self.finished = None;
+
+            if let Some(update) = self.update.take() {
+                let _ = update.abort();
+            }
+
+            self.state = STATE_IDLE;
+        }
+
+        fn begin(&mut self) -> Result<(), Code> {
+            self.reset();
+
+            let update = match self.ota.initiate_update() {
+                // SAFETY: The update borrows nothing from `EspOta` - whose borrow only ensures
+                // that a single update is in progress - and it is dropped before `EspOta`, which
+                // this object owns
+                Ok(update) => unsafe {
+                    mem::transmute::<EspOtaUpdate<'_>, EspOtaUpdate<'static>>(update)
+                },
+                Err(err) => {
+                    warn!("Failed to initiate the update: {err}");
+
+                    return Err(self.fail(RESULT_NOT_ENOUGH_FLASH));
+                }
+            };
+
+            self.update = Some(update);
+            self.state = STATE_DOWNLOADING;
+            self.result = RESULT_INITIAL;
+
+            info!("Downloading a new firmware");
+
+            Ok(())
+        }
+
+        fn write_package(&mut self, data: &[u8], more: bool) -> Result<(), Code> {
+            let update = self.update.as_mut().ok_or(Code::BAD_REQUEST)?;
+
+            if let Err(err) = update.write(data) {
+                warn!("Failed to write the firmware: {err}");
+
+                return Err(self.fail(RESULT_NOT_ENOUGH_FLASH));
+            }
+
+            if !more {
+                let update = self.update.take().unwrap();
+
+                match update.finish() {
+                    Ok(finished) => {
+                        self.finished = Some(finished);
+                        self.state = STATE_DOWNLOADED;
+
+                        info!("Downloaded a new firmware");
+                    }
+                    Err(err) => {
+                        warn!("Failed to validate the firmware: {err}");
+
+                        return Err(self.fail(RESULT_INTEGRITY_FAILURE));
+                    }
+                }
+            }
+
+            Ok(())
+        }
+
+        fn fail(&mut self, result: i64) -> Code {
+            self.reset();
+            self.result = result;
+
+            Code::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    impl Object for FirmwareUpdate {
+        fn id(&self) -> u16 {
+            Self::ID
+        }
+
+        fn resources(&self) -> &[Resource] {
+            Self::RESOURCES
+        }
+
+        fn read(&mut self, instance: u16, resource: u16) -> Result<Value, Code> {
+            if instance != 0 {
+                return Err(Code::NOT_FOUND);
+            }
+
+            Ok(match resource {
+                1 => Value::String("".into()),
+                3 => Value::Int(self.state),
+                5 => Value::Int(self.result),
+                // Push only
+                9 => Value::Int(1),
+                _ => return Err(Code::NOT_FOUND),
+            })
+        }
+
+        fn write(&mut self, instance: u16, resource: u16, value: Value) -> Result<(), Code> {
+            if instance != 0 {
+                return Err(Code::NOT_FOUND);
+            }
+
+            match (resource, value) {
+                // An empty package - or URI - cancels the update
+                (0, Value::Opaque(package)) if package.is_empty() => {
+                    self.reset();
+                    self.result = RESULT_INITIAL;
+
+                    Ok(())
+                }
+                (0, Value::Opaque(package)) => {
+                    self.begin()?;
+                    self.write_package(&package, false)
+                }
+                (1, Value::String(uri)) if uri.is_empty() => {
+                    self.reset();
+                    self.result = RESULT_INITIAL;
+
+                    Ok(())
+                }
+                (1, _) => {
+                    self.result = RESULT_UNSUPPORTED_PROTOCOL;
+
+                    Ok(())
+                }
+                _ => Err(Code::BAD_REQUEST),
+            }
+        }
+
+        fn write_block(
+            &mut self,
+            instance: u16,
+            resource: u16,
+            offset: usize,
+            data: &[u8],
+            more: bool,
+        ) -> Result<(), Code> {
+            if (instance, resource) != (0, 0) {
+                return Err(Code::METHOD_NOT_ALLOWED);
+            }
+
+            if offset == 0 {
+                self.begin()?;
+            } else if self.state != STATE_DOWNLOADING {
+                return Err(Code::REQUEST_ENTITY_INCOMPLETE);
+            }
+
+            self.write_package(data, more)
+        }
+
+        fn execute(&mut self, instance: u16, resource: u16, _args: &[u8]) -> Result<(), Code> {
+            match (instance, resource) {
+                (0, 2) if self.state == STATE_DOWNLOADED => {
+                    // Activated, and rebooted, once the response is sent
+                    self.state = STATE_UPDATING;
+                    self.activate = true;
+
+                    Ok(())
+                }
+                (0, _) => Err(Code::METHOD_NOT_ALLOWED),
+                _ => Err(Code::NOT_FOUND),
+            }
+        }
+
+        fn poll(&mut self) {
+            if !mem::take(&mut self.activate) {
+                return;
+            }
+
+            let Some(finished) = self.finished.take() else {
+                return;
+            };
+
+            match finished.activate() {
+                Ok(()) => {
+                    info!("Rebooting into the new firmware");
+
+                    unsafe { esp_restart() };
+                }
+                Err(err) => {
+                    warn!("Failed to activate the new firmware: {err}");
+
+                    self.fail(RESULT_UPDATE_FAILED);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! The OMA-TLV content format of LwM2M
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::sys::*;
+
+/// What a TLV identifies
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TlvKind {
+    /// An object instance, whose value holds the TLVs of its resources
+    ObjectInstance = 0,
+    /// An instance of a multiple resource
+    ResourceInstance = 1,
+    /// A multiple resource, whose value holds the TLVs of its instances
+    MultipleResource = 2,
+    /// A single resource, with its value
+    Resource = 3,
+}
+
+/// A TLV, borrowing its value from the payload decoded
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Tlv<'a> {
+    pub kind: TlvKind,
+    pub id: u16,
+    pub value: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    pub const fn new(kind: TlvKind, id: u16, value: &'a [u8]) -> Self {
+        Self { kind, id, value }
+    }
+
+    /// Decode all the TLVs of a payload
+    pub fn decode_all(mut data: &'a [u8]) -> Result<Vec<Self>, EspError> {
+        let mut tlvs = Vec::new();
+
+        while !data.is_empty() {
+            let (tlv, len) = Self::decode(data)?;
+
+            tlvs.push(tlv);
+            data = &data[len..];
+        }
+
+        Ok(tlvs)
+    }
+
+    /// Decode the first TLV of a payload, returning it with its encoded length
+    pub fn decode(data: &'a [u8]) -> Result<(Self, usize), EspError> {
+        let (kind, id, header_len, len) = Self::decode_header(data)?;
+
+        let value = data
+            .get(header_len..header_len + len)
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        Ok((Self { kind, id, value }, header_len + len))
+    }
+
+    /// Decode the header of the first TLV of a payload - which may hold only a part of its
+    /// value, e.g. in the first block of a block-wise write - returning its kind, its ID, the
+    /// length of the header and the length of the value
+    pub fn decode_header(data: &[u8]) -> Result<(TlvKind, u16, usize, usize), EspError> {
+        let invalid = || EspError::from_infallible::<ESP_ERR_INVALID_ARG>();
+
+        let header = *data.first().ok_or_else(invalid)?;
+
+        let kind = match header >> 6 {
+            0 => TlvKind::ObjectInstance,
+            1 => TlvKind::ResourceInstance,
+            2 => TlvKind::MultipleResource,
+            _ => TlvKind::Resource,
+        };
+
+        let id_len = if header & 0x20 != 0 { 2 } else { 1 };
+        let len_len = ((header >> 3) & 0x03) as usize;
+
+        let mut offset = 1;
+
+        let id = data
+            .get(offset..offset + id_len)
+            .ok_or_else(invalid)?
+            .iter()
+            .fold(0_u16, |id, byte| (id << 8) | *byte as u16);
+        offset += id_len;
+
+        let len = if len_len == 0 {
+            (header & 0x07) as usize
+        } else {
+            let len = data
+                .get(offset..offset + len_len)
+                .ok_or_else(invalid)?
+                .iter()
+                .fold(0_usize, |len, byte| (len << 8) | *byte as usize);
+            offset += len_len;
+
+            len
+        };
+
+        Ok((kind, id, offset, len))
+    }
+
+    /// Encode the TLV, appending it to `out`
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let len = self.value.len();
+
+        let mut header = (self.kind as u8) << 6;
+
+        if self.id > 0xff {
+            header |= 0x20;
+        }
+
+        let len_len = if len < 8 {
+            header |= len as u8;
+            0
+        } else if len <= 0xff {
+            1
+        } else if len <= 0xffff {
+            2
+        } else {
+            3
+        };
+
+        header |= (len_len as u8) << 3;
+
+        out.push(header);
+
+        if self.id > 0xff {
+            out.extend_from_slice(&self.id.to_be_bytes());
+        } else {
+            out.push(self.id as u8);
+        }
+
+        out.extend_from_slice(&(len as u32).to_be_bytes()[4 - len_len..]);
+        out.extend_from_slice(self.value);
+    }
+}
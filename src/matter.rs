@@ -0,0 +1,436 @@
+//! Glue for esp-matter
+//!
+//! esp-matter - and the connectedhomeip SDK under it - is a C++ API, which can not be bound
+//! directly. This module is the Rust half of a thin C shim, which the application compiles
+//! in one of its C++ components:
+//! - the shim implements the `esp_svc_matter_*` functions which Rust calls - to open and close
+//!   the commissioning window, count the fabrics and report attribute changes - with the
+//!   esp-matter API, e.g. `CommissioningWindowManager::OpenBasicCommissioningWindow` and
+//!   `esp_matter::attribute::report`
+//! - Rust implements the `esp_svc_matter_kvs_*` functions - the key-value store of the
+//!   fabrics, the credentials and the attributes, in NVS - which the shim calls from its
+//!   `chip::PersistentStorageDelegate`, and the `esp_svc_matter_attribute_*` functions, which
+//!   the shim calls from the attribute callback of esp-matter for the bridged attributes
+//!
+//! ```ignore
+//! use esp_idf_svc::matter::{self, AttributePath, MatterValue};
+//! use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+//!
+//! matter::set_storage(EspNvs::new(EspDefaultNvsPartition::take()?, "matter", true)?);
+//!
+//! let state = Arc::new(Mutex::new(false));
+//!
+//! let _light = matter::bridge(
+//!     AttributePath::new(1, 0x0006, 0x0000), // The `OnOff` attribute
+//!     {
+//!         let state = state.clone();
+//!         move || Ok(MatterValue::Bool(*state.lock().unwrap()))
+//!     },
+//!     move |value| match value {
+//!         MatterValue::Bool(on) => {
+//!             *state.lock().unwrap() = on;
+//!             Ok(())
+//!         }
+//!         _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()),
+//!     },
+//! );
+//!
+//! matter::open_commissioning_window(Duration::from_secs(300))?;
+//! ```
+
+use core::ffi::{c_char, CStr};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use embedded_svc::storage::{RawStorage, StorageBase};
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+/// The maximum length of a key of the key-value store
+pub const MAX_KEY_LEN: usize = 32;
+
+/// The maximum size of a value of the key-value store
+pub const MAX_VALUE_LEN: usize = 2048;
+
+extern "C" {
+    fn esp_svc_matter_open_commissioning_window(timeout_secs: u16) -> esp_err_t;
+    fn esp_svc_matter_close_commissioning_window() -> esp_err_t;
+    fn esp_svc_matter_is_commissioning_window_open() -> bool;
+    fn esp_svc_matter_fabric_count() -> u8;
+    fn esp_svc_matter_report_attribute(
+        endpoint: u16,
+        cluster: u32,
+        attribute: u32,
+        value: *const MatterRawValue,
+    ) -> esp_err_t;
+}
+
+/// Open the basic commissioning window - so that a controller can commission the device
+/// with its setup code - for `timeout`
+pub fn open_commissioning_window(timeout: Duration) -> Result<(), EspError> {
+    esp!(unsafe {
+        esp_svc_matter_open_commissioning_window(timeout.as_secs().min(u16::MAX as _) as _)
+    })
+}
+
+/// Close the commissioning window, if open
+pub fn close_commissioning_window() -> Result<(), EspError> {
+    esp!(unsafe { esp_svc_matter_close_commissioning_window() })
+}
+
+pub fn is_commissioning_window_open() -> bool {
+    unsafe { esp_svc_matter_is_commissioning_window_open() }
+}
+
+/// The number of fabrics the device is commissioned in; 0 for a device to commission
+pub fn fabric_count() -> u8 {
+    unsafe { esp_svc_matter_fabric_count() }
+}
+
+/// The path of an attribute
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct AttributePath {
+    pub endpoint: u16,
+    pub cluster: u32,
+    pub attribute: u32,
+}
+
+impl AttributePath {
+    pub const fn new(endpoint: u16, cluster: u32, attribute: u32) -> Self {
+        Self {
+            endpoint,
+            cluster,
+            attribute,
+        }
+    }
+}
+
+/// The value of an attribute, for the scalar types of Matter
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MatterValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f32),
+}
+
+/// The value of an attribute, as exchanged with the shim
+///
+/// `kind` is 0 for null, 1 for a boolean, 2 for a signed integer, 3 for an unsigned integer
+/// and 4 for a float; `bits` hold the value, as an `i64`, an `u64` or the bits of an `f32`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MatterRawValue {
+    pub kind: u32,
+    pub bits: u64,
+}
+
+impl From<MatterValue> for MatterRawValue {
+    fn from(value: MatterValue) -> Self {
+        match value {
+            MatterValue::Null => Self { kind: 0, bits: 0 },
+            MatterValue::Bool(value) => Self {
+                kind: 1,
+                bits: value as _,
+            },
+            MatterValue::Int(value) => Self {
+                kind: 2,
+                bits: value as _,
+            },
+            MatterValue::UInt(value) => Self {
+                kind: 3,
+                bits: value,
+            },
+            MatterValue::Float(value) => Self {
+                kind: 4,
+                bits: value.to_bits() as _,
+            },
+        }
+    }
+}
+
+impl TryFrom<MatterRawValue> for MatterValue {
+    type Error = EspError;
+
+    fn try_from(value: MatterRawValue) -> Result<Self, Self::Error> {
+        Ok(match value.kind {
+            0 => Self::Null,
+            1 => Self::Bool(value.bits != 0),
+            2 => Self::Int(value.bits as _),
+            3 => Self::UInt(value.bits),
+            4 => Self::Float(f32::from_bits(value.bits as _)),
+            _ => return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()),
+        })
+    }
+}
+
+/// Report a change of an attribute made locally, e.g. with a button, to the subscribed
+/// controllers
+pub fn report(path: AttributePath, value: MatterValue) -> Result<(), EspError> {
+    let raw = MatterRawValue::from(value);
+
+    esp!(unsafe {
+        esp_svc_matter_report_attribute(path.endpoint, path.cluster, path.attribute, &raw)
+    })
+}
+
+type ReadCallback = Box<dyn FnMut() -> Result<MatterValue, EspError> + Send + 'static>;
+type WriteCallback = Box<dyn FnMut(MatterValue) -> Result<(), EspError> + Send + 'static>;
+
+struct Bridged {
+    id: u32,
+    path: AttributePath,
+    read: ReadCallback,
+    write: WriteCallback,
+}
+
+static BRIDGED: Mutex<Vec<Bridged>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Back the attribute at `path` with Rust code: `read` is called on every read of the
+/// attribute by a controller, and `write` on every write
+///
+/// The attribute is bridged until the returned `BridgedAttribute` is dropped.
+pub fn bridge<R, W>(path: AttributePath, read: R, write: W) -> BridgedAttribute
+where
+    R: FnMut() -> Result<MatterValue, EspError> + Send + 'static,
+    W: FnMut(MatterValue) -> Result<(), EspError> + Send + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    BRIDGED.lock().push(Bridged {
+        id,
+        path,
+        read: Box::new(read),
+        write: Box::new(write),
+    });
+
+    BridgedAttribute(id)
+}
+
+/// An attribute backed by Rust code, until dropped
+#[derive(Debug)]
+pub struct BridgedAttribute(u32);
+
+impl Drop for BridgedAttribute {
+    fn drop(&mut self) {
+        BRIDGED.lock().retain(|bridged| bridged.id != self.0);
+    }
+}
+
+/// Called by the shim on a read of an attribute; `ESP_ERR_NOT_FOUND` when the attribute
+/// is not bridged, so that esp-matter serves it itself
+#[no_mangle]
+unsafe extern "C" fn esp_svc_matter_attribute_read(
+    endpoint: u16,
+    cluster: u32,
+    attribute: u32,
+    value: *mut MatterRawValue,
+) -> esp_err_t {
+    let path = AttributePath::new(endpoint, cluster, attribute);
+
+    let mut bridged = BRIDGED.lock();
+
+    let Some(bridged) = bridged.iter_mut().find(|bridged| bridged.path == path) else {
+        return ESP_ERR_NOT_FOUND;
+    };
+
+    match (bridged.read)() {
+        Ok(read) => {
+            *value = read.into();
+            ESP_OK
+        }
+        Err(err) => err.code(),
+    }
+}
+
+/// Called by the shim on a write of an attribute; `ESP_ERR_NOT_FOUND` when the attribute
+/// is not bridged
+#[no_mangle]
+unsafe extern "C" fn esp_svc_matter_attribute_write(
+    endpoint: u16,
+    cluster: u32,
+    attribute: u32,
+    value: *const MatterRawValue,
+) -> esp_err_t {
+    let path = AttributePath::new(endpoint, cluster, attribute);
+
+    let value = match MatterValue::try_from(*value) {
+        Ok(value) => value,
+        Err(err) => return err.code(),
+    };
+
+    let mut bridged = BRIDGED.lock();
+
+    let Some(bridged) = bridged.iter_mut().find(|bridged| bridged.path == path) else {
+        return ESP_ERR_NOT_FOUND;
+    };
+
+    match (bridged.write)(value) {
+        Ok(()) => ESP_OK,
+        Err(err) => err.code(),
+    }
+}
+
+type Storage = Box<dyn RawStorage<Error = EspError> + Send + 'static>;
+
+static STORAGE: Mutex<Option<Storage>> = Mutex::new(None);
+
+/// Set the storage of the key-value store of Matter, e.g. an `EspNvs` in its own namespace
+///
+/// The keys of Matter - e.g. `f/1/n` for the NOC of the first fabric - are longer than the
+/// NVS keys, so they are hashed, and stored along with the values to detect the collisions.
+pub fn set_storage<S>(storage: S)
+where
+    S: RawStorage<Error = EspError> + Send + 'static,
+{
+    *STORAGE.lock() = Some(Box::new(storage));
+}
+
+// FNV-1a, in 14 hex digits prefixed with `m`, as a NVS key is 15 characters at most
+fn storage_key(key: &[u8]) -> heapless::String<15> {
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+
+    let mut storage_key = heapless::String::new();
+    core::fmt::Write::write_fmt(
+        &mut storage_key,
+        format_args!("m{:014x}", hash & 0x00ff_ffff_ffff_ffff),
+    )
+    .unwrap();
+
+    storage_key
+}
+
+unsafe fn key_arg<'a>(key: *const c_char) -> Result<&'a [u8], esp_err_t> {
+    if key.is_null() {
+        return Err(ESP_ERR_INVALID_ARG);
+    }
+
+    let key = CStr::from_ptr(key).to_bytes();
+
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        Err(ESP_ERR_INVALID_ARG)
+    } else {
+        Ok(key)
+    }
+}
+
+/// Called by the shim to get the value of `key` into `buf`, with `len` its size - set to
+/// the size of the value
+///
+/// Fails with `ESP_ERR_NVS_NOT_FOUND` when `key` has no value, and with
+/// `ESP_ERR_NVS_INVALID_LENGTH` when `buf` is too small - the first `len` bytes of the value
+/// are read, as `PersistentStorageDelegate::SyncGetKeyValue` expects.
+#[no_mangle]
+unsafe extern "C" fn esp_svc_matter_kvs_get(
+    key: *const c_char,
+    buf: *mut u8,
+    len: *mut usize,
+) -> esp_err_t {
+    let key = match key_arg(key) {
+        Ok(key) => key,
+        Err(err) => return err,
+    };
+
+    let storage = STORAGE.lock();
+    let Some(storage) = storage.as_ref() else {
+        return ESP_ERR_INVALID_STATE;
+    };
+
+    let mut record = vec![0; 1 + MAX_KEY_LEN + MAX_VALUE_LEN];
+
+    let record = match storage.get_raw(&storage_key(key), &mut record) {
+        Ok(Some(record)) => record,
+        Ok(None) => return ESP_ERR_NVS_NOT_FOUND,
+        Err(err) => return err.code(),
+    };
+
+    // The record is the length of the key, the key and the value
+    let key_len = record[0] as usize;
+
+    if record.len() < 1 + key_len || &record[1..1 + key_len] != key {
+        return ESP_ERR_NVS_NOT_FOUND;
+    }
+
+    let value = &record[1 + key_len..];
+    let copied = value.len().min(*len);
+
+    if copied > 0 {
+        core::ptr::copy_nonoverlapping(value.as_ptr(), buf, copied);
+    }
+
+    let result = if copied < value.len() {
+        ESP_ERR_NVS_INVALID_LENGTH
+    } else {
+        ESP_OK
+    };
+
+    *len = copied;
+
+    result
+}
+
+/// Called by the shim to set the value of `key`
+#[no_mangle]
+unsafe extern "C" fn esp_svc_matter_kvs_set(
+    key: *const c_char,
+    value: *const u8,
+    len: usize,
+) -> esp_err_t {
+    let key = match key_arg(key) {
+        Ok(key) => key,
+        Err(err) => return err,
+    };
+
+    if len > MAX_VALUE_LEN || (len > 0 && value.is_null()) {
+        return ESP_ERR_INVALID_ARG;
+    }
+
+    let mut storage = STORAGE.lock();
+    let Some(storage) = storage.as_mut() else {
+        return ESP_ERR_INVALID_STATE;
+    };
+
+    let mut record = Vec::with_capacity(1 + key.len() + len);
+    record.push(key.len() as u8);
+    record.extend_from_slice(key);
+
+    if len > 0 {
+        record.extend_from_slice(core::slice::from_raw_parts(value, len));
+    }
+
+    match storage.set_raw(&storage_key(key), &record) {
+        Ok(_) => ESP_OK,
+        Err(err) => err.code(),
+    }
+}
+
+/// Called by the shim to delete the value of `key`; `ESP_ERR_NVS_NOT_FOUND` when it has
+/// none
+#[no_mangle]
+unsafe extern "C" fn esp_svc_matter_kvs_delete(key: *const c_char) -> esp_err_t {
+    let key = match key_arg(key) {
+        Ok(key) => key,
+        Err(err) => return err,
+    };
+
+    let mut storage = STORAGE.lock();
+    let Some(storage) = storage.as_mut() else {
+        return ESP_ERR_INVALID_STATE;
+    };
+
+    match storage.remove(&storage_key(key)) {
+        Ok(true) => ESP_OK,
+        Ok(false) => ESP_ERR_NVS_NOT_FOUND,
+        Err(err) => err.code(),
+    }
+}
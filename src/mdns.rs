@@ -17,6 +17,33 @@ use crate::private::cstr::to_cstring_arg;
 use crate::private::cstr::CStr;
 use crate::private::mutex::Mutex;
 
+#[cfg(all(esp_idf_comp_espressif__mdns_enabled, esp_idf_comp_esp_netif_enabled))]
+use crate::{handle::RawHandle, netif::EspNetif};
+
+#[cfg(all(esp_idf_comp_espressif__mdns_enabled, esp_idf_comp_esp_netif_enabled))]
+const NETIF_ENABLE: mdns_event_actions_t = mdns_event_actions_t_MDNS_EVENT_ENABLE_IP4
+    | if cfg!(esp_idf_lwip_ipv6) {
+        mdns_event_actions_t_MDNS_EVENT_ENABLE_IP6
+    } else {
+        0
+    };
+
+#[cfg(all(esp_idf_comp_espressif__mdns_enabled, esp_idf_comp_esp_netif_enabled))]
+const NETIF_ANNOUNCE: mdns_event_actions_t = mdns_event_actions_t_MDNS_EVENT_ANNOUNCE_IP4
+    | if cfg!(esp_idf_lwip_ipv6) {
+        mdns_event_actions_t_MDNS_EVENT_ANNOUNCE_IP6
+    } else {
+        0
+    };
+
+#[cfg(all(esp_idf_comp_espressif__mdns_enabled, esp_idf_comp_esp_netif_enabled))]
+const NETIF_DISABLE: mdns_event_actions_t = mdns_event_actions_t_MDNS_EVENT_DISABLE_IP4
+    | if cfg!(esp_idf_lwip_ipv6) {
+        mdns_event_actions_t_MDNS_EVENT_DISABLE_IP6
+    } else {
+        0
+    };
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Interface {
     STA,
@@ -129,6 +156,21 @@ impl From<mdns_result_t> for QueryResult {
     }
 }
 
+/// A service to be advertised with `EspMdns::register_service`
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Service<'a> {
+    /// The instance name of the service, or `None` to use the instance name of the host
+    pub instance_name: Option<&'a str>,
+    /// The service type, e.g. `_hap`
+    pub service_type: &'a str,
+    /// The protocol, i.e. `_tcp` or `_udp`
+    pub proto: &'a str,
+    pub port: u16,
+    pub txt: &'a [(&'a str, &'a str)],
+    /// Service subtypes, e.g. `_printer`. Only supported with the `espressif/mdns` component
+    pub subtypes: &'a [&'a str],
+}
+
 static TAKEN: Mutex<bool> = Mutex::new(false);
 
 pub struct EspMdns(());
@@ -202,6 +244,179 @@ impl EspMdns {
         })
     }
 
+    /// Register a service, including its TXT records and subtypes
+    pub fn register_service(&mut self, service: &Service) -> Result<(), EspError> {
+        self.add_service(
+            service.instance_name,
+            service.service_type,
+            service.proto,
+            service.port,
+            service.txt,
+        )?;
+
+        if let Err(err) = self.add_service_subtypes(service) {
+            let _ = self.remove_service(service.service_type, service.proto);
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Register multiple services at once
+    ///
+    /// If registering one of the services fails, the services registered so far are removed again.
+    pub fn register_services(&mut self, services: &[Service]) -> Result<(), EspError> {
+        for (index, service) in services.iter().enumerate() {
+            if let Err(err) = self.register_service(service) {
+                for service in &services[..index] {
+                    let _ = self.remove_service(service.service_type, service.proto);
+                }
+
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Register a service, making sure its instance name is not already used on the network
+    ///
+    /// Before registering, the network is probed for a service of the same type with the same instance name
+    /// for at most `timeout`. On conflict, a numeric suffix is appended to the instance name -
+    /// `"Name (2)"`, `"Name (3)"` and so on - until a free one is found.
+    ///
+    /// Returns the instance name with which the service was registered.
+    pub fn register_service_unique(
+        &mut self,
+        service: &Service,
+        timeout: Duration,
+    ) -> Result<String, EspError> {
+        let Some(instance_name) = service.instance_name else {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        };
+
+        let mut results = [QueryResult {
+            instance_name: None,
+            hostname: None,
+            port: 0,
+            txt: Vec::new(),
+            addr: Vec::new(),
+            interface: Interface::STA,
+            ip_protocol: Protocol::V4,
+        }];
+
+        let mut unique_name = instance_name.to_string();
+        let mut suffix = 1;
+
+        while self.query_srv(
+            &unique_name,
+            service.service_type,
+            service.proto,
+            timeout,
+            &mut results,
+        )? > 0
+        {
+            suffix += 1;
+            unique_name = alloc::format!("{} ({})", instance_name, suffix);
+        }
+
+        self.register_service(&Service {
+            instance_name: Some(&unique_name),
+            ..service.clone()
+        })?;
+
+        Ok(unique_name)
+    }
+
+    /// Set the hostname, making sure it is not already used on the network
+    ///
+    /// Before setting it, the network is probed for a host with the same name for at most
+    /// `timeout`. On conflict, `on_conflict` is called with the name in use and a numeric suffix
+    /// is appended to the hostname - `"name-2"`, `"name-3"` and so on - until a free one is found.
+    ///
+    /// Returns the hostname which was set. Call before announcing any other hostname, as the
+    /// device would otherwise answer the probes for its own name.
+    pub fn set_hostname_unique<F>(
+        &mut self,
+        hostname: impl AsRef<str>,
+        timeout: Duration,
+        mut on_conflict: F,
+    ) -> Result<String, EspError>
+    where
+        F: FnMut(&str),
+    {
+        let hostname = hostname.as_ref();
+
+        let mut unique_name = hostname.to_string();
+        let mut suffix = 1;
+
+        while self.is_hostname_taken(&unique_name, timeout)? {
+            on_conflict(&unique_name);
+
+            suffix += 1;
+            unique_name = alloc::format!("{}-{}", hostname, suffix);
+        }
+
+        self.set_hostname(&unique_name)?;
+
+        info!("Set hostname {unique_name}");
+
+        Ok(unique_name)
+    }
+
+    fn is_hostname_taken(&self, hostname: &str, timeout: Duration) -> Result<bool, EspError> {
+        match self.query_a(hostname, timeout) {
+            Ok(_) => return Ok(true),
+            Err(err) if err.code() == ESP_ERR_NOT_FOUND => (),
+            Err(err) => return Err(err),
+        }
+
+        #[cfg(esp_idf_lwip_ipv6)]
+        match self.query_aaaa(hostname, timeout) {
+            Ok(_) => return Ok(true),
+            Err(err) if err.code() == ESP_ERR_NOT_FOUND => (),
+            Err(err) => return Err(err),
+        }
+
+        Ok(false)
+    }
+
+    /// Update the port and TXT records of an already registered service
+    pub fn update_service(&mut self, service: &Service) -> Result<(), EspError> {
+        self.set_service_port(service.service_type, service.proto, service.port)?;
+        self.set_service_txt(service.service_type, service.proto, service.txt)?;
+
+        if let Some(instance_name) = service.instance_name {
+            self.set_service_instance_name(service.service_type, service.proto, instance_name)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(esp_idf_comp_espressif__mdns_enabled)]
+    fn add_service_subtypes(&mut self, service: &Service) -> Result<(), EspError> {
+        for subtype in service.subtypes {
+            self.add_service_subtype(
+                service.instance_name,
+                service.service_type,
+                service.proto,
+                subtype,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(esp_idf_comp_espressif__mdns_enabled))]
+    fn add_service_subtypes(&mut self, service: &Service) -> Result<(), EspError> {
+        if service.subtypes.is_empty() {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+        }
+    }
+
     pub fn set_service_port(
         &mut self,
         service_type: impl AsRef<str>,
@@ -465,6 +680,122 @@ impl EspMdns {
     }
 }
 
+#[cfg(esp_idf_comp_espressif__mdns_enabled)]
+impl EspMdns {
+    /// Return the hostname, which the responder changes by itself on a conflict detected
+    /// after it was set
+    pub fn hostname(&self) -> Result<String, EspError> {
+        let mut hostname = [0 as core::ffi::c_char; 64];
+
+        esp!(unsafe { mdns_hostname_get(hostname.as_mut_ptr()) })?;
+
+        Ok(unsafe { CStr::from_ptr(hostname.as_ptr()) }
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    /// Respond on a network interface other than the default WiFi and Ethernet ones
+    #[cfg(esp_idf_comp_esp_netif_enabled)]
+    pub fn register_netif(&mut self, netif: &EspNetif) -> Result<(), EspError> {
+        esp!(unsafe { mdns_register_netif(netif.handle()) })?;
+
+        self.netif_action(netif, NETIF_ENABLE)?;
+        self.announce(netif)
+    }
+
+    /// Stop responding on a network interface registered with `register_netif`
+    #[cfg(esp_idf_comp_esp_netif_enabled)]
+    pub fn unregister_netif(&mut self, netif: &EspNetif) -> Result<(), EspError> {
+        self.netif_action(netif, NETIF_DISABLE)?;
+
+        esp!(unsafe { mdns_unregister_netif(netif.handle()) })
+    }
+
+    /// Announce the A - and, with IPv6, AAAA - records of a network interface, e.g. after its
+    /// addresses changed
+    ///
+    /// The responder publishes an AAAA record for each interface with an IPv6 address.
+    #[cfg(esp_idf_comp_esp_netif_enabled)]
+    pub fn announce(&mut self, netif: &EspNetif) -> Result<(), EspError> {
+        self.netif_action(netif, NETIF_ANNOUNCE)
+    }
+
+    #[cfg(esp_idf_comp_esp_netif_enabled)]
+    fn netif_action(
+        &mut self,
+        netif: &EspNetif,
+        action: mdns_event_actions_t,
+    ) -> Result<(), EspError> {
+        esp!(unsafe { mdns_netif_action(netif.handle(), action) })
+    }
+
+    /// Add a subtype (e.g. `_printer`) to an already registered service
+    pub fn add_service_subtype(
+        &mut self,
+        instance_name: Option<&str>,
+        service_type: impl AsRef<str>,
+        proto: impl AsRef<str>,
+        subtype: impl AsRef<str>,
+    ) -> Result<(), EspError> {
+        let instance_name = if let Some(instance_name) = instance_name {
+            Some(to_cstring_arg(instance_name)?)
+        } else {
+            None
+        };
+        let service_type = to_cstring_arg(service_type.as_ref())?;
+        let proto = to_cstring_arg(proto.as_ref())?;
+        let subtype = to_cstring_arg(subtype.as_ref())?;
+
+        esp!(unsafe {
+            mdns_service_subtype_add_for_host(
+                instance_name
+                    .as_ref()
+                    .map_or(core::ptr::null(), |x| x.as_ptr()),
+                service_type.as_ptr(),
+                proto.as_ptr(),
+                core::ptr::null(),
+                subtype.as_ptr(),
+            )
+        })
+    }
+
+    /// Return `true` if a service of the provided type is registered
+    pub fn has_service(
+        &self,
+        service_type: impl AsRef<str>,
+        proto: impl AsRef<str>,
+    ) -> Result<bool, EspError> {
+        let service_type = to_cstring_arg(service_type.as_ref())?;
+        let proto = to_cstring_arg(proto.as_ref())?;
+
+        Ok(
+            unsafe {
+                mdns_service_exists(service_type.as_ptr(), proto.as_ptr(), core::ptr::null())
+            },
+        )
+    }
+
+    /// Continuously browse for instances of the provided service type
+    ///
+    /// The callback is invoked with `BrowseEvent::Added` whenever an instance appears
+    /// (or its records change), and with `BrowseEvent::Removed` once it disappears (i.e. its TTL expired).
+    /// Browsing stops once the returned `EspMdnsBrowse` is dropped.
+    ///
+    /// Note that the callback is called from within the mDNS task and must not drop
+    /// any `EspMdnsBrowse` instance, or a deadlock will occur.
+    pub fn browse<F>(
+        &self,
+        service_type: impl AsRef<str>,
+        proto: impl AsRef<str>,
+        callback: F,
+    ) -> Result<EspMdnsBrowse, EspError>
+    where
+        F: FnMut(BrowseEvent) + Send + 'static,
+    {
+        browse::browse(service_type.as_ref(), proto.as_ref(), callback)
+    }
+}
+
 impl Drop for EspMdns {
     fn drop(&mut self) {
         let mut taken = TAKEN.lock();
@@ -508,3 +839,145 @@ fn from_esp_ip6_addr_t(addr: &esp_ip6_addr_t) -> Ipv6Addr {
     }
     Ipv6Addr::from(buf)
 }
+
+#[cfg(esp_idf_comp_espressif__mdns_enabled)]
+pub use browse::{BrowseEvent, EspMdnsBrowse};
+
+#[cfg(esp_idf_comp_espressif__mdns_enabled)]
+mod browse {
+    extern crate alloc;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    use ::log::info;
+
+    use crate::private::cstr::{to_cstring_arg, CStr, CString};
+    use crate::private::mutex::Mutex;
+    use crate::sys::*;
+
+    use super::QueryResult;
+
+    /// An event delivered by `EspMdns::browse`
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub enum BrowseEvent {
+        /// A service instance appeared, or its records were updated
+        Added(QueryResult),
+        /// A service instance disappeared
+        Removed(QueryResult),
+    }
+
+    struct Browse {
+        id: u32,
+        service_type: CString,
+        proto: CString,
+        callback: Box<dyn FnMut(BrowseEvent) + Send + 'static>,
+    }
+
+    // The `mdns_browse_notify_t` callback does not take a user argument, hence
+    // the active browse callbacks are kept in a global registry
+    static BROWSES: Mutex<(u32, Vec<Browse>)> = Mutex::new((0, Vec::new()));
+
+    /// An active mDNS browse, created with `EspMdns::browse`
+    pub struct EspMdnsBrowse(u32);
+
+    pub(super) fn browse<F>(
+        service_type: &str,
+        proto: &str,
+        callback: F,
+    ) -> Result<EspMdnsBrowse, EspError>
+    where
+        F: FnMut(BrowseEvent) + Send + 'static,
+    {
+        let service_type = to_cstring_arg(service_type)?;
+        let proto = to_cstring_arg(proto)?;
+
+        let mut browses = BROWSES.lock();
+
+        let already_browsing = browses
+            .1
+            .iter()
+            .any(|browse| browse.service_type == service_type && browse.proto == proto);
+
+        if !already_browsing {
+            let browse =
+                unsafe { mdns_browse_new(service_type.as_ptr(), proto.as_ptr(), Some(notify)) };
+
+            if browse.is_null() {
+                return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+            }
+        }
+
+        info!("Browsing {:?}.{:?}", service_type, proto);
+
+        let id = browses.0;
+        browses.0 = browses.0.wrapping_add(1);
+
+        browses.1.push(Browse {
+            id,
+            service_type,
+            proto,
+            callback: Box::new(callback),
+        });
+
+        Ok(EspMdnsBrowse(id))
+    }
+
+    impl Drop for EspMdnsBrowse {
+        fn drop(&mut self) {
+            let mut browses = BROWSES.lock();
+
+            if let Some(index) = browses.1.iter().position(|browse| browse.id == self.0) {
+                let browse = browses.1.remove(index);
+
+                let still_browsing = browses.1.iter().any(|other| {
+                    other.service_type == browse.service_type && other.proto == browse.proto
+                });
+
+                if !still_browsing {
+                    // Might fail if mDNS had been freed in the meantime, which is fine
+                    unsafe {
+                        mdns_browse_delete(browse.service_type.as_ptr(), browse.proto.as_ptr());
+                    }
+                }
+
+                info!(
+                    "Stopped browsing {:?}.{:?}",
+                    browse.service_type, browse.proto
+                );
+            }
+        }
+    }
+
+    unsafe extern "C" fn notify(result: *mut mdns_result_t) {
+        let mut browses = BROWSES.lock();
+
+        let mut p = result;
+
+        while !p.is_null() {
+            let raw = *p;
+
+            let service_type = raw.service_type.as_ref().map(|p| CStr::from_ptr(p));
+            let proto = raw.proto.as_ref().map(|p| CStr::from_ptr(p));
+
+            let removed = raw.ttl == 0;
+            let result = QueryResult::from(raw);
+
+            for browse in browses.1.iter_mut().filter(|browse| {
+                service_type
+                    .map(|service_type| browse.service_type.as_c_str() == service_type)
+                    .unwrap_or(true)
+                    && proto
+                        .map(|proto| browse.proto.as_c_str() == proto)
+                        .unwrap_or(true)
+            }) {
+                (browse.callback)(if removed {
+                    BrowseEvent::Removed(result.clone())
+                } else {
+                    BrowseEvent::Added(result.clone())
+                });
+            }
+
+            p = raw.next;
+        }
+    }
+}
@@ -0,0 +1,1086 @@
+//! Modbus RTU and TCP master and slave, with the `esp-modbus` component
+//!
+//! `EspModbusMaster` sends requests to the slaves of an RS-485 bus (RTU) or of the network
+//! (TCP):
+//!
+//! ```ignore
+//! let mut master = EspModbusMaster::new_rtu(
+//!     peripherals.uart1,
+//!     pins.gpio17,
+//!     pins.gpio16,
+//!     Some(pins.gpio18),
+//!     &RtuConfiguration::new(19200),
+//! )?;
+//!
+//! let mut temperatures = [0; 4];
+//! master.read_input_registers(1, 0, &mut temperatures)?;
+//!
+//! master.write_coil(1, 0, true)?;
+//! ```
+//!
+//! `EspModbusSlave` serves register areas - holding and input registers, coils and discrete
+//! inputs - which the application reads and updates, and reports the accesses of the master:
+//!
+//! ```ignore
+//! let mut slave = EspModbusSlave::new_tcp(
+//!     wifi.sta_netif(),
+//!     1,
+//!     &TcpConfiguration::new(),
+//!     &[
+//!         RegisterArea::new(RegisterType::Holding, 0, 16),
+//!         RegisterArea::new(RegisterType::Coil, 0, 8),
+//!     ],
+//! )?;
+//!
+//! loop {
+//!     if let Some(access) = slave.access(Duration::from_secs(1))? {
+//!         info!("{access:?}");
+//!     }
+//!
+//!     slave.set_registers(RegisterType::Holding, 0, &[read_sensor()])?;
+//! }
+//! ```
+//!
+//! The `esp-modbus` component is a singleton: a single master and a single slave may exist at a
+//! time. The RTU transport drives the UART in RS-485 half-duplex mode when the RTS pin is given,
+//! for the transceivers with a driver enable input.
+//!
+//! With the `std` feature, `AsyncEspModbusMaster` offers the requests of the master as futures,
+//! which a dedicated thread executes.
+
+use core::ffi::{c_char, c_void};
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::hal::delay::TickType;
+use crate::hal::gpio::{InputPin, OutputPin};
+use crate::hal::peripheral::Peripheral;
+use crate::hal::uart::Uart;
+use crate::handle::RawHandle;
+use crate::ipv4::Ipv4Addr;
+use crate::netif::EspNetif;
+use crate::sys::*;
+
+#[cfg(feature = "std")]
+pub use asynch::*;
+
+static MASTER_TAKEN: AtomicBool = AtomicBool::new(false);
+static SLAVE_TAKEN: AtomicBool = AtomicBool::new(false);
+
+const READ_COILS: u8 = 0x01;
+const READ_DISCRETE_INPUTS: u8 = 0x02;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_SINGLE_COIL: u8 = 0x05;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_COILS: u8 = 0x0f;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+// The limits of the protocol, per request
+const MAX_READ_BITS: usize = 2000;
+const MAX_READ_REGISTERS: usize = 125;
+const MAX_WRITE_BITS: usize = 1968;
+const MAX_WRITE_REGISTERS: usize = 123;
+
+/// The parity of the serial line
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl From<Parity> for uart_parity_t {
+    fn from(parity: Parity) -> Self {
+        match parity {
+            Parity::None => uart_parity_t_UART_PARITY_DISABLE,
+            Parity::Even => uart_parity_t_UART_PARITY_EVEN,
+            Parity::Odd => uart_parity_t_UART_PARITY_ODD,
+        }
+    }
+}
+
+/// The configuration of the RTU (serial) transport
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RtuConfiguration {
+    pub baudrate: u32,
+    pub parity: Parity,
+    /// Whether the frames are ASCII rather than binary, if enabled in the component
+    pub ascii: bool,
+}
+
+impl RtuConfiguration {
+    pub const fn new(baudrate: u32) -> Self {
+        Self {
+            baudrate,
+            parity: Parity::None,
+            ascii: false,
+        }
+    }
+}
+
+impl Default for RtuConfiguration {
+    fn default() -> Self {
+        Self::new(9600)
+    }
+}
+
+/// The configuration of the TCP transport
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TcpConfiguration {
+    pub port: u16,
+}
+
+impl TcpConfiguration {
+    pub const fn new() -> Self {
+        Self { port: 502 }
+    }
+}
+
+impl Default for TcpConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn rtu_info(address: u8, port: uart_port_t, conf: &RtuConfiguration) -> mb_communication_info_t {
+    let mut info: mb_communication_info_t = Default::default();
+
+    info.__bindgen_anon_1.mode = if conf.ascii {
+        mb_mode_type_t_MB_MODE_ASCII
+    } else {
+        mb_mode_type_t_MB_MODE_RTU
+    };
+    info.__bindgen_anon_1.slave_addr = address;
+    info.__bindgen_anon_1.port = port;
+    info.__bindgen_anon_1.baudrate = conf.baudrate;
+    info.__bindgen_anon_1.parity = conf.parity.into();
+
+    info
+}
+
+fn tcp_info(
+    address: u8,
+    netif: &EspNetif,
+    ip_addr: *mut c_void,
+    conf: &TcpConfiguration,
+) -> mb_communication_info_t {
+    let mut info: mb_communication_info_t = Default::default();
+
+    info.__bindgen_anon_2.ip_mode = mb_mode_type_t_MB_MODE_TCP;
+    info.__bindgen_anon_2.slave_uid = address;
+    info.__bindgen_anon_2.ip_port = conf.port;
+    info.__bindgen_anon_2.ip_addr_type = mb_tcp_addr_type_t_MB_IPV4;
+    info.__bindgen_anon_2.ip_addr = ip_addr;
+    info.__bindgen_anon_2.ip_netif_ptr = netif.handle() as *mut c_void;
+
+    info
+}
+
+fn set_rtu_pins(port: uart_port_t, tx: i32, rx: i32, rts: Option<i32>) -> Result<(), EspError> {
+    esp!(unsafe {
+        uart_set_pin(
+            port,
+            tx,
+            rx,
+            rts.unwrap_or(UART_PIN_NO_CHANGE),
+            UART_PIN_NO_CHANGE,
+        )
+    })
+}
+
+fn set_rtu_mode(port: uart_port_t, rts: Option<i32>) -> Result<(), EspError> {
+    if rts.is_some() {
+        esp!(unsafe { uart_set_mode(port, uart_mode_t_UART_MODE_RS485_HALF_DUPLEX) })?;
+    }
+
+    Ok(())
+}
+
+fn take(taken: &AtomicBool) -> Result<(), EspError> {
+    if taken.swap(true, Ordering::SeqCst) {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+    }
+
+    Ok(())
+}
+
+/// A Modbus master
+pub struct EspModbusMaster<'d> {
+    // The NULL-terminated table of the addresses of the TCP slaves
+    _ip_table: Option<(Vec<CString>, Vec<*const c_char>)>,
+    _p: PhantomData<&'d mut ()>,
+}
+
+impl<'d> EspModbusMaster<'d> {
+    /// A master on a serial line; with `rts`, the UART is in RS-485 half-duplex mode
+    pub fn new_rtu<UART: Uart>(
+        _uart: impl Peripheral<P = UART> + 'd,
+        tx: impl Peripheral<P = impl OutputPin> + 'd,
+        rx: impl Peripheral<P = impl InputPin> + 'd,
+        rts: Option<impl Peripheral<P = impl OutputPin> + 'd>,
+        conf: &RtuConfiguration,
+    ) -> Result<Self, EspError> {
+        crate::hal::into_ref!(tx, rx);
+
+        let rts = rts.map(|rts| rts.into_ref().pin());
+
+        take(&MASTER_TAKEN)?;
+
+        let mut handler = ptr::null_mut();
+        esp!(unsafe { mbc_master_init(mb_port_type_t_MB_PORT_SERIAL_MASTER, &mut handler) })
+            .inspect_err(|_| MASTER_TAKEN.store(false, Ordering::SeqCst))?;
+
+        // Destroys the stack if the setup fails
+        let this = Self {
+            _ip_table: None,
+            _p: PhantomData,
+        };
+
+        let mut info = rtu_info(0, UART::port(), conf);
+
+        esp!(unsafe { mbc_master_setup(&mut info as *mut _ as *mut c_void) })?;
+        set_rtu_pins(UART::port(), tx.pin(), rx.pin(), rts)?;
+        esp!(unsafe { mbc_master_start() })?;
+        set_rtu_mode(UART::port(), rts)?;
+
+        info!("Modbus RTU master started, {} bauds", conf.baudrate);
+
+        Ok(this)
+    }
+
+    /// A master on the network; the slave address `n` is the `n`-th address of `slaves`,
+    /// starting from 1
+    pub fn new_tcp(
+        netif: &'d EspNetif,
+        slaves: &[Ipv4Addr],
+        conf: &TcpConfiguration,
+    ) -> Result<Self, EspError> {
+        if slaves.is_empty() || slaves.len() > 247 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let addresses = slaves
+            .iter()
+            .map(|slave| CString::new(format!("{slave}")).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut table = addresses
+            .iter()
+            .map(|address| address.as_ptr())
+            .collect::<Vec<_>>();
+        table.push(ptr::null());
+
+        take(&MASTER_TAKEN)?;
+
+        let mut handler = ptr::null_mut();
+        esp!(unsafe { mbc_master_init_tcp(&mut handler) })
+            .inspect_err(|_| MASTER_TAKEN.store(false, Ordering::SeqCst))?;
+
+        let mut info = tcp_info(0, netif, table.as_mut_ptr() as *mut c_void, conf);
+
+        // The component keeps the table; destroys the stack if the setup fails
+        let this = Self {
+            _ip_table: Some((addresses, table)),
+            _p: PhantomData,
+        };
+
+        esp!(unsafe { mbc_master_setup(&mut info as *mut _ as *mut c_void) })?;
+        esp!(unsafe { mbc_master_start() })?;
+
+        info!("Modbus TCP master started, {} slaves", slaves.len());
+
+        Ok(this)
+    }
+
+    pub fn read_coils(
+        &mut self,
+        slave: u8,
+        address: u16,
+        coils: &mut [bool],
+    ) -> Result<(), EspError> {
+        self.read_bits(slave, READ_COILS, address, coils)
+    }
+
+    pub fn read_discrete_inputs(
+        &mut self,
+        slave: u8,
+        address: u16,
+        inputs: &mut [bool],
+    ) -> Result<(), EspError> {
+        self.read_bits(slave, READ_DISCRETE_INPUTS, address, inputs)
+    }
+
+    pub fn read_holding_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        registers: &mut [u16],
+    ) -> Result<(), EspError> {
+        self.read_registers(slave, READ_HOLDING_REGISTERS, address, registers)
+    }
+
+    pub fn read_input_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        registers: &mut [u16],
+    ) -> Result<(), EspError> {
+        self.read_registers(slave, READ_INPUT_REGISTERS, address, registers)
+    }
+
+    pub fn write_coil(&mut self, slave: u8, address: u16, value: bool) -> Result<(), EspError> {
+        let mut data = [if value { 0xff00_u16 } else { 0 }];
+
+        self.request(slave, WRITE_SINGLE_COIL, address, 1, data.as_mut_ptr() as _)
+    }
+
+    pub fn write_coils(&mut self, slave: u8, address: u16, coils: &[bool]) -> Result<(), EspError> {
+        if coils.is_empty() || coils.len() > MAX_WRITE_BITS {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut data = pack_bits(coils);
+
+        self.request(
+            slave,
+            WRITE_MULTIPLE_COILS,
+            address,
+            coils.len() as _,
+            data.as_mut_ptr() as _,
+        )
+    }
+
+    pub fn write_register(&mut self, slave: u8, address: u16, value: u16) -> Result<(), EspError> {
+        let mut data = [value];
+
+        self.request(
+            slave,
+            WRITE_SINGLE_REGISTER,
+            address,
+            1,
+            data.as_mut_ptr() as _,
+        )
+    }
+
+    pub fn write_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        registers: &[u16],
+    ) -> Result<(), EspError> {
+        if registers.is_empty() || registers.len() > MAX_WRITE_REGISTERS {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut data = registers.to_vec();
+
+        self.request(
+            slave,
+            WRITE_MULTIPLE_REGISTERS,
+            address,
+            registers.len() as _,
+            data.as_mut_ptr() as _,
+        )
+    }
+
+    fn read_bits(
+        &mut self,
+        slave: u8,
+        command: u8,
+        address: u16,
+        bits: &mut [bool],
+    ) -> Result<(), EspError> {
+        if bits.is_empty() || bits.len() > MAX_READ_BITS {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut data = alloc::vec![0_u8; bits.len().div_ceil(8)];
+
+        self.request(
+            slave,
+            command,
+            address,
+            bits.len() as _,
+            data.as_mut_ptr() as _,
+        )?;
+
+        unpack_bits(&data, bits);
+
+        Ok(())
+    }
+
+    fn read_registers(
+        &mut self,
+        slave: u8,
+        command: u8,
+        address: u16,
+        registers: &mut [u16],
+    ) -> Result<(), EspError> {
+        if registers.is_empty() || registers.len() > MAX_READ_REGISTERS {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        self.request(
+            slave,
+            command,
+            address,
+            registers.len() as _,
+            registers.as_mut_ptr() as _,
+        )
+    }
+
+    fn request(
+        &mut self,
+        slave: u8,
+        command: u8,
+        address: u16,
+        count: u16,
+        data: *mut c_void,
+    ) -> Result<(), EspError> {
+        let mut request = mb_param_request_t {
+            slave_addr: slave,
+            command,
+            reg_start: address,
+            reg_size: count,
+        };
+
+        esp!(unsafe { mbc_master_send_request(&mut request, data) })
+    }
+}
+
+impl Drop for EspModbusMaster<'_> {
+    fn drop(&mut self) {
+        esp!(unsafe { mbc_master_destroy() }).unwrap();
+
+        MASTER_TAKEN.store(false, Ordering::SeqCst);
+
+        info!("Modbus master stopped");
+    }
+}
+
+unsafe impl Send for EspModbusMaster<'_> {}
+
+impl Debug for EspModbusMaster<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspModbusMaster").finish_non_exhaustive()
+    }
+}
+
+/// The type of a register area
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RegisterType {
+    /// 16-bit registers, read and written by the master
+    Holding,
+    /// 16-bit registers, read by the master
+    Input,
+    /// Bits, read and written by the master
+    Coil,
+    /// Bits, read by the master
+    Discrete,
+}
+
+impl RegisterType {
+    fn is_bits(&self) -> bool {
+        matches!(self, Self::Coil | Self::Discrete)
+    }
+}
+
+impl From<RegisterType> for mb_param_type_t {
+    fn from(kind: RegisterType) -> Self {
+        match kind {
+            RegisterType::Holding => mb_param_type_t_MB_PARAM_HOLDING,
+            RegisterType::Input => mb_param_type_t_MB_PARAM_INPUT,
+            RegisterType::Coil => mb_param_type_t_MB_PARAM_COIL,
+            RegisterType::Discrete => mb_param_type_t_MB_PARAM_DISCRETE,
+        }
+    }
+}
+
+/// A register area served by the slave
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RegisterArea {
+    pub kind: RegisterType,
+    /// The address of the first register or bit
+    pub start: u16,
+    /// The number of registers or bits
+    pub count: u16,
+}
+
+impl RegisterArea {
+    pub const fn new(kind: RegisterType, start: u16, count: u16) -> Self {
+        Self { kind, start, count }
+    }
+
+    fn contains(&self, kind: RegisterType, address: u16, count: usize) -> bool {
+        self.kind == kind
+            && address >= self.start
+            && address as usize + count <= self.start as usize + self.count as usize
+    }
+}
+
+/// The kind of an access of the master
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    HoldingRead,
+    HoldingWrite,
+    InputRead,
+    CoilsRead,
+    CoilsWrite,
+    DiscreteRead,
+}
+
+/// An access of the master to a register area
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SlaveAccess {
+    pub kind: AccessKind,
+    /// The address of the first register or bit accessed
+    pub address: u16,
+    /// The number of registers or bits accessed
+    pub count: usize,
+    /// The time of the access, in microseconds since the start of the stack
+    pub timestamp: u32,
+}
+
+/// A Modbus slave
+pub struct EspModbusSlave<'d> {
+    // The storage of the areas; register areas hold one `u16` per register, bit areas one bit
+    // per bit, packed LSB first
+    areas: Vec<(RegisterArea, Box<[u16]>)>,
+    _p: PhantomData<&'d mut ()>,
+}
+
+impl<'d> EspModbusSlave<'d> {
+    /// A slave on a serial line, at `address`; with `rts`, the UART is in RS-485 half-duplex
+    /// mode
+    pub fn new_rtu<UART: Uart>(
+        _uart: impl Peripheral<P = UART> + 'd,
+        tx: impl Peripheral<P = impl OutputPin> + 'd,
+        rx: impl Peripheral<P = impl InputPin> + 'd,
+        rts: Option<impl Peripheral<P = impl OutputPin> + 'd>,
+        address: u8,
+        conf: &RtuConfiguration,
+        areas: &[RegisterArea],
+    ) -> Result<Self, EspError> {
+        crate::hal::into_ref!(tx, rx);
+
+        let rts = rts.map(|rts| rts.into_ref().pin());
+
+        let this = Self::init(mb_port_type_t_MB_PORT_SERIAL_SLAVE, areas)?;
+
+        let mut info = rtu_info(address, UART::port(), conf);
+
+        esp!(unsafe { mbc_slave_setup(&mut info as *mut _ as *mut c_void) })?;
+        this.set_descriptors()?;
+        set_rtu_pins(UART::port(), tx.pin(), rx.pin(), rts)?;
+        esp!(unsafe { mbc_slave_start() })?;
+        set_rtu_mode(UART::port(), rts)?;
+
+        info!(
+            "Modbus RTU slave {address} started, {} bauds",
+            conf.baudrate
+        );
+
+        Ok(this)
+    }
+
+    /// A slave on the network, with unit identifier `address`
+    pub fn new_tcp(
+        netif: &'d EspNetif,
+        address: u8,
+        conf: &TcpConfiguration,
+        areas: &[RegisterArea],
+    ) -> Result<Self, EspError> {
+        let this = Self::init(mb_port_type_t_MB_PORT_TCP_SLAVE, areas)?;
+
+        let mut info = tcp_info(address, netif, ptr::null_mut(), conf);
+
+        esp!(unsafe { mbc_slave_setup(&mut info as *mut _ as *mut c_void) })?;
+        this.set_descriptors()?;
+        esp!(unsafe { mbc_slave_start() })?;
+
+        info!("Modbus TCP slave {address} started on port {}", conf.port);
+
+        Ok(this)
+    }
+
+    /// The values of the registers of a holding or input area, from `address`
+    pub fn get_registers(
+        &self,
+        kind: RegisterType,
+        address: u16,
+        values: &mut [u16],
+    ) -> Result<(), EspError> {
+        let (area, storage) = self.area(kind, address, values.len(), false)?;
+        let offset = (address - area.start) as usize;
+
+        for (index, value) in values.iter_mut().enumerate() {
+            // The stack accesses the storage concurrently
+            *value = unsafe { ptr::read_volatile(&storage[offset + index]) };
+        }
+
+        Ok(())
+    }
+
+    /// Update the registers of a holding or input area, from `address`
+    pub fn set_registers(
+        &mut self,
+        kind: RegisterType,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), EspError> {
+        let (area, storage) = self.area_mut(kind, address, values.len(), false)?;
+        let offset = (address - area.start) as usize;
+
+        for (index, value) in values.iter().enumerate() {
+            unsafe { ptr::write_volatile(&mut storage[offset + index], *value) };
+        }
+
+        Ok(())
+    }
+
+    /// The values of the bits of a coil or discrete area, from `address`
+    pub fn get_bits(
+        &self,
+        kind: RegisterType,
+        address: u16,
+        values: &mut [bool],
+    ) -> Result<(), EspError> {
+        let (area, storage) = self.area(kind, address, values.len(), true)?;
+        let offset = (address - area.start) as usize;
+
+        for (index, value) in values.iter_mut().enumerate() {
+            let bit = offset + index;
+            let word = unsafe { ptr::read_volatile(&storage[bit / 16]) };
+
+            *value = word.to_le_bytes()[bit % 16 / 8] & (1 << (bit % 8)) != 0;
+        }
+
+        Ok(())
+    }
+
+    /// Update the bits of a coil or discrete area, from `address`
+    pub fn set_bits(
+        &mut self,
+        kind: RegisterType,
+        address: u16,
+        values: &[bool],
+    ) -> Result<(), EspError> {
+        let (area, storage) = self.area_mut(kind, address, values.len(), true)?;
+        let offset = (address - area.start) as usize;
+
+        for (index, value) in values.iter().enumerate() {
+            let bit = offset + index;
+            let word = &mut storage[bit / 16];
+
+            // The bits are packed in bytes, whatever the word they are in
+            let mut bytes = unsafe { ptr::read_volatile(word) }.to_le_bytes();
+
+            if *value {
+                bytes[bit % 16 / 8] |= 1 << (bit % 8);
+            } else {
+                bytes[bit % 16 / 8] &= !(1 << (bit % 8));
+            }
+
+            unsafe { ptr::write_volatile(word, u16::from_le_bytes(bytes)) };
+        }
+
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for an access of the master
+    pub fn access(&self, timeout: Duration) -> Result<Option<SlaveAccess>, EspError> {
+        let mut info: mb_param_info_t = Default::default();
+
+        let timeout: TickType = timeout.into();
+
+        match esp!(unsafe { mbc_slave_get_param_info(&mut info, timeout.0) }) {
+            Ok(()) => (),
+            Err(err) if err.code() == ESP_ERR_TIMEOUT => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        #[allow(non_upper_case_globals)]
+        let kind = match info.type_ {
+            mb_event_group_t_MB_EVENT_HOLDING_REG_RD => AccessKind::HoldingRead,
+            mb_event_group_t_MB_EVENT_HOLDING_REG_WR => AccessKind::HoldingWrite,
+            mb_event_group_t_MB_EVENT_INPUT_REG_RD => AccessKind::InputRead,
+            mb_event_group_t_MB_EVENT_COILS_RD => AccessKind::CoilsRead,
+            mb_event_group_t_MB_EVENT_COILS_WR => AccessKind::CoilsWrite,
+            mb_event_group_t_MB_EVENT_DISCRETE_RD => AccessKind::DiscreteRead,
+            _ => return Ok(None),
+        };
+
+        Ok(Some(SlaveAccess {
+            kind,
+            address: info.mb_offset,
+            count: info.size as _,
+            timestamp: info.time_stamp,
+        }))
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = &RegisterArea> {
+        self.areas.iter().map(|(area, _)| area)
+    }
+
+    fn init(port_type: mb_port_type_t, areas: &[RegisterArea]) -> Result<Self, EspError> {
+        if areas.is_empty() || areas.iter().any(|area| area.count == 0) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        take(&SLAVE_TAKEN)?;
+
+        let mut handler = ptr::null_mut();
+
+        if port_type == mb_port_type_t_MB_PORT_TCP_SLAVE {
+            esp!(unsafe { mbc_slave_init_tcp(&mut handler) })
+        } else {
+            esp!(unsafe { mbc_slave_init(port_type, &mut handler) })
+        }
+        .inspect_err(|_| SLAVE_TAKEN.store(false, Ordering::SeqCst))?;
+
+        let areas = areas
+            .iter()
+            .map(|area| {
+                let words = if area.kind.is_bits() {
+                    (area.count as usize).div_ceil(16)
+                } else {
+                    area.count as usize
+                };
+
+                (*area, alloc::vec![0_u16; words].into_boxed_slice())
+            })
+            .collect();
+
+        // Destroys the stack if the setup fails
+        Ok(Self {
+            areas,
+            _p: PhantomData,
+        })
+    }
+
+    fn set_descriptors(&self) -> Result<(), EspError> {
+        for (area, storage) in &self.areas {
+            let size = if area.kind.is_bits() {
+                (area.count as usize).div_ceil(8)
+            } else {
+                area.count as usize * 2
+            };
+
+            esp!(unsafe {
+                mbc_slave_set_descriptor(mb_register_area_descriptor_t {
+                    start_offset: area.start,
+                    type_: area.kind.into(),
+                    address: storage.as_ptr() as *mut c_void,
+                    size: size as _,
+                })
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn area(
+        &self,
+        kind: RegisterType,
+        address: u16,
+        count: usize,
+        bits: bool,
+    ) -> Result<&(RegisterArea, Box<[u16]>), EspError> {
+        if kind.is_bits() != bits {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        self.areas
+            .iter()
+            .find(|(area, _)| area.contains(kind, address, count))
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+    }
+
+    fn area_mut(
+        &mut self,
+        kind: RegisterType,
+        address: u16,
+        count: usize,
+        bits: bool,
+    ) -> Result<(RegisterArea, &mut [u16]), EspError> {
+        if kind.is_bits() != bits {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        self.areas
+            .iter_mut()
+            .find(|(area, _)| area.contains(kind, address, count))
+            .map(|(area, storage)| (*area, &mut storage[..]))
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())
+    }
+}
+
+impl Drop for EspModbusSlave<'_> {
+    fn drop(&mut self) {
+        esp!(unsafe { mbc_slave_destroy() }).unwrap();
+
+        SLAVE_TAKEN.store(false, Ordering::SeqCst);
+
+        info!("Modbus slave stopped");
+    }
+}
+
+unsafe impl Send for EspModbusSlave<'_> {}
+
+impl Debug for EspModbusSlave<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspModbusSlave")
+            .field("areas", &self.areas().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+// The bits of the requests are packed in bytes, LSB first
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut data = alloc::vec![0_u8; bits.len().div_ceil(8)];
+
+    for (index, _) in bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        data[index / 8] |= 1 << (index % 8);
+    }
+
+    data
+}
+
+fn unpack_bits(data: &[u8], bits: &mut [bool]) {
+    for (index, bit) in bits.iter_mut().enumerate() {
+        *bit = data[index / 8] & (1 << (index % 8)) != 0;
+    }
+}
+
+#[cfg(feature = "std")]
+mod asynch {
+    use core::future::Future;
+    use core::num::NonZeroU32;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+
+    extern crate alloc;
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+
+    use crate::hal::task::asynch::Notification;
+    use crate::private::mutex::Mutex;
+    use crate::sys::*;
+
+    use super::EspModbusMaster;
+
+    type Job = Box<dyn FnOnce(&mut EspModbusMaster<'static>) + Send + 'static>;
+
+    struct Shared<R> {
+        result: Mutex<Option<R>>,
+        notification: Notification,
+    }
+
+    /// A request of an `AsyncEspModbusMaster`, resolving to its result
+    pub struct ModbusRequest<R>(Arc<Shared<R>>);
+
+    impl<R> Future for ModbusRequest<R> {
+        type Output = R;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if let Some(result) = self.0.result.lock().take() {
+                return Poll::Ready(result);
+            }
+
+            if self.0.notification.poll_wait(cx).is_ready() {
+                if let Some(result) = self.0.result.lock().take() {
+                    return Poll::Ready(result);
+                }
+            }
+
+            Poll::Pending
+        }
+    }
+
+    /// A Modbus master, the requests of which are executed in order by a dedicated thread
+    pub struct AsyncEspModbusMaster {
+        jobs: Option<mpsc::Sender<Job>>,
+        worker: Option<JoinHandle<()>>,
+    }
+
+    impl AsyncEspModbusMaster {
+        pub fn new(mut master: EspModbusMaster<'static>) -> Result<Self, EspError> {
+            let (jobs, receiver) = mpsc::channel::<Job>();
+
+            let worker = thread::Builder::new()
+                .name("modbus".into())
+                .stack_size(4096)
+                .spawn(move || {
+                    for job in receiver {
+                        job(&mut master);
+                    }
+                })
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+            Ok(Self {
+                jobs: Some(jobs),
+                worker: Some(worker),
+            })
+        }
+
+        pub async fn read_coils(
+            &self,
+            slave: u8,
+            address: u16,
+            count: usize,
+        ) -> Result<Vec<bool>, EspError> {
+            self.call(move |master| {
+                let mut coils = alloc::vec![false; count];
+                master.read_coils(slave, address, &mut coils).map(|_| coils)
+            })
+            .await
+        }
+
+        pub async fn read_discrete_inputs(
+            &self,
+            slave: u8,
+            address: u16,
+            count: usize,
+        ) -> Result<Vec<bool>, EspError> {
+            self.call(move |master| {
+                let mut inputs = alloc::vec![false; count];
+                master
+                    .read_discrete_inputs(slave, address, &mut inputs)
+                    .map(|_| inputs)
+            })
+            .await
+        }
+
+        pub async fn read_holding_registers(
+            &self,
+            slave: u8,
+            address: u16,
+            count: usize,
+        ) -> Result<Vec<u16>, EspError> {
+            self.call(move |master| {
+                let mut registers = alloc::vec![0; count];
+                master
+                    .read_holding_registers(slave, address, &mut registers)
+                    .map(|_| registers)
+            })
+            .await
+        }
+
+        pub async fn read_input_registers(
+            &self,
+            slave: u8,
+            address: u16,
+            count: usize,
+        ) -> Result<Vec<u16>, EspError> {
+            self.call(move |master| {
+                let mut registers = alloc::vec![0; count];
+                master
+                    .read_input_registers(slave, address, &mut registers)
+                    .map(|_| registers)
+            })
+            .await
+        }
+
+        pub async fn write_coil(
+            &self,
+            slave: u8,
+            address: u16,
+            value: bool,
+        ) -> Result<(), EspError> {
+            self.call(move |master| master.write_coil(slave, address, value))
+                .await
+        }
+
+        pub async fn write_coils(
+            &self,
+            slave: u8,
+            address: u16,
+            coils: Vec<bool>,
+        ) -> Result<(), EspError> {
+            self.call(move |master| master.write_coils(slave, address, &coils))
+                .await
+        }
+
+        pub async fn write_register(
+            &self,
+            slave: u8,
+            address: u16,
+            value: u16,
+        ) -> Result<(), EspError> {
+            self.call(move |master| master.write_register(slave, address, value))
+                .await
+        }
+
+        pub async fn write_registers(
+            &self,
+            slave: u8,
+            address: u16,
+            registers: Vec<u16>,
+        ) -> Result<(), EspError> {
+            self.call(move |master| master.write_registers(slave, address, &registers))
+                .await
+        }
+
+        /// Run `f` with the master, on the thread of the requests
+        pub fn call<F, R>(&self, f: F) -> ModbusRequest<Result<R, EspError>>
+        where
+            F: FnOnce(&mut EspModbusMaster<'static>) -> Result<R, EspError> + Send + 'static,
+            R: Send + 'static,
+        {
+            let shared = Arc::new(Shared {
+                result: Mutex::new(None),
+                notification: Notification::new(),
+            });
+
+            let job: Job = Box::new({
+                let shared = shared.clone();
+
+                move |master| {
+                    *shared.result.lock() = Some(f(master));
+                    shared.notification.notify(NonZeroU32::new(1).unwrap());
+                }
+            });
+
+            if self.jobs.as_ref().unwrap().send(job).is_err() {
+                *shared.result.lock() =
+                    Some(Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()));
+            }
+
+            ModbusRequest(shared)
+        }
+    }
+
+    impl Drop for AsyncEspModbusMaster {
+        fn drop(&mut self) {
+            // Closing the channel stops the thread, which drops the master
+            self.jobs = None;
+
+            if let Some(worker) = self.worker.take() {
+                let _ = worker.join();
+            }
+        }
+    }
+
+    impl core::fmt::Debug for AsyncEspModbusMaster {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("AsyncEspModbusMaster")
+                .finish_non_exhaustive()
+        }
+    }
+}
@@ -2,4 +2,9 @@
 //!
 //! MQTT is a lightweight publish/subscribe messaging protocol.
 
+pub mod aws;
+#[cfg(esp_idf_comp_mbedtls_enabled)]
+pub mod azure;
 pub mod client;
+#[cfg(all(feature = "json", esp_idf_comp_nvs_flash_enabled))]
+pub mod shadow;
@@ -0,0 +1,191 @@
+//! AWS IoT Core connection profile
+//!
+//! `AwsIot` produces the MQTT client configuration expected by AWS IoT Core - mutual TLS with
+//! the certificate of the thing, the thing name as the client ID, and optionally ALPN
+//! `x-amzn-mqtt-ca` on port 443, for the networks which only let HTTPS through - and the names
+//! of the reserved topics of the thing:
+//!
+//! ```ignore
+//! let aws = AwsIot::new(&AwsIotConfiguration::new(
+//!     "a1b2c3d4e5f6g7-ats.iot.eu-west-1.amazonaws.com",
+//!     "my-thing",
+//!     X509::pem_until_nul(include_bytes!("certificate.pem.crt")),
+//!     X509::pem_until_nul(include_bytes!("private.pem.key")),
+//! ))?;
+//!
+//! let (mut client, mut connection) = aws.connect()?;
+//!
+//! client.subscribe(&aws.shadow_topic(None, "update/delta"), QoS::AtLeastOnce)?;
+//! ```
+//!
+//! The server name indication of the TLS handshake - which AWS IoT requires - is the host name
+//! of the URI, so `endpoint` must be the host name of the endpoint, not its address.
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+
+use crate::sys::*;
+use crate::tls::X509;
+
+use super::client::{EspMqttClient, EspMqttConnection, EspMqttEvent, MqttClientConfiguration};
+
+/// The ALPN protocol of MQTT over port 443
+pub const ALPN_PROTOCOL: &str = "x-amzn-mqtt-ca";
+
+const ALPN_PROTOCOLS: &[&str] = &[ALPN_PROTOCOL];
+
+/// The maximum length of a thing name
+const MAX_THING_NAME_LEN: usize = 128;
+
+/// How to reach the endpoint
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AwsIotPort {
+    /// MQTT over TLS, on port 8883
+    Mqtts,
+    /// MQTT over TLS with ALPN `x-amzn-mqtt-ca`, on port 443
+    Alpn,
+}
+
+/// The configuration of the connection to AWS IoT Core
+#[derive(Copy, Clone, Debug)]
+pub struct AwsIotConfiguration<'a> {
+    /// The device data endpoint of the account, e.g.
+    /// `a1b2c3d4e5f6g7-ats.iot.eu-west-1.amazonaws.com`
+    pub endpoint: &'a str,
+    /// The name of the thing, used as the client ID
+    pub thing_name: &'a str,
+    pub client_certificate: X509<'static>,
+    pub private_key: X509<'static>,
+    /// The certificate of the root CA of the endpoint, e.g. Amazon Root CA 1; when `None`, the
+    /// certificate bundle is used
+    pub server_certificate: Option<X509<'static>>,
+    pub port: AwsIotPort,
+    /// At most 1200 seconds, as enforced by AWS IoT
+    pub keep_alive_interval: Duration,
+    pub buffer_size: usize,
+}
+
+impl<'a> AwsIotConfiguration<'a> {
+    pub const fn new(
+        endpoint: &'a str,
+        thing_name: &'a str,
+        client_certificate: X509<'static>,
+        private_key: X509<'static>,
+    ) -> Self {
+        Self {
+            endpoint,
+            thing_name,
+            client_certificate,
+            private_key,
+            server_certificate: None,
+            port: AwsIotPort::Mqtts,
+            keep_alive_interval: Duration::from_secs(60),
+            buffer_size: 2048,
+        }
+    }
+}
+
+/// The connection profile of a thing of AWS IoT Core
+#[derive(Debug)]
+pub struct AwsIot<'a> {
+    conf: AwsIotConfiguration<'a>,
+}
+
+impl<'a> AwsIot<'a> {
+    pub fn new(conf: &AwsIotConfiguration<'a>) -> Result<Self, EspError> {
+        let valid_name = |name: &str| {
+            !name.is_empty()
+                && name.len() <= MAX_THING_NAME_LEN
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '-'))
+        };
+
+        if !valid_name(conf.thing_name)
+            || conf.endpoint.is_empty()
+            || conf.keep_alive_interval > Duration::from_secs(1200)
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        #[cfg(not(esp_idf_mbedtls_certificate_bundle))]
+        if conf.server_certificate.is_none() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(Self { conf: *conf })
+    }
+
+    pub fn thing_name(&self) -> &str {
+        self.conf.thing_name
+    }
+
+    /// The URI of the endpoint
+    pub fn url(&self) -> String {
+        let port = match self.conf.port {
+            AwsIotPort::Mqtts => 8883,
+            AwsIotPort::Alpn => 443,
+        };
+
+        format!("mqtts://{}:{port}", self.conf.endpoint)
+    }
+
+    /// The configuration of the MQTT client
+    pub fn mqtt_configuration(&self) -> MqttClientConfiguration<'_> {
+        MqttClientConfiguration {
+            client_id: Some(self.conf.thing_name),
+            keep_alive_interval: Some(self.conf.keep_alive_interval),
+            buffer_size: self.conf.buffer_size,
+            out_buffer_size: self.conf.buffer_size,
+            client_certificate: Some(self.conf.client_certificate),
+            private_key: Some(self.conf.private_key),
+            server_certificate: self.conf.server_certificate,
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            crt_bundle_attach: if self.conf.server_certificate.is_none() {
+                Some(esp_crt_bundle_attach)
+            } else {
+                None
+            },
+            alpn_protos: match self.conf.port {
+                AwsIotPort::Mqtts => &[],
+                AwsIotPort::Alpn => ALPN_PROTOCOLS,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Connect to the endpoint
+    pub fn connect(&self) -> Result<(EspMqttClient<'static>, EspMqttConnection), EspError> {
+        EspMqttClient::new(&self.url(), &self.mqtt_configuration())
+    }
+
+    /// Connect to the endpoint, with a callback for the events
+    pub fn connect_cb<F>(&self, callback: F) -> Result<EspMqttClient<'static>, EspError>
+    where
+        F: for<'b> FnMut(EspMqttEvent<'b>) + Send + 'static,
+    {
+        EspMqttClient::new_cb(&self.url(), &self.mqtt_configuration(), callback)
+    }
+
+    /// A reserved topic of the thing, i.e. `$aws/things/<thing name>/<suffix>`
+    pub fn topic(&self, suffix: &str) -> String {
+        format!("$aws/things/{}/{suffix}", self.conf.thing_name)
+    }
+
+    /// A topic of the classic shadow (with `None`) or of a named shadow of the thing, e.g.
+    /// `shadow_topic(None, "update/delta")`
+    pub fn shadow_topic(&self, shadow: Option<&str>, operation: &str) -> String {
+        match shadow {
+            Some(name) => self.topic(&format!("shadow/name/{name}/{operation}")),
+            None => self.topic(&format!("shadow/{operation}")),
+        }
+    }
+
+    /// A topic of the jobs of the thing, e.g. `jobs_topic("notify-next")`
+    pub fn jobs_topic(&self, operation: &str) -> String {
+        self.topic(&format!("jobs/{operation}"))
+    }
+}
@@ -0,0 +1,352 @@
+//! Azure IoT Hub connection profile
+//!
+//! `AzureIotHub` produces the MQTT client configuration expected by Azure IoT Hub - the device ID
+//! as the client ID, `<hub>/<device ID>/?api-version=...` as the user name and, with a shared
+//! access key, a SAS token as the password - and the names of the reserved topics of the device.
+//!
+//! The SAS tokens expire, so the token is renewed before it does: call `renew` whenever
+//! `renewal_due` returns `true`, e.g. from the loop reading the connection events:
+//!
+//! ```ignore
+//! let mut hub = AzureIotHub::new(&AzureIotHubConfiguration::new(
+//!     "my-hub.azure-devices.net",
+//!     "my-device",
+//!     AzureAuthentication::SharedAccessKey {
+//!         key: "<base64 key of the device>",
+//!         policy_name: None,
+//!     },
+//! ))?;
+//!
+//! let (mut client, mut connection) = hub.connect()?;
+//!
+//! client.subscribe(&hub.cloud_to_device_topic(), QoS::AtLeastOnce)?;
+//!
+//! while let Ok(event) = connection.next() {
+//!     if hub.renewal_due() {
+//!         hub.renew(&mut client)?;
+//!     }
+//!
+//!     // ...
+//! }
+//! ```
+//!
+//! The SAS tokens are signed with the system time, which must therefore be synchronized first,
+//! e.g. with SNTP.
+
+use core::fmt::Write;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::crypto::hmac::HmacSha256;
+use crate::private::base64;
+use crate::sys::*;
+use crate::systime::EspSystemTime;
+use crate::tls::X509;
+
+use super::client::{EspMqttClient, EspMqttConnection, EspMqttEvent, MqttClientConfiguration};
+
+/// The API version of the MQTT interface
+pub const API_VERSION: &str = "2021-04-12";
+
+// 2020-01-01T00:00:00Z: a system time before it has not been synchronized
+const MIN_VALID_TIME: Duration = Duration::from_secs(1577836800);
+
+/// How the device authenticates to the hub
+#[derive(Copy, Clone, Debug)]
+pub enum AzureAuthentication<'a> {
+    /// With SAS tokens signed by a shared access key
+    SharedAccessKey {
+        /// The key, base64-encoded as displayed by the portal
+        key: &'a str,
+        /// The name of the shared access policy of the key, if not the key of the device itself
+        policy_name: Option<&'a str>,
+    },
+    /// With an X.509 client certificate
+    X509 {
+        certificate: X509<'static>,
+        private_key: X509<'static>,
+    },
+}
+
+/// The configuration of the connection to Azure IoT Hub
+#[derive(Copy, Clone, Debug)]
+pub struct AzureIotHubConfiguration<'a> {
+    /// The host name of the hub, e.g. `my-hub.azure-devices.net`
+    pub hub: &'a str,
+    pub device_id: &'a str,
+    pub authentication: AzureAuthentication<'a>,
+    /// The validity of the SAS tokens; a token is renewed when less than a tenth of it remains
+    pub token_ttl: Duration,
+    /// The certificate of the root CA of the hub, e.g. DigiCert Global Root G2; when `None`, the
+    /// certificate bundle is used
+    pub server_certificate: Option<X509<'static>>,
+    pub keep_alive_interval: Duration,
+    pub buffer_size: usize,
+}
+
+impl<'a> AzureIotHubConfiguration<'a> {
+    pub const fn new(
+        hub: &'a str,
+        device_id: &'a str,
+        authentication: AzureAuthentication<'a>,
+    ) -> Self {
+        Self {
+            hub,
+            device_id,
+            authentication,
+            token_ttl: Duration::from_secs(3600),
+            server_certificate: None,
+            keep_alive_interval: Duration::from_secs(240),
+            buffer_size: 2048,
+        }
+    }
+}
+
+/// The connection profile of a device of Azure IoT Hub
+pub struct AzureIotHub<'a> {
+    conf: AzureIotHubConfiguration<'a>,
+    key: Vec<u8>,
+    username: String,
+    password: Option<String>,
+    expiry: Duration,
+}
+
+impl<'a> AzureIotHub<'a> {
+    pub fn new(conf: &AzureIotHubConfiguration<'a>) -> Result<Self, EspError> {
+        if conf.hub.is_empty()
+            || conf.device_id.is_empty()
+            || conf.device_id.len() > 128
+            || conf.token_ttl < Duration::from_secs(60)
+        {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        #[cfg(not(esp_idf_mbedtls_certificate_bundle))]
+        if conf.server_certificate.is_none() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let key = match conf.authentication {
+            AzureAuthentication::SharedAccessKey { key, .. } => base64::decode(key)
+                .filter(|key| !key.is_empty())
+                .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+            AzureAuthentication::X509 { .. } => Vec::new(),
+        };
+
+        Ok(Self {
+            conf: *conf,
+            key,
+            username: format!("{}/{}/?api-version={API_VERSION}", conf.hub, conf.device_id),
+            password: None,
+            expiry: Duration::ZERO,
+        })
+    }
+
+    pub fn device_id(&self) -> &str {
+        self.conf.device_id
+    }
+
+    /// The URI of the hub
+    pub fn url(&self) -> String {
+        format!("mqtts://{}:8883", self.conf.hub)
+    }
+
+    /// The user name of the MQTT connection
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// A SAS token of the device, expiring at `expiry` since the Unix epoch
+    pub fn sas_token(&self, expiry: Duration) -> Result<String, EspError> {
+        let AzureAuthentication::SharedAccessKey { policy_name, .. } = self.conf.authentication
+        else {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        };
+
+        let resource = url_encode(&format!(
+            "{}/devices/{}",
+            self.conf.hub, self.conf.device_id
+        ));
+        let expiry = expiry.as_secs();
+
+        let signature = HmacSha256::mac(&self.key, format!("{resource}\n{expiry}").as_bytes())?;
+
+        let mut token = format!(
+            "SharedAccessSignature sr={resource}&sig={}&se={expiry}",
+            url_encode(&base64::encode(&signature))
+        );
+
+        if let Some(policy_name) = policy_name {
+            write!(&mut token, "&skn={}", url_encode(policy_name)).unwrap();
+        }
+
+        Ok(token)
+    }
+
+    /// The configuration of the MQTT client, with the current SAS token if any
+    ///
+    /// `connect` and `renew` create the SAS tokens; to use this configuration otherwise, call
+    /// `refresh_token` first.
+    pub fn mqtt_configuration(&self) -> MqttClientConfiguration<'_> {
+        let (client_certificate, private_key) = match self.conf.authentication {
+            AzureAuthentication::X509 {
+                certificate,
+                private_key,
+            } => (Some(certificate), Some(private_key)),
+            AzureAuthentication::SharedAccessKey { .. } => (None, None),
+        };
+
+        MqttClientConfiguration {
+            client_id: Some(self.conf.device_id),
+            username: Some(&self.username),
+            password: self.password.as_deref(),
+            keep_alive_interval: Some(self.conf.keep_alive_interval),
+            buffer_size: self.conf.buffer_size,
+            out_buffer_size: self.conf.buffer_size,
+            client_certificate,
+            private_key,
+            server_certificate: self.conf.server_certificate,
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            crt_bundle_attach: if self.conf.server_certificate.is_none() {
+                Some(esp_crt_bundle_attach)
+            } else {
+                None
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Create a new SAS token, valid for `token_ttl` from now; does nothing with an X.509
+    /// authentication
+    ///
+    /// Fails with `ESP_ERR_INVALID_STATE` if the system time is not synchronized.
+    pub fn refresh_token(&mut self) -> Result<(), EspError> {
+        if matches!(self.conf.authentication, AzureAuthentication::X509 { .. }) {
+            return Ok(());
+        }
+
+        let now = EspSystemTime.now();
+
+        if now < MIN_VALID_TIME {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let expiry = Duration::from_secs((now + self.conf.token_ttl).as_secs());
+
+        self.password = Some(self.sas_token(expiry)?);
+        self.expiry = expiry;
+
+        debug!("SAS token renewed, expiring in {:?}", self.conf.token_ttl);
+
+        Ok(())
+    }
+
+    /// Whether the SAS token expires in less than a tenth of `token_ttl`
+    pub fn renewal_due(&self) -> bool {
+        self.password.is_some() && EspSystemTime.now() + self.conf.token_ttl / 10 >= self.expiry
+    }
+
+    /// Connect to the hub
+    pub fn connect(&mut self) -> Result<(EspMqttClient<'static>, EspMqttConnection), EspError> {
+        self.refresh_token()?;
+
+        EspMqttClient::new(&self.url(), &self.mqtt_configuration())
+    }
+
+    /// Connect to the hub, with a callback for the events
+    pub fn connect_cb<F>(&mut self, callback: F) -> Result<EspMqttClient<'static>, EspError>
+    where
+        F: for<'b> FnMut(EspMqttEvent<'b>) + Send + 'static,
+    {
+        self.refresh_token()?;
+
+        EspMqttClient::new_cb(&self.url(), &self.mqtt_configuration(), callback)
+    }
+
+    /// Renew the SAS token of a client created with `connect` or `connect_cb`, and reconnect it
+    /// with the new token
+    ///
+    /// The hub closes the connections whose token expired, so the client has to disconnect and
+    /// reconnect with the new one: it does so automatically, unless its automatic reconnection is
+    /// disabled. The subscriptions have to be renewed if the session is not persistent.
+    pub fn renew(&mut self, client: &mut EspMqttClient<'_>) -> Result<(), EspError> {
+        self.refresh_token()?;
+
+        client.set_configuration(&self.mqtt_configuration())?;
+        client.disconnect()
+    }
+
+    /// The topic of the device-to-cloud messages, with the URL-encoded properties if any, e.g.
+    /// `telemetry_topic("$.ct=application%2Fjson&$.ce=utf-8")`
+    pub fn telemetry_topic(&self, properties: &str) -> String {
+        format!(
+            "devices/{}/messages/events/{properties}",
+            self.conf.device_id
+        )
+    }
+
+    /// The topic filter of the cloud-to-device messages
+    pub fn cloud_to_device_topic(&self) -> String {
+        format!("devices/{}/messages/devicebound/#", self.conf.device_id)
+    }
+
+    /// The topic filter of the responses to the twin requests
+    pub fn twin_response_topic(&self) -> &'static str {
+        "$iothub/twin/res/#"
+    }
+
+    /// The topic of the request for the whole twin
+    pub fn twin_get_topic(&self, request_id: &str) -> String {
+        format!("$iothub/twin/GET/?$rid={request_id}")
+    }
+
+    /// The topic of the updates of the reported properties
+    pub fn twin_reported_topic(&self, request_id: &str) -> String {
+        format!("$iothub/twin/PATCH/properties/reported/?$rid={request_id}")
+    }
+
+    /// The topic filter of the updates of the desired properties
+    pub fn twin_desired_topic(&self) -> &'static str {
+        "$iothub/twin/PATCH/properties/desired/#"
+    }
+
+    /// The topic filter of the direct method invocations
+    pub fn methods_topic(&self) -> &'static str {
+        "$iothub/methods/POST/#"
+    }
+
+    /// The topic of the response to a direct method invocation
+    pub fn method_response_topic(&self, status: u16, request_id: &str) -> String {
+        format!("$iothub/methods/res/{status}/?$rid={request_id}")
+    }
+}
+
+impl core::fmt::Debug for AzureIotHub<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AzureIotHub")
+            .field("hub", &self.conf.hub)
+            .field("device_id", &self.conf.device_id)
+            .field("expiry", &self.expiry)
+            .finish_non_exhaustive()
+    }
+}
+
+fn url_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            write!(&mut encoded, "%{byte:02X}").unwrap();
+        }
+    }
+
+    encoded
+}
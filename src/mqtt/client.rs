@@ -83,9 +83,18 @@ pub struct MqttClientConfiguration<'a> {
 
     #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
     pub psk: Option<Psk<'a>>,
-    // pub alpn_protos: &'a [&'a str],
-    // pub use_secure_element: bool,
-    // void *ds_data;                          /*!< carrier of handle for digital signature parameters */
+    /// The protocols offered with ALPN in the TLS handshake, e.g. `x-amzn-mqtt-ca`
+    pub alpn_protos: &'a [&'a str],
+    /// Use the private key held by the ATECC608 secure element
+    pub use_secure_element: bool,
+    /// Use the private key held by the Digital Signature peripheral, instead of `private_key`
+    #[cfg(all(
+        esp_idf_comp_esp_tls_enabled,
+        esp_idf_esp_tls_using_mbedtls,
+        esp_idf_esp_tls_use_ds_peripheral,
+        feature = "alloc"
+    ))]
+    pub ds_key: Option<&'static crate::tls::ds::DsKey>,
 }
 
 impl<'a> Default for MqttClientConfiguration<'a> {
@@ -125,6 +134,17 @@ impl<'a> Default for MqttClientConfiguration<'a> {
 
             #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
             psk: None,
+
+            alpn_protos: &[],
+
+            use_secure_element: false,
+            #[cfg(all(
+                esp_idf_comp_esp_tls_enabled,
+                esp_idf_esp_tls_using_mbedtls,
+                esp_idf_esp_tls_use_ds_peripheral,
+                feature = "alloc"
+            ))]
+            ds_key: None,
         }
     }
 }
@@ -206,6 +226,21 @@ impl<'a> TryFrom<&'a MqttClientConfiguration<'a>>
             }
         }
 
+        c_conf.use_secure_element = conf.use_secure_element;
+
+        #[cfg(all(
+            esp_idf_comp_esp_tls_enabled,
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_esp_tls_use_ds_peripheral,
+            feature = "alloc"
+        ))]
+        if let (Some(cert), Some(ds_key)) = (conf.client_certificate, conf.ds_key) {
+            c_conf.client_cert_pem = cert.as_esp_idf_raw_ptr() as _;
+            c_conf.client_cert_len = cert.as_esp_idf_raw_len();
+
+            c_conf.ds_data = ds_key.as_ds_data();
+        }
+
         #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
         let tls_psk_conf = conf.psk.as_ref().map(|psk| psk.try_into()).transpose()?;
         #[cfg(not(all(esp_idf_esp_tls_psk_verification, feature = "alloc")))]
@@ -316,6 +351,21 @@ impl<'a> TryFrom<&'a MqttClientConfiguration<'a>>
             }
         }
 
+        c_conf.credentials.authentication.use_secure_element = conf.use_secure_element;
+
+        #[cfg(all(
+            esp_idf_comp_esp_tls_enabled,
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_esp_tls_use_ds_peripheral,
+            feature = "alloc"
+        ))]
+        if let (Some(cert), Some(ds_key)) = (conf.client_certificate, conf.ds_key) {
+            c_conf.credentials.authentication.certificate = cert.as_esp_idf_raw_ptr() as _;
+            c_conf.credentials.authentication.certificate_len = cert.as_esp_idf_raw_len();
+
+            c_conf.credentials.authentication.ds_data = ds_key.as_ds_data();
+        }
+
         #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
         let tls_psk_conf = conf.psk.as_ref().map(|psk| psk.try_into()).transpose()?;
         #[cfg(not(all(esp_idf_esp_tls_psk_verification, feature = "alloc")))]
@@ -325,6 +375,37 @@ impl<'a> TryFrom<&'a MqttClientConfiguration<'a>>
     }
 }
 
+// The ALPN protocols, as the NULL-terminated array of C strings kept by the C client
+struct AlpnProtos {
+    _cstrs: RawCstrs,
+    protos: alloc::vec::Vec<*const core::ffi::c_char>,
+}
+
+impl AlpnProtos {
+    fn new(protos: &[&str]) -> Result<Option<Self>, EspError> {
+        if protos.is_empty() {
+            return Ok(None);
+        }
+
+        let mut cstrs = RawCstrs::new();
+
+        let mut ptrs = alloc::vec::Vec::with_capacity(protos.len() + 1);
+        for proto in protos {
+            ptrs.push(cstrs.as_ptr(proto)?);
+        }
+        ptrs.push(core::ptr::null());
+
+        Ok(Some(Self {
+            _cstrs: cstrs,
+            protos: ptrs,
+        }))
+    }
+
+    fn as_ptr(&mut self) -> *mut *const core::ffi::c_char {
+        self.protos.as_mut_ptr()
+    }
+}
+
 struct UnsafeCallback<'a>(*mut Box<dyn FnMut(esp_mqtt_event_handle_t) + Send + 'a>);
 
 impl<'a> UnsafeCallback<'a> {
@@ -351,6 +432,7 @@ pub struct EspMqttClient<'a> {
     raw_client: esp_mqtt_client_handle_t,
     _boxed_raw_callback: Box<dyn FnMut(esp_mqtt_event_handle_t) + Send + 'a>,
     _tls_psk_conf: Option<TlsPsk>,
+    _alpn_protos: Option<AlpnProtos>,
 }
 
 impl<'a> RawHandle for EspMqttClient<'a> {
@@ -453,7 +535,7 @@ impl<'a> EspMqttClient<'a> {
 
         let unsafe_callback = UnsafeCallback::from(&mut boxed_raw_callback);
 
-        let (mut c_conf, mut cstrs, tls_psk_conf) = conf.try_into()?;
+        let (mut c_conf, mut cstrs, mut tls_psk_conf) = conf.try_into()?;
 
         #[cfg(esp_idf_version_major = "4")]
         {
@@ -465,17 +547,9 @@ impl<'a> EspMqttClient<'a> {
             c_conf.broker.address.uri = cstrs.as_ptr(url)?;
         }
 
-        #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
-        {
-            #[cfg(esp_idf_version_major = "4")]
-            if let Some(ref conf) = tls_psk_conf {
-                c_conf.psk_hint_key = &*conf.psk;
-            }
-            #[cfg(not(esp_idf_version_major = "4"))]
-            if let Some(ref conf) = tls_psk_conf {
-                c_conf.broker.verification.psk_hint_key = &*conf.psk;
-            }
-        }
+        let mut alpn_protos = AlpnProtos::new(conf.alpn_protos)?;
+
+        Self::set_pinned(&mut c_conf, &mut tls_psk_conf, &mut alpn_protos);
 
         let raw_client = unsafe { esp_mqtt_client_init(&c_conf as *const _) };
         if raw_client.is_null() {
@@ -486,6 +560,7 @@ impl<'a> EspMqttClient<'a> {
             raw_client,
             _boxed_raw_callback: boxed_raw_callback,
             _tls_psk_conf: tls_psk_conf,
+            _alpn_protos: alpn_protos,
         };
 
         esp!(unsafe {
@@ -625,6 +700,65 @@ impl<'a> EspMqttClient<'a> {
         Self::check(unsafe { esp_mqtt_client_set_uri(self.raw_client, uri.as_ptr()) })
     }
 
+    /// Replace the configuration of the client, e.g. to renew its password; it applies from the
+    /// next connection
+    pub fn set_configuration(&mut self, conf: &MqttClientConfiguration) -> Result<(), EspError> {
+        let (mut c_conf, _cstrs, mut tls_psk_conf) = conf.try_into()?;
+        let mut alpn_protos = AlpnProtos::new(conf.alpn_protos)?;
+
+        Self::set_pinned(&mut c_conf, &mut tls_psk_conf, &mut alpn_protos);
+
+        esp!(unsafe { esp_mqtt_set_config(self.raw_client, &c_conf) })?;
+
+        self._tls_psk_conf = tls_psk_conf;
+        self._alpn_protos = alpn_protos;
+
+        Ok(())
+    }
+
+    /// Disconnect from the broker; the client reconnects after the reconnect timeout, unless
+    /// the automatic reconnection is disabled
+    pub fn disconnect(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_mqtt_client_disconnect(self.raw_client) })
+    }
+
+    /// Reconnect to the broker, when the automatic reconnection is disabled
+    pub fn reconnect(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_mqtt_client_reconnect(self.raw_client) })
+    }
+
+    // Point the configuration to the data which the C client does not copy, and which must live as
+    // long as the client
+    #[allow(unused_variables)]
+    fn set_pinned(
+        c_conf: &mut esp_mqtt_client_config_t,
+        tls_psk_conf: &mut Option<TlsPsk>,
+        alpn_protos: &mut Option<AlpnProtos>,
+    ) {
+        #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+        {
+            #[cfg(esp_idf_version_major = "4")]
+            if let Some(conf) = tls_psk_conf {
+                c_conf.psk_hint_key = &*conf.psk;
+            }
+            #[cfg(not(esp_idf_version_major = "4"))]
+            if let Some(conf) = tls_psk_conf {
+                c_conf.broker.verification.psk_hint_key = &*conf.psk;
+            }
+        }
+
+        if let Some(alpn_protos) = alpn_protos {
+            #[cfg(esp_idf_version_major = "4")]
+            {
+                c_conf.alpn_protos = alpn_protos.as_ptr() as _;
+            }
+            #[cfg(not(esp_idf_version_major = "4"))]
+            {
+                c_conf.broker.verification.alpn_protos = alpn_protos.as_ptr() as _;
+            }
+        }
+    }
+
     extern "C" fn handle(
         event_handler_arg: *mut c_void,
         _event_base: esp_event_base_t,
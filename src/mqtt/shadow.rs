@@ -0,0 +1,757 @@
+//! Synchronization of a device shadow (AWS IoT) or device twin (Azure IoT Hub)
+//!
+//! `EspShadow` keeps a local copy of the document of the device: the `desired` state, set by the
+//! cloud, and the `reported` state, set by the device. The application reports its state with
+//! `report`, is called back with the delta - the desired properties which differ from the
+//! reported ones - whenever the desired state changes, and feeds `handle` the events of its MQTT
+//! client:
+//!
+//! ```ignore
+//! let mut shadow = EspShadow::new_with_callback(
+//!     ShadowService::aws(&aws, None),
+//!     nvs_partition,
+//!     "shadow",
+//!     &Default::default(),
+//!     |delta| info!("Delta: {delta}"),
+//! )?;
+//!
+//! shadow.report(&mut client, &json!({"led": "on"}))?;
+//!
+//! loop {
+//!     let event = connection.next()?;
+//!
+//!     shadow.handle(&mut client, &event.payload())?;
+//! }
+//! ```
+//!
+//! The desired state is merged with the updates from the cloud in the order of their versions,
+//! the older ones being ignored; the whole document is requested again on every connection, and
+//! whenever an update of the twin was missed.
+//!
+//! The documents - and the reports not acknowledged by the cloud yet - are persisted in NVS, so
+//! that the device starts with the last known desired state, and that its reports made offline
+//! are sent once it connects. The reports are JSON merge patches (RFC 7386): a `null` property
+//! removes the property from the reported state.
+
+use core::fmt::{self, Debug};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use serde_json::{json, Map, Value};
+
+use crate::mqtt::client::{Details, EspMqttClient, EventPayload, QoS};
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::sys::*;
+
+use super::aws::AwsIot;
+
+// The NVS key of the document
+const KEY: &str = "shadow";
+
+const AZURE_RESPONSE_PREFIX: &str = "$iothub/twin/res/";
+const AZURE_RESPONSE_TOPIC: &str = "$iothub/twin/res/#";
+const AZURE_DESIRED_PREFIX: &str = "$iothub/twin/PATCH/properties/desired/";
+const AZURE_DESIRED_TOPIC: &str = "$iothub/twin/PATCH/properties/desired/#";
+
+/// The cloud service hosting the document
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ShadowService {
+    /// A shadow of AWS IoT, with the prefix of its topics, e.g. `$aws/things/my-thing/shadow/`
+    Aws { prefix: String },
+    /// The twin of the device of Azure IoT Hub
+    Azure,
+}
+
+impl ShadowService {
+    /// The classic shadow (with `None`) or a named shadow of the thing
+    pub fn aws(aws: &AwsIot<'_>, shadow: Option<&str>) -> Self {
+        Self::Aws {
+            prefix: aws.shadow_topic(shadow, ""),
+        }
+    }
+
+    pub fn azure() -> Self {
+        Self::Azure
+    }
+}
+
+/// The configuration of the synchronization
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowConfiguration {
+    pub qos: QoS,
+    /// The maximum length of a document received
+    pub max_document_len: usize,
+}
+
+impl ShadowConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            qos: QoS::AtLeastOnce,
+            max_document_len: 8192,
+        }
+    }
+}
+
+impl Default for ShadowConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The local copy of a device shadow or twin, synchronized over MQTT
+pub struct EspShadow<T: NvsPartitionId> {
+    service: ShadowService,
+    nvs: EspNvs<T>,
+    qos: QoS,
+    max_document_len: usize,
+    desired: Value,
+    reported: Value,
+    // The version of the desired state, as per the cloud
+    version: Option<u64>,
+    // The reports not sent yet
+    pending: Option<Value>,
+    // The report sent and its token, until acknowledged
+    in_flight: Option<(String, Value)>,
+    // The token of the request for the whole document
+    get_token: Option<String>,
+    next_token: u32,
+    connected: bool,
+    // The message being received, when fragmented by the MQTT client
+    partial: Option<(String, Vec<u8>)>,
+    callback: Option<Box<dyn FnMut(&Value) + Send + 'static>>,
+}
+
+impl<T: NvsPartitionId> EspShadow<T> {
+    /// Load the document persisted in the namespace, if any
+    pub fn new(
+        service: ShadowService,
+        partition: EspNvsPartition<T>,
+        namespace: &str,
+        conf: &ShadowConfiguration,
+    ) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, namespace, true)?;
+
+        let mut this = Self {
+            service,
+            nvs,
+            qos: conf.qos,
+            max_document_len: conf.max_document_len,
+            desired: Value::Object(Map::new()),
+            reported: Value::Object(Map::new()),
+            version: None,
+            pending: None,
+            in_flight: None,
+            get_token: None,
+            next_token: 0,
+            connected: false,
+            partial: None,
+            callback: None,
+        };
+
+        this.load()?;
+
+        Ok(this)
+    }
+
+    /// Load the document persisted in the namespace, if any, and call `callback` with the delta
+    /// whenever the desired state changes
+    pub fn new_with_callback<F>(
+        service: ShadowService,
+        partition: EspNvsPartition<T>,
+        namespace: &str,
+        conf: &ShadowConfiguration,
+        callback: F,
+    ) -> Result<Self, EspError>
+    where
+        F: FnMut(&Value) + Send + 'static,
+    {
+        let mut this = Self::new(service, partition, namespace, conf)?;
+
+        this.callback = Some(Box::new(callback));
+
+        Ok(this)
+    }
+
+    /// The desired state, as last received from the cloud
+    pub fn desired(&self) -> &Value {
+        &self.desired
+    }
+
+    /// The reported state, including the reports not acknowledged by the cloud yet
+    pub fn reported(&self) -> &Value {
+        &self.reported
+    }
+
+    /// The version of the desired state, if ever received
+    pub fn version(&self) -> Option<u64> {
+        self.version
+    }
+
+    /// The desired properties which differ from the reported ones, if any
+    pub fn delta(&self) -> Option<Value> {
+        delta(&self.desired, &self.reported)
+    }
+
+    /// Whether all the reports were acknowledged by the cloud
+    pub fn is_synchronized(&self) -> bool {
+        self.pending.is_none() && self.in_flight.is_none()
+    }
+
+    /// Merge a patch into the reported state, and send it to the cloud - once connected, if not
+    /// connected yet
+    pub fn report(
+        &mut self,
+        client: &mut EspMqttClient<'_>,
+        patch: &Value,
+    ) -> Result<(), EspError> {
+        if !patch.is_object() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        merge(&mut self.reported, patch, false);
+        merge(self.pending.get_or_insert(Value::Null), patch, true);
+
+        self.save()?;
+
+        if self.connected && self.in_flight.is_none() && self.get_token.is_none() {
+            self.send_pending(client)?;
+        }
+
+        Ok(())
+    }
+
+    /// Subscribe to the topics of the document; done by `handle` on every connection
+    pub fn subscribe(&mut self, client: &mut EspMqttClient<'_>) -> Result<(), EspError> {
+        match &self.service {
+            ShadowService::Aws { prefix } => {
+                for operation in [
+                    "get/accepted",
+                    "get/rejected",
+                    "update/accepted",
+                    "update/rejected",
+                    "update/delta",
+                ] {
+                    client.subscribe(&format!("{prefix}{operation}"), self.qos)?;
+                }
+            }
+            ShadowService::Azure => {
+                client.subscribe(AZURE_RESPONSE_TOPIC, self.qos)?;
+                client.subscribe(AZURE_DESIRED_TOPIC, self.qos)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Request the whole document; done by `handle` on every connection
+    pub fn request(&mut self, client: &mut EspMqttClient<'_>) -> Result<(), EspError> {
+        let token = self.token();
+
+        match &self.service {
+            ShadowService::Aws { prefix } => client.publish(
+                &format!("{prefix}get"),
+                self.qos,
+                false,
+                json!({ "clientToken": token }).to_string().as_bytes(),
+            )?,
+            ShadowService::Azure => client.publish(
+                &format!("$iothub/twin/GET/?$rid={token}"),
+                self.qos,
+                false,
+                &[],
+            )?,
+        };
+
+        self.get_token = Some(token);
+
+        Ok(())
+    }
+
+    /// Handle an event of the MQTT client; return whether the desired state changed
+    pub fn handle(
+        &mut self,
+        client: &mut EspMqttClient<'_>,
+        event: &EventPayload<'_, EspError>,
+    ) -> Result<bool, EspError> {
+        match event {
+            EventPayload::Connected(_) => {
+                self.connected = true;
+
+                // The report in flight may have been lost
+                self.requeue();
+
+                self.subscribe(client)?;
+                self.request(client)?;
+
+                Ok(false)
+            }
+            EventPayload::Disconnected => {
+                self.connected = false;
+                self.get_token = None;
+                self.partial = None;
+
+                Ok(false)
+            }
+            EventPayload::Received {
+                topic,
+                data,
+                details,
+                ..
+            } => match details {
+                Details::Complete => match topic {
+                    Some(topic) if self.is_document_topic(topic) => {
+                        self.partial = None;
+                        self.message(client, topic, data)
+                    }
+                    _ => Ok(false),
+                },
+                Details::InitialChunk(initial) => {
+                    self.partial = topic
+                        .filter(|topic| self.is_document_topic(topic))
+                        .filter(|_| initial.total_data_size <= self.max_document_len)
+                        .map(|topic| {
+                            let mut message = Vec::with_capacity(initial.total_data_size);
+                            message.extend_from_slice(data);
+
+                            (topic.to_string(), message)
+                        });
+
+                    Ok(false)
+                }
+                Details::SubsequentChunk(subsequent) => {
+                    let complete = self.partial.as_mut().is_some_and(|(_, message)| {
+                        message.extend_from_slice(data);
+                        message.len() >= subsequent.total_data_size
+                    });
+
+                    if complete {
+                        let (topic, message) = self.partial.take().unwrap();
+                        return self.message(client, &topic, &message);
+                    }
+
+                    Ok(false)
+                }
+            },
+            _ => Ok(false),
+        }
+    }
+
+    fn is_document_topic(&self, topic: &str) -> bool {
+        match &self.service {
+            ShadowService::Aws { prefix } => topic.starts_with(prefix.as_str()),
+            ShadowService::Azure => {
+                topic.starts_with(AZURE_RESPONSE_PREFIX) || topic.starts_with(AZURE_DESIRED_PREFIX)
+            }
+        }
+    }
+
+    fn message(
+        &mut self,
+        client: &mut EspMqttClient<'_>,
+        topic: &str,
+        data: &[u8],
+    ) -> Result<bool, EspError> {
+        if data.len() > self.max_document_len {
+            warn!("Ignoring a document of {} bytes on {topic}", data.len());
+            return Ok(false);
+        }
+
+        match &self.service {
+            ShadowService::Aws { prefix } => {
+                let operation = topic[prefix.len()..].to_string();
+                self.aws_message(client, &operation, data)
+            }
+            ShadowService::Azure => self.azure_message(client, topic, data),
+        }
+    }
+
+    fn aws_message(
+        &mut self,
+        client: &mut EspMqttClient<'_>,
+        operation: &str,
+        data: &[u8],
+    ) -> Result<bool, EspError> {
+        let Ok(message) = serde_json::from_slice::<Value>(data) else {
+            warn!("Ignoring an invalid document on {operation}");
+            return Ok(false);
+        };
+
+        let token = message["clientToken"].as_str();
+        let version = message["version"].as_u64();
+
+        match operation {
+            "get/accepted" if token == self.get_token.as_deref() => {
+                self.get_token = None;
+
+                let state = &message["state"];
+
+                let changed = self.document(version, &state["desired"], &state["reported"])?;
+                self.send_pending(client)?;
+
+                Ok(changed && self.notify())
+            }
+            "get/rejected" if token == self.get_token.as_deref() => {
+                // 404 when the shadow does not exist yet: the reports create it
+                self.get_token = None;
+
+                if message["code"].as_u64() != Some(404) {
+                    warn!("Shadow request rejected: {}", message["message"]);
+                }
+
+                self.send_pending(client)?;
+
+                Ok(false)
+            }
+            "update/accepted" | "update/rejected" => {
+                if self.in_flight.as_ref().map(|(sent, _)| sent.as_str()) == token {
+                    self.in_flight = None;
+
+                    if operation == "update/rejected" {
+                        warn!("Shadow report rejected: {}", message["message"]);
+                    }
+
+                    self.save()?;
+                    self.send_pending(client)?;
+                }
+
+                Ok(false)
+            }
+            "update/delta" => {
+                if version.is_some_and(|version| Some(version) <= self.version) {
+                    debug!("Ignoring an outdated delta, version {version:?}");
+                    return Ok(false);
+                }
+
+                let previous = self.desired.clone();
+
+                merge(&mut self.desired, &message["state"], false);
+                self.version = version;
+                self.save()?;
+
+                Ok(self.desired != previous && self.notify())
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn azure_message(
+        &mut self,
+        client: &mut EspMqttClient<'_>,
+        topic: &str,
+        data: &[u8],
+    ) -> Result<bool, EspError> {
+        // `$iothub/twin/res/<status>/?$rid=<rid>[&$version=<version>]`
+        if let Some(response) = topic.strip_prefix(AZURE_RESPONSE_PREFIX) {
+            let (status, query) = response.split_once('/').unwrap_or((response, ""));
+            let status: u16 = status.parse().unwrap_or(0);
+            let rid = query_param(query, "$rid");
+
+            if rid.is_some() && rid == self.get_token.as_deref() {
+                self.get_token = None;
+
+                match serde_json::from_slice::<Value>(data) {
+                    Ok(twin) if status == 200 => {
+                        let version = twin["desired"]["$version"].as_u64();
+
+                        let changed =
+                            self.document(version, &twin["desired"], &twin["reported"])?;
+                        self.send_pending(client)?;
+
+                        return Ok(changed && self.notify());
+                    }
+                    _ => warn!("Twin request failed with status {status}"),
+                }
+
+                self.send_pending(client)?;
+            } else if rid.is_some() && rid == self.in_flight.as_ref().map(|(rid, _)| rid.as_str()) {
+                self.in_flight = None;
+
+                if !(200..300).contains(&status) {
+                    warn!("Twin report failed with status {status}");
+                }
+
+                self.save()?;
+                self.send_pending(client)?;
+            }
+
+            return Ok(false);
+        }
+
+        // `$iothub/twin/PATCH/properties/desired/?$version=<version>`
+        let Some(query) = topic.strip_prefix(AZURE_DESIRED_PREFIX) else {
+            return Ok(false);
+        };
+
+        let version = query_param(query, "$version").and_then(|version| version.parse().ok());
+
+        let Ok(patch) = serde_json::from_slice::<Value>(data) else {
+            warn!("Ignoring an invalid twin patch");
+            return Ok(false);
+        };
+
+        match (version, self.version) {
+            (Some(version), Some(current)) if version <= current => {
+                debug!("Ignoring an outdated twin patch, version {version}");
+                Ok(false)
+            }
+            (Some(version), Some(current)) if version == current + 1 => {
+                let previous = self.desired.clone();
+
+                merge(&mut self.desired, &patch, false);
+                strip_metadata(&mut self.desired);
+                self.version = Some(version);
+                self.save()?;
+
+                Ok(self.desired != previous && self.notify())
+            }
+            _ => {
+                // A patch was missed, or the twin was never received
+                info!("Requesting the twin, after a patch of version {version:?}");
+
+                if self.get_token.is_none() {
+                    self.request(client)?;
+                }
+
+                Ok(false)
+            }
+        }
+    }
+
+    /// Replace the local documents with those of the cloud, keeping the reports not sent yet;
+    /// return whether the desired state changed
+    fn document(
+        &mut self,
+        version: Option<u64>,
+        desired: &Value,
+        reported: &Value,
+    ) -> Result<bool, EspError> {
+        let previous = core::mem::replace(&mut self.desired, Value::Object(Map::new()));
+
+        // Either may be missing, if never set
+        if desired.is_object() {
+            merge(&mut self.desired, desired, false);
+            strip_metadata(&mut self.desired);
+        }
+
+        self.reported = Value::Object(Map::new());
+
+        if reported.is_object() {
+            merge(&mut self.reported, reported, false);
+            strip_metadata(&mut self.reported);
+        }
+
+        let in_flight = self.in_flight.as_ref().map(|(_, in_flight)| in_flight);
+
+        for patch in in_flight.into_iter().chain(&self.pending) {
+            merge(&mut self.reported, patch, false);
+        }
+
+        self.version = version;
+
+        self.save()?;
+
+        Ok(self.desired != previous)
+    }
+
+    /// Call the callback with the delta, if any; return `true`, for the chaining
+    fn notify(&mut self) -> bool {
+        if let Some(delta) = self.delta() {
+            if let Some(callback) = &mut self.callback {
+                callback(&delta);
+            }
+        }
+
+        true
+    }
+
+    fn send_pending(&mut self, client: &mut EspMqttClient<'_>) -> Result<(), EspError> {
+        if !self.connected || self.in_flight.is_some() {
+            return Ok(());
+        }
+
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+
+        let token = self.token();
+
+        let result = match &self.service {
+            ShadowService::Aws { prefix } => client.publish(
+                &format!("{prefix}update"),
+                self.qos,
+                false,
+                json!({ "state": { "reported": pending }, "clientToken": token })
+                    .to_string()
+                    .as_bytes(),
+            ),
+            ShadowService::Azure => client.publish(
+                &format!("$iothub/twin/PATCH/properties/reported/?$rid={token}"),
+                self.qos,
+                false,
+                pending.to_string().as_bytes(),
+            ),
+        };
+
+        self.in_flight = Some((token, pending));
+
+        if let Err(err) = result {
+            self.requeue();
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Merge the report in flight back into the reports not sent yet
+    fn requeue(&mut self) {
+        if let Some((_, mut in_flight)) = self.in_flight.take() {
+            if let Some(pending) = self.pending.take() {
+                merge(&mut in_flight, &pending, true);
+            }
+
+            self.pending = Some(in_flight);
+        }
+    }
+
+    fn token(&mut self) -> String {
+        self.next_token = self.next_token.wrapping_add(1);
+        self.next_token.to_string()
+    }
+
+    fn load(&mut self) -> Result<(), EspError> {
+        let Some(len) = self.nvs.blob_len(KEY)? else {
+            return Ok(());
+        };
+
+        let mut buf = alloc::vec![0; len];
+
+        let Some(stored) = self.nvs.get_blob(KEY, &mut buf)? else {
+            return Ok(());
+        };
+
+        let Ok(stored) = serde_json::from_slice::<Value>(stored) else {
+            warn!("Ignoring an invalid stored document");
+            return Ok(());
+        };
+
+        if stored["desired"].is_object() {
+            self.desired = stored["desired"].clone();
+        }
+
+        if stored["reported"].is_object() {
+            self.reported = stored["reported"].clone();
+        }
+
+        self.version = stored["version"].as_u64();
+        self.pending = stored["pending"]
+            .is_object()
+            .then(|| stored["pending"].clone());
+
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<(), EspError> {
+        // The report in flight is persisted as not sent, in case of a restart before its
+        // acknowledgement
+        let mut pending = self
+            .in_flight
+            .as_ref()
+            .map(|(_, in_flight)| in_flight.clone());
+
+        if let Some(not_sent) = &self.pending {
+            merge(pending.get_or_insert(Value::Null), not_sent, true);
+        }
+
+        let stored = json!({
+            "desired": self.desired,
+            "reported": self.reported,
+            "version": self.version,
+            "pending": pending,
+        });
+
+        self.nvs.set_blob(KEY, stored.to_string().as_bytes())
+    }
+}
+
+impl<T: NvsPartitionId> Debug for EspShadow<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspShadow")
+            .field("service", &self.service)
+            .field("version", &self.version)
+            .field("connected", &self.connected)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Merge a JSON merge patch into a document; with `keep_null`, the `null` properties are kept,
+/// so as to merge two patches into one
+fn merge(target: &mut Value, patch: &Value, keep_null: bool) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+
+    let Value::Object(target) = target else {
+        unreachable!()
+    };
+
+    for (name, value) in patch {
+        if value.is_null() && !keep_null {
+            target.remove(name);
+        } else {
+            merge(
+                target.entry(name.clone()).or_insert(Value::Null),
+                value,
+                keep_null,
+            );
+        }
+    }
+}
+
+/// The properties of `desired` which differ from those of `reported`, if any
+fn delta(desired: &Value, reported: &Value) -> Option<Value> {
+    match (desired, reported) {
+        (Value::Object(desired), Value::Object(reported)) => {
+            let delta = desired
+                .iter()
+                .filter(|(name, _)| !name.starts_with('$'))
+                .filter_map(|(name, value)| {
+                    let delta = match reported.get(name) {
+                        Some(reported) => delta(value, reported),
+                        None => (!value.is_null()).then(|| value.clone()),
+                    };
+
+                    delta.map(|delta| (name.clone(), delta))
+                })
+                .collect::<Map<_, _>>();
+
+            (!delta.is_empty()).then_some(Value::Object(delta))
+        }
+        _ => (desired != reported).then(|| desired.clone()),
+    }
+}
+
+/// Remove the metadata properties of a twin, i.e. `$version` and `$metadata`
+fn strip_metadata(document: &mut Value) {
+    if let Value::Object(document) = document {
+        document.retain(|name, _| !name.starts_with('$'));
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .trim_start_matches('?')
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find_map(|(other, value)| (other == name).then_some(value))
+}
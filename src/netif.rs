@@ -22,6 +22,9 @@ use crate::private::common::*;
 use crate::private::cstr::*;
 use crate::private::mutex;
 
+#[cfg(feature = "alloc")]
+pub mod stats;
+
 #[cfg(feature = "alloc")]
 pub use driver::*;
 #[cfg(esp_idf_lwip_ppp_support)]
@@ -552,6 +555,9 @@ impl EspNetif {
 
 impl Drop for EspNetif {
     fn drop(&mut self) {
+        #[cfg(feature = "alloc")]
+        stats::release(self.handle);
+
         unsafe { esp_netif_destroy(self.handle) };
 
         info!("Dropped");
@@ -0,0 +1,342 @@
+//! Traffic statistics of the network interfaces
+//!
+//! The counters of an interface are enabled with `EspNetif::enable_stats` - wrapping the input
+//! and link output functions of its lwIP interface - and read with `EspNetif::stats`.
+//! `StatsSampler` turns them into throughputs, e.g. for a bandwidth dashboard, or for enforcing
+//! a data cap:
+//!
+//! ```ignore
+//! let netif = wifi.sta_netif();
+//! netif.enable_stats()?;
+//!
+//! let mut sampler = StatsSampler::new(netif)?;
+//!
+//! loop {
+//!     FreeRtos::delay_ms(10_000);
+//!
+//!     let sample = sampler.sample(netif)?;
+//!     info!("RX {} B/s, TX {} B/s", sample.rx_bytes_per_sec(), sample.tx_bytes_per_sec());
+//! }
+//! ```
+//!
+//! The counters are those of the link layer, i.e. they include the Ethernet headers, but not the
+//! frames which lwIP never sees, such as those filtered by the Wi-Fi driver. The interfaces
+//! which do not use the link output of lwIP, such as PPP, only count their received packets.
+//!
+//! With `CONFIG_LWIP_STATS`, `lwip_stats` also returns the global per-protocol counters of lwIP.
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::handle::RawHandle;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::EspNetif;
+
+/// The traffic counters of a network interface, since their enabling
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NetifStats {
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    /// The packets which the driver failed to send
+    pub tx_errors: u64,
+}
+
+impl NetifStats {
+    /// The counters since `previous`
+    pub fn delta(&self, previous: &Self) -> Self {
+        Self {
+            rx_bytes: self.rx_bytes.wrapping_sub(previous.rx_bytes),
+            rx_packets: self.rx_packets.wrapping_sub(previous.rx_packets),
+            tx_bytes: self.tx_bytes.wrapping_sub(previous.tx_bytes),
+            tx_packets: self.tx_packets.wrapping_sub(previous.tx_packets),
+            tx_errors: self.tx_errors.wrapping_sub(previous.tx_errors),
+        }
+    }
+}
+
+/// The traffic of an interface over an interval
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StatsSample {
+    pub delta: NetifStats,
+    pub interval: Duration,
+}
+
+impl StatsSample {
+    pub fn rx_bytes_per_sec(&self) -> u64 {
+        per_sec(self.delta.rx_bytes, self.interval)
+    }
+
+    pub fn tx_bytes_per_sec(&self) -> u64 {
+        per_sec(self.delta.tx_bytes, self.interval)
+    }
+
+    pub fn rx_packets_per_sec(&self) -> u64 {
+        per_sec(self.delta.rx_packets, self.interval)
+    }
+
+    pub fn tx_packets_per_sec(&self) -> u64 {
+        per_sec(self.delta.tx_packets, self.interval)
+    }
+}
+
+fn per_sec(count: u64, interval: Duration) -> u64 {
+    match interval.as_micros() {
+        0 => 0,
+        micros => (count as u128 * 1_000_000 / micros) as u64,
+    }
+}
+
+/// Samples the counters of an interface, returning the traffic since the previous sample
+#[derive(Clone, Debug)]
+pub struct StatsSampler {
+    previous: NetifStats,
+    at: Duration,
+}
+
+impl StatsSampler {
+    /// Start sampling the counters of `netif`, which must be enabled
+    pub fn new(netif: &EspNetif) -> Result<Self, EspError> {
+        Ok(Self {
+            previous: netif.stats()?,
+            at: now(),
+        })
+    }
+
+    pub fn sample(&mut self, netif: &EspNetif) -> Result<StatsSample, EspError> {
+        let stats = netif.stats()?;
+        let at = now();
+
+        let sample = StatsSample {
+            delta: stats.delta(&self.previous),
+            interval: at.saturating_sub(self.at),
+        };
+
+        self.previous = stats;
+        self.at = at;
+
+        Ok(sample)
+    }
+}
+
+struct Counted {
+    netif: *mut netif,
+    input: netif_input_fn,
+    linkoutput: netif_linkoutput_fn,
+    stats: NetifStats,
+}
+
+unsafe impl Send for Counted {}
+
+static COUNTED: Mutex<Vec<Counted>> = Mutex::new(Vec::new());
+
+impl EspNetif {
+    /// Start counting the traffic of the interface; does nothing if already counting
+    pub fn enable_stats(&self) -> Result<(), EspError> {
+        let netif = self.lwip_netif()?;
+
+        esp!(unsafe { esp_netif_tcpip_exec(Some(wrap), netif as *mut _) })
+    }
+
+    /// The traffic counters of the interface, since `enable_stats`
+    pub fn stats(&self) -> Result<NetifStats, EspError> {
+        let netif = self.lwip_netif()?;
+
+        COUNTED
+            .lock()
+            .iter()
+            .find(|counted| counted.netif == netif)
+            .map(|counted| counted.stats)
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+    }
+
+    /// Reset the traffic counters of the interface
+    pub fn reset_stats(&self) -> Result<(), EspError> {
+        let netif = self.lwip_netif()?;
+
+        let mut counted = COUNTED.lock();
+
+        let counted = counted
+            .iter_mut()
+            .find(|counted| counted.netif == netif)
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+
+        counted.stats = Default::default();
+
+        Ok(())
+    }
+
+    fn lwip_netif(&self) -> Result<*mut netif, EspError> {
+        let netif = unsafe { esp_netif_get_netif_impl(self.handle()) } as *mut netif;
+
+        if netif.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        Ok(netif)
+    }
+}
+
+/// Forget the counters of an interface about to be destroyed
+pub(crate) fn release(handle: *mut esp_netif_t) {
+    let netif = unsafe { esp_netif_get_netif_impl(handle) } as *mut netif;
+
+    COUNTED.lock().retain(|counted| counted.netif != netif);
+}
+
+// Run in the context of the TCP/IP task, so as not to race with the traffic
+unsafe extern "C" fn wrap(ctx: *mut core::ffi::c_void) -> esp_err_t {
+    let netif = ctx as *mut netif;
+
+    let mut counted = COUNTED.lock();
+
+    if counted.iter().any(|counted| counted.netif == netif) {
+        return ESP_OK;
+    }
+
+    counted.push(Counted {
+        netif,
+        input: (*netif).input,
+        linkoutput: (*netif).linkoutput,
+        stats: Default::default(),
+    });
+
+    (*netif).input = Some(counting_input);
+
+    if (*netif).linkoutput.is_some() {
+        (*netif).linkoutput = Some(counting_linkoutput);
+    }
+
+    ESP_OK
+}
+
+unsafe extern "C" fn counting_input(p: *mut pbuf, inp: *mut netif) -> err_t {
+    let input = {
+        let mut counted = COUNTED.lock();
+
+        counted
+            .iter_mut()
+            .find(|counted| counted.netif == inp)
+            .and_then(|counted| {
+                counted.stats.rx_bytes += (*p).tot_len as u64;
+                counted.stats.rx_packets += 1;
+
+                counted.input
+            })
+    };
+
+    match input {
+        Some(input) => input(p, inp),
+        None => {
+            pbuf_free(p);
+            err_enum_t_ERR_IF as _
+        }
+    }
+}
+
+unsafe extern "C" fn counting_linkoutput(netif: *mut netif, p: *mut pbuf) -> err_t {
+    let (linkoutput, len) = {
+        let counted = COUNTED.lock();
+
+        let linkoutput = counted
+            .iter()
+            .find(|counted| counted.netif == netif)
+            .and_then(|counted| counted.linkoutput);
+
+        (linkoutput, (*p).tot_len as u64)
+    };
+
+    let Some(linkoutput) = linkoutput else {
+        return err_enum_t_ERR_IF as _;
+    };
+
+    let result = linkoutput(netif, p);
+
+    if let Some(counted) = COUNTED
+        .lock()
+        .iter_mut()
+        .find(|counted| counted.netif == netif)
+    {
+        if result == err_enum_t_ERR_OK as err_t {
+            counted.stats.tx_bytes += len;
+            counted.stats.tx_packets += 1;
+        } else {
+            counted.stats.tx_errors += 1;
+        }
+    }
+
+    result
+}
+
+/// The counters of a protocol of lwIP
+#[cfg(esp_idf_lwip_stats)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ProtocolStats {
+    pub xmit: u32,
+    pub recv: u32,
+    pub forwarded: u32,
+    pub dropped: u32,
+    pub checksum_errors: u32,
+    pub length_errors: u32,
+    pub memory_errors: u32,
+    pub routing_errors: u32,
+    pub protocol_errors: u32,
+    pub option_errors: u32,
+    pub errors: u32,
+}
+
+#[cfg(esp_idf_lwip_stats)]
+impl From<&stats_proto> for ProtocolStats {
+    fn from(stats: &stats_proto) -> Self {
+        Self {
+            xmit: stats.xmit as _,
+            recv: stats.recv as _,
+            forwarded: stats.fw as _,
+            dropped: stats.drop as _,
+            checksum_errors: stats.chkerr as _,
+            length_errors: stats.lenerr as _,
+            memory_errors: stats.memerr as _,
+            routing_errors: stats.rterr as _,
+            protocol_errors: stats.proterr as _,
+            option_errors: stats.opterr as _,
+            errors: stats.err as _,
+        }
+    }
+}
+
+/// The global counters of lwIP, for all the interfaces
+#[cfg(esp_idf_lwip_stats)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LwipStats {
+    pub link: ProtocolStats,
+    pub etharp: ProtocolStats,
+    pub ip: ProtocolStats,
+    pub icmp: ProtocolStats,
+    pub udp: ProtocolStats,
+    pub tcp: ProtocolStats,
+}
+
+/// The global counters of lwIP; they wrap at 65536 unless `LWIP_STATS_LARGE` is set
+#[cfg(esp_idf_lwip_stats)]
+pub fn lwip_stats() -> LwipStats {
+    // Counters updated without synchronization by lwIP itself; a torn read is harmless
+    let stats = unsafe { &*core::ptr::addr_of!(crate::sys::lwip_stats) };
+
+    LwipStats {
+        link: (&stats.link).into(),
+        etharp: (&stats.etharp).into(),
+        ip: (&stats.ip).into(),
+        icmp: (&stats.icmp).into(),
+        udp: (&stats.udp).into(),
+        tcp: (&stats.tcp).into(),
+    }
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
@@ -0,0 +1,544 @@
+//! Network connectivity supervisor
+//!
+//! `EspNetMgr` watches the actual internet reachability - not just whether an interface has an
+//! IP address - of a set of network interfaces (Ethernet, Wifi, PPP...), and keeps the default
+//! route on the most preferred interface which reaches the internet:
+//!
+//! ```ignore
+//! let mut netmgr = EspNetMgr::new(&sys_loop, &Default::default())?;
+//!
+//! netmgr.add_interface("eth", eth.netif(), 20);
+//! netmgr.add_interface("wifi", wifi.sta_netif(), 10);
+//!
+//! netmgr.run()?;
+//! ```
+//!
+//! Every `interval`, the interfaces which are up are probed by priority, with an HTTP request or a
+//! DNS query, until one reaches the internet; the default route then moves to it. The probe
+//! sockets are bound to the probed interface (`SO_BINDTODEVICE`), so that an interface is tested
+//! before the default route moves to it. When `failure_threshold` probes of an interface in a row
+//! fail, the interface is deemed without internet access, and is not probed for
+//! `recovery_interval`; the state becomes `Offline` once no interface is left to probe. Note that
+//! host names are resolved with the DNS servers of the default interface.
+//!
+//! The changes of connectivity are posted as `ConnectivityEvent`s to the system event loop.
+
+use core::convert::Infallible;
+use core::ffi::{c_int, c_void, CStr};
+use core::fmt::{self, Debug};
+use core::time::Duration;
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::time::Instant;
+use std::vec::Vec;
+
+use ::log::{debug, info, warn};
+
+use crate::eventloop::{
+    EspEvent, EspEventDeserializer, EspEventPostData, EspEventSerializer, EspEventSource,
+    EspSystemEventLoop,
+};
+use crate::handle::RawHandle;
+use crate::netif::EspNetif;
+use crate::sys::*;
+
+/// How the internet reachability is probed
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Probe<'a> {
+    /// An HTTP `GET` request, which must answer with the given status
+    Http {
+        host: &'a str,
+        port: u16,
+        path: &'a str,
+        status: u16,
+    },
+    /// A DNS query of the `A` record of `host`, which the server must answer
+    Dns { server: SocketAddr, host: &'a str },
+}
+
+impl Probe<'_> {
+    fn run(&self, netif: &EspNetif, timeout: Duration) -> io::Result<bool> {
+        match self {
+            Self::Http {
+                host,
+                port,
+                path,
+                status,
+            } => probe_http(netif, host, *port, path, *status, timeout),
+            Self::Dns { server, host } => probe_dns(netif, *server, host, timeout),
+        }
+    }
+}
+
+/// The configuration of the connectivity supervisor
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NetMgrConfiguration<'a> {
+    pub probe: Probe<'a>,
+    /// The interval between two probes
+    pub interval: Duration,
+    /// The timeout of a probe
+    pub timeout: Duration,
+    /// The number of probes of an interface in a row which must fail for it to be deemed
+    /// without internet access
+    pub failure_threshold: u8,
+    /// How long an interface without internet access is not used for the default route
+    pub recovery_interval: Duration,
+}
+
+impl NetMgrConfiguration<'_> {
+    pub const fn new() -> Self {
+        Self {
+            probe: Probe::Http {
+                host: "connectivitycheck.gstatic.com",
+                port: 80,
+                path: "/generate_204",
+                status: 204,
+            },
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            failure_threshold: 3,
+            recovery_interval: Duration::from_secs(300),
+        }
+    }
+}
+
+impl Default for NetMgrConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The connectivity state
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectivityState {
+    /// Not probed yet
+    Unknown,
+    /// The internet is reachable through the given interface, which has the default route
+    Online { interface: &'static str },
+    /// No interface reaches the internet
+    Offline,
+}
+
+/// A connectivity event
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectivityEvent {
+    /// The connectivity state changed
+    StateChanged(ConnectivityState),
+    /// The default route moved to another interface
+    Switched {
+        from: Option<&'static str>,
+        to: &'static str,
+    },
+}
+
+unsafe impl EspEventSource for ConnectivityEvent {
+    fn source() -> Option<&'static CStr> {
+        Some(CStr::from_bytes_with_nul(b"ESP-SVC-NETMGR\0").unwrap())
+    }
+}
+
+impl EspEventSerializer for ConnectivityEvent {
+    type Data<'a> = ConnectivityEvent;
+
+    fn serialize<F, R>(event: &Self::Data<'_>, f: F) -> R
+    where
+        F: FnOnce(&EspEventPostData) -> R,
+    {
+        f(&unsafe { EspEventPostData::new(Self::source().unwrap(), Self::event_id(), event) })
+    }
+}
+
+impl EspEventDeserializer for ConnectivityEvent {
+    type Data<'a> = ConnectivityEvent;
+
+    fn deserialize<'a>(data: &EspEvent<'a>) -> Self::Data<'a> {
+        *unsafe { data.as_payload::<ConnectivityEvent>() }
+    }
+}
+
+struct Interface<'a> {
+    name: &'static str,
+    netif: &'a EspNetif,
+    priority: u8,
+    // The probes failed in a row
+    failures: u8,
+    failed_at: Option<Instant>,
+}
+
+/// The connectivity supervisor
+pub struct EspNetMgr<'a> {
+    sys_loop: EspSystemEventLoop,
+    conf: NetMgrConfiguration<'a>,
+    // Sorted by descending priority
+    interfaces: Vec<Interface<'a>>,
+    state: ConnectivityState,
+    next_probe: Option<Instant>,
+}
+
+impl<'a> EspNetMgr<'a> {
+    pub fn new(
+        sys_loop: &EspSystemEventLoop,
+        conf: &NetMgrConfiguration<'a>,
+    ) -> Result<Self, EspError> {
+        if conf.failure_threshold == 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        Ok(Self {
+            sys_loop: sys_loop.clone(),
+            conf: conf.clone(),
+            interfaces: Vec::new(),
+            state: ConnectivityState::Unknown,
+            next_probe: None,
+        })
+    }
+
+    /// Supervise an interface; the higher the priority, the more preferred the interface is for
+    /// the default route
+    pub fn add_interface(&mut self, name: &'static str, netif: &'a EspNetif, priority: u8) {
+        self.interfaces.push(Interface {
+            name,
+            netif,
+            priority,
+            failures: 0,
+            failed_at: None,
+        });
+
+        // Stable, so that equal priorities keep the order in which they were added
+        self.interfaces.sort_by(|a, b| b.priority.cmp(&a.priority));
+
+        self.next_probe = None;
+    }
+
+    /// The connectivity state, as of the last probe
+    pub fn state(&self) -> ConnectivityState {
+        self.state
+    }
+
+    /// The name of the interface with the default route, if it is one of the supervised ones
+    pub fn current(&self) -> Option<&'static str> {
+        let default = unsafe { esp_netif_get_default_netif() };
+
+        self.interfaces
+            .iter()
+            .find(|interface| interface.netif.handle() == default)
+            .map(|interface| interface.name)
+    }
+
+    /// Probe the interfaces, and move the default route if needed, once the probe interval
+    /// elapsed; return the connectivity state
+    pub fn poll(&mut self) -> Result<ConnectivityState, EspError> {
+        let now = Instant::now();
+
+        if self.next_probe.is_some_and(|next_probe| now < next_probe) {
+            return Ok(self.state);
+        }
+
+        self.next_probe = Some(now + self.conf.interval);
+
+        // Whether an interface failed, but is not deemed without internet access yet
+        let mut pending = false;
+
+        for index in 0..self.interfaces.len() {
+            if !self.is_candidate(index, now)? {
+                continue;
+            }
+
+            let interface = &mut self.interfaces[index];
+            let name = interface.name;
+
+            let reachable = match self.conf.probe.run(interface.netif, self.conf.timeout) {
+                Ok(reachable) => reachable,
+                Err(err) => {
+                    debug!("Probe through {name} failed: {err}");
+                    false
+                }
+            };
+
+            if reachable {
+                interface.failures = 0;
+
+                if self.current() != Some(name) {
+                    self.switch(index)?;
+                }
+
+                self.set_state(ConnectivityState::Online { interface: name });
+
+                return Ok(self.state);
+            }
+
+            interface.failures += 1;
+
+            if interface.failures >= self.conf.failure_threshold {
+                warn!("No internet access through {name}");
+
+                interface.failures = 0;
+                interface.failed_at = Some(now);
+            } else if self.current() == Some(name) {
+                // The default route stays on the interface until it is deemed without internet
+                // access
+                return Ok(self.state);
+            } else {
+                pending = true;
+            }
+        }
+
+        if !pending {
+            self.set_state(ConnectivityState::Offline);
+        }
+
+        Ok(self.state)
+    }
+
+    /// Supervise the interfaces forever, or until an error
+    pub fn run(&mut self) -> Result<Infallible, EspError> {
+        loop {
+            self.poll()?;
+
+            if let Some(next_probe) = self.next_probe {
+                std::thread::sleep(next_probe.saturating_duration_since(Instant::now()));
+            }
+        }
+    }
+
+    pub fn sys_loop(&self) -> &EspSystemEventLoop {
+        &self.sys_loop
+    }
+
+    // Whether the interface is up and not deemed without internet access
+    fn is_candidate(&mut self, index: usize, now: Instant) -> Result<bool, EspError> {
+        let recovery_interval = self.conf.recovery_interval;
+        let interface = &mut self.interfaces[index];
+
+        if interface
+            .failed_at
+            .is_some_and(|failed_at| now.duration_since(failed_at) < recovery_interval)
+        {
+            return Ok(false);
+        }
+
+        interface.failed_at = None;
+
+        interface.netif.is_up()
+    }
+
+    fn switch(&mut self, index: usize) -> Result<(), EspError> {
+        let from = self.current();
+        let to = self.interfaces[index].name;
+
+        esp!(unsafe { esp_netif_set_default_netif(self.interfaces[index].netif.handle()) })?;
+
+        info!("Default route moved from {from:?} to {to}");
+
+        self.post(ConnectivityEvent::Switched { from, to });
+
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: ConnectivityState) {
+        if self.state != state {
+            info!("Connectivity: {state:?}");
+
+            self.state = state;
+            self.post(ConnectivityEvent::StateChanged(state));
+        }
+    }
+
+    fn post(&self, event: ConnectivityEvent) {
+        if !matches!(self.sys_loop.post::<ConnectivityEvent>(&event, 0), Ok(true)) {
+            warn!("Failed to post {event:?}");
+        }
+    }
+}
+
+impl Debug for EspNetMgr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspNetMgr")
+            .field("conf", &self.conf)
+            .field("interfaces", &self.interfaces.len())
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+fn probe_http(
+    netif: &EspNetif,
+    host: &str,
+    port: u16,
+    path: &str,
+    status: u16,
+    timeout: Duration,
+) -> io::Result<bool> {
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or(io::ErrorKind::NotFound)?;
+
+    let mut stream = connect(netif, &addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"
+    )?;
+
+    // Only the status line matters, e.g. `HTTP/1.1 204 No Content`
+    let mut buf = [0; 16];
+    let mut len = 0;
+
+    while len < buf.len() {
+        match stream.read(&mut buf[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+
+    let line = core::str::from_utf8(&buf[..len]).unwrap_or_default();
+
+    Ok(line.starts_with("HTTP/")
+        && line
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            == Some(status))
+}
+
+fn probe_dns(
+    netif: &EspNetif,
+    server: SocketAddr,
+    host: &str,
+    timeout: Duration,
+) -> io::Result<bool> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    bind_to_device(socket.as_raw_fd(), netif)?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let id = (unsafe { esp_random() } as u16).to_be_bytes();
+
+    // Header: ID, recursion desired, one question
+    let mut query = Vec::with_capacity(18 + host.len());
+    query.extend_from_slice(&[id[0], id[1], 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        query.push(label.len() as _);
+        query.extend_from_slice(label.as_bytes());
+    }
+
+    // Root label, type A, class IN
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+
+    socket.send_to(&query, server)?;
+
+    let mut buf = [0; 512];
+    let (len, from) = socket.recv_from(&mut buf)?;
+
+    let response = &buf[..len];
+
+    Ok(from == server
+        && response.len() >= 12
+        && response[..2] == id
+        // A response, without error
+        && response[2] & 0x80 != 0
+        && response[3] & 0x0f == 0
+        // With at least one answer
+        && u16::from_be_bytes([response[6], response[7]]) > 0)
+}
+
+// Connect through `netif`, whatever the default route
+fn connect(netif: &EspNetif, addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    let SocketAddr::V4(addr) = addr else {
+        return Err(io::ErrorKind::Unsupported.into());
+    };
+
+    let fd = unsafe { lwip_socket(AF_INET as _, SOCK_STREAM as _, IPPROTO_TCP as _) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Owned, and closed on error, from now on
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    bind_to_device(fd, netif)?;
+
+    // Not to wait for the SYN retransmissions beyond the timeout
+    stream.set_nonblocking(true)?;
+
+    let sockaddr = sockaddr_in {
+        sin_len: core::mem::size_of::<sockaddr_in>() as _,
+        sin_family: AF_INET as _,
+        sin_port: addr.port().to_be(),
+        sin_addr: in_addr {
+            s_addr: u32::from_ne_bytes(addr.ip().octets()),
+        },
+        sin_zero: Default::default(),
+    };
+
+    if unsafe {
+        lwip_connect(
+            fd,
+            &sockaddr as *const _ as *const sockaddr,
+            core::mem::size_of::<sockaddr_in>() as _,
+        )
+    } < 0
+    {
+        let err = io::Error::last_os_error();
+
+        if err.raw_os_error() != Some(EINPROGRESS as _) {
+            return Err(err);
+        }
+
+        let mut pending = pollfd {
+            fd,
+            events: POLLOUT as _,
+            revents: 0,
+        };
+
+        match unsafe { poll(&mut pending, 1, timeout.as_millis() as _) } {
+            0 => return Err(io::ErrorKind::TimedOut.into()),
+            ret if ret < 0 => return Err(io::Error::last_os_error()),
+            _ => (),
+        }
+
+        if let Some(err) = stream.take_error()? {
+            return Err(err);
+        }
+    }
+
+    stream.set_nonblocking(false)?;
+
+    Ok(stream)
+}
+
+// Send the packets of the socket through `netif`, whatever the default route
+fn bind_to_device(fd: c_int, netif: &EspNetif) -> io::Result<()> {
+    let mut ifreq = ifreq {
+        ifr_name: Default::default(),
+    };
+
+    for (dst, src) in ifreq.ifr_name.iter_mut().zip(netif.get_name().bytes()) {
+        *dst = src as _;
+    }
+
+    let ret = unsafe {
+        lwip_setsockopt(
+            fd,
+            SOL_SOCKET as _,
+            SO_BINDTODEVICE as _,
+            &ifreq as *const _ as *const c_void,
+            core::mem::size_of::<ifreq>() as _,
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
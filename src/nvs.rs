@@ -14,6 +14,9 @@ use crate::handle::RawHandle;
 use crate::private::cstr::*;
 use crate::private::mutex;
 
+#[cfg(not(esp_idf_version_major = "4"))]
+pub mod backup;
+
 static DEFAULT_TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
 static NONDEFAULT_LOCKED: mutex::Mutex<alloc::collections::BTreeSet<CString>> =
     mutex::Mutex::new(alloc::collections::BTreeSet::new());
@@ -268,7 +271,7 @@ pub type EspCustomNvs = EspNvs<NvsCustom>;
 pub type EspEncryptedNvs = EspNvs<NvsEncrypted>;
 
 #[allow(dead_code)]
-pub struct EspNvs<T: NvsPartitionId>(EspNvsPartition<T>, nvs_handle_t);
+pub struct EspNvs<T: NvsPartitionId>(EspNvsPartition<T>, nvs_handle_t, CString);
 
 impl<T: NvsPartitionId> EspNvs<T> {
     pub fn new(
@@ -307,7 +310,7 @@ impl<T: NvsPartitionId> EspNvs<T> {
             })?;
         }
 
-        Ok(Self(partition, handle))
+        Ok(Self(partition, handle, c_namespace))
     }
 
     pub fn contains(&self, name: &str) -> Result<bool, EspError> {
@@ -0,0 +1,742 @@
+//! Export and import of the keys of an NVS namespace
+//!
+//! `EspNvs::export` serializes all the keys of the namespace of a handle, with their types, and
+//! `EspNvs::import` restores them, e.g. to back up the settings of a device over HTTP or MQTT,
+//! or to preload them in the factory:
+//!
+//! ```ignore
+//! let nvs = EspNvs::new(partition, "settings", true)?;
+//!
+//! nvs.export(&mut response, NvsBackupFormat::Binary)?;
+//! // ... and later, on this device or another one
+//! nvs.import(&mut request, NvsBackupFormat::Binary)?;
+//! ```
+//!
+//! Two formats are supported:
+//! - `Binary`: a compact format with a version and a CRC32, for backups
+//! - `Csv`: the CSV format of the `nvs_partition_gen.py` tool of the ESP-IDF, with `u8` to `i64`,
+//!   `string`, `hex2bin` and `base64` entries; an exported namespace can thus be flashed with
+//!   a generated partition, and the CSV files of the tool can be imported
+//!
+//! An import is parsed and checked as a whole before any key is written.
+
+use core::fmt::{self, Debug, Display};
+use core::ptr;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::io::{Read, Write};
+
+use crate::sys::*;
+
+use crate::private::base64;
+use crate::private::cstr::*;
+
+use super::{EspNvs, NvsPartitionId};
+
+const MAGIC: &[u8; 4] = b"NVSB";
+const VERSION: u8 = 1;
+const END: u8 = 0xff;
+
+/// The maximum length of a key, without its terminating NUL
+const MAX_KEY_LEN: usize = 15;
+
+/// The format of an NVS backup
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NvsBackupFormat {
+    /// A compact binary format, with a version and a CRC32
+    Binary,
+    /// The CSV format of `nvs_partition_gen.py`
+    Csv,
+}
+
+/// The type of the value of an NVS key
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NvsDataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    Str,
+    Blob,
+}
+
+impl NvsDataType {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(raw: nvs_type_t) -> Option<Self> {
+        Some(match raw {
+            nvs_type_t_NVS_TYPE_U8 => Self::U8,
+            nvs_type_t_NVS_TYPE_I8 => Self::I8,
+            nvs_type_t_NVS_TYPE_U16 => Self::U16,
+            nvs_type_t_NVS_TYPE_I16 => Self::I16,
+            nvs_type_t_NVS_TYPE_U32 => Self::U32,
+            nvs_type_t_NVS_TYPE_I32 => Self::I32,
+            nvs_type_t_NVS_TYPE_U64 => Self::U64,
+            nvs_type_t_NVS_TYPE_I64 => Self::I64,
+            nvs_type_t_NVS_TYPE_STR => Self::Str,
+            nvs_type_t_NVS_TYPE_BLOB => Self::Blob,
+            _ => return None,
+        })
+    }
+
+    fn raw(&self) -> nvs_type_t {
+        match self {
+            Self::U8 => nvs_type_t_NVS_TYPE_U8,
+            Self::I8 => nvs_type_t_NVS_TYPE_I8,
+            Self::U16 => nvs_type_t_NVS_TYPE_U16,
+            Self::I16 => nvs_type_t_NVS_TYPE_I16,
+            Self::U32 => nvs_type_t_NVS_TYPE_U32,
+            Self::I32 => nvs_type_t_NVS_TYPE_I32,
+            Self::U64 => nvs_type_t_NVS_TYPE_U64,
+            Self::I64 => nvs_type_t_NVS_TYPE_I64,
+            Self::Str => nvs_type_t_NVS_TYPE_STR,
+            Self::Blob => nvs_type_t_NVS_TYPE_BLOB,
+        }
+    }
+
+    /// The encoding of the type in the CSV format of `nvs_partition_gen.py`
+    fn encoding(&self) -> &'static str {
+        match self {
+            Self::U8 => "u8",
+            Self::I8 => "i8",
+            Self::U16 => "u16",
+            Self::I16 => "i16",
+            Self::U32 => "u32",
+            Self::I32 => "i32",
+            Self::U64 => "u64",
+            Self::I64 => "i64",
+            Self::Str => "string",
+            Self::Blob => "hex2bin",
+        }
+    }
+}
+
+/// An error of an NVS export or import
+#[derive(Debug)]
+pub enum NvsBackupError<E> {
+    /// The writer or the reader failed
+    Io(E),
+    /// The NVS failed
+    Esp(EspError),
+    /// The backup is malformed, or its checksum does not match
+    Malformed(&'static str),
+    /// The backup has a version of the binary format which is not supported
+    Version(u8),
+}
+
+impl<E> Display for NvsBackupError<E>
+where
+    E: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "IO error: {err:?}"),
+            Self::Esp(err) => write!(f, "{err}"),
+            Self::Malformed(reason) => write!(f, "Malformed backup: {reason}"),
+            Self::Version(version) => write!(f, "Unsupported backup version {version}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for NvsBackupError<E> where E: Debug {}
+
+impl<E> From<EspError> for NvsBackupError<E> {
+    fn from(err: EspError) -> Self {
+        Self::Esp(err)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    fn data_type(&self) -> NvsDataType {
+        match self {
+            Self::U8(_) => NvsDataType::U8,
+            Self::I8(_) => NvsDataType::I8,
+            Self::U16(_) => NvsDataType::U16,
+            Self::I16(_) => NvsDataType::I16,
+            Self::U32(_) => NvsDataType::U32,
+            Self::I32(_) => NvsDataType::I32,
+            Self::U64(_) => NvsDataType::U64,
+            Self::I64(_) => NvsDataType::I64,
+            Self::Str(_) => NvsDataType::Str,
+            Self::Blob(_) => NvsDataType::Blob,
+        }
+    }
+
+    /// The little-endian bytes of the value, for the binary format
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::U8(v) => v.to_le_bytes().to_vec(),
+            Self::I8(v) => v.to_le_bytes().to_vec(),
+            Self::U16(v) => v.to_le_bytes().to_vec(),
+            Self::I16(v) => v.to_le_bytes().to_vec(),
+            Self::U32(v) => v.to_le_bytes().to_vec(),
+            Self::I32(v) => v.to_le_bytes().to_vec(),
+            Self::U64(v) => v.to_le_bytes().to_vec(),
+            Self::I64(v) => v.to_le_bytes().to_vec(),
+            Self::Str(v) => v.as_bytes().to_vec(),
+            Self::Blob(v) => v.clone(),
+        }
+    }
+
+    fn from_bytes(data_type: NvsDataType, bytes: &[u8]) -> Option<Self> {
+        Some(match data_type {
+            NvsDataType::U8 => Self::U8(u8::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::I8 => Self::I8(i8::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::U16 => Self::U16(u16::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::I16 => Self::I16(i16::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::U32 => Self::U32(u32::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::I32 => Self::I32(i32::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::U64 => Self::U64(u64::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::I64 => Self::I64(i64::from_le_bytes(bytes.try_into().ok()?)),
+            NvsDataType::Str => Self::Str(core::str::from_utf8(bytes).ok()?.to_string()),
+            NvsDataType::Blob => Self::Blob(bytes.to_vec()),
+        })
+    }
+
+    /// The value as encoded in the CSV format of `nvs_partition_gen.py`
+    fn to_csv(&self) -> String {
+        match self {
+            Self::U8(v) => v.to_string(),
+            Self::I8(v) => v.to_string(),
+            Self::U16(v) => v.to_string(),
+            Self::I16(v) => v.to_string(),
+            Self::U32(v) => v.to_string(),
+            Self::I32(v) => v.to_string(),
+            Self::U64(v) => v.to_string(),
+            Self::I64(v) => v.to_string(),
+            Self::Str(v) => v.clone(),
+            Self::Blob(v) => v.iter().map(|byte| alloc::format!("{byte:02x}")).collect(),
+        }
+    }
+
+    fn from_csv(encoding: &str, value: &str) -> Option<Self> {
+        Some(match encoding {
+            "u8" => Self::U8(parse_int(value)?.try_into().ok()?),
+            "i8" => Self::I8(parse_int(value)?.try_into().ok()?),
+            "u16" => Self::U16(parse_int(value)?.try_into().ok()?),
+            "i16" => Self::I16(parse_int(value)?.try_into().ok()?),
+            "u32" => Self::U32(parse_int(value)?.try_into().ok()?),
+            "i32" => Self::I32(parse_int(value)?.try_into().ok()?),
+            "u64" => Self::U64(parse_int(value)?.try_into().ok()?),
+            "i64" => Self::I64(parse_int(value)?.try_into().ok()?),
+            "string" => Self::Str(value.to_string()),
+            "hex2bin" => Self::Blob(decode_hex(value.trim())?),
+            "base64" => Self::Blob(base64::decode(value)?),
+            _ => return None,
+        })
+    }
+}
+
+impl<T: NvsPartitionId> EspNvs<T> {
+    /// The keys of the namespace, with the types of their values
+    pub fn keys(&self) -> Result<Vec<(String, NvsDataType)>, EspError> {
+        let partition = if self.0 .0.is_default() {
+            c"nvs"
+        } else {
+            self.0 .0.name()
+        };
+
+        let mut iterator = ptr::null_mut();
+
+        let ret = unsafe {
+            nvs_entry_find(
+                partition.as_ptr(),
+                self.2.as_ptr(),
+                nvs_type_t_NVS_TYPE_ANY,
+                &mut iterator,
+            )
+        };
+
+        let mut keys: Vec<(String, NvsDataType)> = Vec::new();
+
+        if ret == ESP_ERR_NVS_NOT_FOUND {
+            return Ok(keys);
+        }
+
+        esp!(ret)?;
+
+        while !iterator.is_null() {
+            let mut info: nvs_entry_info_t = Default::default();
+            unsafe { nvs_entry_info(iterator, &mut info) };
+
+            let key = unsafe { from_cstr_ptr(info.key.as_ptr()) };
+
+            match NvsDataType::from_raw(info.type_) {
+                Some(data_type) => {
+                    if !keys.iter().any(|(other, _)| other == key) {
+                        keys.push((key.to_string(), data_type));
+                    }
+                }
+                None => warn!("Skipping key {} of unknown type 0x{:02x}", key, info.type_),
+            }
+
+            if unsafe { nvs_entry_next(&mut iterator) } != ESP_OK {
+                break;
+            }
+        }
+
+        unsafe { nvs_release_iterator(iterator) };
+
+        Ok(keys)
+    }
+
+    /// Serialize all the keys of the namespace to `write`
+    ///
+    /// Returns the number of keys exported.
+    pub fn export<W>(
+        &self,
+        mut write: W,
+        format: NvsBackupFormat,
+    ) -> Result<usize, NvsBackupError<W::Error>>
+    where
+        W: Write,
+    {
+        let namespace = self.2.to_str().unwrap_or_default();
+
+        let entries = self
+            .keys()?
+            .into_iter()
+            .map(|(key, data_type)| Ok((self.get_value(&key, data_type)?, key)))
+            .collect::<Result<Vec<_>, EspError>>()?;
+
+        let mut out = Vec::new();
+
+        match format {
+            NvsBackupFormat::Binary => {
+                out.extend_from_slice(MAGIC);
+                out.push(VERSION);
+                out.push(namespace.len() as u8);
+                out.extend_from_slice(namespace.as_bytes());
+
+                for (value, key) in &entries {
+                    let bytes = value.to_bytes();
+
+                    out.push(value.data_type().raw() as u8);
+                    out.push(key.len() as u8);
+                    out.extend_from_slice(key.as_bytes());
+                    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                    out.extend_from_slice(&bytes);
+                }
+
+                out.push(END);
+
+                let crc = crc32(&out);
+                out.extend_from_slice(&crc.to_le_bytes());
+            }
+            NvsBackupFormat::Csv => {
+                out.extend_from_slice(b"key,type,encoding,value\n");
+                push_csv_row(&mut out, &[namespace, "namespace", "", ""]);
+
+                for (value, key) in &entries {
+                    push_csv_row(
+                        &mut out,
+                        &[key, "data", value.data_type().encoding(), &value.to_csv()],
+                    );
+                }
+            }
+        }
+
+        write.write_all(&out).map_err(NvsBackupError::Io)?;
+        write.flush().map_err(NvsBackupError::Io)?;
+
+        info!(
+            "Exported {} keys of namespace {} as {:?}",
+            entries.len(),
+            namespace,
+            format
+        );
+
+        Ok(entries.len())
+    }
+
+    /// Restore the keys serialized by `export` - or by `nvs_partition_gen.py` - from `read`
+    ///
+    /// The keys are written to the namespace of the handle, replacing the existing values of the
+    /// same keys. A CSV file may hold several namespaces; only the keys of the namespace of the
+    /// handle are imported then. Returns the number of keys imported.
+    pub fn import<R>(
+        &mut self,
+        mut read: R,
+        format: NvsBackupFormat,
+    ) -> Result<usize, NvsBackupError<R::Error>>
+    where
+        R: Read,
+    {
+        let mut data = Vec::new();
+        let mut buf = [0_u8; 256];
+
+        loop {
+            let len = read.read(&mut buf).map_err(NvsBackupError::Io)?;
+
+            if len == 0 {
+                break;
+            }
+
+            data.extend_from_slice(&buf[..len]);
+        }
+
+        let namespace = self.2.to_str().unwrap_or_default();
+
+        let entries = match format {
+            NvsBackupFormat::Binary => parse_binary(&data)?,
+            NvsBackupFormat::Csv => parse_csv(&data, namespace)?,
+        };
+
+        for (key, value) in &entries {
+            self.put_value(key, value)?;
+        }
+
+        esp!(unsafe { nvs_commit(self.1) })?;
+
+        info!(
+            "Imported {} keys into namespace {} from {:?}",
+            entries.len(),
+            namespace,
+            format
+        );
+
+        Ok(entries.len())
+    }
+
+    fn get_value(&self, key: &str, data_type: NvsDataType) -> Result<Value, EspError> {
+        let c_key = to_cstring_arg(key)?;
+        let key = c_key.as_ptr();
+
+        let value = match data_type {
+            NvsDataType::U8 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_u8(self.1, key, &mut v) })?;
+                Value::U8(v)
+            }
+            NvsDataType::I8 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_i8(self.1, key, &mut v) })?;
+                Value::I8(v)
+            }
+            NvsDataType::U16 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_u16(self.1, key, &mut v) })?;
+                Value::U16(v)
+            }
+            NvsDataType::I16 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_i16(self.1, key, &mut v) })?;
+                Value::I16(v)
+            }
+            NvsDataType::U32 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_u32(self.1, key, &mut v) })?;
+                Value::U32(v)
+            }
+            NvsDataType::I32 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_i32(self.1, key, &mut v) })?;
+                Value::I32(v)
+            }
+            NvsDataType::U64 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_u64(self.1, key, &mut v) })?;
+                Value::U64(v)
+            }
+            NvsDataType::I64 => {
+                let mut v = 0;
+                esp!(unsafe { nvs_get_i64(self.1, key, &mut v) })?;
+                Value::I64(v)
+            }
+            NvsDataType::Str => {
+                let mut len = 0;
+                esp!(unsafe { nvs_get_str(self.1, key, ptr::null_mut(), &mut len) })?;
+
+                let mut buf = alloc::vec![0_u8; len];
+                esp!(unsafe { nvs_get_str(self.1, key, buf.as_mut_ptr() as *mut _, &mut len) })?;
+
+                Value::Str(from_cstr(&buf).to_string())
+            }
+            NvsDataType::Blob => {
+                let mut len = 0;
+                esp!(unsafe { nvs_get_blob(self.1, key, ptr::null_mut(), &mut len) })?;
+
+                let mut buf = alloc::vec![0_u8; len];
+                esp!(unsafe { nvs_get_blob(self.1, key, buf.as_mut_ptr() as *mut _, &mut len) })?;
+                buf.truncate(len);
+
+                Value::Blob(buf)
+            }
+        };
+
+        Ok(value)
+    }
+
+    /// Write a value, without committing it
+    fn put_value(&mut self, key: &str, value: &Value) -> Result<(), EspError> {
+        let c_key = to_cstring_arg(key)?;
+        let key = c_key.as_ptr();
+
+        // A key has a single value, of whatever type
+        let ret = unsafe { nvs_erase_key(self.1, key) };
+        if ret != ESP_ERR_NVS_NOT_FOUND {
+            esp!(ret)?;
+        }
+
+        esp!(unsafe {
+            match value {
+                Value::U8(v) => nvs_set_u8(self.1, key, *v),
+                Value::I8(v) => nvs_set_i8(self.1, key, *v),
+                Value::U16(v) => nvs_set_u16(self.1, key, *v),
+                Value::I16(v) => nvs_set_i16(self.1, key, *v),
+                Value::U32(v) => nvs_set_u32(self.1, key, *v),
+                Value::I32(v) => nvs_set_i32(self.1, key, *v),
+                Value::U64(v) => nvs_set_u64(self.1, key, *v),
+                Value::I64(v) => nvs_set_i64(self.1, key, *v),
+                Value::Str(v) => {
+                    let c_value = to_cstring_arg(v)?;
+                    nvs_set_str(self.1, key, c_value.as_ptr())
+                }
+                Value::Blob(v) => nvs_set_blob(self.1, key, v.as_ptr().cast(), v.len()),
+            }
+        })
+    }
+}
+
+fn parse_binary<E>(data: &[u8]) -> Result<Vec<(String, Value)>, NvsBackupError<E>> {
+    const TRUNCATED: &str = "truncated";
+
+    if data.len() < MAGIC.len() + 1 || &data[..MAGIC.len()] != MAGIC {
+        return Err(NvsBackupError::Malformed("not a binary NVS backup"));
+    }
+
+    if data[MAGIC.len()] != VERSION {
+        return Err(NvsBackupError::Version(data[MAGIC.len()]));
+    }
+
+    let (body, crc) = data
+        .split_last_chunk::<4>()
+        .ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+
+    if crc32(body) != u32::from_le_bytes(*crc) {
+        return Err(NvsBackupError::Malformed("checksum mismatch"));
+    }
+
+    let mut cursor = Cursor(&body[MAGIC.len() + 1..]);
+
+    let namespace_len = cursor.u8().ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+    cursor
+        .take(namespace_len as _)
+        .ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+
+    let mut entries = Vec::new();
+
+    loop {
+        let raw_type = cursor.u8().ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+
+        if raw_type == END {
+            break;
+        }
+
+        let data_type = NvsDataType::from_raw(raw_type as _)
+            .ok_or(NvsBackupError::Malformed("unknown value type"))?;
+
+        let key_len = cursor.u8().ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+        let key = cursor
+            .take(key_len as _)
+            .ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+        let key = check_key(core::str::from_utf8(key).ok())?;
+
+        let value_len = cursor.take(4).ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+        let value_len = u32::from_le_bytes(value_len.try_into().unwrap());
+
+        let value = cursor
+            .take(value_len as _)
+            .ok_or(NvsBackupError::Malformed(TRUNCATED))?;
+        let value = Value::from_bytes(data_type, value)
+            .ok_or(NvsBackupError::Malformed("invalid value"))?;
+
+        entries.push((key.to_string(), value));
+    }
+
+    Ok(entries)
+}
+
+fn parse_csv<E>(data: &[u8], namespace: &str) -> Result<Vec<(String, Value)>, NvsBackupError<E>> {
+    let data = core::str::from_utf8(data).map_err(|_| NvsBackupError::Malformed("not UTF-8"))?;
+
+    let mut entries = Vec::new();
+
+    // The keys before the first namespace entry are imported as well
+    let mut in_namespace = true;
+
+    for (index, row) in csv_rows(data)?.into_iter().enumerate() {
+        let field = |i: usize| row.get(i).map(String::as_str).unwrap_or_default();
+
+        if index == 0 && field(0) == "key" {
+            continue;
+        }
+
+        match field(1) {
+            "namespace" => in_namespace = field(0) == namespace,
+            "data" if in_namespace => {
+                let key = check_key(Some(field(0)))?;
+                let value = Value::from_csv(field(2), field(3))
+                    .ok_or(NvsBackupError::Malformed("invalid or unsupported value"))?;
+
+                entries.push((key.to_string(), value));
+            }
+            "data" => (),
+            "file" => return Err(NvsBackupError::Malformed("file entries are not supported")),
+            _ => return Err(NvsBackupError::Malformed("unknown entry type")),
+        }
+    }
+
+    Ok(entries)
+}
+
+fn check_key<E>(key: Option<&str>) -> Result<&str, NvsBackupError<E>> {
+    match key {
+        Some(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => Ok(key),
+        _ => Err(NvsBackupError::Malformed("invalid key")),
+    }
+}
+
+/// Split CSV data into rows of fields, unquoting the quoted fields
+fn csv_rows<E>(data: &str) -> Result<Vec<Vec<String>>, NvsBackupError<E>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+
+    let mut chars = data.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => row.push(core::mem::take(&mut field)),
+            '\r' if !quoted => (),
+            '\n' if !quoted => {
+                row.push(core::mem::take(&mut field));
+
+                if row.iter().any(|field| !field.is_empty()) {
+                    rows.push(core::mem::take(&mut row));
+                } else {
+                    row.clear();
+                }
+            }
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(NvsBackupError::Malformed("unterminated quote"));
+    }
+
+    row.push(field);
+
+    if row.iter().any(|field| !field.is_empty()) {
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+fn push_csv_row(out: &mut Vec<u8>, fields: &[&str]) {
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            out.push(b',');
+        }
+
+        if field.contains([',', '"', '\n', '\r']) {
+            out.push(b'"');
+            out.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+            out.push(b'"');
+        } else {
+            out.extend_from_slice(field.as_bytes());
+        }
+    }
+
+    out.push(b'\n');
+}
+
+/// Parse a decimal or a `0x` prefixed hexadecimal integer
+fn parse_int(value: &str) -> Option<i128> {
+    let value = value.trim();
+
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+
+    let magnitude = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The CRC32 (IEEE 802.3) of `data`
+fn crc32(data: &[u8]) -> u32 {
+    unsafe { esp_rom_crc32_le(0, data.as_ptr(), data.len() as _) }
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Some(taken)
+    }
+}
@@ -0,0 +1,386 @@
+//! Thread networking, with the OpenThread stack
+//!
+//! On the chips with an 802.15.4 radio - the ESP32-C6 and the ESP32-H2 - `EspThread` runs
+//! the OpenThread stack on the native radio, and attaches it to an lwIP netif, so that the
+//! device is reachable over IPv6 - e.g. with a `std::net::UdpSocket`, or the `coap` module.
+//!
+//! The device joins a network with the operational dataset provisioned by the commissioner -
+//! e.g. the TLVs exported by `ot-ctl dataset active -x` on the border router - and attaches
+//! as an end device or - with a full thread device build - as a router:
+//!
+//! ```ignore
+//! use esp_idf_svc::openthread::{EspThread, ThreadEvent, ThreadMode};
+//!
+//! let mut thread = EspThread::new(nvs_partition, sys_loop.clone())?;
+//!
+//! thread.set_mode(ThreadMode::EndDevice)?;
+//! thread.set_active_dataset_tlvs(&dataset)?;
+//! thread.start()?;
+//!
+//! let _subscription = sys_loop.subscribe::<ThreadEvent, _>(|event| {
+//!     if let ThreadEvent::RoleChanged { current, .. } = event {
+//!         log::info!("Thread role: {current:?}");
+//!     }
+//! })?;
+//! ```
+//!
+//! The OpenThread stack runs in its own task, for the lifetime of the program: dropping
+//! `EspThread` detaches the device from the network, but does not deinitialize the stack.
+
+use core::ffi::{self, CStr};
+use core::ptr;
+use core::time::Duration;
+
+use ::log::info;
+
+use crate::eventloop::{EspEvent, EspEventDeserializer, EspEventSource, EspSystemEventLoop};
+use crate::netif::NetifStack;
+use crate::nvs::EspDefaultNvsPartition;
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+// As `esp!`, for the errors of the OpenThread API
+macro_rules! ot {
+    ($err:expr) => {{
+        let err = $err;
+
+        if err == otError_OT_ERROR_NONE {
+            Ok(())
+        } else {
+            ::log::warn!("OpenThread error: {}", err);
+
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        }
+    }};
+}
+
+/// The maximum size of an operational dataset, in TLVs
+pub const MAX_DATASET_TLVS_LEN: usize = 254;
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+// The netif of the stack, once initialized; the stack is never deinitialized, as its task
+// can not be stopped
+static NETIF: Mutex<Option<Netif>> = Mutex::new(None);
+
+struct Netif(*mut esp_netif_t);
+
+unsafe impl Send for Netif {}
+
+/// The role of the device in the Thread network
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ThreadRole {
+    Disabled,
+    /// Not attached to a network
+    Detached,
+    /// Attached as an end device
+    Child,
+    Router,
+    /// The router managing the network
+    Leader,
+}
+
+impl ThreadRole {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(role: otDeviceRole) -> Self {
+        match role {
+            otDeviceRole_OT_DEVICE_ROLE_DETACHED => Self::Detached,
+            otDeviceRole_OT_DEVICE_ROLE_CHILD => Self::Child,
+            otDeviceRole_OT_DEVICE_ROLE_ROUTER => Self::Router,
+            otDeviceRole_OT_DEVICE_ROLE_LEADER => Self::Leader,
+            _ => Self::Disabled,
+        }
+    }
+
+    /// Whether the device is attached to a network, in any role
+    pub fn is_attached(&self) -> bool {
+        matches!(self, Self::Child | Self::Router | Self::Leader)
+    }
+}
+
+/// How the device attaches to the network
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ThreadMode {
+    /// A router-eligible full thread device, which becomes a router when the network needs one
+    #[cfg(esp_idf_openthread_ftd)]
+    Router,
+    /// A minimal end device, with its receiver always on
+    EndDevice,
+    /// A sleepy end device, turning its receiver off and polling its parent every period
+    SleepyEndDevice(Duration),
+}
+
+/// An event of the OpenThread stack
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ThreadEvent {
+    Started,
+    Stopped,
+    /// The role of the device changed, e.g. to `ThreadRole::Child` once attached
+    RoleChanged {
+        previous: ThreadRole,
+        current: ThreadRole,
+    },
+    /// The netif is up
+    IfUp,
+    /// The netif is down
+    IfDown,
+    /// Another event of the stack, by ID
+    Other(u32),
+}
+
+unsafe impl EspEventSource for ThreadEvent {
+    fn source() -> Option<&'static CStr> {
+        Some(unsafe { CStr::from_ptr(OPENTHREAD_EVENT) })
+    }
+}
+
+impl EspEventDeserializer for ThreadEvent {
+    type Data<'a> = ThreadEvent;
+
+    #[allow(non_upper_case_globals, non_snake_case)]
+    fn deserialize<'a>(data: &EspEvent<'a>) -> Self::Data<'a> {
+        let event_id = data.event_id as u32;
+
+        if event_id == esp_openthread_event_t_OPENTHREAD_EVENT_START {
+            ThreadEvent::Started
+        } else if event_id == esp_openthread_event_t_OPENTHREAD_EVENT_STOP {
+            ThreadEvent::Stopped
+        } else if event_id == esp_openthread_event_t_OPENTHREAD_EVENT_ROLE_CHANGED {
+            let payload = unsafe { data.as_payload::<esp_openthread_role_changed_event_t>() };
+
+            ThreadEvent::RoleChanged {
+                previous: ThreadRole::from_raw(payload.previous_role),
+                current: ThreadRole::from_raw(payload.current_role),
+            }
+        } else if event_id == esp_openthread_event_t_OPENTHREAD_EVENT_IF_UP {
+            ThreadEvent::IfUp
+        } else if event_id == esp_openthread_event_t_OPENTHREAD_EVENT_IF_DOWN {
+            ThreadEvent::IfDown
+        } else {
+            ThreadEvent::Other(event_id)
+        }
+    }
+}
+
+/// The OpenThread stack, on the native 802.15.4 radio
+pub struct EspThread {
+    _nvs: EspDefaultNvsPartition,
+    _sys_loop: EspSystemEventLoop,
+}
+
+impl EspThread {
+    /// Initialize the stack - on the first call - and its netif
+    ///
+    /// The settings of the stack, e.g. the last dataset, are stored in the default NVS
+    /// partition.
+    pub fn new(
+        nvs: EspDefaultNvsPartition,
+        sys_loop: EspSystemEventLoop,
+    ) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut netif = NETIF.lock();
+
+        if netif.is_none() {
+            *netif = Some(Netif(init()?));
+        }
+
+        *taken = true;
+
+        Ok(Self {
+            _nvs: nvs,
+            _sys_loop: sys_loop,
+        })
+    }
+
+    /// The netif of the stack, e.g. to get its IPv6 addresses
+    pub fn netif_handle(&self) -> *mut esp_netif_t {
+        NETIF.lock().as_ref().unwrap().0
+    }
+
+    /// Set how the device attaches to the network
+    pub fn set_mode(&mut self, mode: ThreadMode) -> Result<(), EspError> {
+        let mut link_mode: otLinkModeConfig = Default::default();
+
+        link_mode.set_mRxOnWhenIdle(!matches!(mode, ThreadMode::SleepyEndDevice(_)));
+        link_mode.set_mNetworkData(false);
+
+        #[cfg(esp_idf_openthread_ftd)]
+        {
+            let router = matches!(mode, ThreadMode::Router);
+
+            link_mode.set_mDeviceType(router);
+            link_mode.set_mNetworkData(router);
+        }
+
+        with_instance(|instance| {
+            #[cfg(esp_idf_openthread_ftd)]
+            ot!(unsafe {
+                otThreadSetRouterEligible(instance, matches!(mode, ThreadMode::Router))
+            })?;
+
+            if let ThreadMode::SleepyEndDevice(period) = mode {
+                ot!(unsafe { otLinkSetPollPeriod(instance, period.as_millis() as _) })?;
+            }
+
+            ot!(unsafe { otThreadSetLinkMode(instance, link_mode) })
+        })
+    }
+
+    /// Set the active operational dataset - the network name, the channel, the network key,
+    /// etc. - from its TLVs
+    pub fn set_active_dataset_tlvs(&mut self, tlvs: &[u8]) -> Result<(), EspError> {
+        if tlvs.len() > MAX_DATASET_TLVS_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut dataset: otOperationalDatasetTlvs = Default::default();
+
+        dataset.mTlvs[..tlvs.len()].copy_from_slice(tlvs);
+        dataset.mLength = tlvs.len() as _;
+
+        with_instance(|instance| ot!(unsafe { otDatasetSetActiveTlvs(instance, &dataset) }))
+    }
+
+    /// Get the TLVs of the active operational dataset, if any, into `buf`
+    pub fn active_dataset_tlvs<'a>(&self, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, EspError> {
+        let mut dataset: otOperationalDatasetTlvs = Default::default();
+
+        let result =
+            with_instance(|instance| unsafe { otDatasetGetActiveTlvs(instance, &mut dataset) });
+
+        if result == otError_OT_ERROR_NOT_FOUND {
+            return Ok(None);
+        }
+
+        ot!(result)?;
+
+        let len = dataset.mLength as usize;
+
+        if buf.len() < len {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        buf[..len].copy_from_slice(&dataset.mTlvs[..len]);
+
+        Ok(Some(&buf[..len]))
+    }
+
+    /// Enable IPv6 and start attaching to the network of the active dataset
+    pub fn start(&mut self) -> Result<(), EspError> {
+        with_instance(|instance| {
+            ot!(unsafe { otIp6SetEnabled(instance, true) })?;
+            ot!(unsafe { otThreadSetEnabled(instance, true) })
+        })
+    }
+
+    /// Detach from the network, and disable IPv6
+    pub fn stop(&mut self) -> Result<(), EspError> {
+        with_instance(|instance| {
+            ot!(unsafe { otThreadSetEnabled(instance, false) })?;
+            ot!(unsafe { otIp6SetEnabled(instance, false) })
+        })
+    }
+
+    /// The role of the device in the network
+    pub fn role(&self) -> ThreadRole {
+        ThreadRole::from_raw(with_instance(|instance| unsafe {
+            otThreadGetDeviceRole(instance)
+        }))
+    }
+}
+
+impl Drop for EspThread {
+    fn drop(&mut self) {
+        self.stop().unwrap();
+
+        *TAKEN.lock() = false;
+
+        info!("Dropped");
+    }
+}
+
+unsafe impl Send for EspThread {}
+
+// Calls to the OpenThread API from outside of its task must hold the lock of the stack
+fn with_instance<F, R>(f: F) -> R
+where
+    F: FnOnce(*mut otInstance) -> R,
+{
+    unsafe {
+        esp_openthread_lock_acquire(portMAX_DELAY);
+    }
+
+    let result = f(unsafe { esp_openthread_get_instance() });
+
+    unsafe {
+        esp_openthread_lock_release();
+    }
+
+    result
+}
+
+fn init() -> Result<*mut esp_netif_t, EspError> {
+    NetifStack::initialize()?;
+
+    esp!(unsafe { esp_vfs_eventfd_register(&esp_vfs_eventfd_config_t { max_fds: 3 }) })?;
+
+    let config = esp_openthread_platform_config_t {
+        radio_config: esp_openthread_radio_config_t {
+            radio_mode: esp_openthread_radio_mode_t_RADIO_MODE_NATIVE,
+            ..Default::default()
+        },
+        host_config: esp_openthread_host_connection_config_t {
+            host_connection_mode: esp_openthread_host_connection_mode_t_HOST_CONNECTION_MODE_NONE,
+            ..Default::default()
+        },
+        port_config: esp_openthread_port_config_t {
+            storage_partition_name: b"nvs\0".as_ptr() as *const ffi::c_char,
+            netif_queue_size: 10,
+            task_queue_size: 10,
+        },
+    };
+
+    esp!(unsafe { esp_openthread_init(&config) })?;
+
+    let inherent_config = esp_netif_inherent_config_t {
+        flags: 0,
+        mac: [0; 6],
+        ip_info: ptr::null(),
+        get_ip_event: 0,
+        lost_ip_event: 0,
+        if_key: b"OT_DEF\0".as_ptr() as *const _,
+        if_desc: b"openthread\0".as_ptr() as *const _,
+        route_prio: 15,
+        bridge_info: ptr::null_mut(),
+    };
+
+    let netif_config = esp_netif_config_t {
+        base: &inherent_config,
+        driver: ptr::null(),
+        stack: unsafe { ptr::addr_of!(g_esp_netif_netstack_default_openthread) },
+    };
+
+    let netif = unsafe { esp_netif_new(&netif_config).as_mut() }
+        .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+    esp!(unsafe { esp_netif_attach(netif, esp_openthread_netif_glue_init(&config)) })?;
+
+    std::thread::Builder::new()
+        .name("ot-main".into())
+        .stack_size(8192)
+        .spawn(|| {
+            let result = esp!(unsafe { esp_openthread_launch_mainloop() });
+
+            ::log::error!("OpenThread mainloop exited: {:?}", result);
+        })
+        .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+    info!("Initialized");
+
+    Ok(netif)
+}
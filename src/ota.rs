@@ -23,6 +23,20 @@ use crate::sys::*;
 use crate::io::EspIOError;
 use crate::private::{common::*, cstr::*, mutex};
 
+#[cfg(all(
+    feature = "json",
+    esp_idf_comp_mqtt_enabled,
+    esp_idf_comp_esp_event_enabled,
+    esp_idf_comp_mbedtls_enabled
+))]
+pub mod mqtt;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_mbedtls_enabled,
+    not(esp_idf_version_major = "4")
+))]
+pub mod signature;
+
 static TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
 
 impl From<Newtype<&esp_app_desc_t>> for FirmwareInfo {
@@ -0,0 +1,544 @@
+//! OTA updates over MQTT
+//!
+//! `EspMqttOta` downloads a new firmware published to MQTT topics - as cloud IoT deployments
+//! commonly do - writes it with `EspOta` while hashing it, and reports its progress. The
+//! application feeds it the events of its MQTT client:
+//!
+//! ```ignore
+//! let mut ota = EspMqttOta::new(EspOta::new()?, &MqttOtaConfiguration {
+//!     base_topic: "devices/42/ota",
+//!     current_version: Some(env!("CARGO_PKG_VERSION")),
+//!     ..Default::default()
+//! })?;
+//!
+//! loop {
+//!     let event = connection.next()?;
+//!
+//!     if ota.handle(&mut client, &event.payload())? {
+//!         ota.activate()?;
+//!         esp_idf_svc::hal::reset::restart();
+//!     }
+//! }
+//! ```
+//!
+//! The topics, below `base_topic`:
+//! - `<base>/manifest` - from the server, JSON: a new firmware, as
+//!   `{"version": "1.2.0", "size": 1048576, "sha256": "<64 hex digits>"}`
+//! - `<base>/chunk` - from the server, binary: a chunk of the firmware, as its offset (4 bytes,
+//!   big endian) followed by its data
+//! - `<base>/request` - from the device, JSON: the offset from which the device expects the
+//!   chunks, as `{"version": "1.2.0", "offset": 0}`
+//! - `<base>/status` - from the device, JSON: the progress of the update, as
+//!   `{"state": "downloading", "version": "1.2.0", "offset": 65536, "size": 1048576}`, where the
+//!   state is one of `downloading`, `downloaded` or `failed` (with an `error`)
+//!
+//! The device requests the chunks from offset 0 once it accepts a manifest, and then again from
+//! the offset it reached whenever it reconnects - resuming the download after a disconnection -
+//! or receives a chunk past that offset, i.e. some chunks were lost. The chunks before that
+//! offset are ignored, so that the server may simply restart streaming from the offset requested.
+//!
+//! The firmware is only made bootable by `activate`, once its SHA-256 matches the manifest.
+
+use core::mem;
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use serde_json::{json, Value};
+
+use crate::crypto::hash::Sha256;
+use crate::mqtt::client::{Details, EspMqttClient, EventPayload, QoS};
+use crate::sys::*;
+
+use super::{EspOta, EspOtaUpdate, EspOtaUpdateFinished};
+
+/// The configuration of the OTA updates over MQTT
+#[derive(Copy, Clone, Debug)]
+pub struct MqttOtaConfiguration<'a> {
+    /// The topic below which the OTA topics are
+    pub base_topic: &'a str,
+    pub qos: QoS,
+    /// The version of the running firmware; a manifest of the same version is ignored
+    pub current_version: Option<&'a str>,
+    /// The number of bytes downloaded between two reports of the progress
+    pub progress_interval: usize,
+    /// The maximum size of a chunk message
+    pub max_chunk_len: usize,
+}
+
+impl MqttOtaConfiguration<'_> {
+    pub const fn new() -> Self {
+        Self {
+            base_topic: "ota",
+            qos: QoS::AtLeastOnce,
+            current_version: None,
+            progress_interval: 65536,
+            max_chunk_len: 8192,
+        }
+    }
+}
+
+impl Default for MqttOtaConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state of the OTA update
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MqttOtaState {
+    Idle,
+    Downloading {
+        version: heapless::String<32>,
+        offset: usize,
+        size: usize,
+    },
+    /// The firmware is downloaded and verified, and can be activated
+    Downloaded {
+        version: heapless::String<32>,
+    },
+}
+
+struct Download {
+    version: heapless::String<32>,
+    size: usize,
+    sha256: [u8; 32],
+    offset: usize,
+    // The offset last requested, so as to request a lost chunk only once
+    requested: usize,
+    reported: usize,
+    hasher: Sha256,
+}
+
+/// The OTA updates over MQTT
+pub struct EspMqttOta {
+    // Declared before `ota`, so as to be dropped before it
+    update: Option<EspOtaUpdate<'static>>,
+    finished: Option<(heapless::String<32>, EspOtaUpdateFinished<'static>)>,
+    ota: EspOta,
+    download: Option<Download>,
+    manifest_topic: String,
+    chunk_topic: String,
+    request_topic: String,
+    status_topic: String,
+    qos: QoS,
+    current_version: Option<heapless::String<32>>,
+    progress_interval: usize,
+    max_chunk_len: usize,
+    // The chunk message being received, when fragmented by the MQTT client
+    chunk: Option<Vec<u8>>,
+}
+
+impl EspMqttOta {
+    pub fn new(ota: EspOta, conf: &MqttOtaConfiguration) -> Result<Self, EspError> {
+        let base = conf.base_topic.trim_end_matches('/');
+
+        Ok(Self {
+            update: None,
+            finished: None,
+            ota,
+            download: None,
+            manifest_topic: format!("{base}/manifest"),
+            chunk_topic: format!("{base}/chunk"),
+            request_topic: format!("{base}/request"),
+            status_topic: format!("{base}/status"),
+            qos: conf.qos,
+            current_version: conf
+                .current_version
+                .map(|version| version.try_into().map_err(|_| invalid_size()))
+                .transpose()?,
+            progress_interval: conf.progress_interval.max(1),
+            max_chunk_len: conf.max_chunk_len,
+            chunk: None,
+        })
+    }
+
+    pub fn state(&self) -> MqttOtaState {
+        if let Some(download) = &self.download {
+            MqttOtaState::Downloading {
+                version: download.version.clone(),
+                offset: download.offset,
+                size: download.size,
+            }
+        } else if let Some((version, _)) = &self.finished {
+            MqttOtaState::Downloaded {
+                version: version.clone(),
+            }
+        } else {
+            MqttOtaState::Idle
+        }
+    }
+
+    /// Subscribe to the OTA topics; done by `handle` on every connection
+    pub fn subscribe(&mut self, client: &mut EspMqttClient<'_>) -> Result<(), EspError> {
+        client.subscribe(&self.manifest_topic, self.qos)?;
+        client.subscribe(&self.chunk_topic, self.qos)?;
+
+        Ok(())
+    }
+
+    /// Handle an event of the MQTT client; return whether the firmware was just downloaded and
+    /// verified, and can be activated
+    pub fn handle(
+        &mut self,
+        client: &mut EspMqttClient<'_>,
+        event: &EventPayload<'_, EspError>,
+    ) -> Result<bool, EspError> {
+        match event {
+            EventPayload::Connected(_) => {
+                self.subscribe(client)?;
+
+                // Resume the download in progress, if any
+                if let Some(download) = &mut self.download {
+                    info!("Resuming the download at {}", download.offset);
+
+                    download.requested = download.offset;
+                    Self::request(client, &self.request_topic, self.qos, download)?;
+                }
+
+                Ok(false)
+            }
+            EventPayload::Received {
+                topic,
+                data,
+                details,
+                ..
+            } => {
+                let message = match details {
+                    Details::Complete if *topic == Some(self.manifest_topic.as_str()) => {
+                        self.manifest(client, data)?;
+                        return Ok(false);
+                    }
+                    Details::Complete if *topic == Some(self.chunk_topic.as_str()) => {
+                        self.chunk = None;
+                        Some(*data)
+                    }
+                    Details::InitialChunk(initial) if *topic == Some(self.chunk_topic.as_str()) => {
+                        self.chunk = (initial.total_data_size <= self.max_chunk_len).then(|| {
+                            let mut chunk = Vec::with_capacity(initial.total_data_size);
+                            chunk.extend_from_slice(data);
+                            chunk
+                        });
+
+                        None
+                    }
+                    Details::InitialChunk(_) => {
+                        self.chunk = None;
+                        None
+                    }
+                    Details::SubsequentChunk(subsequent) => {
+                        let complete = self.chunk.as_mut().is_some_and(|chunk| {
+                            chunk.extend_from_slice(data);
+                            chunk.len() >= subsequent.total_data_size
+                        });
+
+                        if complete {
+                            let chunk = self.chunk.take().unwrap();
+                            return self.write(client, &chunk);
+                        }
+
+                        None
+                    }
+                    _ => None,
+                };
+
+                match message {
+                    Some(message) => self.write(client, message),
+                    None => Ok(false),
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Make the downloaded firmware bootable; it runs once the device restarts
+    pub fn activate(&mut self) -> Result<(), EspError> {
+        let (version, finished) = self
+            .finished
+            .take()
+            .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())?;
+
+        finished.activate()?;
+
+        info!("Firmware {version} activated");
+
+        Ok(())
+    }
+
+    /// Abort the download in progress, if any, and discard the downloaded firmware
+    pub fn abort(&mut self) {
+        self.download = None;
+        self.finished = None;
+        self.chunk = None;
+
+        if let Some(update) = self.update.take() {
+            let _ = update.abort();
+        }
+    }
+
+    pub fn release(mut self) -> EspOta {
+        self.abort();
+
+        self.ota
+    }
+
+    fn manifest(&mut self, client: &mut EspMqttClient<'_>, data: &[u8]) -> Result<(), EspError> {
+        let Some((version, size, sha256)) = parse_manifest(data) else {
+            warn!("Ignoring an invalid manifest");
+            return self.report_failure(client, None, "invalid manifest");
+        };
+
+        if self.current_version.as_deref() == Some(version.as_str()) {
+            info!("Firmware {version} already running");
+            return Ok(());
+        }
+
+        if let Some(download) = &mut self.download {
+            if download.version == version && download.sha256 == sha256 {
+                // Most likely a retained manifest, received again on reconnection
+                download.requested = download.offset;
+                return Self::request(client, &self.request_topic, self.qos, download);
+            }
+        }
+
+        self.abort();
+
+        let update = match self.ota.initiate_update() {
+            // SAFETY: The update borrows nothing from `EspOta` - whose borrow only ensures that a
+            // single update is in progress - and it is dropped before `EspOta`, which this owns
+            Ok(update) => unsafe {
+                mem::transmute::<EspOtaUpdate<'_>, EspOtaUpdate<'static>>(update)
+            },
+            Err(err) => {
+                self.report_failure(client, Some(version.as_str()), "cannot initiate the update")?;
+                return Err(err);
+            }
+        };
+
+        info!("Downloading firmware {version} ({size} bytes)");
+
+        let download = Download {
+            version,
+            size,
+            sha256,
+            offset: 0,
+            requested: 0,
+            reported: 0,
+            hasher: Sha256::new()?,
+        };
+
+        self.update = Some(update);
+
+        Self::request(client, &self.request_topic, self.qos, &download)?;
+        self.report(client, &download, "downloading")?;
+
+        self.download = Some(download);
+
+        Ok(())
+    }
+
+    fn write(&mut self, client: &mut EspMqttClient<'_>, message: &[u8]) -> Result<bool, EspError> {
+        let (Some(download), Some(update)) = (&mut self.download, &mut self.update) else {
+            return Ok(false);
+        };
+
+        if message.len() < 4 {
+            warn!("Ignoring an invalid chunk");
+            return Ok(false);
+        }
+
+        let (offset, data) = message.split_at(4);
+        let offset = u32::from_be_bytes(offset.try_into().unwrap()) as usize;
+
+        if offset < download.offset {
+            // Already written
+            return Ok(false);
+        }
+
+        if offset > download.offset {
+            // Lost chunks
+            if download.requested != download.offset {
+                warn!("Chunks lost at {}, requesting them again", download.offset);
+
+                download.requested = download.offset;
+                Self::request(client, &self.request_topic, self.qos, download)?;
+            }
+
+            return Ok(false);
+        }
+
+        if download.offset + data.len() > download.size {
+            let version = download.version.clone();
+            self.abort();
+
+            self.report_failure(
+                client,
+                Some(version.as_str()),
+                "firmware larger than its manifest",
+            )?;
+            return Ok(false);
+        }
+
+        if let Err(err) = update
+            .write(data)
+            .and_then(|_| download.hasher.update(data))
+        {
+            let version = download.version.clone();
+            self.abort();
+
+            self.report_failure(client, Some(version.as_str()), "cannot write the firmware")?;
+            return Err(err);
+        }
+
+        download.offset += data.len();
+
+        if download.offset < download.size {
+            if download.offset - download.reported >= self.progress_interval {
+                download.reported = download.offset;
+                Self::report_progress(
+                    client,
+                    &self.status_topic,
+                    self.qos,
+                    download,
+                    "downloading",
+                )?;
+            }
+
+            return Ok(false);
+        }
+
+        let download = self.download.take().unwrap();
+        let update = self.update.take().unwrap();
+
+        if download.hasher.finalize()? != download.sha256 {
+            warn!("Firmware {} does not match its SHA-256", download.version);
+
+            let _ = update.abort();
+
+            self.report_failure(client, Some(download.version.as_str()), "SHA-256 mismatch")?;
+            return Ok(false);
+        }
+
+        match update.finish() {
+            Ok(finished) => {
+                info!("Firmware {} downloaded", download.version);
+
+                self.report(client, &download, "downloaded")?;
+                self.finished = Some((download.version, finished));
+
+                Ok(true)
+            }
+            Err(err) => {
+                self.report_failure(
+                    client,
+                    Some(download.version.as_str()),
+                    "invalid firmware image",
+                )?;
+                Err(err)
+            }
+        }
+    }
+
+    fn request(
+        client: &mut EspMqttClient<'_>,
+        topic: &str,
+        qos: QoS,
+        download: &Download,
+    ) -> Result<(), EspError> {
+        let request = json!({
+            "version": download.version.as_str(),
+            "offset": download.offset,
+        });
+
+        client.publish(topic, qos, false, request.to_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    fn report(
+        &self,
+        client: &mut EspMqttClient<'_>,
+        download: &Download,
+        state: &str,
+    ) -> Result<(), EspError> {
+        Self::report_progress(client, &self.status_topic, self.qos, download, state)
+    }
+
+    fn report_progress(
+        client: &mut EspMqttClient<'_>,
+        topic: &str,
+        qos: QoS,
+        download: &Download,
+        state: &str,
+    ) -> Result<(), EspError> {
+        let status = json!({
+            "state": state,
+            "version": download.version.as_str(),
+            "offset": download.offset,
+            "size": download.size,
+        });
+
+        client.publish(topic, qos, false, status.to_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    fn report_failure(
+        &self,
+        client: &mut EspMqttClient<'_>,
+        version: Option<&str>,
+        error: &str,
+    ) -> Result<(), EspError> {
+        let status = json!({
+            "state": "failed",
+            "version": version,
+            "error": error,
+        });
+
+        client.publish(
+            &self.status_topic,
+            self.qos,
+            false,
+            status.to_string().as_bytes(),
+        )?;
+
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for EspMqttOta {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EspMqttOta")
+            .field("state", &self.state())
+            .field("manifest_topic", &self.manifest_topic)
+            .finish_non_exhaustive()
+    }
+}
+
+fn parse_manifest(data: &[u8]) -> Option<(heapless::String<32>, usize, [u8; 32])> {
+    let manifest: Value = serde_json::from_slice(data).ok()?;
+
+    let version = manifest.get("version")?.as_str()?.try_into().ok()?;
+    let size = manifest.get("size")?.as_u64()?.try_into().ok()?;
+    let hex = manifest.get("sha256")?.as_str()?.as_bytes();
+
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut sha256 = [0; 32];
+
+    for (byte, digits) in sha256.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+
+    Some((version, size, sha256))
+}
+
+fn invalid_size() -> EspError {
+    EspError::from_infallible::<ESP_ERR_INVALID_SIZE>()
+}
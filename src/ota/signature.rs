@@ -0,0 +1,316 @@
+//! Signature verification of OTA images
+//!
+//! For devices which cannot enable secure boot, `EspOta::initiate_verified_update` returns an
+//! update which checks a signature appended to the image against a public key held by the
+//! firmware - either embedded in it, or provisioned in NVS - before the update slot can be
+//! activated:
+//!
+//! ```ignore
+//! static KEY: &[u8] = include_bytes!("ota_key.pem\0");
+//!
+//! let key = OtaVerifyingKey::parse(X509::pem_until_nul(KEY))?;
+//!
+//! let mut update = ota.initiate_verified_update(&key)?;
+//! update.write_all(&image)?;
+//! update.complete()?; // Fails with `ESP_ERR_OTA_VALIDATE_FAILED` on a bad signature
+//! ```
+//!
+//! The signature is an RSA PKCS#1 v1.5 or an ECDSA signature of the SHA-256 of the image,
+//! followed by its length as a little-endian `u16` and by the `OSIG` magic. E.g. with OpenSSL:
+//!
+//! ```text
+//! openssl dgst -sha256 -sign key.pem -out app.sig app.bin
+//! python3 -c "import struct; s = open('app.sig', 'rb').read(); \
+//!     open('app-signed.bin', 'wb').write(open('app.bin', 'rb').read() + s \
+//!     + struct.pack('<H', len(s)) + b'OSIG')"
+//! ```
+//!
+//! The signature trailer is not written to the update slot.
+
+use core::fmt::{self, Debug};
+use core::mem;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::io;
+use embedded_svc::ota::OtaUpdate;
+
+use crate::sys::*;
+
+use crate::crypto::hash::Sha256;
+use crate::io::EspIOError;
+use crate::tls::X509;
+
+use super::{EspOta, EspOtaUpdate, EspOtaUpdateFinished};
+
+const MAGIC: &[u8; 4] = b"OSIG";
+
+/// The maximum length of a signature, i.e. that of an RSA-4096 signature
+pub const MAX_SIGNATURE_LEN: usize = 512;
+
+const MAX_TRAILER_LEN: usize = MAX_SIGNATURE_LEN + 2 + MAGIC.len();
+
+/// The public key verifying the signatures of the OTA images
+pub struct OtaVerifyingKey(Box<mbedtls_pk_context>);
+
+impl OtaVerifyingKey {
+    /// Parse a PEM or DER RSA or EC public key
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the key cannot be parsed.
+    pub fn parse(key: X509) -> Result<Self, EspError> {
+        let mut pk = Box::new(unsafe { mem::zeroed() });
+
+        unsafe { mbedtls_pk_init(&mut *pk) };
+
+        let mut this = Self(pk);
+
+        let ret = unsafe {
+            mbedtls_pk_parse_public_key(&mut *this.0, key.data().as_ptr(), key.data().len())
+        };
+
+        if ret == 0 {
+            Ok(this)
+        } else {
+            warn!("Failed to parse the OTA verifying key (error {ret})");
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        }
+    }
+
+    /// Load a PEM or DER public key stored as a blob in NVS, e.g. at provisioning
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if there is no such blob.
+    #[cfg(esp_idf_comp_nvs_flash_enabled)]
+    pub fn from_nvs<T>(nvs: &crate::nvs::EspNvs<T>, name: &str) -> Result<Self, EspError>
+    where
+        T: crate::nvs::NvsPartitionId,
+    {
+        let len = nvs
+            .blob_len(name)?
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        // With room for the NUL terminator the PEM keys need
+        let mut buf = alloc::vec![0; len + 1];
+
+        let data = nvs
+            .get_blob(name, &mut buf)?
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let len = data.len();
+
+        if buf.starts_with(b"-----BEGIN") {
+            if buf[..len].last() != Some(&0) {
+                buf[len] = 0;
+            }
+
+            Self::parse(X509::pem_until_nul(&buf))
+        } else {
+            Self::parse(X509::der(&buf[..len]))
+        }
+    }
+
+    fn verify(&self, hash: &[u8; 32], signature: &[u8]) -> bool {
+        let ret = unsafe {
+            mbedtls_pk_verify(
+                self.0.as_ref() as *const _ as *mut _,
+                mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                hash.as_ptr(),
+                hash.len(),
+                signature.as_ptr(),
+                signature.len(),
+            )
+        };
+
+        if ret != 0 {
+            warn!("OTA image signature verification failed (error {ret})");
+        }
+
+        ret == 0
+    }
+}
+
+impl Drop for OtaVerifyingKey {
+    fn drop(&mut self) {
+        unsafe { mbedtls_pk_free(&mut *self.0) };
+    }
+}
+
+impl Debug for OtaVerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtaVerifyingKey")
+            .field("bits", &unsafe { mbedtls_pk_get_bitlen(&*self.0) })
+            .finish()
+    }
+}
+
+// The parsed key is immutable
+unsafe impl Send for OtaVerifyingKey {}
+unsafe impl Sync for OtaVerifyingKey {}
+
+/// An OTA update whose image is only accepted with a valid signature
+pub struct EspVerifiedOtaUpdate<'a> {
+    update: EspOtaUpdate<'a>,
+    key: &'a OtaVerifyingKey,
+    sha: Sha256,
+    // The last bytes written, which might be the signature trailer
+    tail: Vec<u8>,
+}
+
+impl<'a> EspVerifiedOtaUpdate<'a> {
+    fn new(update: EspOtaUpdate<'a>, key: &'a OtaVerifyingKey) -> Result<Self, EspError> {
+        Ok(Self {
+            update,
+            key,
+            sha: Sha256::new()?,
+            tail: Vec::with_capacity(2 * MAX_TRAILER_LEN),
+        })
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        self.tail.extend_from_slice(buf);
+
+        if self.tail.len() > MAX_TRAILER_LEN {
+            let len = self.tail.len() - MAX_TRAILER_LEN;
+
+            self.write_image(len)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), EspError> {
+        self.update.flush()
+    }
+
+    /// Verify the signature and finish the update, without activating it
+    pub fn finish(self) -> Result<EspOtaUpdateFinished<'a>, EspError> {
+        self.verify()?.finish()
+    }
+
+    /// Verify the signature, finish the update, and activate it
+    pub fn complete(self) -> Result<(), EspError> {
+        self.verify()?.complete()
+    }
+
+    pub fn abort(self) -> Result<(), EspError> {
+        self.update.abort()
+    }
+
+    /// Hash and write the first `len` bytes of the tail to the update slot
+    fn write_image(&mut self, len: usize) -> Result<(), EspError> {
+        let image = &self.tail[..len];
+
+        self.sha.update(image)?;
+        self.update.write(image)?;
+
+        self.tail.drain(..len);
+
+        Ok(())
+    }
+
+    /// Verify the signature of the image, returning the update if it is valid
+    fn verify(mut self) -> Result<EspOtaUpdate<'a>, EspError> {
+        let invalid = EspError::from_infallible::<ESP_ERR_OTA_VALIDATE_FAILED>();
+
+        let len = self.tail.len();
+
+        if len < 2 + MAGIC.len() || &self.tail[len - MAGIC.len()..] != MAGIC {
+            warn!("The OTA image is not signed");
+            return Err(invalid);
+        }
+
+        let signature_len = u16::from_le_bytes([
+            self.tail[len - MAGIC.len() - 2],
+            self.tail[len - MAGIC.len() - 1],
+        ]) as usize;
+
+        let Some(image_len) = (len - MAGIC.len() - 2).checked_sub(signature_len) else {
+            warn!("The OTA image signature trailer is malformed");
+            return Err(invalid);
+        };
+
+        self.write_image(image_len)?;
+
+        // The update is aborted when dropped, if the signature is not valid
+        let Self {
+            update,
+            key,
+            sha,
+            tail,
+        } = self;
+
+        if !key.verify(&sha.finalize()?, &tail[..signature_len]) {
+            return Err(invalid);
+        }
+
+        info!("OTA image signature verified");
+
+        Ok(update)
+    }
+}
+
+impl Debug for EspVerifiedOtaUpdate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspVerifiedOtaUpdate")
+            .field("update", &self.update)
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl Send for EspVerifiedOtaUpdate<'_> {}
+
+impl EspOta {
+    /// Initiate an update whose image has to be signed with the private key of `key`
+    ///
+    /// See the documentation of the [`signature`](self) module for the format of the signature.
+    pub fn initiate_verified_update<'a>(
+        &'a mut self,
+        key: &'a OtaVerifyingKey,
+    ) -> Result<EspVerifiedOtaUpdate<'a>, EspError> {
+        EspVerifiedOtaUpdate::new(self.initiate_update()?, key)
+    }
+}
+
+impl<'a> io::ErrorType for EspVerifiedOtaUpdate<'a> {
+    type Error = EspIOError;
+}
+
+impl<'a> OtaUpdate for EspVerifiedOtaUpdate<'a> {
+    type OtaUpdateFinished = EspOtaUpdateFinished<'a>;
+
+    fn finish(self) -> Result<Self::OtaUpdateFinished, Self::Error> {
+        let finish = EspVerifiedOtaUpdate::finish(self)?;
+
+        Ok(finish)
+    }
+
+    fn complete(self) -> Result<(), Self::Error> {
+        EspVerifiedOtaUpdate::complete(self)?;
+
+        Ok(())
+    }
+
+    fn abort(self) -> Result<(), Self::Error> {
+        EspVerifiedOtaUpdate::abort(self)?;
+
+        Ok(())
+    }
+}
+
+impl<'a> io::Write for EspVerifiedOtaUpdate<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        EspVerifiedOtaUpdate::write(self, buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        EspVerifiedOtaUpdate::flush(self)?;
+
+        Ok(())
+    }
+}
@@ -8,6 +8,12 @@ use crate::private::common::*;
 use crate::private::waitable::*;
 use crate::sys::*;
 
+#[cfg(feature = "alloc")]
+pub use asynch::*;
+
+#[cfg(feature = "alloc")]
+pub mod icmp;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Configuration {
     pub count: u32,
@@ -15,6 +21,8 @@ pub struct Configuration {
     pub timeout: Duration,
     pub data_size: u32,
     pub tos: u8,
+    /// The time-to-live of the echo requests
+    pub ttl: u8,
 }
 
 impl Default for Configuration {
@@ -25,6 +33,7 @@ impl Default for Configuration {
             timeout: Duration::from_secs(1),
             data_size: 56,
             tos: 0,
+            ttl: 64,
         }
     }
 }
@@ -49,6 +58,32 @@ pub struct Summary {
     pub transmitted: u32,
     pub received: u32,
     pub time: Duration,
+    /// The minimum round-trip time of all replies so far, or `None` if there were no replies
+    pub min_rtt: Option<Duration>,
+    /// The maximum round-trip time of all replies so far, or `None` if there were no replies
+    pub max_rtt: Option<Duration>,
+    /// The average round-trip time of all replies so far, or `None` if there were no replies
+    pub avg_rtt: Option<Duration>,
+}
+
+impl Summary {
+    /// The percentage of echo requests for which no reply was received
+    pub fn loss_percent(&self) -> f32 {
+        if self.transmitted == 0 {
+            0.0
+        } else {
+            (self.transmitted.saturating_sub(self.received)) as f32 * 100.0
+                / self.transmitted as f32
+        }
+    }
+
+    fn record_rtt(&mut self, rtt: Duration, replies: u32) {
+        self.min_rtt = Some(self.min_rtt.map(|min| min.min(rtt)).unwrap_or(rtt));
+        self.max_rtt = Some(self.max_rtt.map(|max| max.max(rtt)).unwrap_or(rtt));
+
+        let avg_rtt = self.avg_rtt.unwrap_or_default();
+        self.avg_rtt = Some((avg_rtt * (replies - 1) + rtt) / replies);
+    }
 }
 
 #[derive(Debug, Default)]
@@ -93,38 +128,31 @@ impl EspPing {
         Ok(tracker.summary)
     }
 
+    /// Start an asynchronous ping session
+    ///
+    /// The returned `EspAsyncPing` yields each reply as it arrives, and - once the session
+    /// is complete - provides the final summary. Dropping it cancels the session.
+    #[cfg(feature = "alloc")]
+    pub fn ping_async(
+        &mut self,
+        ip: ipv4::Ipv4Addr,
+        conf: &Configuration,
+    ) -> Result<EspAsyncPing, EspError> {
+        info!(
+            "About to run an async ping {} with configuration {:?}",
+            ip, conf
+        );
+
+        EspAsyncPing::new(self, ip, conf)
+    }
+
     fn run_ping<F: FnMut(&Summary, &Reply) + Send>(
         &self,
         ip: ipv4::Ipv4Addr,
         conf: &Configuration,
         tracker: &mut Tracker<F>,
     ) -> Result<(), EspError> {
-        #[cfg(not(esp_idf_lwip_ipv6))]
-        let ta = ip4_addr_t {
-            addr: u32::from_be_bytes(ip.octets()),
-        };
-        #[cfg(esp_idf_lwip_ipv6)]
-        let ta = ip_addr_t {
-            u_addr: ip_addr__bindgen_ty_1 {
-                ip4: Newtype::<ip4_addr_t>::from(ip).0,
-            },
-            type_: 0,
-        };
-        #[allow(clippy::needless_update)]
-        #[allow(clippy::useless_conversion)]
-        let config = esp_ping_config_t {
-            count: conf.count,
-            interval_ms: conf.interval.as_millis() as u32,
-            timeout_ms: conf.timeout.as_millis() as u32,
-            data_size: conf.data_size,
-            tos: conf.tos.into(),
-            target_addr: ta,
-            task_stack_size: 4096,
-            task_prio: 2,
-            interface: self.0,
-            ttl: 64,
-            ..Default::default()
-        };
+        let config = self.session_config(ip, conf);
 
         let callbacks = esp_ping_callbacks_t {
             on_ping_success: Some(EspPing::on_ping_success::<F>),
@@ -133,18 +161,7 @@ impl EspPing {
             cb_args: tracker as *mut Tracker<F> as *mut ffi::c_void,
         };
 
-        let mut handle: esp_ping_handle_t = ptr::null_mut();
-        let handle_ref = &mut handle;
-
-        esp!(unsafe {
-            esp_ping_new_session(&config, &callbacks, handle_ref as *mut *mut ffi::c_void)
-        })?;
-
-        if handle.is_null() {
-            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
-        }
-
-        info!("Ping session established, got handle {:?}", handle);
+        let handle = Self::new_session(&config, &callbacks)?;
 
         {
             let mut running = tracker.waitable.state.lock();
@@ -168,6 +185,55 @@ impl EspPing {
         Ok(())
     }
 
+    fn new_session(
+        config: &esp_ping_config_t,
+        callbacks: &esp_ping_callbacks_t,
+    ) -> Result<esp_ping_handle_t, EspError> {
+        let mut handle: esp_ping_handle_t = ptr::null_mut();
+        let handle_ref = &mut handle;
+
+        esp!(unsafe {
+            esp_ping_new_session(config, callbacks, handle_ref as *mut *mut ffi::c_void)
+        })?;
+
+        if handle.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        info!("Ping session established, got handle {:?}", handle);
+
+        Ok(handle)
+    }
+
+    fn session_config(&self, ip: ipv4::Ipv4Addr, conf: &Configuration) -> esp_ping_config_t {
+        #[cfg(not(esp_idf_lwip_ipv6))]
+        let ta = ip4_addr_t {
+            addr: u32::from_be_bytes(ip.octets()),
+        };
+        #[cfg(esp_idf_lwip_ipv6)]
+        let ta = ip_addr_t {
+            u_addr: ip_addr__bindgen_ty_1 {
+                ip4: Newtype::<ip4_addr_t>::from(ip).0,
+            },
+            type_: 0,
+        };
+        #[allow(clippy::needless_update)]
+        #[allow(clippy::useless_conversion)]
+        esp_ping_config_t {
+            count: conf.count,
+            interval_ms: conf.interval.as_millis() as u32,
+            timeout_ms: conf.timeout.as_millis() as u32,
+            data_size: conf.data_size,
+            tos: conf.tos.into(),
+            target_addr: ta,
+            task_stack_size: 4096,
+            task_prio: 2,
+            interface: self.0,
+            ttl: conf.ttl,
+            ..Default::default()
+        }
+    }
+
     unsafe extern "C" fn on_ping_success<F: FnMut(&Summary, &Reply) + Send>(
         handle: esp_ping_handle_t,
         args: *mut ffi::c_void,
@@ -177,6 +243,30 @@ impl EspPing {
         let tracker_ptr: *mut Tracker<F> = args as _;
         let tracker = tracker_ptr.as_mut().unwrap();
 
+        let info = Self::reply_info(handle);
+
+        info!(
+            "From {} icmp_seq={} ttl={} time={}ms bytes={}",
+            info.addr,
+            info.seqno,
+            info.ttl,
+            info.elapsed_time.as_millis(),
+            info.recv_len
+        );
+
+        tracker.replies += 1;
+        tracker
+            .summary
+            .record_rtt(info.elapsed_time, tracker.replies);
+
+        if let Some(reply_callback) = tracker.reply_callback.as_mut() {
+            Self::update_summary(handle, &mut tracker.summary);
+
+            reply_callback(&tracker.summary, &Reply::Success(info));
+        }
+    }
+
+    unsafe fn reply_info(handle: esp_ping_handle_t) -> Info {
         let mut seqno: ffi::c_ushort = 0;
         esp_ping_get_profile(
             handle,
@@ -224,24 +314,12 @@ impl EspPing {
         #[cfg(esp_idf_lwip_ipv6)]
         let addr = ipv4::Ipv4Addr::from(target_addr.u_addr.ip4.addr);
 
-        info!(
-            "From {} icmp_seq={} ttl={} time={}ms bytes={}",
-            addr, seqno, ttl, elapsed_time, recv_len
-        );
-
-        if let Some(reply_callback) = tracker.reply_callback.as_mut() {
-            Self::update_summary(handle, &mut tracker.summary);
-
-            reply_callback(
-                &tracker.summary,
-                &Reply::Success(Info {
-                    addr,
-                    seqno: seqno as u32,
-                    ttl,
-                    recv_len,
-                    elapsed_time: Duration::from_millis(elapsed_time as u64),
-                }),
-            );
+        Info {
+            addr,
+            seqno: seqno as u32,
+            ttl,
+            recv_len,
+            elapsed_time: Duration::from_millis(elapsed_time as u64),
         }
     }
 
@@ -339,6 +417,7 @@ impl EspPing {
 
 struct Tracker<F: FnMut(&Summary, &Reply) + Send> {
     summary: Summary,
+    replies: u32,
     waitable: Waitable<bool>,
     reply_callback: Option<F>,
 }
@@ -348,6 +427,7 @@ impl<F: FnMut(&Summary, &Reply) + Send> Tracker<F> {
     pub fn new(reply_callback: Option<F>) -> Self {
         Self {
             summary: Default::default(),
+            replies: 0,
             waitable: Waitable::new(false),
             reply_callback,
         }
@@ -355,3 +435,205 @@ impl<F: FnMut(&Summary, &Reply) + Send> Tracker<F> {
 }
 
 fn nop_callback(_summary: &Summary, _reply: &Reply) {}
+
+#[cfg(feature = "alloc")]
+mod asynch {
+    use core::ffi;
+
+    extern crate alloc;
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+
+    use ::log::*;
+
+    use crate::hal::task::asynch::Notification;
+    use crate::ipv4;
+    use crate::private::mutex::Mutex;
+    use crate::sys::*;
+
+    use super::{Configuration, EspPing, Reply, Summary};
+
+    struct State {
+        replies: VecDeque<Reply>,
+        summary: Summary,
+        successes: u32,
+        done: bool,
+    }
+
+    struct Shared {
+        state: Mutex<State>,
+        notification: Notification,
+    }
+
+    /// An asynchronous ping session, created with `EspPing::ping_async`
+    pub struct EspAsyncPing {
+        handle: esp_ping_handle_t,
+        shared: Arc<Shared>,
+    }
+
+    unsafe impl Send for EspAsyncPing {}
+
+    impl EspAsyncPing {
+        pub(super) fn new(
+            ping: &EspPing,
+            ip: ipv4::Ipv4Addr,
+            conf: &Configuration,
+        ) -> Result<Self, EspError> {
+            let shared = Arc::new(Shared {
+                state: Mutex::new(State {
+                    replies: VecDeque::new(),
+                    summary: Default::default(),
+                    successes: 0,
+                    done: false,
+                }),
+                notification: Notification::new(),
+            });
+
+            let callbacks = esp_ping_callbacks_t {
+                on_ping_success: Some(Self::on_ping_success),
+                on_ping_timeout: Some(Self::on_ping_timeout),
+                on_ping_end: Some(Self::on_ping_end),
+                cb_args: Arc::as_ptr(&shared) as *mut ffi::c_void,
+            };
+
+            let handle = EspPing::new_session(&ping.session_config(ip, conf), &callbacks)?;
+
+            if let Err(err) = esp!(unsafe { esp_ping_start(handle) }) {
+                unsafe {
+                    esp_ping_delete_session(handle);
+                }
+
+                return Err(err);
+            }
+
+            info!("Async ping session started");
+
+            Ok(Self { handle, shared })
+        }
+
+        /// Wait for the next reply (or timeout) of the session
+        ///
+        /// Returns `None` once the session is complete (or cancelled) and all replies were consumed.
+        pub async fn next(&mut self) -> Option<Reply> {
+            loop {
+                {
+                    let mut state = self.shared.state.lock();
+
+                    if let Some(reply) = state.replies.pop_front() {
+                        return Some(reply);
+                    }
+
+                    if state.done {
+                        return None;
+                    }
+                }
+
+                self.shared.notification.wait().await;
+            }
+        }
+
+        /// Return the summary of the session so far
+        pub fn summary(&self) -> Summary {
+            self.shared.state.lock().summary.clone()
+        }
+
+        /// Return `true` if the session is complete or was cancelled
+        pub fn is_done(&self) -> bool {
+            self.shared.state.lock().done
+        }
+
+        /// Wait for the session to complete, discarding all pending replies, and return its final summary
+        pub async fn finish(mut self) -> Summary {
+            while self.next().await.is_some() {}
+
+            self.summary()
+        }
+
+        /// Cancel the session
+        ///
+        /// Replies received before the cancellation can still be consumed with `next`.
+        pub fn cancel(&mut self) -> Result<(), EspError> {
+            esp!(unsafe { esp_ping_stop(self.handle) })?;
+
+            {
+                let mut state = self.shared.state.lock();
+
+                unsafe {
+                    EspPing::update_summary(self.handle, &mut state.summary);
+                }
+
+                state.done = true;
+            }
+
+            self.shared.notification.notify_lsb();
+
+            info!("Async ping session cancelled");
+
+            Ok(())
+        }
+
+        unsafe fn shared<'a>(args: *mut ffi::c_void) -> &'a Shared {
+            (args as *const Shared).as_ref().unwrap()
+        }
+
+        unsafe extern "C" fn on_ping_success(handle: esp_ping_handle_t, args: *mut ffi::c_void) {
+            let shared = Self::shared(args);
+
+            let info = EspPing::reply_info(handle);
+
+            {
+                let mut state = shared.state.lock();
+
+                state.successes += 1;
+
+                let successes = state.successes;
+                state.summary.record_rtt(info.elapsed_time, successes);
+
+                EspPing::update_summary(handle, &mut state.summary);
+
+                state.replies.push_back(Reply::Success(info));
+            }
+
+            shared.notification.notify_lsb();
+        }
+
+        unsafe extern "C" fn on_ping_timeout(handle: esp_ping_handle_t, args: *mut ffi::c_void) {
+            let shared = Self::shared(args);
+
+            {
+                let mut state = shared.state.lock();
+
+                EspPing::update_summary(handle, &mut state.summary);
+
+                state.replies.push_back(Reply::Timeout);
+            }
+
+            shared.notification.notify_lsb();
+        }
+
+        unsafe extern "C" fn on_ping_end(handle: esp_ping_handle_t, args: *mut ffi::c_void) {
+            let shared = Self::shared(args);
+
+            {
+                let mut state = shared.state.lock();
+
+                EspPing::update_summary(handle, &mut state.summary);
+
+                state.done = true;
+            }
+
+            shared.notification.notify_lsb();
+        }
+    }
+
+    impl Drop for EspAsyncPing {
+        fn drop(&mut self) {
+            unsafe {
+                esp!(esp_ping_stop(self.handle)).unwrap();
+                esp!(esp_ping_delete_session(self.handle)).unwrap();
+            }
+
+            info!("Async ping session {:?} removed", self.handle);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Raw ICMP sockets and traceroute
+//!
+//! `EspIcmpSocket` sends ICMP echo requests with a chosen time-to-live and identifier, and
+//! receives the echo replies as well as the time exceeded and destination unreachable errors
+//! they cause - which `EspPing` does not report. `traceroute` builds on it to enumerate the
+//! hops to a target, with the round-trip time of each probe:
+//!
+//! ```ignore
+//! let route = icmp::traceroute(Ipv4Addr::new(8, 8, 8, 8), &TracerouteConfiguration::default())?;
+//!
+//! for hop in &route.hops {
+//!     info!("{:2} {:?} {:?}", hop.ttl, hop.addr, hop.rtts);
+//! }
+//! ```
+//!
+//! The probes are routed by the default interface, as for the other lwIP sockets.
+
+use core::ffi::{c_int, c_void};
+use core::mem;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::ipv4;
+use crate::sys::*;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+
+const ICMP_HEADER_LEN: usize = 8;
+
+/// Large enough for an IP header with options, an ICMP error header, and the IP header with
+/// options and the first 8 bytes of the echo request it quotes
+const RECV_BUF_LEN: usize = 2 * (60 + ICMP_HEADER_LEN);
+
+/// The kind of an ICMP message answering an echo request
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IcmpKind {
+    /// The target answered the echo request
+    EchoReply,
+    /// The time-to-live of the echo request expired at the sender of the message
+    TimeExceeded,
+    /// The echo request could not be delivered, with the code of the error
+    Unreachable(u8),
+}
+
+/// An ICMP message answering one of the echo requests of an `EspIcmpSocket`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IcmpMessage {
+    /// The sender of the message: the target, or the router reporting an error
+    pub from: ipv4::Ipv4Addr,
+    /// The time-to-live of the message when it was received
+    pub ttl: u8,
+    /// The sequence number of the echo request this message answers
+    pub seqno: u16,
+    pub kind: IcmpKind,
+}
+
+/// A raw ICMP socket sending echo requests
+#[derive(Debug)]
+pub struct EspIcmpSocket {
+    fd: c_int,
+    identifier: u16,
+}
+
+impl EspIcmpSocket {
+    /// Create a socket with a random identifier
+    pub fn new() -> Result<Self, EspError> {
+        Self::with_identifier(unsafe { esp_random() } as u16)
+    }
+
+    /// Create a socket with the given identifier
+    ///
+    /// Only the messages answering echo requests with this identifier are received.
+    pub fn with_identifier(identifier: u16) -> Result<Self, EspError> {
+        let fd = unsafe { lwip_socket(AF_INET as _, SOCK_RAW as _, IPPROTO_ICMP as _) };
+
+        if fd < 0 {
+            return Err(last_error("create"));
+        }
+
+        Ok(Self { fd, identifier })
+    }
+
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    /// Set the time-to-live of the echo requests sent from now on
+    pub fn set_ttl(&mut self, ttl: u8) -> Result<(), EspError> {
+        self.setsockopt(IPPROTO_IP, IP_TTL, ttl as c_int)
+    }
+
+    /// Set the type of service of the echo requests sent from now on
+    pub fn set_tos(&mut self, tos: u8) -> Result<(), EspError> {
+        self.setsockopt(IPPROTO_IP, IP_TOS, tos as c_int)
+    }
+
+    /// Send an echo request to `addr`, with the identifier of the socket and `data` as payload
+    pub fn send_echo(
+        &mut self,
+        addr: ipv4::Ipv4Addr,
+        seqno: u16,
+        data: &[u8],
+    ) -> Result<(), EspError> {
+        let mut packet = Vec::with_capacity(ICMP_HEADER_LEN + data.len());
+
+        packet.extend_from_slice(&[ICMP_ECHO_REQUEST, 0, 0, 0]);
+        packet.extend_from_slice(&self.identifier.to_be_bytes());
+        packet.extend_from_slice(&seqno.to_be_bytes());
+        packet.extend_from_slice(data);
+
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut sin: sockaddr_in = unsafe { mem::zeroed() };
+        sin.sin_len = mem::size_of::<sockaddr_in>() as _;
+        sin.sin_family = AF_INET as _;
+        sin.sin_addr.s_addr = u32::from_ne_bytes(addr.octets());
+
+        let sent = unsafe {
+            lwip_sendto(
+                self.fd,
+                packet.as_ptr() as *const c_void,
+                packet.len() as _,
+                0,
+                &sin as *const _ as *const sockaddr,
+                mem::size_of::<sockaddr_in>() as _,
+            )
+        };
+
+        if sent < 0 {
+            Err(last_error("send"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait up to `timeout` for a message answering one of the echo requests of the socket
+    ///
+    /// Returns `None` on timeout. The messages answering the echo requests of other sockets
+    /// are skipped.
+    pub fn recv(&mut self, timeout: Duration) -> Result<Option<IcmpMessage>, EspError> {
+        let deadline = now() + timeout;
+
+        let mut buf = [0_u8; RECV_BUF_LEN];
+
+        loop {
+            let remaining = deadline.saturating_sub(now());
+
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+
+            let tv = timeval {
+                tv_sec: remaining.as_secs() as _,
+                // Never zero, as a zero timeout blocks forever
+                tv_usec: remaining.subsec_micros().max(1) as _,
+            };
+
+            self.setsockopt(SOL_SOCKET, SO_RCVTIMEO, tv)?;
+
+            let len =
+                unsafe { lwip_recv(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len() as _, 0) };
+
+            if len < 0 {
+                let errno = unsafe { *__errno() } as u32;
+
+                if errno == EAGAIN || errno == EWOULDBLOCK {
+                    return Ok(None);
+                }
+
+                return Err(last_error("receive"));
+            }
+
+            if let Some(message) = parse(&buf[..len as usize], self.identifier) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    fn setsockopt<T>(&mut self, level: u32, name: u32, value: T) -> Result<(), EspError> {
+        let ret = unsafe {
+            lwip_setsockopt(
+                self.fd,
+                level as _,
+                name as _,
+                &value as *const _ as *const c_void,
+                mem::size_of::<T>() as _,
+            )
+        };
+
+        if ret < 0 {
+            Err(last_error("configure"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for EspIcmpSocket {
+    fn drop(&mut self) {
+        unsafe { lwip_close(self.fd) };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracerouteConfiguration {
+    /// The time-to-live of the first probes
+    pub first_ttl: u8,
+    /// The time-to-live after which the target is given up
+    pub max_hops: u8,
+    /// The number of probes sent to each hop
+    pub probes: u32,
+    /// The time to wait for the answer to each probe
+    pub timeout: Duration,
+    pub data_size: u32,
+    pub tos: u8,
+}
+
+impl TracerouteConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            first_ttl: 1,
+            max_hops: 30,
+            probes: 3,
+            timeout: Duration::from_secs(1),
+            data_size: 32,
+            tos: 0,
+        }
+    }
+}
+
+impl Default for TracerouteConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A hop on the route to a target
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hop {
+    pub ttl: u8,
+    /// The address of the hop, or `None` if none of its probes were answered
+    pub addr: Option<ipv4::Ipv4Addr>,
+    /// The round-trip time of each probe, or `None` for the probes not answered in time
+    pub rtts: Vec<Option<Duration>>,
+    /// The code of the destination unreachable error reported by the hop, if any
+    pub unreachable: Option<u8>,
+}
+
+impl Hop {
+    /// The average round-trip time of the answered probes
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        let rtts = self.rtts.iter().flatten();
+        let count = rtts.clone().count() as u32;
+
+        (count > 0).then(|| rtts.sum::<Duration>() / count)
+    }
+}
+
+/// The result of a traceroute
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Traceroute {
+    pub hops: Vec<Hop>,
+    /// Whether the target answered the probes of the last hop
+    pub reached: bool,
+}
+
+/// Enumerate the hops to `target`
+pub fn traceroute(
+    target: ipv4::Ipv4Addr,
+    conf: &TracerouteConfiguration,
+) -> Result<Traceroute, EspError> {
+    traceroute_details(target, conf, |_| ())
+}
+
+/// Enumerate the hops to `target`, calling `hop_callback` with each hop as it is probed
+pub fn traceroute_details<F: FnMut(&Hop)>(
+    target: ipv4::Ipv4Addr,
+    conf: &TracerouteConfiguration,
+    mut hop_callback: F,
+) -> Result<Traceroute, EspError> {
+    info!(
+        "About to run a traceroute to {} with configuration {:?}",
+        target, conf
+    );
+
+    let mut socket = EspIcmpSocket::new()?;
+    socket.set_tos(conf.tos)?;
+
+    let data = (0..conf.data_size).map(|i| i as u8).collect::<Vec<_>>();
+
+    let mut route = Traceroute::default();
+    let mut seqno = 0_u16;
+
+    for ttl in conf.first_ttl.max(1)..=conf.max_hops {
+        socket.set_ttl(ttl)?;
+
+        let mut hop = Hop {
+            ttl,
+            addr: None,
+            rtts: Vec::with_capacity(conf.probes as _),
+            unreachable: None,
+        };
+
+        for _ in 0..conf.probes {
+            seqno = seqno.wrapping_add(1);
+
+            let sent = now();
+            socket.send_echo(target, seqno, &data)?;
+
+            let mut rtt = None;
+
+            // Skip the late answers to the previous probes
+            while let Some(message) = socket.recv(conf.timeout.saturating_sub(now() - sent))? {
+                if message.seqno != seqno {
+                    continue;
+                }
+
+                rtt = Some(now() - sent);
+                hop.addr.get_or_insert(message.from);
+
+                match message.kind {
+                    IcmpKind::EchoReply => route.reached = true,
+                    IcmpKind::TimeExceeded => (),
+                    IcmpKind::Unreachable(code) => hop.unreachable = Some(code),
+                }
+
+                break;
+            }
+
+            hop.rtts.push(rtt);
+        }
+
+        debug!("Hop {:?}", hop);
+
+        hop_callback(&hop);
+
+        let done = route.reached || hop.unreachable.is_some();
+
+        route.hops.push(hop);
+
+        if done {
+            break;
+        }
+    }
+
+    info!(
+        "Traceroute to {} complete with {} hops, reached: {}",
+        target,
+        route.hops.len(),
+        route.reached
+    );
+
+    Ok(route)
+}
+
+/// Parse an IP packet holding an ICMP message which answers an echo request with `identifier`
+fn parse(packet: &[u8], identifier: u16) -> Option<IcmpMessage> {
+    let (from, ttl, icmp) = split_ip(packet)?;
+
+    let (kind, echo) = match icmp[0] {
+        ICMP_ECHO_REPLY => (IcmpKind::EchoReply, icmp),
+        ICMP_TIME_EXCEEDED | ICMP_DEST_UNREACHABLE => {
+            // The error quotes the IP header and the ICMP header of the echo request
+            let (_, _, echo) = split_ip(&icmp[ICMP_HEADER_LEN..])?;
+
+            if echo[0] != ICMP_ECHO_REQUEST {
+                return None;
+            }
+
+            let kind = if icmp[0] == ICMP_TIME_EXCEEDED {
+                IcmpKind::TimeExceeded
+            } else {
+                IcmpKind::Unreachable(icmp[1])
+            };
+
+            (kind, echo)
+        }
+        _ => return None,
+    };
+
+    if u16::from_be_bytes([echo[4], echo[5]]) != identifier {
+        return None;
+    }
+
+    Some(IcmpMessage {
+        from,
+        ttl,
+        seqno: u16::from_be_bytes([echo[6], echo[7]]),
+        kind,
+    })
+}
+
+/// Split an IPv4 packet into its source, its TTL, and an ICMP header and payload
+fn split_ip(packet: &[u8]) -> Option<(ipv4::Ipv4Addr, u8, &[u8])> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let icmp = packet.get(header_len..)?;
+
+    if icmp.len() < ICMP_HEADER_LEN {
+        return None;
+    }
+
+    let from = ipv4::Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+
+    Some((from, packet[8], icmp))
+}
+
+/// The internet checksum of `data`
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
+
+fn last_error(op: &str) -> EspError {
+    warn!("Failed to {} the ICMP socket, errno {}", op, unsafe {
+        *__errno()
+    });
+
+    EspError::from_infallible::<ESP_FAIL>()
+}
@@ -0,0 +1,168 @@
+//! Power management
+//!
+//! With `CONFIG_PM_ENABLE`, the ESP-IDF scales the CPU and APB frequencies between a minimum
+//! and a maximum (DFS), and enters light sleep automatically when idle, unless a power
+//! management lock is held. `configure` sets the frequencies and the automatic light sleep,
+//! and `EspPmLock` keeps the maximum frequencies or prevents light sleep during latency
+//! critical sections:
+//!
+//! ```ignore
+//! use esp_idf_svc::pm::{self, EspPmLock, LockType, PmConfiguration};
+//!
+//! pm::configure(&PmConfiguration {
+//!     max_freq_mhz: 160,
+//!     min_freq_mhz: 40,
+//!     light_sleep_enable: true,
+//! })?;
+//!
+//! let lock = EspPmLock::new(LockType::CpuFreqMax, c"sampling")?;
+//!
+//! {
+//!     let _guard = lock.acquire()?;
+//!     // At the maximum CPU frequency
+//! }
+//! ```
+
+use core::ffi::CStr;
+use core::ptr;
+
+use crate::sys::*;
+
+/// The power management configuration
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PmConfiguration {
+    /// The maximum CPU frequency, in MHz
+    pub max_freq_mhz: u32,
+    /// The minimum CPU frequency, in MHz, used when no lock is held; usually the XTAL
+    /// frequency
+    pub min_freq_mhz: u32,
+    /// Whether to enter light sleep automatically when idle; requires
+    /// `CONFIG_FREERTOS_USE_TICKLESS_IDLE`
+    pub light_sleep_enable: bool,
+}
+
+impl PmConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            max_freq_mhz: 160,
+            min_freq_mhz: 40,
+            light_sleep_enable: false,
+        }
+    }
+}
+
+impl Default for PmConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&PmConfiguration> for esp_pm_config_t {
+    fn from(conf: &PmConfiguration) -> Self {
+        Self {
+            max_freq_mhz: conf.max_freq_mhz as _,
+            min_freq_mhz: conf.min_freq_mhz as _,
+            light_sleep_enable: conf.light_sleep_enable,
+        }
+    }
+}
+
+impl From<&esp_pm_config_t> for PmConfiguration {
+    fn from(conf: &esp_pm_config_t) -> Self {
+        Self {
+            max_freq_mhz: conf.max_freq_mhz as _,
+            min_freq_mhz: conf.min_freq_mhz as _,
+            light_sleep_enable: conf.light_sleep_enable,
+        }
+    }
+}
+
+/// Set the power management configuration
+///
+/// Fails with `ESP_ERR_INVALID_ARG` for frequencies not supported by the chip, and with
+/// `ESP_ERR_NOT_SUPPORTED` when light sleep is enabled without tickless idle.
+pub fn configure(conf: &PmConfiguration) -> Result<(), EspError> {
+    let conf: esp_pm_config_t = conf.into();
+
+    esp!(unsafe { esp_pm_configure(&conf as *const _ as *const _) })
+}
+
+/// Get the current power management configuration
+pub fn configuration() -> Result<PmConfiguration, EspError> {
+    let mut conf: esp_pm_config_t = Default::default();
+
+    esp!(unsafe { esp_pm_get_configuration(&mut conf as *mut _ as *mut _) })?;
+
+    Ok((&conf).into())
+}
+
+/// The constraint a power management lock puts while held
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockType {
+    /// Keep the CPU at the maximum frequency
+    CpuFreqMax,
+    /// Keep the APB at the maximum frequency, e.g. for peripherals clocked by it
+    ApbFreqMax,
+    /// Prevent automatic light sleep
+    NoLightSleep,
+}
+
+impl From<LockType> for esp_pm_lock_type_t {
+    fn from(lock_type: LockType) -> Self {
+        match lock_type {
+            LockType::CpuFreqMax => esp_pm_lock_type_t_ESP_PM_CPU_FREQ_MAX,
+            LockType::ApbFreqMax => esp_pm_lock_type_t_ESP_PM_APB_FREQ_MAX,
+            LockType::NoLightSleep => esp_pm_lock_type_t_ESP_PM_NO_LIGHT_SLEEP,
+        }
+    }
+}
+
+/// A power management lock
+///
+/// The lock is recursive: its constraint holds while at least one `PmLockGuard` is alive.
+pub struct EspPmLock(esp_pm_lock_handle_t);
+
+impl EspPmLock {
+    /// Create a lock
+    ///
+    /// # Arguments
+    /// - `lock_type`: The constraint of the lock
+    /// - `name`: The name of the lock, reported by `esp_pm_dump_locks`
+    pub fn new(lock_type: LockType, name: &'static CStr) -> Result<Self, EspError> {
+        let mut handle = ptr::null_mut();
+
+        esp!(unsafe { esp_pm_lock_create(lock_type.into(), 0, name.as_ptr(), &mut handle) })?;
+
+        Ok(Self(handle))
+    }
+
+    /// Acquire the lock, until the returned guard is dropped
+    pub fn acquire(&self) -> Result<PmLockGuard<'_>, EspError> {
+        esp!(unsafe { esp_pm_lock_acquire(self.0) })?;
+
+        Ok(PmLockGuard(self))
+    }
+}
+
+impl Drop for EspPmLock {
+    fn drop(&mut self) {
+        unsafe {
+            esp_pm_lock_delete(self.0);
+        }
+    }
+}
+
+// The locks are safe to use from any task and from ISRs
+unsafe impl Send for EspPmLock {}
+unsafe impl Sync for EspPmLock {}
+
+/// Releases the lock when dropped
+pub struct PmLockGuard<'a>(&'a EspPmLock);
+
+impl Drop for PmLockGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            esp_pm_lock_release(self.0 .0);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(unused)]
 
+#[cfg(feature = "alloc")]
+pub mod base64;
 pub mod common;
 pub mod cstr;
 pub mod mutex;
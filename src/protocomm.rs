@@ -0,0 +1,486 @@
+//! Protocomm, the framing and session security layer of the ESP-IDF provisioning
+//!
+//! `EspProtocomm` exposes the `protocomm` component directly, so that custom onboarding or
+//! diagnostic protocols can reuse the transports - an HTTP server, a GATT service or the console
+//! - and the session security of the Espressif provisioning, with endpoints served by Rust
+//! handlers:
+//!
+//! ```ignore
+//! let mut pc = EspProtocomm::new()?;
+//!
+//! pc.set_version("proto-ver", r#"{"diag":{"ver":"v1.0"}}"#)?;
+//! pc.set_security("diag-session", &ProtocommSecurity::Security1 { pop: Some("abcd1234") })?;
+//!
+//! pc.add_endpoint("diag-dump", |_session_id, _request| Ok(diagnostics().into_bytes()))?;
+//!
+//! pc.start_httpd(&HttpdTransportConfiguration::default())?;
+//! ```
+//!
+//! Once a security is set, the payloads of all the other endpoints are encrypted with the key of
+//! the session established on the session endpoint, and the handlers receive and return them in
+//! clear.
+
+use core::ffi;
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+use core::ptr;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+
+use ::log::info;
+
+use crate::private::cstr::*;
+use crate::sys::*;
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use crate::handle::RawHandle;
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+use crate::http::server::EspHttpServer;
+
+type EndpointHandler = Box<dyn FnMut(u32, &[u8]) -> Result<Vec<u8>, EspError> + Send + 'static>;
+
+/// The security of the protocomm sessions
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProtocommSecurity<'a> {
+    /// No encryption and no authentication
+    None,
+    /// Curve25519 key exchange and AES-CTR encryption, with an optional proof-of-possession
+    Security1 { pop: Option<&'a str> },
+    /// SRP6a key exchange and AES-GCM encryption; the salt and the verifier are derived
+    /// from the username and the password entered by the user
+    Security2 { salt: &'a [u8], verifier: &'a [u8] },
+}
+
+/// The configuration of the HTTP server transport, started by protocomm
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HttpdTransportConfiguration {
+    pub port: u16,
+    pub stack_size: usize,
+    pub task_priority: u32,
+}
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+impl HttpdTransportConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            port: 80,
+            stack_size: 4096,
+            task_priority: 5,
+        }
+    }
+}
+
+#[cfg(esp_idf_comp_esp_http_server_enabled)]
+impl Default for HttpdTransportConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The configuration of the GATT service transport
+#[cfg(esp_idf_bt_enabled)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BleTransportConfiguration<'a> {
+    pub device_name: &'a str,
+    /// The 128-bit UUID of the GATT service, in little endian byte order
+    pub service_uuid: [u8; 16],
+    /// The 16-bit UUIDs of the characteristics of the endpoints, by endpoint name; they replace
+    /// the bytes 12 and 13 of the service UUID
+    pub endpoints: &'a [(&'a str, u16)],
+}
+
+/// The configuration of the console transport
+#[cfg(esp_idf_comp_console_enabled)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConsoleTransportConfiguration {
+    pub stack_size: usize,
+    pub task_priority: u32,
+}
+
+#[cfg(esp_idf_comp_console_enabled)]
+impl ConsoleTransportConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            stack_size: 4096,
+            task_priority: 5,
+        }
+    }
+}
+
+#[cfg(esp_idf_comp_console_enabled)]
+impl Default for ConsoleTransportConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Transport {
+    #[cfg(esp_idf_comp_esp_http_server_enabled)]
+    Httpd,
+    #[cfg(esp_idf_bt_enabled)]
+    Ble,
+    #[cfg(esp_idf_comp_console_enabled)]
+    Console,
+}
+
+/// A protocomm instance, with its endpoints
+///
+/// Dropping it stops its transport and deletes it.
+pub struct EspProtocomm<'a> {
+    pc: *mut protocomm_t,
+    transport: Option<Transport>,
+    endpoints: Vec<(CString, Box<EndpointHandler>)>,
+    // Protocomm keeps pointers to these until it is deleted
+    versions: Vec<(CString, CString)>,
+    security: Vec<(CString, Box<SecurityParams>)>,
+    #[cfg(esp_idf_bt_enabled)]
+    ble_names: Vec<CString>,
+    _server: PhantomData<&'a ()>,
+}
+
+enum SecurityParams {
+    None,
+    Security1(CString, protocomm_security1_params_t),
+    Security2(Vec<u8>, Vec<u8>, protocomm_security2_params_t),
+}
+
+impl<'a> EspProtocomm<'a> {
+    pub fn new() -> Result<Self, EspError> {
+        let pc = unsafe { protocomm_new() };
+
+        if pc.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        Ok(Self {
+            pc,
+            transport: None,
+            endpoints: Vec::new(),
+            versions: Vec::new(),
+            security: Vec::new(),
+            #[cfg(esp_idf_bt_enabled)]
+            ble_names: Vec::new(),
+            _server: PhantomData,
+        })
+    }
+
+    /// Serve the version of the protocol - usually as a JSON document - on an endpoint, e.g.
+    /// `proto-ver`
+    pub fn set_version(&mut self, endpoint: &str, version: &str) -> Result<(), EspError> {
+        let endpoint = to_cstring_arg(endpoint)?;
+        let version = to_cstring_arg(version)?;
+
+        esp!(unsafe { protocomm_set_version(self.pc, endpoint.as_ptr(), version.as_ptr()) })?;
+
+        self.versions.push((endpoint, version));
+
+        Ok(())
+    }
+
+    /// Establish the sessions on an endpoint, e.g. `prov-session`, with the given security
+    pub fn set_security(
+        &mut self,
+        endpoint: &str,
+        security: &ProtocommSecurity,
+    ) -> Result<(), EspError> {
+        let endpoint = to_cstring_arg(endpoint)?;
+
+        let mut params = Box::new(match security {
+            ProtocommSecurity::None => SecurityParams::None,
+            ProtocommSecurity::Security1 { pop } => SecurityParams::Security1(
+                pop.map(to_cstring_arg).transpose()?.unwrap_or_default(),
+                protocomm_security1_params_t {
+                    data: ptr::null(),
+                    len: 0,
+                },
+            ),
+            ProtocommSecurity::Security2 { salt, verifier } => {
+                if salt.len() > u16::MAX as usize || verifier.len() > u16::MAX as usize {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+                }
+
+                SecurityParams::Security2(
+                    salt.to_vec(),
+                    verifier.to_vec(),
+                    protocomm_security2_params_t {
+                        salt: ptr::null(),
+                        salt_len: salt.len() as _,
+                        verifier: ptr::null(),
+                        verifier_len: verifier.len() as _,
+                    },
+                )
+            }
+        });
+
+        let (sec, sec_params): (*const protocomm_security_t, *const ffi::c_void) =
+            match &mut *params {
+                SecurityParams::None => {
+                    (unsafe { ptr::addr_of!(protocomm_security0) }, ptr::null())
+                }
+                SecurityParams::Security1(pop, raw) => {
+                    let raw_ptr = if pop.as_bytes().is_empty() {
+                        ptr::null()
+                    } else {
+                        raw.data = pop.as_ptr() as *const _;
+                        raw.len = pop.as_bytes().len() as _;
+
+                        raw as *const _ as *const ffi::c_void
+                    };
+
+                    (unsafe { ptr::addr_of!(protocomm_security1) }, raw_ptr)
+                }
+                SecurityParams::Security2(salt, verifier, raw) => {
+                    raw.salt = salt.as_ptr() as *const _;
+                    raw.verifier = verifier.as_ptr() as *const _;
+
+                    (
+                        unsafe { ptr::addr_of!(protocomm_security2) },
+                        raw as *const _ as *const ffi::c_void,
+                    )
+                }
+            };
+
+        esp!(unsafe { protocomm_set_security(self.pc, endpoint.as_ptr(), sec, sec_params) })?;
+
+        self.security.push((endpoint, params));
+
+        Ok(())
+    }
+
+    /// Serve an endpoint
+    ///
+    /// The handler receives the session ID and the (decrypted) request payload, and returns the
+    /// response payload.
+    pub fn add_endpoint<F>(&mut self, name: &str, handler: F) -> Result<(), EspError>
+    where
+        F: FnMut(u32, &[u8]) -> Result<Vec<u8>, EspError> + Send + 'static,
+    {
+        let name = to_cstring_arg(name)?;
+
+        let handler: Box<EndpointHandler> = Box::new(Box::new(handler));
+        let handler_ptr = &*handler as *const EndpointHandler as *mut ffi::c_void;
+
+        esp!(unsafe {
+            protocomm_add_endpoint(
+                self.pc,
+                name.as_ptr(),
+                Some(Self::handle_endpoint),
+                handler_ptr,
+            )
+        })?;
+
+        self.endpoints.push((name, handler));
+
+        Ok(())
+    }
+
+    /// Stop serving an endpoint
+    pub fn remove_endpoint(&mut self, name: &str) -> Result<(), EspError> {
+        let index = self
+            .endpoints
+            .iter()
+            .position(|(endpoint, _)| endpoint.to_bytes() == name.as_bytes())
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        esp!(unsafe { protocomm_remove_endpoint(self.pc, self.endpoints[index].0.as_ptr()) })?;
+
+        self.endpoints.remove(index);
+
+        Ok(())
+    }
+
+    /// Start an HTTP server serving the endpoints, as `POST /<endpoint>`
+    #[cfg(esp_idf_comp_esp_http_server_enabled)]
+    pub fn start_httpd(&mut self, conf: &HttpdTransportConfiguration) -> Result<(), EspError> {
+        self.check_stopped()?;
+
+        let config = protocomm_httpd_config_t {
+            data: protocomm_httpd_config_data_t {
+                config: protocomm_http_server_config_t {
+                    port: conf.port,
+                    stack_size: conf.stack_size as _,
+                    task_priority: conf.task_priority as _,
+                },
+            },
+            ext_handle_provided: false,
+        };
+
+        self.started(Transport::Httpd, unsafe {
+            protocomm_httpd_start(self.pc, &config)
+        })
+    }
+
+    /// Serve the endpoints, as `POST /<endpoint>`, on an existing HTTP server
+    #[cfg(esp_idf_comp_esp_http_server_enabled)]
+    pub fn start_on_server(&mut self, server: &'a EspHttpServer<'a>) -> Result<(), EspError> {
+        self.check_stopped()?;
+
+        let config = protocomm_httpd_config_t {
+            data: protocomm_httpd_config_data_t {
+                handle: server.handle() as *mut ffi::c_void,
+            },
+            ext_handle_provided: true,
+        };
+
+        self.started(Transport::Httpd, unsafe {
+            protocomm_httpd_start(self.pc, &config)
+        })
+    }
+
+    /// Start a GATT service serving the endpoints, as characteristics
+    #[cfg(esp_idf_bt_enabled)]
+    pub fn start_ble(&mut self, conf: &BleTransportConfiguration) -> Result<(), EspError> {
+        self.check_stopped()?;
+
+        self.ble_names = conf
+            .endpoints
+            .iter()
+            .map(|(name, _)| to_cstring_arg(name))
+            .collect::<Result<_, _>>()?;
+
+        let mut nu_lookup = self
+            .ble_names
+            .iter()
+            .zip(conf.endpoints)
+            .map(|(name, (_, uuid))| protocomm_ble_name_uuid_t {
+                name: name.as_ptr(),
+                uuid: *uuid,
+            })
+            .collect::<Vec<_>>();
+
+        let mut config: protocomm_ble_config_t = Default::default();
+
+        set_str(
+            c_char_to_u8_slice_mut(&mut config.device_name),
+            conf.device_name,
+        )?;
+        config.service_uuid = conf.service_uuid;
+        config.nu_lookup_count = nu_lookup.len() as _;
+        config.nu_lookup = nu_lookup.as_mut_ptr();
+
+        // Protocomm copies the lookup table
+        self.started(Transport::Ble, unsafe {
+            protocomm_ble_start(self.pc, &config)
+        })
+    }
+
+    /// Serve the endpoints on the console, as `<endpoint> <hex payload>` commands
+    #[cfg(esp_idf_comp_console_enabled)]
+    pub fn start_console(&mut self, conf: &ConsoleTransportConfiguration) -> Result<(), EspError> {
+        self.check_stopped()?;
+
+        let config = protocomm_console_config_t {
+            stack_size: conf.stack_size as _,
+            task_priority: conf.task_priority as _,
+        };
+
+        self.started(Transport::Console, unsafe {
+            protocomm_console_start(self.pc, &config)
+        })
+    }
+
+    /// Stop the transport
+    pub fn stop(&mut self) -> Result<(), EspError> {
+        match self.transport.take() {
+            #[cfg(esp_idf_comp_esp_http_server_enabled)]
+            Some(Transport::Httpd) => esp!(unsafe { protocomm_httpd_stop(self.pc) })?,
+            #[cfg(esp_idf_bt_enabled)]
+            Some(Transport::Ble) => esp!(unsafe { protocomm_ble_stop(self.pc) })?,
+            #[cfg(esp_idf_comp_console_enabled)]
+            Some(Transport::Console) => esp!(unsafe { protocomm_console_stop(self.pc) })?,
+            None => return Ok(()),
+        }
+
+        info!("Transport stopped");
+
+        Ok(())
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    fn check_stopped(&self) -> Result<(), EspError> {
+        if self.transport.is_some() {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn started(&mut self, transport: Transport, err: esp_err_t) -> Result<(), EspError> {
+        esp!(err)?;
+
+        self.transport = Some(transport);
+
+        info!("Transport {:?} started", transport);
+
+        Ok(())
+    }
+
+    unsafe extern "C" fn handle_endpoint(
+        session_id: u32,
+        inbuf: *const u8,
+        inlen: ssize_t,
+        outbuf: *mut *mut u8,
+        outlen: *mut ssize_t,
+        priv_data: *mut ffi::c_void,
+    ) -> esp_err_t {
+        let handler = (priv_data as *mut EndpointHandler).as_mut().unwrap();
+
+        let request = if inbuf.is_null() || inlen <= 0 {
+            &[]
+        } else {
+            core::slice::from_raw_parts(inbuf, inlen as _)
+        };
+
+        match handler(session_id, request) {
+            Ok(response) => {
+                // Protocomm frees the response with `free`
+                let buf = malloc(response.len().max(1) as _) as *mut u8;
+                if buf.is_null() {
+                    return ESP_ERR_NO_MEM;
+                }
+
+                ptr::copy_nonoverlapping(response.as_ptr(), buf, response.len());
+
+                *outbuf = buf;
+                *outlen = response.len() as _;
+
+                ESP_OK
+            }
+            Err(err) => err.code(),
+        }
+    }
+}
+
+impl Drop for EspProtocomm<'_> {
+    fn drop(&mut self) {
+        self.stop().unwrap();
+
+        unsafe { protocomm_delete(self.pc) };
+    }
+}
+
+unsafe impl Send for EspProtocomm<'_> {}
+
+impl Debug for EspProtocomm<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspProtocomm")
+            .field("transport", &self.transport)
+            .field(
+                "endpoints",
+                &self
+                    .endpoints
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,76 @@
+//! Random numbers from the hardware random number generator
+//!
+//! `EspRng` - and `fill` - draw from the random number generator of the chip, which is also
+//! the one behind the keys, nonces and IDs generated by this crate. With the `rand_core`
+//! feature, `EspRng` implements `RngCore` and `CryptoRng`, so it can be used with the `rand`
+//! ecosystem, e.g. to generate keys:
+//!
+//! ```ignore
+//! let key = p256::SecretKey::random(&mut EspRng::new());
+//! ```
+//!
+//! The generator only produces true random numbers while it has a source of entropy, that is:
+//! - while Wi-Fi or Bluetooth is enabled, the RF subsystem being the entropy source;
+//! - or, before either is enabled, after the SAR ADC was enabled as the entropy source with
+//!   `bootloader_random_enable` - which precludes using the ADC and the RF until
+//!   `bootloader_random_disable`.
+//!
+//! The bootloader enables the ADC source while it runs, so the generator is seeded at boot,
+//! but without either source the numbers are only pseudo-random: keys should then not be
+//! generated before the radio is up.
+
+use core::ffi::c_void;
+
+use crate::sys::*;
+
+/// Fill `buf` with random bytes
+pub fn fill(buf: &mut [u8]) {
+    unsafe { esp_fill_random(buf.as_mut_ptr() as *mut c_void, buf.len()) };
+}
+
+/// The hardware random number generator
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EspRng(());
+
+impl EspRng {
+    pub const fn new() -> Self {
+        Self(())
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        unsafe { esp_random() }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        fill(buf)
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for EspRng {
+    fn next_u32(&mut self) -> u32 {
+        EspRng::next_u32(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        EspRng::next_u64(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        EspRng::fill_bytes(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        EspRng::fill_bytes(self, dest);
+
+        Ok(())
+    }
+}
+
+// See the entropy requirements in the documentation of the module
+#[cfg(feature = "rand_core")]
+impl rand_core::CryptoRng for EspRng {}
@@ -0,0 +1,206 @@
+//! Secure storage of secrets in NVS, sealed with a device-unique key
+//!
+//! `EspSecureStore` encrypts and authenticates each value with AES-256-GCM before storing it in
+//! NVS, with a key derived by the HMAC peripheral from an HMAC key burnt into an eFuse key block.
+//! This key cannot be read back by the software, so the sealed values - e.g. cloud tokens or user
+//! credentials - can only be unsealed on the same device, even if the flash is not encrypted and
+//! is dumped.
+//!
+//! The HMAC key is burnt once, e.g. with `espefuse.py burn_key BLOCK_KEY4 hmac_key.bin HMAC_UP`,
+//! or on the device with `esp_efuse_write_key`. Note that the same eFuse key can also protect the
+//! NVS encryption keys (`CONFIG_NVS_SEC_KEY_PROTECT_USING_HMAC`), in which case the NVS partition
+//! itself is encrypted as well.
+//!
+//! The sealed values are bound to their name, so a sealed value copied under another name does
+//! not unseal.
+
+use core::fmt::{self, Debug};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::nvs::{EspNvs, NvsPartitionId};
+use crate::sys::*;
+
+// Derives the encryption key of the store from the eFuse HMAC key
+const KEY_DERIVATION_LABEL: &[u8] = b"esp-idf-svc secure_store v1";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// The eFuse key block holding the HMAC key, burnt with the `HMAC_UP` purpose
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum HmacKey {
+    Key0 = hmac_key_id_t_HMAC_KEY0,
+    Key1 = hmac_key_id_t_HMAC_KEY1,
+    Key2 = hmac_key_id_t_HMAC_KEY2,
+    Key3 = hmac_key_id_t_HMAC_KEY3,
+    Key4 = hmac_key_id_t_HMAC_KEY4,
+    Key5 = hmac_key_id_t_HMAC_KEY5,
+}
+
+/// An NVS namespace whose values are sealed with a device-unique key
+pub struct EspSecureStore<T: NvsPartitionId> {
+    nvs: EspNvs<T>,
+    key: [u8; KEY_LEN],
+}
+
+impl<T: NvsPartitionId> EspSecureStore<T> {
+    /// Create the store on top of an NVS namespace, dedicated to the sealed values
+    ///
+    /// Returns an error if the eFuse key block does not hold an HMAC key with the `HMAC_UP`
+    /// purpose.
+    pub fn new(nvs: EspNvs<T>, hmac_key: HmacKey) -> Result<Self, EspError> {
+        let mut key = [0; KEY_LEN];
+
+        esp!(unsafe {
+            esp_hmac_calculate(
+                hmac_key as _,
+                KEY_DERIVATION_LABEL.as_ptr() as *const _,
+                KEY_DERIVATION_LABEL.len(),
+                key.as_mut_ptr(),
+            )
+        })?;
+
+        Ok(Self { nvs, key })
+    }
+
+    /// Encrypt `data` and store it under `name`, replacing any previous value
+    pub fn seal(&mut self, name: &str, data: &[u8]) -> Result<(), EspError> {
+        // The sealed value is the nonce, followed by the tag and the ciphertext
+        let mut sealed = alloc::vec![0; NONCE_LEN + TAG_LEN + data.len()];
+        let (nonce, rest) = sealed.split_at_mut(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at_mut(TAG_LEN);
+
+        crate::rng::fill(nonce);
+
+        self.with_gcm(|gcm| {
+            Self::check(unsafe {
+                mbedtls_gcm_crypt_and_tag(
+                    gcm,
+                    MBEDTLS_GCM_ENCRYPT as _,
+                    data.len(),
+                    nonce.as_ptr(),
+                    nonce.len(),
+                    name.as_ptr(),
+                    name.len(),
+                    data.as_ptr(),
+                    ciphertext.as_mut_ptr(),
+                    tag.len(),
+                    tag.as_mut_ptr(),
+                )
+            })
+        })?;
+
+        self.nvs.set_raw(name, &sealed)?;
+
+        Ok(())
+    }
+
+    /// Decrypt the value stored under `name`, or return `None` if there is none
+    ///
+    /// Returns `ESP_ERR_INVALID_CRC` if the value was tampered with, or was sealed on another
+    /// device or with another HMAC key.
+    pub fn unseal(&self, name: &str) -> Result<Option<Vec<u8>>, EspError> {
+        let Some(len) = self.nvs.blob_len(name)? else {
+            return Ok(None);
+        };
+
+        if len < NONCE_LEN + TAG_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>());
+        }
+
+        let mut sealed = alloc::vec![0; len];
+        let Some(sealed) = self.nvs.get_raw(name, &mut sealed)? else {
+            return Ok(None);
+        };
+
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+
+        let mut data = alloc::vec![0; ciphertext.len()];
+
+        self.with_gcm(|gcm| {
+            let ret = unsafe {
+                mbedtls_gcm_auth_decrypt(
+                    gcm,
+                    ciphertext.len(),
+                    nonce.as_ptr(),
+                    nonce.len(),
+                    name.as_ptr(),
+                    name.len(),
+                    tag.as_ptr(),
+                    tag.len(),
+                    ciphertext.as_ptr(),
+                    data.as_mut_ptr(),
+                )
+            };
+
+            if ret == MBEDTLS_ERR_GCM_AUTH_FAILED {
+                Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>())
+            } else {
+                Self::check(ret)
+            }
+        })?;
+
+        Ok(Some(data))
+    }
+
+    /// Return `true` if a value is stored under `name`
+    pub fn contains(&self, name: &str) -> Result<bool, EspError> {
+        self.nvs.contains(name)
+    }
+
+    /// Remove the value stored under `name`, returning `false` if there was none
+    pub fn remove(&mut self, name: &str) -> Result<bool, EspError> {
+        self.nvs.remove(name)
+    }
+
+    fn with_gcm<R>(
+        &self,
+        f: impl FnOnce(&mut mbedtls_gcm_context) -> Result<R, EspError>,
+    ) -> Result<R, EspError> {
+        let mut gcm: mbedtls_gcm_context = unsafe { core::mem::zeroed() };
+
+        unsafe { mbedtls_gcm_init(&mut gcm) };
+
+        let result = Self::check(unsafe {
+            mbedtls_gcm_setkey(
+                &mut gcm,
+                mbedtls_cipher_id_t_MBEDTLS_CIPHER_ID_AES,
+                self.key.as_ptr(),
+                (KEY_LEN * 8) as _,
+            )
+        })
+        .and_then(|_| f(&mut gcm));
+
+        unsafe { mbedtls_gcm_free(&mut gcm) };
+
+        result
+    }
+
+    fn check(ret: core::ffi::c_int) -> Result<(), EspError> {
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(EspError::from(ret).unwrap())
+        }
+    }
+}
+
+impl<T: NvsPartitionId> Drop for EspSecureStore<T> {
+    fn drop(&mut self) {
+        // Do not leave the key in RAM
+        for byte in &mut self.key {
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+impl<T: NvsPartitionId> Debug for EspSecureStore<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspSecureStore").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,128 @@
+//! Secure boot and flash encryption status
+//!
+//! `status` reports the security posture of the chip - whether secure boot and flash
+//! encryption are enabled, and in which mode - so that the firmware can refuse to handle
+//! secrets on units which are not secured, and report the posture of a fleet to the cloud:
+//!
+//! ```ignore
+//! let status = security::status();
+//!
+//! if !status.is_release() {
+//!     warn!("Not a production unit: {status:?}");
+//! }
+//! ```
+//!
+//! The development modes keep the chip reflashable - e.g. flash encryption in development
+//! mode leaves the UART download mode able to write plaintext - so they do not protect the
+//! secrets of a device in the field.
+
+use crate::sys::*;
+
+use crate::efuse;
+
+/// The mode of a security feature
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SecurityMode {
+    Disabled,
+    /// Enabled, but with the protections which keep the chip reflashable or debuggable on
+    Development,
+    /// Enabled, with all its protections burned in the eFuses
+    Release,
+}
+
+impl SecurityMode {
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, Self::Disabled)
+    }
+}
+
+/// The version of the secure boot scheme
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SecureBootVersion {
+    /// The AES-based scheme of the ESP32
+    V1,
+    /// The scheme based on RSA-PSS or ECDSA signatures
+    V2,
+}
+
+/// The security posture of the chip
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SecurityStatus {
+    pub secure_boot: SecurityMode,
+    /// The version of secure boot the firmware was built for, if secure boot is enabled
+    pub secure_boot_version: Option<SecureBootVersion>,
+    pub flash_encryption: SecurityMode,
+    /// The number of eFuse key blocks still free for secure boot key digests, flash encryption
+    /// keys or HMAC keys; `None` on the ESP32, which has no such key blocks
+    pub free_key_blocks: Option<u32>,
+    /// Whether the JTAG interface is disabled
+    pub jtag_disabled: bool,
+    /// Whether the UART download mode of the ROM is disabled
+    pub download_mode_disabled: bool,
+}
+
+impl SecurityStatus {
+    /// Whether both secure boot and flash encryption are enabled in release mode
+    pub fn is_release(&self) -> bool {
+        self.secure_boot == SecurityMode::Release && self.flash_encryption == SecurityMode::Release
+    }
+}
+
+/// Get the security posture of the chip
+pub fn status() -> SecurityStatus {
+    let flags = efuse::security_flags();
+
+    let secure_boot = if !flags.secure_boot {
+        SecurityMode::Disabled
+    } else if secure_boot_release_mode() {
+        SecurityMode::Release
+    } else {
+        SecurityMode::Development
+    };
+
+    let secure_boot_version = if !flags.secure_boot {
+        None
+    } else if cfg!(esp_idf_secure_boot_v2_enabled) {
+        Some(SecureBootVersion::V2)
+    } else if cfg!(esp_idf_secure_boot_v1_enabled) {
+        Some(SecureBootVersion::V1)
+    } else {
+        None
+    };
+
+    #[allow(non_upper_case_globals)]
+    let flash_encryption = match unsafe { esp_get_flash_encryption_mode() } {
+        esp_flash_enc_mode_t_ESP_FLASH_ENC_MODE_RELEASE => SecurityMode::Release,
+        esp_flash_enc_mode_t_ESP_FLASH_ENC_MODE_DEVELOPMENT => SecurityMode::Development,
+        _ => SecurityMode::Disabled,
+    };
+
+    #[cfg(not(esp32))]
+    let free_key_blocks = Some(unsafe { esp_efuse_find_unused_key_block_count() } as u32);
+    #[cfg(esp32)]
+    let free_key_blocks = None;
+
+    SecurityStatus {
+        secure_boot,
+        secure_boot_version,
+        flash_encryption,
+        free_key_blocks,
+        jtag_disabled: flags.jtag_disabled,
+        download_mode_disabled: flags.download_mode_disabled,
+    }
+}
+
+/// Whether the eFuses burned match the release mode of secure boot
+fn secure_boot_release_mode() -> bool {
+    // Only declared when the firmware is built with secure boot. Note that it logs a warning
+    // for each eFuse not burned as the release mode requires
+    #[cfg(esp_idf_secure_boot)]
+    {
+        unsafe { esp_secure_boot_cfg_verify_release_mode() }
+    }
+
+    #[cfg(not(esp_idf_secure_boot))]
+    {
+        false
+    }
+}
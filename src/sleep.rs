@@ -0,0 +1,244 @@
+//! Light and deep sleep
+//!
+//! `Sleep` configures the wakeup sources - timer, RTC GPIOs (ext0 / ext1), GPIOs, touch pads,
+//! the ULP coprocessor and UARTs - and enters light or deep sleep:
+//!
+//! ```ignore
+//! use esp_idf_svc::sleep::{Sleep, WakeupCause};
+//!
+//! if WakeupCause::get() == WakeupCause::Timer {
+//!     // ...
+//! }
+//!
+//! Sleep::new()
+//!     .timer(Duration::from_secs(60))?
+//!     .ext0(&peripherals.pins.gpio4, false)?
+//!     .deep_sleep();
+//! ```
+//!
+//! WiFi and Bluetooth connections are not kept while sleeping: entering deep sleep stops WiFi
+//! and disables the Bluetooth controller, as required by the ESP-IDF, and light sleep fails
+//! with `ESP_ERR_SLEEP_REJECT` if one of them is still running.
+
+use core::time::Duration;
+
+use crate::hal::gpio::InputPin;
+#[cfg(any(
+    esp_idf_soc_pm_support_ext0_wakeup,
+    esp_idf_soc_pm_support_ext_wakeup,
+    esp_idf_soc_pm_support_ext1_wakeup
+))]
+use crate::hal::gpio::RTCPin;
+use crate::hal::uart::UartDriver;
+use crate::sys::*;
+
+/// The cause of the last wakeup from sleep
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WakeupCause {
+    /// Not a wakeup from sleep, e.g. a power-on reset
+    Undefined,
+    Ext0,
+    Ext1,
+    Timer,
+    Touchpad,
+    Ulp,
+    Gpio,
+    Uart,
+    Wifi,
+    Bt,
+    Other(u32),
+}
+
+impl WakeupCause {
+    /// Get the cause of the last wakeup, e.g. after boot following deep sleep
+    pub fn get() -> Self {
+        #[allow(non_upper_case_globals)]
+        match unsafe { esp_sleep_get_wakeup_cause() } {
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => Self::Undefined,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => Self::Ext0,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 => Self::Ext1,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => Self::Timer,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_TOUCHPAD => Self::Touchpad,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_ULP => Self::Ulp,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_GPIO => Self::Gpio,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_UART => Self::Uart,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_WIFI => Self::Wifi,
+            esp_sleep_source_t_ESP_SLEEP_WAKEUP_BT => Self::Bt,
+            other => Self::Other(other as _),
+        }
+    }
+
+    /// The RTC GPIOs which caused an ext1 wakeup, as a bit mask of GPIO numbers
+    #[cfg(any(esp_idf_soc_pm_support_ext_wakeup, esp_idf_soc_pm_support_ext1_wakeup))]
+    pub fn ext1_pins() -> u64 {
+        unsafe { esp_sleep_get_ext1_wakeup_status() }
+    }
+
+    /// The GPIOs which caused a GPIO wakeup from deep sleep, as a bit mask of GPIO numbers
+    #[cfg(esp_idf_soc_gpio_support_deepsleep_wakeup)]
+    pub fn gpio_pins() -> u64 {
+        unsafe { esp_sleep_get_gpio_wakeup_status() }
+    }
+}
+
+/// The level of the ext1 RTC GPIOs waking up the chip
+#[cfg(any(esp_idf_soc_pm_support_ext_wakeup, esp_idf_soc_pm_support_ext1_wakeup))]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Ext1Mode {
+    /// When all the pins are low
+    #[cfg(esp32)]
+    AllLow,
+    /// When any of the pins is low
+    #[cfg(not(esp32))]
+    AnyLow,
+    /// When any of the pins is high
+    AnyHigh,
+}
+
+/// The wakeup sources and the entry points of light and deep sleep
+///
+/// Creating a `Sleep` disables the wakeup sources previously enabled.
+pub struct Sleep(());
+
+impl Sleep {
+    pub fn new() -> Self {
+        unsafe {
+            esp_sleep_disable_wakeup_source(esp_sleep_source_t_ESP_SLEEP_WAKEUP_ALL);
+        }
+
+        Self(())
+    }
+
+    /// Wake up after `duration`
+    pub fn timer(self, duration: Duration) -> Result<Self, EspError> {
+        esp!(unsafe { esp_sleep_enable_timer_wakeup(duration.as_micros() as _) })?;
+
+        Ok(self)
+    }
+
+    /// Wake up when the RTC GPIO is at the level
+    #[cfg(esp_idf_soc_pm_support_ext0_wakeup)]
+    pub fn ext0(self, pin: &impl RTCPin, high: bool) -> Result<Self, EspError> {
+        esp!(unsafe { esp_sleep_enable_ext0_wakeup(pin.pin(), high as _) })?;
+
+        Ok(self)
+    }
+
+    /// Wake up when the RTC GPIOs are at the level of the mode
+    #[cfg(any(esp_idf_soc_pm_support_ext_wakeup, esp_idf_soc_pm_support_ext1_wakeup))]
+    pub fn ext1(self, pins: &[&dyn RTCPinNumber], mode: Ext1Mode) -> Result<Self, EspError> {
+        let mask = pins
+            .iter()
+            .fold(0_u64, |mask, pin| mask | 1 << pin.number());
+
+        let mode = match mode {
+            #[cfg(esp32)]
+            Ext1Mode::AllLow => esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ALL_LOW,
+            #[cfg(not(esp32))]
+            Ext1Mode::AnyLow => esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_LOW,
+            Ext1Mode::AnyHigh => esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_HIGH,
+        };
+
+        esp!(unsafe { esp_sleep_enable_ext1_wakeup(mask, mode) })?;
+
+        Ok(self)
+    }
+
+    /// Wake up from light sleep when the GPIO is at the level
+    pub fn gpio(self, pin: &impl InputPin, high: bool) -> Result<Self, EspError> {
+        let level = if high {
+            gpio_int_type_t_GPIO_INTR_HIGH_LEVEL
+        } else {
+            gpio_int_type_t_GPIO_INTR_LOW_LEVEL
+        };
+
+        esp!(unsafe { gpio_wakeup_enable(pin.pin(), level) })?;
+        esp!(unsafe { esp_sleep_enable_gpio_wakeup() })?;
+
+        Ok(self)
+    }
+
+    /// Wake up when a touch pad configured with the touch sensor driver is touched
+    #[cfg(esp_idf_soc_pm_support_touch_sensor_wakeup)]
+    pub fn touchpad(self) -> Result<Self, EspError> {
+        esp!(unsafe { esp_sleep_enable_touchpad_wakeup() })?;
+
+        Ok(self)
+    }
+
+    /// Wake up when the ULP coprocessor program wakes up the chip
+    #[cfg(any(esp_idf_soc_ulp_supported, esp_idf_ulp_coproc_enabled))]
+    pub fn ulp(self) -> Result<Self, EspError> {
+        esp!(unsafe { esp_sleep_enable_ulp_wakeup() })?;
+
+        Ok(self)
+    }
+
+    /// Wake up from light sleep when the UART receives `edges` rising edges on its RX pin
+    ///
+    /// The characters received meanwhile are lost.
+    pub fn uart(self, uart: &UartDriver, edges: u32) -> Result<Self, EspError> {
+        esp!(unsafe { uart_set_wakeup_threshold(uart.port(), edges as _) })?;
+        esp!(unsafe { esp_sleep_enable_uart_wakeup(uart.port() as _) })?;
+
+        Ok(self)
+    }
+
+    /// Enter light sleep, returning the cause of the wakeup
+    ///
+    /// The CPUs are paused and the RAM is kept, so the execution continues after the wakeup.
+    pub fn light_sleep(self) -> Result<WakeupCause, EspError> {
+        esp!(unsafe { esp_light_sleep_start() })?;
+
+        Ok(WakeupCause::get())
+    }
+
+    /// Enter deep sleep; the chip boots again on wakeup
+    ///
+    /// Only the RTC memory is kept, e.g. the variables placed in `.rtc.data`.
+    pub fn deep_sleep(self) -> ! {
+        shutdown_radios();
+
+        unsafe { esp_deep_sleep_start() }
+    }
+}
+
+impl Default for Sleep {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An RTC GPIO which can be part of an ext1 wakeup
+#[cfg(any(esp_idf_soc_pm_support_ext_wakeup, esp_idf_soc_pm_support_ext1_wakeup))]
+pub trait RTCPinNumber {
+    fn number(&self) -> i32;
+}
+
+#[cfg(any(esp_idf_soc_pm_support_ext_wakeup, esp_idf_soc_pm_support_ext1_wakeup))]
+impl<P> RTCPinNumber for P
+where
+    P: RTCPin,
+{
+    fn number(&self) -> i32 {
+        self.pin()
+    }
+}
+
+fn shutdown_radios() {
+    // Fail if not started or not initialized, which is fine
+    #[cfg(esp_idf_comp_esp_wifi_enabled)]
+    unsafe {
+        esp_wifi_stop();
+    }
+
+    #[cfg(all(esp_idf_bt_enabled, esp_idf_bt_bluedroid_enabled))]
+    unsafe {
+        esp_bluedroid_disable();
+    }
+
+    #[cfg(esp_idf_bt_enabled)]
+    unsafe {
+        esp_bt_controller_disable();
+    }
+}
@@ -13,6 +13,11 @@ use crate::private::mutex;
 #[cfg(feature = "alloc")]
 extern crate alloc;
 
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_http_client_enabled))]
+pub mod http_date;
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+pub mod local;
+
 #[cfg(not(any(
     esp_idf_version_major = "4",
     all(esp_idf_version_major = "5", esp_idf_version_minor = "0"),
@@ -0,0 +1,184 @@
+//! Time synchronization from the `Date` header of an HTTP(S) response
+//!
+//! A fallback for the networks which block SNTP (UDP port 123): the time is taken from the `Date`
+//! header of the response to a `HEAD` request, compensated by half the round-trip time of the
+//! request. The `Date` header only has a resolution of one second, so expect an accuracy around
+//! half a second - plenty for validating TLS certificates, but not for time-stamping.
+//!
+//! The system time is set with `sntp_sync_time`, i.e. with the `SyncMode` of the SNTP service,
+//! which then reports the synchronization with `get_sync_status` and with its callback, as if the
+//! time came from an SNTP server:
+//!
+//! ```ignore
+//! let sntp = EspSntp::new_with_callback(&Default::default(), |time| info!("Synced: {time:?}"))?;
+//!
+//! let wait = Duration::from_secs(15);
+//!
+//! let source = http_date::sync_with_fallback(&sntp, wait, &Default::default())?;
+//! ```
+
+use core::time::Duration;
+
+use ::log::*;
+
+use embedded_svc::http::Method;
+
+use crate::hal::delay::FreeRtos;
+use crate::http::client::{Configuration, EspHttpConnection};
+use crate::sys::*;
+
+use super::{EspSntp, SyncStatus};
+
+/// The configuration of the time synchronization over HTTP(S)
+#[derive(Copy, Clone, Debug)]
+pub struct HttpDateConf<'a> {
+    /// The URL requested; any server returning a `Date` header will do
+    pub url: &'a str,
+    pub timeout: Duration,
+    pub use_global_ca_store: bool,
+    pub crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut core::ffi::c_void) -> esp_err_t>,
+}
+
+impl HttpDateConf<'_> {
+    pub const fn new() -> Self {
+        Self {
+            url: "https://www.google.com/",
+            timeout: Duration::from_secs(10),
+            use_global_ca_store: false,
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            #[cfg(not(esp_idf_mbedtls_certificate_bundle))]
+            crt_bundle_attach: None,
+        }
+    }
+}
+
+impl Default for HttpDateConf<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where the time was synchronized from
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SyncSource {
+    Sntp,
+    HttpDate,
+}
+
+/// Set the system time from the `Date` header of the response to a `HEAD` request; return the
+/// time set, since the Unix epoch
+///
+/// Note that the certificate of the server can only be validated if the system time is already
+/// within its validity period, or if the TLS stack does not check it (the default of mbedTLS
+/// without `MBEDTLS_HAVE_TIME_DATE`).
+pub fn sync(conf: &HttpDateConf) -> Result<Duration, EspError> {
+    let mut connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(conf.timeout),
+        use_global_ca_store: conf.use_global_ca_store,
+        crt_bundle_attach: conf.crt_bundle_attach,
+        ..Default::default()
+    })?;
+
+    connection.initiate_request(Method::Head, conf.url, &[])?;
+
+    let sent = now();
+
+    connection.initiate_response()?;
+
+    let rtt = now().saturating_sub(sent);
+
+    let date = connection
+        .header("Date")
+        .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+    let secs = parse_date(date).ok_or(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())?;
+
+    // The header is truncated to the second, and was generated about half the round-trip time ago
+    let time = Duration::from_secs(secs) + Duration::from_millis(500) + rtt / 2;
+
+    debug!("Date: {date}, RTT: {rtt:?}");
+
+    let mut tv = timeval {
+        tv_sec: time.as_secs() as _,
+        tv_usec: time.subsec_micros() as _,
+    };
+
+    unsafe { sntp_sync_time(&mut tv) };
+
+    info!("Time synchronized from {}", conf.url);
+
+    Ok(time)
+}
+
+/// Wait up to `wait` for the SNTP service to synchronize the time, and fall back to `sync` if it
+/// did not
+pub fn sync_with_fallback(
+    sntp: &EspSntp,
+    wait: Duration,
+    conf: &HttpDateConf,
+) -> Result<SyncSource, EspError> {
+    const POLL_MS: u32 = 100;
+
+    for _ in 0..=wait.as_millis() / POLL_MS as u128 {
+        if sntp.get_sync_status() == SyncStatus::Completed {
+            return Ok(SyncSource::Sntp);
+        }
+
+        FreeRtos::delay_ms(POLL_MS);
+    }
+
+    warn!("No SNTP synchronization after {wait:?}, falling back to the HTTP Date header");
+
+    sync(conf)?;
+
+    Ok(SyncSource::HttpDate)
+}
+
+fn now() -> Duration {
+    Duration::from_micros(unsafe { esp_timer_get_time() } as _)
+}
+
+/// Parse an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, into seconds since the Unix epoch
+fn parse_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let mut parts = date.split_ascii_whitespace().skip(1);
+
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|other| *other == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+
+    let mut time = parts.next()?.split(':');
+    let hour: u64 = time.next()?.parse().ok()?;
+    let minute: u64 = time.next()?.parse().ok()?;
+    let second: u64 = time.next()?.parse().ok()?;
+
+    if parts.next()? != "GMT"
+        || !(1970..10000).contains(&year)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // Days since the epoch of the civil date, after H. Hinnant
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
@@ -0,0 +1,799 @@
+//! Precise time synchronization between local devices
+//!
+//! A lightweight, PTP-like protocol letting a cluster of devices time-stamp events within a
+//! fraction of a millisecond of each other, without an Internet connection nor an NTP server.
+//!
+//! A master periodically broadcasts a `Sync` message, followed by a `FollowUp` message carrying
+//! the time at which `Sync` was sent. The slaves time-stamp the reception of `Sync`, and from
+//! time to time measure the path delay with a `DelayReq` message, which the master answers
+//! with a `DelayResp` message carrying the time at which it received the request.
+//!
+//! The slaves do not touch the system time: they discipline an offset to their `esp_timer`,
+//! along with an estimation of its drift relative to the clock of the master, which gives the
+//! cluster time with `TimeSyncSlave::now`. The cluster time is the `esp_timer` time of the
+//! master, in microseconds.
+//!
+//! All the messages are broadcast, so the protocol runs over ESP-NOW (`EspNowTimeSyncMaster`
+//! and `EspNowTimeSyncSlave`) as well as over UDP (`UdpTimeSyncMaster` and
+//! `UdpTimeSyncSlave`), or over any other transport with `TimeSyncMaster` and
+//! `TimeSyncSlave`:
+//!
+//! ```ignore
+//! // On the master
+//! let master = EspNowTimeSyncMaster::new(EspNow::take()?, &Default::default())?;
+//!
+//! loop {
+//!     master.sync()?;
+//!     FreeRtos::delay_ms(1000);
+//! }
+//!
+//! // On the slaves
+//! let slave = EspNowTimeSyncSlave::new(EspNow::take()?, &Default::default())?;
+//!
+//! if let Some(now) = slave.now() {
+//!     info!("Event at {now} us");
+//! }
+//! ```
+//!
+//! The accuracy depends on how close to the radio the messages are time-stamped: ESP-NOW frames
+//! are time-stamped in the callback of the WiFi task, while UDP datagrams are time-stamped once
+//! received by the socket, i.e. with the additional - and more variable - latency of lwIP.
+
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+use ::log::*;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+#[cfg(all(
+    not(esp32h2),
+    esp_idf_comp_esp_wifi_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+pub use espnow::*;
+#[cfg(feature = "std")]
+pub use udp::*;
+
+const MAGIC: [u8; 4] = *b"LTS1";
+
+/// The length of all the messages of the protocol
+pub const MESSAGE_LEN: usize = MAGIC.len() + 1 + 1 + 2 + 4 + 8;
+
+/// The UDP port used by default
+pub const DEFAULT_PORT: u16 = 3190;
+
+/// The local time, i.e. the time of `esp_timer`, in microseconds
+pub fn local_time() -> i64 {
+    unsafe { esp_timer_get_time() }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Sync = 0,
+    FollowUp = 1,
+    DelayReq = 2,
+    DelayResp = 3,
+}
+
+/// A message of the protocol
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Message {
+    kind: Kind,
+    domain: u8,
+    seq: u16,
+    /// The master for `Sync` and `FollowUp`, the slave for `DelayReq` and `DelayResp`
+    node: u32,
+    /// The send time of `Sync` for `FollowUp`, the receive time of `DelayReq` for `DelayResp`
+    time: i64,
+}
+
+impl Message {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() != MESSAGE_LEN || data[..4] != MAGIC {
+            return None;
+        }
+
+        let kind = match data[4] {
+            0 => Kind::Sync,
+            1 => Kind::FollowUp,
+            2 => Kind::DelayReq,
+            3 => Kind::DelayResp,
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            domain: data[5],
+            seq: u16::from_be_bytes([data[6], data[7]]),
+            node: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            time: i64::from_be_bytes(data[12..20].try_into().unwrap()),
+        })
+    }
+
+    fn serialize(&self) -> [u8; MESSAGE_LEN] {
+        let mut data = [0; MESSAGE_LEN];
+
+        data[..4].copy_from_slice(&MAGIC);
+        data[4] = self.kind as u8;
+        data[5] = self.domain;
+        data[6..8].copy_from_slice(&self.seq.to_be_bytes());
+        data[8..12].copy_from_slice(&self.node.to_be_bytes());
+        data[12..20].copy_from_slice(&self.time.to_be_bytes());
+
+        data
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TimeSyncConfiguration {
+    /// Devices only synchronize with the masters of their domain
+    pub domain: u8,
+    /// A slave steps its clock - instead of slewing it - when off by more than this
+    pub step_threshold: Duration,
+    /// A slave measures the path delay after every `delay_req_interval` synchronizations
+    pub delay_req_interval: u32,
+    /// How many synchronizations a slave needs before it considers itself synchronized
+    pub min_samples: u32,
+    /// How long a slave stays synchronized - and locked to its master - without synchronization
+    pub timeout: Duration,
+}
+
+impl TimeSyncConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            domain: 0,
+            step_threshold: Duration::from_millis(10),
+            delay_req_interval: 4,
+            min_samples: 4,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for TimeSyncConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The master side of the protocol, independent of the transport
+#[derive(Clone, Debug)]
+pub struct TimeSyncMaster {
+    id: u32,
+    domain: u8,
+    seq: u16,
+}
+
+impl TimeSyncMaster {
+    pub fn new(conf: &TimeSyncConfiguration) -> Self {
+        Self {
+            id: unsafe { esp_random() },
+            domain: conf.domain,
+            seq: 0,
+        }
+    }
+
+    /// The time of the cluster, in microseconds
+    pub fn now(&self) -> i64 {
+        local_time()
+    }
+
+    /// Broadcast a synchronization with `send`, which should return as soon as the message is sent
+    pub fn sync<F, E>(&mut self, mut send: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut message = Message {
+            kind: Kind::Sync,
+            domain: self.domain,
+            seq: self.seq,
+            node: self.id,
+            time: 0,
+        };
+
+        send(&message.serialize())?;
+
+        message.kind = Kind::FollowUp;
+        message.time = local_time();
+
+        send(&message.serialize())
+    }
+
+    /// Handle a message received at `received` - as returned by `local_time` - answering the
+    /// path delay requests with `send`
+    pub fn handle<F, E>(&mut self, data: &[u8], received: i64, mut send: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        match Message::parse(data) {
+            Some(message) if message.kind == Kind::DelayReq && message.domain == self.domain => {
+                send(
+                    &Message {
+                        kind: Kind::DelayResp,
+                        time: received,
+                        ..message
+                    }
+                    .serialize(),
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The state of the synchronization of a slave
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TimeSyncStatus {
+    /// The master the slave is locked to
+    pub master: Option<u32>,
+    pub synchronized: bool,
+    /// The cluster time minus the local time, in microseconds
+    pub offset: i64,
+    /// The drift of the local clock relative to the clock of the master, in parts per million
+    pub drift_ppm: f64,
+    /// The estimated one-way path delay, in microseconds
+    pub path_delay: i64,
+    /// The average error of the last synchronizations, in microseconds
+    pub jitter: i64,
+    /// The number of synchronizations since the last step
+    pub samples: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Reference {
+    local: i64,
+    offset: f64,
+}
+
+/// The slave side of the protocol, independent of the transport
+#[derive(Clone, Debug)]
+pub struct TimeSyncSlave {
+    id: u32,
+    conf: TimeSyncConfiguration,
+    master: Option<u32>,
+    last_heard: i64,
+    /// The `Sync` awaiting its `FollowUp`, with its receive time
+    sync: Option<(u16, i64)>,
+    /// The send time of the last `Sync`, with its receive time
+    last_sync: Option<(i64, i64)>,
+    /// The `DelayReq` awaiting its `DelayResp`, with its send time
+    delay_req: Option<(u16, i64)>,
+    delay_seq: u16,
+    reference: Option<Reference>,
+    /// The last raw offset, with its local time
+    last_sample: Option<(i64, f64)>,
+    drift: f64,
+    path_delay: Option<i64>,
+    jitter: f64,
+    samples: u32,
+    syncs: u32,
+}
+
+impl TimeSyncSlave {
+    pub fn new(conf: &TimeSyncConfiguration) -> Self {
+        Self {
+            id: unsafe { esp_random() },
+            conf: conf.clone(),
+            master: None,
+            last_heard: 0,
+            sync: None,
+            last_sync: None,
+            delay_req: None,
+            delay_seq: 0,
+            reference: None,
+            last_sample: None,
+            drift: 0.0,
+            path_delay: None,
+            jitter: 0.0,
+            samples: 0,
+            syncs: 0,
+        }
+    }
+
+    /// The time of the cluster, in microseconds, or `None` if not synchronized
+    pub fn now(&self) -> Option<i64> {
+        self.cluster_time(local_time())
+    }
+
+    /// The time of the cluster at `local` - as returned by `local_time` - or `None` if not
+    /// synchronized
+    pub fn cluster_time(&self, local: i64) -> Option<i64> {
+        if !self.is_synchronized() {
+            return None;
+        }
+
+        self.reference.map(|reference| {
+            let offset = reference.offset + self.drift * (local - reference.local) as f64;
+
+            local + offset as i64
+        })
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.reference.is_some()
+            && self.samples >= self.conf.min_samples
+            && local_time() - self.last_heard < self.conf.timeout.as_micros() as i64
+    }
+
+    pub fn status(&self) -> TimeSyncStatus {
+        TimeSyncStatus {
+            master: self.master,
+            synchronized: self.is_synchronized(),
+            offset: self
+                .reference
+                .map(|reference| {
+                    reference.offset + self.drift * (local_time() - reference.local) as f64
+                })
+                .unwrap_or_default() as i64,
+            drift_ppm: self.drift * 1_000_000.0,
+            path_delay: self.path_delay.unwrap_or_default(),
+            jitter: self.jitter as i64,
+            samples: self.samples,
+        }
+    }
+
+    /// Handle a message received at `received` - as returned by `local_time` - sending the path
+    /// delay requests with `send`, which should return as soon as the message is sent
+    pub fn handle<F, E>(&mut self, data: &[u8], received: i64, mut send: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        let Some(message) = Message::parse(data) else {
+            return Ok(());
+        };
+
+        if message.domain != self.conf.domain {
+            return Ok(());
+        }
+
+        match message.kind {
+            Kind::Sync | Kind::FollowUp if self.master.is_some_and(|id| id != message.node) => {
+                if received - self.last_heard < self.conf.timeout.as_micros() as i64 {
+                    return Ok(());
+                }
+
+                info!(
+                    "Master {:08x} silent, switching to master {:08x}",
+                    self.master.unwrap(),
+                    message.node
+                );
+
+                *self = Self {
+                    id: self.id,
+                    ..Self::new(&self.conf)
+                };
+
+                self.handle(data, received, send)
+            }
+            Kind::Sync => {
+                self.master = Some(message.node);
+                self.sync = Some((message.seq, received));
+
+                Ok(())
+            }
+            Kind::FollowUp => {
+                let Some((_, sync_received)) =
+                    self.sync.take().filter(|(seq, _)| *seq == message.seq)
+                else {
+                    return Ok(());
+                };
+
+                self.last_heard = received;
+                self.last_sync = Some((message.time, sync_received));
+
+                let offset =
+                    (message.time + self.path_delay.unwrap_or_default() - sync_received) as f64;
+
+                self.discipline(sync_received, offset);
+
+                self.syncs = self.syncs.wrapping_add(1);
+
+                if self.path_delay.is_some()
+                    && self.syncs % self.conf.delay_req_interval.max(1) != 0
+                {
+                    return Ok(());
+                }
+
+                self.delay_seq = self.delay_seq.wrapping_add(1);
+
+                send(
+                    &Message {
+                        kind: Kind::DelayReq,
+                        domain: self.conf.domain,
+                        seq: self.delay_seq,
+                        node: self.id,
+                        time: 0,
+                    }
+                    .serialize(),
+                )?;
+
+                self.delay_req = Some((self.delay_seq, local_time()));
+
+                Ok(())
+            }
+            Kind::DelayResp if message.node == self.id => {
+                let Some((_, req_sent)) =
+                    self.delay_req.take().filter(|(seq, _)| *seq == message.seq)
+                else {
+                    return Ok(());
+                };
+
+                let Some((sync_sent, sync_received)) = self.last_sync else {
+                    return Ok(());
+                };
+
+                // The offset between the clocks cancels out, leaving the round-trip time
+                let delay = ((sync_received - sync_sent) + (message.time - req_sent)) / 2;
+
+                if delay >= 0 {
+                    self.path_delay = Some(match self.path_delay {
+                        Some(path_delay) => path_delay + (delay - path_delay) / 4,
+                        None => delay,
+                    });
+                }
+
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn discipline(&mut self, local: i64, offset: f64) {
+        let Some(reference) = self.reference else {
+            self.step(local, offset);
+            return;
+        };
+
+        let predicted = reference.offset + self.drift * (local - reference.local) as f64;
+        let error = offset - predicted;
+
+        if error.abs() > self.conf.step_threshold.as_micros() as f64 {
+            warn!("Off by {}us, stepping", error as i64);
+
+            self.step(local, offset);
+            return;
+        }
+
+        if let Some((last_local, last_offset)) = self.last_sample {
+            if local > last_local {
+                let drift = (offset - last_offset) / (local - last_local) as f64;
+                let weight = 1.0 / self.samples.clamp(1, 8) as f64;
+
+                self.drift += (drift - self.drift) * weight;
+            }
+        }
+
+        self.jitter += (error.abs() - self.jitter) / 8.0;
+
+        self.reference = Some(Reference {
+            local,
+            offset: predicted + error / 4.0,
+        });
+        self.last_sample = Some((local, offset));
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    fn step(&mut self, local: i64, offset: f64) {
+        self.reference = Some(Reference { local, offset });
+        self.last_sample = Some((local, offset));
+        self.drift = 0.0;
+        self.jitter = 0.0;
+        self.samples = 1;
+    }
+}
+
+#[cfg(all(
+    not(esp32h2),
+    esp_idf_comp_esp_wifi_enabled,
+    esp_idf_comp_esp_event_enabled
+))]
+mod espnow {
+    use super::*;
+
+    use crate::espnow::{EspNow, BROADCAST};
+
+    fn broadcast(data: &[u8]) -> Result<(), EspError> {
+        esp!(unsafe { esp_now_send(BROADCAST.as_ptr(), data.as_ptr(), data.len()) })
+    }
+
+    /// A time synchronization master broadcasting over ESP-NOW
+    ///
+    /// The broadcast address must be a peer of the `EspNow` service, which the master owns: it
+    /// consumes all the ESP-NOW frames it receives.
+    pub struct EspNowTimeSyncMaster<'a> {
+        espnow: EspNow<'a>,
+        master: Arc<Mutex<TimeSyncMaster>>,
+    }
+
+    impl<'a> EspNowTimeSyncMaster<'a> {
+        pub fn new(espnow: EspNow<'a>, conf: &TimeSyncConfiguration) -> Result<Self, EspError> {
+            let master = Arc::new(Mutex::new(TimeSyncMaster::new(conf)));
+
+            let callback_master = master.clone();
+
+            espnow.register_recv_cb(move |_, data| {
+                let received = local_time();
+
+                if let Err(err) = callback_master.lock().handle(data, received, broadcast) {
+                    warn!("Sending a delay response failed: {err}");
+                }
+            })?;
+
+            Ok(Self { espnow, master })
+        }
+
+        /// Broadcast a synchronization; call periodically, e.g. every second
+        pub fn sync(&self) -> Result<(), EspError> {
+            self.master.lock().sync(broadcast)
+        }
+
+        pub fn now(&self) -> i64 {
+            self.master.lock().now()
+        }
+
+        pub fn espnow(&self) -> &EspNow<'a> {
+            &self.espnow
+        }
+    }
+
+    impl Drop for EspNowTimeSyncMaster<'_> {
+        fn drop(&mut self) {
+            self.espnow.unregister_recv_cb().unwrap();
+        }
+    }
+
+    /// A time synchronization slave over ESP-NOW
+    ///
+    /// The broadcast address must be a peer of the `EspNow` service, which the slave owns: it
+    /// consumes all the ESP-NOW frames it receives.
+    pub struct EspNowTimeSyncSlave<'a> {
+        espnow: EspNow<'a>,
+        slave: Arc<Mutex<TimeSyncSlave>>,
+    }
+
+    impl<'a> EspNowTimeSyncSlave<'a> {
+        pub fn new(espnow: EspNow<'a>, conf: &TimeSyncConfiguration) -> Result<Self, EspError> {
+            let slave = Arc::new(Mutex::new(TimeSyncSlave::new(conf)));
+
+            let callback_slave = slave.clone();
+
+            espnow.register_recv_cb(move |_, data| {
+                let received = local_time();
+
+                if let Err(err) = callback_slave.lock().handle(data, received, broadcast) {
+                    warn!("Sending a delay request failed: {err}");
+                }
+            })?;
+
+            Ok(Self { espnow, slave })
+        }
+
+        /// The time of the cluster, in microseconds, or `None` if not synchronized
+        pub fn now(&self) -> Option<i64> {
+            self.slave.lock().now()
+        }
+
+        pub fn cluster_time(&self, local: i64) -> Option<i64> {
+            self.slave.lock().cluster_time(local)
+        }
+
+        pub fn is_synchronized(&self) -> bool {
+            self.slave.lock().is_synchronized()
+        }
+
+        pub fn status(&self) -> TimeSyncStatus {
+            self.slave.lock().status()
+        }
+
+        pub fn espnow(&self) -> &EspNow<'a> {
+            &self.espnow
+        }
+    }
+
+    impl Drop for EspNowTimeSyncSlave<'_> {
+        fn drop(&mut self) {
+            self.espnow.unregister_recv_cb().unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod udp {
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::{self, JoinHandle};
+    use std::time::Instant;
+
+    use super::*;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    fn bind(port: u16) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))?;
+
+        socket.set_broadcast(true)?;
+
+        Ok(socket)
+    }
+
+    struct Worker {
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Worker {
+        fn spawn<F>(f: F) -> io::Result<Self>
+        where
+            F: FnOnce(Arc<AtomicBool>) + Send + 'static,
+        {
+            let stop = Arc::new(AtomicBool::new(false));
+
+            let thread_stop = stop.clone();
+            let thread = thread::Builder::new()
+                .name("timesync".into())
+                .stack_size(4096)
+                .spawn(move || f(thread_stop))?;
+
+            Ok(Self {
+                stop,
+                thread: Some(thread),
+            })
+        }
+    }
+
+    impl Drop for Worker {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// A time synchronization master broadcasting over UDP, from a thread of its own
+    pub struct UdpTimeSyncMaster {
+        master: Arc<Mutex<TimeSyncMaster>>,
+        _worker: Worker,
+    }
+
+    impl UdpTimeSyncMaster {
+        /// Broadcast a synchronization every `interval` to `port` of `broadcast`, e.g. the
+        /// broadcast address of the subnet or `255.255.255.255`
+        pub fn new(
+            broadcast: Ipv4Addr,
+            port: u16,
+            interval: Duration,
+            conf: &TimeSyncConfiguration,
+        ) -> io::Result<Self> {
+            let socket = bind(port)?;
+            let to = SocketAddr::V4(SocketAddrV4::new(broadcast, port));
+
+            let master = Arc::new(Mutex::new(TimeSyncMaster::new(conf)));
+
+            let thread_master = master.clone();
+            let worker = Worker::spawn(move |stop| {
+                let mut next_sync = Instant::now();
+                let mut buf = [0; MESSAGE_LEN + 1];
+
+                while !stop.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+
+                    if now >= next_sync {
+                        if let Err(err) = thread_master
+                            .lock()
+                            .sync(|data| socket.send_to(data, to).map(|_| ()))
+                        {
+                            warn!("Sending a synchronization failed: {err}");
+                        }
+
+                        next_sync = now + interval;
+                    }
+
+                    let timeout = next_sync.saturating_duration_since(Instant::now());
+
+                    if socket
+                        .set_read_timeout(Some(
+                            timeout.clamp(Duration::from_millis(1), POLL_INTERVAL),
+                        ))
+                        .is_err()
+                    {
+                        continue;
+                    }
+
+                    if let Ok(len) = socket.recv(&mut buf) {
+                        let received = local_time();
+
+                        if let Err(err) =
+                            thread_master.lock().handle(&buf[..len], received, |data| {
+                                socket.send_to(data, to).map(|_| ())
+                            })
+                        {
+                            warn!("Sending a delay response failed: {err}");
+                        }
+                    }
+                }
+            })?;
+
+            Ok(Self {
+                master,
+                _worker: worker,
+            })
+        }
+
+        pub fn now(&self) -> i64 {
+            self.master.lock().now()
+        }
+    }
+
+    /// A time synchronization slave over UDP, receiving from a thread of its own
+    pub struct UdpTimeSyncSlave {
+        slave: Arc<Mutex<TimeSyncSlave>>,
+        _worker: Worker,
+    }
+
+    impl UdpTimeSyncSlave {
+        /// Synchronize with the master broadcasting to `port`, sending the path delay requests
+        /// to `port` of `broadcast`
+        pub fn new(
+            broadcast: Ipv4Addr,
+            port: u16,
+            conf: &TimeSyncConfiguration,
+        ) -> io::Result<Self> {
+            let socket = bind(port)?;
+            socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+            let to = SocketAddr::V4(SocketAddrV4::new(broadcast, port));
+
+            let slave = Arc::new(Mutex::new(TimeSyncSlave::new(conf)));
+
+            let thread_slave = slave.clone();
+            let worker = Worker::spawn(move |stop| {
+                let mut buf = [0; MESSAGE_LEN + 1];
+
+                while !stop.load(Ordering::SeqCst) {
+                    if let Ok(len) = socket.recv(&mut buf) {
+                        let received = local_time();
+
+                        if let Err(err) =
+                            thread_slave.lock().handle(&buf[..len], received, |data| {
+                                socket.send_to(data, to).map(|_| ())
+                            })
+                        {
+                            warn!("Sending a delay request failed: {err}");
+                        }
+                    }
+                }
+            })?;
+
+            Ok(Self {
+                slave,
+                _worker: worker,
+            })
+        }
+
+        /// The time of the cluster, in microseconds, or `None` if not synchronized
+        pub fn now(&self) -> Option<i64> {
+            self.slave.lock().now()
+        }
+
+        pub fn cluster_time(&self, local: i64) -> Option<i64> {
+            self.slave.lock().cluster_time(local)
+        }
+
+        pub fn is_synchronized(&self) -> bool {
+            self.slave.lock().is_synchronized()
+        }
+
+        pub fn status(&self) -> TimeSyncStatus {
+            self.slave.lock().status()
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Internal temperature sensor service
+//!
+//! On the chips with an internal temperature sensor, `EspTempSensor` samples it periodically
+//! with a timer of the `EspTimerService`, and posts a `TempSensorEvent` to the system event
+//! loop whenever the temperature crosses the high or the low threshold - e.g. to throttle or
+//! shut down the load of a device in an enclosure overheating:
+//!
+//! ```ignore
+//! use esp_idf_svc::temp_sensor::{EspTempSensor, TempSensorConfiguration, TempSensorEvent};
+//!
+//! let sensor = EspTempSensor::new(
+//!     &timer_service,
+//!     sys_loop.clone(),
+//!     &TempSensorConfiguration {
+//!         high_threshold: Some(70.0),
+//!         ..Default::default()
+//!     },
+//! )?;
+//!
+//! let _subscription = sys_loop.subscribe::<TempSensorEvent, _>(|event| {
+//!     if let TempSensorEvent::High(celsius) = event {
+//!         // Throttle
+//!     }
+//! })?;
+//! ```
+//!
+//! The sensor measures the temperature of the chip, which is higher than the ambient one.
+
+use core::ffi::CStr;
+use core::ptr;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::sync::Arc;
+
+use log::warn;
+
+use crate::eventloop::{
+    EspEvent, EspEventDeserializer, EspEventPostData, EspEventSerializer, EspEventSource,
+    EspSystemEventLoop,
+};
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+use crate::timer::{EspTimer, EspTimerService, EspTimerServiceType};
+
+/// The configuration of the temperature sensor service
+#[derive(Clone, Debug, PartialEq)]
+pub struct TempSensorConfiguration {
+    /// The range of temperatures measured, in degrees Celsius; the narrower, the more accurate
+    pub range: (i32, i32),
+    /// The period of the sampling
+    pub interval: Duration,
+    /// The temperature above which `TempSensorEvent::High` is posted
+    pub high_threshold: Option<f32>,
+    /// The temperature below which `TempSensorEvent::Low` is posted
+    pub low_threshold: Option<f32>,
+    /// How much the temperature must cross back a threshold for `TempSensorEvent::Normal` to
+    /// be posted, so that a temperature oscillating around a threshold does not flood the
+    /// event loop
+    pub hysteresis: f32,
+}
+
+impl TempSensorConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            range: (-10, 80),
+            interval: Duration::from_secs(5),
+            high_threshold: None,
+            low_threshold: None,
+            hysteresis: 2.0,
+        }
+    }
+}
+
+impl Default for TempSensorConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A threshold crossing of the temperature, in degrees Celsius
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TempSensorEvent {
+    High(f32),
+    Low(f32),
+    /// Back between the thresholds
+    Normal(f32),
+}
+
+unsafe impl EspEventSource for TempSensorEvent {
+    fn source() -> Option<&'static CStr> {
+        Some(CStr::from_bytes_with_nul(b"ESP-SVC-TEMP-SENSOR\0").unwrap())
+    }
+}
+
+impl EspEventSerializer for TempSensorEvent {
+    type Data<'a> = TempSensorEvent;
+
+    fn serialize<F, R>(event: &Self::Data<'_>, f: F) -> R
+    where
+        F: FnOnce(&EspEventPostData) -> R,
+    {
+        f(&unsafe { EspEventPostData::new(Self::source().unwrap(), Self::event_id(), event) })
+    }
+}
+
+impl EspEventDeserializer for TempSensorEvent {
+    type Data<'a> = TempSensorEvent;
+
+    fn deserialize<'a>(data: &EspEvent<'a>) -> Self::Data<'a> {
+        *unsafe { data.as_payload::<TempSensorEvent>() }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Zone {
+    Low,
+    Normal,
+    High,
+}
+
+struct Sensor {
+    handle: temperature_sensor_handle_t,
+    last: Option<f32>,
+}
+
+impl Sensor {
+    fn read(&mut self) -> Result<f32, EspError> {
+        let mut celsius = 0.0;
+
+        esp!(unsafe { temperature_sensor_get_celsius(self.handle, &mut celsius) })?;
+
+        self.last = Some(celsius);
+
+        Ok(celsius)
+    }
+}
+
+impl Drop for Sensor {
+    fn drop(&mut self) {
+        unsafe {
+            temperature_sensor_disable(self.handle);
+            temperature_sensor_uninstall(self.handle);
+        }
+    }
+}
+
+unsafe impl Send for Sensor {}
+
+/// The temperature sensor service; sampling stops when dropped
+pub struct EspTempSensor {
+    sensor: Arc<Mutex<Sensor>>,
+    _timer: EspTimer<'static>,
+}
+
+impl EspTempSensor {
+    pub fn new<T>(
+        timer_service: &EspTimerService<T>,
+        sys_loop: EspSystemEventLoop,
+        conf: &TempSensorConfiguration,
+    ) -> Result<Self, EspError>
+    where
+        T: EspTimerServiceType,
+    {
+        #[allow(clippy::needless_update)]
+        let config = temperature_sensor_config_t {
+            range_min: conf.range.0,
+            range_max: conf.range.1,
+            ..Default::default()
+        };
+
+        let mut handle = ptr::null_mut();
+
+        esp!(unsafe { temperature_sensor_install(&config, &mut handle) })?;
+
+        let sensor = Sensor { handle, last: None };
+
+        esp!(unsafe { temperature_sensor_enable(handle) })?;
+
+        let sensor = Arc::new(Mutex::new(sensor));
+
+        let timer = {
+            let sensor = sensor.clone();
+            let high = conf.high_threshold;
+            let low = conf.low_threshold;
+            let hysteresis = conf.hysteresis;
+
+            let mut zone = Zone::Normal;
+
+            timer_service.timer(move || {
+                let celsius = match sensor.lock().read() {
+                    Ok(celsius) => celsius,
+                    Err(err) => {
+                        warn!("Reading the temperature failed: {err}");
+                        return;
+                    }
+                };
+
+                let new_zone = match zone {
+                    _ if high.map(|high| celsius > high).unwrap_or(false) => Zone::High,
+                    _ if low.map(|low| celsius < low).unwrap_or(false) => Zone::Low,
+                    Zone::High if celsius > high.unwrap() - hysteresis => Zone::High,
+                    Zone::Low if celsius < low.unwrap() + hysteresis => Zone::Low,
+                    _ => Zone::Normal,
+                };
+
+                if new_zone != zone {
+                    zone = new_zone;
+
+                    let event = match zone {
+                        Zone::High => TempSensorEvent::High(celsius),
+                        Zone::Low => TempSensorEvent::Low(celsius),
+                        Zone::Normal => TempSensorEvent::Normal(celsius),
+                    };
+
+                    // Never block the timer task
+                    if !matches!(sys_loop.post::<TempSensorEvent>(&event, 0), Ok(true)) {
+                        warn!("Temperature event dropped: {event:?}");
+                    }
+                }
+            })?
+        };
+
+        timer.every(conf.interval)?;
+
+        Ok(Self {
+            sensor,
+            _timer: timer,
+        })
+    }
+
+    /// Read the temperature now, in degrees Celsius
+    pub fn temperature(&self) -> Result<f32, EspError> {
+        self.sensor.lock().read()
+    }
+
+    /// The temperature read last, in degrees Celsius
+    pub fn last_temperature(&self) -> Option<f32> {
+        self.sensor.lock().last
+    }
+}
@@ -8,6 +8,10 @@
 //!
 //! EspTimer is a set of APIs that provides one-shot and periodic timers,
 //! microsecond time resolution, and 52-bit range.
+//!
+//! All the timers created through an `EspTimerService` can be suspended at once with
+//! `EspTimerService::suspend_all` - e.g. while flashing an OTA update, or before entering
+//! sleep - and restored with `EspTimerService::resume_all`.
 
 use core::num::NonZeroU32;
 use core::time::Duration;
@@ -16,6 +20,7 @@ use core::{ffi, ptr};
 extern crate alloc;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use esp_idf_hal::task::asynch::Notification;
 
@@ -27,6 +32,42 @@ use ::log::debug;
 pub use isr::*;
 
 use crate::handle::RawHandle;
+use crate::private::mutex::Mutex;
+
+#[derive(Copy, Clone, Debug)]
+enum Schedule {
+    /// Fires once at the deadline, in microseconds since boot
+    Once(u64),
+    /// Fires every period, in microseconds
+    Periodic(u64),
+}
+
+struct TrackedTimer {
+    handle: esp_timer_handle_t,
+    schedule: Option<Schedule>,
+    /// The remaining time of a one-shot timer, or the period of a periodic timer, while suspended
+    suspended: Option<Schedule>,
+}
+
+unsafe impl Send for TrackedTimer {}
+
+static TIMERS: Mutex<Vec<TrackedTimer>> = Mutex::new(Vec::new());
+
+fn track(handle: esp_timer_handle_t, schedule: Option<Schedule>) {
+    // The schedules of the timers started from an ISR are not tracked
+    if crate::hal::interrupt::active() {
+        return;
+    }
+
+    if let Some(timer) = TIMERS
+        .lock()
+        .iter_mut()
+        .find(|timer| timer.handle == handle)
+    {
+        timer.schedule = schedule;
+        timer.suspended = None;
+    }
+}
 
 struct UnsafeCallback<'a>(*mut Box<dyn FnMut() + Send + 'a>);
 
@@ -63,6 +104,8 @@ impl<'a> EspTimer<'a> {
     pub fn cancel(&self) -> Result<bool, EspError> {
         let res = unsafe { esp_timer_stop(self.handle) };
 
+        track(self.handle, None);
+
         Ok(res != ESP_OK)
     }
 
@@ -71,6 +114,9 @@ impl<'a> EspTimer<'a> {
 
         esp!(unsafe { esp_timer_start_once(self.handle, duration.as_micros() as _) })?;
 
+        let deadline = unsafe { esp_timer_get_time() } as u64 + duration.as_micros() as u64;
+        track(self.handle, Some(Schedule::Once(deadline)));
+
         Ok(())
     }
 
@@ -79,6 +125,11 @@ impl<'a> EspTimer<'a> {
 
         esp!(unsafe { esp_timer_start_periodic(self.handle, duration.as_micros() as _) })?;
 
+        track(
+            self.handle,
+            Some(Schedule::Periodic(duration.as_micros() as _)),
+        );
+
         Ok(())
     }
 
@@ -119,6 +170,8 @@ impl<'a> Drop for EspTimer<'a> {
             // Timer is still running, busy-loop
         }
 
+        TIMERS.lock().retain(|timer| timer.handle != self.handle);
+
         debug!("Timer dropped");
     }
 }
@@ -293,11 +346,89 @@ where
             )
         })?;
 
+        TIMERS.lock().push(TrackedTimer {
+            handle,
+            schedule: None,
+            suspended: None,
+        });
+
         Ok(EspTimer {
             handle,
             _callback: callback,
         })
     }
+
+    /// Stop all the scheduled timers created through any timer service, returning how many
+    /// were stopped
+    ///
+    /// The one-shot timers remember the time remaining until their deadline, so that
+    /// `resume_all` shifts their deadline by the duration of the suspension. Note that this also
+    /// suspends the timers used by `EspAsyncTimer` - and by the `embassy-time` driver, if enabled.
+    pub fn suspend_all(&self) -> Result<usize, EspError> {
+        let now = unsafe { esp_timer_get_time() } as u64;
+
+        let mut suspended = 0;
+
+        for timer in TIMERS.lock().iter_mut() {
+            let Some(schedule) = timer.schedule else {
+                continue;
+            };
+
+            if timer.suspended.is_some() || !unsafe { esp_timer_is_active(timer.handle) } {
+                continue;
+            }
+
+            // The timer might have fired meanwhile, in which case there is nothing to suspend
+            if unsafe { esp_timer_stop(timer.handle) } != ESP_OK {
+                continue;
+            }
+
+            timer.suspended = Some(match schedule {
+                Schedule::Once(deadline) => Schedule::Once(deadline.saturating_sub(now)),
+                periodic => periodic,
+            });
+
+            suspended += 1;
+        }
+
+        debug!("Suspended {suspended} timers");
+
+        Ok(suspended)
+    }
+
+    /// Restart all the timers stopped by `suspend_all`, returning how many were restarted
+    ///
+    /// The one-shot timers fire after the time which remained until their deadline when
+    /// suspended, the periodic timers restart with a whole period. The timers rescheduled or
+    /// cancelled while suspended are left as they are.
+    pub fn resume_all(&self) -> Result<usize, EspError> {
+        let now = unsafe { esp_timer_get_time() } as u64;
+
+        let mut resumed = 0;
+
+        for timer in TIMERS.lock().iter_mut() {
+            let Some(suspended) = timer.suspended.take() else {
+                continue;
+            };
+
+            match suspended {
+                Schedule::Once(remaining) => {
+                    esp!(unsafe { esp_timer_start_once(timer.handle, remaining) })?;
+
+                    timer.schedule = Some(Schedule::Once(now + remaining));
+                }
+                Schedule::Periodic(period) => {
+                    esp!(unsafe { esp_timer_start_periodic(timer.handle, period) })?;
+                }
+            }
+
+            resumed += 1;
+        }
+
+        debug!("Resumed {resumed} timers");
+
+        Ok(resumed)
+    }
 }
 
 pub type EspTaskTimerService = EspTimerService<Task>;
@@ -1,4 +1,15 @@
 //! Type safe abstraction for esp-tls
+//!
+//! `EspTls` runs TLS on top of a connected socket, as a client (`EspTls::negotiate`) or a server
+//! (`EspTls::negotiate_server`), and implements `Read` / `Write`, so that protocols other than
+//! HTTP and MQTT can use the hardware-accelerated TLS of the ESP IDF. The socket is either
+//! managed by `esp-tls` itself (`EspTls::new` and `EspTls::connect`), adopted from an already
+//! connected `std::net::TcpStream` (`TcpSocket`), or any other file descriptor based socket
+//! implementing `Socket`.
+//!
+//! `EspAsyncTls` is the async counterpart, for sockets implementing `PollableSocket`.
+//!
+//! DTLS over UDP sockets is provided by `dtls::EspDtls`.
 
 #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
 use core::convert::TryFrom;
@@ -14,6 +25,48 @@ use crate::sys::EspError;
 ))]
 pub use self::esptls::*;
 
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_esp_tls_using_mbedtls,
+    feature = "alloc"
+))]
+pub mod ca;
+
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_esp_tls_using_mbedtls,
+    esp_idf_esp_tls_use_ds_peripheral,
+    feature = "alloc"
+))]
+pub mod ds;
+
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_esp_tls_using_mbedtls,
+    any(
+        not(esp_idf_version_major = "4"),
+        esp_idf_esp_tls_client_session_tickets
+    )
+))]
+pub mod session;
+
+#[cfg(all(
+    esp_idf_comp_mbedtls_enabled,
+    esp_idf_mbedtls_ssl_proto_dtls,
+    not(esp_idf_version_major = "4"),
+    feature = "std"
+))]
+pub mod dtls;
+
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_esp_tls_using_mbedtls,
+    esp_idf_mbedtls_certificate_bundle,
+    not(esp_idf_version_major = "4"),
+    feature = "alloc"
+))]
+pub mod pinning;
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Psk<'a> {
     pub key: &'a [u8],
@@ -143,6 +196,7 @@ mod esptls {
     /// see https://www.ietf.org/rfc/rfc3280.txt ub-common-name-length
     const MAX_COMMON_NAME_LENGTH: usize = 64;
 
+    #[derive(Clone, Debug)]
     pub struct Config<'a> {
         /// up to 9 ALPNs allowed, with avg 10 bytes for each name
         pub alpn_protos: Option<&'a [&'a str]>,
@@ -151,9 +205,13 @@ mod esptls {
         pub client_key: Option<X509<'a>>,
         pub client_key_password: Option<&'a str>,
         pub non_block: bool,
+        /// Use the private key held by the ATECC608 secure element
         pub use_secure_element: bool,
         pub timeout_ms: u32,
+        /// Verify the server certificate against the global CA store, see `ca::EspGlobalCaStore`
         pub use_global_ca_store: bool,
+        /// The name to verify the server certificate against and to send with SNI, if different
+        /// from the host connected to
         pub common_name: Option<&'a str>,
         pub skip_common_name: bool,
         pub keep_alive_cfg: Option<KeepAliveConfig>,
@@ -161,7 +219,21 @@ mod esptls {
         /// whether to use esp_crt_bundle_attach, see https://docs.espressif.com/projects/esp-idf/en/latest/esp32s2/api-reference/protocols/esp_crt_bundle.html
         #[cfg(esp_idf_mbedtls_certificate_bundle)]
         pub use_crt_bundle_attach: bool,
-        // TODO ds_data not implemented
+        /// Verify the server certificate against the global CA store and the pinned public
+        /// keys, see `pinning`; takes precedence over `use_crt_bundle_attach`
+        #[cfg(all(
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_mbedtls_certificate_bundle,
+            not(esp_idf_version_major = "4"),
+            feature = "alloc"
+        ))]
+        pub use_pinning: bool,
+        /// Use the private key held by the Digital Signature peripheral, instead of `client_key`
+        #[cfg(all(esp_idf_esp_tls_use_ds_peripheral, feature = "alloc"))]
+        pub ds_key: Option<&'a super::ds::DsKey>,
+        /// A session saved from a previous connection to the server, to resume
+        #[cfg(all(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_client_session_tickets))]
+        pub client_session: Option<&'a super::session::ClientSession>,
         pub is_plain_tcp: bool,
     }
 
@@ -183,6 +255,17 @@ mod esptls {
                 psk_hint_key: None,
                 #[cfg(esp_idf_mbedtls_certificate_bundle)]
                 use_crt_bundle_attach: true,
+                #[cfg(all(
+                    esp_idf_esp_tls_using_mbedtls,
+                    esp_idf_mbedtls_certificate_bundle,
+                    not(esp_idf_version_major = "4"),
+                    feature = "alloc"
+                ))]
+                use_pinning: false,
+                #[cfg(all(esp_idf_esp_tls_use_ds_peripheral, feature = "alloc"))]
+                ds_key: None,
+                #[cfg(all(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_client_session_tickets))]
+                client_session: None,
                 is_plain_tcp: false,
             }
         }
@@ -254,6 +337,26 @@ mod esptls {
                 rcfg.crt_bundle_attach = Some(sys::esp_crt_bundle_attach);
             }
 
+            #[cfg(all(
+                esp_idf_esp_tls_using_mbedtls,
+                esp_idf_mbedtls_certificate_bundle,
+                not(esp_idf_version_major = "4"),
+                feature = "alloc"
+            ))]
+            if self.use_pinning {
+                rcfg.crt_bundle_attach = Some(super::pinning::PINNING_ATTACH);
+            }
+
+            #[cfg(all(esp_idf_esp_tls_use_ds_peripheral, feature = "alloc"))]
+            if let Some(ds_key) = self.ds_key {
+                rcfg.ds_data = ds_key.as_ds_data();
+            }
+
+            #[cfg(all(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_client_session_tickets))]
+            if let Some(client_session) = self.client_session {
+                rcfg.client_session = client_session.as_raw();
+            }
+
             rcfg.is_plain_tcp = self.is_plain_tcp;
 
             #[cfg(esp_idf_comp_lwip_enabled)]
@@ -294,9 +397,15 @@ mod esptls {
     #[derive(Clone, Default)]
     pub struct CompletedHandshake {
         alpn: AlpnBuf,
+        duration: Duration,
     }
 
     impl CompletedHandshake {
+        /// How long the handshake took, including the TCP connection for `EspTls::connect`
+        pub fn duration(&self) -> Duration {
+            self.duration
+        }
+
         pub fn alpn_proto(&self) -> Option<&str> {
             let p = CStr::from_bytes_until_nul(self.alpn.as_slice()).unwrap();
             // Safety: the bytes always come from a user supplied &str.
@@ -311,9 +420,10 @@ mod esptls {
         }
 
         // Safety: Must be called while the configured ALPN protocol strings are valid.
-        unsafe fn extract(raw: *mut sys::esp_tls) -> CompletedHandshake {
+        unsafe fn extract(raw: *mut sys::esp_tls, duration: Duration) -> CompletedHandshake {
             CompletedHandshake {
                 alpn: unsafe { Self::extract_alpn(raw) }.unwrap_or_default(),
+                duration,
             }
         }
 
@@ -372,6 +482,7 @@ mod esptls {
         pub count: u32,
     }
 
+    #[derive(Clone, Debug)]
     pub struct PskHintKey<'a> {
         pub key: &'a [u8],
         pub hint: &'a CStr,
@@ -388,6 +499,9 @@ mod esptls {
         pub use_secure_element: bool,
         #[cfg(esp_idf_esp_tls_server_cert_select_hook)]
         pub handshake_callback: Option<extern "C" fn(*mut sys::mbedtls_ssl_context) -> c_int>,
+        /// The session ticket keys, enabling clients to resume their sessions
+        #[cfg(all(esp_idf_esp_tls_server_session_tickets, feature = "alloc"))]
+        pub session_tickets: Option<ServerSessionTickets>,
     }
 
     #[cfg(esp_idf_esp_tls_server)]
@@ -402,6 +516,8 @@ mod esptls {
                 use_secure_element: false,
                 #[cfg(esp_idf_esp_tls_server_cert_select_hook)]
                 handshake_callback: None,
+                #[cfg(all(esp_idf_esp_tls_server_session_tickets, feature = "alloc"))]
+                session_tickets: None,
             }
         }
 
@@ -444,6 +560,11 @@ mod esptls {
                 rcfg.cert_select_cb = cb;
             }
 
+            #[cfg(all(esp_idf_esp_tls_server_session_tickets, feature = "alloc"))]
+            if let Some(session_tickets) = &self.session_tickets {
+                rcfg.ticket_ctx = session_tickets.0.raw;
+            }
+
             Ok(rcfg)
         }
     }
@@ -455,6 +576,86 @@ mod esptls {
         }
     }
 
+    /// The keys used by a server to encrypt the session tickets handed out to its clients
+    ///
+    /// The same `ServerSessionTickets` should be used for all the sessions of the server, so that
+    /// the tickets handed out in one session are accepted in the next ones. The keys are
+    /// freed once the last session using them is dropped.
+    #[cfg(all(
+        esp_idf_esp_tls_server,
+        esp_idf_esp_tls_server_session_tickets,
+        feature = "alloc"
+    ))]
+    #[derive(Clone)]
+    pub struct ServerSessionTickets(alloc::sync::Arc<RawSessionTickets>);
+
+    #[cfg(all(
+        esp_idf_esp_tls_server,
+        esp_idf_esp_tls_server_session_tickets,
+        feature = "alloc"
+    ))]
+    impl ServerSessionTickets {
+        pub fn new() -> Result<Self, EspError> {
+            let mut rcfg: sys::esp_tls_cfg_server = Default::default();
+
+            sys::esp!(unsafe { sys::esp_tls_cfg_server_session_tickets_init(&mut rcfg) })?;
+
+            Ok(Self(alloc::sync::Arc::new(RawSessionTickets {
+                raw: rcfg.ticket_ctx,
+            })))
+        }
+    }
+
+    #[cfg(all(
+        esp_idf_esp_tls_server,
+        esp_idf_esp_tls_server_session_tickets,
+        feature = "alloc"
+    ))]
+    impl core::fmt::Debug for ServerSessionTickets {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("ServerSessionTickets")
+                .finish_non_exhaustive()
+        }
+    }
+
+    #[cfg(all(
+        esp_idf_esp_tls_server,
+        esp_idf_esp_tls_server_session_tickets,
+        feature = "alloc"
+    ))]
+    struct RawSessionTickets {
+        raw: *mut sys::esp_tls_server_session_ticket_ctx_t,
+    }
+
+    #[cfg(all(
+        esp_idf_esp_tls_server,
+        esp_idf_esp_tls_server_session_tickets,
+        feature = "alloc"
+    ))]
+    impl Drop for RawSessionTickets {
+        fn drop(&mut self) {
+            let mut rcfg: sys::esp_tls_cfg_server = Default::default();
+            rcfg.ticket_ctx = self.raw;
+
+            unsafe { sys::esp_tls_cfg_server_session_tickets_free(&mut rcfg) };
+        }
+    }
+
+    // The ticket context is protected by its own mutex in Mbed TLS
+    #[cfg(all(
+        esp_idf_esp_tls_server,
+        esp_idf_esp_tls_server_session_tickets,
+        feature = "alloc"
+    ))]
+    unsafe impl Send for RawSessionTickets {}
+
+    #[cfg(all(
+        esp_idf_esp_tls_server,
+        esp_idf_esp_tls_server_session_tickets,
+        feature = "alloc"
+    ))]
+    unsafe impl Sync for RawSessionTickets {}
+
     pub trait Socket {
         /// Returns the integer FD.
         fn handle(&self) -> i32;
@@ -481,15 +682,67 @@ mod esptls {
         }
     }
 
-    /// Wrapper for `esp-tls` module. Only supports synchronous operation for now.
+    /// A connected `std::net::TcpStream`, to be adopted by `EspTls`
+    ///
+    /// Once adopted, the socket is owned and eventually closed by `esp-tls`.
+    #[cfg(feature = "std")]
+    pub struct TcpSocket(Option<std::net::TcpStream>);
+
+    #[cfg(feature = "std")]
+    impl TcpSocket {
+        pub const fn new(stream: std::net::TcpStream) -> Self {
+            Self(Some(stream))
+        }
+
+        /// Return the underlying stream, e.g. to query its addresses or set its timeouts
+        pub fn stream(&self) -> &std::net::TcpStream {
+            self.0.as_ref().unwrap()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl From<std::net::TcpStream> for TcpSocket {
+        fn from(stream: std::net::TcpStream) -> Self {
+            Self::new(stream)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl Socket for TcpSocket {
+        fn handle(&self) -> i32 {
+            use std::os::fd::AsRawFd;
+
+            self.stream().as_raw_fd()
+        }
+
+        fn release(&mut self) -> Result<(), EspError> {
+            use std::os::fd::IntoRawFd;
+
+            if let Some(stream) = self.0.take() {
+                let _ = stream.into_raw_fd();
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Wrapper for `esp-tls` module, for synchronous operation. See `EspAsyncTls` for the
+    /// async one.
     pub struct EspTls<S>
     where
         S: Socket,
     {
         raw: *mut sys::esp_tls,
         socket: S,
+        handshake_started: Option<Duration>,
         #[cfg(esp_idf_esp_tls_server)]
         server_session: bool,
+        #[cfg(all(
+            esp_idf_esp_tls_server,
+            esp_idf_esp_tls_server_session_tickets,
+            feature = "alloc"
+        ))]
+        _session_tickets: Option<ServerSessionTickets>,
     }
 
     // A single Mbed TLS context itself is safe to send across threads.
@@ -514,8 +767,15 @@ mod esptls {
                 Ok(Self {
                     raw,
                     socket: InternalSocket(()),
+                    handshake_started: None,
                     #[cfg(esp_idf_esp_tls_server)]
                     server_session: false,
+                    #[cfg(all(
+                        esp_idf_esp_tls_server,
+                        esp_idf_esp_tls_server_session_tickets,
+                        feature = "alloc"
+                    ))]
+                    _session_tickets: None,
                 })
             } else {
                 Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())
@@ -576,8 +836,15 @@ mod esptls {
                 Ok(Self {
                     raw,
                     socket,
+                    handshake_started: None,
                     #[cfg(esp_idf_esp_tls_server)]
                     server_session: false,
+                    #[cfg(all(
+                        esp_idf_esp_tls_server,
+                        esp_idf_esp_tls_server_session_tickets,
+                        feature = "alloc"
+                    ))]
+                    _session_tickets: None,
                 })
             } else {
                 Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())
@@ -614,6 +881,31 @@ mod esptls {
             res
         }
 
+        /// Create a new `EspTls` instance adopting the supplied socket, for acting as the server
+        /// with `negotiate_server`. The socket should be an accepted connection.
+        ///
+        /// Unlike `adopt`, this is supported by all ESP IDF versions.
+        ///
+        /// # Errors
+        ///
+        /// * `ESP_ERR_NO_MEM` if not enough memory to create the TLS connection
+        #[cfg(esp_idf_esp_tls_server)]
+        pub fn adopt_server(socket: S) -> Result<Self, EspError> {
+            let raw = unsafe { sys::esp_tls_init() };
+            if !raw.is_null() {
+                Ok(Self {
+                    raw,
+                    socket,
+                    handshake_started: None,
+                    server_session: false,
+                    #[cfg(all(esp_idf_esp_tls_server_session_tickets, feature = "alloc"))]
+                    _session_tickets: None,
+                })
+            } else {
+                Err(EspError::from_infallible::<ESP_ERR_NO_MEM>())
+            }
+        }
+
         /// Establish a TLS/SSL connection using the adopted connection, acting as the server.
         ///
         /// # Errors
@@ -624,6 +916,12 @@ mod esptls {
             let mut bufs = RawConfigBufs::default();
             let mut rcfg = cfg.try_into_raw(&mut bufs)?;
 
+            // Keep the session ticket keys around for as long as the session
+            #[cfg(all(esp_idf_esp_tls_server_session_tickets, feature = "alloc"))]
+            {
+                self._session_tickets.clone_from(&cfg.session_tickets);
+            }
+
             unsafe {
                 let error =
                     sys::esp_tls_server_session_create(&mut rcfg, self.socket.handle(), self.raw);
@@ -649,6 +947,11 @@ mod esptls {
             asynch: bool,
             cfg: &sys::esp_tls_cfg,
         ) -> Result<CompletedHandshake, EspError> {
+            let now = || Duration::from_micros(unsafe { sys::esp_timer_get_time() } as _);
+
+            // In non-blocking mode, the handshake spans several calls
+            let started = *self.handshake_started.get_or_insert_with(now);
+
             let ret = unsafe {
                 if asynch {
                     sys::esp_tls_conn_new_async(
@@ -669,8 +972,15 @@ mod esptls {
                 }
             };
 
+            if !matches!(
+                ret,
+                0 | ESP_TLS_ERR_SSL_WANT_READ | ESP_TLS_ERR_SSL_WANT_WRITE
+            ) {
+                self.handshake_started = None;
+            }
+
             match ret {
-                1 => Ok(unsafe { CompletedHandshake::extract(self.raw) }),
+                1 => Ok(unsafe { CompletedHandshake::extract(self.raw, now() - started) }),
                 ESP_TLS_ERR_SSL_WANT_READ => Err(EspError::from_infallible::<
                     { ESP_TLS_ERR_SSL_WANT_READ as i32 },
                 >()),
@@ -0,0 +1,208 @@
+//! Trust anchors: the certificate bundle and the global CA store
+//!
+//! The server certificates are verified against either:
+//! - The certificate bundle (`Config::use_crt_bundle_attach`, or `CRT_BUNDLE_ATTACH` for the
+//!   `crt_bundle_attach` fields of the HTTP, MQTT and WebSocket client configurations), i.e. the
+//!   Mozilla root CAs embedded in the firmware, or a custom bundle set with `set_crt_bundle`
+//! - The global CA store (`use_global_ca_store`), whose certificates are managed at runtime with
+//!   `EspGlobalCaStore`, e.g. after being downloaded or read from NVS
+//!
+//! Once connected, `EspTls::matched_ca_name` returns the name of the CA the certificate chain
+//! of the server was verified against.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::X509;
+
+#[cfg(all(
+    not(esp_idf_version_major = "4"),
+    esp_idf_mbedtls_ssl_keep_peer_certificate
+))]
+use super::{EspTls, Socket};
+
+/// The attach callback of the certificate bundle
+#[cfg(esp_idf_mbedtls_certificate_bundle)]
+pub const CRT_BUNDLE_ATTACH: unsafe extern "C" fn(*mut core::ffi::c_void) -> esp_err_t =
+    esp_crt_bundle_attach;
+
+/// Replace the certificate bundle embedded in the firmware with a custom one, as generated by
+/// the `gen_crt_bundle.py` script of the ESP IDF
+#[cfg(esp_idf_mbedtls_certificate_bundle)]
+pub fn set_crt_bundle(bundle: &'static [u8]) -> Result<(), EspError> {
+    #[cfg(esp_idf_version_major = "4")]
+    {
+        unsafe { esp_crt_bundle_set(bundle.as_ptr(), bundle.len() as _) };
+
+        Ok(())
+    }
+
+    #[cfg(not(esp_idf_version_major = "4"))]
+    esp!(unsafe { esp_crt_bundle_set(bundle.as_ptr(), bundle.len() as _) })
+}
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+/// The global CA store, used by the TLS connections configured with `use_global_ca_store`
+///
+/// The certificates are copied, so they can be e.g. read from NVS into a temporary buffer.
+/// Note that the store must not be modified while a handshake using it is in progress.
+pub struct EspGlobalCaStore {
+    certs: Vec<Vec<u8>>,
+}
+
+impl EspGlobalCaStore {
+    /// Take the global CA store, which is initially empty
+    ///
+    /// Returns `ESP_ERR_INVALID_STATE` if the store is already taken, or if a global CA store is
+    /// already set otherwise, e.g. with `esp_tls_set_global_ca_store`, which is left untouched.
+    pub fn take() -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken || !unsafe { esp_tls_get_global_ca_store() }.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        *taken = true;
+
+        Ok(Self { certs: Vec::new() })
+    }
+
+    /// Add a PEM or DER certificate - or a chain of PEM certificates - to the store
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the certificate cannot be parsed.
+    pub fn add(&mut self, cert: X509) -> Result<(), EspError> {
+        if self.certs.is_empty() {
+            esp!(unsafe { esp_tls_init_global_ca_store() })?;
+        }
+
+        self.certs.push(cert.data().to_vec());
+
+        if let Err(err) = Self::parse(cert.data()) {
+            // The chain might have been partially updated
+            self.certs.pop();
+            self.rebuild()?;
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Remove a certificate previously added to the store, returning `false` if not found
+    pub fn remove(&mut self, cert: X509) -> Result<bool, EspError> {
+        let len = self.certs.len();
+
+        self.certs.retain(|other| other.as_slice() != cert.data());
+
+        if self.certs.len() != len {
+            self.rebuild()?;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Remove all the certificates from the store
+    pub fn clear(&mut self) {
+        self.certs.clear();
+
+        unsafe { esp_tls_free_global_ca_store() };
+    }
+
+    /// Return the certificates in the store, as added
+    pub fn certificates(&self) -> impl Iterator<Item = &[u8]> {
+        self.certs.iter().map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.certs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certs.is_empty()
+    }
+
+    fn rebuild(&mut self) -> Result<(), EspError> {
+        unsafe { esp_tls_free_global_ca_store() };
+
+        if self.certs.is_empty() {
+            return Ok(());
+        }
+
+        esp!(unsafe { esp_tls_init_global_ca_store() })?;
+
+        for cert in &self.certs {
+            Self::parse(cert)?;
+        }
+
+        Ok(())
+    }
+
+    fn parse(cert: &[u8]) -> Result<(), EspError> {
+        let ret = unsafe {
+            mbedtls_x509_crt_parse(esp_tls_get_global_ca_store(), cert.as_ptr(), cert.len())
+        };
+
+        if ret == 0 {
+            Ok(())
+        } else {
+            log::warn!("Failed to parse CA certificate (error {ret})");
+
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        }
+    }
+}
+
+impl Drop for EspGlobalCaStore {
+    fn drop(&mut self) {
+        unsafe { esp_tls_free_global_ca_store() };
+
+        *TAKEN.lock() = false;
+    }
+}
+
+#[cfg(all(
+    not(esp_idf_version_major = "4"),
+    esp_idf_mbedtls_ssl_keep_peer_certificate
+))]
+impl<S> EspTls<S>
+where
+    S: Socket,
+{
+    /// Return the distinguished name of the CA which issued the topmost certificate of the
+    /// chain presented by the peer - i.e. the trust anchor it was verified against - or `None`
+    /// if no handshake was completed
+    ///
+    /// The name is formatted in `buf`, e.g. `C=US, O=Let's Encrypt, CN=R3`.
+    pub fn matched_ca_name<'b>(&self, buf: &'b mut [u8]) -> Option<&'b str> {
+        let ssl =
+            unsafe { esp_tls_get_ssl_context(self.context_handle()) } as *const mbedtls_ssl_context;
+
+        if ssl.is_null() {
+            return None;
+        }
+
+        let mut crt = unsafe { mbedtls_ssl_get_peer_cert(ssl) };
+
+        if crt.is_null() {
+            return None;
+        }
+
+        while !unsafe { (*crt).next }.is_null() {
+            crt = unsafe { (*crt).next };
+        }
+
+        let len = unsafe { mbedtls_x509_dn_gets(buf.as_mut_ptr() as _, buf.len(), &(*crt).issuer) };
+
+        if len >= 0 {
+            core::str::from_utf8(&buf[..len as usize]).ok()
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Client private keys held by the Digital Signature peripheral
+//!
+//! With the Digital Signature (DS) peripheral of the ESP32-S2, ESP32-S3, ESP32-C3 and later chips,
+//! the RSA private key of the TLS client certificate is only stored encrypted with an HMAC key
+//! burnt into an eFuse key block, which cannot be read back by the software. The signatures of
+//! the TLS handshake are computed by the peripheral, so the plaintext private key never exists
+//! in flash or in RAM.
+//!
+//! The encrypted key parameters are generated on the host by the `configure_ds.py` script of the
+//! ESP IDF, which also burns the HMAC key and stores the parameters in NVS - see `DsKey::from_nvs`.
+//!
+//! A `DsKey` is used instead of the private key (`client_key` / `private_key`) in the
+//! TLS, MQTT and HTTP client configurations, along with the client certificate.
+//!
+//! On the ESP32-ROOM-32SE, the private key is similarly held by the ATECC608 secure element,
+//! which is selected with `use_secure_element` in these configurations.
+
+use core::fmt::{self, Debug};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+#[cfg(esp_idf_comp_nvs_flash_enabled)]
+use crate::nvs::{EspNvs, NvsPartitionId};
+use crate::sys::*;
+
+/// The NVS namespace and keys used by `configure_ds.py`
+pub const NVS_NAMESPACE: &str = "esp_ds_ns";
+const NVS_KEY_ID: &str = "esp_ds_key_id";
+const NVS_RSA_LEN: &str = "esp_ds_rsa_len";
+const NVS_CIPHER_C: &str = "esp_ds_c";
+const NVS_IV: &str = "esp_ds_iv";
+
+const IV_LEN: usize = 16;
+
+// Mirrors `esp_ds_data_ctx_t`, which is passed as `ds_data` to ESP-TLS
+#[repr(C)]
+struct DsDataCtx {
+    esp_ds_data: *mut esp_ds_data_t,
+    efuse_key_id: u8,
+    rsa_length_bits: u16,
+}
+
+struct RawDsKey {
+    ctx: DsDataCtx,
+    data: esp_ds_data_t,
+}
+
+/// The encrypted parameters of an RSA private key held by the Digital Signature peripheral
+pub struct DsKey(Box<RawDsKey>);
+
+impl DsKey {
+    /// Create the key from its encrypted parameters
+    ///
+    /// - `efuse_key_id`: the eFuse key block holding the HMAC key, between 0 and 5
+    /// - `rsa_length_bits`: the length of the RSA key: 1024, 2048, 3072 or 4096 bits
+    ///   (up to 3072 bits on the ESP32-C3)
+    /// - `iv` and `cipher_c`: the initialization vector and the encrypted key parameters
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the parameters are not valid for the chip.
+    pub fn new(
+        efuse_key_id: u8,
+        rsa_length_bits: u16,
+        iv: &[u8; IV_LEN],
+        cipher_c: &[u8],
+    ) -> Result<Self, EspError> {
+        if efuse_key_id > 5 || !matches!(rsa_length_bits, 1024 | 2048 | 3072 | 4096) {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut raw = Box::new(RawDsKey {
+            ctx: DsDataCtx {
+                esp_ds_data: core::ptr::null_mut(),
+                efuse_key_id,
+                rsa_length_bits,
+            },
+            data: unsafe { core::mem::zeroed() },
+        });
+
+        let max_len = raw.data.c.len();
+        if cipher_c.len() != max_len || (rsa_length_bits as usize / 8) * 3 > max_len {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        // The length is encoded as the number of 32-bit words, minus one
+        raw.data.rsa_length = (rsa_length_bits as u32 / 32 - 1) as _;
+        raw.data.c.copy_from_slice(cipher_c);
+
+        for (word, bytes) in raw.data.iv.iter_mut().zip(iv.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        raw.ctx.esp_ds_data = &mut raw.data;
+
+        Ok(Self(raw))
+    }
+
+    /// Load the key parameters stored in NVS by `configure_ds.py`, which should be opened with
+    /// the `NVS_NAMESPACE` namespace
+    ///
+    /// Returns `ESP_ERR_NOT_FOUND` if the parameters are missing.
+    #[cfg(esp_idf_comp_nvs_flash_enabled)]
+    pub fn from_nvs<T>(nvs: &EspNvs<T>) -> Result<Self, EspError>
+    where
+        T: NvsPartitionId,
+    {
+        let not_found = || EspError::from_infallible::<ESP_ERR_NOT_FOUND>();
+
+        let efuse_key_id = nvs.get_u8(NVS_KEY_ID)?.ok_or_else(not_found)?;
+        let rsa_length_bits = nvs.get_u16(NVS_RSA_LEN)?.ok_or_else(not_found)?;
+
+        let mut iv = [0; IV_LEN];
+        let iv_len = nvs.get_raw(NVS_IV, &mut iv)?.ok_or_else(not_found)?.len();
+        if iv_len != IV_LEN {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+        }
+
+        let mut cipher_c = alloc::vec![0; core::mem::size_of::<esp_ds_data_t>()];
+        let cipher_c = nvs
+            .get_raw(NVS_CIPHER_C, &mut cipher_c)?
+            .ok_or_else(not_found)?;
+
+        Self::new(efuse_key_id, rsa_length_bits, &iv, cipher_c)
+    }
+
+    /// The eFuse key block holding the HMAC key
+    pub fn efuse_key_id(&self) -> u8 {
+        self.0.ctx.efuse_key_id
+    }
+
+    /// The length of the RSA key, in bits
+    pub fn rsa_length_bits(&self) -> u16 {
+        self.0.ctx.rsa_length_bits
+    }
+
+    /// The `ds_data` pointer of the ESP-TLS, MQTT and HTTP client configurations
+    pub(crate) fn as_ds_data(&self) -> *mut core::ffi::c_void {
+        &self.0.ctx as *const _ as *mut _
+    }
+}
+
+impl Debug for DsKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DsKey")
+            .field("efuse_key_id", &self.efuse_key_id())
+            .field("rsa_length_bits", &self.rsa_length_bits())
+            .finish_non_exhaustive()
+    }
+}
+
+// The parameters are only read by ESP-TLS, and are encrypted anyway
+unsafe impl Send for DsKey {}
+unsafe impl Sync for DsKey {}
@@ -0,0 +1,714 @@
+//! DTLS 1.2 over UDP
+//!
+//! `EspDtls` secures the datagrams exchanged over a connected `std::net::UdpSocket` with
+//! DTLS 1.2, as used by e.g. CoAPs or custom low-latency telemetry protocols. As `esp-tls` only
+//! supports TLS over TCP, this is built on top of Mbed TLS directly, but benefits all the same
+//! from the hardware acceleration of the ESP IDF port.
+//!
+//! As a client, the socket is connected to the server and passed to `EspDtls::connect`:
+//!
+//! ```ignore
+//! let socket = UdpSocket::bind("0.0.0.0:0")?;
+//! socket.connect("coap.example.com:5684")?;
+//!
+//! let mut dtls = EspDtls::connect(socket, &Config {
+//!     server_name: Some("coap.example.com"),
+//!     psk: Some(Psk { key: &PSK, hint: "device-1" }),
+//!     ..Default::default()
+//! })?;
+//!
+//! dtls.write(b"...")?;
+//! ```
+//!
+//! As a server, the socket is connected to the client, i.e. once its first datagram is received
+//! (`UdpSocket::peek_from`), and passed to `EspDtls::accept`. The server verifies the address of
+//! the client with a HelloVerifyRequest cookie, against denial of service amplification.
+//!
+//! Each `read` returns one datagram, and each `write` sends one datagram, which should fit in the
+//! MTU of the path (see `Config::mtu`).
+
+use core::ffi::{c_int, c_void};
+use core::fmt::{self, Debug};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+
+use std::net::{IpAddr, UdpSocket};
+
+use embedded_svc::io;
+
+use crate::crypto::mbedtls_rng;
+use crate::io::EspIOError;
+use crate::sys::*;
+
+use super::{Psk, X509};
+
+// The PSK ciphersuites offered when the server is only authenticated by the PSK, in order of
+// preference; the ones not enabled in the Mbed TLS configuration are skipped
+static PSK_CIPHERSUITES: [c_int; 9] = [
+    MBEDTLS_TLS_ECDHE_PSK_WITH_AES_128_CBC_SHA256 as _,
+    MBEDTLS_TLS_PSK_WITH_AES_128_GCM_SHA256 as _,
+    MBEDTLS_TLS_PSK_WITH_AES_256_GCM_SHA384 as _,
+    MBEDTLS_TLS_PSK_WITH_AES_128_CCM as _,
+    MBEDTLS_TLS_PSK_WITH_AES_128_CCM_8 as _,
+    MBEDTLS_TLS_PSK_WITH_AES_256_CCM as _,
+    MBEDTLS_TLS_PSK_WITH_AES_128_CBC_SHA256 as _,
+    MBEDTLS_TLS_PSK_WITH_AES_256_CBC_SHA384 as _,
+    0,
+];
+
+// From `mbedtls/net_sockets.h`
+const MBEDTLS_ERR_NET_SEND_FAILED: c_int = -0x004E;
+const MBEDTLS_ERR_NET_RECV_FAILED: c_int = -0x004C;
+
+/// The configuration of a DTLS client
+#[derive(Clone, Debug)]
+pub struct Config<'a> {
+    /// The CA to verify the server certificate against
+    pub ca_cert: Option<X509<'a>>,
+    /// Verify the server certificate against the certificate bundle
+    #[cfg(esp_idf_mbedtls_certificate_bundle)]
+    pub use_crt_bundle_attach: bool,
+    pub client_cert: Option<X509<'a>>,
+    pub client_key: Option<X509<'a>>,
+    pub client_key_password: Option<&'a str>,
+    /// The pre-shared key, and its identity
+    ///
+    /// Without a CA certificate or the certificate bundle, only the PSK ciphersuites are offered.
+    #[cfg(esp_idf_mbedtls_psk_modes)]
+    pub psk: Option<Psk<'a>>,
+    /// The name to verify the server certificate against and to send with SNI
+    pub server_name: Option<&'a str>,
+    /// Do not verify the server certificate; only for testing
+    pub skip_verification: bool,
+    /// The initial and maximum retransmission timeouts of the handshake
+    pub handshake_timeout_min: Duration,
+    pub handshake_timeout_max: Duration,
+    /// The timeout of `EspDtls::read`, after which `ESP_ERR_TIMEOUT` is returned
+    pub read_timeout: Option<Duration>,
+    /// The MTU of the path, which fragments the handshake messages accordingly
+    pub mtu: Option<u16>,
+}
+
+impl<'a> Config<'a> {
+    pub const fn new() -> Self {
+        Self {
+            ca_cert: None,
+            #[cfg(esp_idf_mbedtls_certificate_bundle)]
+            use_crt_bundle_attach: false,
+            client_cert: None,
+            client_key: None,
+            client_key_password: None,
+            #[cfg(esp_idf_mbedtls_psk_modes)]
+            psk: None,
+            server_name: None,
+            skip_verification: false,
+            handshake_timeout_min: Duration::from_secs(1),
+            handshake_timeout_max: Duration::from_secs(60),
+            read_timeout: None,
+            mtu: None,
+        }
+    }
+}
+
+impl<'a> Default for Config<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The configuration of a DTLS server
+#[derive(Clone, Debug)]
+pub struct ServerConfig<'a> {
+    pub server_cert: Option<X509<'a>>,
+    pub server_key: Option<X509<'a>>,
+    pub server_key_password: Option<&'a str>,
+    /// The CA to verify the client certificates against; client certificates are not
+    /// requested if not set
+    pub ca_cert: Option<X509<'a>>,
+    /// The pre-shared key, and its identity
+    #[cfg(esp_idf_mbedtls_psk_modes)]
+    pub psk: Option<Psk<'a>>,
+    /// The initial and maximum retransmission timeouts of the handshake
+    pub handshake_timeout_min: Duration,
+    pub handshake_timeout_max: Duration,
+    /// The timeout of `EspDtls::read`, after which `ESP_ERR_TIMEOUT` is returned
+    pub read_timeout: Option<Duration>,
+    /// The MTU of the path, which fragments the handshake messages accordingly
+    pub mtu: Option<u16>,
+}
+
+impl<'a> ServerConfig<'a> {
+    pub const fn new() -> Self {
+        Self {
+            server_cert: None,
+            server_key: None,
+            server_key_password: None,
+            ca_cert: None,
+            #[cfg(esp_idf_mbedtls_psk_modes)]
+            psk: None,
+            handshake_timeout_min: Duration::from_secs(1),
+            handshake_timeout_max: Duration::from_secs(60),
+            read_timeout: None,
+            mtu: None,
+        }
+    }
+}
+
+impl<'a> Default for ServerConfig<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The retransmission timer of the handshake, as per `mbedtls_ssl_set_timer_cb`
+#[derive(Default)]
+struct Timer {
+    intermediate_ms: u32,
+    final_ms: u32,
+    started_us: i64,
+}
+
+struct Context {
+    ssl: mbedtls_ssl_context,
+    conf: mbedtls_ssl_config,
+    ca_cert: mbedtls_x509_crt,
+    own_cert: mbedtls_x509_crt,
+    own_key: mbedtls_pk_context,
+    cookie: mbedtls_ssl_cookie_ctx,
+    timer: Timer,
+    socket: UdpSocket,
+}
+
+/// A DTLS connection over a connected UDP socket
+pub struct EspDtls(Box<Context>);
+
+impl EspDtls {
+    /// Perform the handshake with the server the socket is connected to
+    ///
+    /// # Errors
+    ///
+    /// * `ESP_ERR_INVALID_ARG` if the configuration has no way to authenticate the server, or
+    ///   if the certificates or keys cannot be parsed
+    /// * `ESP_ERR_TIMEOUT` if the server did not answer before `handshake_timeout_max`
+    /// * Any other `EspError` - wrapping the Mbed TLS error - if the handshake failed
+    pub fn connect(socket: UdpSocket, conf: &Config) -> Result<Self, EspError> {
+        #[cfg(esp_idf_mbedtls_certificate_bundle)]
+        let use_crt_bundle = conf.use_crt_bundle_attach;
+        #[cfg(not(esp_idf_mbedtls_certificate_bundle))]
+        let use_crt_bundle = false;
+
+        #[cfg(esp_idf_mbedtls_psk_modes)]
+        let psk = conf.psk.as_ref();
+        #[cfg(not(esp_idf_mbedtls_psk_modes))]
+        let psk = None;
+
+        if conf.ca_cert.is_none() && psk.is_none() && !use_crt_bundle && !conf.skip_verification {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        let mut dtls = Self::new(socket, MBEDTLS_SSL_IS_CLIENT as _)?;
+
+        dtls.configure(
+            conf.ca_cert,
+            conf.client_cert.zip(conf.client_key),
+            conf.client_key_password,
+            psk,
+            conf.handshake_timeout_min..conf.handshake_timeout_max,
+            conf.read_timeout,
+        )?;
+
+        let ctx = &mut *dtls.0;
+
+        let authmode = if conf.skip_verification {
+            MBEDTLS_SSL_VERIFY_NONE
+        } else {
+            MBEDTLS_SSL_VERIFY_REQUIRED
+        };
+
+        unsafe { mbedtls_ssl_conf_authmode(&mut ctx.conf, authmode as _) };
+
+        // With only a PSK to authenticate the server, the certificate ciphersuites - whose
+        // certificate could not be verified - must not be negotiated
+        if psk.is_some() && conf.ca_cert.is_none() && !use_crt_bundle && !conf.skip_verification {
+            unsafe { mbedtls_ssl_conf_ciphersuites(&mut ctx.conf, PSK_CIPHERSUITES.as_ptr()) };
+        }
+
+        #[cfg(esp_idf_mbedtls_certificate_bundle)]
+        if use_crt_bundle {
+            esp!(unsafe { esp_crt_bundle_attach(&mut ctx.conf as *mut _ as *mut c_void) })?;
+        }
+
+        dtls.setup(conf.mtu)?;
+
+        if let Some(server_name) = conf.server_name {
+            let server_name = CString::new(server_name)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+            Self::check(unsafe {
+                mbedtls_ssl_set_hostname(&mut dtls.0.ssl, server_name.as_ptr())
+            })?;
+        }
+
+        dtls.handshake(None)?;
+
+        Ok(dtls)
+    }
+
+    /// Perform the handshake with the client the socket is connected to
+    ///
+    /// # Errors
+    ///
+    /// * `ESP_ERR_INVALID_ARG` if the configuration has neither a certificate nor a pre-shared
+    ///   key, or if the certificates or keys cannot be parsed
+    /// * `ESP_ERR_TIMEOUT` if the client did not complete the handshake before
+    ///   `handshake_timeout_max`
+    /// * Any other `EspError` - wrapping the Mbed TLS error - if the handshake failed
+    pub fn accept(socket: UdpSocket, conf: &ServerConfig) -> Result<Self, EspError> {
+        let own_cert = conf.server_cert.zip(conf.server_key);
+
+        #[cfg(esp_idf_mbedtls_psk_modes)]
+        let psk = conf.psk.as_ref();
+        #[cfg(not(esp_idf_mbedtls_psk_modes))]
+        let psk = None;
+
+        if own_cert.is_none() && psk.is_none() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        // The address of the client, for the HelloVerifyRequest cookies
+        let client_id = match socket.peer_addr() {
+            Ok(addr) => {
+                let mut id = alloc::vec::Vec::with_capacity(18);
+
+                match addr.ip() {
+                    IpAddr::V4(ip) => id.extend_from_slice(&ip.octets()),
+                    IpAddr::V6(ip) => id.extend_from_slice(&ip.octets()),
+                }
+
+                id.extend_from_slice(&addr.port().to_be_bytes());
+                id
+            }
+            Err(_) => return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+        };
+
+        let mut dtls = Self::new(socket, MBEDTLS_SSL_IS_SERVER as _)?;
+
+        dtls.configure(
+            conf.ca_cert,
+            own_cert,
+            conf.server_key_password,
+            psk,
+            conf.handshake_timeout_min..conf.handshake_timeout_max,
+            conf.read_timeout,
+        )?;
+
+        let ctx = &mut *dtls.0;
+
+        let authmode = if conf.ca_cert.is_some() {
+            MBEDTLS_SSL_VERIFY_REQUIRED
+        } else {
+            MBEDTLS_SSL_VERIFY_NONE
+        };
+
+        unsafe {
+            mbedtls_ssl_conf_authmode(&mut ctx.conf, authmode as _);
+
+            Self::check(mbedtls_ssl_cookie_setup(
+                &mut ctx.cookie,
+                Some(mbedtls_rng),
+                core::ptr::null_mut(),
+            ))?;
+
+            mbedtls_ssl_conf_dtls_cookies(
+                &mut ctx.conf,
+                Some(mbedtls_ssl_cookie_write),
+                Some(mbedtls_ssl_cookie_check),
+                &mut ctx.cookie as *mut _ as *mut c_void,
+            );
+        }
+
+        dtls.setup(conf.mtu)?;
+        dtls.handshake(Some(&client_id))?;
+
+        Ok(dtls)
+    }
+
+    /// Read one datagram into the supplied buffer. Returns the number of bytes read, or 0 if
+    /// the peer closed the connection.
+    ///
+    /// # Errors
+    ///
+    /// * `ESP_ERR_TIMEOUT` if no datagram was received within `read_timeout`
+    /// * Any other `EspError` - wrapping the Mbed TLS error - for a general error
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let ret = unsafe { mbedtls_ssl_read(&mut self.0.ssl, buf.as_mut_ptr(), buf.len()) };
+
+            match ret {
+                MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_WANT_WRITE => continue,
+                MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY => break Ok(0),
+                ret => break Self::check(ret).map(|len| len as usize),
+            }
+        }
+    }
+
+    /// Send the supplied buffer as one datagram. Returns the number of bytes written.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let ret = unsafe { mbedtls_ssl_write(&mut self.0.ssl, buf.as_ptr(), buf.len()) };
+
+            match ret {
+                MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_WANT_WRITE => continue,
+                ret => break Self::check(ret).map(|len| len as usize),
+            }
+        }
+    }
+
+    /// Change the timeout of `EspDtls::read`; `None` blocks until a datagram is received
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        unsafe {
+            mbedtls_ssl_conf_read_timeout(
+                &mut self.0.conf,
+                timeout
+                    .map(|timeout| timeout.as_millis().max(1) as _)
+                    .unwrap_or(0),
+            )
+        };
+    }
+
+    /// Return the underlying socket, e.g. to query its addresses
+    pub fn socket(&self) -> &UdpSocket {
+        &self.0.socket
+    }
+
+    /// Return the negotiated ciphersuite
+    pub fn ciphersuite(&self) -> &str {
+        unsafe { crate::private::cstr::from_cstr_ptr(mbedtls_ssl_get_ciphersuite(&self.0.ssl)) }
+    }
+
+    fn new(socket: UdpSocket, endpoint: c_int) -> Result<Self, EspError> {
+        let mut ctx: Box<Context> = Box::new(Context {
+            ssl: unsafe { core::mem::zeroed() },
+            conf: unsafe { core::mem::zeroed() },
+            ca_cert: unsafe { core::mem::zeroed() },
+            own_cert: unsafe { core::mem::zeroed() },
+            own_key: unsafe { core::mem::zeroed() },
+            cookie: unsafe { core::mem::zeroed() },
+            timer: Default::default(),
+            socket,
+        });
+
+        unsafe {
+            mbedtls_ssl_init(&mut ctx.ssl);
+            mbedtls_ssl_config_init(&mut ctx.conf);
+            mbedtls_x509_crt_init(&mut ctx.ca_cert);
+            mbedtls_x509_crt_init(&mut ctx.own_cert);
+            mbedtls_pk_init(&mut ctx.own_key);
+            mbedtls_ssl_cookie_init(&mut ctx.cookie);
+        }
+
+        // From now on, the contexts are freed on drop
+        let mut dtls = Self(ctx);
+
+        Self::check(unsafe {
+            mbedtls_ssl_config_defaults(
+                &mut dtls.0.conf,
+                endpoint,
+                MBEDTLS_SSL_TRANSPORT_DATAGRAM as _,
+                MBEDTLS_SSL_PRESET_DEFAULT as _,
+            )
+        })?;
+
+        unsafe { mbedtls_ssl_conf_rng(&mut dtls.0.conf, Some(mbedtls_rng), core::ptr::null_mut()) };
+
+        Ok(dtls)
+    }
+
+    fn configure(
+        &mut self,
+        ca_cert: Option<X509>,
+        own_cert: Option<(X509, X509)>,
+        own_key_password: Option<&str>,
+        #[allow(unused_variables)] psk: Option<&Psk>,
+        handshake_timeout: core::ops::Range<Duration>,
+        read_timeout: Option<Duration>,
+    ) -> Result<(), EspError> {
+        let ctx = &mut *self.0;
+
+        if let Some(ca_cert) = ca_cert {
+            Self::parse_cert(&mut ctx.ca_cert, ca_cert)?;
+
+            unsafe {
+                mbedtls_ssl_conf_ca_chain(&mut ctx.conf, &mut ctx.ca_cert, core::ptr::null_mut())
+            };
+        }
+
+        if let Some((cert, key)) = own_cert {
+            Self::parse_cert(&mut ctx.own_cert, cert)?;
+
+            let password = own_key_password.unwrap_or_default();
+
+            let ret = unsafe {
+                mbedtls_pk_parse_key(
+                    &mut ctx.own_key,
+                    key.data().as_ptr(),
+                    key.data().len(),
+                    password.as_ptr(),
+                    password.len(),
+                    Some(mbedtls_rng),
+                    core::ptr::null_mut(),
+                )
+            };
+
+            if ret != 0 {
+                log::warn!("Failed to parse the private key (error {ret})");
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+
+            Self::check(unsafe {
+                mbedtls_ssl_conf_own_cert(&mut ctx.conf, &mut ctx.own_cert, &mut ctx.own_key)
+            })?;
+        }
+
+        #[cfg(esp_idf_mbedtls_psk_modes)]
+        if let Some(psk) = psk {
+            Self::check(unsafe {
+                mbedtls_ssl_conf_psk(
+                    &mut ctx.conf,
+                    psk.key.as_ptr(),
+                    psk.key.len(),
+                    psk.hint.as_ptr(),
+                    psk.hint.len(),
+                )
+            })?;
+        }
+
+        unsafe {
+            mbedtls_ssl_conf_handshake_timeout(
+                &mut ctx.conf,
+                handshake_timeout.start.as_millis() as _,
+                handshake_timeout.end.as_millis() as _,
+            );
+
+            mbedtls_ssl_conf_read_timeout(
+                &mut ctx.conf,
+                read_timeout
+                    .map(|timeout| timeout.as_millis() as _)
+                    .unwrap_or(0),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn setup(&mut self, mtu: Option<u16>) -> Result<(), EspError> {
+        let ctx = &mut *self.0;
+
+        Self::check(unsafe { mbedtls_ssl_setup(&mut ctx.ssl, &ctx.conf) })?;
+
+        unsafe {
+            mbedtls_ssl_set_bio(
+                &mut ctx.ssl,
+                &ctx.socket as *const _ as *mut c_void,
+                Some(Self::send),
+                None,
+                Some(Self::recv_timeout),
+            );
+
+            mbedtls_ssl_set_timer_cb(
+                &mut ctx.ssl,
+                &mut ctx.timer as *mut _ as *mut c_void,
+                Some(Self::set_timer),
+                Some(Self::get_timer),
+            );
+
+            if let Some(mtu) = mtu {
+                mbedtls_ssl_set_mtu(&mut ctx.ssl, mtu);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handshake(&mut self, client_id: Option<&[u8]>) -> Result<(), EspError> {
+        let ctx = &mut *self.0;
+
+        if let Some(client_id) = client_id {
+            Self::check(unsafe {
+                mbedtls_ssl_set_client_transport_id(
+                    &mut ctx.ssl,
+                    client_id.as_ptr(),
+                    client_id.len(),
+                )
+            })?;
+        }
+
+        loop {
+            let ret = unsafe { mbedtls_ssl_handshake(&mut ctx.ssl) };
+
+            match ret {
+                0 => break Ok(()),
+                MBEDTLS_ERR_SSL_WANT_READ | MBEDTLS_ERR_SSL_WANT_WRITE => continue,
+                MBEDTLS_ERR_SSL_HELLO_VERIFY_REQUIRED if client_id.is_some() => {
+                    // The client is expected to resend its hello with the cookie
+                    Self::check(unsafe { mbedtls_ssl_session_reset(&mut ctx.ssl) })?;
+
+                    let client_id = client_id.unwrap();
+
+                    Self::check(unsafe {
+                        mbedtls_ssl_set_client_transport_id(
+                            &mut ctx.ssl,
+                            client_id.as_ptr(),
+                            client_id.len(),
+                        )
+                    })?;
+                }
+                ret => {
+                    log::warn!("DTLS handshake failed (error {ret})");
+                    break Self::check(ret).map(|_| ());
+                }
+            }
+        }
+    }
+
+    fn parse_cert(crt: &mut mbedtls_x509_crt, cert: X509) -> Result<(), EspError> {
+        let ret = unsafe { mbedtls_x509_crt_parse(crt, cert.data().as_ptr(), cert.data().len()) };
+
+        if ret == 0 {
+            Ok(())
+        } else {
+            log::warn!("Failed to parse certificate (error {ret})");
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        }
+    }
+
+    fn check(ret: c_int) -> Result<c_int, EspError> {
+        match ret {
+            ret if ret >= 0 => Ok(ret),
+            MBEDTLS_ERR_SSL_TIMEOUT => Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>()),
+            ret => Err(EspError::from(ret).unwrap()),
+        }
+    }
+
+    unsafe extern "C" fn send(ctx: *mut c_void, buf: *const u8, len: usize) -> c_int {
+        let socket = &*(ctx as *const UdpSocket);
+
+        match socket.send(core::slice::from_raw_parts(buf, len)) {
+            Ok(len) => len as _,
+            Err(_) => MBEDTLS_ERR_NET_SEND_FAILED,
+        }
+    }
+
+    unsafe extern "C" fn recv_timeout(
+        ctx: *mut c_void,
+        buf: *mut u8,
+        len: usize,
+        timeout: u32,
+    ) -> c_int {
+        let socket = &*(ctx as *const UdpSocket);
+
+        let timeout = (timeout > 0).then(|| Duration::from_millis(timeout as _));
+        if socket.set_read_timeout(timeout).is_err() {
+            return MBEDTLS_ERR_NET_RECV_FAILED;
+        }
+
+        match socket.recv(core::slice::from_raw_parts_mut(buf, len)) {
+            Ok(len) => len as _,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                MBEDTLS_ERR_SSL_TIMEOUT
+            }
+            Err(_) => MBEDTLS_ERR_NET_RECV_FAILED,
+        }
+    }
+
+    unsafe extern "C" fn set_timer(ctx: *mut c_void, intermediate_ms: u32, final_ms: u32) {
+        let timer = &mut *(ctx as *mut Timer);
+
+        timer.intermediate_ms = intermediate_ms;
+        timer.final_ms = final_ms;
+        timer.started_us = esp_timer_get_time();
+    }
+
+    unsafe extern "C" fn get_timer(ctx: *mut c_void) -> c_int {
+        let timer = &*(ctx as *const Timer);
+
+        if timer.final_ms == 0 {
+            // Cancelled
+            return -1;
+        }
+
+        let elapsed_ms = ((esp_timer_get_time() - timer.started_us) / 1000) as u32;
+
+        if elapsed_ms >= timer.final_ms {
+            2
+        } else if elapsed_ms >= timer.intermediate_ms {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+impl Drop for EspDtls {
+    fn drop(&mut self) {
+        let ctx = &mut *self.0;
+
+        unsafe {
+            mbedtls_ssl_close_notify(&mut ctx.ssl);
+
+            mbedtls_ssl_free(&mut ctx.ssl);
+            mbedtls_ssl_config_free(&mut ctx.conf);
+            mbedtls_x509_crt_free(&mut ctx.ca_cert);
+            mbedtls_x509_crt_free(&mut ctx.own_cert);
+            mbedtls_pk_free(&mut ctx.own_key);
+            mbedtls_ssl_cookie_free(&mut ctx.cookie);
+        }
+    }
+}
+
+impl Debug for EspDtls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EspDtls")
+            .field("socket", &self.0.socket)
+            .finish_non_exhaustive()
+    }
+}
+
+// A single Mbed TLS context itself is safe to send across threads
+unsafe impl Send for EspDtls {}
+
+impl io::ErrorType for EspDtls {
+    type Error = EspIOError;
+}
+
+impl io::Read for EspDtls {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspIOError> {
+        EspDtls::read(self, buf).map_err(EspIOError)
+    }
+}
+
+impl io::Write for EspDtls {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, EspIOError> {
+        EspDtls::write(self, buf).map_err(EspIOError)
+    }
+
+    fn flush(&mut self) -> Result<(), EspIOError> {
+        Ok(())
+    }
+}
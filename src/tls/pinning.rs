@@ -0,0 +1,123 @@
+//! Certificate pinning
+//!
+//! In addition to the verification of the certificate chain, the server is only trusted if one
+//! of the certificates of the verified chain - the leaf certificate, an intermediate CA or the
+//! root CA - has a public key whose SHA-256 fingerprint (of the DER `SubjectPublicKeyInfo`, as in
+//! HPKP) was pinned with `set_pins`. Certificates sent by the server but not part of the verified
+//! chain are ignored. A compromised or coerced CA thus cannot issue a certificate accepted by the
+//! device.
+//!
+//! Pinning hooks into the certificate verification through the `crt_bundle_attach` callback
+//! of the TLS configurations: `PINNING_ATTACH` for the HTTP, MQTT and WebSocket clients, or
+//! `Config::use_pinning` for `EspTls`. As ESP-TLS ignores the other trust anchors when this
+//! callback is set, the chain is verified against the global CA store, whose certificates are
+//! managed with `ca::EspGlobalCaStore`:
+//!
+//! ```ignore
+//! let mut ca_store = EspGlobalCaStore::take()?;
+//! ca_store.add(X509::pem_until_nul(ROOT_CA))?;
+//!
+//! pinning::set_pins(&[SERVER_SPKI_SHA256, BACKUP_SPKI_SHA256]);
+//!
+//! let client = EspHttpConnection::new(&Configuration {
+//!     crt_bundle_attach: Some(pinning::PINNING_ATTACH),
+//!     ..Default::default()
+//! })?;
+//! ```
+//!
+//! Note that Mbed TLS does not support OCSP stapling, so the revocation status of the server
+//! certificate cannot be required; short-lived certificates and pinning are the alternatives.
+
+use core::ffi::{c_int, c_void};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+use super::X509;
+
+/// The SHA-256 fingerprint of a DER `SubjectPublicKeyInfo`
+pub type Pin = [u8; 32];
+
+static PINS: Mutex<Vec<Pin>> = Mutex::new(Vec::new());
+
+// The tasks whose ongoing chain verification already met a pinned certificate. The verification
+// callback is called for each certificate of the verified chain in turn, down to the leaf
+static MATCHED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// The attach callback enabling pinning, for the `crt_bundle_attach` fields of the HTTP, MQTT and
+/// WebSocket client configurations
+pub const PINNING_ATTACH: unsafe extern "C" fn(*mut c_void) -> esp_err_t = attach;
+
+/// Replace the pinned public keys, which apply to all the connections using pinning
+///
+/// With no pins, all the connections using pinning fail.
+pub fn set_pins(pins: &[Pin]) {
+    let mut current = PINS.lock();
+
+    current.clear();
+    current.extend_from_slice(pins);
+}
+
+/// Return the pinned public keys
+pub fn pins() -> Vec<Pin> {
+    PINS.lock().clone()
+}
+
+/// Compute the pin of the public key of a PEM or DER certificate
+///
+/// Returns `ESP_ERR_INVALID_ARG` if the certificate cannot be parsed.
+pub fn pin_of(cert: X509) -> Result<Pin, EspError> {
+    let cert = crate::crypto::x509::Certificate::parse(cert)?;
+
+    cert.spki_sha256()
+}
+
+unsafe extern "C" fn attach(conf: *mut c_void) -> esp_err_t {
+    let conf = conf as *mut mbedtls_ssl_config;
+
+    let ca_store = esp_tls_get_global_ca_store();
+    if ca_store.is_null() {
+        log::warn!("Certificate pinning requires the global CA store to be initialized");
+        return ESP_ERR_INVALID_STATE;
+    }
+
+    mbedtls_ssl_conf_ca_chain(conf, ca_store, core::ptr::null_mut());
+    mbedtls_ssl_conf_verify(conf, Some(verify), core::ptr::null_mut());
+
+    ESP_OK
+}
+
+unsafe extern "C" fn verify(
+    _ctx: *mut c_void,
+    crt: *mut mbedtls_x509_crt,
+    depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    // Called for each certificate of the verified chain - not of the chain presented by the
+    // server, which may contain unrelated certificates - from the root down to the leaf
+    let task = crate::hal::task::current().unwrap_or(core::ptr::null_mut()) as usize;
+
+    let pinned = crate::crypto::x509::spki_sha256(&(*crt).pk)
+        .map(|pin| PINS.lock().contains(&pin))
+        .unwrap_or(false);
+
+    let mut matched = MATCHED.lock();
+
+    if pinned && !matched.contains(&task) {
+        matched.push(task);
+    }
+
+    if depth == 0 {
+        if let Some(index) = matched.iter().position(|matched| *matched == task) {
+            matched.swap_remove(index);
+        } else {
+            log::warn!("None of the certificates of the verified chain matches a pinned key");
+            *flags |= MBEDTLS_X509_BADCERT_OTHER;
+        }
+    }
+
+    0
+}
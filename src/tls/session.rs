@@ -0,0 +1,217 @@
+//! TLS session resumption and handshake information
+//!
+//! With session tickets (`CONFIG_ESP_TLS_CLIENT_SESSION_TICKETS`), the session of a connection
+//! is saved with `EspTls::client_session` once connected, and passed with
+//! `Config::client_session` to the next connection to the same server, which then skips the
+//! expensive public key operations of a full handshake. The session can be serialized,
+//! e.g. to resume it after a reboot with `ClientSession::save_to_nvs` and
+//! `ClientSession::load_from_nvs`.
+//!
+//! Once connected, the negotiated protocol version and ciphersuite and the certificate of the
+//! peer are returned by `EspTls::protocol_version`, `EspTls::ciphersuite` and
+//! `EspTls::peer_certificate`, and the duration of the handshake by
+//! `CompletedHandshake::duration`.
+
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+use core::fmt::{self, Debug};
+
+#[cfg(all(
+    esp_idf_esp_tls_client_session_tickets,
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled
+))]
+extern crate alloc;
+
+#[cfg(all(
+    esp_idf_esp_tls_client_session_tickets,
+    feature = "alloc",
+    esp_idf_comp_nvs_flash_enabled
+))]
+use crate::nvs::{EspNvs, NvsPartitionId};
+#[cfg(not(esp_idf_version_major = "4"))]
+use crate::private::cstr::from_cstr_ptr;
+use crate::sys::*;
+
+use super::{EspTls, Socket};
+
+/// A saved TLS client session, for resuming it in a later connection
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+pub struct ClientSession(*mut esp_tls_client_session_t);
+
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+impl ClientSession {
+    /// Deserialize a session serialized with `ClientSession::save`
+    ///
+    /// Returns `ESP_ERR_INVALID_ARG` if the data is not a valid session, e.g. serialized by
+    /// a different Mbed TLS version or configuration.
+    pub fn load(data: &[u8]) -> Result<Self, EspError> {
+        let raw = unsafe { calloc(1, core::mem::size_of::<esp_tls_client_session_t>() as _) }
+            as *mut esp_tls_client_session_t;
+
+        if raw.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+        }
+
+        // Freed with `esp_tls_free_client_session` from now on
+        let session = Self(raw);
+
+        let ret = unsafe {
+            mbedtls_ssl_session_init(&mut (*raw).saved_session);
+            mbedtls_ssl_session_load(&mut (*raw).saved_session, data.as_ptr(), data.len())
+        };
+
+        if ret == 0 {
+            Ok(session)
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        }
+    }
+
+    /// Serialize the session into `buf`, returning the serialized data
+    ///
+    /// Returns `ESP_ERR_INVALID_SIZE` if `buf` is too small, in which case `serialized_len`
+    /// returns the required size.
+    pub fn save<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], EspError> {
+        let mut len = 0;
+
+        let ret = unsafe {
+            mbedtls_ssl_session_save(
+                &(*self.0).saved_session,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut len,
+            )
+        };
+
+        if ret == 0 {
+            Ok(&buf[..len])
+        } else if ret == MBEDTLS_ERR_SSL_BUFFER_TOO_SMALL {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())
+        } else {
+            Err(EspError::from_infallible::<ESP_FAIL>())
+        }
+    }
+
+    /// Return the size of the serialized session
+    pub fn serialized_len(&self) -> usize {
+        let mut len = 0;
+
+        unsafe {
+            mbedtls_ssl_session_save(&(*self.0).saved_session, core::ptr::null_mut(), 0, &mut len);
+        }
+
+        len
+    }
+
+    /// Load a session saved with `save_to_nvs`, if any
+    #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+    pub fn load_from_nvs<T>(nvs: &EspNvs<T>, name: &str) -> Result<Option<Self>, EspError>
+    where
+        T: NvsPartitionId,
+    {
+        let Some(len) = nvs.blob_len(name)? else {
+            return Ok(None);
+        };
+
+        let mut buf = alloc::vec![0; len];
+
+        match nvs.get_raw(name, &mut buf)? {
+            Some(data) => Self::load(data).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Save the session as a blob in NVS
+    #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+    pub fn save_to_nvs<T>(&self, nvs: &mut EspNvs<T>, name: &str) -> Result<(), EspError>
+    where
+        T: NvsPartitionId,
+    {
+        let mut buf = alloc::vec![0; self.serialized_len()];
+        let data = self.save(&mut buf)?;
+
+        nvs.set_raw(name, data)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn as_raw(&self) -> *mut esp_tls_client_session_t {
+        self.0
+    }
+}
+
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        unsafe { esp_tls_free_client_session(self.0) };
+    }
+}
+
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+impl Debug for ClientSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientSession").finish_non_exhaustive()
+    }
+}
+
+// The session is only read when passed to a new connection, where it is copied
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+unsafe impl Send for ClientSession {}
+
+#[cfg(esp_idf_esp_tls_client_session_tickets)]
+unsafe impl Sync for ClientSession {}
+
+impl<S> EspTls<S>
+where
+    S: Socket,
+{
+    /// Save the session of the connection, for resuming it with `Config::client_session`
+    ///
+    /// Returns `None` if no handshake was completed, or if the server did not issue a ticket.
+    #[cfg(esp_idf_esp_tls_client_session_tickets)]
+    pub fn client_session(&self) -> Option<ClientSession> {
+        let raw = unsafe { esp_tls_get_client_session(self.context_handle()) };
+
+        (!raw.is_null()).then_some(ClientSession(raw))
+    }
+
+    /// Return the negotiated protocol version, e.g. `TLSv1.2`, or `None` if no handshake
+    /// was completed
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn protocol_version(&self) -> Option<&'static str> {
+        let ssl = self.ssl_context()?;
+
+        Some(unsafe { from_cstr_ptr(mbedtls_ssl_get_version(ssl)) })
+    }
+
+    /// Return the negotiated ciphersuite, e.g. `TLS-ECDHE-RSA-WITH-AES-128-GCM-SHA256`, or `None`
+    /// if no handshake was completed
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn ciphersuite(&self) -> Option<&'static str> {
+        let ssl = self.ssl_context()?;
+
+        Some(unsafe { from_cstr_ptr(mbedtls_ssl_get_ciphersuite(ssl)) })
+    }
+
+    /// Return the DER certificate presented by the peer, or `None` if no handshake was completed
+    /// or the peer presented no certificate
+    #[cfg(all(
+        not(esp_idf_version_major = "4"),
+        esp_idf_mbedtls_ssl_keep_peer_certificate
+    ))]
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        let ssl = self.ssl_context()?;
+        let crt = unsafe { mbedtls_ssl_get_peer_cert(ssl).as_ref() }?;
+
+        Some(unsafe { core::slice::from_raw_parts(crt.raw.p, crt.raw.len) })
+    }
+
+    #[cfg(not(esp_idf_version_major = "4"))]
+    fn ssl_context(&self) -> Option<*const mbedtls_ssl_context> {
+        let ssl =
+            unsafe { esp_tls_get_ssl_context(self.context_handle()) } as *const mbedtls_ssl_context;
+
+        // The ciphersuite is only known once the handshake is over
+        (!ssl.is_null() && !unsafe { mbedtls_ssl_get_ciphersuite(ssl) }.is_null()).then_some(ssl)
+    }
+}
@@ -0,0 +1,173 @@
+//! ULP coprocessor
+//!
+//! The ULP coprocessor - the FSM one, or the RISC-V one of the ESP32-S2 and ESP32-S3 - runs
+//! a small program from RTC slow memory while the main cores are in deep sleep, e.g. to
+//! sample a sensor and wake the chip up only when a reading is out of range.
+//!
+//! The program is built separately - with the ULP toolchain of the ESP-IDF - and embedded
+//! with `include_bytes!`. Its variables live in RTC slow memory, at the offsets given by the
+//! symbol map of the program, and are accessed with `UlpVariable`:
+//!
+//! ```ignore
+//! use esp_idf_svc::ulp::EspUlp;
+//!
+//! static PROGRAM: &[u8] = include_bytes!("../ulp/build/ulp_main.bin");
+//!
+//! let mut ulp = EspUlp::take()?;
+//! ulp.load(PROGRAM)?;
+//!
+//! // The offsets of the variables, from `ulp_main.sym`
+//! let threshold = unsafe { ulp.variable::<u32>(0x100) };
+//! let reading = unsafe { ulp.variable::<u32>(0x104) };
+//!
+//! threshold.write(1000);
+//!
+//! ulp.set_wakeup_period(Duration::from_millis(100))?;
+//! ulp.enable_wakeup()?;
+//! ulp.start()?;
+//!
+//! esp_idf_svc::sleep::Sleep::new().ulp()?.deep_sleep();
+//! ```
+
+use core::time::Duration;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+// The base address of the RTC slow memory, where the programs are loaded
+const RTC_SLOW_MEM: usize = 0x5000_0000;
+
+// The size of the RTC slow memory reserved for the programs
+const RESERVE_MEM: usize = CONFIG_ULP_COPROC_RESERVE_MEM as usize;
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+/// The ULP coprocessor
+pub struct EspUlp {
+    #[cfg(esp_idf_ulp_coproc_type_fsm)]
+    entry: u32,
+}
+
+impl EspUlp {
+    pub fn take() -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        *taken = true;
+
+        Ok(Self {
+            #[cfg(esp_idf_ulp_coproc_type_fsm)]
+            entry: 0,
+        })
+    }
+
+    /// Load a program, as output by the ULP toolchain, into RTC slow memory
+    ///
+    /// The coprocessor must be stopped.
+    pub fn load(&mut self, program: &[u8]) -> Result<(), EspError> {
+        #[cfg(esp_idf_ulp_coproc_type_fsm)]
+        esp!(unsafe { ulp_load_binary(0, program.as_ptr(), program.len() / 4) })?;
+
+        #[cfg(esp_idf_ulp_coproc_type_riscv)]
+        esp!(unsafe { ulp_riscv_load_binary(program.as_ptr(), program.len()) })?;
+
+        Ok(())
+    }
+
+    /// Set the entry point of an FSM program, as a word offset from the start of RTC slow
+    /// memory: e.g. the offset of the `entry` symbol divided by 4
+    #[cfg(esp_idf_ulp_coproc_type_fsm)]
+    pub fn set_entry(&mut self, entry_word: u32) {
+        self.entry = entry_word;
+    }
+
+    /// Set the period at which the ULP timer starts the program again once halted
+    pub fn set_wakeup_period(&mut self, period: Duration) -> Result<(), EspError> {
+        esp!(unsafe { ulp_set_wakeup_period(0, period.as_micros() as _) })
+    }
+
+    /// Start the program
+    pub fn start(&mut self) -> Result<(), EspError> {
+        #[cfg(esp_idf_ulp_coproc_type_fsm)]
+        esp!(unsafe { ulp_run(self.entry) })?;
+
+        #[cfg(esp_idf_ulp_coproc_type_riscv)]
+        esp!(unsafe { ulp_riscv_run() })?;
+
+        Ok(())
+    }
+
+    /// Stop the ULP timer, so that the program is not started again once halted
+    #[cfg(not(all(esp_idf_ulp_coproc_type_fsm, esp_idf_version = "5.0")))]
+    pub fn stop(&mut self) {
+        #[cfg(esp_idf_ulp_coproc_type_fsm)]
+        unsafe {
+            ulp_timer_stop();
+        }
+
+        #[cfg(esp_idf_ulp_coproc_type_riscv)]
+        unsafe {
+            ulp_riscv_timer_stop();
+            ulp_riscv_halt();
+        }
+    }
+
+    /// Let the program wake the chip up from sleep, e.g. with the `wake` instruction
+    pub fn enable_wakeup(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_sleep_enable_ulp_wakeup() })
+    }
+
+    /// Get a variable of the program, at `offset` bytes from the start of RTC slow memory
+    ///
+    /// Panics if the variable is not in the memory reserved for the programs, or is not
+    /// aligned.
+    ///
+    /// # Safety
+    ///
+    /// A variable of type `T` must be at the offset in the program.
+    pub unsafe fn variable<T>(&self, offset: usize) -> UlpVariable<T>
+    where
+        T: Copy,
+    {
+        assert!(offset + core::mem::size_of::<T>() <= RESERVE_MEM);
+        assert!(offset % core::mem::align_of::<T>() == 0);
+
+        UlpVariable((RTC_SLOW_MEM + offset) as *mut T)
+    }
+}
+
+impl Drop for EspUlp {
+    fn drop(&mut self) {
+        *TAKEN.lock() = false;
+    }
+}
+
+/// A variable shared with the ULP program, in RTC slow memory
+///
+/// The variables of FSM programs are 32-bit words, of which the program only reads and
+/// writes the lower 16 bits; the upper 16 bits of a word written by the program hold the PC
+/// of the store instruction.
+pub struct UlpVariable<T>(*mut T);
+
+impl<T> UlpVariable<T>
+where
+    T: Copy,
+{
+    pub fn read(&self) -> T {
+        unsafe { self.0.read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { self.0.write_volatile(value) }
+    }
+}
+
+impl UlpVariable<u32> {
+    /// The lower 16 bits of the word, as written by an FSM program
+    pub fn read_fsm(&self) -> u16 {
+        (self.read() & 0xffff) as _
+    }
+}
@@ -0,0 +1,205 @@
+//! Task watchdog (TWDT)
+//!
+//! The task watchdog triggers - with a panic, or an error log - when one of its subscribers
+//! is not fed within the timeout. `Twdt` configures it and subscribes the current task, or a
+//! "user" - any piece of code, not bound to a task - with RAII subscriptions:
+//!
+//! ```ignore
+//! use esp_idf_svc::watchdog::{Twdt, TwdtConfiguration};
+//!
+//! let twdt = Twdt::take(&TwdtConfiguration {
+//!     timeout: Duration::from_secs(10),
+//!     ..Default::default()
+//! })?;
+//!
+//! let mut subscription = twdt.subscribe()?;
+//!
+//! loop {
+//!     subscription.feed()?;
+//!     // ...
+//! }
+//! ```
+
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ptr;
+use core::time::Duration;
+
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+static TAKEN: Mutex<bool> = Mutex::new(false);
+
+/// The task watchdog configuration
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TwdtConfiguration {
+    /// The time after which a subscriber not fed triggers the watchdog
+    pub timeout: Duration,
+    /// Whether to panic - and so reset - when triggered, rather than only logging an error
+    pub panic_on_trigger: bool,
+    /// The bit mask of the cores whose idle task is monitored, so that a task starving the
+    /// idle task of its core triggers the watchdog
+    pub idle_core_mask: u32,
+}
+
+impl TwdtConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            panic_on_trigger: true,
+            idle_core_mask: 0,
+        }
+    }
+}
+
+impl Default for TwdtConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&TwdtConfiguration> for esp_task_wdt_config_t {
+    fn from(conf: &TwdtConfiguration) -> Self {
+        #[allow(clippy::needless_update)]
+        Self {
+            timeout_ms: conf.timeout.as_millis() as _,
+            idle_core_mask: conf.idle_core_mask,
+            trigger_panic: conf.panic_on_trigger,
+            ..Default::default()
+        }
+    }
+}
+
+/// The task watchdog
+///
+/// If the task watchdog was initialized at boot (`CONFIG_ESP_TASK_WDT_INIT`), it is
+/// reconfigured, and left running when dropped; otherwise it is deinitialized when dropped.
+pub struct Twdt {
+    initialized: bool,
+}
+
+impl Twdt {
+    pub fn take(conf: &TwdtConfiguration) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let config = conf.into();
+
+        let initialized = match esp!(unsafe { esp_task_wdt_init(&config) }) {
+            Ok(()) => true,
+            Err(err) if err.code() == ESP_ERR_INVALID_STATE => {
+                esp!(unsafe { esp_task_wdt_reconfigure(&config) })?;
+
+                false
+            }
+            Err(err) => return Err(err),
+        };
+
+        *taken = true;
+
+        Ok(Self { initialized })
+    }
+
+    /// Change the timeout, the panic behavior, or the idle tasks monitored
+    pub fn reconfigure(&mut self, conf: &TwdtConfiguration) -> Result<(), EspError> {
+        esp!(unsafe { esp_task_wdt_reconfigure(&conf.into()) })
+    }
+
+    /// Subscribe the current task, until the returned subscription is dropped
+    ///
+    /// The subscription must be fed from the task.
+    pub fn subscribe(&self) -> Result<TwdtSubscription<'_>, EspError> {
+        esp!(unsafe { esp_task_wdt_add(ptr::null_mut()) })?;
+
+        Ok(TwdtSubscription {
+            task: unsafe { xTaskGetCurrentTaskHandle() },
+            _twdt: PhantomData,
+        })
+    }
+
+    /// Subscribe a user, until the returned subscription is dropped
+    ///
+    /// Unlike a task subscription, the subscription can be moved and fed from any task.
+    ///
+    /// # Arguments
+    /// - `name`: The name of the user, reported when the watchdog triggers
+    pub fn subscribe_user(
+        &self,
+        name: &'static CStr,
+    ) -> Result<TwdtUserSubscription<'_>, EspError> {
+        let mut handle = ptr::null_mut();
+
+        esp!(unsafe { esp_task_wdt_add_user(name.as_ptr(), &mut handle) })?;
+
+        Ok(TwdtUserSubscription {
+            handle,
+            _twdt: PhantomData,
+        })
+    }
+
+    /// Whether the current task is subscribed
+    pub fn is_subscribed(&self) -> bool {
+        unsafe { esp_task_wdt_status(ptr::null_mut()) == ESP_OK }
+    }
+}
+
+impl Drop for Twdt {
+    fn drop(&mut self) {
+        let mut taken = TAKEN.lock();
+
+        if self.initialized {
+            unsafe {
+                esp_task_wdt_deinit();
+            }
+        }
+
+        *taken = false;
+    }
+}
+
+/// A subscription of a task to the task watchdog, unsubscribed when dropped
+pub struct TwdtSubscription<'a> {
+    task: TaskHandle_t,
+    _twdt: PhantomData<&'a Twdt>,
+}
+
+impl TwdtSubscription<'_> {
+    /// Feed the watchdog, restarting the timeout of the task
+    pub fn feed(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_task_wdt_reset() })
+    }
+}
+
+impl Drop for TwdtSubscription<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            esp_task_wdt_delete(self.task);
+        }
+    }
+}
+
+/// A subscription of a user to the task watchdog, unsubscribed when dropped
+pub struct TwdtUserSubscription<'a> {
+    handle: esp_task_wdt_user_handle_t,
+    _twdt: PhantomData<&'a Twdt>,
+}
+
+impl TwdtUserSubscription<'_> {
+    /// Feed the watchdog, restarting the timeout of the user
+    pub fn feed(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_task_wdt_reset_user(self.handle) })
+    }
+}
+
+impl Drop for TwdtUserSubscription<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            esp_task_wdt_delete_user(self.handle);
+        }
+    }
+}
+
+unsafe impl Send for TwdtUserSubscription<'_> {}
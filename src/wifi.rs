@@ -38,6 +38,18 @@ pub use embedded_svc::wifi::{
     Configuration, PmfConfiguration, Protocol, ScanMethod, ScanSortMethod, SecondaryChannel,
 };
 
+#[cfg(all(
+    esp_idf_comp_wifi_provisioning_enabled,
+    esp_idf_comp_esp_netif_enabled,
+    feature = "alloc"
+))]
+pub mod provisioning;
+
+#[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
+pub mod profiles;
+
+pub mod scan;
+
 pub mod config {
     use core::time::Duration;
 
@@ -312,7 +324,32 @@ impl TryFrom<Newtype<&wifi_ap_record_t>> for AccessPointInfo {
                 _ => panic!(),
             },
             signal_strength: a.rssi,
-            protocols: EnumSet::<Protocol>::empty(), // TODO
+            protocols: {
+                let mut protocols = EnumSet::<Protocol>::empty();
+
+                if a.phy_11b() != 0 {
+                    protocols |= Protocol::P802D11B;
+                }
+
+                if a.phy_11g() != 0 {
+                    protocols |= Protocol::P802D11BG;
+                }
+
+                if a.phy_11n() != 0 {
+                    protocols |= Protocol::P802D11BGN;
+                }
+
+                #[cfg(not(esp_idf_version_major = "4"))]
+                if a.phy_11ax() != 0 {
+                    protocols |= Protocol::P802D11BGNAX;
+                }
+
+                if a.phy_lr() != 0 {
+                    protocols |= Protocol::P802D11LR;
+                }
+
+                protocols
+            },
             auth_method: Option::<AuthMethod>::from(Newtype::<wifi_auth_mode_t>(a.authmode)),
         })
     }
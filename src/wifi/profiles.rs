@@ -0,0 +1,341 @@
+//! Known Wifi networks, with priority-based auto-join
+//!
+//! `WifiProfiles` stores multiple known networks in NVS - like the saved networks of a phone -
+//! each with a priority, and picks the best one in range after a scan:
+//!
+//! ```ignore
+//! let mut profiles = WifiProfiles::new(nvs_partition, "wifi_profiles")?;
+//!
+//! profiles.add(WifiProfile::new("home", "home password", 10)?)?;
+//! profiles.add(WifiProfile::new("office", "office password", 5)?)?;
+//!
+//! if let Some(ssid) = profiles.connect_best(&mut wifi)? {
+//!     info!("Joined {ssid}");
+//! }
+//! ```
+//!
+//! The networks in range are scored by their RSSI, plus `priority_weight_db` per priority level,
+//! so that a preferred network wins over a slightly stronger one, but not over a much stronger
+//! one; those weaker than `min_rssi` are never joined.
+
+use core::fmt::{self, Debug};
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use ::log::info;
+
+use embedded_svc::wifi::{AccessPointInfo, AuthMethod, ClientConfiguration, Configuration, Wifi};
+
+use crate::nvs::{EspNvs, EspNvsPartition, NvsPartitionId};
+use crate::sys::*;
+use crate::wifi::{BlockingWifi, NonBlocking};
+
+// The NVS key of the profiles
+const KEY: &str = "profiles";
+
+// The version of the stored format
+const FORMAT_VERSION: u8 = 1;
+
+/// A known network
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct WifiProfile {
+    pub ssid: heapless::String<32>,
+    /// The password; empty for an open network
+    pub password: heapless::String<64>,
+    /// The BSSID to join, if locked to an access point
+    pub bssid: Option<[u8; 6]>,
+    /// The priority; the higher, the more preferred
+    pub priority: u8,
+}
+
+impl WifiProfile {
+    pub fn new(ssid: &str, password: &str, priority: u8) -> Result<Self, EspError> {
+        Ok(Self {
+            ssid: ssid.try_into().map_err(|_| invalid_size())?,
+            password: password.try_into().map_err(|_| invalid_size())?,
+            bssid: None,
+            priority,
+        })
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.ssid.len() as _);
+        out.extend_from_slice(self.ssid.as_bytes());
+        out.push(self.password.len() as _);
+        out.extend_from_slice(self.password.as_bytes());
+
+        match self.bssid {
+            Some(bssid) => {
+                out.push(1);
+                out.extend_from_slice(&bssid);
+            }
+            None => out.push(0),
+        }
+
+        out.push(self.priority);
+    }
+
+    fn decode(data: &mut &[u8]) -> Result<Self, EspError> {
+        fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], EspError> {
+            if data.len() < len {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>());
+            }
+
+            let (head, tail) = data.split_at(len);
+            *data = tail;
+
+            Ok(head)
+        }
+
+        fn string<const N: usize>(data: &mut &[u8]) -> Result<heapless::String<N>, EspError> {
+            let len = take(data, 1)?[0] as usize;
+
+            core::str::from_utf8(take(data, len)?)
+                .ok()
+                .and_then(|str| str.try_into().ok())
+                .ok_or(EspError::from_infallible::<ESP_ERR_INVALID_CRC>())
+        }
+
+        let ssid = string(data)?;
+        let password = string(data)?;
+
+        let bssid = match take(data, 1)?[0] {
+            0 => None,
+            _ => Some(take(data, 6)?.try_into().unwrap()),
+        };
+
+        let priority = take(data, 1)?[0];
+
+        Ok(Self {
+            ssid,
+            password,
+            bssid,
+            priority,
+        })
+    }
+}
+
+impl Debug for WifiProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiProfile")
+            .field("ssid", &self.ssid)
+            .field("bssid", &self.bssid)
+            .field("priority", &self.priority)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The known networks, stored in NVS
+pub struct WifiProfiles<T: NvsPartitionId> {
+    nvs: EspNvs<T>,
+    // Sorted by descending priority
+    profiles: Vec<WifiProfile>,
+    /// The score of each priority level, in dB
+    pub priority_weight_db: i16,
+    /// The minimum RSSI of a network to join, in dBm
+    pub min_rssi: i8,
+}
+
+impl<T: NvsPartitionId> WifiProfiles<T> {
+    /// Load the known networks stored in the namespace
+    pub fn new(partition: EspNvsPartition<T>, namespace: &str) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, namespace, true)?;
+
+        let mut profiles = Vec::new();
+
+        if let Some(len) = nvs.blob_len(KEY)? {
+            let mut buf = alloc::vec![0; len];
+
+            if let Some(stored) = nvs.get_blob(KEY, &mut buf)? {
+                match stored.split_first() {
+                    Some((&FORMAT_VERSION, mut data)) => {
+                        while !data.is_empty() {
+                            profiles.push(WifiProfile::decode(&mut data)?);
+                        }
+                    }
+                    _ => ::log::warn!("Ignoring the profiles stored in an unknown format"),
+                }
+            }
+        }
+
+        let mut this = Self {
+            nvs,
+            profiles,
+            priority_weight_db: 10,
+            min_rssi: -85,
+        };
+
+        this.sort();
+
+        Ok(this)
+    }
+
+    /// The known networks, by descending priority
+    pub fn profiles(&self) -> &[WifiProfile] {
+        &self.profiles
+    }
+
+    pub fn get(&self, ssid: &str) -> Option<&WifiProfile> {
+        self.profiles.iter().find(|profile| profile.ssid == ssid)
+    }
+
+    /// Add a known network, replacing the one with the same SSID if any
+    pub fn add(&mut self, profile: WifiProfile) -> Result<(), EspError> {
+        self.profiles.retain(|other| other.ssid != profile.ssid);
+        self.profiles.push(profile);
+
+        self.save()
+    }
+
+    /// Remove a known network; return whether it was known
+    pub fn remove(&mut self, ssid: &str) -> Result<bool, EspError> {
+        let len = self.profiles.len();
+
+        self.profiles.retain(|profile| profile.ssid != ssid);
+
+        if self.profiles.len() == len {
+            return Ok(false);
+        }
+
+        self.save()?;
+
+        Ok(true)
+    }
+
+    /// Set the priority of a known network
+    pub fn set_priority(&mut self, ssid: &str, priority: u8) -> Result<(), EspError> {
+        self.profiles
+            .iter_mut()
+            .find(|profile| profile.ssid == ssid)
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?
+            .priority = priority;
+
+        self.save()
+    }
+
+    /// Reorder the known networks, from the most preferred to the least preferred, by assigning
+    /// them descending priorities; the networks not listed get the lowest priority
+    pub fn reorder(&mut self, ssids: &[&str]) -> Result<(), EspError> {
+        for profile in &mut self.profiles {
+            profile.priority = match ssids.iter().position(|ssid| profile.ssid == *ssid) {
+                Some(index) => u8::try_from(index).map_or(0, |index| u8::MAX - index),
+                None => 0,
+            };
+        }
+
+        self.save()
+    }
+
+    /// Forget all the known networks
+    pub fn clear(&mut self) -> Result<(), EspError> {
+        self.profiles.clear();
+        self.nvs.remove(KEY)?;
+
+        Ok(())
+    }
+
+    /// Pick the best known network among the access points scanned, with the access point to join
+    pub fn select<'a, 'b>(
+        &'a self,
+        access_points: &'b [AccessPointInfo],
+    ) -> Option<(&'a WifiProfile, &'b AccessPointInfo)> {
+        access_points
+            .iter()
+            .filter(|ap| ap.signal_strength >= self.min_rssi)
+            .filter_map(|ap| {
+                self.profiles
+                    .iter()
+                    .find(|profile| {
+                        profile.ssid == ap.ssid
+                            && profile.bssid.map_or(true, |bssid| bssid == ap.bssid)
+                    })
+                    .map(|profile| (profile, ap))
+            })
+            .max_by_key(|(profile, ap)| {
+                ap.signal_strength as i32 + profile.priority as i32 * self.priority_weight_db as i32
+            })
+    }
+
+    /// Scan, and connect to the best known network in range; return its SSID, or `None` if no
+    /// known network is in range
+    ///
+    /// The Wifi driver must be started, in station or mixed mode.
+    pub fn connect_best<W>(
+        &self,
+        wifi: &mut BlockingWifi<W>,
+    ) -> Result<Option<heapless::String<32>>, EspError>
+    where
+        W: Wifi<Error = EspError> + NonBlocking,
+    {
+        if self.profiles.is_empty() {
+            return Ok(None);
+        }
+
+        let access_points = wifi.scan()?;
+
+        let Some((profile, ap)) = self.select(&access_points) else {
+            info!("No known network in range");
+            return Ok(None);
+        };
+
+        info!(
+            "Joining {} (RSSI {}, priority {})",
+            profile.ssid, ap.signal_strength, profile.priority
+        );
+
+        let client = ClientConfiguration {
+            ssid: profile.ssid.clone(),
+            bssid: Some(ap.bssid),
+            auth_method: ap.auth_method.unwrap_or(if profile.password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            }),
+            password: profile.password.clone(),
+            channel: Some(ap.channel),
+            ..Default::default()
+        };
+
+        let conf = match wifi.get_configuration()? {
+            Configuration::Mixed(_, ap_conf) => Configuration::Mixed(client, ap_conf),
+            _ => Configuration::Client(client),
+        };
+
+        wifi.set_configuration(&conf)?;
+        wifi.connect()?;
+
+        Ok(Some(profile.ssid.clone()))
+    }
+
+    fn sort(&mut self) {
+        // Stable, so that equal priorities keep the order in which they were added
+        self.profiles.sort_by(|a, b| b.priority.cmp(&a.priority));
+    }
+
+    fn save(&mut self) -> Result<(), EspError> {
+        self.sort();
+
+        let mut data = alloc::vec![FORMAT_VERSION];
+
+        for profile in &self.profiles {
+            profile.encode(&mut data);
+        }
+
+        self.nvs.set_blob(KEY, &data)
+    }
+}
+
+impl<T: NvsPartitionId> Debug for WifiProfiles<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WifiProfiles")
+            .field("profiles", &self.profiles)
+            .field("priority_weight_db", &self.priority_weight_db)
+            .field("min_rssi", &self.min_rssi)
+            .finish()
+    }
+}
+
+fn invalid_size() -> EspError {
+    EspError::from_infallible::<ESP_ERR_INVALID_SIZE>()
+}
@@ -0,0 +1,493 @@
+//! Wifi provisioning, as per the ESP-IDF unified provisioning manager
+//!
+//! `EspWifiProvisioning` wraps the `wifi_provisioning` component, which receives the Wifi
+//! credentials of the station from a phone app (i.e. the "ESP BLE Prov" / "ESP SoftAP Prov"
+//! apps or any other app using the `esp-idf-provisioning` libraries), over BLE or a SoftAP.
+//!
+//! The provisioning sessions can be secured with a proof-of-possession (`Security1`) or
+//! with SRP6a (`Security2`, ESP-IDF 5.0+), and custom endpoints can be registered to exchange
+//! application-specific data with the phone app during the provisioning.
+//!
+//! The progress of the provisioning is reported with `WifiProvEvent` on the system event loop:
+//!
+//! ```ignore
+//! let mut prov = EspWifiProvisioning::new(&mut wifi, ProvisioningScheme::Ble(BtMemoryRelease::Bt))?;
+//!
+//! if !prov.is_provisioned()? {
+//!     let _subscription = sysloop.subscribe::<WifiProvEvent, _>(|event| info!("{event:?}"))?;
+//!
+//!     prov.start(&ProvisioningConfiguration {
+//!         service_name: "PROV_123456",
+//!         security: ProvisioningSecurity::Security1 { pop: Some("abcd1234") },
+//!         ..Default::default()
+//!     })?;
+//!
+//!     prov.wait();
+//! }
+//! ```
+//!
+//! Note that with the BLE scheme, the provisioning manager initializes the Bluetooth controller
+//! and host by itself, so a `BtDriver` must not be in use at the same time.
+
+use core::ffi;
+use core::fmt::{self, Debug};
+use core::marker::PhantomData;
+use core::ptr;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::eventloop::{EspEventDeserializer, EspEventSource};
+use crate::private::cstr::*;
+use crate::sys::*;
+
+use super::EspWifi;
+
+pub mod softap;
+
+/// How the BT memory is released once the BLE provisioning is over
+#[cfg(esp_idf_bt_enabled)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BtMemoryRelease {
+    /// Keep the BT memory, so that BT can be used by the application afterwards
+    None,
+    /// Release the memory of both BT Classic and BLE
+    Btdm,
+    /// Release the memory of BT Classic only
+    Bt,
+    /// Release the memory of BLE only
+    Ble,
+}
+
+/// The transport over which the phone app sends the credentials
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProvisioningScheme {
+    /// A GATT service, advertised with the service name
+    #[cfg(esp_idf_bt_enabled)]
+    Ble(BtMemoryRelease),
+    /// An HTTP server on a SoftAP named after the service name
+    SoftAp,
+}
+
+impl ProvisioningScheme {
+    fn raw(&self) -> wifi_prov_scheme_t {
+        match self {
+            #[cfg(esp_idf_bt_enabled)]
+            Self::Ble(_) => unsafe { wifi_prov_scheme_ble },
+            Self::SoftAp => unsafe { wifi_prov_scheme_softap },
+        }
+    }
+
+    fn raw_event_handler(&self) -> wifi_prov_event_handler_t {
+        let event_cb = match self {
+            #[cfg(esp_idf_bt_enabled)]
+            Self::Ble(BtMemoryRelease::Btdm) => Some(wifi_prov_scheme_ble_event_cb_free_btdm as _),
+            #[cfg(esp_idf_bt_enabled)]
+            Self::Ble(BtMemoryRelease::Bt) => Some(wifi_prov_scheme_ble_event_cb_free_bt as _),
+            #[cfg(esp_idf_bt_enabled)]
+            Self::Ble(BtMemoryRelease::Ble) => Some(wifi_prov_scheme_ble_event_cb_free_ble as _),
+            _ => None,
+        };
+
+        wifi_prov_event_handler_t {
+            event_cb,
+            user_data: ptr::null_mut(),
+        }
+    }
+}
+
+/// The security of the provisioning sessions
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProvisioningSecurity<'a> {
+    /// No encryption and no authentication
+    None,
+    /// Curve25519 key exchange and AES-CTR encryption, with an optional proof-of-possession
+    Security1 { pop: Option<&'a str> },
+    /// SRP6a key exchange and AES-GCM encryption; the salt and the verifier are derived
+    /// from the username and the password entered in the phone app
+    #[cfg(not(esp_idf_version_major = "4"))]
+    Security2 { salt: &'a [u8], verifier: &'a [u8] },
+}
+
+impl ProvisioningSecurity<'_> {
+    fn raw(&self) -> wifi_prov_security_t {
+        match self {
+            Self::None => wifi_prov_security_WIFI_PROV_SECURITY_0,
+            Self::Security1 { .. } => wifi_prov_security_WIFI_PROV_SECURITY_1,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            Self::Security2 { .. } => wifi_prov_security_WIFI_PROV_SECURITY_2,
+        }
+    }
+}
+
+/// The configuration of a provisioning session
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ProvisioningConfiguration<'a> {
+    /// The BLE device name or the SSID of the SoftAP
+    pub service_name: &'a str,
+    /// The password of the SoftAP; ignored by the BLE scheme
+    pub service_key: Option<&'a str>,
+    /// The 128-bit UUID of the provisioning GATT service, in little endian byte order;
+    /// ignored by the SoftAP scheme
+    pub service_uuid: Option<[u8; 16]>,
+    pub security: ProvisioningSecurity<'a>,
+    /// The names of the custom endpoints, which should be served with
+    /// `EspWifiProvisioning::register_endpoint` once the provisioning is started
+    pub endpoints: &'a [&'a str],
+}
+
+impl Default for ProvisioningConfiguration<'_> {
+    fn default() -> Self {
+        Self {
+            service_name: "PROV_ESP",
+            service_key: None,
+            service_uuid: None,
+            security: ProvisioningSecurity::Security1 { pop: None },
+            endpoints: &[],
+        }
+    }
+}
+
+type EndpointHandler = Box<dyn FnMut(u32, &[u8]) -> Result<Vec<u8>, EspError> + Send + 'static>;
+
+/// A session of the unified provisioning manager
+///
+/// Dropping it de-initializes the provisioning manager, thus stopping a provisioning in progress.
+pub struct EspWifiProvisioning<'a> {
+    scheme: ProvisioningScheme,
+    // The provisioning manager and protocomm keep pointers to these until the de-initialization
+    service_uuid: Box<[u8; 16]>,
+    pop: Option<CString>,
+    #[cfg(not(esp_idf_version_major = "4"))]
+    security2: Option<(Vec<u8>, Vec<u8>, Box<wifi_prov_security2_params_t>)>,
+    endpoints: Vec<(CString, Option<Box<EndpointHandler>>)>,
+    _wifi: PhantomData<&'a mut ()>,
+}
+
+impl<'a> EspWifiProvisioning<'a> {
+    /// Initialize the provisioning manager
+    ///
+    /// The Wifi driver must use the system event loop and must not be running in Wifi station mode
+    /// with a configuration different from the one the provisioning manager sets.
+    pub fn new(_wifi: &'a mut EspWifi<'_>, scheme: ProvisioningScheme) -> Result<Self, EspError> {
+        let config = wifi_prov_mgr_config_t {
+            scheme: scheme.raw(),
+            scheme_event_handler: scheme.raw_event_handler(),
+            app_event_handler: wifi_prov_event_handler_t {
+                event_cb: None,
+                user_data: ptr::null_mut(),
+            },
+        };
+
+        esp!(unsafe { wifi_prov_mgr_init(config) })?;
+
+        debug!("Provisioning manager initialized with scheme {:?}", scheme);
+
+        Ok(Self {
+            scheme,
+            service_uuid: Box::new([0; 16]),
+            pop: None,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            security2: None,
+            endpoints: Vec::new(),
+            _wifi: PhantomData,
+        })
+    }
+
+    pub fn scheme(&self) -> ProvisioningScheme {
+        self.scheme
+    }
+
+    /// Return `true` if Wifi station credentials are already stored in NVS
+    pub fn is_provisioned(&self) -> Result<bool, EspError> {
+        let mut provisioned = false;
+
+        esp!(unsafe { wifi_prov_mgr_is_provisioned(&mut provisioned) })?;
+
+        Ok(provisioned)
+    }
+
+    /// Start the provisioning service
+    ///
+    /// Once the credentials received from the phone app are applied successfully, the provisioning
+    /// service stops by itself (unless `disable_auto_stop` is called) and the Wifi driver stays
+    /// connected in station mode.
+    pub fn start(&mut self, conf: &ProvisioningConfiguration) -> Result<(), EspError> {
+        #[cfg(esp_idf_bt_enabled)]
+        if let (ProvisioningScheme::Ble(_), Some(uuid)) = (self.scheme, conf.service_uuid) {
+            *self.service_uuid = uuid;
+
+            esp!(unsafe { wifi_prov_scheme_ble_set_service_uuid(self.service_uuid.as_mut_ptr()) })?;
+        }
+
+        for endpoint in conf.endpoints {
+            let name = to_cstring_arg(endpoint)?;
+
+            esp!(unsafe { wifi_prov_mgr_endpoint_create(name.as_ptr()) })?;
+
+            self.endpoints.push((name, None));
+        }
+
+        let service_name = to_cstring_arg(conf.service_name)?;
+        let service_key = conf.service_key.map(to_cstring_arg).transpose()?;
+
+        let params = self.security_params(&conf.security)?;
+
+        esp!(unsafe {
+            wifi_prov_mgr_start_provisioning(
+                conf.security.raw(),
+                params as _,
+                service_name.as_ptr(),
+                service_key
+                    .as_ref()
+                    .map(|key| key.as_ptr())
+                    .unwrap_or(ptr::null()),
+            )
+        })?;
+
+        info!("Provisioning started as {}", conf.service_name);
+
+        Ok(())
+    }
+
+    /// Serve a custom endpoint declared in the `ProvisioningConfiguration::endpoints` of a started
+    /// provisioning
+    ///
+    /// The handler receives the protocomm session ID and the (decrypted) request payload, and
+    /// returns the response payload.
+    pub fn register_endpoint<F>(&mut self, name: &str, handler: F) -> Result<(), EspError>
+    where
+        F: FnMut(u32, &[u8]) -> Result<Vec<u8>, EspError> + Send + 'static,
+    {
+        let endpoint = self
+            .endpoints
+            .iter_mut()
+            .find(|(endpoint, _)| endpoint.to_bytes() == name.as_bytes())
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let handler: Box<EndpointHandler> = Box::new(Box::new(handler));
+        let handler_ptr = &*handler as *const EndpointHandler as *mut ffi::c_void;
+
+        esp!(unsafe {
+            wifi_prov_mgr_endpoint_register(
+                endpoint.0.as_ptr(),
+                Some(Self::handle_endpoint),
+                handler_ptr,
+            )
+        })?;
+
+        endpoint.1 = Some(handler);
+
+        Ok(())
+    }
+
+    /// Stop serving a custom endpoint
+    pub fn unregister_endpoint(&mut self, name: &str) -> Result<(), EspError> {
+        let endpoint = self
+            .endpoints
+            .iter_mut()
+            .find(|(endpoint, _)| endpoint.to_bytes() == name.as_bytes())
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        unsafe { wifi_prov_mgr_endpoint_unregister(endpoint.0.as_ptr()) };
+
+        endpoint.1 = None;
+
+        Ok(())
+    }
+
+    /// Keep the provisioning service running after a successful provisioning, so that the phone app
+    /// can keep on using the custom endpoints; the service is then stopped with `stop`
+    pub fn disable_auto_stop(&mut self, cleanup_delay_ms: u32) -> Result<(), EspError> {
+        esp!(unsafe { wifi_prov_mgr_disable_auto_stop(cleanup_delay_ms) })
+    }
+
+    /// Stop the provisioning service
+    pub fn stop(&mut self) {
+        unsafe { wifi_prov_mgr_stop_provisioning() };
+    }
+
+    /// Block until the provisioning service is stopped
+    ///
+    /// For an asynchronous alternative, wait for `WifiProvEvent::End` on the system event loop.
+    pub fn wait(&self) {
+        unsafe { wifi_prov_mgr_wait() };
+    }
+
+    /// Erase the Wifi station credentials stored in NVS
+    pub fn reset_provisioning(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { wifi_prov_mgr_reset_provisioning() })
+    }
+
+    fn security_params(
+        &mut self,
+        security: &ProvisioningSecurity,
+    ) -> Result<*const ffi::c_void, EspError> {
+        match security {
+            ProvisioningSecurity::None => Ok(ptr::null()),
+            ProvisioningSecurity::Security1 { pop } => {
+                self.pop = pop.map(to_cstring_arg).transpose()?;
+
+                Ok(self
+                    .pop
+                    .as_ref()
+                    .map(|pop| pop.as_ptr() as *const ffi::c_void)
+                    .unwrap_or(ptr::null()))
+            }
+            #[cfg(not(esp_idf_version_major = "4"))]
+            ProvisioningSecurity::Security2 { salt, verifier } => {
+                if salt.len() > u16::MAX as usize || verifier.len() > u16::MAX as usize {
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+                }
+
+                let salt = salt.to_vec();
+                let verifier = verifier.to_vec();
+
+                let params = Box::new(wifi_prov_security2_params_t {
+                    salt: salt.as_ptr() as *const _,
+                    salt_len: salt.len() as _,
+                    verifier: verifier.as_ptr() as *const _,
+                    verifier_len: verifier.len() as _,
+                });
+
+                let params_ptr = &*params as *const wifi_prov_security2_params_t;
+
+                self.security2 = Some((salt, verifier, params));
+
+                Ok(params_ptr as *const ffi::c_void)
+            }
+        }
+    }
+
+    unsafe extern "C" fn handle_endpoint(
+        session_id: u32,
+        inbuf: *const u8,
+        inlen: ssize_t,
+        outbuf: *mut *mut u8,
+        outlen: *mut ssize_t,
+        priv_data: *mut ffi::c_void,
+    ) -> esp_err_t {
+        let handler = (priv_data as *mut EndpointHandler).as_mut().unwrap();
+
+        let request = if inbuf.is_null() || inlen <= 0 {
+            &[]
+        } else {
+            core::slice::from_raw_parts(inbuf, inlen as _)
+        };
+
+        match handler(session_id, request) {
+            Ok(response) => {
+                // Protocomm frees the response with `free`
+                let buf = malloc(response.len().max(1) as _) as *mut u8;
+                if buf.is_null() {
+                    return ESP_ERR_NO_MEM;
+                }
+
+                ptr::copy_nonoverlapping(response.as_ptr(), buf, response.len());
+
+                *outbuf = buf;
+                *outlen = response.len() as _;
+
+                ESP_OK
+            }
+            Err(err) => err.code(),
+        }
+    }
+}
+
+impl Drop for EspWifiProvisioning<'_> {
+    fn drop(&mut self) {
+        unsafe { wifi_prov_mgr_deinit() };
+
+        debug!("Provisioning manager de-initialized");
+    }
+}
+
+unsafe impl Send for EspWifiProvisioning<'_> {}
+
+/// The reason why the Wifi station could not connect with the received credentials
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProvisioningFailure {
+    AuthenticationError,
+    AccessPointNotFound,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum WifiProvEvent {
+    Initialized,
+    Started,
+    CredentialsReceived {
+        ssid: heapless::String<32>,
+        password: heapless::String<64>,
+    },
+    CredentialsFailed(ProvisioningFailure),
+    CredentialsSuccess,
+    Ended,
+    Deinitialized,
+}
+
+impl Debug for WifiProvEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Initialized => write!(f, "Initialized"),
+            Self::Started => write!(f, "Started"),
+            Self::CredentialsReceived { ssid, .. } => f
+                .debug_struct("CredentialsReceived")
+                .field("ssid", ssid)
+                .finish_non_exhaustive(),
+            Self::CredentialsFailed(reason) => {
+                f.debug_tuple("CredentialsFailed").field(reason).finish()
+            }
+            Self::CredentialsSuccess => write!(f, "CredentialsSuccess"),
+            Self::Ended => write!(f, "Ended"),
+            Self::Deinitialized => write!(f, "Deinitialized"),
+        }
+    }
+}
+
+unsafe impl EspEventSource for WifiProvEvent {
+    fn source() -> Option<&'static ffi::CStr> {
+        Some(unsafe { ffi::CStr::from_ptr(WIFI_PROV_EVENT) })
+    }
+}
+
+impl EspEventDeserializer for WifiProvEvent {
+    type Data<'d> = WifiProvEvent;
+
+    #[allow(non_upper_case_globals, non_snake_case)]
+    fn deserialize<'d>(data: &crate::eventloop::EspEvent<'d>) -> WifiProvEvent {
+        let event_id = data.event_id as u32;
+
+        match event_id {
+            wifi_prov_cb_event_t_WIFI_PROV_INIT => WifiProvEvent::Initialized,
+            wifi_prov_cb_event_t_WIFI_PROV_START => WifiProvEvent::Started,
+            wifi_prov_cb_event_t_WIFI_PROV_CRED_RECV => {
+                let conf: &wifi_sta_config_t = unsafe { data.as_payload() };
+
+                WifiProvEvent::CredentialsReceived {
+                    ssid: array_to_heapless_string_failible(conf.ssid).unwrap_or_default(),
+                    password: array_to_heapless_string_failible(conf.password).unwrap_or_default(),
+                }
+            }
+            wifi_prov_cb_event_t_WIFI_PROV_CRED_FAIL => {
+                let reason: &wifi_prov_sta_fail_reason_t = unsafe { data.as_payload() };
+
+                WifiProvEvent::CredentialsFailed(
+                    if *reason == wifi_prov_sta_fail_reason_t_WIFI_PROV_STA_AUTH_ERROR {
+                        ProvisioningFailure::AuthenticationError
+                    } else {
+                        ProvisioningFailure::AccessPointNotFound
+                    },
+                )
+            }
+            wifi_prov_cb_event_t_WIFI_PROV_CRED_SUCCESS => WifiProvEvent::CredentialsSuccess,
+            wifi_prov_cb_event_t_WIFI_PROV_END => WifiProvEvent::Ended,
+            wifi_prov_cb_event_t_WIFI_PROV_DEINIT => WifiProvEvent::Deinitialized,
+            _ => panic!("unknown event ID: {}", event_id),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Turnkey SoftAP provisioning, for the chips without BLE
+//!
+//! `SoftApProvisioning` runs the whole SoftAP flow of the unified provisioning manager: it
+//! generates the SSID and the password of the SoftAP and the proof-of-possession of the session
+//! - or takes the ones of the application - and the QR code payload scanned by the "ESP SoftAP
+//! Prov" phone app, then serves the provisioning endpoints until the station connects with the
+//! credentials received.
+//!
+//! The credentials are validated by the provisioning manager, by connecting the station with
+//! them, and then persisted to NVS by the Wifi driver:
+//!
+//! ```ignore
+//! let mut prov = SoftApProvisioning::new(&mut wifi, &sys_loop, &Default::default())?;
+//!
+//! info!("Scan: {}", prov.qr_payload());
+//!
+//! prov.run()?;
+//! ```
+//!
+//! Besides the `WifiProvEvent` of the provisioning manager, the flow posts a
+//! `SoftApProvisioningEvent` to the system event loop once it completes or fails.
+
+use core::ffi::CStr;
+use core::fmt::Write;
+
+extern crate alloc;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use ::log::{info, warn};
+
+use crate::eventloop::{
+    EspEvent, EspEventDeserializer, EspEventPostData, EspEventSerializer, EspEventSource,
+    EspSubscription, EspSystemEventLoop, System,
+};
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+use crate::wifi::EspWifi;
+
+use super::{
+    EspWifiProvisioning, ProvisioningConfiguration, ProvisioningFailure, ProvisioningScheme,
+    ProvisioningSecurity, WifiProvEvent,
+};
+
+const RANDOM_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz23456789";
+
+/// The configuration of the SoftAP provisioning
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SoftApProvisioningConfiguration<'a> {
+    /// The prefix of the generated SSID, which is followed by the last 3 bytes of the MAC address
+    /// of the SoftAP, e.g. `PROV_A1B2C3`
+    pub ssid_prefix: &'a str,
+    /// The SSID of the SoftAP; generated when `None`
+    pub ssid: Option<&'a str>,
+    /// The password of the SoftAP, of at least 8 characters; a random one is generated when
+    /// `None`, and the SoftAP is open with `Some("")`
+    pub password: Option<&'a str>,
+    /// The proof-of-possession of the session; a random one is generated when `None`
+    pub pop: Option<&'a str>,
+    /// Whether to run the flow even if the station credentials are already stored in NVS
+    pub force: bool,
+}
+
+impl SoftApProvisioningConfiguration<'_> {
+    pub const fn new() -> Self {
+        Self {
+            ssid_prefix: "PROV_",
+            ssid: None,
+            password: None,
+            pop: None,
+            force: false,
+        }
+    }
+}
+
+impl Default for SoftApProvisioningConfiguration<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The outcome of the SoftAP provisioning
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SoftApProvisioningOutcome {
+    /// The station credentials were already stored in NVS, so the flow did not run
+    AlreadyProvisioned,
+    /// The station connected with the credentials received, for the given SSID
+    Provisioned { ssid: heapless::String<32> },
+}
+
+/// An event of the SoftAP provisioning flow
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SoftApProvisioningEvent {
+    /// The SoftAP and the provisioning endpoints are up
+    Started,
+    /// The credentials received did not connect the station; the phone app can send others
+    Failed(ProvisioningFailure),
+    /// The station connected with the credentials received, which are persisted
+    Completed,
+}
+
+unsafe impl EspEventSource for SoftApProvisioningEvent {
+    fn source() -> Option<&'static CStr> {
+        Some(CStr::from_bytes_with_nul(b"ESP-SVC-SOFTAP-PROV\0").unwrap())
+    }
+}
+
+impl EspEventSerializer for SoftApProvisioningEvent {
+    type Data<'a> = SoftApProvisioningEvent;
+
+    fn serialize<F, R>(event: &Self::Data<'_>, f: F) -> R
+    where
+        F: FnOnce(&EspEventPostData) -> R,
+    {
+        f(&unsafe { EspEventPostData::new(Self::source().unwrap(), Self::event_id(), event) })
+    }
+}
+
+impl EspEventDeserializer for SoftApProvisioningEvent {
+    type Data<'a> = SoftApProvisioningEvent;
+
+    fn deserialize<'a>(data: &EspEvent<'a>) -> Self::Data<'a> {
+        *unsafe { data.as_payload::<SoftApProvisioningEvent>() }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    ssid: Option<heapless::String<32>>,
+    succeeded: bool,
+}
+
+/// The SoftAP provisioning flow
+pub struct SoftApProvisioning<'a> {
+    prov: EspWifiProvisioning<'a>,
+    sys_loop: EspSystemEventLoop,
+    ssid: heapless::String<32>,
+    password: heapless::String<64>,
+    pop: heapless::String<32>,
+    force: bool,
+    state: Arc<Mutex<State>>,
+    _subscription: EspSubscription<'static, System>,
+}
+
+impl<'a> SoftApProvisioning<'a> {
+    /// Initialize the provisioning manager with the SoftAP scheme
+    ///
+    /// The Wifi driver must use the system event loop.
+    pub fn new(
+        wifi: &'a mut EspWifi<'_>,
+        sys_loop: &EspSystemEventLoop,
+        conf: &SoftApProvisioningConfiguration,
+    ) -> Result<Self, EspError> {
+        let ssid = match conf.ssid {
+            Some(ssid) => ssid.try_into().map_err(|_| invalid_size())?,
+            None => {
+                let mut mac = [0; 6];
+                esp!(unsafe {
+                    esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_SOFTAP)
+                })?;
+
+                let mut ssid = heapless::String::new();
+                write!(
+                    &mut ssid,
+                    "{}{:02X}{:02X}{:02X}",
+                    conf.ssid_prefix, mac[3], mac[4], mac[5]
+                )
+                .map_err(|_| invalid_size())?;
+
+                ssid
+            }
+        };
+
+        let password = match conf.password {
+            Some(password) if !password.is_empty() && password.len() < 8 => {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+            }
+            Some(password) => password.try_into().map_err(|_| invalid_size())?,
+            None => random(12),
+        };
+
+        let pop = match conf.pop {
+            Some(pop) => pop.try_into().map_err(|_| invalid_size())?,
+            None => random(8),
+        };
+
+        let prov = EspWifiProvisioning::new(wifi, ProvisioningScheme::SoftAp)?;
+
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let subscription = {
+            let state = state.clone();
+            let sys_loop = sys_loop.clone();
+
+            sys_loop
+                .clone()
+                .subscribe::<WifiProvEvent, _>(move |event| {
+                    let posted = match event {
+                        WifiProvEvent::Started => Some(SoftApProvisioningEvent::Started),
+                        WifiProvEvent::CredentialsReceived { ssid, .. } => {
+                            state.lock().ssid = Some(ssid);
+                            None
+                        }
+                        WifiProvEvent::CredentialsFailed(failure) => {
+                            Some(SoftApProvisioningEvent::Failed(failure))
+                        }
+                        WifiProvEvent::CredentialsSuccess => {
+                            state.lock().succeeded = true;
+                            Some(SoftApProvisioningEvent::Completed)
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(posted) = posted {
+                        if !matches!(
+                            sys_loop.post::<SoftApProvisioningEvent>(&posted, 0),
+                            Ok(true)
+                        ) {
+                            warn!("Failed to post {posted:?}");
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            prov,
+            sys_loop: sys_loop.clone(),
+            ssid,
+            password,
+            pop,
+            force: conf.force,
+            state,
+            _subscription: subscription,
+        })
+    }
+
+    /// The SSID of the SoftAP
+    pub fn ssid(&self) -> &str {
+        &self.ssid
+    }
+
+    /// The password of the SoftAP; empty for an open SoftAP
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// The proof-of-possession of the session
+    pub fn pop(&self) -> &str {
+        &self.pop
+    }
+
+    /// The payload of the QR code scanned by the phone app, with the SSID, the password and the
+    /// proof-of-possession
+    pub fn qr_payload(&self) -> String {
+        let mut payload = format!(
+            r#"{{"ver":"v1","name":"{}","pop":"{}","transport":"softap""#,
+            self.ssid, self.pop
+        );
+
+        if !self.password.is_empty() {
+            payload.push_str(&format!(r#","password":"{}""#, self.password));
+        }
+
+        payload.push('}');
+
+        payload
+    }
+
+    /// The provisioning manager, e.g. to register custom endpoints once started
+    pub fn provisioning(&mut self) -> &mut EspWifiProvisioning<'a> {
+        &mut self.prov
+    }
+
+    /// Start the SoftAP and the provisioning endpoints, unless the station credentials are already
+    /// stored in NVS (and the flow is not forced); return whether it started
+    pub fn start(&mut self) -> Result<bool, EspError> {
+        if !self.force && self.prov.is_provisioned()? {
+            info!("Already provisioned");
+            return Ok(false);
+        }
+
+        self.prov.start(&ProvisioningConfiguration {
+            service_name: &self.ssid,
+            service_key: (!self.password.is_empty()).then_some(self.password.as_str()),
+            security: ProvisioningSecurity::Security1 {
+                pop: Some(&self.pop),
+            },
+            ..Default::default()
+        })?;
+
+        info!("SoftAP {} up for provisioning", self.ssid);
+
+        Ok(true)
+    }
+
+    /// Block until the station connected with the credentials received and the provisioning
+    /// stopped, and return the outcome
+    pub fn wait(&mut self) -> Result<SoftApProvisioningOutcome, EspError> {
+        self.prov.wait();
+
+        let state = self.state.lock();
+
+        match (&state.ssid, state.succeeded) {
+            (Some(ssid), true) => {
+                info!("Provisioned for {ssid}");
+
+                Ok(SoftApProvisioningOutcome::Provisioned { ssid: ssid.clone() })
+            }
+            // Stopped before the station connected
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()),
+        }
+    }
+
+    /// Run the whole flow: `start`, then `wait`
+    pub fn run(&mut self) -> Result<SoftApProvisioningOutcome, EspError> {
+        if self.start()? {
+            self.wait()
+        } else {
+            Ok(SoftApProvisioningOutcome::AlreadyProvisioned)
+        }
+    }
+
+    /// Stop the provisioning in progress
+    pub fn stop(&mut self) {
+        self.prov.stop();
+    }
+
+    pub fn sys_loop(&self) -> &EspSystemEventLoop {
+        &self.sys_loop
+    }
+}
+
+impl core::fmt::Debug for SoftApProvisioning<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SoftApProvisioning")
+            .field("ssid", &self.ssid)
+            .field("force", &self.force)
+            .finish_non_exhaustive()
+    }
+}
+
+fn random<const N: usize>(len: usize) -> heapless::String<N> {
+    let mut bytes = [0_u8; N];
+    let len = len.min(N);
+
+    crate::rng::fill(&mut bytes[..len]);
+
+    bytes[..len]
+        .iter()
+        .map(|byte| RANDOM_ALPHABET[*byte as usize % RANDOM_ALPHABET.len()] as char)
+        .collect()
+}
+
+fn invalid_size() -> EspError {
+    EspError::from_infallible::<ESP_ERR_INVALID_SIZE>()
+}
@@ -0,0 +1,221 @@
+//! Extended access point scan results
+//!
+//! `ScanResult` complements the `AccessPointInfo` of an access point with the details of its
+//! scan record which `embedded-svc` does not model: its ciphers, the PHY modes it supports, WPS,
+//! FTM and its country IE. Use `WifiDriver::get_scan_records` - or `get_scan_records_n` -
+//! instead of `get_scan_result` after a scan.
+
+use core::str::Utf8Error;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use crate::private::common::*;
+
+use super::{AccessPointInfo, WifiDriver};
+
+/// A cipher used by an access point
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Cipher {
+    None,
+    Wep40,
+    Wep104,
+    Tkip,
+    Ccmp,
+    TkipCcmp,
+    AesCmac128,
+    Sms4,
+    Gcmp,
+    Gcmp256,
+    AesGmac128,
+    AesGmac256,
+    Unknown,
+}
+
+#[allow(non_upper_case_globals)]
+impl From<Newtype<wifi_cipher_type_t>> for Cipher {
+    fn from(cipher: Newtype<wifi_cipher_type_t>) -> Self {
+        match cipher.0 {
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_NONE => Self::None,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP40 => Self::Wep40,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP104 => Self::Wep104,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP => Self::Tkip,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_CCMP => Self::Ccmp,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP_CCMP => Self::TkipCcmp,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_AES_CMAC128 => Self::AesCmac128,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_SMS4 => Self::Sms4,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_GCMP => Self::Gcmp,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_GCMP256 => Self::Gcmp256,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_AES_GMAC128 => Self::AesGmac128,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_AES_GMAC256 => Self::AesGmac256,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// The environment a country IE applies to, from its third character
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CountryEnvironment {
+    Any,
+    Indoor,
+    Outdoor,
+}
+
+/// The country IE of an access point
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Country {
+    /// The ISO 3166-1 alpha-2 code of the country, e.g. `"DE"`
+    pub code: heapless::String<2>,
+    pub environment: CountryEnvironment,
+    pub first_channel: u8,
+    pub channels: u8,
+    /// The maximum transmit power, in dBm
+    pub max_tx_power: i8,
+}
+
+impl Country {
+    fn from_raw(country: &wifi_country_t) -> Option<Self> {
+        let cc = country.cc.map(|c| c as u8);
+
+        if !cc[0].is_ascii_alphabetic() || !cc[1].is_ascii_alphabetic() {
+            return None;
+        }
+
+        let mut code = heapless::String::new();
+        code.push(cc[0] as char).unwrap();
+        code.push(cc[1] as char).unwrap();
+
+        Some(Self {
+            code,
+            environment: match cc[2] {
+                b'I' => CountryEnvironment::Indoor,
+                b'O' => CountryEnvironment::Outdoor,
+                _ => CountryEnvironment::Any,
+            },
+            first_channel: country.schan,
+            channels: country.nchan,
+            max_tx_power: country.max_tx_power,
+        })
+    }
+}
+
+/// The scan record of an access point
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScanResult {
+    pub info: AccessPointInfo,
+    pub pairwise_cipher: Cipher,
+    pub group_cipher: Cipher,
+    pub phy_11b: bool,
+    pub phy_11g: bool,
+    /// 802.11n, i.e. HT; an HT40 access point has a secondary channel
+    pub phy_11n: bool,
+    /// 802.11ax, i.e. HE. Always `false` on ESP-IDF 4
+    pub phy_11ax: bool,
+    /// The Espressif long range mode
+    pub phy_lr: bool,
+    pub wps: bool,
+    /// Whether the access point answers fine timing measurement requests
+    pub ftm_responder: bool,
+    pub ftm_initiator: bool,
+    /// The country IE, if the access point advertises one
+    pub country: Option<Country>,
+    /// The BSS color of an 802.11ax access point; not available on ESP-IDF 4
+    pub bss_color: Option<u8>,
+}
+
+impl TryFrom<Newtype<&wifi_ap_record_t>> for ScanResult {
+    type Error = Utf8Error;
+
+    fn try_from(ap_info: Newtype<&wifi_ap_record_t>) -> Result<Self, Self::Error> {
+        let a = ap_info.0;
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let phy_11ax = a.phy_11ax() != 0;
+        #[cfg(esp_idf_version_major = "4")]
+        let phy_11ax = false;
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let bss_color =
+            (phy_11ax && a.he_ap.bss_color_disabled() == 0).then(|| a.he_ap.bss_color() as u8);
+        #[cfg(esp_idf_version_major = "4")]
+        let bss_color = None;
+
+        Ok(Self {
+            info: Newtype(ap_info.0).try_into()?,
+            pairwise_cipher: Newtype(a.pairwise_cipher).into(),
+            group_cipher: Newtype(a.group_cipher).into(),
+            phy_11b: a.phy_11b() != 0,
+            phy_11g: a.phy_11g() != 0,
+            phy_11n: a.phy_11n() != 0,
+            phy_11ax,
+            phy_lr: a.phy_lr() != 0,
+            wps: a.wps() != 0,
+            ftm_responder: a.ftm_responder() != 0,
+            ftm_initiator: a.ftm_initiator() != 0,
+            country: Country::from_raw(&a.country),
+            bss_color,
+        })
+    }
+}
+
+impl WifiDriver<'_> {
+    /// Get the extended results of an access point scan.
+    ///
+    /// The same as [`WifiDriver::get_scan_result_n()`], but with the whole scan records.
+    pub fn get_scan_records_n<const N: usize>(
+        &mut self,
+    ) -> Result<(heapless::Vec<ScanResult, N>, usize), EspError> {
+        let scanned_count = self.get_scan_count()?;
+
+        let mut ap_infos_raw: heapless::Vec<wifi_ap_record_t, N> = heapless::Vec::new();
+        unsafe {
+            ap_infos_raw.set_len(scanned_count.min(N));
+        }
+
+        let fetched_count = self.fetch_scan_result(&mut ap_infos_raw)?;
+
+        let result = ap_infos_raw[..fetched_count]
+            .iter()
+            .map::<Result<ScanResult, Utf8Error>, _>(|ap_info_raw| Newtype(ap_info_raw).try_into())
+            .filter_map(|r| r.ok())
+            .inspect(|record| debug!("Found access point {:?}", record))
+            .collect();
+
+        Ok((result, scanned_count))
+    }
+
+    /// Get the extended results of an access point scan.
+    ///
+    /// The same as [`WifiDriver::get_scan_result()`], but with the whole scan records.
+    #[cfg(feature = "alloc")]
+    pub fn get_scan_records(&mut self) -> Result<alloc::vec::Vec<ScanResult>, EspError> {
+        let scanned_count = self.get_scan_count()?;
+
+        let mut ap_infos_raw: alloc::vec::Vec<wifi_ap_record_t> =
+            alloc::vec::Vec::with_capacity(scanned_count);
+        #[allow(clippy::uninit_vec)]
+        // ... because we are filling it in on the next line and only reading the initialized members
+        unsafe {
+            ap_infos_raw.set_len(scanned_count)
+        };
+
+        let fetched_count = self.fetch_scan_result(&mut ap_infos_raw)?;
+
+        let result = ap_infos_raw[..fetched_count]
+            .iter()
+            .map::<Result<ScanResult, Utf8Error>, _>(|ap_info_raw| Newtype(ap_info_raw).try_into())
+            .filter_map(|r| r.ok())
+            .inspect(|record| debug!("Found access point {:?}", record))
+            .collect();
+
+        Ok(result)
+    }
+}
@@ -21,6 +21,12 @@ use crate::tls::X509;
 
 pub use embedded_svc::ws::{Final, Fragmented, FrameType};
 
+#[cfg(all(
+    feature = "ws-deflate",
+    esp_idf_comp_espressif__esp_websocket_client_enabled
+))]
+pub mod deflate;
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum EspWebSocketTransport {
     TransportUnknown,
@@ -205,6 +211,13 @@ pub struct EspWebSocketClientConfig<'a> {
     pub server_cert: Option<X509<'static>>,
     pub client_cert: Option<X509<'static>>,
     pub client_key: Option<X509<'static>>,
+    /// Offer the `permessage-deflate` extension, to inflate the messages received and,
+    /// optionally, compress the messages sent
+    #[cfg(all(
+        feature = "ws-deflate",
+        esp_idf_comp_espressif__esp_websocket_client_enabled
+    ))]
+    pub permessage_deflate: Option<deflate::PermessageDeflate>,
 }
 
 impl<'a> TryFrom<&'a EspWebSocketClientConfig<'a>> for (esp_websocket_client_config_t, RawCstrs) {
@@ -213,6 +226,17 @@ impl<'a> TryFrom<&'a EspWebSocketClientConfig<'a>> for (esp_websocket_client_con
     fn try_from(conf: &EspWebSocketClientConfig) -> Result<Self, Self::Error> {
         let mut cstrs = RawCstrs::new();
 
+        #[cfg(all(
+            feature = "ws-deflate",
+            esp_idf_comp_espressif__esp_websocket_client_enabled
+        ))]
+        let headers = deflate::headers(conf.headers, conf.permessage_deflate.as_ref());
+        #[cfg(not(all(
+            feature = "ws-deflate",
+            esp_idf_comp_espressif__esp_websocket_client_enabled
+        )))]
+        let headers = conf.headers;
+
         let mut c_conf = esp_websocket_client_config_t {
             username: cstrs.as_nptr(conf.username)?,
             password: cstrs.as_nptr(conf.password)?,
@@ -228,7 +252,7 @@ impl<'a> TryFrom<&'a EspWebSocketClientConfig<'a>> for (esp_websocket_client_con
 
             subprotocol: cstrs.as_nptr(conf.subprotocol)? as _,
             user_agent: cstrs.as_nptr(conf.user_agent)? as _,
-            headers: cstrs.as_nptr(conf.headers)? as _,
+            headers: cstrs.as_nptr(headers)? as _,
 
             pingpong_timeout_sec: conf.pingpong_timeout_sec.as_secs() as _,
             disable_pingpong_discon: conf.disable_pingpong_discon,
@@ -406,6 +430,11 @@ pub struct EspWebSocketClient<'a> {
     // used for the timeout in every call to a send method in the c lib as the
     // `send` method in the `Sender` trait in embedded_svc::ws does not take a timeout itself
     timeout: TickType_t,
+    #[cfg(all(
+        feature = "ws-deflate",
+        esp_idf_comp_espressif__esp_websocket_client_enabled
+    ))]
+    deflate: Option<deflate::PermessageDeflate>,
     _callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send + 'a>,
 }
 
@@ -505,6 +534,25 @@ impl<'a> EspWebSocketClient<'a> {
         timeout: time::Duration,
         raw_callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send + 'a>,
     ) -> Result<Self, EspIOError> {
+        #[cfg(all(
+            feature = "ws-deflate",
+            esp_idf_comp_espressif__esp_websocket_client_enabled
+        ))]
+        let raw_callback: Box<dyn FnMut(i32, *mut esp_websocket_event_data_t) + Send + 'a> =
+            match config.permessage_deflate {
+                Some(conf) if conf.inflate => {
+                    let mut inflater = deflate::Inflater::new(conf);
+                    let mut raw_callback = raw_callback;
+
+                    Box::new(move |event_id, event_handle| {
+                        if let Some(event_handle) = inflater.process(event_id, event_handle) {
+                            raw_callback(event_id, event_handle);
+                        }
+                    })
+                }
+                _ => raw_callback,
+            };
+
         let mut boxed_raw_callback = Box::new(raw_callback);
         let unsafe_callback = UnsafeCallback::from(&mut boxed_raw_callback);
 
@@ -522,6 +570,11 @@ impl<'a> EspWebSocketClient<'a> {
         let client = Self {
             handle,
             timeout: t.0,
+            #[cfg(all(
+                feature = "ws-deflate",
+                esp_idf_comp_espressif__esp_websocket_client_enabled
+            ))]
+            deflate: config.permessage_deflate,
             _callback: boxed_raw_callback,
         };
 
@@ -588,6 +641,30 @@ impl<'a> EspWebSocketClient<'a> {
     }
 
     fn send_data(&mut self, frame_type: FrameType, frame_data: &[u8]) -> Result<usize, EspError> {
+        #[cfg(all(
+            feature = "ws-deflate",
+            esp_idf_comp_espressif__esp_websocket_client_enabled
+        ))]
+        if let Some((opcode, compressed)) = self.deflate.as_ref().and_then(|deflate| {
+            let opcode = match frame_type {
+                FrameType::Text(false) => 1,
+                _ => 2,
+            };
+
+            deflate.compress(opcode, frame_data)
+        }) {
+            // The opcode is written as is in the first byte of the frame header, with the RSV1 bit
+            return Self::check(unsafe {
+                esp_websocket_client_send_with_opcode(
+                    self.handle,
+                    opcode as _,
+                    compressed.as_ptr(),
+                    compressed.len() as _,
+                    self.timeout,
+                )
+            });
+        }
+
         let content = frame_data.as_ref().as_ptr();
         let content_length = frame_data.as_ref().len();
 
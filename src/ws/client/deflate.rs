@@ -0,0 +1,244 @@
+//! `permessage-deflate` compression of the WebSocket messages (RFC 7692)
+//!
+//! With `EspWebSocketClientConfig::permessage_deflate` set, the client offers the extension in
+//! the opening handshake. With `inflate` set, it inflates the messages it receives, reassembling
+//! them first if fragmented; with `compress` set, it compresses the messages it sends - those of
+//! at least `threshold` bytes, if it makes them smaller. JSON typically shrinks 3 to 5 times.
+//!
+//! The extension is offered with `client_no_context_takeover` and `server_no_context_takeover`,
+//! so that every message is compressed on its own: this costs some compression ratio, but no
+//! memory is kept for the sliding windows between the messages.
+//!
+//! Note that the ESP IDF client exposes neither the headers of the handshake response nor the
+//! RSV1 bit of the frames received, so whether the server accepted the extension - and whether
+//! it compressed a message - is unknown. Both directions are thus an explicit opt-in, for servers
+//! known to accept the extension:
+//! - with `inflate`, every message received is taken as compressed: a server which ignored the
+//!   offer, or which does not compress all its messages, would have its messages dropped, as
+//!   they do not inflate - or worse, inflate into garbage;
+//! - with `compress`, a server which ignored the offer fails the connection on the first
+//!   compressed message.
+//!
+//! The messages received which do not inflate, or which are longer than `max_message_len`, are
+//! dropped with a warning.
+//!
+//! Requires the `ws-deflate` feature, as the compressor adds some code size.
+
+use core::mem;
+
+extern crate alloc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use miniz_oxide::deflate::core::{create_comp_flags_from_zip_params, CompressorOxide};
+use miniz_oxide::deflate::stream::deflate;
+use miniz_oxide::{MZFlush, MZStatus};
+
+use crate::sys::*;
+
+const EXTENSION_OFFER: &str = "Sec-WebSocket-Extensions: permessage-deflate; \
+    client_no_context_takeover; server_no_context_takeover\r\n";
+
+// The RSV1 bit of the first frame of a compressed message
+const RSV1: u32 = 0x40;
+
+// The trailer of a sync flush, stripped by the sender
+const SYNC_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// The trailer stripped by the sender, followed by an empty final block
+const TRAILER: [u8; 6] = [0x00, 0x00, 0xff, 0xff, 0x03, 0x00];
+
+/// The configuration of the `permessage-deflate` compression
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PermessageDeflate {
+    /// Whether to inflate the messages received; only to be set when the server is known to
+    /// accept the extension, and to compress all its messages
+    pub inflate: bool,
+    /// Whether to compress the messages sent; only to be set when the server is known to
+    /// accept the extension
+    pub compress: bool,
+    /// The compression level, from 1 (fastest) to 10 (smallest)
+    pub level: u8,
+    /// The minimum length of a message to compress
+    pub threshold: usize,
+    /// The maximum length of a message received, once inflated
+    pub max_message_len: usize,
+}
+
+impl PermessageDeflate {
+    pub const fn new() -> Self {
+        Self {
+            inflate: false,
+            compress: false,
+            level: 6,
+            threshold: 64,
+            max_message_len: 16384,
+        }
+    }
+
+    /// The compressed message and its opcode, or `None` if the message is better sent as is
+    pub(crate) fn compress(&self, opcode: u32, data: &[u8]) -> Option<(u32, Vec<u8>)> {
+        if !self.compress || data.len() < self.threshold {
+            return None;
+        }
+
+        // Raw deflate, ended with a sync flush rather than a final block, as per the RFC
+        let mut compressor =
+            CompressorOxide::new(create_comp_flags_from_zip_params(self.level as _, 0, 0));
+
+        // Only worth it if smaller than the message: a longer output is given up on
+        let mut compressed = alloc::vec![0; data.len() + SYNC_TRAILER.len()];
+
+        let result = deflate(&mut compressor, data, &mut compressed, MZFlush::Sync);
+
+        if !matches!(result.status, Ok(MZStatus::Ok))
+            || result.bytes_consumed < data.len()
+            || result.bytes_written == compressed.len()
+        {
+            return None;
+        }
+
+        compressed.truncate(result.bytes_written);
+
+        if !compressed.ends_with(&SYNC_TRAILER) {
+            return None;
+        }
+
+        compressed.truncate(compressed.len() - SYNC_TRAILER.len());
+
+        (compressed.len() < data.len()).then_some((opcode | RSV1, compressed))
+    }
+}
+
+impl Default for PermessageDeflate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The custom headers of the handshake, with the extension offer
+pub(crate) fn headers(
+    headers: Option<&str>,
+    deflate: Option<&PermessageDeflate>,
+) -> Option<String> {
+    let mut headers = headers.map(ToString::to_string);
+
+    if deflate.is_some() {
+        headers
+            .get_or_insert_with(String::new)
+            .push_str(EXTENSION_OFFER);
+    }
+
+    headers
+}
+
+/// Reassembles and inflates the messages received, before they reach the event callback
+pub(crate) struct Inflater {
+    conf: PermessageDeflate,
+    opcode: i32,
+    message: Vec<u8>,
+    overflow: bool,
+    // The inflated messages and their events, alternating, so that the previous one stays valid
+    // while the next is inflated
+    slots: [(Vec<u8>, Option<esp_websocket_event_data_t>); 2],
+    slot: usize,
+}
+
+impl Inflater {
+    pub(crate) fn new(conf: PermessageDeflate) -> Self {
+        Self {
+            conf,
+            opcode: 0,
+            message: Vec::new(),
+            overflow: false,
+            slots: Default::default(),
+            slot: 0,
+        }
+    }
+
+    /// The event to deliver, if any: the event itself unless it is a data one; the inflated
+    /// message once its last fragment is received
+    pub(crate) fn process(
+        &mut self,
+        event_id: i32,
+        event: *mut esp_websocket_event_data_t,
+    ) -> Option<*mut esp_websocket_event_data_t> {
+        if event_id != esp_websocket_event_id_t_WEBSOCKET_EVENT_DATA {
+            return Some(event);
+        }
+
+        let data = unsafe { event.as_ref() }?;
+
+        match data.op_code {
+            // The start of a message
+            1 | 2 if data.payload_offset == 0 => {
+                self.opcode = data.op_code as _;
+                self.message.clear();
+                self.overflow = false;
+            }
+            // The rest of a frame, or a continuation frame
+            0..=2 => (),
+            // A control frame
+            _ => return Some(event),
+        }
+
+        let chunk = unsafe {
+            core::slice::from_raw_parts(data.data_ptr as *const u8, data.data_len as usize)
+        };
+
+        if self.message.len() + chunk.len() > self.conf.max_message_len {
+            self.overflow = true;
+        } else if !self.overflow {
+            self.message.extend_from_slice(chunk);
+        }
+
+        let frame_end = data.payload_offset + data.data_len >= data.payload_len;
+
+        if !(frame_end && data.fin) {
+            return None;
+        }
+
+        if mem::take(&mut self.overflow) {
+            warn!(
+                "Dropping a message larger than {} bytes",
+                self.conf.max_message_len
+            );
+            return None;
+        }
+
+        let mut compressed = mem::take(&mut self.message);
+        compressed.extend_from_slice(&TRAILER);
+
+        let message = match miniz_oxide::inflate::decompress_to_vec_with_limit(
+            &compressed,
+            self.conf.max_message_len,
+        ) {
+            Ok(inflated) => inflated,
+            Err(err) => {
+                warn!("Dropping a message which does not inflate: {err:?}");
+                return None;
+            }
+        };
+
+        self.slot = 1 - self.slot;
+
+        let slot = &mut self.slots[self.slot];
+        slot.0 = message;
+
+        let mut inflated = *data;
+        inflated.op_code = self.opcode as _;
+        inflated.fin = true;
+        inflated.data_ptr = slot.0.as_ptr() as _;
+        inflated.data_len = slot.0.len() as _;
+        inflated.payload_len = slot.0.len() as _;
+        inflated.payload_offset = 0;
+
+        let inflated = slot.1.insert(inflated);
+
+        Some(inflated as *mut _)
+    }
+}
+
+unsafe impl Send for Inflater {}
@@ -0,0 +1,609 @@
+//! Zigbee end devices and routers, with the esp-zigbee-sdk
+//!
+//! On the chips with an 802.15.4 radio - the ESP32-C6 and the ESP32-H2 - `EspZigbee` runs
+//! the Zigbee stack of the `espressif/esp-zigbee-lib` component on the native radio, with
+//! the endpoints and the standard Home Automation clusters of the device:
+//!
+//! ```ignore
+//! use esp_idf_svc::zigbee::*;
+//!
+//! let endpoints = [Endpoint {
+//!     id: 10,
+//!     device_id: DEVICE_ID_ON_OFF_LIGHT,
+//!     clusters: &[Cluster::OnOff { on: false }, Cluster::Level { level: 0 }],
+//! }];
+//!
+//! let mut zigbee = EspZigbee::new(
+//!     &ZigbeeConfiguration::default(),
+//!     &endpoints,
+//!     sys_loop.clone(),
+//!     |write| {
+//!         if write.cluster == CLUSTER_ON_OFF {
+//!             // Switch the light, with `write.value`
+//!         }
+//!     },
+//! )?;
+//!
+//! let _subscription = sys_loop.subscribe::<ZigbeeEvent, _>(|event| {
+//!     if let ZigbeeEvent::Joined { pan_id, .. } = event {
+//!         log::info!("Joined {pan_id:04x}");
+//!     }
+//! })?;
+//!
+//! // Report a change made locally, e.g. with a button
+//! zigbee.set_attribute(10, CLUSTER_ON_OFF, ATTR_ON_OFF, AttributeValue::Bool(true))?;
+//! ```
+//!
+//! A factory new device starts the network steering - i.e. looks for a network open for
+//! joining - and keeps retrying until it joins; once joined, it rejoins the same network on
+//! every boot. The stack stores the network in the `zb_storage` and `zb_fct` partitions,
+//! which must be in the partition table.
+//!
+//! The stack runs in its own task, for the lifetime of the program.
+
+use core::ffi::{c_void, CStr};
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+use ::log::{info, warn};
+
+use crate::eventloop::{
+    EspEvent, EspEventDeserializer, EspEventPostData, EspEventSerializer, EspEventSource,
+    EspSystemEventLoop,
+};
+use crate::private::mutex::Mutex;
+use crate::sys::*;
+
+/// The Basic cluster, on every endpoint
+pub const CLUSTER_BASIC: u16 = 0x0000;
+/// The Identify cluster, on every endpoint
+pub const CLUSTER_IDENTIFY: u16 = 0x0003;
+pub const CLUSTER_ON_OFF: u16 = 0x0006;
+pub const CLUSTER_LEVEL: u16 = 0x0008;
+pub const CLUSTER_TEMPERATURE_MEASUREMENT: u16 = 0x0402;
+
+/// The `OnOff` attribute of the On/Off cluster
+pub const ATTR_ON_OFF: u16 = 0x0000;
+/// The `CurrentLevel` attribute of the Level Control cluster
+pub const ATTR_CURRENT_LEVEL: u16 = 0x0000;
+/// The `MeasuredValue` attribute of the Temperature Measurement cluster, in 0.01 degrees
+/// Celsius
+pub const ATTR_MEASURED_VALUE: u16 = 0x0000;
+
+/// The Home Automation device IDs of the most common devices
+pub const DEVICE_ID_ON_OFF_LIGHT: u16 = 0x0100;
+pub const DEVICE_ID_DIMMABLE_LIGHT: u16 = 0x0101;
+pub const DEVICE_ID_ON_OFF_SWITCH: u16 = 0x0000;
+pub const DEVICE_ID_TEMPERATURE_SENSOR: u16 = 0x0302;
+
+// The delay before the network steering is retried
+const STEERING_RETRY_MS: u32 = 1000;
+
+/// The role of the device in the Zigbee network
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ZigbeeRole {
+    EndDevice,
+    Router,
+}
+
+/// The configuration of the Zigbee stack
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ZigbeeConfiguration {
+    pub role: ZigbeeRole,
+    /// The mask of the channels - 11 to 26 - on which to look for a network
+    pub channel_mask: u32,
+    /// Whether the device joins with an install code, rather than the well-known link key
+    pub install_code_policy: bool,
+    /// The period at which an end device polls its parent, in ms
+    pub keep_alive_ms: u32,
+    /// The maximum number of children of a router
+    pub max_children: u8,
+}
+
+impl ZigbeeConfiguration {
+    pub const fn new() -> Self {
+        Self {
+            role: ZigbeeRole::EndDevice,
+            channel_mask: 0x07ff_f800,
+            install_code_policy: false,
+            keep_alive_ms: 3000,
+            max_children: 10,
+        }
+    }
+}
+
+impl Default for ZigbeeConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A standard cluster of an endpoint, with the initial value of its attributes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cluster {
+    OnOff {
+        on: bool,
+    },
+    Level {
+        level: u8,
+    },
+    /// The temperatures, in 0.01 degrees Celsius
+    TemperatureMeasurement {
+        measured: i16,
+        min: i16,
+        max: i16,
+    },
+}
+
+/// An endpoint of the device; the Basic and the Identify clusters are added to each
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint<'a> {
+    /// The endpoint, 1 to 240
+    pub id: u8,
+    /// The Home Automation device ID, e.g. `DEVICE_ID_ON_OFF_LIGHT`
+    pub device_id: u16,
+    /// The server clusters
+    pub clusters: &'a [Cluster],
+}
+
+/// The value of an attribute
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AttributeValue {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    I16(i16),
+    /// A value of another type, by ZCL type ID
+    Other(u8),
+}
+
+impl AttributeValue {
+    #[allow(non_upper_case_globals)]
+    unsafe fn from_raw(data: &esp_zb_zcl_attribute_data_t) -> Self {
+        if data.value.is_null() {
+            return Self::Other(data.type_ as _);
+        }
+
+        match data.type_ {
+            esp_zb_zcl_attr_type_t_ESP_ZB_ZCL_ATTR_TYPE_BOOL => {
+                Self::Bool(*(data.value as *const u8) != 0)
+            }
+            esp_zb_zcl_attr_type_t_ESP_ZB_ZCL_ATTR_TYPE_U8 => Self::U8(*(data.value as *const u8)),
+            esp_zb_zcl_attr_type_t_ESP_ZB_ZCL_ATTR_TYPE_U16 => {
+                Self::U16((data.value as *const u16).read_unaligned())
+            }
+            esp_zb_zcl_attr_type_t_ESP_ZB_ZCL_ATTR_TYPE_S16 => {
+                Self::I16((data.value as *const i16).read_unaligned())
+            }
+            other => Self::Other(other as _),
+        }
+    }
+}
+
+/// A write of an attribute by a remote device, e.g. the `OnOff` attribute by a switch
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AttributeWrite {
+    pub endpoint: u8,
+    pub cluster: u16,
+    pub attribute: u16,
+    pub value: AttributeValue,
+}
+
+/// An event of the Zigbee stack
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ZigbeeEvent {
+    /// Joined a network, as a factory new device or on boot
+    Joined {
+        pan_id: u16,
+        channel: u8,
+        short_address: u16,
+    },
+    /// The steering failed to find a network open for joining; it is retried
+    SteeringFailed,
+    /// Left the network
+    Left,
+}
+
+unsafe impl EspEventSource for ZigbeeEvent {
+    fn source() -> Option<&'static CStr> {
+        Some(CStr::from_bytes_with_nul(b"ESP-SVC-ZIGBEE\0").unwrap())
+    }
+}
+
+impl EspEventSerializer for ZigbeeEvent {
+    type Data<'a> = ZigbeeEvent;
+
+    fn serialize<F, R>(event: &Self::Data<'_>, f: F) -> R
+    where
+        F: FnOnce(&EspEventPostData) -> R,
+    {
+        f(&unsafe { EspEventPostData::new(Self::source().unwrap(), Self::event_id(), event) })
+    }
+}
+
+impl EspEventDeserializer for ZigbeeEvent {
+    type Data<'a> = ZigbeeEvent;
+
+    fn deserialize<'a>(data: &EspEvent<'a>) -> Self::Data<'a> {
+        *unsafe { data.as_payload::<ZigbeeEvent>() }
+    }
+}
+
+type WriteCallback = Box<dyn FnMut(AttributeWrite) + Send + 'static>;
+
+// The state used by the callbacks of the stack, which take no context
+struct State {
+    sys_loop: EspSystemEventLoop,
+    callback: WriteCallback,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// The Zigbee stack, on the native 802.15.4 radio
+pub struct EspZigbee(());
+
+impl EspZigbee {
+    /// Start the stack with the endpoints of the device
+    ///
+    /// `callback` is called - in the task of the stack - on every write of an attribute by a
+    /// remote device, e.g. to switch a light when a switch writes its `OnOff` attribute; it
+    /// must not call back into `EspZigbee`.
+    ///
+    /// The stack can be started only once.
+    pub fn new<F>(
+        conf: &ZigbeeConfiguration,
+        endpoints: &[Endpoint<'_>],
+        sys_loop: EspSystemEventLoop,
+        callback: F,
+    ) -> Result<Self, EspError>
+    where
+        F: FnMut(AttributeWrite) + Send + 'static,
+    {
+        let mut state = STATE.lock();
+
+        if state.is_some() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+
+        let mut platform_config: esp_zb_platform_config_t = Default::default();
+        platform_config.radio_config.radio_mode = esp_zb_radio_mode_t_ZB_RADIO_MODE_NATIVE;
+        platform_config.host_config.host_connection_mode =
+            esp_zb_host_connection_mode_t_ZB_HOST_CONNECTION_MODE_NONE;
+
+        esp!(unsafe { esp_zb_platform_config(&mut platform_config) })?;
+
+        let mut zb_config: esp_zb_cfg_t = Default::default();
+        zb_config.install_code_policy = conf.install_code_policy;
+
+        match conf.role {
+            ZigbeeRole::EndDevice => {
+                zb_config.esp_zb_role = esp_zb_nwk_device_type_t_ESP_ZB_DEVICE_TYPE_ED;
+                zb_config.nwk_cfg.zed_cfg = esp_zb_zed_cfg_t {
+                    ed_timeout: esp_zb_aging_timeout_t_ESP_ZB_ED_AGING_TIMEOUT_64MIN as _,
+                    keep_alive: conf.keep_alive_ms,
+                };
+            }
+            ZigbeeRole::Router => {
+                zb_config.esp_zb_role = esp_zb_nwk_device_type_t_ESP_ZB_DEVICE_TYPE_ROUTER;
+                zb_config.nwk_cfg.zczr_cfg = esp_zb_zczr_cfg_t {
+                    max_children: conf.max_children,
+                };
+            }
+        }
+
+        unsafe {
+            esp_zb_init(&mut zb_config);
+        }
+
+        let ep_list = unsafe { esp_zb_ep_list_create() };
+
+        for endpoint in endpoints {
+            let cluster_list = create_clusters(endpoint.clusters)?;
+
+            let ep_config = esp_zb_endpoint_config_t {
+                endpoint: endpoint.id,
+                app_profile_id: esp_zb_af_profile_id_t_ESP_ZB_AF_HA_PROFILE_ID as _,
+                app_device_id: endpoint.device_id,
+                app_device_version: 0,
+            };
+
+            esp!(unsafe { esp_zb_ep_list_add_ep(ep_list, cluster_list, ep_config) })?;
+        }
+
+        esp!(unsafe { esp_zb_device_register(ep_list) })?;
+        esp!(unsafe { esp_zb_core_action_handler_register(Some(Self::handle_action)) })?;
+        esp!(unsafe { esp_zb_set_primary_network_channel_set(conf.channel_mask) })?;
+
+        *state = Some(State {
+            sys_loop,
+            callback: Box::new(callback),
+        });
+
+        drop(state);
+
+        std::thread::Builder::new()
+            .name("zb-main".into())
+            .stack_size(8192)
+            .spawn(|| {
+                if let Err(err) = esp!(unsafe { esp_zb_start(false) }) {
+                    ::log::error!("Failed to start the Zigbee stack: {err}");
+                    return;
+                }
+
+                unsafe {
+                    esp_zb_stack_main_loop();
+                }
+            })
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_NO_MEM>())?;
+
+        info!("Started");
+
+        Ok(Self(()))
+    }
+
+    /// Set the value of an attribute of a server cluster, e.g. to report a change made
+    /// locally
+    pub fn set_attribute(
+        &mut self,
+        endpoint: u8,
+        cluster: u16,
+        attribute: u16,
+        value: AttributeValue,
+    ) -> Result<(), EspError> {
+        let mut bytes = [0_u8; 2];
+
+        match value {
+            AttributeValue::Bool(value) => bytes[0] = value as u8,
+            AttributeValue::U8(value) => bytes[0] = value,
+            AttributeValue::U16(value) => bytes = value.to_le_bytes(),
+            AttributeValue::I16(value) => bytes = value.to_le_bytes(),
+            AttributeValue::Other(_) => {
+                return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>())
+            }
+        }
+
+        let status = with_lock(|| unsafe {
+            esp_zb_zcl_set_attribute_val(
+                endpoint,
+                cluster,
+                esp_zb_zcl_cluster_role_t_ESP_ZB_ZCL_CLUSTER_SERVER_ROLE as _,
+                attribute,
+                bytes.as_mut_ptr() as *mut c_void,
+                false,
+            )
+        });
+
+        if status == esp_zb_zcl_status_t_ESP_ZB_ZCL_STATUS_SUCCESS {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>())
+        }
+    }
+
+    /// Get the value of an attribute of a server cluster
+    pub fn attribute(
+        &self,
+        endpoint: u8,
+        cluster: u16,
+        attribute: u16,
+    ) -> Result<AttributeValue, EspError> {
+        with_lock(|| unsafe {
+            let attr = esp_zb_zcl_get_attribute(
+                endpoint,
+                cluster,
+                esp_zb_zcl_cluster_role_t_ESP_ZB_ZCL_CLUSTER_SERVER_ROLE as _,
+                attribute,
+            )
+            .as_ref()
+            .ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+            Ok(AttributeValue::from_raw(&esp_zb_zcl_attribute_data_t {
+                type_: attr.type_ as _,
+                size: 0,
+                value: attr.data_p,
+            }))
+        })
+    }
+
+    /// Whether the device is joined to a network
+    pub fn is_joined(&self) -> bool {
+        with_lock(|| unsafe { esp_zb_bdb_dev_joined() })
+    }
+
+    /// Leave the network, and start the network steering again, e.g. on a factory reset
+    /// requested by the user
+    pub fn factory_reset(&mut self) {
+        with_lock(|| unsafe { esp_zb_factory_reset() })
+    }
+
+    unsafe extern "C" fn handle_action(
+        callback_id: esp_zb_core_action_callback_id_t,
+        message: *const c_void,
+    ) -> esp_err_t {
+        if callback_id != esp_zb_core_action_callback_id_s_ESP_ZB_CORE_SET_ATTR_VALUE_CB_ID
+            || message.is_null()
+        {
+            return ESP_OK;
+        }
+
+        let message = &*(message as *const esp_zb_zcl_set_attr_value_message_t);
+
+        if message.info.status != esp_zb_zcl_status_t_ESP_ZB_ZCL_STATUS_SUCCESS {
+            return ESP_ERR_INVALID_ARG;
+        }
+
+        let write = AttributeWrite {
+            endpoint: message.info.dst_endpoint,
+            cluster: message.info.cluster,
+            attribute: message.attribute.id,
+            value: AttributeValue::from_raw(&message.attribute.data),
+        };
+
+        if let Some(state) = STATE.lock().as_mut() {
+            (state.callback)(write);
+        }
+
+        ESP_OK
+    }
+}
+
+unsafe impl Send for EspZigbee {}
+
+fn create_clusters(clusters: &[Cluster]) -> Result<*mut esp_zb_cluster_list_t, EspError> {
+    let list = unsafe { esp_zb_zcl_cluster_list_create() };
+    let role = esp_zb_zcl_cluster_role_t_ESP_ZB_ZCL_CLUSTER_SERVER_ROLE as _;
+
+    let mut basic: esp_zb_basic_cluster_cfg_t = Default::default();
+    basic.zcl_version = ESP_ZB_ZCL_BASIC_ZCL_VERSION_DEFAULT_VALUE as _;
+    basic.power_source = ESP_ZB_ZCL_BASIC_POWER_SOURCE_DEFAULT_VALUE as _;
+
+    let mut identify: esp_zb_identify_cluster_cfg_t = Default::default();
+
+    unsafe {
+        esp!(esp_zb_cluster_list_add_basic_cluster(
+            list,
+            esp_zb_basic_cluster_create(&mut basic),
+            role,
+        ))?;
+
+        esp!(esp_zb_cluster_list_add_identify_cluster(
+            list,
+            esp_zb_identify_cluster_create(&mut identify),
+            role,
+        ))?;
+    }
+
+    for cluster in clusters {
+        match *cluster {
+            Cluster::OnOff { on } => {
+                let mut cfg = esp_zb_on_off_cluster_cfg_t { on_off: on };
+
+                esp!(unsafe {
+                    esp_zb_cluster_list_add_on_off_cluster(
+                        list,
+                        esp_zb_on_off_cluster_create(&mut cfg),
+                        role,
+                    )
+                })?;
+            }
+            Cluster::Level { level } => {
+                let mut cfg = esp_zb_level_cluster_cfg_t {
+                    current_level: level,
+                };
+
+                esp!(unsafe {
+                    esp_zb_cluster_list_add_level_cluster(
+                        list,
+                        esp_zb_level_cluster_create(&mut cfg),
+                        role,
+                    )
+                })?;
+            }
+            Cluster::TemperatureMeasurement { measured, min, max } => {
+                let mut cfg = esp_zb_temperature_meas_cluster_cfg_t {
+                    measured_value: measured,
+                    min_value: min,
+                    max_value: max,
+                };
+
+                esp!(unsafe {
+                    esp_zb_cluster_list_add_temperature_meas_cluster(
+                        list,
+                        esp_zb_temperature_meas_cluster_create(&mut cfg),
+                        role,
+                    )
+                })?;
+            }
+        }
+    }
+
+    Ok(list)
+}
+
+// Calls to the Zigbee API from outside of its task must hold the lock of the stack
+fn with_lock<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    unsafe {
+        esp_zb_lock_acquire(portMAX_DELAY);
+    }
+
+    let result = f();
+
+    unsafe {
+        esp_zb_lock_release();
+    }
+
+    result
+}
+
+fn post(event: ZigbeeEvent) {
+    if let Some(state) = STATE.lock().as_ref() {
+        if !matches!(state.sys_loop.post::<ZigbeeEvent>(&event, 0), Ok(true)) {
+            warn!("Dropped {event:?}");
+        }
+    }
+}
+
+unsafe extern "C" fn start_steering(_param: u8) {
+    esp_zb_bdb_start_top_level_commissioning(
+        esp_zb_bdb_commissioning_mode_mask_t_ESP_ZB_BDB_MODE_NETWORK_STEERING as _,
+    );
+}
+
+/// The signal handler of the stack, which the SDK expects the application to define
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+unsafe extern "C" fn esp_zb_app_signal_handler(signal: *mut esp_zb_app_signal_t) {
+    let signal = &*signal;
+    let signal_type = *signal.p_app_signal;
+    let ok = signal.esp_err_status == ESP_OK;
+
+    match signal_type {
+        esp_zb_app_signal_type_t_ESP_ZB_ZDO_SIGNAL_SKIP_STARTUP => {
+            esp_zb_bdb_start_top_level_commissioning(
+                esp_zb_bdb_commissioning_mode_mask_t_ESP_ZB_BDB_MODE_INITIALIZATION as _,
+            );
+        }
+        esp_zb_app_signal_type_t_ESP_ZB_BDB_SIGNAL_DEVICE_FIRST_START
+        | esp_zb_app_signal_type_t_ESP_ZB_BDB_SIGNAL_DEVICE_REBOOT => {
+            if !ok {
+                warn!("Failed to initialize the Zigbee stack, retrying");
+                esp_zb_scheduler_alarm(Some(retry_initialization), 0, STEERING_RETRY_MS);
+            } else if esp_zb_bdb_is_factory_new() {
+                info!("Factory new, starting the network steering");
+                start_steering(0);
+            } else {
+                post_joined();
+            }
+        }
+        esp_zb_app_signal_type_t_ESP_ZB_BDB_SIGNAL_STEERING => {
+            if ok {
+                post_joined();
+            } else {
+                post(ZigbeeEvent::SteeringFailed);
+                esp_zb_scheduler_alarm(Some(start_steering), 0, STEERING_RETRY_MS);
+            }
+        }
+        esp_zb_app_signal_type_t_ESP_ZB_ZDO_SIGNAL_LEAVE => {
+            post(ZigbeeEvent::Left);
+        }
+        _ => (),
+    }
+}
+
+unsafe extern "C" fn retry_initialization(_param: u8) {
+    esp_zb_bdb_start_top_level_commissioning(
+        esp_zb_bdb_commissioning_mode_mask_t_ESP_ZB_BDB_MODE_INITIALIZATION as _,
+    );
+}
+
+unsafe fn post_joined() {
+    post(ZigbeeEvent::Joined {
+        pan_id: esp_zb_get_pan_id(),
+        channel: esp_zb_get_current_channel(),
+        short_address: esp_zb_get_short_address(),
+    });
+}